@@ -1,13 +1,19 @@
-use core::ptr::NonNull;
+use core::arch::x86_64::__cpuid;
+use core::mem::size_of;
+use core::ptr::{self, NonNull};
+use core::slice;
 use acpi::{AcpiHandler, PhysicalMapping};
+use acpi::address::AddressSpace;
 use acpi::fadt::Fadt;
-use acpi::madt::{Madt, MadtEntry};
+use acpi::madt::{InterruptSourceOverrideEntry, IoApicEntry, LocalApicEntry, Madt, MadtEntry, NmiSourceEntry};
 use acpi::rsdp::Rsdp;
 use log::{info, warn};
 use uefi::table::{cfg::{ACPI2_GUID, ACPI_GUID}, Boot, SystemTable, Runtime};
+use uefi::table::boot::{AllocateType, MemoryType};
 use x86_64::instructions::port::Port;
-use shared::arg::{AcpiSettings, MadtInterruptSrcOverride, MadtIoApic, MadtLocalApic, MAX_CPUS};
+use shared::arg::{AcpiResetRegister, AcpiSettings, MadtInterruptSrcOverride, MadtIoApic, MadtLocalApic, MadtNmiSource, MadtTableLayout};
 use shared::print_panic::PrintPanic;
+use crate::mp_table::parse_mp_table;
 use crate::read_local_apic_base;
 
 
@@ -78,58 +84,204 @@ pub fn find_acpi_table_pointer(system_table: &SystemTable<Boot>) -> Option<(usiz
     None
 }
 
-pub fn parse_acpi_table(system_table: &SystemTable<Boot>, acpi_base: usize) -> AcpiSettings {
-    let handler = UefiAcpiHandler(system_table);
-    let acpi_table = unsafe { ::acpi::AcpiTables::from_rsdp(handler, acpi_base) }
-        .or_panic("failed to parse ACPI table from RSDP");
+/// interrupt controllers and cpus of this machine. acpi is preferred, the intel
+/// mp table stands in for a missing madt, and a machine with neither boots the
+/// bsp alone with legacy irqs through the 8259 pic.
+pub fn platform_settings(system_table: &SystemTable<Boot>) -> AcpiSettings {
+    let mut settings = match find_acpi_table_pointer(system_table) {
+        Some((acpi_base, _)) => parse_acpi_table(system_table, acpi_base),
+        None => {
+            warn!("ACPI is not supported on this machine.");
+            AcpiSettings::default()
+        }
+    };
 
-    let fadt = acpi_table.find_table::<Fadt>()
-        .or_panic("no FADT entry in ACPI table");
+    if !settings.has(AcpiSettings::MADT) {
+        match parse_mp_table(system_table) {
+            Some(mp) => settings = AcpiSettings {
+                reset_register: settings.reset_register,
+                flags: settings.flags | mp.flags,
+                ..mp
+            },
+            None => warn!("no MADT or MP table, booting the bsp only with PIC interrupts"),
+        }
+    }
 
-    if fadt.smi_cmd_port == 0 {
-        warn!("System Management Mode is not supported.");
-        return Default::default()
+    if has_local_apic() {
+        settings.local_apic_base = read_local_apic_base() as usize;
+        settings.flags |= AcpiSettings::LOCAL_APIC;
+    } else {
+        warn!("cpu has no local APIC");
     }
+    info!("platform flags: {:#x}", settings.flags);
+    settings
+}
 
-    let mut smi_serial = Port::new(fadt.smi_cmd_port as u16);
-    let mut pm1a_cb_serial: Port<u16> = Port::new(fadt.pm1a_control_block().unwrap().address as u16);
+fn has_local_apic() -> bool {
+    unsafe { __cpuid(1) }.edx & 1 << 9 != 0
+}
+
+/// pages holding `lapics` local apics, `io_apics` io apics, `overrides` source
+/// overrides and `nmi_sources` nmi sources for the kernel, see [`MadtTableLayout`]
+pub struct MadtTable {
+    pub addr: u64,
+    pub lapics: &'static mut [MadtLocalApic],
+    pub io_apics: &'static mut [MadtIoApic],
+    pub overrides: &'static mut [MadtInterruptSrcOverride],
+    pub nmi_sources: &'static mut [MadtNmiSource],
+}
+
+impl MadtTable {
+    /// move the arrays down to the layout of the first `lapics`, `io_apics`,
+    /// `overrides` and `nmi_sources` entries, returns the table address
+    pub fn shrink(self, lapics: usize, io_apics: usize, overrides: usize, nmi_sources: usize) -> u64 {
+        let layout = MadtTableLayout::new(lapics, io_apics, overrides, nmi_sources);
+        let io_apic_dst = (self.addr as usize + layout.io_apic_offset) as *mut MadtIoApic;
+        let override_dst = (self.addr as usize + layout.interrupt_src_override_offset) as *mut MadtInterruptSrcOverride;
+        let nmi_source_dst = (self.addr as usize + layout.nmi_source_offset) as *mut MadtNmiSource;
+        // every array only moves down, io apics never reach the overrides not moved yet
+        unsafe {
+            ptr::copy(self.io_apics.as_ptr(), io_apic_dst, io_apics.min(self.io_apics.len()));
+            ptr::copy(self.overrides.as_ptr(), override_dst, overrides.min(self.overrides.len()));
+            ptr::copy(self.nmi_sources.as_ptr(), nmi_source_dst, nmi_sources.min(self.nmi_sources.len()));
+        }
+        self.addr
+    }
+}
+
+pub fn alloc_madt_table(
+    system_table: &SystemTable<Boot>,
+    lapics: usize,
+    io_apics: usize,
+    overrides: usize,
+    nmi_sources: usize,
+) -> MadtTable {
+    let layout = MadtTableLayout::new(lapics, io_apics, overrides, nmi_sources);
+    // LOADER_DATA is kept after exit_boot_services, the kernel reads the table through identity map
+    let addr = system_table.boot_services()
+        .allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, layout.len.div_ceil(4096).max(1))
+        .or_panic("failed to allocate pages for madt table");
+    // SAFETY: bootloader runs identity mapped, the pages are allocated above
     unsafe {
-        smi_serial.write(fadt.acpi_enable);
-        // TODO: wait for 3 seconds which do as same as linux kernel
-        while pm1a_cb_serial.read() & 1 == 0 {
-            core::arch::asm!("hlt");
+        MadtTable {
+            addr,
+            lapics: slice::from_raw_parts_mut(addr as *mut MadtLocalApic, lapics),
+            io_apics: slice::from_raw_parts_mut((addr as usize + layout.io_apic_offset) as *mut MadtIoApic, io_apics),
+            overrides: slice::from_raw_parts_mut(
+                (addr as usize + layout.interrupt_src_override_offset) as *mut MadtInterruptSrcOverride,
+                overrides
+            ),
+            nmi_sources: slice::from_raw_parts_mut(
+                (addr as usize + layout.nmi_source_offset) as *mut MadtNmiSource,
+                nmi_sources
+            ),
+        }
+    }
+}
+
+fn parse_acpi_table(system_table: &SystemTable<Boot>, acpi_base: usize) -> AcpiSettings {
+    let handler = UefiAcpiHandler(system_table);
+    let acpi_table = match unsafe { ::acpi::AcpiTables::from_rsdp(handler, acpi_base) } {
+        Ok(acpi_table) => acpi_table,
+        Err(err) => {
+            warn!("failed to parse ACPI table from RSDP: {:?}", err);
+            return AcpiSettings::default();
+        }
+    };
+    let mut settings = AcpiSettings { flags: AcpiSettings::ACPI, ..Default::default() };
+
+    match acpi_table.find_table::<Fadt>() {
+        Ok(fadt) => {
+            settings.flags |= AcpiSettings::FADT;
+            settings.reset_register = fadt_reset_register(&fadt);
+            enable_acpi_mode(system_table, &fadt);
         }
+        Err(_) => warn!("no FADT entry in ACPI table, ACPI reset is unavailable"),
     }
 
-    let madt = acpi_table.find_table::<Madt>()
-        .or_panic("no MADT entry in ACPI table");
-    let madt_entries = madt.entries();
+    match acpi_table.find_table::<Madt>() {
+        Ok(madt) => {
+            parse_madt(system_table, &madt, &mut settings);
+            settings.flags |= AcpiSettings::MADT;
+        }
+        Err(_) => warn!("no MADT entry in ACPI table"),
+    }
+    settings
+}
+
+// how long firmware may take to hand sci over, as linux waits
+const ACPI_ENABLE_TIMEOUT_MS: usize = 3000;
+const PM1_CONTROL_SCI_EN: u16 = 1;
+
+fn enable_acpi_mode(system_table: &SystemTable<Boot>, fadt: &Fadt) {
+    let Ok(pm1a_control_block) = fadt.pm1a_control_block() else {
+        warn!("no PM1a control block in FADT, ACPI mode is not enabled");
+        return;
+    };
+    let mut pm1a_cb_serial: Port<u16> = Port::new(pm1a_control_block.address as u16);
+    // firmware of hardware reduced or already switched machines leaves nothing to do
+    if unsafe { pm1a_cb_serial.read() } & PM1_CONTROL_SCI_EN != 0 {
+        info!("ACPI mode is enabled already");
+        return;
+    }
+    if fadt.smi_cmd_port == 0 || fadt.acpi_enable == 0 {
+        warn!("System Management Mode is not supported.");
+        return;
+    }
 
-    let mut local_apic_base: Option<usize> = None;
+    let mut smi_serial = Port::new(fadt.smi_cmd_port as u16);
+    unsafe { smi_serial.write(fadt.acpi_enable) };
+    for _ in 0..ACPI_ENABLE_TIMEOUT_MS {
+        if unsafe { pm1a_cb_serial.read() } & PM1_CONTROL_SCI_EN != 0 {
+            info!("ACPI mode is enabled");
+            return;
+        }
+        system_table.boot_services().stall(1000);
+    }
+    warn!("firmware did not enable ACPI mode in {} ms, continuing without it", ACPI_ENABLE_TIMEOUT_MS);
+}
 
-    let mut lapics: [MadtLocalApic; MAX_CPUS] = [Default::default(); MAX_CPUS];
-    let mut lapic_count = 0;
-    let mut ioapics: [MadtIoApic; MAX_CPUS] = [Default::default(); MAX_CPUS];
-    let mut ioapics_count = 0;
-    let mut iso: [MadtInterruptSrcOverride; MAX_CPUS] = [Default::default(); MAX_CPUS];
-    let mut iso_count = 0;
+fn parse_madt(system_table: &SystemTable<Boot>, madt: &Madt, settings: &mut AcpiSettings) {
+    // sized by the table length, shrunk to the entries found afterwards
+    let body = (madt.header.length as usize).saturating_sub(size_of::<Madt>());
+    let table = alloc_madt_table(
+        system_table,
+        body / size_of::<LocalApicEntry>(),
+        body / size_of::<IoApicEntry>(),
+        body / size_of::<InterruptSourceOverrideEntry>(),
+        body / size_of::<NmiSourceEntry>()
+    );
+    let (mut lapic_count, mut ioapics_count, mut iso_count, mut nmi_count) = (0, 0, 0, 0);
 
-    for entry in madt_entries {
+    for entry in madt.entries() {
         match entry {
             MadtEntry::LocalApic(local_apic) => {
                 let flags = local_apic.flags;
                 if flags & 3 != 0 {
-                    lapics[lapic_count] = MadtLocalApic {
-                        id: local_apic.apic_id,
-                        processor_id: local_apic.processor_id
+                    table.lapics[lapic_count] = MadtLocalApic {
+                        id: local_apic.apic_id.into(),
+                        processor_id: local_apic.processor_id.into()
                     };
                     lapic_count += 1;
                 } else {
                     warn!("Local APIC cannot be enabled, flag: {flags}")
                 }
             },
+            // firmware lists cpus with apic id >= 255 here
+            MadtEntry::LocalX2Apic(local_x2apic) => {
+                let flags = local_x2apic.flags;
+                if flags & 3 != 0 {
+                    table.lapics[lapic_count] = MadtLocalApic {
+                        id: local_x2apic.x2apic_id,
+                        processor_id: local_x2apic.processor_uid
+                    };
+                    lapic_count += 1;
+                } else {
+                    warn!("Local x2APIC cannot be enabled, flag: {flags}")
+                }
+            },
             MadtEntry::IoApic(io_apic) => {
-                ioapics[ioapics_count] = MadtIoApic {
+                table.io_apics[ioapics_count] = MadtIoApic {
                     id: io_apic.io_apic_id,
                     address: io_apic.io_apic_address,
                     gsi_base: io_apic.global_system_interrupt_base
@@ -137,7 +289,7 @@ pub fn parse_acpi_table(system_table: &SystemTable<Boot>, acpi_base: usize) -> A
                 ioapics_count += 1;
             }
             MadtEntry::InterruptSourceOverride(iso_entry) => {
-                iso[iso_count] = MadtInterruptSrcOverride {
+                table.overrides[iso_count] = MadtInterruptSrcOverride {
                     bus_source: iso_entry.bus,
                     irq_source: iso_entry.irq,
                     gsi: iso_entry.global_system_interrupt,
@@ -145,21 +297,39 @@ pub fn parse_acpi_table(system_table: &SystemTable<Boot>, acpi_base: usize) -> A
                 };
                 iso_count += 1;
             }
+            MadtEntry::NmiSource(nmi_entry) => {
+                table.nmi_sources[nmi_count] = MadtNmiSource {
+                    gsi: nmi_entry.global_system_interrupt,
+                    flags: nmi_entry.flags
+                };
+                nmi_count += 1;
+            }
             _ => { }
         }
     }
+    info!("madt: {} local apics, {} io apics, {} overrides, {} nmi sources", lapic_count, ioapics_count, iso_count, nmi_count);
 
-    if lapic_count != 0 {
-        local_apic_base.replace(read_local_apic_base() as usize);
-    }
+    settings.madt_table_addr = table.shrink(lapic_count, ioapics_count, iso_count, nmi_count);
+    settings.local_apic_count = lapic_count;
+    settings.io_apic_count = ioapics_count;
+    settings.interrupt_src_override_count = iso_count;
+    settings.nmi_source_count = nmi_count;
+}
 
-    AcpiSettings {
-        local_apic_base: local_apic_base.unwrap_or(0),
-        local_apic: lapics.clone(),
-        local_apic_count: lapic_count,
-        io_apic: ioapics.clone(),
-        io_apic_count: ioapics_count,
-        interrupt_src_override: iso.clone(),
-        interrupt_src_override_count: iso_count
+fn fadt_reset_register(fadt: &Fadt) -> AcpiResetRegister {
+    // fadt is packed, copy out before calling methods
+    let flags = fadt.flags;
+    if !flags.supports_system_reset_via_fadt() {
+        return AcpiResetRegister::default();
     }
-}
\ No newline at end of file
+    let Ok(register) = fadt.reset_register() else {
+        return AcpiResetRegister::default();
+    };
+    let address_space = match register.address_space {
+        AddressSpace::SystemMemory => AcpiResetRegister::SYSTEM_MEMORY,
+        AddressSpace::SystemIo => AcpiResetRegister::SYSTEM_IO,
+        AddressSpace::PciConfigSpace => AcpiResetRegister::PCI_CONFIG,
+        _ => return AcpiResetRegister::default(),
+    };
+    AcpiResetRegister { address_space, address: register.address, value: fadt.reset_value }
+}
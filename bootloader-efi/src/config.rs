@@ -0,0 +1,185 @@
+use core::str;
+use log::LevelFilter;
+use shared::arg::{RestartPolicy, MAX_BOOT_MODULES};
+use shared::font::FontConfig;
+use uefi::table::{Boot, SystemTable};
+use crate::fs::load_file_sfs;
+
+pub const BOOT_CONFIG_FILE: &str = "boot.cfg";
+pub const MAX_CMDLINE_LEN: usize = shared::arg::MAX_CMDLINE_LEN;
+
+/// `boot.cfg` at the root of boot partition, one `key=value` per line, `#` starts a comment.
+///
+/// ```text
+/// kernel=kernel-x86_64
+/// bootstrap=bootstrap
+/// interp=ld.so
+/// module=console,restart=on-crash
+/// resume=hibernate.img
+/// resolution=1280x720
+/// font_size=auto
+/// font_weight=regular
+/// log_level=info
+/// serial=on
+/// cmdline=loglevel=debug
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct BootConfig {
+    pub kernel_path: &'static str,
+    pub bootstrap_path: &'static str,
+    // dynamic loader of bootstrap, only loaded if given
+    pub interp_path: Option<&'static str>,
+    // started by the kernel next to bootstrap, `module` may repeat up to MAX_BOOT_MODULES times,
+    // `restart` is never, on-crash or always
+    pub modules: [(&'static str, RestartPolicy); MAX_BOOT_MODULES],
+    pub modules_len: usize,
+    // hibernation image to resume from instead of starting bootstrap, booting goes on normally if it is missing
+    pub resume_path: Option<&'static str>,
+    // preferred graphics mode, the largest mode not larger than 1600x900 is chosen if absent
+    pub resolution: Option<(usize, usize)>,
+    // console font, `font_size` is auto, 16, 20 or 24, `font_weight` is regular or bold
+    pub font: FontConfig,
+    pub log_level: LevelFilter,
+    pub serial: bool,
+    pub cmdline: &'static str,
+}
+
+impl Default for BootConfig {
+    fn default() -> Self {
+        Self {
+            kernel_path: "kernel-x86_64",
+            bootstrap_path: "bootstrap",
+            interp_path: None,
+            modules: [("", RestartPolicy::Never); MAX_BOOT_MODULES],
+            modules_len: 0,
+            resume_path: None,
+            resolution: None,
+            font: FontConfig::default(),
+            log_level: LevelFilter::Debug,
+            serial: true,
+            cmdline: "",
+        }
+    }
+}
+
+impl BootConfig {
+    // unknown keys and malformed values are ignored and reported through `errors`.
+    pub fn parse(text: &'static str, mut errors: impl FnMut(&'static str)) -> Self {
+        let mut config = Self::default();
+
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue
+            }
+
+            let Some((key, value)) = line.split_once('=') else {
+                errors(line);
+                continue
+            };
+            let value = value.trim();
+
+            let ok = match key.trim() {
+                "kernel" => { config.kernel_path = value; true }
+                "bootstrap" => { config.bootstrap_path = value; true }
+                "interp" => { config.interp_path = Some(value); true }
+                "module" => match parse_module(value) {
+                    Some(module) if config.modules_len < MAX_BOOT_MODULES => {
+                        config.modules[config.modules_len] = module;
+                        config.modules_len += 1;
+                        true
+                    }
+                    _ => false
+                }
+                "resume" => { config.resume_path = Some(value); true }
+                "resolution" => match parse_resolution(value) {
+                    Some(res) => { config.resolution = Some(res); true }
+                    None => false
+                }
+                "font_size" => match FontConfig::parse_size(value) {
+                    Some(size) => { config.font.size = size; true }
+                    None => false
+                }
+                "font_weight" => match FontConfig::parse_weight(value) {
+                    Some(bold) => { config.font.bold = bold; true }
+                    None => false
+                }
+                "log_level" => match parse_log_level(value) {
+                    Some(level) => { config.log_level = level; true }
+                    None => false
+                }
+                "serial" => match value {
+                    "on" | "true" | "1" => { config.serial = true; true }
+                    "off" | "false" | "0" => { config.serial = false; true }
+                    _ => false
+                }
+                "cmdline" => {
+                    config.cmdline = value;
+                    value.len() <= MAX_CMDLINE_LEN
+                }
+                _ => false
+            };
+
+            if !ok {
+                errors(line);
+            }
+        }
+
+        config
+    }
+}
+
+fn parse_resolution(value: &str) -> Option<(usize, usize)> {
+    let (w, h) = value.split_once('x')?;
+    Some((w.trim().parse().ok()?, h.trim().parse().ok()?))
+}
+
+// `path` or `path,restart=<policy>`
+fn parse_module(value: &'static str) -> Option<(&'static str, RestartPolicy)> {
+    let (path, options) = value.split_once(',').unwrap_or((value, ""));
+    let path = path.trim();
+    if path.is_empty() {
+        return None
+    }
+    let restart = match options.trim() {
+        "" => RestartPolicy::Never,
+        option => RestartPolicy::parse(option.strip_prefix("restart=")?.trim())?
+    };
+    Some((path, restart))
+}
+
+fn parse_log_level(value: &str) -> Option<LevelFilter> {
+    Some(match value {
+        "off" => LevelFilter::Off,
+        "error" => LevelFilter::Error,
+        "warn" => LevelFilter::Warn,
+        "info" => LevelFilter::Info,
+        "debug" => LevelFilter::Debug,
+        "trace" => LevelFilter::Trace,
+        _ => return None
+    })
+}
+
+/// load `boot.cfg` from the file system where this image is loaded.
+/// returns default config if it doesn't exist, this runs before any logger is ready.
+pub fn load_boot_config(system_table: &SystemTable<Boot>) -> (BootConfig, usize) {
+    let boot_services = system_table.boot_services();
+    let Ok(mut sfs) = boot_services.get_image_file_system(boot_services.image_handle()) else {
+        return (BootConfig::default(), 0)
+    };
+    let Ok(mut root) = sfs.open_volume() else {
+        return (BootConfig::default(), 0)
+    };
+
+    let Some(bytes) = load_file_sfs(system_table, &mut root, BOOT_CONFIG_FILE) else {
+        return (BootConfig::default(), 0)
+    };
+    let bytes: &'static [u8] = bytes;
+    let Ok(text) = str::from_utf8(bytes) else {
+        return (BootConfig::default(), 1)
+    };
+
+    let mut error_count = 0;
+    let config = BootConfig::parse(text, |_| error_count += 1);
+    (config, error_count)
+}
@@ -1,3 +1,4 @@
 pub mod retrieve;
 pub mod partition;
+#[cfg(feature = "qemu-debug")]
 pub mod qemu;
\ No newline at end of file
@@ -1,8 +1,10 @@
 use uefi::{table::{SystemTable, Boot, boot::SearchType}, proto::console::gop::{GraphicsOutput, PixelFormat}, Identify};
-use shared::{framebuffer::{FBPixelFormat, Framebuffer}, print_panic::PrintPanic};
+use shared::{framebuffer::{FBPixelFormat, Framebuffer, PixelMask}, print_panic::PrintPanic};
 
 
-pub fn locate_framebuffer(system_table: &SystemTable<Boot>) -> Option<Framebuffer> {
+// `preferred` resolution is used if the mode exists,
+// otherwise choose the largest one not larger than 1600x900.
+pub fn locate_framebuffer(system_table: &SystemTable<Boot>, preferred: Option<(usize, usize)>) -> Option<Framebuffer> {
     let boot_services = system_table.boot_services();
 
     let graphics_output_handle_buffer = match boot_services
@@ -28,7 +30,11 @@ pub fn locate_framebuffer(system_table: &SystemTable<Boot>) -> Option<Framebuffe
         }
     };
 
-    let largest_resolution_mode = protocol
+    let preferred_mode = preferred.and_then(|res| protocol
+        .modes(boot_services)
+        .find(|mode| mode.info().resolution() == res));
+
+    let largest_resolution_mode = preferred_mode.or_else(|| protocol
         .modes(boot_services)
         .filter(|mode| {
             let (width, height) = mode.info().resolution();
@@ -39,7 +45,7 @@ pub fn locate_framebuffer(system_table: &SystemTable<Boot>) -> Option<Framebuffe
             let (b_width, b_height) = b.info().resolution();
 
             (a_width * a_height).cmp(&(b_width * b_height))
-        });
+        }));
         
     if let Some(mode) = largest_resolution_mode {
         protocol.set_mode(&mode)
@@ -47,18 +53,25 @@ pub fn locate_framebuffer(system_table: &SystemTable<Boot>) -> Option<Framebuffe
     }
 
     let current_info = protocol.current_mode_info();
+    let (pixel_format, pixel_mask) = match (current_info.pixel_format(), current_info.pixel_bitmask()) {
+        (PixelFormat::Rgb, _) => (FBPixelFormat::RGB, PixelMask::RGB),
+        (PixelFormat::Bgr, _) => (FBPixelFormat::BGR, PixelMask::BGR),
+        (PixelFormat::Bitmask, Some(mask)) => (FBPixelFormat::BITMASK, PixelMask {
+            red: mask.red, green: mask.green, blue: mask.blue, reserved: mask.reserved
+        }),
+        // blt only, no framebuffer to draw to
+        _ => return None
+    };
     let mut framebuffer = protocol.frame_buffer();
 
-    Some(Framebuffer::new(
+    let framebuffer = Framebuffer::new(
         framebuffer.as_mut_ptr(), 
         framebuffer.size(), 
         current_info.resolution().0, 
         current_info.resolution().1, 
         current_info.stride(), 
-        match current_info.pixel_format() {
-            PixelFormat::Rgb => FBPixelFormat::RGB,
-            PixelFormat::Bgr => FBPixelFormat::BGR,
-            others => return None
-        }
-    ))
+        pixel_format,
+        pixel_mask
+    );
+    framebuffer.drawable().then_some(framebuffer)
 }
\ No newline at end of file
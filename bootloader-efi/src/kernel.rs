@@ -5,13 +5,15 @@ use xmas_elf::{dynamic, header::{self, Type as EType}, program::{self, SegmentDa
 use core::{cmp, iter::Step, mem::size_of, ptr};
 
 use crate::mem::tracked_mapper::TrackedMapper;
-use shared::{arg::TlsTemplate, KERNEL_BYTES_P4, print_panic::PrintPanic};
+use shared::{arg::TlsTemplate, layout::KERNEL_BYTES_P4, print_panic::PrintPanic};
 
 pub struct LoadKernel {
     // kernel 实际虚拟地址入口
     pub kernel_entry: VirtAddr,
     // kernel 实际虚拟地址相比于定义虚拟地址的偏移
     pub kernel_virt_space_offset: i128,
+    // kernel 占用的虚拟地址空间大小
+    pub kernel_virt_space_size: u64,
     // thread local storage
     pub tls_template: Option<TlsTemplate>,
 }
@@ -289,7 +291,8 @@ pub fn load_kernel_to_virt_mem(
                 tls_template.replace(TlsTemplate {
                     start_virt_addr: seg_start_virt_addr.as_u64(),
                     mem_size: ph.mem_size() as usize,
-                    file_size: ph.file_size() as usize
+                    file_size: ph.file_size() as usize,
+                    align: ph.align() as usize
                 });
             }
             _ => {}
@@ -318,6 +321,7 @@ pub fn load_kernel_to_virt_mem(
     LoadKernel {
         kernel_entry: kernel_start_virt_addr + (kernel_elf.header.pt2.entry_point() - kernel_defined_start_virt_addr),
         kernel_virt_space_offset: i128::from(kernel_start_virt_addr.as_u64()) - i128::from(kernel_defined_start_virt_addr),
+        kernel_virt_space_size: kernel_virt_addr_space_size as u64,
         tls_template
     }
 
@@ -1,67 +1,73 @@
-use core::{mem::MaybeUninit, fmt::Write};
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicBool, Ordering};
+use log::Log;
 
 use lazy_static::lazy_static;
 use log::info;
-use shared::{framebuffer::Framebuffer, uni_processor::UPSafeCell};
-use spin::mutex::Mutex;
+use shared::{font::FontConfig, framebuffer::Framebuffer, logger::FramebufferLogger, uni_processor::UPSafeCell};
 use uefi::table::{SystemTable, Boot};
 
-use crate::logger::writer::FrameBufferWriter;
+pub mod serial;
 
-pub mod writer;
+static UEFI_STDOUT_LOGGER_INITIALIZED: AtomicBool = AtomicBool::new(false);
 
 lazy_static! {
     static ref FRAMEBUFFER_LOGGER: UPSafeCell<MaybeUninit<FramebufferLogger<'static>>> = unsafe { UPSafeCell::new(MaybeUninit::uninit()) };
-    static ref UEFI_STDOUT_LOGGER: UPSafeCell<Mutex<MaybeUninit<uefi::logger::Logger>>> = unsafe { UPSafeCell::new(Mutex::new(MaybeUninit::uninit())) };
+    static ref UEFI_STDOUT_LOGGER: UPSafeCell<MaybeUninit<UefiStdoutLogger>> = unsafe { UPSafeCell::new(MaybeUninit::uninit()) };
 }
 
-pub struct FramebufferLogger<'a> {
-    writter: Mutex<FrameBufferWriter<'a>>,
+pub fn init_framebuffer_logger(framebuffer: &'static Framebuffer, font: FontConfig) {
+    let mut logger = FRAMEBUFFER_LOGGER.inner_exclusive_mut();
+    logger.write(FramebufferLogger::new(framebuffer, font, Some(serial::write_serial)));
+
+    if let Err(err) = log::set_logger(unsafe { &*logger.as_ptr() }) {
+        info!("failed to set global logger: {}", err);
+    };
+    log::set_max_level(log::LevelFilter::Debug);
 }
 
-impl <'a> FramebufferLogger<'a> {
-    pub fn new(framebuffer: &'a Framebuffer) -> Self {
-        Self {
-            writter: Mutex::new(FrameBufferWriter::new(framebuffer))
-        }
-    }
+// uefi stdout is only available before exiting boot services, serial sink keeps working after that.
+pub struct UefiStdoutLogger {
+    stdout: uefi::logger::Logger,
 }
 
-impl log::Log for FramebufferLogger<'_> {
-    fn enabled(&self, _metadata: &log::Metadata) -> bool {
-        true
+impl log::Log for UefiStdoutLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.stdout.enabled(metadata)
     }
 
     fn log(&self, record: &log::Record) {
-        let mut fb_writter = self.writter.lock();
-        
-        let _ = writeln!(fb_writter, "{:5}: {}", record.level(), record.args());
+        self.stdout.log(record);
+        serial::write_serial(format_args!("{:5}: {}\n", record.level(), record.args()));
     }
 
     fn flush(&self) {
-        
+        self.stdout.flush()
     }
 }
 
-pub fn init_framebuffer_logger(framebuffer: &'static Framebuffer) {
-    let mut logger = FRAMEBUFFER_LOGGER.inner_exclusive_mut();
-    logger.write(FramebufferLogger::new(framebuffer));
-
-    if let Err(err) = log::set_logger(unsafe { &*logger.as_ptr() }) {
-        info!("failed to set global logger: {}", err);
-    };
-    log::set_max_level(log::LevelFilter::Debug);
-}
-
 pub fn init_uefi_services_logger(system_table: &mut SystemTable<Boot>) {
-    let logger_mutex: core::cell::RefMut<'_, Mutex<MaybeUninit<uefi::logger::Logger>>> = UEFI_STDOUT_LOGGER.inner_exclusive_mut();
-    let mut logger = logger_mutex.lock();
-
     let uefi_logger = uefi::logger::Logger::new();
     unsafe { uefi_logger.set_output(system_table.stdout()); };
 
-    let _ = log::set_logger(unsafe { &*(&uefi_logger as *const _) });
+    let mut logger = UEFI_STDOUT_LOGGER.inner_exclusive_mut();
+    let logger = logger.write(UefiStdoutLogger { stdout: uefi_logger });
+
+    let _ = log::set_logger(unsafe { &*(logger as *const UefiStdoutLogger) });
     log::set_max_level(log::LevelFilter::Debug);
+    UEFI_STDOUT_LOGGER_INITIALIZED.store(true, Ordering::SeqCst);
+}
+
+/// mirror log output to serial port, returns false if there is no serial device.
+pub fn init_serial_logger(system_table: &SystemTable<Boot>) -> bool {
+    serial::init_serial_sink(system_table.boot_services())
+}
 
-    logger.write(uefi_logger);
-}
\ No newline at end of file
+/// must be called right after exiting boot services.
+pub fn exit_boot_services() {
+    if UEFI_STDOUT_LOGGER_INITIALIZED.load(Ordering::SeqCst) {
+        let logger = UEFI_STDOUT_LOGGER.inner_exclusive_mut();
+        unsafe { logger.assume_init_ref() }.stdout.disable();
+    }
+    serial::switch_to_raw_serial();
+}
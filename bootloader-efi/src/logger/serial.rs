@@ -0,0 +1,138 @@
+use core::fmt;
+use core::mem;
+use spin::Mutex;
+use uefi::proto::console::serial::Serial;
+use uefi::table::boot::{BootServices, OpenProtocolAttributes, OpenProtocolParams};
+use x86_64::instructions::port::Port;
+
+const COM1: u16 = 0x3F8;
+
+static SERIAL_SINK: Mutex<SerialSink> = Mutex::new(SerialSink::Disabled);
+
+/// where serial log output goes.
+/// uefi serial protocol is used before exit_boot_services, then 16550 ports are driven directly.
+enum SerialSink {
+    Disabled,
+    Uefi(*mut Serial),
+    Raw(RawSerialPort),
+}
+
+// SAFETY: bootloader is single-threaded
+unsafe impl Send for SerialSink {}
+
+/// open uefi serial protocol as log sink.
+pub fn init_serial_sink(boot_services: &BootServices) -> bool {
+    let Ok(handle) = boot_services.get_handle_for_protocol::<Serial>() else {
+        return false
+    };
+
+    // open non-exclusively, console driver keeps using the same serial device
+    let protocol = unsafe {
+        boot_services.open_protocol::<Serial>(
+            OpenProtocolParams {
+                handle,
+                agent: boot_services.image_handle(),
+                controller: None,
+            },
+            OpenProtocolAttributes::GetProtocol
+        )
+    };
+
+    match protocol {
+        Ok(mut protocol) => {
+            let serial = &mut *protocol as *mut Serial;
+            // protocol must not be closed through boot services after exiting them
+            mem::forget(protocol);
+            *SERIAL_SINK.lock() = SerialSink::Uefi(serial);
+            true
+        }
+        Err(_) => false
+    }
+}
+
+/// boot services are gone, continue with raw 16550 port if serial was enabled.
+pub fn switch_to_raw_serial() {
+    let mut sink = SERIAL_SINK.lock();
+    if let SerialSink::Uefi(_) = *sink {
+        *sink = SerialSink::Raw(unsafe { RawSerialPort::init(COM1) });
+    }
+}
+
+pub fn write_serial(args: fmt::Arguments) {
+    use fmt::Write;
+
+    let mut sink = SERIAL_SINK.lock();
+    match *sink {
+        SerialSink::Disabled => {}
+        SerialSink::Uefi(serial) => {
+            let _ = UefiSerialWriter(unsafe { &mut *serial }).write_fmt(args);
+        }
+        SerialSink::Raw(ref mut port) => {
+            let _ = port.write_fmt(args);
+        }
+    }
+}
+
+struct UefiSerialWriter<'a>(&'a mut Serial);
+
+impl fmt::Write for UefiSerialWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for line in s.split_inclusive('\n') {
+            let (text, newline) = match line.strip_suffix('\n') {
+                Some(text) => (text, true),
+                None => (line, false)
+            };
+            self.0.write(text.as_bytes()).map_err(|_| fmt::Error)?;
+            if newline {
+                self.0.write(b"\r\n").map_err(|_| fmt::Error)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// minimal polling 16550 uart
+struct RawSerialPort {
+    base: u16,
+}
+
+impl RawSerialPort {
+    unsafe fn init(base: u16) -> Self {
+        // disable interrupts
+        Port::<u8>::new(base + 1).write(0x00);
+        // DLAB on, divisor 1 => 115200 baud
+        Port::<u8>::new(base + 3).write(0x80);
+        Port::<u8>::new(base).write(0x01);
+        Port::<u8>::new(base + 1).write(0x00);
+        // 8 bits, no parity, one stop bit
+        Port::<u8>::new(base + 3).write(0x03);
+        // enable and clear fifo, 14-byte threshold
+        Port::<u8>::new(base + 2).write(0xC7);
+        // DTR | RTS | OUT2
+        Port::<u8>::new(base + 4).write(0x0B);
+
+        Self { base }
+    }
+
+    fn send(&mut self, byte: u8) {
+        unsafe {
+            // wait for transmitter holding register empty
+            while Port::<u8>::new(self.base + 5).read() & 0x20 == 0 {
+                core::hint::spin_loop()
+            }
+            Port::<u8>::new(self.base).write(byte);
+        }
+    }
+}
+
+impl fmt::Write for RawSerialPort {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            if byte == b'\n' {
+                self.send(b'\r');
+            }
+            self.send(byte);
+        }
+        Ok(())
+    }
+}
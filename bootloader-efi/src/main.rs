@@ -3,18 +3,15 @@
 #![feature(step_trait)]
 #![feature(maybe_uninit_uninit_array)]
 
-use core::mem::MaybeUninit;
+use core::mem::{size_of, MaybeUninit};
 use core::ptr::{read_volatile, slice_from_raw_parts, write_volatile, NonNull};
-use core::slice;
-use ::acpi::fadt::Fadt;
-use ::acpi::madt::{Madt, MadtEntry};
-use ::acpi::{AcpiHandler, PhysicalMapping};
 use log::{info, warn, debug};
 use mem::page_allocator::boot::allocate_zeroed_page_aligned;
 use mem::RTMemoryRegionDescriptor;
-use shared::arg::{AcpiSettings, KernelArg, MemoryRegion, MemoryRegionKind, MAX_CPUS, MadtIoApic};
+use shared::arg::{AcpiSettings, BootModule, KernelArg, MemoryRegion, MemoryRegionKind, MAX_BOOT_MODULES, MAX_LOW_MEM_REGIONS, LOW_MEM_END, DEFAULT_BOOT_STACK_SIZE, DEFAULT_CONTEXT_STACK_SIZE, DEFAULT_AP_STACK_SIZE, STACK_FILL_PATTERN};
+use shared::layout::{BOOTSTRAP_BYTES_P4, FRAMEBUFFER_P4, KERNEL_ARG_P4, KERNEL_BYTES_P4, KERNEL_STACK_P4, PHYS_MEM_P4};
+use shared::boot_progress::{report_boot_stage, BootStage};
 use shared::framebuffer::Framebuffer;
-use uefi::proto::console::serial::Serial;
 use uefi::proto::media::partition::PartitionInfo;
 use uefi::table::{SystemTable, Boot};
 use uefi::table::boot::{MemoryDescriptor, MemoryMap, MemoryType};
@@ -29,18 +26,20 @@ use xmas_elf::symbol_table::Visibility::Default;
 use crate::context::context_switch;
 use crate::device::partition::find_current_boot_partition;
 use crate::device::retrieve::{list_handles, ProtocolWithHandle};
-use crate::acpi::{find_acpi_table_pointer, parse_acpi_table};
+use crate::acpi::platform_settings;
 use crate::fs::{open_sfs, load_file_sfs};
 use crate::kernel::load_kernel_to_virt_mem;
 use crate::mem::frame_allocator::LinearIncFrameAllocator;
 use crate::mem::page_allocator;
-use crate::mem::runtime_map::{alloc_and_map_kernel_stack, init_gdt, map_bootstrap, map_framebuffer, map_kernel_arg, map_physics_memory};
+use crate::mem::runtime_map::{init_gdt, seal_kernel_arg, Backing, MappingPlan};
 use shared::print_panic::PrintPanic;
 use crate::framebuffer::locate_framebuffer;
-use crate::logger::{init_framebuffer_logger, init_uefi_services_logger};
+use crate::config::{load_boot_config, BOOT_CONFIG_FILE, MAX_CMDLINE_LEN};
+use crate::logger::{init_framebuffer_logger, init_serial_logger, init_uefi_services_logger};
 
 mod panic;
 mod acpi;
+mod mp_table;
 mod fs;
 mod kernel;
 mod framebuffer;
@@ -48,6 +47,7 @@ mod logger;
 mod mem;
 mod device;
 mod context;
+mod config;
 
 #[entry]
 fn efi_main(image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status {
@@ -57,12 +57,15 @@ fn efi_main(image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status
         system_table.unsafe_clone()
     };
 
+    // boot.cfg decides resolution and logging, so load it before any logger is ready
+    let (boot_config, boot_config_errors) = load_boot_config(&st);
+
     // locate framebuffer and iniitialize framebuffer logger
-    let framebuffer: Option<Framebuffer> = match locate_framebuffer(&st) {
+    let framebuffer: Option<Framebuffer> = match locate_framebuffer(&st, boot_config.resolution) {
         Some(fb) => {
             // SAFETY: the framebuffer poniter points to the corresponding memory region
             // that is allocated by uefi
-            init_framebuffer_logger(unsafe { &*(&fb as *const _) });
+            init_framebuffer_logger(unsafe { &*(&fb as *const _) }, boot_config.font);
             info!("efi framebuffer logger is initialized.");
             Some(fb)
         },
@@ -72,12 +75,19 @@ fn efi_main(image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status
             None
         },
     };
+    if boot_config.serial && init_serial_logger(&st) {
+        info!("serial logger is initialized.");
+    }
+    log::set_max_level(boot_config.log_level);
+    if boot_config_errors > 0 {
+        warn!("ignored {} invalid lines in {}", boot_config_errors, BOOT_CONFIG_FILE);
+    }
     let boot_services = st.boot_services();
+    report_boot_stage(framebuffer.as_ref(), BootStage::Bootloader);
 
     // try to initialize acpi mode
-    let acpi_settings = find_acpi_table_pointer(&st)
-        .map(|(ptr, _)| parse_acpi_table(&st, ptr))
-        .or_panic("ACPI is not supported on this machine.");
+    report_boot_stage(framebuffer.as_ref(), BootStage::Acpi);
+    let acpi_settings = platform_settings(&st);
 
     // find partition of current loaded image.
     const PWH_UNINITIALIZED: MaybeUninit<ProtocolWithHandle<'_, PartitionInfo>> = MaybeUninit::<ProtocolWithHandle<PartitionInfo>>::uninit();
@@ -90,26 +100,46 @@ fn efi_main(image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status
     info!("current loaded image partition: {}", &*current_image_partition.device_path_string);
 
     // load kernel to memory
+    report_boot_stage(framebuffer.as_ref(), BootStage::LoadImages);
     let mut fs = open_sfs(boot_services, current_image_partition.handle)
         .or_panic("cannot open protocol SimpleFileSystem of efi image handle.")
         .open_volume()
         .or_panic("cannot open volumn of efi image filesystem");
 
 
-    let kernel = match load_file_sfs(&system_table, &mut fs, "kernel-x86_64") {
+    let kernel = match load_file_sfs(&system_table, &mut fs, boot_config.kernel_path) {
         Some(kernel_slice) => kernel_slice,
-        None => panic!("kernel is not found in current loaded image!")
+        None => panic!("kernel {} is not found in current loaded image!", boot_config.kernel_path)
     };
     info!("loaded kernel to physics address: 0x{:x}", &kernel[0] as *const _ as usize);
 
-    let bootstrap = match load_file_sfs(&system_table, &mut fs, "bootstrap") {
-        None => panic!("bootstrap is not found in current loaded image!"),
+    let bootstrap = match load_file_sfs(&system_table, &mut fs, boot_config.bootstrap_path) {
+        None => panic!("bootstrap {} is not found in current loaded image!", boot_config.bootstrap_path),
         Some(bootstrap_slice) => bootstrap_slice
     };
+    let interp = boot_config.interp_path.map(|path| match load_file_sfs(&system_table, &mut fs, path) {
+        None => panic!("interpreter {} is not found in current loaded image!", path),
+        Some(interp_slice) => &*interp_slice
+    });
+    let mut modules = [BootModule::EMPTY; MAX_BOOT_MODULES];
+    for (module, (path, restart)) in modules.iter_mut().zip(&boot_config.modules[..boot_config.modules_len]) {
+        match load_file_sfs(&system_table, &mut fs, path) {
+            None => panic!("module {} is not found in current loaded image!", path),
+            Some(module_slice) => *module = BootModule::new(module_slice, path, *restart)
+        }
+    }
+    let resume = boot_config.resume_path.and_then(|path| {
+        let image = load_file_sfs(&system_table, &mut fs, path).map(|image_slice| &*image_slice);
+        if image.is_none() {
+            warn!("hibernation image {} is not found, booting normally", path);
+        }
+        image
+    });
 
     debug!("exiting boot services");
     let (system_table, mut memory_map) = system_table.exit_boot_services(MemoryType::LOADER_DATA);
     allocator::exit_boot_services();
+    logger::exit_boot_services();
 
     // // boot service 现在已经退出，所以我们需要自己实现一个 GlobalAllocator
     // // 要把之前的东西，例如 kernel 指针，framebuffer 指针映射到 runtime 的 memory map 中、
@@ -117,6 +147,7 @@ fn efi_main(image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status
     // // 之后内核也是访问这片 memory map？？
 
     memory_map.sort();
+    report_boot_stage(framebuffer.as_ref(), BootStage::Memory);
 
     let mut frame_allocator = LinearIncFrameAllocator::new(memory_map.entries().copied());
 
@@ -131,14 +162,6 @@ fn efi_main(image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status
     let (mut kernel_page_table, kernel_pml4_table_phys_frame) = 
         page_allocator::runtime::create_page_table(&mut frame_allocator, VirtAddr::new(0));
 
-    // 映射帧分配器可用的地址空间（也就是物理内存地址空间）到内核页表
-    let mapped_phys_space_virt_addr = map_physics_memory(
-        frame_allocator.max_phys_addr(), 
-        &mut kernel_page_table, 
-        &mut frame_allocator
-    );
-    info!("kernel mapped all physics address space to virt addr: 0x{:x}", mapped_phys_space_virt_addr);
-
     unsafe {
         // Enable support for the no-execute bit in page tables.
         Efer::update(|efer| *efer |= EferFlags::NO_EXECUTE_ENABLE );
@@ -159,31 +182,37 @@ fn efi_main(image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status
     let load_kernel = load_kernel_to_virt_mem(kernel, &mut kernel_page_table, &mut frame_allocator);
     info!("kernel entry virt addr: 0x{:x}", load_kernel.kernel_entry.as_u64());
 
-    // map bootstrap
-    let bootstrap_virt_addr = map_bootstrap(bootstrap, &mut kernel_page_table, &mut frame_allocator);
-    info!("bootstrap virt addr: 0x{:x}, len = {}", bootstrap_virt_addr.as_u64(), bootstrap.len());
-
-    // map gdt, identical map
+    // gdt, identical map
     let kernel_gdt = init_gdt(&mut kernel_page_table, &mut frame_allocator);
-    info!("global descriptor table virt addr: 0x{:x}", kernel_gdt.start_address().as_u64());
+    info!("global descriptor table phys addr: 0x{:x}", kernel_gdt.start_address().as_u64());
 
-    // 创建内核栈并加载到内核 PML4 页表
-    let kernel_stack_size = 4096 * 128; // 128 KiB
-    let kernel_stack_virt_addr = alloc_and_map_kernel_stack(kernel_stack_size, &mut kernel_page_table, &mut frame_allocator);
-    info!("kernel stack virt addr: 0x{:x}", kernel_stack_virt_addr.as_u64());
-    let kernel_stack_top_virt_addr = (kernel_stack_virt_addr + kernel_stack_size).align_down(16u8).as_u64();
+    // 其余区域都有固定的 pml4 位置，先规划好虚拟地址，填完 KernelArg 后一次映射
+    let mut mapping_plan = MappingPlan::new();
+    mapping_plan.add("kernel image", KERNEL_BYTES_P4, load_kernel.kernel_virt_space_size, Backing::Premapped, PageTableFlags::PRESENT);
+
+    // 映射帧分配器可用的地址空间（也就是物理内存地址空间）到内核页表
+    let mapped_phys_space_virt_addr = mapping_plan.add(
+        "physical memory", PHYS_MEM_P4, frame_allocator.max_phys_addr().as_u64(),
+        Backing::PhysMem, PageTableFlags::PRESENT | PageTableFlags::WRITABLE
+    );
+    // kernel only and never executed, the kernel copies segments out of it into the init address space
+    let bootstrap_virt_addr = mapping_plan.add(
+        "bootstrap", BOOTSTRAP_BYTES_P4, bootstrap.len() as u64,
+        Backing::Frames(PhysAddr::new(&bootstrap[0] as *const _ as u64)), PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE
+    );
 
-    // map framebuffer to kernel virt addr
-    let framebuffer_virt_addr = framebuffer.map(|f| {
-        let framebuffer_virt_addr: VirtAddr = map_framebuffer(
-            unsafe { slice::from_raw_parts(f.ptr, f.len) }, 
-            &mut kernel_page_table,
-            &mut frame_allocator
-        );
+    // 内核栈，多出的一页用于填充检测溢出
+    let kernel_stack_size = DEFAULT_BOOT_STACK_SIZE;
+    let kernel_stack_virt_addr = mapping_plan.add(
+        "kernel stack", KERNEL_STACK_P4, kernel_stack_size as u64 + 4096,
+        Backing::Fresh(STACK_FILL_PATTERN), PageTableFlags::PRESENT | PageTableFlags::WRITABLE
+    ) + 4096u64;
+    let kernel_stack_top_virt_addr = (kernel_stack_virt_addr + kernel_stack_size).align_down(16u8).as_u64();
 
-        info!("kernel framebuffer virt addr: 0x{:x}", framebuffer_virt_addr.as_u64());
-        framebuffer_virt_addr
-    });
+    let framebuffer_virt_addr = framebuffer.map(|f| mapping_plan.add(
+        "framebuffer", FRAMEBUFFER_P4, f.len as u64, Backing::Frames(PhysAddr::new(f.ptr as u64)),
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE
+    ));
 
     let regions = construct_unsafe_phys_mem_region_map(
         &memory_map, 
@@ -191,14 +220,19 @@ fn efi_main(image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status
         &framebuffer, 
         &kernel,
         &bootstrap,
-        acpi_settings.local_apic_base as u64,
-        &acpi_settings.io_apic[..acpi_settings.io_apic_count],
+        interp,
+        &modules[..boot_config.modules_len],
+        resume,
+        &acpi_settings,
         kernel_gdt.start_address().as_u64(),
         kernel_pml4_table_phys_frame.start_address().as_u64(),
 
     );
+    let low_mem_regions = construct_low_mem_region_map(&memory_map);
     // 创建内核参数，把这些参数传给内核来让内核读取一些信息
-    let kernel_arg = KernelArg {
+    let mut kernel_arg = KernelArg {
+        // seal_kernel_arg 修改完后填写
+        header:                     KernelArg::HEADER,
         kernel_virt_space_offset:   load_kernel.kernel_virt_space_offset,
 
         gdt_start_addr:             kernel_gdt.start_address().as_u64(),
@@ -207,31 +241,48 @@ fn efi_main(image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status
 
         stack_top_addr:             (kernel_stack_virt_addr + kernel_stack_size).align_down(16u8).as_u64(),
         stack_size:                 kernel_stack_size,
+        context_stack_size:         DEFAULT_CONTEXT_STACK_SIZE,
+        ap_stack_size:              DEFAULT_AP_STACK_SIZE,
 
         framebuffer_addr:           framebuffer_virt_addr.unwrap_or(VirtAddr::new(0)).as_u64(),
         framebuffer_len:            framebuffer.map(|f| f.len).unwrap_or(0),
         framebuffer_width:          framebuffer.map(|f| f.width).unwrap_or(0),
         framebuffer_height:         framebuffer.map(|f| f.height).unwrap_or(0),
         framebuffer_stride:         framebuffer.map(|f| f.stride).unwrap_or(0),
+        framebuffer_pixel_format:   framebuffer.map(|f| f.pixel_format.bits()).unwrap_or(0),
+        framebuffer_pixel_mask:     framebuffer.map(|f| f.pixel_mask).unwrap_or_default(),
+        framebuffer_bytes_per_pixel: framebuffer.map(|f| f.bytes_per_pixel).unwrap_or(0),
+        framebuffer_font:           boot_config.font,
 
         phys_mem_mapped_addr:       mapped_phys_space_virt_addr.as_u64(),
         phys_mem_size:              frame_allocator.max_phys_addr().as_u64(),
         unav_phys_mem_regions:      unsafe { *(&regions.0 as *const _ as *const [MemoryRegion; 512]) },
         unav_phys_mem_regions_len:  regions.1,
+        low_mem_regions:            low_mem_regions.0,
+        low_mem_regions_len:        low_mem_regions.1,
 
         bootstrap_base:             bootstrap_virt_addr.as_u64(),
         bootstrap_len:              bootstrap.len(),
+        interp_base:                interp.map(|i| &i[0] as *const _ as u64).unwrap_or(0),
+        interp_len:                 interp.map(|i| i.len()).unwrap_or(0),
+        modules:                    modules,
+        modules_len:                boot_config.modules_len,
+        resume_base:                resume.map(|r| &r[0] as *const _ as u64).unwrap_or(0),
+        resume_len:                 resume.map(|r| r.len()).unwrap_or(0),
 
         tls_template:               load_kernel.tls_template.unwrap_or_default(),
+
+        cmdline:                    cmdline_bytes(boot_config.cmdline),
+        cmdline_len:                boot_config.cmdline.len().min(MAX_CMDLINE_LEN),
     };
-    
-    // TODO: 详见 map_kernel_arg 注解
-    // SAFETY: 详见 map_kernel_arg 注解
-    let kernel_arg_virt_addr: VirtAddr = map_kernel_arg(
-        &mut unsafe { *(&kernel_arg as *const _ as *mut KernelArg) },
-        &mut kernel_page_table,
-        &mut frame_allocator
+
+    let kernel_arg_virt_addr = mapping_plan.add(
+        "kernel arg", KERNEL_ARG_P4, size_of::<KernelArg>() as u64,
+        Backing::Frames(PhysAddr::new(&kernel_arg as *const _ as u64)), PageTableFlags::PRESENT
     );
+    mapping_plan.apply(&mut kernel_page_table, &mut frame_allocator);
+    // 映射时分配的页表帧也要记进去，所以最后才封存
+    seal_kernel_arg(&mut kernel_arg, &frame_allocator);
 
     info!("switching to kernel entry point virt addr: 0x{:x}, arg virt addr: 0x{:x}", load_kernel.kernel_entry, kernel_arg_virt_addr);
     unsafe {
@@ -246,6 +297,13 @@ fn efi_main(image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status
     }
 }
 
+fn cmdline_bytes(cmdline: &str) -> [u8; MAX_CMDLINE_LEN] {
+    let mut bytes = [0; MAX_CMDLINE_LEN];
+    let len = cmdline.len().min(MAX_CMDLINE_LEN);
+    bytes[..len].copy_from_slice(&cmdline.as_bytes()[..len]);
+    bytes
+}
+
 fn read_local_apic_base() -> u64 {
     const IA32_APIC_BASE_MSR: u32 = 0x1B;
     unsafe {
@@ -253,6 +311,28 @@ fn read_local_apic_base() -> u64 {
     }
 }
 
+/// 收集 1 MiB 以下退出 boot services 后可用的物理内存区域，内核从中分配 ap trampoline 等实模式代码。
+/// bootloader 的帧分配器从 1 MiB 开始分配，不会占用这些区域。
+fn construct_low_mem_region_map(memory_map: &MemoryMap) -> ([MemoryRegion; MAX_LOW_MEM_REGIONS], usize) {
+    let mut regions = [MemoryRegion::empty(); MAX_LOW_MEM_REGIONS];
+    let mut len = 0;
+
+    for rg in memory_map.entries().copied() {
+        if !rg.usable_after_bootloader_exit() || len == MAX_LOW_MEM_REGIONS {
+            continue;
+        }
+        // 跳过 0 号页
+        let start = rg.start().as_u64().max(0x1000);
+        let end = (rg.start().as_u64() + rg.len()).min(LOW_MEM_END);
+        if start < end {
+            regions[len] = MemoryRegion { start, length: end - start, kind: MemoryRegionKind::Usable };
+            len += 1;
+        }
+    }
+
+    (regions, len)
+}
+
 /// 构建内核无法使用的物理内存区域，这些内存区域存放了 bootloader 信息或者其他有用信息。
 /// 内核在分配物理帧时应该跳过这些区域。
 /// 
@@ -267,8 +347,10 @@ fn construct_unsafe_phys_mem_region_map<I: ExactSizeIterator<Item = MemoryDescri
     framebuffer: &Option<Framebuffer>,
     kernel_bytes: &[u8],
     bootstrap_bytes: &[u8],
-    lapic_base: u64,
-    io_apics: &[MadtIoApic],
+    interp_bytes: Option<&[u8]>,
+    modules: &[BootModule],
+    resume_bytes: Option<&[u8]>,
+    acpi: &AcpiSettings,
     gdt: u64,
     kernel_page_table: u64,
 ) -> ([MaybeUninit<MemoryRegion>; 512], usize) {
@@ -281,7 +363,7 @@ fn construct_unsafe_phys_mem_region_map<I: ExactSizeIterator<Item = MemoryDescri
             regions[curr_idx].write(MemoryRegion {
                 start: rg.start().as_u64(),
                 length: rg.page_count * 4096,
-                kind: MemoryRegionKind::Bootloader
+                kind: rg.kind()
             });
             curr_idx += 1;
         }
@@ -296,7 +378,7 @@ fn construct_unsafe_phys_mem_region_map<I: ExactSizeIterator<Item = MemoryDescri
         regions[curr_idx].write(MemoryRegion {
             start: framebuffer_start_phys_addr,
             length: framebuffer.len as u64,
-            kind: MemoryRegionKind::Bootloader
+            kind: MemoryRegionKind::Framebuffer
         });
         curr_idx += 1;
     });
@@ -306,7 +388,7 @@ fn construct_unsafe_phys_mem_region_map<I: ExactSizeIterator<Item = MemoryDescri
     regions[curr_idx].write(MemoryRegion {
         start: kernel_bytes_start_phys_addr,
         length: kernel_bytes.len() as u64,
-        kind: MemoryRegionKind::Bootloader
+        kind: MemoryRegionKind::KernelImage
     });
     curr_idx += 1;
 
@@ -314,25 +396,68 @@ fn construct_unsafe_phys_mem_region_map<I: ExactSizeIterator<Item = MemoryDescri
     let bootstrap_start_phys_addr = &bootstrap_bytes[0] as *const _ as u64;
     regions[curr_idx].write(MemoryRegion {
         start: bootstrap_start_phys_addr,
-        length: kernel_bytes.len() as u64,
-        kind: MemoryRegionKind::Bootloader
+        length: bootstrap_bytes.len() as u64,
+        kind: MemoryRegionKind::Bootstrap
     });
     curr_idx += 1;
 
-    // local apic
-    regions[curr_idx].write(MemoryRegion {
-        start: lapic_base,
-        length: Size4KiB::SIZE,
-        kind: MemoryRegionKind::Bootloader
+    // 动态链接器 elf，内核加载 bootstrap 时才读
+    interp_bytes.map(|interp_bytes| {
+        regions[curr_idx].write(MemoryRegion {
+            start: &interp_bytes[0] as *const _ as u64,
+            length: interp_bytes.len() as u64,
+            kind: MemoryRegionKind::Bootstrap
+        });
+        curr_idx += 1;
+    });
+
+    // 其他用户程序 elf
+    for module in modules {
+        regions[curr_idx].write(MemoryRegion {
+            start: module.base,
+            length: module.len as u64,
+            kind: MemoryRegionKind::Bootstrap
+        });
+        curr_idx += 1;
+    }
+
+    // 休眠镜像，内核恢复 context 时才读
+    resume_bytes.map(|resume_bytes| {
+        regions[curr_idx].write(MemoryRegion {
+            start: &resume_bytes[0] as *const _ as u64,
+            length: resume_bytes.len() as u64,
+            kind: MemoryRegionKind::Bootstrap
+        });
+        curr_idx += 1;
     });
-    curr_idx += 1;
+
+    // madt table handed to kernel
+    if acpi.madt_table_addr != 0 {
+        regions[curr_idx].write(MemoryRegion {
+            start: acpi.madt_table_addr,
+            length: (acpi.madt_table_layout().len as u64).div_ceil(Size4KiB::SIZE).max(1) * Size4KiB::SIZE,
+            kind: MemoryRegionKind::KernelArg
+        });
+        curr_idx += 1;
+    }
 
     // local apic
-    for &io_apic_base in io_apics {
+    if acpi.has(AcpiSettings::LOCAL_APIC) {
+        regions[curr_idx].write(MemoryRegion {
+            start: acpi.local_apic_phys(),
+            length: Size4KiB::SIZE,
+            kind: MemoryRegionKind::Mmio
+        });
+        curr_idx += 1;
+    }
+
+    // io apic
+    // SAFETY: bootloader runs identity mapped
+    for &io_apic_base in unsafe { acpi.io_apics(0) } {
         regions[curr_idx].write(MemoryRegion {
             start: io_apic_base.address as u64,
             length: Size4KiB::SIZE,
-            kind: MemoryRegionKind::Bootloader
+            kind: MemoryRegionKind::Mmio
         });
         curr_idx += 1;
     }
@@ -341,7 +466,7 @@ fn construct_unsafe_phys_mem_region_map<I: ExactSizeIterator<Item = MemoryDescri
     regions[curr_idx].write(MemoryRegion {
         start: gdt,
         length: Size4KiB::SIZE,
-        kind: MemoryRegionKind::Bootloader
+        kind: MemoryRegionKind::Gdt
     });
     curr_idx += 1;
 
@@ -349,7 +474,7 @@ fn construct_unsafe_phys_mem_region_map<I: ExactSizeIterator<Item = MemoryDescri
     regions[curr_idx].write(MemoryRegion {
         start: kernel_page_table,
         length: Size4KiB::SIZE,
-        kind: MemoryRegionKind::Bootloader
+        kind: MemoryRegionKind::PageTables
     });
     curr_idx += 1;
 
@@ -18,6 +18,11 @@ impl RTMemoryRegionDescriptor for MemoryDescriptor {
     fn kind(&self) -> MemoryRegionKind {
         match self.ty {
             MemoryType::CONVENTIONAL => MemoryRegionKind::Usable,
+            MemoryType::BOOT_SERVICES_CODE | MemoryType::BOOT_SERVICES_DATA => MemoryRegionKind::BootServices,
+            MemoryType::RUNTIME_SERVICES_CODE | MemoryType::RUNTIME_SERVICES_DATA => MemoryRegionKind::RuntimeServices,
+            MemoryType::ACPI_RECLAIM => MemoryRegionKind::AcpiReclaim,
+            MemoryType::ACPI_NON_VOLATILE => MemoryRegionKind::AcpiNvs,
+            MemoryType::MMIO | MemoryType::MMIO_PORT_SPACE => MemoryRegionKind::Mmio,
             other => MemoryRegionKind::UnknownUefi(other.0),
         }
     }
@@ -50,6 +50,7 @@ pub mod runtime {
 
     use crate::mem::tracked_mapper::TrackedMapper;
     use shared::print_panic::PrintPanic;
+    use shared::layout::KERNEL_RUNTIME_P4;
 
 
     /// map current level4 page table (boot stage) to runtime stage page table
@@ -96,6 +97,8 @@ pub mod runtime {
                 OffsetPageTable::new(&mut *page_table, phys_offset), 
                 PageTableLevel::Four
             );
+            // 这些位置留给内核运行时分配
+            KERNEL_RUNTIME_P4.for_each(|index| { mapper.mark_as_used(index as usize); });
 
             (mapper, frame)
         }
@@ -1,230 +1,248 @@
-use core::{mem::size_of, ptr, slice};
+use core::mem::size_of;
 
-use log::{debug, info};
+use log::info;
 use uefi::table::boot::MemoryDescriptor;
-use x86_64::{align_down, align_up, registers::segmentation::{Segment, CS, DS, ES, SS}, structures::{gdt::{Descriptor, GlobalDescriptorTable}, paging::{FrameAllocator, Mapper, OffsetPageTable, Page, PageTableIndex, PhysFrame, Size2MiB, Size4KiB}}, PhysAddr, VirtAddr};
+use x86_64::{registers::segmentation::{Segment, CS, DS, ES, SS}, structures::{gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector}, paging::{FrameAllocator, Mapper, OffsetPageTable, Page, PageSize, PhysFrame, Size1GiB, Size4KiB}}, PhysAddr, VirtAddr};
 use x86_64::structures::paging::page_table::PageTableFlags as PTFlags;
-use x86_64::structures::paging::{PageTableFlags, Size1GiB};
 
 use crate::mem::tracked_mapper::TrackedMapper;
-use shared::{arg::{KernelArg, MemoryRegion}, BOOTSTRAP_BYTES_P4, FRAMEBUFFER_P4, KERNEL_ARG_P4, KERNEL_BYTES_P4, KERNEL_STACK_P4, PHYS_MEM_P4, print_panic::PrintPanic};
+use shared::{arg::{KernelArg, MemoryRegion, MemoryRegionKind}, gdt::{gdt_index, GdtEntry, BOOT_GDT_ENTRIES, GDT_LAYOUT, KERNEL_CODE_SELECTOR, KERNEL_DATA_SELECTOR}, layout::{p4_base, PML4_ENTRY_SIZE}, print_panic::PrintPanic};
 
 use super::frame_allocator::LinearIncFrameAllocator;
 
-// map kernel stack
-pub fn alloc_and_map_kernel_stack(
-    stack_size: usize,
-    kernel_pml4_table: &mut TrackedMapper<OffsetPageTable>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>
-) -> VirtAddr {
-    // additional size for guarding
-    let total_size = stack_size + 4096;
-
-    let available_p4pti = kernel_pml4_table.find_free_space_and_mark(total_size, true)
-        .or_panic("failed to get available pml4 entry for kernel stack, maybe it run out");
-    assert_eq!(PageTableIndex::new(KERNEL_STACK_P4), available_p4pti.0);
-    let kernel_stack_start_page_1gb = Page::from_page_table_indices_1gib(available_p4pti.0,  PageTableIndex::new(0));
-
-    let kernel_stack_start_page = Page::<Size4KiB>::containing_address(kernel_stack_start_page_1gb.start_address());
-    let kernel_stack_end_page = Page::<Size4KiB>::containing_address(kernel_stack_start_page.start_address() + total_size - 1u64);
-
-    for page in Page::range_inclusive(kernel_stack_start_page, kernel_stack_end_page) {
-        let frame = frame_allocator
-            .allocate_frame()
-            .or_panic("failed to allocate new physics frame for kernel stack");
-
-        unsafe {
-            kernel_pml4_table
-                .map_to(page, frame, PTFlags::PRESENT | PTFlags::WRITABLE, frame_allocator)
-                .or_panic("failed to map new allocated physics frame to kernel stack page.")
-                .flush();
-        }
-    }
-
-    kernel_stack_start_page.start_address() + 4096u64
+/**
+ *  kernel pml4 entries the bootloader maps besides the kernel image.
+ *
+ *  every region owns the pml4 entry `shared::layout` gives it, so its virtual
+ *  address is known before anything is mapped and `KernelArg` can be filled
+ *  in first. `efi_main` adds the regions to a `MappingPlan`, `apply` checks
+ *  that none overlaps another or leaves its entry, maps them in one pass and
+ *  logs the resulting layout as a table. the kernel image is loaded by
+ *  `load_kernel_to_virt_mem` with its relocations and only listed here.
+ */
+
+/// what a planned region is mapped to
+#[derive(Debug, Clone, Copy)]
+pub enum Backing {
+    /// physical memory from 0 up to the length of the region, in 1 GiB pages
+    PhysMem,
+    /// fresh frames filled with the byte
+    Fresh(u8),
+    /// frames already holding the data, from this address on
+    Frames(PhysAddr),
+    /// mapped before the plan is applied
+    Premapped,
 }
 
-// create and map gdt
-pub fn init_gdt(
-    kernel_pml4_table: &mut TrackedMapper<OffsetPageTable>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>
-) -> PhysFrame {
-    let gdt_phys_frame = frame_allocator
-        .allocate_frame()
-        .or_panic("failed to allocate new physics frame for global descriptor table.");
-
-    // unsafe {
-    //     kernel_pml4_table.identity_map(
-    //         gdt_phys_frame,
-    //         PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
-    //         frame_allocator
-    //     )
-    //         .or_panic("failed to identity map kernel pml4 table")
-    //         .flush();
-    // }
-
-    // uefi boootloader runtime 阶段的虚拟地址和物理地址是无偏移映射的
-    let gdt = unsafe {
-        let ptr: *mut GlobalDescriptorTable = gdt_phys_frame.start_address().as_u64() as *mut GlobalDescriptorTable;
-        *ptr = GlobalDescriptorTable::new();
-        &mut *ptr
-    };
+#[derive(Debug, Clone, Copy)]
+struct Region {
+    name: &'static str,
+    start: VirtAddr,
+    len: u64,
+    backing: Backing,
+    flags: PTFlags,
+}
 
-    let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-    let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
-    gdt.load();
+impl Region {
+    const EMPTY: Region = Region { name: "", start: VirtAddr::zero(), len: 0, backing: Backing::Premapped, flags: PTFlags::empty() };
 
-    unsafe {
-        CS::set_reg(code_selector);
-        DS::set_reg(data_selector);
-        ES::set_reg(data_selector);
-        SS::set_reg(data_selector);
+    fn end(&self) -> VirtAddr {
+        self.start + self.len
     }
 
-    gdt_phys_frame
-}
-
-pub fn map_framebuffer(
-    framebuffer: &[u8],
-    kernel_pml4_table: &mut TrackedMapper<OffsetPageTable>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>
-) -> VirtAddr {
-    let available_p4pti = kernel_pml4_table.find_free_space_and_mark(framebuffer.len(), true)
-        .or_panic("failed to get available pml4 entry for framebuffer, maybe it run out");
-    assert_eq!(PageTableIndex::new(FRAMEBUFFER_P4), available_p4pti.0);
-    let framebuffer_start_page_1gb = Page::from_page_table_indices_1gib(available_p4pti.0,  PageTableIndex::new(0));
-
-    let framebuffer_start_page = Page::<Size4KiB>::containing_address(framebuffer_start_page_1gb.start_address());
-    let framebuffer_phys_addr = PhysAddr::new(&framebuffer[0] as *const _ as u64);
-
-    // bootloader runtime 阶段物理内存和虚拟内存是恒等映射
-    let framebuffer_start_phys_frame = PhysFrame::<Size4KiB>::containing_address(framebuffer_phys_addr);
-    let framebuffer_end_phys_frame = PhysFrame::<Size4KiB>::containing_address(framebuffer_phys_addr + framebuffer.len() - 1u64);
-
-    for (idx, frame) in PhysFrame::range_inclusive(framebuffer_start_phys_frame, framebuffer_end_phys_frame).enumerate() {
-        unsafe {
-            kernel_pml4_table
-                .map_to(
-                    framebuffer_start_page + idx as u64, 
-                    frame, 
-                    PTFlags::PRESENT | PTFlags::WRITABLE, 
-                    frame_allocator
-                )
-                .or_panic("failed to map new allocated physics frame to kernel stack page.")
-                .flush();
+    // bytes the first frame holds before the data
+    fn page_offset(&self) -> u64 {
+        match self.backing {
+            Backing::Frames(phys) => phys.as_u64() % Size4KiB::SIZE,
+            _ => 0,
         }
     }
+}
 
-    framebuffer_start_page.start_address() + (framebuffer_phys_addr - framebuffer_phys_addr.align_down(4096u64))
+const MAX_REGIONS: usize = 16;
+
+pub struct MappingPlan {
+    regions: [Region; MAX_REGIONS],
+    len: usize,
 }
 
-pub fn map_physics_memory(
-    max_phys_addr: PhysAddr,
-    kernel_pml4_table: &mut TrackedMapper<OffsetPageTable>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>
-) -> VirtAddr {
-    // bootloader runtime 阶段物理内存和虚拟内存是恒等映射
-    // 用 4kb size 会让下面迭代器迭代过多次
-    let start_phys_frame = PhysFrame::<Size1GiB>::containing_address(PhysAddr::new(0));
-    let end_phys_frame = PhysFrame::<Size1GiB>::containing_address(max_phys_addr - 1u64);
+impl MappingPlan {
+    pub const fn new() -> Self {
+        Self { regions: [Region::EMPTY; MAX_REGIONS], len: 0 }
+    }
 
-    info!("physics address space size: {}", max_phys_addr.as_u64());
+    /// plan `len` bytes of `backing` at the start of pml4 entry `p4`, returns the
+    /// virtual address the data will be at
+    pub fn add(&mut self, name: &'static str, p4: u16, len: u64, backing: Backing, flags: PTFlags) -> VirtAddr {
+        assert!(self.len < MAX_REGIONS, "mapping plan is full, cannot add {}", name);
+        let start = VirtAddr::new(p4_base(p4));
+        self.regions[self.len] = Region { name, start, len, backing, flags };
+        self.len += 1;
+        match backing {
+            // frames are mapped whole, the data keeps its offset in the first one
+            Backing::Frames(phys) => start + phys.as_u64() % Size4KiB::SIZE,
+            _ => start,
+        }
+    }
 
-    let available_p4pti = kernel_pml4_table.mark_as_unused(PHYS_MEM_P4 as usize);
-    let phys_start_page = Page::from_page_table_indices_1gib(PageTableIndex::new(PHYS_MEM_P4),  PageTableIndex::new(0));
+    /// check and map every planned region, then log the layout
+    pub fn apply(
+        &mut self,
+        kernel_pml4_table: &mut TrackedMapper<OffsetPageTable>,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>
+    ) {
+        let regions = &mut self.regions[..self.len];
+        regions.sort_unstable_by_key(|region| region.start);
+        for (prev, next) in regions.iter().zip(regions.iter().skip(1)) {
+            assert!(prev.end() <= next.start, "{} overlaps {} at 0x{:x}", prev.name, next.name, next.start);
+        }
 
-    for frame in PhysFrame::range_inclusive(start_phys_frame, end_phys_frame) {
-        let page = Page::<Size1GiB>::containing_address(phys_start_page.start_address() + frame.start_address().as_u64());
+        for region in regions.iter() {
+            let in_entry = region.start.as_u64() % PML4_ENTRY_SIZE + region.len + region.page_offset();
+            assert!(region.len > 0 && in_entry <= PML4_ENTRY_SIZE, "{} does not fit its pml4 entry, len = {}", region.name, region.len);
+            if matches!(region.backing, Backing::Premapped) {
+                continue;
+            }
+            kernel_pml4_table.mark_range_as_used(region.start..region.end())
+                .or_panic("pml4 entry of a planned region is already taken");
+            unsafe { map_region(region, kernel_pml4_table, frame_allocator) };
+        }
 
-        unsafe {
-            kernel_pml4_table.map_to(page, frame, PTFlags::PRESENT | PTFlags::WRITABLE, frame_allocator)
-                .or_panic("failed to map physics address space to kernel page.")
-                .ignore()
+        info!("kernel virtual memory layout:");
+        info!("  {:<16} {:<18}  {:<18}  {:>12}  {}", "region", "start", "end", "size", "backing");
+        for region in regions.iter() {
+            info!(
+                "  {:<16} 0x{:016x}  0x{:016x}  {:>8} KiB  {}{}",
+                region.name, region.start.as_u64(), region.end().as_u64(), region.len.div_ceil(1024),
+                region.backing, if region.flags.contains(PTFlags::WRITABLE) { ", writable" } else { "" }
+            );
         }
     }
-    // 这里不用把 frame 关联到 kernel pml4 页表
+}
 
-    phys_start_page.start_address()
+impl core::fmt::Display for Backing {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Backing::PhysMem => write!(f, "physical memory"),
+            Backing::Fresh(fill) => write!(f, "fresh frames, filled 0x{:02x}", fill),
+            Backing::Frames(phys) => write!(f, "frames at 0x{:x}", phys),
+            Backing::Premapped => write!(f, "loaded before"),
+        }
+    }
 }
 
-/// 映射 KernelArg 到内核 PML4 页表
-/// 
-/// kernel_arg 是可变的，因为在映射的过程中会分配物理帧，而这些新分配的也要写入到 KernelArg.unav_phys_mem_regions 中
-/// 
-/// TODO: 这样做非常不好，需要优化
-pub fn map_kernel_arg<I: ExactSizeIterator<Item = MemoryDescriptor> + Clone>(
-    kernel_arg: &mut KernelArg,
+unsafe fn map_region(
+    region: &Region,
     kernel_pml4_table: &mut TrackedMapper<OffsetPageTable>,
-    frame_allocator: &mut LinearIncFrameAllocator<I, MemoryDescriptor>
-) -> VirtAddr {
-    let kernel_arg_phys_addr = kernel_arg as *const _ as u64;
-    const KERNEL_ARG_LEN: u64 = size_of::<KernelArg>() as u64;
-
-    let available_p4pti = kernel_pml4_table.find_free_space_and_mark(KERNEL_ARG_LEN as usize, true)
-        .or_panic("failed to get available pml4 entry for KernelArg, maybe it run out");
-    assert_eq!(PageTableIndex::new(KERNEL_ARG_P4), available_p4pti.0);
-    let kernel_arg_start_page = Page::containing_address(
-        Page::from_page_table_indices_1gib(available_p4pti.0, PageTableIndex::new(0)).start_address()
-    );
-
-    let kernel_arg_phys_frame_start = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(kernel_arg_phys_addr));
-    let kernel_arg_phys_frame_end = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(kernel_arg_phys_addr + KERNEL_ARG_LEN - 1));
-
-    for frame in PhysFrame::range_inclusive(kernel_arg_phys_frame_start, kernel_arg_phys_frame_end) {
-        let page = kernel_arg_start_page + (frame - kernel_arg_phys_frame_start);
-
-        unsafe {
-            kernel_pml4_table
-                .map_to(page, frame, PTFlags::PRESENT, frame_allocator)
-                .or_panic("failed to identity map new allocated kernel arg.")
-                .flush();
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>
+) {
+    let start_page = Page::<Size4KiB>::containing_address(region.start);
+    match region.backing {
+        Backing::PhysMem => {
+            // 用 4kb size 会让下面迭代器迭代过多次
+            let start_frame = PhysFrame::<Size1GiB>::containing_address(PhysAddr::new(0));
+            let end_frame = PhysFrame::<Size1GiB>::containing_address(PhysAddr::new(region.len - 1));
+            for frame in PhysFrame::range_inclusive(start_frame, end_frame) {
+                let page = Page::<Size1GiB>::containing_address(region.start + frame.start_address().as_u64());
+                kernel_pml4_table.map_to(page, frame, region.flags, frame_allocator)
+                    .or_panic("failed to map physics address space to kernel page.")
+                    .ignore();
+            }
+        }
+        Backing::Fresh(fill) => {
+            let end_page = Page::<Size4KiB>::containing_address(region.end() - 1u64);
+            for page in Page::range_inclusive(start_page, end_page) {
+                let frame = frame_allocator
+                    .allocate_frame()
+                    .or_panic("failed to allocate new physics frame for a planned region");
+                // runtime 阶段物理地址无偏移映射
+                core::ptr::write_bytes(frame.start_address().as_u64() as *mut u8, fill, Size4KiB::SIZE as usize);
+                kernel_pml4_table.map_to(page, frame, region.flags, frame_allocator)
+                    .or_panic("failed to map new allocated physics frame to a planned region.")
+                    .flush();
+            }
         }
+        Backing::Frames(phys) => {
+            // gop 给出的是完整 64 位地址，高于 4 GiB 的 BAR 也一样映射
+            let start_frame = PhysFrame::<Size4KiB>::containing_address(phys);
+            let end_frame = PhysFrame::<Size4KiB>::containing_address(phys + region.len - 1u64);
+            for frame in PhysFrame::range_inclusive(start_frame, end_frame) {
+                kernel_pml4_table.map_to(start_page + (frame - start_frame), frame, region.flags, frame_allocator)
+                    .or_panic("failed to map existing frames to a planned region.")
+                    .flush();
+            }
+        }
+        Backing::Premapped => {}
     }
+}
 
+/// record the memory of `kernel_arg` and every frame the bootloader allocated
+/// as unavailable and seal it, after the plan is applied
+pub fn seal_kernel_arg<I: ExactSizeIterator<Item = MemoryDescriptor> + Clone>(
+    kernel_arg: &mut KernelArg,
+    frame_allocator: &LinearIncFrameAllocator<I, MemoryDescriptor>
+) {
     // kernel arg 的物理内存区域也不可用
     kernel_arg.unav_phys_mem_regions[kernel_arg.unav_phys_mem_regions_len] = MemoryRegion {
         start: kernel_arg as *const _ as u64,
-        length: KERNEL_ARG_LEN,
-        kind: shared::arg::MemoryRegionKind::Bootloader
+        length: size_of::<KernelArg>() as u64,
+        kind: MemoryRegionKind::KernelArg
     };
     kernel_arg.unav_phys_mem_regions_len += 1;
 
-    // 上面使用 FrameAllocator.allocate_frame 了，需要再记录一下
+    // 映射时使用了 FrameAllocator.allocate_frame，需要再记录一下
     kernel_arg.unav_phys_mem_regions[kernel_arg.unav_phys_mem_regions_len] = frame_allocator.allocated_region();
     kernel_arg.unav_phys_mem_regions_len += 1;
 
     // 按照 MemoryRegion.start 排序
     kernel_arg.unav_phys_mem_regions[..kernel_arg.unav_phys_mem_regions_len].sort_unstable_by_key(|r| r.start);
-
-    kernel_arg_start_page.start_address() + (kernel_arg_phys_addr - align_down(kernel_arg_phys_addr, 4096))
+    // 此后不再修改，内核入口先校验
+    kernel_arg.seal();
 }
 
-pub fn map_bootstrap(
-    bootstrap: &[u8],
+// create and map gdt
+pub fn init_gdt(
     kernel_pml4_table: &mut TrackedMapper<OffsetPageTable>,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>
-) -> VirtAddr {
-    let available_p4pti = kernel_pml4_table.find_free_space_and_mark(bootstrap.len(), true)
-        .or_panic("failed to get available pml4 entry for KernelArg, maybe it run out");
-    assert_eq!(PageTableIndex::new(BOOTSTRAP_BYTES_P4), available_p4pti.0);
-    let kernel_arg_start_page = Page::containing_address(
-        Page::from_page_table_indices_1gib(available_p4pti.0, PageTableIndex::new(0)).start_address()
-    );
-
-    let bs_phys_addr_start = PhysAddr::new(&bootstrap[0] as *const _ as u64);
-    let bs_phys_frame_start = PhysFrame::containing_address(bs_phys_addr_start);
-    let bs_phys_frame_end = PhysFrame::containing_address(bs_phys_addr_start + bootstrap.len());
-
-    for frame in PhysFrame::<Size4KiB>::range_inclusive(bs_phys_frame_start, bs_phys_frame_end) {
-        let page: Page<Size4KiB> = kernel_arg_start_page + (frame - bs_phys_frame_start);
-
-        unsafe {
-            kernel_pml4_table
-                .map_to(page, frame, PTFlags::PRESENT, frame_allocator)
-                .or_panic("failed to identity map new allocated bootstrap.")
-                .flush();
-        }
+) -> PhysFrame {
+    let gdt_phys_frame = frame_allocator
+        .allocate_frame()
+        .or_panic("failed to allocate new physics frame for global descriptor table.");
+
+    // unsafe {
+    //     kernel_pml4_table.identity_map(
+    //         gdt_phys_frame,
+    //         PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+    //         frame_allocator
+    //     )
+    //         .or_panic("failed to identity map kernel pml4 table")
+    //         .flush();
+    // }
+
+    // uefi boootloader runtime 阶段的虚拟地址和物理地址是无偏移映射的
+    let gdt = unsafe {
+        let ptr: *mut GlobalDescriptorTable = gdt_phys_frame.start_address().as_u64() as *mut GlobalDescriptorTable;
+        *ptr = GlobalDescriptorTable::new();
+        &mut *ptr
+    };
+
+    // 只建 shared::gdt 布局的前几项，内核入口在这些选择子上运行
+    for entry in GDT_LAYOUT[..BOOT_GDT_ENTRIES].iter().copied() {
+        let descriptor = match entry {
+            GdtEntry::KernelCode => Descriptor::kernel_code_segment(),
+            GdtEntry::KernelData => Descriptor::kernel_data_segment(),
+            _ => unreachable!("bootloader gdt only holds kernel segments"),
+        };
+        assert_eq!(gdt.add_entry(descriptor).index(), gdt_index(entry), "gdt entry {:?} is out of place", entry);
     }
+    gdt.load();
 
-    kernel_arg_start_page.start_address() + (bs_phys_addr_start.as_u64() - bs_phys_frame_start.start_address().as_u64())
-}
\ No newline at end of file
+    unsafe {
+        CS::set_reg(SegmentSelector(KERNEL_CODE_SELECTOR));
+        DS::set_reg(SegmentSelector(KERNEL_DATA_SELECTOR));
+        ES::set_reg(SegmentSelector(KERNEL_DATA_SELECTOR));
+        SS::set_reg(SegmentSelector(KERNEL_DATA_SELECTOR));
+    }
+
+    gdt_phys_frame
+}
@@ -0,0 +1,253 @@
+use core::mem::size_of;
+use core::ptr::{read_unaligned, read_volatile, write_volatile};
+use core::slice;
+use log::{info, warn};
+use uefi::{guid, Guid};
+use uefi::table::{Boot, SystemTable};
+use shared::arg::{AcpiSettings, MadtInterruptSrcOverride, MadtIoApic, MadtLocalApic, MadtNmiSource};
+use crate::acpi::alloc_madt_table;
+
+/**
+ *  intel multiprocessor specification table, for firmware without a madt.
+ *
+ *  the floating pointer is taken from the uefi configuration table, or found
+ *  the legacy way in the first KiB of the ebda, the last KiB of base memory
+ *  and the bios rom. its configuration table lists the same things madt does:
+ *  processors, io apics and how isa irqs are wired to io apic pins. the
+ *  wiring is turned into interrupt source overrides where it is not identity.
+ *
+ *  io apics carry no gsi base here, they are numbered in table order by the
+ *  redirection entries each one reports.
+ */
+
+const MPS_GUID: Guid = guid!("eb9d2d2f-2d88-11d3-9a16-0090273fc14d");
+
+const FLOATING_SIGNATURE: [u8; 4] = *b"_MP_";
+const CONFIG_SIGNATURE: [u8; 4] = *b"PCMP";
+
+const ENTRY_PROCESSOR: u8 = 0;
+const ENTRY_BUS: u8 = 1;
+const ENTRY_IO_APIC: u8 = 2;
+const ENTRY_IO_INTERRUPT: u8 = 3;
+const ENTRY_LOCAL_INTERRUPT: u8 = 4;
+
+const PROCESSOR_ENABLED: u8 = 1 << 0;
+const IO_APIC_ENABLED: u8 = 1 << 0;
+// vectored interrupt, not nmi, smi or extint
+const INTERRUPT_INT: u8 = 0;
+const INTERRUPT_NMI: u8 = 1;
+
+#[repr(C, packed)]
+struct FloatingPointer {
+    signature: [u8; 4],
+    config_table: u32,
+    length: u8,
+    spec_rev: u8,
+    checksum: u8,
+    // non-zero selects one of the default configurations without a table
+    default_config: u8,
+    features: [u8; 4],
+}
+
+#[repr(C, packed)]
+struct ConfigHeader {
+    signature: [u8; 4],
+    base_length: u16,
+    spec_rev: u8,
+    checksum: u8,
+    oem_id: [u8; 8],
+    product_id: [u8; 12],
+    oem_table: u32,
+    oem_table_size: u16,
+    entry_count: u16,
+    local_apic_address: u32,
+    ext_length: u16,
+    ext_checksum: u8,
+    reserved: u8,
+}
+
+#[repr(C, packed)]
+struct ProcessorEntry {
+    kind: u8,
+    lapic_id: u8,
+    lapic_version: u8,
+    flags: u8,
+    signature: u32,
+    features: u32,
+    reserved: u64,
+}
+
+#[repr(C, packed)]
+struct IoApicEntry {
+    kind: u8,
+    id: u8,
+    version: u8,
+    flags: u8,
+    address: u32,
+}
+
+#[repr(C, packed)]
+struct IoInterruptEntry {
+    kind: u8,
+    interrupt_type: u8,
+    // polarity and trigger mode, encoded as madt source override flags
+    flags: u16,
+    bus_id: u8,
+    bus_irq: u8,
+    io_apic_id: u8,
+    io_apic_pin: u8,
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+// SAFETY: bootloader runs identity mapped, low memory and the bios rom are readable
+unsafe fn scan(start: u64, len: u64) -> Option<u64> {
+    (start..start + len).step_by(16).find(|&addr| {
+        let bytes = slice::from_raw_parts(addr as *const u8, size_of::<FloatingPointer>());
+        bytes[..4] == FLOATING_SIGNATURE && checksum_ok(bytes)
+    })
+}
+
+fn find_floating_pointer(system_table: &SystemTable<Boot>) -> Option<u64> {
+    if let Some(cfg) = system_table.config_table().iter().find(|cfg| cfg.guid == MPS_GUID) {
+        return Some(cfg.address as u64);
+    }
+    unsafe {
+        let ebda = (read_unaligned(0x40e as *const u16) as u64) << 4;
+        let base_mem_kib = read_unaligned(0x413 as *const u16) as u64;
+        (ebda != 0).then(|| scan(ebda, 1024)).flatten()
+            .or_else(|| (base_mem_kib != 0).then(|| scan(base_mem_kib * 1024 - 1024, 1024)).flatten())
+            .or_else(|| scan(0xf0000, 0x10000))
+    }
+}
+
+// redirection entries of the io apic at `address`
+unsafe fn io_apic_pins(address: u32) -> u32 {
+    write_volatile(address as *mut u32, 1);
+    (read_volatile((address + 0x10) as *const u32) >> 16 & 0xff) + 1
+}
+
+/// cpus and io apics from the mp table, `None` if there is no usable one
+pub fn parse_mp_table(system_table: &SystemTable<Boot>) -> Option<AcpiSettings> {
+    let pointer = find_floating_pointer(system_table)?;
+    let pointer = unsafe { &*(pointer as *const FloatingPointer) };
+    if pointer.signature != FLOATING_SIGNATURE {
+        warn!("mp table: invalid floating pointer");
+        return None;
+    }
+    if pointer.default_config != 0 || pointer.config_table == 0 {
+        warn!("mp table: default configuration {} is not supported", pointer.default_config);
+        return None;
+    }
+    let header = unsafe { &*(pointer.config_table as u64 as *const ConfigHeader) };
+    let base_length = header.base_length as usize;
+    let table = unsafe { slice::from_raw_parts(header as *const _ as *const u8, base_length) };
+    if header.signature != CONFIG_SIGNATURE || !checksum_ok(table) {
+        warn!("mp table: invalid configuration table at {:#x}", pointer.config_table);
+        return None;
+    }
+
+    // entries have a fixed size per kind
+    let entries = || {
+        let mut offset = size_of::<ConfigHeader>();
+        (0..header.entry_count).map_while(move |_| {
+            let kind = *table.get(offset)?;
+            let len = match kind {
+                ENTRY_PROCESSOR => size_of::<ProcessorEntry>(),
+                ENTRY_BUS | ENTRY_IO_APIC | ENTRY_IO_INTERRUPT | ENTRY_LOCAL_INTERRUPT => 8,
+                _ => return None,
+            };
+            let entry = table.get(offset..offset + len)?;
+            offset += len;
+            Some((kind, entry.as_ptr()))
+        })
+    };
+
+    let mut isa_buses = [false; 256];
+    let (mut lapic_count, mut ioapics_count, mut iso_count, mut nmi_count) = (0, 0, 0, 0);
+    for (kind, entry) in entries() {
+        match kind {
+            ENTRY_PROCESSOR if unsafe { (*(entry as *const ProcessorEntry)).flags } & PROCESSOR_ENABLED != 0 => lapic_count += 1,
+            ENTRY_BUS => {
+                let bus = unsafe { slice::from_raw_parts(entry, 8) };
+                isa_buses[bus[1] as usize] = bus[2..8].starts_with(b"ISA");
+            }
+            ENTRY_IO_APIC if unsafe { (*(entry as *const IoApicEntry)).flags } & IO_APIC_ENABLED != 0 => ioapics_count += 1,
+            ENTRY_IO_INTERRUPT if unsafe { (*(entry as *const IoInterruptEntry)).interrupt_type } == INTERRUPT_NMI => nmi_count += 1,
+            // upper bound, identity wired irqs are left out below
+            ENTRY_IO_INTERRUPT => iso_count += 1,
+            _ => {}
+        }
+    }
+
+    let madt = alloc_madt_table(system_table, lapic_count, ioapics_count, iso_count, nmi_count);
+    let (mut lapic_count, mut ioapics_count, mut iso_count, mut nmi_count) = (0, 0, 0, 0);
+    let mut gsi_base = 0;
+    for (kind, entry) in entries() {
+        match kind {
+            ENTRY_PROCESSOR => {
+                let processor = unsafe { &*(entry as *const ProcessorEntry) };
+                if processor.flags & PROCESSOR_ENABLED != 0 {
+                    madt.lapics[lapic_count] = MadtLocalApic {
+                        id: processor.lapic_id.into(),
+                        processor_id: lapic_count as u32,
+                    };
+                    lapic_count += 1;
+                }
+            }
+            ENTRY_IO_APIC => {
+                let io_apic = unsafe { &*(entry as *const IoApicEntry) };
+                if io_apic.flags & IO_APIC_ENABLED != 0 {
+                    madt.io_apics[ioapics_count] = MadtIoApic { id: io_apic.id, address: io_apic.address, gsi_base };
+                    gsi_base += unsafe { io_apic_pins(io_apic.address) };
+                    ioapics_count += 1;
+                }
+            }
+            _ => {}
+        }
+    }
+    // io apics are all known now, their gsi bases too
+    for (kind, entry) in entries() {
+        if kind != ENTRY_IO_INTERRUPT {
+            continue;
+        }
+        let interrupt = unsafe { &*(entry as *const IoInterruptEntry) };
+        let Some(io_apic) = madt.io_apics[..ioapics_count].iter().find(|io_apic| io_apic.id == interrupt.io_apic_id) else {
+            continue;
+        };
+        let gsi = io_apic.gsi_base + interrupt.io_apic_pin as u32;
+        let flags = interrupt.flags;
+        if interrupt.interrupt_type == INTERRUPT_NMI {
+            madt.nmi_sources[nmi_count] = MadtNmiSource { gsi, flags };
+            nmi_count += 1;
+            continue;
+        }
+        if interrupt.interrupt_type != INTERRUPT_INT || !isa_buses[interrupt.bus_id as usize] {
+            continue;
+        }
+        if gsi == interrupt.bus_irq as u32 && flags == 0 {
+            continue;
+        }
+        madt.overrides[iso_count] = MadtInterruptSrcOverride {
+            bus_source: 0,
+            irq_source: interrupt.bus_irq,
+            gsi,
+            flags,
+        };
+        iso_count += 1;
+    }
+    info!("mp table: {} local apics, {} io apics, {} overrides, {} nmi sources", lapic_count, ioapics_count, iso_count, nmi_count);
+
+    Some(AcpiSettings {
+        // identity wired irqs were left out, the nmi sources move down behind the overrides
+        madt_table_addr: madt.shrink(lapic_count, ioapics_count, iso_count, nmi_count),
+        local_apic_count: lapic_count,
+        io_apic_count: ioapics_count,
+        interrupt_src_override_count: iso_count,
+        nmi_source_count: nmi_count,
+        flags: AcpiSettings::MP_TABLE,
+        ..Default::default()
+    })
+}
@@ -0,0 +1,87 @@
+use std::io::{Error, ErrorKind, Result};
+use shared::arg::MAX_CMDLINE_LEN;
+
+pub const FILE_BOOT_CFG: &str = "boot.cfg";
+
+/// content of `boot.cfg` read by bootloader, see `bootloader-efi/src/config.rs`.
+#[derive(Debug, Clone, Default)]
+pub struct BootCfg {
+    pub kernel: Option<String>,
+    pub bootstrap: Option<String>,
+    pub resolution: Option<String>,
+    pub font_size: Option<String>,
+    pub font_weight: Option<String>,
+    pub log_level: Option<String>,
+    pub serial: Option<bool>,
+    pub cmdline: Option<String>,
+    pub resume: Option<String>,
+}
+
+impl BootCfg {
+    /// set value by the flag name without leading `--`.
+    pub fn set(&mut self, flag: &str, value: &str) -> Result<()> {
+        let invalid = |msg: &str| Error::new(ErrorKind::InvalidInput, format!("--{flag}: {msg}"));
+
+        match flag {
+            "kernel-path" => self.kernel = Some(value.to_owned()),
+            "bootstrap-path" => self.bootstrap = Some(value.to_owned()),
+            "resolution" => {
+                let valid = value.split_once('x')
+                    .map(|(w, h)| w.parse::<usize>().is_ok() && h.parse::<usize>().is_ok())
+                    .unwrap_or(false);
+                if !valid {
+                    return Err(invalid("expected <width>x<height>"));
+                }
+                self.resolution = Some(value.to_owned())
+            }
+            "font-size" => {
+                if !["auto", "16", "20", "24"].contains(&value) {
+                    return Err(invalid("expected one of auto, 16, 20, 24"));
+                }
+                self.font_size = Some(value.to_owned())
+            }
+            "font-weight" => {
+                if !["regular", "bold"].contains(&value) {
+                    return Err(invalid("expected regular or bold"));
+                }
+                self.font_weight = Some(value.to_owned())
+            }
+            "log-level" => {
+                if !["off", "error", "warn", "info", "debug", "trace"].contains(&value) {
+                    return Err(invalid("expected one of off, error, warn, info, debug, trace"));
+                }
+                self.log_level = Some(value.to_owned())
+            }
+            "serial" => self.serial = Some(match value {
+                "on" | "true" | "1" => true,
+                "off" | "false" | "0" => false,
+                _ => return Err(invalid("expected on or off"))
+            }),
+            "cmdline" => {
+                if value.contains('\n') || value.len() > MAX_CMDLINE_LEN {
+                    return Err(invalid(&format!("must be a single line no longer than {MAX_CMDLINE_LEN} bytes")));
+                }
+                self.cmdline = Some(value.to_owned())
+            }
+            "resume" => self.resume = Some(value.to_owned()),
+            _ => return Err(Error::new(ErrorKind::InvalidInput, format!("unknown flag --{flag}")))
+        }
+        Ok(())
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::from("# generated by build-image\n");
+        let mut line = |key: &str, value: &str| out.push_str(&format!("{key}={value}\n"));
+
+        if let Some(v) = &self.kernel { line("kernel", v) }
+        if let Some(v) = &self.bootstrap { line("bootstrap", v) }
+        if let Some(v) = &self.resolution { line("resolution", v) }
+        if let Some(v) = &self.font_size { line("font_size", v) }
+        if let Some(v) = &self.font_weight { line("font_weight", v) }
+        if let Some(v) = &self.log_level { line("log_level", v) }
+        if let Some(v) = self.serial { line("serial", if v { "on" } else { "off" }) }
+        if let Some(v) = &self.cmdline { line("cmdline", v) }
+        if let Some(v) = &self.resume { line("resume", v) }
+        out
+    }
+}
@@ -0,0 +1,190 @@
+use std::{fs, io::{self, Result}, path::Path};
+
+// mirrors kernel/src/crashdump.rs, bump both together
+const KDUMP_VERSION: u32 = 1;
+const FRAME_PREFIX: &str = "KDUMP ";
+
+const KIND_BEGIN: u8 = 0x01;
+const KIND_CPU: u8 = 0x02;
+const KIND_MEMMAP: u8 = 0x03;
+const KIND_LOG: u8 = 0x04;
+const KIND_END: u8 = 0xff;
+
+const CPU_REG_NAMES: [&str; 24] = [
+    "rax", "rbx", "rcx", "rdx", "rsi", "rdi", "rbp", "rsp",
+    "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15",
+    "rip", "rflags", "cs", "ss", "cr0", "cr2", "cr3", "cr4",
+];
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+pub(crate) fn crc32(crc: u32, bytes: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { crc >> 1 ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+pub(crate) fn parse_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok()).collect()
+}
+
+// `KDUMP <kind> <payload> <crc>`, anything before the prefix is other serial output
+fn parse_frame(line: &str) -> Option<(u8, Vec<u8>, bool)> {
+    let frame = &line[line.find(FRAME_PREFIX)? + FRAME_PREFIX.len()..];
+    let mut fields = frame.split_ascii_whitespace();
+    let kind = u8::from_str_radix(fields.next()?, 16).ok()?;
+    // an empty payload leaves two fields only
+    let (payload, crc) = match (fields.next()?, fields.next()) {
+        (crc, None) => (Vec::new(), crc),
+        (payload, Some(crc)) => (parse_hex(payload)?, crc),
+    };
+    let crc = u32::from_str_radix(crc, 16).ok()?;
+    let valid = crc32(crc32(0, &[kind]), &payload) == crc;
+    Some((kind, payload, valid))
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> Option<&[u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (head, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(head)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.take(8)?.try_into().ok()?))
+    }
+}
+
+fn print_begin(payload: &[u8]) -> Option<()> {
+    let mut r = Reader(payload);
+    let version = r.u32()?;
+    if version != KDUMP_VERSION {
+        println!("warning: record version {version}, decoder knows {KDUMP_VERSION}");
+    }
+    let cpu = r.u32()?;
+    let cpu_count = r.u32()?;
+    let uptime = r.u64()?;
+    println!("kernel panic on cpu #{cpu} of {cpu_count}, uptime {}.{:09}s", uptime / 1_000_000_000, uptime % 1_000_000_000);
+    println!("  {}", String::from_utf8_lossy(r.0));
+    Some(())
+}
+
+fn print_cpu(payload: &[u8]) -> Option<()> {
+    let mut r = Reader(payload);
+    let cpu = r.u32()?;
+    let state = match r.u32()? {
+        0 => "panicking",
+        1 => "parked",
+        2 => "no response",
+        _ => "unknown",
+    };
+    println!("cpu #{cpu}: {state}");
+    if state == "no response" {
+        return Some(());
+    }
+    let regs = (0..CPU_REG_NAMES.len()).map(|_| r.u64()).collect::<Option<Vec<_>>>()?;
+    for (names, values) in CPU_REG_NAMES.chunks(4).zip(regs.chunks(4)) {
+        let line: Vec<String> = names.iter().zip(values).map(|(name, value)| format!("{name:>6}={value:016x}")).collect();
+        println!("  {}", line.join(" "));
+    }
+    Some(())
+}
+
+fn print_memmap(payload: &[u8]) -> Option<()> {
+    let mut r = Reader(payload);
+    while !r.0.is_empty() {
+        let start = r.u64()?;
+        let length = r.u64()?;
+        let name_len = r.take(1)?[0] as usize;
+        let name = String::from_utf8_lossy(r.take(name_len)?);
+        println!("  [{:#012x}-{:#012x}) {:>8} KiB {}", start, start + length, length / 1024, name);
+    }
+    Some(())
+}
+
+/// decode kernel crash records found in a captured serial log
+pub fn decode_crash_dump(log_path: &Path) -> Result<()> {
+    let content = fs::read(log_path)?;
+    let content = String::from_utf8_lossy(&content);
+
+    let mut records = 0;
+    let mut frames = 0;
+    let mut log = Vec::new();
+    let mut memmap_header = false;
+
+    for (line_no, line) in content.lines().enumerate() {
+        let Some((kind, payload, valid)) = parse_frame(line) else { continue };
+        if !valid {
+            println!("warning: line {}: frame {kind:02x} fails crc, skipped", line_no + 1);
+            continue;
+        }
+        let decoded = match kind {
+            KIND_BEGIN => {
+                records += 1;
+                frames = 0;
+                log.clear();
+                memmap_header = false;
+                println!("==== crash record {records} ====");
+                print_begin(&payload)
+            }
+            KIND_CPU => print_cpu(&payload),
+            KIND_MEMMAP => {
+                if !memmap_header {
+                    println!("unavailable memory regions:");
+                    memmap_header = true;
+                }
+                print_memmap(&payload)
+            }
+            // chunks may split utf-8 sequences, printed as a whole at the end
+            KIND_LOG => {
+                log.extend_from_slice(&payload);
+                Some(())
+            }
+            KIND_END => Reader(&payload).u32().map(|expected| {
+                println!("last log lines:");
+                for line in String::from_utf8_lossy(&log).lines() {
+                    println!("  {line}");
+                }
+                if expected == frames {
+                    println!("==== end, {frames} frames ====");
+                } else {
+                    println!("==== end, {frames} of {expected} frames received ====");
+                }
+            }),
+            _ => {
+                println!("warning: line {}: unknown frame kind {kind:02x}", line_no + 1);
+                Some(())
+            }
+        };
+        if kind != KIND_BEGIN && kind != KIND_END {
+            frames += 1;
+        }
+        if decoded.is_none() {
+            println!("warning: line {}: frame {kind:02x} is truncated", line_no + 1);
+        }
+    }
+
+    if records == 0 {
+        return Err(invalid(format!("no crash record in {}", log_path.display())));
+    }
+    Ok(())
+}
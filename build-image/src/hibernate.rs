@@ -0,0 +1,67 @@
+use std::{fs, io::{self, Result}, path::Path};
+use crate::crashdump::{crc32, parse_hex};
+
+// mirrors kernel/src/power/hibernate.rs
+const BEGIN_MARKER: &str = "hibernate: begin";
+const END_MARKER: &str = "hibernate: end";
+const IMAGE_MAGIC: &[u8; 8] = b"MHIBERN1";
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// `hibernate: end <bytes> bytes crc <crc>`
+fn parse_end(line: &str) -> Option<(usize, u32)> {
+    let fields: Vec<&str> = line[line.find(END_MARKER)? + END_MARKER.len()..].split_ascii_whitespace().collect();
+    match fields[..] {
+        [len, "bytes", "crc", crc] => Some((len.parse().ok()?, u32::from_str_radix(crc, 16).ok()?)),
+        _ => None,
+    }
+}
+
+/// write the last hibernation image in a captured serial log to `output`,
+/// the file goes to the boot partition and `resume=` in boot.cfg names it
+pub fn extract_hibernation_image(log_path: &Path, output: &Path) -> Result<()> {
+    let content = fs::read(log_path)?;
+    let content = String::from_utf8_lossy(&content);
+
+    // the last image in the log wins
+    let mut image: Option<Vec<u8>> = None;
+    let mut end = None;
+    for (line_no, line) in content.lines().enumerate() {
+        if line.contains(BEGIN_MARKER) {
+            image = Some(Vec::new());
+            end = None;
+            continue;
+        }
+        let Some(bytes) = image.as_mut().filter(|_| end.is_none()) else { continue };
+        if line.contains(END_MARKER) {
+            end = Some(parse_end(line).ok_or_else(|| invalid(format!("line {}: malformed end marker", line_no + 1)))?);
+            continue;
+        }
+        match parse_hex(line.trim()) {
+            Some(line_bytes) => bytes.extend_from_slice(&line_bytes),
+            None => return Err(invalid(format!("line {}: not a hex line inside the image", line_no + 1))),
+        }
+    }
+
+    let Some(image) = image else {
+        return Err(invalid(format!("no hibernation image in {}", log_path.display())));
+    };
+    let Some((len, crc)) = end else {
+        return Err(invalid("image is cut off before its end marker".to_string()));
+    };
+    if image.len() != len || crc32(0, &image) != crc {
+        return Err(invalid(format!(
+            "image is corrupted: {} bytes with crc {:08x}, end marker says {} bytes with crc {:08x}",
+            image.len(), crc32(0, &image), len, crc
+        )));
+    }
+    if !image.starts_with(IMAGE_MAGIC) {
+        return Err(invalid("image does not start with the hibernation magic".to_string()));
+    }
+
+    fs::write(output, &image)?;
+    println!("{} bytes written to {}, add it to the image and set --resume", image.len(), output.display());
+    Ok(())
+}
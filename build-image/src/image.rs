@@ -0,0 +1,162 @@
+use std::{io::{self, Result, Seek}, path::Path, fs::{self}};
+use sha2::{Digest, Sha256};
+use tempfile::NamedTempFile;
+use uuid::Uuid;
+
+pub const FILE_MANIFEST: &str = "MANIFEST";
+pub const MB: u64 = 1024 * 1024;
+
+// fixed identifiers so the same inputs always produce the same image
+const VOLUME_ID: u32 = 0x4153_4f53;
+const VOLUME_LABEL: [u8; 11] = *b"___ASOS____";
+const DISK_GUID: Uuid = Uuid::from_u128(0x6d696e69_6174_7572_6500_000000000001);
+const PARTITION_GUID: Uuid = Uuid::from_u128(0x6d696e69_6174_7572_6500_000000000002);
+
+/// a file placed in the image, either copied from host or generated
+pub enum ImageFile<'a> {
+    Host { dst: &'a str, src: &'a Path },
+    Generated { dst: &'a str, content: &'a [u8] },
+}
+
+impl ImageFile<'_> {
+    fn dst(&self) -> &str {
+        match self {
+            ImageFile::Host { dst, .. } => dst,
+            ImageFile::Generated { dst, .. } => dst,
+        }
+    }
+
+    fn read(&self) -> Result<Vec<u8>> {
+        match self {
+            ImageFile::Host { src, .. } => fs::read(src),
+            ImageFile::Generated { content, .. } => Ok(content.to_vec()),
+        }
+    }
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// `sha256  path` per line, in the order files are written
+pub fn render_manifest(entries: &[(String, String)]) -> String {
+    entries.iter().map(|(path, hash)| format!("{hash}  {path}\n")).collect()
+}
+
+pub fn parse_manifest(text: &str) -> Vec<(String, String)> {
+    text.lines()
+        .filter_map(|line| line.split_once("  "))
+        .map(|(hash, path)| (path.to_owned(), hash.to_owned()))
+        .collect()
+}
+
+/// build a fat filesystem image containing `files` and a manifest of their hashes.
+/// `size` is the minimal size of filesystem.
+pub fn construct_filesystem_fat(files: &[ImageFile], size: Option<u64>) -> Result<NamedTempFile> {
+    let out_file = NamedTempFile::new()?;
+    let out_file_path = out_file.path();
+
+    let contents = files.iter().map(|f| f.read()).collect::<Result<Vec<_>>>()?;
+
+    // calculate needed size
+    let needed_size: u64 = contents.iter().map(|c| c.len() as u64).sum();
+    let fat_size_padded_and_rounded = ((needed_size + 1024 * 64 - 1) / MB + 1) * MB + MB;
+    let fat_size = match size {
+        Some(size) if size < fat_size_padded_and_rounded => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("image size {size} is too small, at least {fat_size_padded_and_rounded} bytes are needed")
+            ))
+        }
+        Some(size) => size,
+        None => fat_size_padded_and_rounded
+    };
+
+    let fat_file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(out_file_path)?;
+    fat_file.set_len(fat_size)?;
+
+    let format_options = fatfs::FormatVolumeOptions::new()
+        .volume_label(VOLUME_LABEL)
+        .volume_id(VOLUME_ID);
+    fatfs::format_volume(&fat_file, format_options)?;
+    // default time provider of fatfs without `chrono` writes zero timestamps
+    let filesystem = fatfs::FileSystem::new(&fat_file, fatfs::FsOptions::new())?;
+    let root_dir = filesystem.root_dir();
+
+    let mut manifest = Vec::new();
+    for (file, content) in files.iter().zip(contents.iter()) {
+        let dst = file.dst();
+        let target_path = Path::new(dst);
+
+        let ancestors: Vec<_> = target_path.ancestors().skip(1).collect();
+        for ancestor in ancestors.into_iter().rev().skip(1) {
+            root_dir.create_dir(&ancestor.display().to_string())?;
+        }
+
+        let mut new_file = root_dir.create_file(dst)?;
+        new_file.truncate()?;
+
+        match file {
+            ImageFile::Host { src, .. } => println!("copying {} to fs image: {}", src.display(), dst),
+            ImageFile::Generated { .. } => println!("writing generated {} to fs image", dst),
+        }
+        io::Write::write_all(&mut new_file, content)?;
+        manifest.push((dst.to_owned(), sha256_hex(content)));
+    }
+
+    let mut manifest_file = root_dir.create_file(FILE_MANIFEST)?;
+    manifest_file.truncate()?;
+    io::Write::write_all(&mut manifest_file, render_manifest(&manifest).as_bytes())?;
+
+    println!("fat filesystem temp image is created at {}", out_file_path.display());
+    Ok(out_file)
+}
+
+pub fn create_gpt_disk(fat_image: &Path, out_image_path: &Path) -> Result<()> {
+    let mut disk = fs::OpenOptions::new()
+        .create(true)
+        .truncate(true)
+        .read(true)
+        .write(true)
+        .open(out_image_path)?;
+
+    let partition_size: u64 = fs::metadata(fat_image)?.len();
+    let disk_size = partition_size + 1024 * 64; // for GPT headers
+    disk.set_len(disk_size)?;
+
+    let mbr = gpt::mbr::ProtectiveMBR::with_lb_size(
+        u32::try_from((disk_size / 512) - 1).unwrap_or(0xFF_FF_FF_FF),
+    );
+    mbr.overwrite_lba0(&mut disk)?;
+
+    let block_size = gpt::disk::LogicalBlockSize::Lb512;
+    let mut gpt = gpt::GptConfig::new()
+        .writable(true)
+        .initialized(false)
+        .logical_block_size(block_size)
+        .create_from_device(Box::new(&mut disk), Some(DISK_GUID))?;
+    gpt.update_partitions(Default::default())?;
+
+    let partition_id = gpt
+        .add_partition("boot", partition_size, gpt::partition_types::EFI, 0, None)?;
+
+    // partition guid is random by default
+    let mut partitions = gpt.partitions().clone();
+    let partition = partitions.get_mut(&partition_id).unwrap();
+    partition.part_guid = PARTITION_GUID;
+    let start_offset = partition.bytes_start(block_size)?;
+    gpt.update_partitions(partitions)?;
+
+    gpt.write()?;
+
+    disk.seek(io::SeekFrom::Start(start_offset))?;
+    io::copy(&mut fs::File::open(fat_image)?, &mut disk)?;
+
+    println!("gpt partition disk image is created at {}", out_image_path.display());
+    Ok(())
+}
@@ -1,117 +1,223 @@
-use std::{io::{Result, self, Seek}, env::{self}, path::Path, fs::{self}};
-use tempfile::NamedTempFile;
+use std::{io::{self, Result}, path::PathBuf};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use crate::boot_cfg::{BootCfg, FILE_BOOT_CFG};
+use crate::crashdump::decode_crash_dump;
+use crate::hibernate::extract_hibernation_image;
+use crate::image::{construct_filesystem_fat, create_gpt_disk, ImageFile, MB};
+use crate::run::{run_qemu, QemuArgs};
+use crate::sched_trace::convert_sched_trace;
+use crate::verify::verify_image;
+
+mod boot_cfg;
+mod crashdump;
+mod hibernate;
+mod image;
+mod run;
+mod sched_trace;
+mod verify;
 
 const FILE_UEFI_BOOT: &str = "EFI/BOOT/BOOTX64.EFI";
 const FILE_KERNEL: &str = "kernel-x86_64";
-const MB: u64 = 1024 * 1024;
+const FILE_BOOTSTRAP: &str = "bootstrap";
 
-fn main() -> Result<()> {
-    let DEFAULT_FILES_MAPPING: String = format!("{FILE_UEFI_BOOT}->target/x86_64-unknown-uefi/debug/bootloader.efi;{FILE_KERNEL}->target/x86_64-unknown-none/debug/kernel");
-    let DEFAULT_OUTPUT_PATH: String = format!("{}", "target/os.img");
+#[derive(Parser)]
+#[command(name = "build-image", about = "create and verify bootable disk images")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
 
-    let args: Vec<String> = env::args().collect();
+#[derive(Subcommand)]
+enum Command {
+    /// create a gpt disk image with an efi system partition
+    Create(CreateArgs),
+    /// check gpt/fat integrity and file hashes of an image
+    Verify {
+        image: PathBuf,
+    },
+    /// create the image and boot it in qemu
+    Run {
+        #[command(flatten)]
+        create: CreateArgs,
+        #[command(flatten)]
+        qemu: QemuArgs,
+    },
+    /// decode kernel crash records from a captured serial log
+    Crashdump {
+        log: PathBuf,
+    },
+    /// extract a hibernation image from a captured serial log
+    Hibernate {
+        log: PathBuf,
+        /// image file, put it on the boot partition with --extra and name it with --resume
+        #[arg(short, long, default_value = "hibernate.img")]
+        output: PathBuf,
+    },
+    /// convert a scheduler trace dump from a captured serial log to chrome trace json
+    SchedTrace {
+        log: PathBuf,
+        /// json output, loadable in chrome://tracing or perfetto
+        #[arg(short, long, default_value = "sched-trace.json")]
+        output: PathBuf,
+    },
+}
 
-    let env_files_mapping = args.get(1).unwrap_or(&DEFAULT_FILES_MAPPING);
-    let output_path = args.get(2).unwrap_or(&DEFAULT_OUTPUT_PATH);
+#[derive(Args)]
+struct CreateArgs {
+    /// bootloader efi executable
+    #[arg(long, default_value = "target/x86_64-unknown-uefi/debug/bootloader.efi")]
+    bootloader: PathBuf,
+    /// kernel elf
+    #[arg(long, default_value = "target/x86_64-myos/debug/kernel")]
+    kernel: PathBuf,
+    /// bootstrap elf
+    #[arg(long)]
+    bootstrap: Option<PathBuf>,
+    /// additional files, `<host file>=<path in image>`
+    #[arg(long, value_parser = parse_extra)]
+    extra: Vec<(PathBuf, String)>,
+    /// minimal size of efi partition, e.g. `64M`
+    #[arg(long, value_parser = parse_size)]
+    size: Option<u64>,
+    /// firmware interfaces the image boots on
+    #[arg(long, value_enum, default_value_t = BootMode::Uefi)]
+    boot_mode: BootMode,
+
+    #[command(flatten)]
+    boot_cfg: BootCfgArgs,
+
+    /// output image
+    #[arg(default_value = "target/os.img")]
+    output: PathBuf,
+}
 
-    let mut files_mapping: Vec<(&str, &str)> = Vec::new();
-    env_files_mapping.split(";").for_each(|entry| {
-        let paths: Vec<&str> = entry.trim().split("->").collect();
-        files_mapping.push((paths.get(0).unwrap().trim(), paths.get(1).unwrap().trim()));
-    });
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum BootMode {
+    /// gpt disk with efi system partition and protective mbr
+    Uefi,
+    /// uefi layout plus hybrid mbr and legacy boot stage
+    Hybrid,
+    /// legacy bios only
+    Bios,
+}
 
+/// options written to boot.cfg
+#[derive(Args)]
+struct BootCfgArgs {
+    #[arg(long)]
+    kernel_path: Option<String>,
+    #[arg(long)]
+    bootstrap_path: Option<String>,
+    #[arg(long)]
+    resolution: Option<String>,
+    /// console font size: auto, 16, 20 or 24
+    #[arg(long)]
+    font_size: Option<String>,
+    /// console font weight: regular or bold
+    #[arg(long)]
+    font_weight: Option<String>,
+    #[arg(long)]
+    log_level: Option<String>,
+    #[arg(long)]
+    serial: Option<String>,
+    #[arg(long)]
+    cmdline: Option<String>,
+    /// hibernation image on the boot partition to resume from, experimental
+    #[arg(long)]
+    resume: Option<String>,
+}
 
-    let fs_img = construct_filesystem_fat(&files_mapping)?;
-    create_gpt_disk(fs_img.path(), Path::new(&output_path))?;
-    fs_img.close()?;
-    
-    Ok(())
+impl BootCfgArgs {
+    fn to_boot_cfg(&self) -> Result<BootCfg> {
+        let mut boot_cfg = BootCfg::default();
+        let options = [
+            ("kernel-path", &self.kernel_path),
+            ("bootstrap-path", &self.bootstrap_path),
+            ("resolution", &self.resolution),
+            ("font-size", &self.font_size),
+            ("font-weight", &self.font_weight),
+            ("log-level", &self.log_level),
+            ("serial", &self.serial),
+            ("cmdline", &self.cmdline),
+            ("resume", &self.resume),
+        ];
+        for (flag, value) in options {
+            if let Some(value) = value {
+                boot_cfg.set(flag, value)?;
+            }
+        }
+        Ok(boot_cfg)
+    }
 }
 
-pub fn construct_filesystem_fat(
-    files: &Vec<(&str, &str)>,
-) -> Result<NamedTempFile> {
+fn parse_extra(s: &str) -> std::result::Result<(PathBuf, String), String> {
+    let (src, dst) = s.split_once('=').ok_or("expected <host file>=<path in image>")?;
+    Ok((PathBuf::from(src), dst.trim_start_matches('/').to_owned()))
+}
 
-    let out_file = NamedTempFile::new()?;
-    let out_file_path = out_file.path();
+fn parse_size(s: &str) -> std::result::Result<u64, String> {
+    let (number, unit) = match s.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+        Some((i, _)) => s.split_at(i),
+        None => (s, ""),
+    };
+    let number: u64 = number.parse().map_err(|_| format!("invalid size {s}"))?;
+    let unit = match unit.to_ascii_uppercase().as_str() {
+        "" => 1,
+        "K" | "KB" | "KIB" => 1024,
+        "M" | "MB" | "MIB" => MB,
+        "G" | "GB" | "GIB" => 1024 * MB,
+        _ => return Err(format!("invalid size unit in {s}"))
+    };
+    Ok(number * unit)
+}
 
-    // calculate needed size
-    let mut needed_size = 0;
-    for (_, src) in files {
-        needed_size += fs::metadata(*src)?.len();
+fn create(args: &CreateArgs) -> Result<()> {
+    // the bootloader relies on uefi boot services (gop, simple file system, acpi tables),
+    // a legacy stage speaking the same kernel protocol doesn't exist yet.
+    if args.boot_mode != BootMode::Uefi {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "legacy bios boot is not supported: there is no bios stage to load the kernel, \
+             use --boot-mode uefi and a uefi firmware (e.g. OVMF or CSM disabled)"
+        ));
     }
 
-    let fat_file = fs::OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .open(out_file_path)
-        .unwrap();
-    let fat_size_padded_and_rounded = ((needed_size + 1024 * 64 - 1) / MB + 1) * MB + MB;
-    fat_file.set_len(fat_size_padded_and_rounded).unwrap();
-
-    let label = *b"___ASOS____";
-
-    let format_options = fatfs::FormatVolumeOptions::new().volume_label(label);
-    fatfs::format_volume(&fat_file, format_options)?;
-    let filesystem = fatfs::FileSystem::new(&fat_file, fatfs::FsOptions::new())?;
-    let root_dir = filesystem.root_dir();
-
-    for (dst, src) in files {
-        let target_path = Path::new(*dst);
-
-        let ancestors: Vec<_> = target_path.ancestors().skip(1).collect();
-        for ancestor in ancestors.into_iter().rev().skip(1) {
-            root_dir.create_dir(&ancestor.display().to_string())?;
-        }
-
-        let mut new_file = root_dir.create_file(*dst)?;
-        new_file.truncate().unwrap();
-
-        println!("copying {} to fs image: {}", *src, *dst);
-        io::copy(&mut fs::File::open(*src)?, &mut new_file)?;
+    let boot_cfg = args.boot_cfg.to_boot_cfg()?;
+    let kernel_dst = boot_cfg.kernel.clone().unwrap_or(FILE_KERNEL.to_owned());
+    let bootstrap_dst = boot_cfg.bootstrap.clone().unwrap_or(FILE_BOOTSTRAP.to_owned());
+    let boot_cfg = boot_cfg.render();
+
+    let mut files = vec![
+        ImageFile::Host { dst: FILE_UEFI_BOOT, src: &args.bootloader },
+        ImageFile::Host { dst: &kernel_dst, src: &args.kernel },
+    ];
+    if let Some(bootstrap) = &args.bootstrap {
+        files.push(ImageFile::Host { dst: &bootstrap_dst, src: bootstrap });
     }
+    for (src, dst) in args.extra.iter() {
+        files.push(ImageFile::Host { dst, src });
+    }
+    files.push(ImageFile::Generated { dst: FILE_BOOT_CFG, content: boot_cfg.as_bytes() });
 
-    println!("fat filesystem temp image is created at {}", out_file_path.display());
-    Ok(out_file)
-}
+    let fs_img = construct_filesystem_fat(&files, args.size)?;
+    create_gpt_disk(fs_img.path(), &args.output)?;
+    fs_img.close()?;
 
-pub fn create_gpt_disk(fat_image: &Path, out_image_path: &Path) -> Result<()> {
-    let mut disk = fs::OpenOptions::new()
-        .create(true)
-        .truncate(true)
-        .read(true)
-        .write(true)
-        .open(out_image_path)?;
-
-    let partition_size: u64 = fs::metadata(fat_image)?.len();
-    let disk_size = partition_size + 1024 * 64; // for GPT headers
-    disk.set_len(disk_size)?;
-
-    let mbr = gpt::mbr::ProtectiveMBR::with_lb_size(
-        u32::try_from((disk_size / 512) - 1).unwrap_or(0xFF_FF_FF_FF),
-    );
-    mbr.overwrite_lba0(&mut disk)?;
-
-    let block_size = gpt::disk::LogicalBlockSize::Lb512;
-    let mut gpt = gpt::GptConfig::new()
-        .writable(true)
-        .initialized(false)
-        .logical_block_size(block_size)
-        .create_from_device(Box::new(&mut disk), None)?;
-    gpt.update_partitions(Default::default())?;
-
-    let partition_id = gpt
-        .add_partition("boot", partition_size, gpt::partition_types::EFI, 0, None)?;
-    let partition = gpt.partitions().get(&partition_id).unwrap();
-    let start_offset = partition.bytes_start(block_size)?;
-
-    gpt.write()?;
-
-    disk.seek(io::SeekFrom::Start(start_offset))?;
-    io::copy(&mut fs::File::open(fat_image)?, &mut disk)?;
-
-    println!("gpt partition disk image is created at {}", out_image_path.display());
     Ok(())
 }
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+
+    match cli.command {
+        Command::Create(args) => create(&args),
+        Command::Verify { image } => verify_image(&image),
+        Command::Run { create: args, qemu } => {
+            create(&args)?;
+            run_qemu(&args.output, &qemu)
+        }
+        Command::Crashdump { log } => decode_crash_dump(&log),
+        Command::Hibernate { log, output } => extract_hibernation_image(&log, &output),
+        Command::SchedTrace { log, output } => convert_sched_trace(&log, &output),
+    }
+}
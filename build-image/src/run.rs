@@ -0,0 +1,89 @@
+use std::{env, io::{self, Result}, path::{Path, PathBuf}, process::Command};
+use clap::Args;
+
+// kernel writes QemuExitCode to isa-debug-exit, qemu exits with (code << 1) | 1
+const QEMU_EXIT_SUCCESS: i32 = (0x10 << 1) | 1;
+
+const OVMF_CANDIDATES: &[&str] = &[
+    "edk2/OVMF-pure-efi.fd",
+    "/usr/share/OVMF/OVMF_CODE.fd",
+    "/usr/share/OVMF/OVMF.fd",
+    "/usr/share/ovmf/OVMF.fd",
+    "/usr/share/edk2/x64/OVMF_CODE.fd",
+    "/usr/share/edk2/ovmf/OVMF_CODE.fd",
+    "/usr/share/qemu/edk2-x86_64-code.fd",
+    "/usr/local/share/qemu/edk2-x86_64-code.fd",
+    "/opt/homebrew/share/qemu/edk2-x86_64-code.fd",
+    "C:/Program Files/qemu/share/edk2-x86_64-code.fd",
+];
+
+#[derive(Args)]
+pub struct QemuArgs {
+    /// uefi firmware, searched in `OVMF_PATH` and common install locations if absent
+    #[arg(long)]
+    ovmf: Option<PathBuf>,
+    /// qemu executable
+    #[arg(long, default_value = "qemu-system-x86_64")]
+    qemu: String,
+    /// number of cpus
+    #[arg(long, default_value_t = 4)]
+    smp: u32,
+    /// guest memory
+    #[arg(long, default_value = "1024M")]
+    memory: String,
+    /// start gdb server on tcp::1234 and wait for debugger
+    #[arg(long)]
+    gdb: bool,
+    /// don't attach isa-debug-exit device
+    #[arg(long)]
+    no_debug_exit: bool,
+    /// extra arguments passed to qemu as is
+    #[arg(last = true)]
+    qemu_args: Vec<String>,
+}
+
+fn find_ovmf(explicit: Option<&Path>) -> Result<PathBuf> {
+    if let Some(path) = explicit {
+        return Ok(path.to_owned());
+    }
+    if let Some(path) = env::var_os("OVMF_PATH") {
+        return Ok(PathBuf::from(path));
+    }
+
+    OVMF_CANDIDATES.iter()
+        .map(PathBuf::from)
+        .find(|p| p.is_file())
+        .ok_or_else(|| io::Error::new(
+            io::ErrorKind::NotFound,
+            "uefi firmware is not found, pass --ovmf or set OVMF_PATH"
+        ))
+}
+
+pub fn run_qemu(image: &Path, args: &QemuArgs) -> Result<()> {
+    let ovmf = find_ovmf(args.ovmf.as_deref())?;
+
+    let mut cmd = Command::new(&args.qemu);
+    cmd.arg("-drive").arg(format!("if=pflash,format=raw,readonly=on,file={}", ovmf.display()))
+        .arg("-drive").arg(format!("format=raw,file={}", image.display()))
+        .arg("-smp").arg(args.smp.to_string())
+        .arg("-m").arg(&args.memory)
+        .arg("-serial").arg("stdio")
+        .arg("-no-reboot");
+    if !args.no_debug_exit {
+        cmd.arg("-device").arg("isa-debug-exit,iobase=0xf4,iosize=0x04");
+    }
+    if args.gdb {
+        println!("waiting for gdb on tcp::1234");
+        cmd.arg("-s").arg("-S");
+    }
+    cmd.args(&args.qemu_args);
+
+    println!("running {:?}", cmd);
+    let status = cmd.status()?;
+
+    match status.code() {
+        Some(0) | Some(QEMU_EXIT_SUCCESS) => Ok(()),
+        Some(code) => Err(io::Error::new(io::ErrorKind::Other, format!("qemu exited with {code}"))),
+        None => Err(io::Error::new(io::ErrorKind::Other, "qemu is terminated by signal")),
+    }
+}
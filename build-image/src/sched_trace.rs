@@ -0,0 +1,143 @@
+use std::{collections::{BTreeMap, BTreeSet}, fmt::Write, fs, io::{self, Result}, path::Path};
+
+// mirrors kernel/src/context/trace.rs
+const LINE_PREFIX: &str = "STRACE ";
+
+struct Event {
+    cpu: u32,
+    tsc: u64,
+    kind: String,
+    ctx: u64,
+}
+
+#[derive(Default)]
+struct Record {
+    tsc_hz: u64,
+    names: BTreeMap<u64, String>,
+    events: Vec<Event>,
+    complete: bool,
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn json_str(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => { let _ = write!(out, "\\u{:04x}", c as u32); }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn parse_event(fields: &[&str]) -> Option<Event> {
+    match fields {
+        [cpu, tsc, kind, ctx] => Some(Event {
+            cpu: cpu.parse().ok()?,
+            tsc: tsc.parse().ok()?,
+            kind: kind.to_string(),
+            ctx: ctx.parse().ok()?,
+        }),
+        _ => None,
+    }
+}
+
+// the last dump in the log wins, anything before the prefix is other serial output
+fn parse_log(content: &str) -> Option<Record> {
+    let mut record: Option<Record> = None;
+    for (line_no, line) in content.lines().enumerate() {
+        let Some(at) = line.find(LINE_PREFIX) else { continue };
+        let fields: Vec<&str> = line[at + LINE_PREFIX.len()..].split_ascii_whitespace().collect();
+        match (fields.first().copied(), record.as_mut()) {
+            (Some("begin"), _) => {
+                let tsc_hz = fields.get(2).and_then(|hz| hz.parse().ok()).unwrap_or(0);
+                record = Some(Record { tsc_hz, ..Record::default() });
+            }
+            (Some("ctx"), Some(record)) => {
+                if let Some(id) = fields.get(1).and_then(|id| id.parse().ok()) {
+                    record.names.insert(id, fields[2..].join(" "));
+                }
+            }
+            (Some("ev"), Some(record)) => match parse_event(&fields[1..]) {
+                Some(event) => record.events.push(event),
+                None => println!("warning: line {}: malformed event, skipped", line_no + 1),
+            },
+            (Some("end"), Some(record)) => {
+                let expected: usize = fields.get(1).and_then(|count| count.parse().ok()).unwrap_or(0);
+                if expected != record.events.len() {
+                    println!("warning: {} of {} events received", record.events.len(), expected);
+                }
+                record.complete = true;
+            }
+            _ => {}
+        }
+    }
+    record
+}
+
+/// convert the last scheduler trace dump in a captured serial log into chrome trace json
+pub fn convert_sched_trace(log_path: &Path, output: &Path) -> Result<()> {
+    let content = fs::read(log_path)?;
+    let content = String::from_utf8_lossy(&content);
+    let Some(mut record) = parse_log(&content) else {
+        return Err(invalid(format!("no scheduler trace in {}", log_path.display())));
+    };
+    if !record.complete {
+        println!("warning: trace is cut off, converting what was received");
+    }
+    if record.tsc_hz == 0 {
+        return Err(invalid("trace does not report tsc frequency".to_string()));
+    }
+
+    // rings are dumped per cpu, the timeline needs global order
+    record.events.sort_by_key(|event| (event.tsc, event.cpu));
+    let base = record.events.first().map_or(0, |event| event.tsc);
+    let micros = |tsc: u64| (tsc - base) as f64 * 1e6 / record.tsc_hz as f64;
+    let name = |ctx: u64| match record.names.get(&ctx) {
+        Some(name) => format!("{name} ({ctx})"),
+        None => format!("context {ctx}"),
+    };
+
+    let mut entries = Vec::new();
+    let mut cpus = BTreeSet::new();
+    // context running on every cpu since which tsc, closed by the next switch
+    let mut running: BTreeMap<u32, (u64, u64)> = BTreeMap::new();
+    for event in &record.events {
+        cpus.insert(event.cpu);
+        if event.kind == "switch" {
+            if let Some((ctx, since)) = running.insert(event.cpu, (event.ctx, event.tsc)) {
+                entries.push(format!(
+                    r#"{{"name":{},"ph":"X","pid":0,"tid":{},"ts":{:.3},"dur":{:.3}}}"#,
+                    json_str(&name(ctx)), event.cpu, micros(since), micros(event.tsc) - micros(since)
+                ));
+            }
+            continue;
+        }
+        entries.push(format!(
+            r#"{{"name":{},"ph":"i","s":"t","pid":0,"tid":{},"ts":{:.3}}}"#,
+            json_str(&format!("{} {}", event.kind, name(event.ctx))), event.cpu, micros(event.tsc)
+        ));
+    }
+    // still running when the trace was dumped
+    let last = record.events.last().map_or(base, |event| event.tsc);
+    for (cpu, (ctx, since)) in running {
+        entries.push(format!(
+            r#"{{"name":{},"ph":"X","pid":0,"tid":{},"ts":{:.3},"dur":{:.3}}}"#,
+            json_str(&name(ctx)), cpu, micros(since), micros(last) - micros(since)
+        ));
+    }
+    for cpu in &cpus {
+        entries.push(format!(r#"{{"name":"thread_name","ph":"M","pid":0,"tid":{cpu},"args":{{"name":"cpu {cpu}"}}}}"#));
+    }
+
+    let json = format!("{{\"traceEvents\":[\n{}\n],\"displayTimeUnit\":\"ns\"}}\n", entries.join(",\n"));
+    fs::write(output, json)?;
+    println!("{} events on {} cpus written to {}", record.events.len(), cpus.len(), output.display());
+    Ok(())
+}
@@ -0,0 +1,78 @@
+use std::{fs, io::{self, Cursor, Read, Result, Seek}, path::Path};
+use crate::boot_cfg::FILE_BOOT_CFG;
+use crate::image::{parse_manifest, sha256_hex, FILE_MANIFEST};
+use crate::FILE_UEFI_BOOT;
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// check gpt headers, the efi partition, fat filesystem and hashes listed in manifest.
+pub fn verify_image(image_path: &Path) -> Result<()> {
+    let block_size = gpt::disk::LogicalBlockSize::Lb512;
+    // header and partition array crc are checked while opening
+    let disk = gpt::GptConfig::new()
+        .writable(false)
+        .logical_block_size(block_size)
+        .open(image_path)?;
+    println!("gpt: disk guid {}", disk.guid());
+
+    let partition = disk.partitions().values()
+        .find(|p| p.part_type_guid == gpt::partition_types::EFI)
+        .ok_or_else(|| invalid("no efi system partition".into()))?;
+    let start = partition.bytes_start(block_size)?;
+    let len = partition.bytes_len(block_size)?;
+    println!("gpt: efi partition {} at {:#x}, {} bytes", partition.part_guid, start, len);
+
+    let mut image = fs::File::open(image_path)?;
+
+    // a protective mbr has a single 0xee partition, anything else means legacy boot code we don't produce
+    let mut mbr = [0_u8; 512];
+    image.read_exact(&mut mbr)?;
+    if mbr[510..512] != [0x55, 0xaa] || mbr[446 + 4] != 0xee {
+        return Err(invalid("mbr is not a protective mbr, only uefi images are supported".into()));
+    }
+    println!("mbr: protective, boot mode uefi");
+
+    if image.metadata()?.len() < start + len {
+        return Err(invalid("efi partition exceeds image".into()));
+    }
+    let mut fat = vec![0; len as usize];
+    image.seek(io::SeekFrom::Start(start))?;
+    image.read_exact(&mut fat)?;
+
+    let filesystem = fatfs::FileSystem::new(Cursor::new(fat), fatfs::FsOptions::new())?;
+    let root_dir = filesystem.root_dir();
+    let read_file = |path: &str| -> Result<Vec<u8>> {
+        let mut content = Vec::new();
+        root_dir.open_file(path)
+            .map_err(|e| invalid(format!("{path}: {e}")))?
+            .read_to_end(&mut content)?;
+        Ok(content)
+    };
+
+    read_file(FILE_UEFI_BOOT)?;
+    match read_file(FILE_BOOT_CFG) {
+        Ok(_) => {}
+        Err(_) => println!("fat: {FILE_BOOT_CFG} is absent, bootloader will use defaults"),
+    }
+
+    let manifest = String::from_utf8(read_file(FILE_MANIFEST)?)
+        .map_err(|_| invalid(format!("{FILE_MANIFEST} is not utf-8")))?;
+    let mut mismatched = 0;
+    for (path, expected) in parse_manifest(&manifest) {
+        let actual = sha256_hex(&read_file(&path)?);
+        if actual == expected {
+            println!("ok       {path}");
+        } else {
+            println!("MISMATCH {path}: expected {expected}, got {actual}");
+            mismatched += 1;
+        }
+    }
+
+    if mismatched > 0 {
+        return Err(invalid(format!("{mismatched} files do not match manifest")));
+    }
+    println!("{} is valid", image_path.display());
+    Ok(())
+}
@@ -1,17 +1,30 @@
+use std::collections::BTreeMap;
 use std::env;
+use std::fmt::Write;
+use std::fs;
 use std::process::Command;
 
+#[allow(dead_code)]
+#[path = "src/acpi/trampoline_layout.rs"]
+mod trampoline_layout;
+
 const KERNEL_VA_BASE: u64 = 0xffff_ff80_0000_0000;
+const DEFAULT_CONFIG: &str = "kernel.toml";
 
 fn main() {
     println!("cargo:rustc-link-arg=--image-base={}", KERNEL_VA_BASE);
     println!("cargo:rerun-if-changed=src/asm/trampoline.asm");
+    println!("cargo:rerun-if-changed=src/acpi/trampoline_layout.rs");
 
     let out_dir = env::var("OUT_DIR").unwrap();
+    generate_config(&out_dir);
+    git_hash();
 
+    // header offsets come from the layout shared with the kernel
     let status = Command::new("nasm")
         .arg("-f")
         .arg("bin")
+        .args(trampoline_layout::DEFINES.iter().map(|(name, offset)| format!("-D{}={}", name, offset)))
         .arg("-o")
         .arg(format!("{}/trampoline", out_dir))
         .arg("src/asm/trampoline.asm")
@@ -20,4 +33,107 @@ fn main() {
     if !status.success() {
         panic!("nasm failed with exit status {}", status);
     }
-}
\ No newline at end of file
+}
+
+// `KERNEL_GIT_HASH` for uname and /proc/version, "unknown" outside a git checkout
+fn git_hash() {
+    let git = |args: &[&str]| {
+        Command::new("git").args(args).output().ok()
+            .filter(|output| output.status.success())
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+    };
+    // rebuild on commits and checkouts
+    if let Some(dir) = git(&["rev-parse", "--git-dir"]) {
+        println!("cargo:rerun-if-changed={}/HEAD", dir);
+        if let Some(head) = git(&["symbolic-ref", "-q", "HEAD"]) {
+            println!("cargo:rerun-if-changed={}/{}", dir, head);
+        }
+    }
+    let hash = git(&["rev-parse", "--short=12", "HEAD"]).unwrap_or_else(|| "unknown".into());
+    println!("cargo:rustc-env=KERNEL_GIT_HASH={}", hash);
+}
+
+#[derive(Debug)]
+enum Value {
+    Bool(bool),
+    Int(u64),
+    Str(String),
+}
+
+// (key in `section.key` form, const name, expected type, upper bound of ints)
+const CONFIG_KEYS: &[(&str, &str, &str, u64)] = &[
+    ("smp.enabled", "SMP", "bool", 0),
+    ("smp.max_cpus", "MAX_CPUS", "usize", 4096),
+    ("sched.quantum", "SCHED_QUANTUM", "usize", 1000),
+    ("sched.tick_hz", "SCHED_TICK_HZ", "usize", 1000),
+    ("mem.heap_size", "HEAP_SIZE", "usize", 1 << 30),
+    ("log.level", "LOG_LEVEL", "&str", 0),
+    ("security.kpti", "KPTI", "bool", 0),
+    ("security.mitigations", "MITIGATIONS", "bool", 0),
+];
+
+const LOG_LEVELS: &[&str] = &["off", "error", "warn", "info", "debug", "trace"];
+
+fn generate_config(out_dir: &str) {
+    println!("cargo:rerun-if-env-changed=KERNEL_CONFIG");
+    let path = env::var("KERNEL_CONFIG").unwrap_or_else(|_| DEFAULT_CONFIG.into());
+    println!("cargo:rerun-if-changed={}", path);
+
+    let text = fs::read_to_string(&path).unwrap_or_else(|e| panic!("failed to read {}: {}", path, e));
+    let mut values = parse_toml(&text).unwrap_or_else(|e| panic!("{}: {}", path, e));
+
+    let mut out = format!("// generated by build.rs from {}\n", path);
+    for &(key, name, ty, max) in CONFIG_KEYS {
+        let value = values.remove(key).unwrap_or_else(|| panic!("{}: missing `{}`", path, key));
+        let literal = match (ty, value) {
+            ("bool", Value::Bool(b)) => b.to_string(),
+            ("usize", Value::Int(n)) if n > 0 && n <= max => format!("{:#x}", n),
+            ("&str", Value::Str(s)) if key != "log.level" || LOG_LEVELS.contains(&s.as_str()) => format!("{:?}", s),
+            (_, value) => panic!("{}: invalid value {:?} for `{}`", path, value, key),
+        };
+        writeln!(out, "pub const {}: {} = {};", name, ty, literal).unwrap();
+    }
+    if let Some(key) = values.keys().next() {
+        panic!("{}: unknown key `{}`", path, key);
+    }
+
+    fs::write(format!("{}/config.rs", out_dir), out).unwrap();
+}
+
+// the subset kernel.toml uses: `[section]` headers and `key = bool | int | "string"`
+fn parse_toml(text: &str) -> Result<BTreeMap<String, Value>, String> {
+    let mut values = BTreeMap::new();
+    let mut section = String::new();
+
+    for (lineno, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            section = name.trim().to_string();
+            continue;
+        }
+
+        let (key, value) = line.split_once('=').ok_or(format!("line {}: expected `key = value`", lineno + 1))?;
+        let value = value.trim();
+        let value = if let Ok(b) = value.parse::<bool>() {
+            Value::Bool(b)
+        } else if let Some(s) = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+            Value::Str(s.to_string())
+        } else {
+            let digits = value.replace('_', "");
+            let int = match digits.strip_prefix("0x") {
+                Some(hex) => u64::from_str_radix(hex, 16),
+                None => digits.parse(),
+            };
+            Value::Int(int.map_err(|_| format!("line {}: invalid value `{}`", lineno + 1, value))?)
+        };
+
+        let key = if section.is_empty() { key.trim().to_string() } else { format!("{}.{}", section, key.trim()) };
+        if values.insert(key.clone(), value).is_some() {
+            return Err(format!("line {}: duplicate key `{}`", lineno + 1, key));
+        }
+    }
+    Ok(values)
+}
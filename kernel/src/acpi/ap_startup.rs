@@ -1,51 +1,110 @@
 use core::arch::{asm, global_asm};
 use core::hint::spin_loop;
+use core::mem::offset_of;
 use core::sync::atomic::{AtomicU8, Ordering};
 use x86_64::registers::control::Cr3;
 use x86_64::structures::paging::PhysFrame;
 use x86_64::{PhysAddr, VirtAddr};
 use shared::arg::MadtLocalApic;
 use crate::acpi::local_apic::LOCAL_APIC;
-use crate::{_start_ap, AP_READY, CPU_COUNT, infohart};
+use crate::acpi::trampoline_layout as layout;
+use crate::config::config;
+use crate::cpu::LogicalCpuId;
+use crate::{_start_ap, AP_READY, CPU_COUNT, KernelArgAp, infohart, warnhart};
 use crate::mem::frame_allocator::frame_alloc_n;
+use crate::mem::PAGE_SIZE;
+use crate::mem::lowmem::{lowmem_alloc, lowmem_free};
+use crate::mem::stack::{fill_stack_pattern, stack_config};
+use crate::mem::tlb::track_cpu;
 
-const TRAMPOLINE: usize = 0x8000;
 // x86_64 trampoline from redox kernel
 static TRAMPOLINE_DATA: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/trampoline"));
 
-pub fn setup_ap_startup(lapics: &[MadtLocalApic], kernel_page_table: VirtAddr) {
-    let mut lapic = unsafe { LOCAL_APIC };
+// `_start_ap` reads its arguments straight from the trampoline header
+const _: () = {
+    assert!(offset_of!(KernelArgAp, cpu_id) == 0);
+    assert!(offset_of!(KernelArgAp, page_table) == layout::PAGE_TABLE - layout::CPU_ID);
+    assert!(offset_of!(KernelArgAp, stack_start) == layout::STACK_START - layout::CPU_ID);
+    assert!(offset_of!(KernelArgAp, stack_end) == layout::STACK_END - layout::CPU_ID);
+};
+
+/// copy trampoline to a low memory page and relocate it there, returns its physical address
+fn load_trampoline() -> usize {
+    let pages = (TRAMPOLINE_DATA.len() + PAGE_SIZE - 1) / PAGE_SIZE;
+    let base = lowmem_alloc(pages)
+        .expect("no low memory for ap trampoline")
+        .as_u64() as usize;
 
     for i in 0..TRAMPOLINE_DATA.len() {
         unsafe {
-            (*((TRAMPOLINE as *mut u8).add(i) as *const AtomicU8))
+            (*((base as *mut u8).add(i) as *const AtomicU8))
                 .store(TRAMPOLINE_DATA[i], Ordering::SeqCst);
         }
     }
 
+    for i in 0..layout::RELOC_COUNT {
+        unsafe {
+            let offset = ((base + layout::RELOCS) as *const u16).add(i).read_unaligned();
+            let target = (base + offset as usize) as *mut u32;
+            target.write_unaligned(target.read_unaligned() + base as u32);
+        }
+    }
+
+    base
+}
+
+pub fn setup_ap_startup(lapics: &[MadtLocalApic], kernel_page_table: VirtAddr) {
+    let mut lapic = unsafe { LOCAL_APIC };
+
+    let config = config();
+    if !config.smp {
+        infohart!("smp disabled, application processors stay halted");
+        return;
+    }
+
+    // no madt or mp table, only the bsp is known
+    if lapics.is_empty() {
+        infohart!("firmware reports no application processors, running on the bsp only");
+        return;
+    }
+
+    let trampoline = load_trampoline();
+    infohart!("ap trampoline at {:#x}", trampoline);
+
     infohart!("starting ap...");
     for &MadtLocalApic { id, processor_id } in lapics {
-        if lapic.id() as u8 == id {
+        if lapic.id() == id {
             infohart!("  skipping bsp");
             continue
         }
+        if !lapic.addressable(id) {
+            warnhart!("  skipping ap {}, apic id {} needs x2apic mode", processor_id, id);
+            continue
+        }
+        // per-cpu tables are indexed by apic id
+        if id as usize >= config.max_cpus {
+            infohart!("  skipping ap {}, apic id {} exceeds max cpus {}", processor_id, id, config.max_cpus);
+            continue
+        }
 
         infohart!("  starting ap {}", processor_id);
         CPU_COUNT.fetch_add(1, Ordering::SeqCst);
 
-        let stack_start = frame_alloc_n(64)
+        let stack_pages = stack_config().ap_pages();
+        let stack_start = frame_alloc_n(stack_pages)
             .expect("failed to allocate kernel stack for ap")
             .start_address()
             .as_u64();
+        unsafe { fill_stack_pattern(stack_start as *mut u8, stack_pages * PAGE_SIZE) }
         infohart!("ap stack: {:x}", stack_start);
-        let stack_end = stack_start + 64 * 4096;
+        let stack_end = stack_start + (stack_pages * PAGE_SIZE) as u64;
 
-        let ap_ready = (TRAMPOLINE + 8) as *mut u64;
-        let ap_cpu_id = unsafe { ap_ready.add(1) };
-        let ap_page_table = unsafe { ap_ready.add(2) };
-        let ap_stack_start = unsafe { ap_ready.add(3) };
-        let ap_stack_end = unsafe { ap_ready.add(4) };
-        let ap_code = unsafe { ap_ready.add(5) };
+        let ap_ready = (trampoline + layout::READY) as *mut u64;
+        let ap_cpu_id = (trampoline + layout::CPU_ID) as *mut u64;
+        let ap_page_table = (trampoline + layout::PAGE_TABLE) as *mut u64;
+        let ap_stack_start = (trampoline + layout::STACK_START) as *mut u64;
+        let ap_stack_end = (trampoline + layout::STACK_END) as *mut u64;
+        let ap_code = (trampoline + layout::CODE) as *mut u64;
 
         unsafe {
             ap_ready.write(0);
@@ -61,17 +120,10 @@ pub fn setup_ap_startup(lapics: &[MadtLocalApic], kernel_page_table: VirtAddr) {
 
         AP_READY.store(false, Ordering::SeqCst);
 
-        {   // INIT
-            let mut icr = 0x4500 | (id as u64) << if lapic.x2 { 32 } else { 56 };
-            lapic.set_icr(icr);
-        }
-
-
-        {  // START IPI
-            let mut icr = 0x4600 | ((TRAMPOLINE >> 12) & 0xFF) as u64;
-            icr |= (id as u64) << if lapic.x2 { 32 } else { 56 };
-            lapic.set_icr(icr);
-        }
+        // from here on it may cache translations
+        track_cpu(LogicalCpuId(id));
+        lapic.ipi_init(id);
+        lapic.ipi_startup(id, (trampoline >> 12) as u8);
 
         // Wait for trampoline ready
         infohart!("    lapic {} wait...", id);
@@ -88,4 +140,7 @@ pub fn setup_ap_startup(lapics: &[MadtLocalApic], kernel_page_table: VirtAddr) {
             Cr3::write(PhysFrame::containing_address(PhysAddr::new(kernel_page_table.as_u64())), cr3.1)
         }
     }
-}
\ No newline at end of file
+
+    // every ap left the trampoline, `_start_ap` copied its arguments before AP_READY
+    lowmem_free(PhysAddr::new(trampoline as u64), (TRAMPOLINE_DATA.len() + PAGE_SIZE - 1) / PAGE_SIZE);
+}
@@ -1,20 +1,49 @@
 use alloc::vec::Vec;
-use core::ptr;
 use core::ptr::{read_volatile, write_volatile};
-use lazy_static::lazy_static;
-use spin::Mutex;
-use shared::arg::{MadtInterruptSrcOverride, MadtIoApic};
-use shared::uni_processor::UPSafeCell;
+use spin::{Mutex, Once};
+use x86_64::PhysAddr;
+use libvdso::error::{EBUSY, EINVAL, ENODEV, KError, KResult};
+use shared::arg::{MadtInterruptSrcOverride, MadtIoApic, MadtNmiSource};
 use crate::acpi::local_apic::LOCAL_APIC;
-use crate::infohart;
+use crate::cpu::LogicalCpuId;
+use crate::device::pic;
+use crate::mem::kvm::kvm_map_mmio;
+use crate::{infohart, warnhart};
+use crate::initcall;
+use crate::initcall::{kernel_arg, InitCpuArg};
 
-lazy_static! {
-    static ref IOAPICS: UPSafeCell<Vec<IoApic>> = unsafe { UPSafeCell::new(Vec::new()) };
-    static ref SRC_OVERRIDES: UPSafeCell<Vec<Override>> = unsafe { UPSafeCell::new(Vec::new()) };
-}
+/**
+ *  io apics and global system interrupts.
+ *
+ *  every io apic of the madt handles the gsis `gsi_base..gsi_base + count`,
+ *  count is read from its version register. at boot every redirection entry
+ *  is masked, the nmi sources of the madt are delivered as nmi to the bsp and
+ *  the 16 isa irqs are routed to the bsp at vectors 32..48, following the
+ *  interrupt source overrides. other gsis, pci lines mostly, stay masked until
+ *  a driver routes them with [`route_gsi`] or [`route_pci_gsi`] to a vector
+ *  and cpu of its choice.
+ *
+ *  polarity and trigger mode an override or nmi source leaves as "conforms to
+ *  the bus" are the defaults of the bus the line belongs to: edge and active
+ *  high for isa, level and active low for pci. a pci link device routed onto
+ *  an isa irq gets the pci defaults, unless the override of that irq says
+ *  otherwise. [`routing_table`] reads the programmed entries back.
+ */
+
+// lvt delivery mode taking the vector from the 8259 pic
+const LVT_DELIVERY_EXTINT: u32 = 0b111 << 8;
+// ioregsel is 8 bits, entry n takes registers 0x10 + 2n and 0x11 + 2n
+const MAX_REDIRECTION_ENTRIES: u8 = (0x100 - 0x10) / 2;
+// ioregsel and iowin
+const IO_APIC_REGS_LEN: usize = 0x20;
+
+static IOAPICS: Once<Vec<IoApic>> = Once::new();
+static SRC_OVERRIDES: Once<Vec<Override>> = Once::new();
+static NMI_SOURCES: Once<Vec<u32>> = Once::new();
 
 pub struct IoApicRegs {
-    base: u32,
+    // virtual address of ioregsel in the kvm mmio region
+    base: u64,
 }
 
 impl IoApicRegs {
@@ -23,7 +52,7 @@ impl IoApicRegs {
     }
     fn iowin(&self) -> *const u32 {
         // offset 0x10
-        unsafe { (self.base + 0x10) as *const u32 }
+        (self.base + 0x10) as *const u32
     }
     fn write_ioregsel(&mut self, value: u32) {
         unsafe { write_volatile(self.ioregsel() as *mut u32, value) }
@@ -55,14 +84,14 @@ impl IoApicRegs {
         self.read_reg(0x02)
     }
     pub fn read_ioredtbl(&mut self, idx: u8) -> u64 {
-        assert!(idx < 24);
+        assert!(idx < MAX_REDIRECTION_ENTRIES);
         let lo = self.read_reg(0x10 + idx * 2);
         let hi = self.read_reg(0x10 + idx * 2 + 1);
 
         u64::from(lo) | (u64::from(hi) << 32)
     }
     pub fn write_ioredtbl(&mut self, idx: u8, value: u64) {
-        assert!(idx < 24);
+        assert!(idx < MAX_REDIRECTION_ENTRIES);
 
         let lo = value as u32;
         let hi = (value >> 32) as u32;
@@ -71,6 +100,7 @@ impl IoApicRegs {
         self.write_reg(0x10 + idx * 2 + 1, hi);
     }
 
+    /// index of the last redirection entry
     pub fn max_redirection_table_entries(&mut self) -> u8 {
         let ver = self.read_ioapicver();
         ((ver & 0x00FF_0000) >> 16) as u8
@@ -82,26 +112,43 @@ impl IoApicRegs {
 }
 pub struct IoApic {
     regs: Mutex<IoApicRegs>,
+    id: u8,
     gsi_base: u32,
     count: u8,
 }
 impl IoApic {
-    pub fn new(regs_base: u32, gsi_start: u32) -> Self {
-        let mut regs = IoApicRegs { base: regs_base };
-        let count = regs.max_redirection_table_entries();
+    /// map the registers at physical `regs_base` and read the entry count
+    pub fn new(regs_base: u64, gsi_start: u32) -> KResult<Self> {
+        let base = kvm_map_mmio(PhysAddr::try_new(regs_base).map_err(|_| KError::new(EINVAL))?, IO_APIC_REGS_LEN)?;
+        let mut regs = IoApicRegs { base: base.as_u64() };
+        let max_index = regs.max_redirection_table_entries();
+        if max_index >= MAX_REDIRECTION_ENTRIES {
+            warnhart!("io apic at {:#x} reports {} redirection entries, only {} are addressable",
+                regs_base, u16::from(max_index) + 1, MAX_REDIRECTION_ENTRIES);
+        }
+        let count = max_index.saturating_add(1).min(MAX_REDIRECTION_ENTRIES);
+        let id = regs.id();
 
-        Self {
+        Ok(Self {
             regs: Mutex::new(regs),
+            id,
             gsi_base: gsi_start,
             count,
-        }
+        })
+    }
+    /// redirection table index of `gsi`, if this io apic handles it
+    pub fn index(&self, gsi: u32) -> Option<u8> {
+        gsi.checked_sub(self.gsi_base)
+            .filter(|idx| *idx < u32::from(self.count))
+            .map(|idx| idx as u8)
     }
     /// Map an interrupt vector to a physical local APIC ID of a processor (thus physical mode).
     pub fn map(&self, idx: u8, info: MapInfo) {
+        assert!(idx < self.count, "redirection entry {} is out of {}", idx, self.count);
         self.regs.lock().write_ioredtbl(idx, info.as_raw())
     }
     pub fn set_mask(&self, gsi: u32, mask: bool) {
-        let idx = (gsi - self.gsi_base) as u8;
+        let idx = self.index(gsi).unwrap_or_else(|| panic!("gsi {} is not handled by this io apic", gsi));
         let mut guard = self.regs.lock();
 
         let mut reg = guard.read_ioredtbl(idx);
@@ -109,6 +156,131 @@ impl IoApic {
         reg |= u64::from(mask) << 16;
         guard.write_ioredtbl(idx, reg);
     }
+    pub fn read(&self, idx: u8) -> u64 {
+        assert!(idx < self.count, "redirection entry {} is out of {}", idx, self.count);
+        self.regs.lock().read_ioredtbl(idx)
+    }
+    fn mask_all(&self) {
+        (0..self.count).for_each(|idx| self.set_mask(self.gsi_base + u32::from(idx), true));
+    }
+}
+
+fn find_gsi(gsi: u32) -> KResult<(&'static IoApic, u8)> {
+    let ioapics = IOAPICS.get().ok_or(KError::new(ENODEV))?;
+    ioapics.iter()
+        .find_map(|ioapic| ioapic.index(gsi).map(|idx| (ioapic, idx)))
+        .ok_or(KError::new(EINVAL))
+}
+
+fn is_nmi_source(gsi: u32) -> bool {
+    NMI_SOURCES.get().is_some_and(|sources| sources.contains(&gsi))
+}
+
+fn route(gsi: u32, vector: u8, apic_id: u32, trigger_mode: ApicTriggerMode, polarity: ApicPolarity) -> KResult<()> {
+    // redirection entries hold 8-bit destinations, larger ids need interrupt remapping
+    if !(0x20..=0xfe).contains(&vector) || apic_id > 0xff {
+        return Err(KError::new(EINVAL));
+    }
+    if is_nmi_source(gsi) {
+        return Err(KError::new(EBUSY));
+    }
+    let (ioapic, idx) = find_gsi(gsi)?;
+    ioapic.map(idx, MapInfo {
+        dest: apic_id as u8,
+        mask: false,
+        trigger_mode,
+        polarity,
+        dest_mode: DestinationMode::Physical,
+        delivery_mode: DeliveryMode::Fixed,
+        vector,
+    });
+    Ok(())
+}
+
+/// deliver `gsi` to `vector` of `cpu` and unmask it. the handler of the vector has
+/// to be installed on that cpu first. `EINVAL` for a gsi no io apic handles, a
+/// vector below 32 or a cpu out of reach of 8-bit destinations, `EBUSY` for an
+/// nmi source, `ENODEV` without io apic
+pub fn route_gsi(
+    gsi: u32,
+    vector: u8,
+    cpu: LogicalCpuId,
+    trigger_mode: ApicTriggerMode,
+    polarity: ApicPolarity,
+) -> KResult<()> {
+    route(gsi, vector, cpu.0, trigger_mode, polarity)
+}
+
+/// [`route_gsi`] for a pci interrupt line, level triggered and active low unless
+/// the source override of the isa irq a link device shares says otherwise
+pub fn route_pci_gsi(gsi: u32, vector: u8, cpu: LogicalCpuId) -> KResult<()> {
+    let over = SRC_OVERRIDES.get().and_then(|overrides| overrides.iter().find(|over| over.gsi == gsi));
+    let (trigger_mode, polarity) = match over {
+        Some(over) => Bus::Pci.resolve(over.trigger_mode, over.polarity),
+        None => Bus::Pci.resolve(TriggerMode::ConformsToSpecs, Polarity::ConformsToSpecs),
+    };
+    route_gsi(gsi, vector, cpu, trigger_mode, polarity)
+}
+
+/// mask or unmask a routed gsi
+pub fn mask_gsi(gsi: u32, mask: bool) -> KResult<()> {
+    let (ioapic, _) = find_gsi(gsi)?;
+    ioapic.set_mask(gsi, mask);
+    Ok(())
+}
+
+/// gsi isa `irq` is connected to, following the interrupt source overrides
+pub fn legacy_irq_gsi(irq: u8) -> Option<u32> {
+    let overrides = SRC_OVERRIDES.get()?;
+    match overrides.iter().find(|over| over.bus_irq == irq) {
+        Some(over) => Some(over.gsi),
+        // another isa irq took this gsi, the line is not reachable
+        None if overrides.iter().any(|over| over.gsi == u32::from(irq)) => None,
+        None => Some(irq.into()),
+    }
+}
+
+/// a programmed redirection entry
+#[derive(Clone, Copy, Debug)]
+pub struct GsiRoute {
+    pub gsi: u32,
+    pub ioapic_id: u8,
+    /// `None` for reserved delivery modes
+    pub info: Option<MapInfo>,
+}
+
+/// every redirection entry of every io apic as the hardware holds it
+pub fn routing_table() -> Vec<GsiRoute> {
+    IOAPICS.get().map_or(Vec::new(), |ioapics| {
+        ioapics.iter()
+            .flat_map(|ioapic| (0..ioapic.count).map(move |idx| GsiRoute {
+                gsi: ioapic.gsi_base + u32::from(idx),
+                ioapic_id: ioapic.id,
+                info: MapInfo::from_raw(ioapic.read(idx)),
+            }))
+            .collect()
+    })
+}
+
+/// bus an interrupt line belongs to, picks what "conforms to the bus" means
+#[derive(Clone, Copy, Debug)]
+pub enum Bus {
+    Isa,
+    Pci,
+}
+
+impl Bus {
+    fn resolve(self, trigger_mode: TriggerMode, polarity: Polarity) -> (ApicTriggerMode, ApicPolarity) {
+        let trigger_mode = match (trigger_mode, self) {
+            (TriggerMode::Edge, _) | (TriggerMode::ConformsToSpecs, Bus::Isa) => ApicTriggerMode::Edge,
+            (TriggerMode::Level, _) | (TriggerMode::ConformsToSpecs, Bus::Pci) => ApicTriggerMode::Level,
+        };
+        let polarity = match (polarity, self) {
+            (Polarity::ActiveHigh, _) | (Polarity::ConformsToSpecs, Bus::Isa) => ApicPolarity::ActiveHigh,
+            (Polarity::ActiveLow, _) | (Polarity::ConformsToSpecs, Bus::Pci) => ApicPolarity::ActiveLow,
+        };
+        (trigger_mode, polarity)
+    }
 }
 #[repr(u8)]
 #[derive(Clone, Copy, Debug)]
@@ -139,6 +311,20 @@ pub enum DeliveryMode {
     ExtInt = 0b111,
 }
 
+impl DeliveryMode {
+    fn from_bits(bits: u8) -> Option<Self> {
+        Some(match bits {
+            0b000 => DeliveryMode::Fixed,
+            0b001 => DeliveryMode::LowestPriority,
+            0b010 => DeliveryMode::Smi,
+            0b100 => DeliveryMode::Nmi,
+            0b101 => DeliveryMode::Init,
+            0b111 => DeliveryMode::ExtInt,
+            _ => return None,
+        })
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 pub struct MapInfo {
     pub dest: u8,
@@ -152,8 +338,11 @@ pub struct MapInfo {
 
 impl MapInfo {
     pub fn as_raw(&self) -> u64 {
-        assert!(self.vector >= 0x20);
-        assert!(self.vector <= 0xFE);
+        // the vector is ignored by nmi, smi and init
+        if let DeliveryMode::Fixed | DeliveryMode::LowestPriority = self.delivery_mode {
+            assert!(self.vector >= 0x20);
+            assert!(self.vector <= 0xFE);
+        }
 
         // TODO: Check for reserved fields.
 
@@ -165,6 +354,18 @@ impl MapInfo {
             | ((self.delivery_mode as u64) << 8)
             | u64::from(self.vector)
     }
+
+    pub fn from_raw(raw: u64) -> Option<Self> {
+        Some(Self {
+            dest: (raw >> 56) as u8,
+            mask: raw & (1 << 16) != 0,
+            trigger_mode: if raw & (1 << 15) != 0 { ApicTriggerMode::Level } else { ApicTriggerMode::Edge },
+            polarity: if raw & (1 << 13) != 0 { ApicPolarity::ActiveLow } else { ApicPolarity::ActiveHigh },
+            dest_mode: if raw & (1 << 11) != 0 { DestinationMode::Logical } else { DestinationMode::Physical },
+            delivery_mode: DeliveryMode::from_bits((raw >> 8) as u8 & 0b111)?,
+            vector: raw as u8,
+        })
+    }
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -190,99 +391,126 @@ pub enum Polarity {
     ActiveLow,
 }
 
+// mps inti flags of source overrides and nmi sources, `None` for reserved encodings
+fn parse_inti_flags(flags: u16) -> Option<(TriggerMode, Polarity)> {
+    let polarity = match (flags & 0x0003) as u8 {
+        0b00 => Polarity::ConformsToSpecs,
+        0b01 => Polarity::ActiveHigh,
+        0b10 => return None, // reserved
+        0b11 => Polarity::ActiveLow,
+        _ => unreachable!(),
+    };
+    let trigger_mode = match ((flags & 0x000C) >> 2) as u8 {
+        0b00 => TriggerMode::ConformsToSpecs,
+        0b01 => TriggerMode::Edge,
+        0b10 => return None, // reserved
+        0b11 => TriggerMode::Level,
+        _ => unreachable!(),
+    };
+    Some((trigger_mode, polarity))
+}
+
+unsafe fn io_apic_initcall(_: &InitCpuArg) {
+    let arg = kernel_arg();
+    if arg.acpi.io_apic_count == 0 {
+        warnhart!("firmware reports no io apic, legacy irqs go through the 8259 pic");
+        pic::route_legacy_irqs();
+        // pic interrupts reach the bsp as external interrupts on lint0
+        LOCAL_APIC.set_lvt_lint0(LVT_DELIVERY_EXTINT);
+        return;
+    }
+    setup_io_apic(
+        arg.acpi.io_apics(arg.phys_mem_mapped_addr),
+        arg.acpi.interrupt_src_overrides(arg.phys_mem_mapped_addr),
+        arg.acpi.nmi_sources(arg.phys_mem_mapped_addr)
+    );
+}
+initcall!(device, Bsp, io_apic_initcall, order = 0);
+
 pub fn setup_io_apic(
     madt_io_apics: &[MadtIoApic],
-    madt_src_overrides: &[MadtInterruptSrcOverride]
+    madt_src_overrides: &[MadtInterruptSrcOverride],
+    madt_nmi_sources: &[MadtNmiSource]
 ) {
-    let mut ioapics = IOAPICS.inner_exclusive_mut();
-    let mut overrides = SRC_OVERRIDES.inner_exclusive_mut();
+    let mut ioapics = Vec::new();
+    let mut overrides = Vec::new();
     let bsp_lapic_id = unsafe { LOCAL_APIC.id() };
+    // redirection entries hold 8-bit destinations, larger ids need interrupt remapping
+    assert!(bsp_lapic_id <= 0xff, "bsp apic id {} can not receive io apic interrupts", bsp_lapic_id);
 
     for entry in madt_io_apics {
-        let ioapic = IoApic::new(entry.address, entry.gsi_base);
+        let ioapic = match IoApic::new(u64::from(entry.address), entry.gsi_base) {
+            Ok(ioapic) => ioapic,
+            Err(err) => {
+                warnhart!("io apic {} at {:#x} can not be mapped: {:?}, ignored", entry.id, entry.address, err);
+                continue;
+            }
+        };
         assert_eq!(
-            ioapic.regs.lock().id(),
+            ioapic.id,
             entry.id,
             "mismatched ACPI MADT I/O APIC ID, and the ID reported by the I/O APIC"
         );
+        if let Some(other) = ioapics.iter().find(|other: &&IoApic| {
+            ioapic.gsi_base < other.gsi_base + u32::from(other.count) && other.gsi_base < ioapic.gsi_base + u32::from(ioapic.count)
+        }) {
+            warnhart!("io apic {} gsis {}.. overlap the io apic at gsi {}, ignored", entry.id, entry.gsi_base, other.gsi_base);
+            continue;
+        }
+        infohart!("io apic {}: gsi {}..{}", entry.id, ioapic.gsi_base, ioapic.gsi_base + u32::from(ioapic.count));
+        // firmware may leave entries unmasked with stale vectors
+        ioapic.mask_all();
         ioapics.push(ioapic);
     }
 
     for entry in madt_src_overrides {
-        let flags = entry.flags;
-
-        overrides.push(Override {
-            bus_irq: entry.irq_source,
-            gsi: entry.gsi,
-            polarity: match (flags & 0x0003) as u8 {
-                0b00 => Polarity::ConformsToSpecs,
-                0b01 => Polarity::ActiveHigh,
-                0b10 => continue, // reserved
-                0b11 => Polarity::ActiveLow,
-
-                _ => unreachable!(),
-            },
-            trigger_mode: match ((flags & 0x000C) >> 2) as u8 {
-                0b00 => TriggerMode::ConformsToSpecs,
-                0b01 => TriggerMode::Edge,
-                0b10 => continue, // reserved
-                0b11 => TriggerMode::Level,
-                _ => unreachable!(),
-            }
-        });
+        let Some((trigger_mode, polarity)) = parse_inti_flags(entry.flags) else { continue };
+        overrides.push(Override { bus_irq: entry.irq_source, gsi: entry.gsi, trigger_mode, polarity });
     }
 
-    infohart!("IOAPIC count: {}, INTERRUPT_SRC_OVERRIDE count: {}", ioapics.len(), overrides.len());
-
-    for legacy_irq in 0..16 {
-        let (gsi, trigger_mode, polarity) = match overrides.iter()
-            .find(|over| over.bus_irq == legacy_irq)
-        {
-            Some(over) => (over.gsi, over.trigger_mode, over.polarity),
-            None => {
-                if overrides.iter().any(|over|
-                    over.gsi == u32::from(legacy_irq) && over.bus_irq != legacy_irq
-                ) && !overrides.iter().any(|over|
-                    over.bus_irq == legacy_irq
-                ) {
-                    // there's an IRQ conflict, making this legacy IRQ inaccessible.
-                    continue;
-                }
-                (legacy_irq.into(), TriggerMode::ConformsToSpecs, Polarity::ConformsToSpecs)
-            }
-        };
+    infohart!("IOAPIC count: {}, INTERRUPT_SRC_OVERRIDE count: {}, NMI_SOURCE count: {}",
+        ioapics.len(), overrides.len(), madt_nmi_sources.len());
+    IOAPICS.call_once(|| ioapics);
+    let overrides = SRC_OVERRIDES.call_once(|| overrides);
 
-        let target_ioapic = match ioapics.iter().find(|ia|
-            gsi >= ia.gsi_base && gsi < ia.gsi_base + u32::from(ia.count)
-        ) {
-            Some(v) => v,
-            None => {
-                infohart!("Unable to find a suitable APIC for legacy IRQ {} (GSI {}). It will not be mapped.", legacy_irq, gsi);
-                continue;
-            }
+    let mut nmi_sources = Vec::new();
+    for entry in madt_nmi_sources {
+        let Some((trigger_mode, polarity)) = parse_inti_flags(entry.flags) else { continue };
+        let Ok((ioapic, idx)) = find_gsi(entry.gsi) else {
+            warnhart!("no io apic handles nmi source gsi {}", entry.gsi);
+            continue;
         };
-
-        let redir_tbl_index = (gsi - target_ioapic.gsi_base) as u8;
-
-        let map_info = MapInfo {
-            // only send to the BSP
+        // nmi sources sit on isa or motherboard lines
+        let (trigger_mode, polarity) = Bus::Isa.resolve(trigger_mode, polarity);
+        ioapic.map(idx, MapInfo {
             dest: bsp_lapic_id as u8,
-            dest_mode: DestinationMode::Physical,
-            delivery_mode: DeliveryMode::Fixed,
             mask: false,
-            polarity: match polarity {
-                Polarity::ActiveHigh => ApicPolarity::ActiveHigh,
-                Polarity::ActiveLow => ApicPolarity::ActiveLow,
-                Polarity::ConformsToSpecs => ApicPolarity::ActiveHigh,
-            },
-            trigger_mode: match trigger_mode {
-                TriggerMode::Edge => ApicTriggerMode::Edge,
-                TriggerMode::Level => ApicTriggerMode::Level,
-                TriggerMode::ConformsToSpecs => ApicTriggerMode::Edge,
-            },
-            vector: 32 + legacy_irq,
-        };
+            trigger_mode,
+            polarity,
+            dest_mode: DestinationMode::Physical,
+            delivery_mode: DeliveryMode::Nmi,
+            vector: 0,
+        });
+        nmi_sources.push(entry.gsi);
+    }
+    NMI_SOURCES.call_once(|| nmi_sources);
 
-        target_ioapic.map(redir_tbl_index, map_info);
+    for legacy_irq in 0..16 {
+        // there's an IRQ conflict, making this legacy IRQ inaccessible.
+        let Some(gsi) = legacy_irq_gsi(legacy_irq) else { continue };
+        let (trigger_mode, polarity) = overrides.iter()
+            .find(|over| over.bus_irq == legacy_irq)
+            .map_or((TriggerMode::ConformsToSpecs, Polarity::ConformsToSpecs), |over| (over.trigger_mode, over.polarity));
+        let (trigger_mode, polarity) = Bus::Isa.resolve(trigger_mode, polarity);
+
+        if is_nmi_source(gsi) {
+            warnhart!("legacy IRQ {} (GSI {}) is an nmi source, it will not be mapped.", legacy_irq, gsi);
+            continue;
+        }
+        // only send to the BSP
+        let routed = route(gsi, 32 + legacy_irq, bsp_lapic_id, trigger_mode, polarity);
+        if routed.is_err() {
+            infohart!("Unable to find a suitable APIC for legacy IRQ {} (GSI {}). It will not be mapped.", legacy_irq, gsi);
+        }
     }
-}
\ No newline at end of file
+}
@@ -1,17 +1,49 @@
 use core::ptr::{read_volatile, write_volatile};
 use core::fmt::Write;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use libvdso::error::{EINVAL, KError, KResult};
 use log::info;
-use x86_64::{instructions::port::{Port, PortGeneric, ReadWriteAccess}, registers::model_specific::Msr};
 
-use crate::cpu::LogicalCpuId;
+use crate::cmdline::cmdline_value;
+use crate::config::{config, MAX_CPUS};
+use crate::cpu::{LogicalCpuId, PercpuBlock};
 use crate::interrupt::LAPIC_TIMER_HANDLER_IDT;
-use crate::{arch_spec::cpuid::cpuid, arch_spec::msr::{rdmsr, wrmsr}, infohart};
-use crate::arch_spec::port::{inb, outb};
+use crate::{arch_spec::cpuid::{cpu_features, phys_addr_bits, CpuFeatures}, arch_spec::msr::Msr, infohart, warnhart};
+use crate::device::{pic, pit};
+use crate::device::tsc::tsc_invariant;
+use crate::mem::kvm::kvm_map_mmio;
+use crate::mem::PAGE_SIZE;
+use shared::print_panic::PrintPanic;
+use x86_64::PhysAddr;
+use shared::arg::AcpiSettings;
 use crate::IpiKind;
+use crate::initcall;
+use crate::initcall::{kernel_arg, InitCpuArg};
 
 
-const IA32_APIC_BASE_MSR: u32 = 0x1B;
 const IA32_APIC_BASE_MSR_ENABLE: u64 = 0x800;
+const IA32_APIC_BASE_MSR_X2APIC: u64 = 0x400;
+// base address field, bits 12 up to maxphyaddr
+const IA32_APIC_BASE_ADDR_MASK: u64 = !0xfff;
+
+const LVT_TIMER_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+// divide configuration register, divide by 16
+const TIMER_DIV_16: u32 = 0b0011;
+const TIMER_CALIBRATE_MS: u64 = 10;
+const TIMER_CALIBRATE_ROUNDS: usize = 3;
+// range of the system tick rate
+pub const MIN_TICK_HZ: u32 = 10;
+pub const MAX_TICK_HZ: u32 = 1000;
+
+// lapic timer ticks per second at divide 16 per cpu, 0 if it does not count
+const ZERO_HZ: AtomicU64 = AtomicU64::new(0);
+static TIMER_HZ: [AtomicU64; MAX_CPUS] = [ZERO_HZ; MAX_CPUS];
+// system tick rate, bumping TICK_GEN makes every cpu reprogram its timer on its next tick
+static TICK_HZ: AtomicU32 = AtomicU32::new(0);
+static TICK_GEN: AtomicU32 = AtomicU32::new(0);
+const ZERO_GEN: AtomicU32 = AtomicU32::new(0);
+static APPLIED_GEN: [AtomicU32; MAX_CPUS] = [ZERO_GEN; MAX_CPUS];
 
 pub static mut LOCAL_APIC: LocalApic = LocalApic {
     base: 0,
@@ -20,6 +52,7 @@ pub static mut LOCAL_APIC: LocalApic = LocalApic {
 
 #[derive(Clone, Copy)]
 pub struct LocalApic {
+    // virtual address of the register page in the kvm mmio region
     base: u64,
     pub(crate) x2: bool,
 }
@@ -30,26 +63,45 @@ impl LocalApic {
         self.x2 = x2;
     }
 
+    // xapic registers only, x2apic mode reaches them through msrs
     unsafe fn read(&self, reg: u32) -> u32 {
-        read_volatile(((self.base & 0xffffffff) as u32 + reg) as *const u32)
+        debug_assert!(!self.x2 && self.base != 0 && (reg as usize) < PAGE_SIZE, "bad xapic read of {:#x}", reg);
+        read_volatile((self.base + u64::from(reg)) as *const u32)
     }
 
     unsafe fn write(&mut self, reg: u32, value: u32) {
-        write_volatile(((self.base & 0xffffffff) as u32 + reg) as *mut u32, value);
+        debug_assert!(!self.x2 && self.base != 0 && (reg as usize) < PAGE_SIZE, "bad xapic write of {:#x}", reg);
+        write_volatile((self.base + u64::from(reg)) as *mut u32, value);
     }
 
     
+    /// full 32-bit id in x2apic mode, 8-bit id in bits 24..32 of the register otherwise
     pub fn id(&self) -> u32 {
         if self.x2 {
-            unsafe { rdmsr(0x802) as u32 }
+            unsafe { Msr::X2APIC_ID.read() as u32 }
+        } else {
+            unsafe { self.read(0x20) >> 24 }
+        }
+    }
+
+    /// whether `apic_id` can be targeted by an ipi in current mode
+    pub fn addressable(&self, apic_id: u32) -> bool {
+        self.x2 || apic_id <= 0xff
+    }
+
+    // destination field of icr, bits 32..64 in x2apic mode, bits 56..64 in xapic mode
+    fn icr_destination(&self, apic_id: u32) -> u64 {
+        debug_assert!(self.addressable(apic_id), "apic id {} is not addressable in xapic mode", apic_id);
+        if self.x2 {
+            u64::from(apic_id) << 32
         } else {
-            unsafe { self.read(0x20) }
+            u64::from(apic_id & 0xff) << 56
         }
     }
 
     pub fn version(&self) -> u32 {
         if self.x2 {
-            unsafe { rdmsr(0x803) as u32 }
+            unsafe { Msr::X2APIC_VERSION.read() as u32 }
         } else {
             unsafe { self.read(0x30) }
         }
@@ -57,7 +109,7 @@ impl LocalApic {
 
     pub fn icr(&self) -> u64 {
         if self.x2 {
-            unsafe { rdmsr(0x830) }
+            unsafe { Msr::X2APIC_ICR.read() }
         } else {
             unsafe { (self.read(0x310) as u64) << 32 | self.read(0x300) as u64 }
         }
@@ -66,7 +118,7 @@ impl LocalApic {
     pub fn set_icr(&mut self, value: u64) {
         if self.x2 {
             unsafe {
-                wrmsr(0x830, value);
+                Msr::X2APIC_ICR.write(value);
             }
         } else {
             unsafe {
@@ -84,33 +136,65 @@ impl LocalApic {
     }
 
     pub fn ipi(&mut self, apic_id: u32, kind: IpiKind) {
-        let mut icr = 0x40 | kind as u64;
+        let icr = 0x40 | kind as u64;
+        self.set_icr(icr | self.icr_destination(apic_id));
+    }
+    pub fn ipi_nmi(&mut self, apic_id: u32) {
+        self.set_icr(self.icr_destination(apic_id) | (1 << 14) | (0b100 << 8));
+    }
+    /// INIT, level assert
+    pub fn ipi_init(&mut self, apic_id: u32) {
+        self.set_icr(self.icr_destination(apic_id) | 0x4500);
+    }
+    /// STARTUP, ap starts real mode at `vector << 12`
+    pub fn ipi_startup(&mut self, apic_id: u32, vector: u8) {
+        self.set_icr(self.icr_destination(apic_id) | 0x4600 | u64::from(vector));
+    }
+
+    /// Spurious Interrupt Vector Register
+    pub unsafe fn svr(&self) -> u32 {
         if self.x2 {
-            icr |= u64::from(apic_id) << 32;
+            Msr::X2APIC_SVR.read() as u32
         } else {
-            icr |= u64::from(apic_id) << 56;
+            self.read(0xf0)
         }
-        self.set_icr(icr);
     }
-    pub fn ipi_nmi(&mut self, apic_id: u32) {
-        let shift = if self.x2 { 32 } else { 56 };
-        self.set_icr((u64::from(apic_id) << shift) | (1 << 14) | (0b100 << 8));
+    pub unsafe fn set_svr(&mut self, value: u32) {
+        if self.x2 {
+            Msr::X2APIC_SVR.write(u64::from(value));
+        } else {
+            self.write(0xf0, value);
+        }
     }
 
     pub unsafe fn eoi(&mut self) {
         if self.x2 {
-            wrmsr(0x80B, 0);
+            Msr::X2APIC_EOI.write(0);
         } else {
             self.write(0xB0, 0);
         }
     }
+    /// highest vector in service, the interrupt being handled if its handler did not eoi yet
+    pub unsafe fn highest_in_service(&mut self) -> Option<u8> {
+        for index in (0..8).rev() {
+            let bits = if self.x2 {
+                Msr::X2APIC_ISR[index].read() as u32
+            } else {
+                self.read(0x100 + 0x10 * index as u32)
+            };
+            if bits != 0 {
+                return Some((index as u32 * 32 + 31 - bits.leading_zeros()) as u8);
+            }
+        }
+        None
+    }
     /// Reads the Error Status Register.
     pub unsafe fn esr(&mut self) -> u32 {
         if self.x2 {
             // update the ESR to the current state of the local apic.
-            wrmsr(0x828, 0);
+            Msr::X2APIC_ESR.write(0);
             // read the updated value
-            rdmsr(0x828) as u32
+            Msr::X2APIC_ESR.read() as u32
         } else {
             self.write(0x280, 0);
             self.read(0x280)
@@ -118,96 +202,145 @@ impl LocalApic {
     }
     pub unsafe fn lvt_timer(&mut self) -> u32 {
         if self.x2 {
-            rdmsr(0x832) as u32
+            Msr::X2APIC_LVT_TIMER.read() as u32
         } else {
             self.read(0x320)
         }
     }
     pub unsafe fn set_lvt_timer(&mut self, value: u32) {
         if self.x2 {
-            wrmsr(0x832, u64::from(value));
+            Msr::X2APIC_LVT_TIMER.write(u64::from(value));
         } else {
             self.write(0x320, value);
         }
     }
     pub unsafe fn init_count(&mut self) -> u32 {
         if self.x2 {
-            rdmsr(0x838) as u32
+            Msr::X2APIC_TIMER_INIT_COUNT.read() as u32
         } else {
             self.read(0x380)
         }
     }
     pub unsafe fn set_init_count(&mut self, initial_count: u32) {
         if self.x2 {
-            wrmsr(0x838, u64::from(initial_count));
+            Msr::X2APIC_TIMER_INIT_COUNT.write(u64::from(initial_count));
         } else {
             self.write(0x380, initial_count);
         }
     }
     pub unsafe fn cur_count(&mut self) -> u32 {
         if self.x2 {
-            rdmsr(0x839) as u32
+            Msr::X2APIC_TIMER_CURRENT_COUNT.read() as u32
         } else {
             self.read(0x390)
         }
     }
     pub unsafe fn div_conf(&mut self) -> u32 {
         if self.x2 {
-            rdmsr(0x83E) as u32
+            Msr::X2APIC_TIMER_DIV_CONF.read() as u32
         } else {
             self.read(0x3E0)
         }
     }
     pub unsafe fn set_div_conf(&mut self, div_conf: u32) {
         if self.x2 {
-            wrmsr(0x83E, u64::from(div_conf));
+            Msr::X2APIC_TIMER_DIV_CONF.write(u64::from(div_conf));
         } else {
             self.write(0x3E0, div_conf);
         }
     }
     pub unsafe fn lvt_error(&mut self) -> u32 {
         if self.x2 {
-            rdmsr(0x837) as u32
+            Msr::X2APIC_LVT_ERROR.read() as u32
         } else {
             self.read(0x370)
         }
     }
     pub unsafe fn set_lvt_error(&mut self, lvt_error: u32) {
         if self.x2 {
-            wrmsr(0x837, u64::from(lvt_error));
+            Msr::X2APIC_LVT_ERROR.write(u64::from(lvt_error));
         } else {
             self.write(0x370, lvt_error);
         }
     }
+    pub unsafe fn set_lvt_pmc(&mut self, lvt_pmc: u32) {
+        if self.x2 {
+            Msr::X2APIC_LVT_PMC.write(u64::from(lvt_pmc));
+        } else {
+            self.write(0x340, lvt_pmc);
+        }
+    }
+    pub unsafe fn set_lvt_lint0(&mut self, lvt_lint0: u32) {
+        if self.x2 {
+            Msr::X2APIC_LVT_LINT0.write(u64::from(lvt_lint0));
+        } else {
+            self.write(0x350, lvt_lint0);
+        }
+    }
     unsafe fn setup_error_int(&mut self) {
         let vector = 49u32;
         self.set_lvt_error(vector);
     }
 }
 
+unsafe fn lapic_initcall(arg: &InitCpuArg) {
+    assert!(kernel_arg().acpi.has(AcpiSettings::LOCAL_APIC), "cpu has no local apic, interrupts and smp need one");
+    if arg.cpu_id != LogicalCpuId::BSP {
+        return setup_ap_apic(arg.cpu_id);
+    }
+    let base = Msr::IA32_APIC_BASE.read();
+    // firmware or a previous kernel may have moved the page since the bootloader looked
+    let boot_phys = kernel_arg().acpi.local_apic_phys();
+    if base & IA32_APIC_BASE_ADDR_MASK != boot_phys {
+        warnhart!("local apic moved to {:#x} since the bootloader saw it at {:#x}", base & IA32_APIC_BASE_ADDR_MASK, boot_phys);
+    }
+    setup_apic(base);
+}
+initcall!(arch, All, lapic_initcall, order = 20);
+
+// register page named by the IA32_APIC_BASE msr `apic_base`, `None` if it
+// has bits at or above the cpu's `bits` wide physical addresses
+fn apic_phys(apic_base: u64, bits: u8) -> Option<PhysAddr> {
+    let phys = apic_base & IA32_APIC_BASE_ADDR_MASK;
+    (phys >> bits == 0).then(|| PhysAddr::new(phys))
+}
+
+// every cpu sees its own local apic at the physical address of the bsp's,
+// the page the bsp mapped serves all of them
+unsafe fn setup_ap_apic(cpu_id: LogicalCpuId) {
+    assert!(LOCAL_APIC.base != 0, "cpu {} came up before the bsp mapped the local apic", cpu_id);
+    // apic mode is per cpu, follow the bsp
+    if LOCAL_APIC.x2 {
+        enable_x2apic();
+    }
+    // software enable, map spurious interrupt to dummy isr
+    LOCAL_APIC.set_svr(LOCAL_APIC.svr() | 0x100);
+    infohart!("AP LAPIC is enabled.");
+
+    setup_tick(cpu_id);
+    LOCAL_APIC.set_lvt_error(49u32);
+}
+
 /**
  * https://wiki.osdev.org/APIC_timer#Enabling_APIC_Timer
  */
-// TODO: consider x2apic
-pub unsafe fn setup_apic(apic_base: u64, cpu_id: LogicalCpuId) {
-    if cpu_id != LogicalCpuId::BSP {
-        // software enable, map spurious interrupt to dummy isr
-        LOCAL_APIC.write(0xf0, LOCAL_APIC.read(0xf0) | 0x100); // Spurious Interrupt Vector Register
-        infohart!("AP LAPIC is enabled.");
-        return;
-    }
-
+pub unsafe fn setup_apic(apic_base: u64) {
     // Hardware enable the Local APIC if it wasn't enabled
-    let mut msr = Msr::new(IA32_APIC_BASE_MSR);
-    msr.write(apic_base | IA32_APIC_BASE_MSR_ENABLE);
+    Msr::IA32_APIC_BASE.write(apic_base | IA32_APIC_BASE_MSR_ENABLE);
 
-    LOCAL_APIC.init(apic_base & 0xffff0000, cpuid()
-        .get_feature_info()
-        .map_or(false, |feature_info| feature_info.has_x2apic()));
+    let x2 = cpu_features().contains(CpuFeatures::X2APIC);
+    if x2 {
+        enable_x2apic();
+    }
+    // the register page may sit anywhere in the physical address space, not
+    // only below 4 GiB where firmware usually leaves it
+    let phys = apic_phys(apic_base, phys_addr_bits()).or_panic("local apic base is past the physical address width");
+    let base = kvm_map_mmio(phys, PAGE_SIZE).or_panic("failed to map local apic registers");
+    LOCAL_APIC.init(base.as_u64(), x2);
+    infohart!("local apic in {} mode, id {}", if x2 { "x2apic" } else { "xapic" }, LOCAL_APIC.id());
 
-    // disable 8259 PIC
-    outb(0x21, 0xff);
-    outb(0xa1, 0xff);
+    // disable 8259 PIC, mask every line. the pics stay owned by us
+    pic::mask_all();
 
     // initialize LAPIC to a well known state
     // flat mode
@@ -222,49 +355,115 @@ pub unsafe fn setup_apic(apic_base: u64, cpu_id: LogicalCpuId) {
     //LOCAL_APIC.write(0x80, 0); // Task Priority Register
 
     // software enable, map spurious interrupt to dummy isr
-    LOCAL_APIC.write(0xf0, LOCAL_APIC.read(0xf0) | 0x100); // Spurious Interrupt Vector Register
-
-    // map APIC timer to an interrupt, and by that enable it in one-shot mode
-    //LOCAL_APIC.set_lvt_timer(LAPIC_TIMER_HANDLER_IDT); // LVT Timer Register
-    //// set up divide value to 1
-    //LOCAL_APIC.set_div_conf(0xb); // Divide Configuration Register
-//
-    //// initialize PIT Ch 2 in one-shot mode
-    //// PIT has fixed frequency 1193182 Hz, so let PIT ch2 tick 10ms.
-    //outb(0x61, (inb(0x61) & 0xfd) | 1);
-    //outb(0x43, 0b10110010);
-//
-    //const FREQ: u32 = 1193182 / 100;
-//
-    //outb(0x42, (FREQ & 0xff) as u8);
-    //inb(0x60);
-    //outb(0x42, ((FREQ >> 8) & 0xff) as u8);
-
-    // reset PIT one-shot counter (start counting)
-    //let pit2_gate = inb(0x61) & 0xfe;
-    //outb(0x61, pit2_gate); // gate low
-    //outb(0x61, pit2_gate | 1); // gate high
-//
-    //// reset APIC timer
-    //LOCAL_APIC.set_init_count(0xffffffff /* = -1 */); // Initial Count Register (for Timer)
-//
-    //// wait until PIT counter reaches 0
-    //let mut port_pit2_gate: PortGeneric<u8, ReadWriteAccess> = Port::new(0x61);
-    //while port_pit2_gate.read() & 0x20 == 0 { }
-    //// stop APIC timer
-    //LOCAL_APIC.set_lvt_timer(0x10000); // LVT Timer Register
-//
-    //// 0x390 = Current Count Register (for Timer)
-    //let lapic_ticks_in_10_ms: u32 = 0xffffffff - LOCAL_APIC.read(0x390);
-//
-    //// apply freq
-    //// 0x2000 = periodic mode
-    //LOCAL_APIC.set_lvt_timer(LAPIC_TIMER_HANDLER_IDT | 0x20000); // LVT Timer Register
-    //LOCAL_APIC.set_div_conf(0xb); // Divide Configuration Register
-    //// let apic timer send irq every 1ms
-    //LOCAL_APIC.set_init_count(lapic_ticks_in_10_ms / 10); // Initial Count Register (for Timer)
+    LOCAL_APIC.set_svr(LOCAL_APIC.svr() | 0x100);
+
+    TICK_HZ.store(config().tick_hz as u32, Ordering::SeqCst);
+    setup_tick(LogicalCpuId::BSP);
 
     LOCAL_APIC.set_lvt_error(49u32);
+}
+
+// timer ticks per second at divide 16 counted in a few pit windows, the timer is left stopped
+unsafe fn calibrate_timer() -> u64 {
+    LOCAL_APIC.set_lvt_timer(LAPIC_TIMER_HANDLER_IDT | LVT_TIMER_MASKED);
+    LOCAL_APIC.set_div_conf(TIMER_DIV_16);
+    // one-shot from the top, counts down for tens of seconds at any bus clock
+    LOCAL_APIC.set_init_count(u32::MAX);
+    let hz = pit::calibrate(TIMER_CALIBRATE_MS, TIMER_CALIBRATE_ROUNDS, || {
+        u64::from(u32::MAX - unsafe { LOCAL_APIC.cur_count() })
+    });
+    LOCAL_APIC.set_init_count(0);
+    hz
+}
+
+// periodic lapic timer of the current cpu. the bsp falls back to pit channel 0
+// when the lapic timer does not count (some hypervisors) or `timer=pit` is given,
+// an ap then runs without a tick. with invariant tsc the bus clock is taken as
+// constant as well and aps share the bsp's calibration, otherwise each measures its own
+unsafe fn setup_tick(cpu_id: LogicalCpuId) {
+    let bsp_hz = TIMER_HZ[LogicalCpuId::BSP.0 as usize].load(Ordering::SeqCst);
+    let hz = match cmdline_value("timer") {
+        Some("pit") => 0,
+        _ if cpu_id != LogicalCpuId::BSP && tsc_invariant() && bsp_hz != 0 => bsp_hz,
+        _ => calibrate_timer(),
+    };
+    TIMER_HZ[cpu_id.0 as usize].store(hz, Ordering::SeqCst);
+
+    let tick_hz = tick_hz();
+    APPLIED_GEN[cpu_id.0 as usize].store(TICK_GEN.load(Ordering::SeqCst), Ordering::SeqCst);
+    if !program_tick(hz, tick_hz) {
+        if cpu_id == LogicalCpuId::BSP {
+            let actual = pit::set_periodic(tick_hz);
+            infohart!("system tick: pit at {} hz, lapic timer {} hz", actual, hz);
+        } else {
+            warnhart!("system tick: none, lapic timer {} hz", hz);
+        }
+        return;
+    }
+    if cpu_id == LogicalCpuId::BSP {
+        // irq 0 stays routed, it must not tick as well
+        pit::stop();
+    }
+    infohart!("system tick: lapic timer at {} hz, {}.{:03} MHz", tick_hz, hz / 1_000_000, hz / 1000 % 1000);
+}
 
-    //infohart!("BSP LAPIC initialized, CPU bus frequency: {} Hz", lapic_ticks_in_10_ms * 100);
-}
\ No newline at end of file
+// periodic lapic timer at `tick_hz` from a timer counting `hz`, false if the count does not fit
+unsafe fn program_tick(hz: u64, tick_hz: u32) -> bool {
+    let count = hz / u64::from(tick_hz);
+    if count == 0 || count > u64::from(u32::MAX) {
+        return false;
+    }
+    LOCAL_APIC.set_lvt_timer(LAPIC_TIMER_HANDLER_IDT | LVT_TIMER_PERIODIC);
+    LOCAL_APIC.set_init_count(count as u32);
+    true
+}
+
+/// lapic timer frequency of `cpu_id` at divide 16, 0 if it does not count or the pit ticks instead
+pub fn lapic_timer_hz(cpu_id: LogicalCpuId) -> u64 {
+    TIMER_HZ[cpu_id.0 as usize].load(Ordering::Relaxed)
+}
+
+/// system tick rate, the scheduler quantum counts these ticks
+pub fn tick_hz() -> u32 {
+    TICK_HZ.load(Ordering::Relaxed)
+}
+
+/// change the tick rate of every cpu, returns the previous one. a cpu on the
+/// lapic timer picks the rate up on its next tick, the pit is reprogrammed right away
+pub fn set_tick_hz(hz: u32) -> KResult<u32> {
+    if !(MIN_TICK_HZ..=MAX_TICK_HZ).contains(&hz) {
+        return Err(KError::new(EINVAL));
+    }
+    let prev = TICK_HZ.swap(hz, Ordering::SeqCst);
+    TICK_GEN.fetch_add(1, Ordering::SeqCst);
+    if lapic_timer_hz(LogicalCpuId::BSP) == 0 {
+        pit::set_periodic(hz);
+    }
+    Ok(prev)
+}
+
+/// called by the lapic timer handler, reprograms the timer after [`set_tick_hz`]
+pub unsafe fn retune_tick() {
+    let cpu_id = PercpuBlock::current().cpu_id;
+    let gen = TICK_GEN.load(Ordering::Relaxed);
+    if APPLIED_GEN[cpu_id.0 as usize].swap(gen, Ordering::Relaxed) == gen {
+        return;
+    }
+    if !program_tick(lapic_timer_hz(cpu_id), tick_hz()) {
+        warnhart!("system tick: {} hz does not fit the lapic timer", tick_hz());
+    }
+}
+
+// xapic must be enabled before switching to x2apic, registers are msrs afterwards
+unsafe fn enable_x2apic() {
+    Msr::IA32_APIC_BASE.update(|base| base | IA32_APIC_BASE_MSR_ENABLE | IA32_APIC_BASE_MSR_X2APIC);
+}
+
+#[test_case]
+pub(crate) fn test_apic_phys() {
+    // flag bits go, the address stays whole above 4 GiB
+    let base = 0x1_fee0_0000 | IA32_APIC_BASE_MSR_ENABLE | 0x100;
+    assert_eq!(apic_phys(base, 40), Some(PhysAddr::new(0x1_fee0_0000)));
+    assert_eq!(apic_phys(0xfee0_0000, 36), Some(PhysAddr::new(0xfee0_0000)));
+    assert_eq!(apic_phys(base, 32), None);
+}
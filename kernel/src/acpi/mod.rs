@@ -1,3 +1,6 @@
 pub mod local_apic;
 pub mod ap_startup;
+// also included by build.rs, which is the only user of some items
+#[allow(dead_code)]
+pub mod trampoline_layout;
 pub mod io_apic;
\ No newline at end of file
@@ -0,0 +1,35 @@
+/**
+ *  header layout of the ap trampoline, byte offsets from its start.
+ *
+ *  build.rs includes this file and passes every offset to nasm as a
+ *  `TRAMPOLINE_*` define, trampoline.asm places its header fields at them and
+ *  fails to assemble when they do not line up. keep it free of `use` and
+ *  crate paths so build.rs can include it.
+ */
+
+/// `jmp short` over the header
+pub const JUMP: usize = 0;
+/// set to 1 by the ap once it is in long mode
+pub const READY: usize = 8;
+// arguments of `_start_ap`, which gets a pointer to CPU_ID
+pub const CPU_ID: usize = 16;
+pub const PAGE_TABLE: usize = 24;
+pub const STACK_START: usize = 32;
+pub const STACK_END: usize = 40;
+pub const CODE: usize = 48;
+/// words holding offsets of dwords the kernel adds the load address to
+pub const RELOCS: usize = 56;
+pub const RELOC_COUNT: usize = 2;
+
+/// (nasm define, offset)
+pub const DEFINES: &[(&str, usize)] = &[
+    ("TRAMPOLINE_JUMP", JUMP),
+    ("TRAMPOLINE_READY", READY),
+    ("TRAMPOLINE_CPU_ID", CPU_ID),
+    ("TRAMPOLINE_PAGE_TABLE", PAGE_TABLE),
+    ("TRAMPOLINE_STACK_START", STACK_START),
+    ("TRAMPOLINE_STACK_END", STACK_END),
+    ("TRAMPOLINE_CODE", CODE),
+    ("TRAMPOLINE_RELOCS", RELOCS),
+    ("TRAMPOLINE_RELOC_COUNT", RELOC_COUNT),
+];
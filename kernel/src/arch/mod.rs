@@ -0,0 +1,55 @@
+// architecture abstraction.
+// generic code (context, scheduler, fs) should go through `CurrentArch` instead of
+// reaching into `arch_spec` or `x86_64` crate directly, so another architecture
+// only has to provide these hooks.
+
+#[cfg(target_arch = "x86_64")]
+mod x86_64;
+#[cfg(target_arch = "x86_64")]
+pub use self::x86_64::X86_64 as CurrentArch;
+
+pub trait ArchInterrupts {
+    unsafe fn disable_interrupts();
+    unsafe fn enable_interrupts();
+    fn interrupts_enabled() -> bool;
+    /// enable interrupts and wait for the next one atomically
+    unsafe fn enable_interrupts_and_halt();
+    /// enable interrupts and give pending ones a chance to be delivered
+    unsafe fn enable_interrupts_and_nop();
+    /// stop this cpu until next interrupt
+    fn halt();
+}
+
+pub trait ArchPaging {
+    /// switch to top level page table at `phys_addr`, flushes non-global tlb entries
+    unsafe fn switch_page_table(phys_addr: u64);
+}
+
+pub trait ArchTimer {
+    /// free running per-cpu cycle counter
+    fn timestamp() -> u64;
+}
+
+/// user registers saved on kernel entry that generic code needs to touch,
+/// implemented by the interrupt stack of the architecture
+pub trait ArchContextRegs {
+    /// reset to a fresh entry into user mode, interrupts enabled
+    fn init(&mut self);
+    fn instr_pointer(&self) -> usize;
+    fn set_instr_pointer(&mut self, ip: usize);
+    fn stack_pointer(&self) -> usize;
+    fn set_stack_pointer(&mut self, sp: usize);
+    /// first argument register of the entry point
+    fn set_entry_arg(&mut self, arg: usize);
+    /// syscall number and its five arguments
+    fn syscall_args(&self) -> [usize; 6];
+    /// return value register of syscall
+    fn set_syscall_ret(&mut self, ret: usize);
+}
+
+/// halt this cpu forever
+pub fn halt_loop() -> ! {
+    loop {
+        CurrentArch::halt();
+    }
+}
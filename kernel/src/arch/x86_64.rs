@@ -0,0 +1,88 @@
+use core::arch::asm;
+use x86_64::instructions::{self, interrupts};
+use x86_64::registers::control::{Cr3, Cr3Flags};
+use x86_64::structures::paging::PhysFrame;
+use x86_64::PhysAddr;
+use x86_64::registers::rflags::RFlags;
+use shared::gdt::{USER_CODE_SELECTOR, USER_DATA_SELECTOR};
+use crate::syscall::InterruptStack;
+use super::{ArchContextRegs, ArchInterrupts, ArchPaging, ArchTimer};
+
+pub struct X86_64;
+
+impl ArchContextRegs for InterruptStack {
+    fn init(&mut self) {
+        self.iret.rflags = RFlags::INTERRUPT_FLAG.bits() as usize;
+        self.iret.cs = USER_CODE_SELECTOR as usize;
+        self.iret.ss = USER_DATA_SELECTOR as usize;
+    }
+    fn instr_pointer(&self) -> usize {
+        self.iret.rip
+    }
+    fn set_instr_pointer(&mut self, ip: usize) {
+        self.iret.rip = ip;
+    }
+    fn stack_pointer(&self) -> usize {
+        self.iret.rsp
+    }
+    fn set_stack_pointer(&mut self, sp: usize) {
+        self.iret.rsp = sp;
+    }
+    fn set_entry_arg(&mut self, arg: usize) {
+        self.scratch.rdi = arg;
+    }
+    fn syscall_args(&self) -> [usize; 6] {
+        let scratch = &self.scratch;
+        [scratch.rax, scratch.rdi, scratch.rsi, scratch.rdx, scratch.r10, scratch.r8]
+    }
+    fn set_syscall_ret(&mut self, ret: usize) {
+        self.scratch.rax = ret;
+    }
+}
+
+impl ArchInterrupts for X86_64 {
+    #[inline(always)]
+    unsafe fn disable_interrupts() {
+        interrupts::disable()
+    }
+
+    #[inline(always)]
+    unsafe fn enable_interrupts() {
+        interrupts::enable()
+    }
+
+    #[inline(always)]
+    fn interrupts_enabled() -> bool {
+        interrupts::are_enabled()
+    }
+
+    #[inline(always)]
+    unsafe fn enable_interrupts_and_halt() {
+        // sti only takes effect after next instruction, so no interrupt can slip in between
+        asm!("sti; hlt", options(nomem, nostack));
+    }
+
+    #[inline(always)]
+    unsafe fn enable_interrupts_and_nop() {
+        asm!("sti; nop", options(nomem, nostack));
+    }
+
+    #[inline(always)]
+    fn halt() {
+        instructions::hlt()
+    }
+}
+
+impl ArchPaging for X86_64 {
+    #[inline(always)]
+    unsafe fn switch_page_table(phys_addr: u64) {
+        Cr3::write(PhysFrame::containing_address(PhysAddr::new(phys_addr)), Cr3Flags::empty())
+    }
+}
+
+impl ArchTimer for X86_64 {
+    #[inline(always)]
+    fn timestamp() -> u64 {
+        unsafe { core::arch::x86_64::_rdtsc() }
+    }
+}
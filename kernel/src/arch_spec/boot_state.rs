@@ -0,0 +1,85 @@
+use core::fmt;
+use log::error;
+use x86_64::instructions::segmentation::{Segment, CS, DS, SS};
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr3, Cr4, Cr4Flags};
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use shared::arg::KernelArg;
+use shared::gdt::{KERNEL_CODE_SELECTOR, KERNEL_DATA_SELECTOR};
+use crate::arch::halt_loop;
+use crate::arch_spec::cpuid::cpuid;
+
+/**
+ *  sanity checks of the state bootloader hands over.
+ *
+ *  kernel code assumes long mode with paging, NXE and WP set, segments from a
+ *  gdt laid out as in `shared::gdt` (code at index 1, data at index 2), a
+ *  local apic and the page table of the kernel arg in cr3. a regression in the
+ *  handoff shows up here with what is wrong instead of as a fault later.
+ */
+
+/// check every assumption, log all that fail and halt if any did
+pub fn verify_boot_state(arg: &KernelArg) {
+    let mut ok = true;
+    let mut check = |holds: bool, what: fmt::Arguments, hint: &str| {
+        if !holds {
+            error!("boot state: {}, {}", what, hint);
+            ok = false;
+        }
+    };
+
+    let efer = Efer::read();
+    check(
+        efer.contains(EferFlags::LONG_MODE_ENABLE | EferFlags::LONG_MODE_ACTIVE),
+        format_args!("not in long mode"),
+        "bootloader must enter the kernel from 64-bit code",
+    );
+    check(
+        efer.contains(EferFlags::NO_EXECUTE_ENABLE),
+        format_args!("EFER.NXE is clear"),
+        "bootloader must set it before loading the kernel page table, whose NX bits are reserved otherwise",
+    );
+
+    let cr0 = Cr0::read();
+    check(
+        cr0.contains(Cr0Flags::PAGING | Cr0Flags::PROTECTED_MODE_ENABLE),
+        format_args!("paging is off"),
+        "cr0.PG and cr0.PE must be set",
+    );
+    check(
+        cr0.contains(Cr0Flags::WRITE_PROTECT),
+        format_args!("CR0.WP is clear"),
+        "kernel writes to read-only pages would go unnoticed, bootloader must set it",
+    );
+    check(
+        Cr4::read().contains(Cr4Flags::PHYSICAL_ADDRESS_EXTENSION),
+        format_args!("CR4.PAE is clear"),
+        "4-level paging needs it",
+    );
+
+    let cr3 = Cr3::read().0.start_address().as_u64();
+    check(
+        cr3 == arg.kernel_pml4_start_addr,
+        format_args!("cr3 {:#x} is not the kernel page table {:#x}", cr3, arg.kernel_pml4_start_addr),
+        "bootloader must switch to kernel_pml4_start_addr before jumping to the kernel",
+    );
+
+    // the gdt itself may be another one after kexec, the kernel gdt keeps the selectors
+    // but leaves ds null
+    let (cs, ds, ss) = (CS::get_reg().0, DS::get_reg().0, SS::get_reg().0);
+    check(
+        cs == KERNEL_CODE_SELECTOR && ss == KERNEL_DATA_SELECTOR && (ds == KERNEL_DATA_SELECTOR || ds == 0),
+        format_args!("unexpected segment selectors cs {:#x}, ds {:#x}, ss {:#x}", cs, ds, ss),
+        "cs, ss and ds must be the kernel code and data selectors of shared::gdt",
+    );
+
+    check(
+        cpuid().get_feature_info().map_or(false, |info| info.has_apic()),
+        format_args!("no local apic"),
+        "interrupts and smp need one, the cpu or hypervisor lacks it",
+    );
+
+    if !ok {
+        error!("boot state: handoff from bootloader is broken, halting");
+        halt_loop();
+    }
+}
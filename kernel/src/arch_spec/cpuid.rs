@@ -1,7 +1,11 @@
-use raw_cpuid::{CpuId, CpuIdResult};
-use core::fmt::{Result, Write};
-
-use crate::logger::{FRAMEBUFFER_LOGGER};
+use raw_cpuid::{ApmInfo, CpuId, CpuIdResult, ExtendedFeatures, ExtendedProcessorFeatureIdentifiers, FeatureInfo};
+use core::fmt;
+use core::sync::atomic::{AtomicU32, Ordering};
+use bitflags::bitflags;
+use log::info;
+use crate::cpu::LogicalCpuId;
+use crate::initcall;
+use crate::initcall::InitCpuArg;
 
 pub fn cpuid() -> CpuId {
     // FIXME check for cpuid availability during early boot and error out if it doesn't exist.
@@ -16,287 +20,244 @@ pub fn cpuid() -> CpuId {
     })
 }
 
-pub fn cpu_info() -> Result {
-    let fl_ref = FRAMEBUFFER_LOGGER.inner_exclusive_mut();
-    let fl = unsafe { fl_ref.assume_init_ref() };
-    let mut fl_writer = fl.writer.lock();
+// feature names as in linux /proc/cpuinfo, per cpuid leaf
+const BASIC_FEATURES: &[(&str, fn(&FeatureInfo) -> bool)] = &[
+    ("fpu", FeatureInfo::has_fpu),
+    ("vme", FeatureInfo::has_vme),
+    ("de", FeatureInfo::has_de),
+    ("pse", FeatureInfo::has_pse),
+    ("tsc", FeatureInfo::has_tsc),
+    ("msr", FeatureInfo::has_msr),
+    ("pae", FeatureInfo::has_pae),
+    ("mce", FeatureInfo::has_mce),
+    ("cx8", FeatureInfo::has_cmpxchg8b),
+    ("apic", FeatureInfo::has_apic),
+    ("sep", FeatureInfo::has_sysenter_sysexit),
+    ("mtrr", FeatureInfo::has_mtrr),
+    ("pge", FeatureInfo::has_pge),
+    ("mca", FeatureInfo::has_mca),
+    ("cmov", FeatureInfo::has_cmov),
+    ("pat", FeatureInfo::has_pat),
+    ("pse36", FeatureInfo::has_pse36),
+    ("psn", FeatureInfo::has_psn),
+    ("clflush", FeatureInfo::has_clflush),
+    ("ds", FeatureInfo::has_ds),
+    ("acpi", FeatureInfo::has_acpi),
+    ("mmx", FeatureInfo::has_mmx),
+    ("fxsr", FeatureInfo::has_fxsave_fxstor),
+    ("sse", FeatureInfo::has_sse),
+    ("sse2", FeatureInfo::has_sse2),
+    ("ss", FeatureInfo::has_ss),
+    ("ht", FeatureInfo::has_htt),
+    ("tm", FeatureInfo::has_tm),
+    ("pbe", FeatureInfo::has_pbe),
+    ("sse3", FeatureInfo::has_sse3),
+    ("pclmulqdq", FeatureInfo::has_pclmulqdq),
+    ("dtes64", FeatureInfo::has_ds_area),
+    ("monitor", FeatureInfo::has_monitor_mwait),
+    ("ds_cpl", FeatureInfo::has_cpl),
+    ("vmx", FeatureInfo::has_vmx),
+    ("smx", FeatureInfo::has_smx),
+    ("est", FeatureInfo::has_eist),
+    ("tm2", FeatureInfo::has_tm2),
+    ("ssse3", FeatureInfo::has_ssse3),
+    ("cnxtid", FeatureInfo::has_cnxtid),
+    ("fma", FeatureInfo::has_fma),
+    ("cx16", FeatureInfo::has_cmpxchg16b),
+    ("pdcm", FeatureInfo::has_pdcm),
+    ("pcid", FeatureInfo::has_pcid),
+    ("dca", FeatureInfo::has_dca),
+    ("sse4_1", FeatureInfo::has_sse41),
+    ("sse4_2", FeatureInfo::has_sse42),
+    ("x2apic", FeatureInfo::has_x2apic),
+    ("movbe", FeatureInfo::has_movbe),
+    ("popcnt", FeatureInfo::has_popcnt),
+    ("tsc_deadline_timer", FeatureInfo::has_tsc_deadline),
+    ("aes", FeatureInfo::has_aesni),
+    ("xsave", FeatureInfo::has_xsave),
+    ("xsaveopt", FeatureInfo::has_oxsave),
+    ("avx", FeatureInfo::has_avx),
+    ("f16c", FeatureInfo::has_f16c),
+    ("rdrand", FeatureInfo::has_rdrand),
+];
 
-    let cpuid = cpuid();
+const EXTENDED_FEATURES: &[(&str, fn(&ExtendedProcessorFeatureIdentifiers) -> bool)] = &[
+    ("lm", ExtendedProcessorFeatureIdentifiers::has_64bit_mode),
+    ("rdtscp", ExtendedProcessorFeatureIdentifiers::has_rdtscp),
+    ("pdpe1gb", ExtendedProcessorFeatureIdentifiers::has_1gib_pages),
+    ("nx", ExtendedProcessorFeatureIdentifiers::has_execute_disable),
+    ("syscall_module", ExtendedProcessorFeatureIdentifiers::has_syscall_sysret),
+    ("prefetchw", ExtendedProcessorFeatureIdentifiers::has_prefetchw),
+    ("lzcnt", ExtendedProcessorFeatureIdentifiers::has_lzcnt),
+    ("lahf_lm", ExtendedProcessorFeatureIdentifiers::has_lahf_sahf),
+];
+
+const POWER_FEATURES: &[(&str, fn(&ApmInfo) -> bool)] = &[
+    ("constant_tsc", ApmInfo::has_invariant_tsc),
+];
 
+const STRUCTURED_FEATURES: &[(&str, fn(&ExtendedFeatures) -> bool)] = &[
+    ("fsgsbase", ExtendedFeatures::has_fsgsbase),
+    ("tsc_adjust", ExtendedFeatures::has_tsc_adjust_msr),
+    ("bmi1", ExtendedFeatures::has_bmi1),
+    ("hle", ExtendedFeatures::has_hle),
+    ("avx2", ExtendedFeatures::has_avx2),
+    ("smep", ExtendedFeatures::has_smep),
+    ("bmi2", ExtendedFeatures::has_bmi2),
+    ("erms", ExtendedFeatures::has_rep_movsb_stosb),
+    ("invpcid", ExtendedFeatures::has_invpcid),
+    ("rtm", ExtendedFeatures::has_rtm),
+    // ("qm", ExtendedFeatures::has_qm),
+    ("fpu_seg", ExtendedFeatures::has_fpu_cs_ds_deprecated),
+    ("mpx", ExtendedFeatures::has_mpx),
+];
 
-    writeln!(fl_writer, "CPU Info: ");
+// features per log line, a line of the log ring holds 512 bytes
+const FEATURES_PER_LINE: usize = 16;
+
+// space separated, without allocating: cpu_info runs before the heap is set up
+struct Names<'a>(&'a [&'static str]);
+
+impl fmt::Display for Names<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, name) in self.0.iter().enumerate() {
+            if i != 0 {
+                f.write_str(" ")?;
+            }
+            f.write_str(name)?;
+        }
+        Ok(())
+    }
+}
+
+fn collect<T>(info: Option<T>, table: &[(&'static str, fn(&T) -> bool)], names: &mut [&'static str], len: &mut usize) {
+    let Some(info) = info else { return };
+    for (name, has) in table {
+        if has(&info) && *len < names.len() {
+            names[*len] = name;
+            *len += 1;
+        }
+    }
+}
+
+/// log vendor, model and features of the current cpu
+pub fn cpu_info() {
+    let cpuid = cpuid();
+
+    info!("CPU Info:");
 
     if let Some(info) = cpuid.get_vendor_info() {
-        writeln!(fl_writer, "  Vendor: {}", info.as_str())?;
+        info!("  Vendor: {}", info.as_str());
     }
 
     if let Some(brand) = cpuid.get_processor_brand_string() {
-        writeln!(fl_writer, "  Model: {}", brand.as_str())?;
+        info!("  Model: {}", brand.as_str());
     }
 
     if let Some(info) = cpuid.get_processor_frequency_info() {
-        writeln!(fl_writer, "  CPU Base MHz: {}", info.processor_base_frequency())?;
-        writeln!(fl_writer, "  CPU Max MHz: {}", info.processor_max_frequency())?;
-        writeln!(fl_writer, "  Bus MHz: {}", info.bus_frequency())?;
+        info!("  CPU Base MHz: {}", info.processor_base_frequency());
+        info!("  CPU Max MHz: {}", info.processor_max_frequency());
+        info!("  Bus MHz: {}", info.bus_frequency());
     }
 
-    write!(fl_writer, "  Features:")?;
+    let mut names = [""; BASIC_FEATURES.len() + EXTENDED_FEATURES.len() + POWER_FEATURES.len() + STRUCTURED_FEATURES.len()];
+    let mut len = 0;
+    collect(cpuid.get_feature_info(), BASIC_FEATURES, &mut names, &mut len);
+    collect(cpuid.get_extended_processor_and_feature_identifiers(), EXTENDED_FEATURES, &mut names, &mut len);
+    collect(cpuid.get_advanced_power_mgmt_info(), POWER_FEATURES, &mut names, &mut len);
+    collect(cpuid.get_extended_feature_info(), STRUCTURED_FEATURES, &mut names, &mut len);
 
-    if let Some(info) = cpuid.get_feature_info() {
-        if info.has_fpu() {
-            write!(fl_writer, " fpu")?
-        };
-        if info.has_vme() {
-            write!(fl_writer, " vme")?
-        };
-        if info.has_de() {
-            write!(fl_writer, " de")?
-        };
-        if info.has_pse() {
-            write!(fl_writer, " pse")?
-        };
-        if info.has_tsc() {
-            write!(fl_writer, " tsc")?
-        };
-        if info.has_msr() {
-            write!(fl_writer, " msr")?
-        };
-        if info.has_pae() {
-            write!(fl_writer, " pae")?
-        };
-        if info.has_mce() {
-            write!(fl_writer, " mce")?
-        };
-
-        if info.has_cmpxchg8b() {
-            write!(fl_writer, " cx8")?
-        };
-        if info.has_apic() {
-            write!(fl_writer, " apic")?
-        };
-        if info.has_sysenter_sysexit() {
-            write!(fl_writer, " sep")?
-        };
-        if info.has_mtrr() {
-            write!(fl_writer, " mtrr")?
-        };
-        if info.has_pge() {
-            write!(fl_writer, " pge")?
-        };
-        if info.has_mca() {
-            write!(fl_writer, " mca")?
-        };
-        if info.has_cmov() {
-            write!(fl_writer, " cmov")?
-        };
-        if info.has_pat() {
-            write!(fl_writer, " pat")?
-        };
+    for (i, line) in names[..len].chunks(FEATURES_PER_LINE).enumerate() {
+        info!("  {} {}", if i == 0 { "Features:" } else { "         " }, Names(line));
+    }
+}
 
-        if info.has_pse36() {
-            write!(fl_writer, " pse36")?
-        };
-        if info.has_psn() {
-            write!(fl_writer, " psn")?
-        };
-        if info.has_clflush() {
-            write!(fl_writer, " clflush")?
-        };
-        if info.has_ds() {
-            write!(fl_writer, " ds")?
-        };
-        if info.has_acpi() {
-            write!(fl_writer, " acpi")?
-        };
-        if info.has_mmx() {
-            write!(fl_writer, " mmx")?
-        };
-        if info.has_fxsave_fxstor() {
-            write!(fl_writer, " fxsr")?
-        };
-        if info.has_sse() {
-            write!(fl_writer, " sse")?
-        };
+bitflags! {
+    /// features kernel fast paths are selected by. read once on bsp, every ap
+    /// must have them too, so a path picked at boot is valid on every cpu
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct CpuFeatures: u32 {
+        const X2APIC = 1 << 0;
+        const FSGSBASE = 1 << 1;
+        const PCID = 1 << 2;
+        const INVPCID = 1 << 3;
+        const XSAVE = 1 << 4;
+        const XSAVEOPT = 1 << 5;
+        const TSC_DEADLINE = 1 << 6;
+        const INVARIANT_TSC = 1 << 7;
+        const ERMS = 1 << 8;
+    }
+}
 
-        if info.has_sse2() {
-            write!(fl_writer, " sse2")?
-        };
-        if info.has_ss() {
-            write!(fl_writer, " ss")?
-        };
-        if info.has_htt() {
-            write!(fl_writer, " ht")?
-        };
-        if info.has_tm() {
-            write!(fl_writer, " tm")?
-        };
-        if info.has_pbe() {
-            write!(fl_writer, " pbe")?
-        };
+// name and fast path of every feature, for the boot summary
+const FAST_PATHS: &[(CpuFeatures, &str, &str)] = &[
+    (CpuFeatures::X2APIC, "x2apic", "local apic through msrs"),
+    (CpuFeatures::FSGSBASE, "fsgsbase", "user fs/gs base switch without msrs"),
+    (CpuFeatures::PCID, "pcid", "tagged tlb entries"),
+    (CpuFeatures::INVPCID, "invpcid", "tlb flush by pcid"),
+    (CpuFeatures::XSAVE, "xsave", "extended fpu state"),
+    (CpuFeatures::XSAVEOPT, "xsaveopt", "fpu save skips unmodified state"),
+    (CpuFeatures::TSC_DEADLINE, "tsc_deadline", "lapic timer deadline mode"),
+    (CpuFeatures::INVARIANT_TSC, "invariant_tsc", "one tsc calibration for all cpus"),
+    (CpuFeatures::ERMS, "erms", "rep movsb copies"),
+];
 
-        if info.has_sse3() {
-            write!(fl_writer, " sse3")?
-        };
-        if info.has_pclmulqdq() {
-            write!(fl_writer, " pclmulqdq")?
-        };
-        if info.has_ds_area() {
-            write!(fl_writer, " dtes64")?
-        };
-        if info.has_monitor_mwait() {
-            write!(fl_writer, " monitor")?
-        };
-        if info.has_cpl() {
-            write!(fl_writer, " ds_cpl")?
-        };
-        if info.has_vmx() {
-            write!(fl_writer, " vmx")?
-        };
-        if info.has_smx() {
-            write!(fl_writer, " smx")?
-        };
-        if info.has_eist() {
-            write!(fl_writer, " est")?
-        };
+static CPU_FEATURES: AtomicU32 = AtomicU32::new(0);
 
-        if info.has_tm2() {
-            write!(fl_writer, " tm2")?
-        };
-        if info.has_ssse3() {
-            write!(fl_writer, " ssse3")?
-        };
-        if info.has_cnxtid() {
-            write!(fl_writer, " cnxtid")?
-        };
-        if info.has_fma() {
-            write!(fl_writer, " fma")?
-        };
-        if info.has_cmpxchg16b() {
-            write!(fl_writer, " cx16")?
-        };
-        if info.has_pdcm() {
-            write!(fl_writer, " pdcm")?
-        };
-        if info.has_pcid() {
-            write!(fl_writer, " pcid")?
-        };
-        if info.has_dca() {
-            write!(fl_writer, " dca")?
-        };
+impl CpuFeatures {
+    /// features of the current cpu
+    pub fn detect() -> Self {
+        let cpuid = cpuid();
+        let mut features = CpuFeatures::empty();
+        if let Some(info) = cpuid.get_feature_info() {
+            features.set(CpuFeatures::X2APIC, info.has_x2apic());
+            features.set(CpuFeatures::PCID, info.has_pcid());
+            features.set(CpuFeatures::XSAVE, info.has_xsave());
+            features.set(CpuFeatures::TSC_DEADLINE, info.has_tsc_deadline());
+        }
+        if let Some(info) = cpuid.get_extended_feature_info() {
+            features.set(CpuFeatures::FSGSBASE, info.has_fsgsbase());
+            features.set(CpuFeatures::INVPCID, info.has_invpcid());
+            features.set(CpuFeatures::ERMS, info.has_rep_movsb_stosb());
+        }
+        if let Some(info) = cpuid.get_extended_state_info() {
+            features.set(CpuFeatures::XSAVEOPT, info.has_xsaveopt());
+        }
+        if let Some(info) = cpuid.get_advanced_power_mgmt_info() {
+            features.set(CpuFeatures::INVARIANT_TSC, info.has_invariant_tsc());
+        }
+        features
+    }
+}
 
-        if info.has_sse41() {
-            write!(fl_writer, " sse4_1")?
-        };
-        if info.has_sse42() {
-            write!(fl_writer, " sse4_2")?
-        };
-        if info.has_x2apic() {
-            write!(fl_writer, " x2apic")?
-        };
-        if info.has_movbe() {
-            write!(fl_writer, " movbe")?
-        };
-        if info.has_popcnt() {
-            write!(fl_writer, " popcnt")?
-        };
-        if info.has_tsc_deadline() {
-            write!(fl_writer, " tsc_deadline_timer")?
-        };
-        if info.has_aesni() {
-            write!(fl_writer, " aes")?
-        };
-        if info.has_xsave() {
-            write!(fl_writer, " xsave")?
-        };
+/// features every cpu has, empty before [`init_cpu_features`]
+#[inline(always)]
+pub fn cpu_features() -> CpuFeatures {
+    CpuFeatures::from_bits_retain(CPU_FEATURES.load(Ordering::Relaxed))
+}
 
-        if info.has_oxsave() {
-            write!(fl_writer, " xsaveopt")?
-        };
-        if info.has_avx() {
-            write!(fl_writer, " avx")?
-        };
-        if info.has_f16c() {
-            write!(fl_writer, " f16c")?
-        };
-        if info.has_rdrand() {
-            write!(fl_writer, " rdrand")?
-        };
-    }
+/// width of physical addresses, 36 bits when cpuid does not report it
+pub fn phys_addr_bits() -> u8 {
+    cpuid().get_processor_capacity_feature_info().map_or(36, |info| info.physical_address_bits())
+}
 
-    if let Some(info) = cpuid.get_extended_processor_and_feature_identifiers() {
-        if info.has_64bit_mode() {
-            write!(fl_writer, " lm")?
-        };
-        if info.has_rdtscp() {
-            write!(fl_writer, " rdtscp")?
-        };
-        if info.has_1gib_pages() {
-            write!(fl_writer, " pdpe1gb")?
-        };
-        if info.has_execute_disable() {
-            write!(fl_writer, " nx")?
-        };
-        if info.has_syscall_sysret() {
-            write!(fl_writer, " syscall_module")?
-        };
-        if info.has_prefetchw() {
-            write!(fl_writer, " prefetchw")?
-        };
-        if info.has_lzcnt() {
-            write!(fl_writer, " lzcnt")?
-        };
-        if info.has_lahf_sahf() {
-            write!(fl_writer, " lahf_lm")?
-        };
-    }
+/// read the features on bsp and log which fast paths they enable
+pub fn init_cpu_features() {
+    let features = CpuFeatures::detect();
+    CPU_FEATURES.store(features.bits(), Ordering::SeqCst);
 
-    if let Some(info) = cpuid.get_advanced_power_mgmt_info() {
-        if info.has_invariant_tsc() {
-            write!(fl_writer, " constant_tsc")?
-        };
+    info!("Fast paths:");
+    for (feature, name, path) in FAST_PATHS {
+        info!("  {:<14} {:<3} {}", name, if features.contains(*feature) { "yes" } else { "no" }, path);
     }
+}
 
-    if let Some(info) = cpuid.get_extended_feature_info() {
-        if info.has_fsgsbase() {
-            write!(fl_writer, " fsgsbase")?
-        };
-        if info.has_tsc_adjust_msr() {
-            write!(fl_writer, " tsc_adjust")?
-        };
-        if info.has_bmi1() {
-            write!(fl_writer, " bmi1")?
-        };
-        if info.has_hle() {
-            write!(fl_writer, " hle")?
-        };
-        if info.has_avx2() {
-            write!(fl_writer, " avx2")?
-        };
-        if info.has_smep() {
-            write!(fl_writer, " smep")?
-        };
-        if info.has_bmi2() {
-            write!(fl_writer, " bmi2")?
-        };
-        if info.has_rep_movsb_stosb() {
-            write!(fl_writer, " erms")?
-        };
-        if info.has_invpcid() {
-            write!(fl_writer, " invpcid")?
-        };
-        if info.has_rtm() {
-            write!(fl_writer, " rtm")?
-        };
-        //if info.has_qm() { write!(fl_writer, " qm")? };
-        if info.has_fpu_cs_ds_deprecated() {
-            write!(fl_writer, " fpu_seg")?
-        };
-        if info.has_mpx() {
-            write!(fl_writer, " mpx")?
-        };
+// an ap without a feature the bsp picked a fast path by would fault on it later
+unsafe fn cpu_features_initcall(arg: &InitCpuArg) {
+    if arg.cpu_id == LogicalCpuId::BSP {
+        return;
     }
-
-    writeln!(fl_writer)?;
-
-    Ok(())
+    let missing = cpu_features().difference(CpuFeatures::detect());
+    assert!(missing.is_empty(), "cpu {} lacks features of the bsp: {:?}", arg.cpu_id, missing);
 }
+initcall!(arch, All, cpu_features_initcall, order = 0);
@@ -0,0 +1,204 @@
+use core::arch::asm;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use libvdso::error::{EINVAL, ENOSPC, KError, KResult};
+use crate::context::context_id;
+use crate::initcall;
+use crate::initcall::InitCpuArg;
+use crate::ipi::{ipi, IpiKind, IpiTarget};
+use crate::sync::IrqSpinlock;
+use crate::syscall::IretRegisters;
+use crate::warnhart;
+
+/**
+ *  hardware watchpoints on the debug registers.
+ *
+ *  dr0-dr3 hold up to four linear addresses, dr7 enables each one for
+ *  instruction fetch, writes or reads and writes of 1, 2, 4 or 8 aligned
+ *  bytes. the watchpoints are global: `set_watchpoint` publishes the registers
+ *  and broadcasts `IpiKind::Debug`, every cpu loads its own from them, a cpu
+ *  coming up does so in its initcall.
+ *
+ *  a hit raises #db, after the access for data watchpoints, before the
+ *  instruction for execution ones. the handler reads dr6 to see which fired
+ *  and logs the address, the rip of the frame and the context. it is paranoid
+ *  and only reads the published registers, so code on the logging path must
+ *  not be watched. user addresses hit in whichever address space is loaded.
+ */
+
+pub const WATCHPOINTS: usize = 4;
+
+// dr6 bits B0-B3, the rest reads as the value it is reset to
+const DR6_HITS: u64 = 0xf;
+const DR6_RESET: u64 = 0xffff_0ff0;
+// dr7: exact data breakpoints, then the reserved bit that reads as one
+const DR7_GE: u64 = 1 << 9;
+const DR7_RESERVED: u64 = 1 << 10;
+
+/// what a watchpoint fires on, the dr7 r/w encoding
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum Watch {
+    Exec = 0b00,
+    Write = 0b01,
+    ReadWrite = 0b11,
+}
+
+impl Watch {
+    fn from_bits(bits: u64) -> Self {
+        match bits & 0b11 {
+            0b00 => Watch::Exec,
+            0b01 => Watch::Write,
+            _ => Watch::ReadWrite,
+        }
+    }
+}
+
+// dr7 len encoding, 8 bytes is 0b10
+fn len_bits(len: usize) -> Option<u64> {
+    match len {
+        1 => Some(0b00),
+        2 => Some(0b01),
+        4 => Some(0b11),
+        8 => Some(0b10),
+        _ => None,
+    }
+}
+
+fn len_from_bits(bits: u64) -> usize {
+    match bits & 0b11 {
+        0b00 => 1,
+        0b01 => 2,
+        0b11 => 4,
+        _ => 8,
+    }
+}
+
+/// dr7 bits enabling `slot` globally for `len` bytes of `kind`
+fn dr7_enable(slot: usize, len: usize, kind: Watch) -> Option<u64> {
+    let len = if kind == Watch::Exec { (len == 1).then_some(0)? } else { len_bits(len)? };
+    Some(1 << (2 * slot + 1) | (kind as u64 | len << 2) << (16 + 4 * slot))
+}
+
+fn dr7_mask(slot: usize) -> u64 {
+    0b11 << (2 * slot) | 0b1111 << (16 + 4 * slot)
+}
+
+// published for `apply` and the #db handler, updated under `UPDATE`
+static ADDRS: [AtomicU64; WATCHPOINTS] = [const { AtomicU64::new(0) }; WATCHPOINTS];
+static DR7: AtomicU64 = AtomicU64::new(DR7_RESERVED);
+static HITS: [AtomicUsize; WATCHPOINTS] = [const { AtomicUsize::new(0) }; WATCHPOINTS];
+static UPDATE: IrqSpinlock<()> = IrqSpinlock::new(());
+
+unsafe fn write_dr(index: usize, value: u64) {
+    match index {
+        0 => asm!("mov dr0, {}", in(reg) value, options(nomem, nostack)),
+        1 => asm!("mov dr1, {}", in(reg) value, options(nomem, nostack)),
+        2 => asm!("mov dr2, {}", in(reg) value, options(nomem, nostack)),
+        3 => asm!("mov dr3, {}", in(reg) value, options(nomem, nostack)),
+        6 => asm!("mov dr6, {}", in(reg) value, options(nomem, nostack)),
+        _ => asm!("mov dr7, {}", in(reg) value, options(nomem, nostack)),
+    }
+}
+
+unsafe fn read_dr6() -> u64 {
+    let value: u64;
+    asm!("mov {}, dr6", out(reg) value, options(nomem, nostack));
+    value
+}
+
+/// load the debug registers of this cpu from the published watchpoints
+pub unsafe fn apply() {
+    write_dr(7, DR7_RESERVED);
+    for (index, addr) in ADDRS.iter().enumerate() {
+        write_dr(index, addr.load(Ordering::SeqCst));
+    }
+    write_dr(6, DR6_RESET);
+    write_dr(7, DR7.load(Ordering::SeqCst));
+}
+
+// the calling cpu right away, the others through an ipi
+fn broadcast() {
+    unsafe { apply() };
+    ipi(IpiKind::Debug, IpiTarget::Other);
+}
+
+unsafe fn debug_initcall(_arg: &InitCpuArg) {
+    apply();
+}
+// after the idt, the ipi may already reach a cpu that is still coming up
+initcall!(arch, All, debug_initcall, order = 11);
+
+/// watch `len` bytes at `addr` for `kind` on every cpu, returns the slot.
+/// `addr` must be aligned to `len`, an execution watchpoint covers one byte
+pub fn set_watchpoint(addr: usize, len: usize, kind: Watch) -> KResult<usize> {
+    if addr % len.max(1) != 0 {
+        return Err(KError::new(EINVAL));
+    }
+    let _guard = UPDATE.lock();
+    let dr7 = DR7.load(Ordering::SeqCst);
+    let slot = (0..WATCHPOINTS).find(|slot| dr7 & dr7_mask(*slot) == 0).ok_or(KError::new(ENOSPC))?;
+    let enable = dr7_enable(slot, len, kind).ok_or(KError::new(EINVAL))?;
+    ADDRS[slot].store(addr as u64, Ordering::SeqCst);
+    HITS[slot].store(0, Ordering::SeqCst);
+    DR7.store(dr7 | DR7_GE | enable, Ordering::SeqCst);
+    broadcast();
+    Ok(slot)
+}
+
+/// stop watching `slot` on every cpu, returns how often it fired
+pub fn clear_watchpoint(slot: usize) -> KResult<usize> {
+    let _guard = UPDATE.lock();
+    let dr7 = DR7.load(Ordering::SeqCst);
+    if slot >= WATCHPOINTS || dr7 & dr7_mask(slot) == 0 {
+        return Err(KError::new(EINVAL));
+    }
+    let dr7 = dr7 & !dr7_mask(slot);
+    let any_left = (0..WATCHPOINTS).any(|slot| dr7 & dr7_mask(slot) != 0);
+    DR7.store(if any_left { dr7 } else { DR7_RESERVED }, Ordering::SeqCst);
+    ADDRS[slot].store(0, Ordering::SeqCst);
+    broadcast();
+    Ok(HITS[slot].load(Ordering::SeqCst))
+}
+
+/// called by the #db handler, logs the watchpoints that fired and returns
+/// true if any did
+pub unsafe fn handle_debug(iret: &IretRegisters) -> bool {
+    let dr7 = DR7.load(Ordering::Relaxed);
+    let hits = read_dr6() & DR6_HITS;
+    // the cpu never clears dr6
+    write_dr(6, DR6_RESET);
+    let mut fired = false;
+    for slot in (0..WATCHPOINTS).filter(|slot| hits & 1 << slot != 0 && dr7 & dr7_mask(*slot) != 0) {
+        let control = dr7 >> (16 + 4 * slot);
+        HITS[slot].fetch_add(1, Ordering::Relaxed);
+        warnhart!(
+            "watchpoint {} ({:?}, {} bytes at {:#x}) hit at rip {:#x} by context {}",
+            slot, Watch::from_bits(control), len_from_bits(control >> 2), ADDRS[slot].load(Ordering::Relaxed),
+            iret.rip, context_id().get()
+        );
+        fired = true;
+    }
+    fired
+}
+
+#[cfg(test)]
+static WATCHED: AtomicU64 = AtomicU64::new(0);
+
+#[test_case]
+pub(crate) fn test_watchpoints() {
+    assert_eq!(dr7_enable(0, 1, Watch::Exec), Some(0b10));
+    assert_eq!(dr7_enable(2, 8, Watch::Write), Some(1 << 5 | 0b1001 << 24));
+    assert_eq!(dr7_enable(3, 4, Watch::ReadWrite), Some(1 << 7 | 0b1111 << 28));
+    assert_eq!(dr7_enable(1, 2, Watch::Exec), None);
+    assert_eq!(dr7_enable(1, 3, Watch::Write), None);
+
+    let addr = WATCHED.as_ptr() as usize;
+    assert!(set_watchpoint(addr + 4, 8, Watch::Write).is_err());
+    let slot = set_watchpoint(addr, 8, Watch::Write).unwrap();
+    // reads pass, every write fires once
+    assert_eq!(WATCHED.load(Ordering::SeqCst), 0);
+    WATCHED.store(1, Ordering::SeqCst);
+    WATCHED.store(0, Ordering::SeqCst);
+    assert_eq!(clear_watchpoint(slot).unwrap(), 2);
+    assert!(clear_watchpoint(slot).is_err());
+}
@@ -0,0 +1,128 @@
+use core::fmt;
+use shared::layout::USER_SPACE_END;
+use shared::gdt::{KERNEL_CODE_SELECTOR, KERNEL_DATA_SELECTOR, USER_CODE_SELECTOR, USER_DATA_SELECTOR};
+use crate::syscall::IretRegisters;
+
+/**
+ *  validation of the iret frame a kernel entry works on.
+ *
+ *  entry code trusts the frame: `cs & 3` decides swapgs and whether a fault
+ *  kills a context, rip and rsp go back out through sysret/iret. a frame the
+ *  cpu could not have pushed, a selector outside the kernel gdt or ring 1/2,
+ *  means entry asm or a handler editing the frame is broken. those are
+ *  debug assertions on every entry.
+ *
+ *  a ring 3 frame may hold whatever userspace left in its registers: a stack
+ *  pointer outside user space, or a kernel/non-canonical instruction pointer
+ *  it jumped to. those are user errors, reported as a diagnostic line when
+ *  the fault kills the context or a syscall comes in with such a stack.
+ */
+
+// kernel gdt, see `shared::gdt`
+const KERNEL_CODE: usize = KERNEL_CODE_SELECTOR as usize;
+const KERNEL_DATA: usize = KERNEL_DATA_SELECTOR as usize;
+const USER_DATA: usize = USER_DATA_SELECTOR as usize;
+const USER_CODE: usize = USER_CODE_SELECTOR as usize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameIssue {
+    /// cs claims ring 1 or 2, nothing runs there
+    BadRing { cs: usize },
+    /// cs or ss is not a selector of the claimed ring
+    BadSelectors { cs: usize, ss: usize },
+    NonCanonicalRip(usize),
+    NonCanonicalRsp(usize),
+    /// ring 3 rip at or above the end of user space
+    KernelRip(usize),
+    /// ring 3 rsp at or above the end of user space
+    KernelRsp(usize),
+}
+
+impl FrameIssue {
+    /// userspace can cause it, every other issue is a kernel bug
+    pub fn is_user(&self) -> bool {
+        matches!(self, FrameIssue::KernelRip(_) | FrameIssue::KernelRsp(_))
+    }
+}
+
+impl fmt::Display for FrameIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            FrameIssue::BadRing { cs } => write!(f, "cs {:#x} is ring {}", cs, cs & 3),
+            FrameIssue::BadSelectors { cs, ss } => write!(f, "selectors cs {:#x}, ss {:#x} do not match ring {}", cs, ss, cs & 3),
+            FrameIssue::NonCanonicalRip(rip) => write!(f, "non-canonical rip {:#x}", rip),
+            FrameIssue::NonCanonicalRsp(rsp) => write!(f, "non-canonical rsp {:#x}", rsp),
+            FrameIssue::KernelRip(rip) => write!(f, "rip {:#x} outside user space", rip),
+            FrameIssue::KernelRsp(rsp) => write!(f, "rsp {:#x} outside user space", rsp),
+        }
+    }
+}
+
+fn is_canonical(addr: usize) -> bool {
+    ((addr as i64) << 16 >> 16) as usize == addr
+}
+
+/// first thing wrong with `iret` for the ring its cs claims
+pub fn frame_issue(iret: &IretRegisters) -> Option<FrameIssue> {
+    let (cs, ss) = (iret.cs, iret.ss);
+    match cs & 3 {
+        0 => {
+            // a ring change loads a null ss, nested entries see the kernel one
+            if cs != KERNEL_CODE || (ss != KERNEL_DATA && ss != 0) {
+                return Some(FrameIssue::BadSelectors { cs, ss });
+            }
+            if !is_canonical(iret.rip) {
+                return Some(FrameIssue::NonCanonicalRip(iret.rip));
+            }
+            if !is_canonical(iret.rsp) {
+                return Some(FrameIssue::NonCanonicalRsp(iret.rsp));
+            }
+            None
+        }
+        3 => {
+            if cs != USER_CODE || ss != USER_DATA {
+                return Some(FrameIssue::BadSelectors { cs, ss });
+            }
+            // the cpu faults before loading a non-canonical rip, so anything
+            // above user space is a jump into the kernel half
+            if iret.rip >= USER_SPACE_END as usize {
+                return Some(FrameIssue::KernelRip(iret.rip));
+            }
+            if iret.rsp >= USER_SPACE_END as usize {
+                return Some(FrameIssue::KernelRsp(iret.rsp));
+            }
+            None
+        }
+        _ => Some(FrameIssue::BadRing { cs }),
+    }
+}
+
+/// called on every kernel entry, asserts the frame is one the cpu could have pushed
+#[inline(always)]
+pub fn debug_check_entry(iret: &IretRegisters) {
+    if cfg!(debug_assertions) {
+        if let Some(issue) = frame_issue(iret) {
+            debug_assert!(issue.is_user(), "corrupt entry frame: {}, {:?}", issue, iret);
+        }
+    }
+}
+
+#[test_case]
+pub(crate) fn test_frame_issue() {
+    let user = |rip: usize, rsp: usize| IretRegisters { rip, cs: USER_CODE, rflags: 0x202, rsp, ss: USER_DATA };
+    let kernel = |rip: usize, rsp: usize, ss: usize| IretRegisters { rip, cs: KERNEL_CODE, rflags: 0x2, rsp, ss };
+
+    assert_eq!(frame_issue(&user(0x40_1000, 0x7fff_f000)), None);
+    assert_eq!(frame_issue(&user(0xffff_8000_0000_0000, 0x7fff_f000)), Some(FrameIssue::KernelRip(0xffff_8000_0000_0000)));
+    assert_eq!(frame_issue(&user(0x40_1000, 0x8000_0000_0000)), Some(FrameIssue::KernelRsp(0x8000_0000_0000)));
+    assert!(frame_issue(&user(0x40_1000, 0x8000_0000_0000)).unwrap().is_user());
+
+    assert_eq!(frame_issue(&kernel(0xffff_8000_0010_0000, 0xffff_9000_0000_0000, 0)), None);
+    assert_eq!(frame_issue(&kernel(0xffff_8000_0010_0000, 0xffff_9000_0000_0000, KERNEL_DATA)), None);
+    assert_eq!(frame_issue(&kernel(0x8000_0000_0000, 0, 0)), Some(FrameIssue::NonCanonicalRip(0x8000_0000_0000)));
+    assert_eq!(frame_issue(&kernel(0, 0, USER_DATA)), Some(FrameIssue::BadSelectors { cs: KERNEL_CODE, ss: USER_DATA }));
+
+    let ring1 = IretRegisters { cs: KERNEL_CODE | 1, ..user(0, 0) };
+    assert_eq!(frame_issue(&ring1), Some(FrameIssue::BadRing { cs: KERNEL_CODE | 1 }));
+    assert!(!frame_issue(&ring1).unwrap().is_user());
+}
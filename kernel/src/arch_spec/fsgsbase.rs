@@ -0,0 +1,102 @@
+use core::arch::asm;
+use x86_64::instructions::interrupts;
+use x86_64::registers::control::{Cr4, Cr4Flags};
+use crate::arch_spec::cpuid::{cpu_features, CpuFeatures};
+use crate::arch_spec::msr::Msr;
+use crate::context::ContextRegisters;
+use crate::cpu::PercpuBlock;
+use crate::initcall;
+use crate::initcall::InitCpuArg;
+
+/**
+ *  user fs and gs base.
+ *
+ *  in kernel mode the user gs base sits in IA32_KERNEL_GS_BASE (swapped with
+ *  the pcr on entry), the user fs base in `PercpuBlock::user_fsbase` while
+ *  IA32_FS_BASE points to the kernel tls block (see `tls`). cpus with
+ *  fsgsbase get CR4.FSGSBASE set at boot and are switched with RD/WRFSBASE
+ *  and RD/WRGSBASE around a swapgs, which skips the serializing msr
+ *  accesses. otherwise the msrs are used.
+ *
+ *  userspace may change both bases on its own with fsgsbase, the copies in
+ *  `ContextRegisters` of a running context are only right after
+ *  [`save_user_bases`]. the context switch saves them before it loads the next
+ *  ones, anyone else reading the current context's copy has to save first.
+ */
+
+unsafe fn fsgsbase_initcall(_arg: &InitCpuArg) {
+    // every ap has the features of bsp, checked by the initcall before
+    if fsgsbase() {
+        Cr4::update(|cr4| *cr4 |= Cr4Flags::FSGSBASE);
+    }
+}
+initcall!(arch, All, fsgsbase_initcall, order = 1);
+
+#[inline(always)]
+pub fn fsgsbase() -> bool {
+    cpu_features().contains(CpuFeatures::FSGSBASE)
+}
+
+/// live IA32_FS_BASE, the kernel tls block in kernel mode
+pub unsafe fn fsbase() -> usize {
+    if fsgsbase() {
+        let base: usize;
+        asm!("rdfsbase {}", out(reg) base, options(nomem, nostack, preserves_flags));
+        base
+    } else {
+        Msr::IA32_FS_BASE.read() as usize
+    }
+}
+
+pub unsafe fn set_fsbase(base: usize) {
+    if fsgsbase() {
+        asm!("wrfsbase {}", in(reg) base, options(nomem, nostack, preserves_flags));
+    } else {
+        Msr::IA32_FS_BASE.write(base as u64);
+    }
+}
+
+/// loaded into IA32_FS_BASE on the way back to userspace
+pub fn user_fsbase() -> usize {
+    PercpuBlock::current().user_fsbase.get()
+}
+
+pub fn set_user_fsbase(base: usize) {
+    PercpuBlock::current().user_fsbase.set(base);
+}
+
+pub unsafe fn user_gsbase() -> usize {
+    if fsgsbase() {
+        // the active gs base is the pcr until swapped, no interrupt may see that
+        interrupts::without_interrupts(|| {
+            let base: usize;
+            asm!("swapgs", "rdgsbase {}", "swapgs", out(reg) base, options(nomem, nostack, preserves_flags));
+            base
+        })
+    } else {
+        Msr::IA32_KERNEL_GS_BASE.read() as usize
+    }
+}
+
+pub unsafe fn set_user_gsbase(base: usize) {
+    if fsgsbase() {
+        interrupts::without_interrupts(|| {
+            asm!("swapgs", "wrgsbase {}", "swapgs", in(reg) base, options(nomem, nostack, preserves_flags));
+        })
+    } else {
+        Msr::IA32_KERNEL_GS_BASE.write(base as u64);
+    }
+}
+
+/// read the live bases of the running context into `regs`
+pub unsafe fn save_user_bases(regs: &mut ContextRegisters) {
+    regs.fsbase = user_fsbase();
+    regs.gsbase = user_gsbase();
+}
+
+/// context switch hook, saves the bases of `prev` before loading the ones of `next`
+pub unsafe fn switch_user_bases(prev: &mut ContextRegisters, next: &ContextRegisters) {
+    save_user_bases(prev);
+    set_user_fsbase(next.fsbase);
+    set_user_gsbase(next.gsbase);
+}
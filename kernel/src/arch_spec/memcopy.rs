@@ -0,0 +1,220 @@
+use core::arch::asm;
+use core::arch::x86_64::_rdtsc;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::arch_spec::cpuid::{cpu_features, CpuFeatures};
+use crate::initcall;
+use crate::initcall::InitCpuArg;
+use crate::mem::vmalloc::{vfree, vmalloc};
+use crate::infohart;
+
+/**
+ *  bulk copy and zero fill of kernel memory: loaded images, fresh frames.
+ *
+ *  with fast strings (erms) `rep movsb` / `rep stosb` beat any loop the
+ *  kernel can build without simd registers, without it the compiler's memcpy
+ *  is used. big copies pollute the caches with data nobody reads soon, there
+ *  sse2 `movnti` stores bypass them, eight bytes at a time from general
+ *  purpose registers, so the kernel still runs without sse state.
+ *
+ *  whether and from which size the non-temporal path pays off depends on the
+ *  cpu and the hypervisor, the boot run times both paths on a few sizes and
+ *  keeps the smallest size where non-temporal stores win. until then, and if
+ *  they never win, everything goes through the string instructions.
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CopyPath {
+    /// `rep movsb` / `rep stosb` with erms, the compiler's memcpy otherwise
+    Strings,
+    /// `movnti` stores, eight bytes per store
+    NonTemporal,
+}
+
+impl CopyPath {
+    pub fn name(self) -> &'static str {
+        match self {
+            CopyPath::Strings => if cpu_features().contains(CpuFeatures::ERMS) { "rep movsb" } else { "memcpy" },
+            CopyPath::NonTemporal => "movnti",
+        }
+    }
+}
+
+/// sizes the boot run compares the paths on
+pub const BENCH_SIZES: [usize; 5] = [4 << 10, 16 << 10, 64 << 10, 256 << 10, 1 << 20];
+const BENCH_ROUNDS: usize = 8;
+
+// copies of at least this many bytes take the non-temporal path
+static NT_THRESHOLD: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// path `copy` and `zero` take for `len` bytes
+#[inline(always)]
+pub fn path_for(len: usize) -> CopyPath {
+    if len >= NT_THRESHOLD.load(Ordering::Relaxed) { CopyPath::NonTemporal } else { CopyPath::Strings }
+}
+
+unsafe fn copy_strings(dst: *mut u8, src: *const u8, len: usize) {
+    if cpu_features().contains(CpuFeatures::ERMS) {
+        asm!("rep movsb", inout("rdi") dst => _, inout("rsi") src => _, inout("rcx") len => _, options(nostack, preserves_flags));
+    } else {
+        ptr::copy_nonoverlapping(src, dst, len);
+    }
+}
+
+unsafe fn zero_strings(dst: *mut u8, len: usize) {
+    if cpu_features().contains(CpuFeatures::ERMS) {
+        asm!("rep stosb", inout("rdi") dst => _, inout("rcx") len => _, in("al") 0u8, options(nostack, preserves_flags));
+    } else {
+        ptr::write_bytes(dst, 0, len);
+    }
+}
+
+// `dst` aligned to 8, `len` a multiple of 64
+unsafe fn copy_nt(dst: *mut u8, src: *const u8, len: usize) {
+    asm!(
+        "2:",
+        "mov {t0}, [{src}]",
+        "mov {t1}, [{src} + 8]",
+        "movnti [{dst}], {t0}",
+        "movnti [{dst} + 8], {t1}",
+        "mov {t0}, [{src} + 16]",
+        "mov {t1}, [{src} + 24]",
+        "movnti [{dst} + 16], {t0}",
+        "movnti [{dst} + 24], {t1}",
+        "mov {t0}, [{src} + 32]",
+        "mov {t1}, [{src} + 40]",
+        "movnti [{dst} + 32], {t0}",
+        "movnti [{dst} + 40], {t1}",
+        "mov {t0}, [{src} + 48]",
+        "mov {t1}, [{src} + 56]",
+        "movnti [{dst} + 48], {t0}",
+        "movnti [{dst} + 56], {t1}",
+        "add {src}, 64",
+        "add {dst}, 64",
+        "sub {len}, 64",
+        "jnz 2b",
+        // weakly ordered, make them visible before anything that follows
+        "sfence",
+        src = inout(reg) src => _,
+        dst = inout(reg) dst => _,
+        len = inout(reg) len => _,
+        t0 = out(reg) _,
+        t1 = out(reg) _,
+        options(nostack),
+    );
+}
+
+// `dst` aligned to 8, `len` a multiple of 64
+unsafe fn zero_nt(dst: *mut u8, len: usize) {
+    asm!(
+        "2:",
+        "movnti [{dst}], {zero}",
+        "movnti [{dst} + 8], {zero}",
+        "movnti [{dst} + 16], {zero}",
+        "movnti [{dst} + 24], {zero}",
+        "movnti [{dst} + 32], {zero}",
+        "movnti [{dst} + 40], {zero}",
+        "movnti [{dst} + 48], {zero}",
+        "movnti [{dst} + 56], {zero}",
+        "add {dst}, 64",
+        "sub {len}, 64",
+        "jnz 2b",
+        "sfence",
+        dst = inout(reg) dst => _,
+        len = inout(reg) len => _,
+        zero = in(reg) 0u64,
+        options(nostack),
+    );
+}
+
+/// copy `len` bytes through `path`, the non-temporal one takes the 64 byte
+/// blocks from the first 8 byte aligned `dst` and leaves head and tail to the strings
+pub unsafe fn copy_with(path: CopyPath, dst: *mut u8, src: *const u8, len: usize) {
+    let head = dst.align_offset(8).min(len);
+    let body = (len - head) & !63;
+    if path == CopyPath::Strings || body == 0 {
+        return copy_strings(dst, src, len);
+    }
+    copy_strings(dst, src, head);
+    copy_nt(dst.add(head), src.add(head), body);
+    copy_strings(dst.add(head + body), src.add(head + body), len - head - body);
+}
+
+/// zero `len` bytes through `path`, see [`copy_with`]
+pub unsafe fn zero_with(path: CopyPath, dst: *mut u8, len: usize) {
+    let head = dst.align_offset(8).min(len);
+    let body = (len - head) & !63;
+    if path == CopyPath::Strings || body == 0 {
+        return zero_strings(dst, len);
+    }
+    zero_strings(dst, head);
+    zero_nt(dst.add(head), body);
+    zero_strings(dst.add(head + body), len - head - body);
+}
+
+/// copy `len` bytes from `src` to `dst` on the path picked for the size
+#[inline]
+pub unsafe fn copy(dst: *mut u8, src: *const u8, len: usize) {
+    copy_with(path_for(len), dst, src, len)
+}
+
+/// zero `len` bytes at `dst` on the path picked for the size
+#[inline]
+pub unsafe fn zero(dst: *mut u8, len: usize) {
+    zero_with(path_for(len), dst, len)
+}
+
+/// fewest tsc ticks of copying `len` bytes of `buf` into its second half through `path`
+pub fn time_copy(path: CopyPath, buf: &mut [u8], len: usize) -> u64 {
+    assert!(2 * len <= buf.len(), "benchmark buffer below {} bytes", 2 * len);
+    let (src, dst) = buf.split_at_mut(len);
+    (0..BENCH_ROUNDS)
+        .map(|_| unsafe {
+            let start = _rdtsc();
+            copy_with(path, dst.as_mut_ptr(), src.as_ptr(), len);
+            _rdtsc().saturating_sub(start)
+        })
+        .min()
+        .unwrap_or(0)
+}
+
+unsafe fn memcopy_initcall(_: &InitCpuArg) {
+    let len = 2 * BENCH_SIZES[BENCH_SIZES.len() - 1];
+    let Ok(buf) = vmalloc(len) else {
+        infohart!("memcopy: no memory to time copies, keeping {}", CopyPath::Strings.name());
+        return;
+    };
+    let bytes = core::slice::from_raw_parts_mut(buf.as_mut_ptr::<u8>(), len);
+    let threshold = BENCH_SIZES.iter()
+        .find(|size| time_copy(CopyPath::NonTemporal, bytes, **size) < time_copy(CopyPath::Strings, bytes, **size))
+        .copied();
+    vfree(buf);
+
+    match threshold {
+        Some(size) => infohart!("memcopy: {} up to {} KiB, {} from there", CopyPath::Strings.name(), size >> 10, CopyPath::NonTemporal.name()),
+        None => infohart!("memcopy: {} for all sizes", CopyPath::Strings.name()),
+    }
+    NT_THRESHOLD.store(threshold.unwrap_or(usize::MAX), Ordering::SeqCst);
+}
+// features are known, the tsc ticks, nothing is loaded yet
+initcall!(arch, Bsp, memcopy_initcall, order = 50);
+
+#[test_case]
+pub(crate) fn test_memcopy_paths() {
+    let mut src = [0u8; 300];
+    src.iter_mut().enumerate().for_each(|(i, byte)| *byte = i as u8);
+    for path in [CopyPath::Strings, CopyPath::NonTemporal] {
+        // odd offsets give a head, body and tail on the non-temporal path
+        for offset in [0, 3] {
+            let mut dst = [0xffu8; 300];
+            let len = 300 - offset;
+            unsafe { copy_with(path, dst[offset..].as_mut_ptr(), src.as_ptr(), len) };
+            assert_eq!(&dst[offset..], &src[..len], "{:?} copy at offset {}", path, offset);
+            assert!(dst[..offset].iter().all(|byte| *byte == 0xff));
+
+            unsafe { zero_with(path, dst[offset..].as_mut_ptr(), len - 1) };
+            assert!(dst[offset..299].iter().all(|byte| *byte == 0), "{:?} zero at offset {}", path, offset);
+            assert_eq!(dst[299], src[299 - offset]);
+        }
+    }
+}
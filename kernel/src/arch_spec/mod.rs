@@ -1,22 +1,13 @@
-use core::arch::asm;
-
 pub mod msr;
+mod boot_state;
 pub mod cpuid;
+pub mod debug;
+pub mod frame_check;
+pub mod fsgsbase;
+pub mod memcopy;
+pub mod pmu;
 pub mod port;
+pub mod tls;
+pub mod usercopy;
 
-#[naked]
-pub unsafe extern "C" fn copy_to(dst: usize, src: usize, len: usize) -> u8 {
-    // `movsb` instruction copies from rsi(arg 1) to rdi(arg 0)
-    // control length with `rep` instruction
-    asm!(
-        "
-        xor eax, eax
-        mov rcx, rdx
-        stac
-        rep movsb
-        clac
-        ret
-        ",
-        options(noreturn)
-    )
-}
\ No newline at end of file
+pub use boot_state::verify_boot_state;
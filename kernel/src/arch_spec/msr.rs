@@ -1,11 +1,151 @@
-use x86_64::registers::model_specific::Msr;
+use core::arch::{asm, global_asm};
+use core::fmt;
+use crate::syscall::InterruptStack;
 
-#[inline]
-pub unsafe fn rdmsr(reg: u32) -> u64 {
-    Msr::new(reg).read()
+/**
+ *  model specific registers.
+ *
+ *  every msr the kernel touches is a named constant of [`Msr`], raw numbers are
+ *  not accepted anywhere. optional msrs are probed with [`Msr::try_read`] /
+ *  [`Msr::try_write`], a #GP raised by the access is caught by the fault handler
+ *  through [`msr_probe_fixup`] and reported as `None`.
+ */
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Msr(u32);
+
+impl Msr {
+    pub const IA32_APIC_BASE: Msr = Msr(0x1b);
+    pub const IA32_FIXED_CTR0: Msr = Msr(0x309);
+    pub const IA32_FIXED_CTR1: Msr = Msr(0x30a);
+    pub const IA32_FIXED_CTR_CTRL: Msr = Msr(0x38d);
+    pub const IA32_PERF_GLOBAL_STATUS: Msr = Msr(0x38e);
+    pub const IA32_PERF_GLOBAL_CTRL: Msr = Msr(0x38f);
+    pub const IA32_PERF_GLOBAL_OVF_CTRL: Msr = Msr(0x390);
+    pub const IA32_MISC_ENABLE: Msr = Msr(0x1a0);
+    pub const IA32_TSC_DEADLINE: Msr = Msr(0x6e0);
+
+    // x2apic registers, mmio offset >> 4 + 0x800
+    pub const X2APIC_ID: Msr = Msr(0x802);
+    pub const X2APIC_VERSION: Msr = Msr(0x803);
+    pub const X2APIC_EOI: Msr = Msr(0x80b);
+    pub const X2APIC_SVR: Msr = Msr(0x80f);
+    // in-service register, 32 vectors each
+    pub const X2APIC_ISR: [Msr; 8] = [Msr(0x810), Msr(0x811), Msr(0x812), Msr(0x813), Msr(0x814), Msr(0x815), Msr(0x816), Msr(0x817)];
+    pub const X2APIC_ESR: Msr = Msr(0x828);
+    pub const X2APIC_ICR: Msr = Msr(0x830);
+    pub const X2APIC_LVT_TIMER: Msr = Msr(0x832);
+    pub const X2APIC_LVT_PMC: Msr = Msr(0x834);
+    pub const X2APIC_LVT_LINT0: Msr = Msr(0x835);
+    pub const X2APIC_LVT_ERROR: Msr = Msr(0x837);
+    pub const X2APIC_TIMER_INIT_COUNT: Msr = Msr(0x838);
+    pub const X2APIC_TIMER_CURRENT_COUNT: Msr = Msr(0x839);
+    pub const X2APIC_TIMER_DIV_CONF: Msr = Msr(0x83e);
+
+    pub const IA32_EFER: Msr = Msr(0xc000_0080);
+    pub const IA32_STAR: Msr = Msr(0xc000_0081);
+    pub const IA32_LSTAR: Msr = Msr(0xc000_0082);
+    pub const IA32_FMASK: Msr = Msr(0xc000_0084);
+    pub const IA32_FS_BASE: Msr = Msr(0xc000_0100);
+    pub const IA32_GS_BASE: Msr = Msr(0xc000_0101);
+    pub const IA32_KERNEL_GS_BASE: Msr = Msr(0xc000_0102);
+    pub const IA32_TSC_AUX: Msr = Msr(0xc000_0103);
+
+    /// msr number, for `const` operands of inline asm
+    pub const fn number(self) -> u32 {
+        self.0
+    }
+
+    /// caller ensures the msr exists on this cpu
+    #[inline]
+    pub unsafe fn read(self) -> u64 {
+        let (low, high): (u32, u32);
+        asm!("rdmsr", in("ecx") self.0, out("eax") low, out("edx") high, options(nomem, nostack, preserves_flags));
+        (high as u64) << 32 | low as u64
+    }
+
+    /// caller ensures the msr exists and the write keeps memory safety
+    #[inline]
+    pub unsafe fn write(self, value: u64) {
+        asm!("wrmsr", in("ecx") self.0, in("eax") value as u32, in("edx") (value >> 32) as u32, options(nostack, preserves_flags));
+    }
+
+    #[inline]
+    pub unsafe fn update(self, f: impl FnOnce(u64) -> u64) {
+        self.write(f(self.read()))
+    }
+
+    /// read an optional msr, `None` if the cpu does not implement it
+    pub fn try_read(self) -> Option<u64> {
+        let result = unsafe { msr_probe_read(self.0) };
+        (result.failed == 0).then_some(result.value)
+    }
+
+    /// write an optional msr, `None` if the cpu does not implement it or rejects the value
+    pub unsafe fn try_write(self, value: u64) -> Option<()> {
+        (msr_probe_write(self.0, value) == 0).then_some(())
+    }
 }
 
-#[inline]
-pub unsafe fn wrmsr(reg: u32, value: u64) {
-    Msr::new(reg).write(value)
-}
\ No newline at end of file
+impl fmt::Debug for Msr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Msr({:#x})", self.0)
+    }
+}
+
+#[repr(C)]
+struct ProbeResult {
+    value: u64,
+    failed: u64,
+}
+
+extern "sysv64" {
+    fn msr_probe_read(msr: u32) -> ProbeResult;
+    fn msr_probe_write(msr: u32, value: u64) -> u64;
+    static msr_probe_read_insn: u8;
+    static msr_probe_write_insn: u8;
+}
+
+// r8 is the failure flag, set by `msr_probe_fixup` when the access faults
+global_asm!(
+    ".global msr_probe_read",
+    ".global msr_probe_read_insn",
+    "msr_probe_read:",
+    "    mov ecx, edi",
+    "    xor r8d, r8d",
+    "msr_probe_read_insn:",
+    "    rdmsr",
+    "    shl rdx, 32",
+    "    or rax, rdx",
+    "    mov rdx, r8",
+    "    ret",
+    "",
+    ".global msr_probe_write",
+    ".global msr_probe_write_insn",
+    "msr_probe_write:",
+    "    mov ecx, edi",
+    "    mov eax, esi",
+    "    mov rdx, rsi",
+    "    shr rdx, 32",
+    "    xor r8d, r8d",
+    "msr_probe_write_insn:",
+    "    wrmsr",
+    "    mov rax, r8",
+    "    ret",
+);
+
+// rdmsr and wrmsr are both `0f xx`
+const MSR_INSN_LEN: usize = 2;
+
+/// called by #GP handler, skips a faulting probe instruction and flags the failure.
+/// returns false if the fault did not come from a probe.
+pub fn msr_probe_fixup(stack: &mut InterruptStack) -> bool {
+    let rip = stack.iret.rip;
+    let probes = unsafe { [&msr_probe_read_insn as *const u8 as usize, &msr_probe_write_insn as *const u8 as usize] };
+    if !probes.contains(&rip) {
+        return false;
+    }
+    stack.scratch.r8 = 1;
+    stack.iret.rip = rip + MSR_INSN_LEN;
+    true
+}
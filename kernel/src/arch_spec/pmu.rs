@@ -0,0 +1,176 @@
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use libvdso::error::{EBUSY, EINVAL, ENODEV, KError, KResult};
+use libvdso::flag::{PROFILE_EVENT_CYCLES, PROFILE_EVENT_INSTRUCTIONS};
+use spin::Once;
+use crate::acpi::local_apic::LOCAL_APIC;
+use crate::arch_spec::cpuid::cpuid;
+use crate::arch_spec::msr::Msr;
+use crate::config::MAX_CPUS;
+use crate::cpu::PercpuBlock;
+use crate::ipi::{ipi, IpiKind, IpiTarget};
+use crate::syscall::IretRegisters;
+use crate::CPU_COUNT;
+
+/**
+ *  performance monitoring unit, sampling profiler on the fixed counters.
+ *
+ *  architectural perfmon version 2 or later has fixed counter 0 counting
+ *  retired instructions and fixed counter 1 unhalted core cycles, enabled
+ *  through IA32_PERF_GLOBAL_CTRL. a profile arms one of them on every cpu to
+ *  overflow after `period` events, kernel mode only. the overflow raises a
+ *  pmi through the local apic perf counter lvt, delivered as nmi so code
+ *  running with interrupts masked (syscalls, locks) is sampled too. the nmi
+ *  handler records the interrupted rip into the ring of its cpu and re-arms
+ *  the counter.
+ *
+ *  start and stop are broadcast with `IpiKind::Profile`, each cpu programs
+ *  its own msrs from the shared config. rings are read without stopping,
+ *  oldest first, the oldest samples are overwritten.
+ */
+
+pub const SAMPLE_ENTRIES: usize = 4096;
+// shortest period accepted, shorter ones would drown the cpu in nmis
+pub const MIN_PERIOD: u64 = 10_000;
+
+const FIXED_INSTRUCTIONS: u32 = 0;
+const FIXED_CYCLES: u32 = 1;
+// per counter nibble of IA32_FIXED_CTR_CTRL
+const FIXED_CTRL_OS: u64 = 1 << 0;
+const FIXED_CTRL_PMI: u64 = 1 << 3;
+const LVT_DELIVERY_NMI: u32 = 0b100 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+
+struct PmuInfo {
+    // bits of the fixed counters
+    width: u32,
+    fixed_counters: u32,
+}
+
+struct SampleRing {
+    head: AtomicUsize,
+    rips: [AtomicU64; SAMPLE_ENTRIES],
+}
+
+const EMPTY_SAMPLE: AtomicU64 = AtomicU64::new(0);
+const EMPTY_SAMPLE_RING: SampleRing = SampleRing { head: AtomicUsize::new(0), rips: [EMPTY_SAMPLE; SAMPLE_ENTRIES] };
+
+static PMU: Once<Option<PmuInfo>> = Once::new();
+static RINGS: [SampleRing; MAX_CPUS] = [EMPTY_SAMPLE_RING; MAX_CPUS];
+static RUNNING: AtomicBool = AtomicBool::new(false);
+// fixed counter index of the running profile
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+static PERIOD: AtomicU64 = AtomicU64::new(0);
+
+fn pmu() -> Option<&'static PmuInfo> {
+    PMU.call_once(|| {
+        let info = cpuid().get_performance_monitoring_info()?;
+        (info.version_id() >= 2 && info.fixed_function_counters() > FIXED_CYCLES as u8).then(|| PmuInfo {
+            width: info.fixed_function_counters_bit_width() as u32,
+            fixed_counters: info.fixed_function_counters() as u32,
+        })
+    }).as_ref()
+}
+
+fn fixed_counter(index: u32) -> Msr {
+    match index {
+        FIXED_INSTRUCTIONS => Msr::IA32_FIXED_CTR0,
+        _ => Msr::IA32_FIXED_CTR1,
+    }
+}
+
+// counts up from here, overflows after `period` events
+fn preload(pmu: &PmuInfo, period: u64) -> u64 {
+    let mask = if pmu.width >= 64 { u64::MAX } else { (1 << pmu.width) - 1 };
+    period.wrapping_neg() & mask
+}
+
+/// program the counters of this cpu from the shared config, called on every cpu
+pub unsafe fn apply() {
+    let Some(pmu) = pmu() else { return };
+    Msr::IA32_PERF_GLOBAL_CTRL.write(0);
+    Msr::IA32_FIXED_CTR_CTRL.write(0);
+    if !RUNNING.load(Ordering::SeqCst) {
+        LOCAL_APIC.set_lvt_pmc(LVT_DELIVERY_NMI | LVT_MASKED);
+        return;
+    }
+    let counter = COUNTER.load(Ordering::SeqCst) as u32;
+    fixed_counter(counter).write(preload(pmu, PERIOD.load(Ordering::SeqCst)));
+    Msr::IA32_PERF_GLOBAL_OVF_CTRL.write(1 << (32 + counter));
+    LOCAL_APIC.set_lvt_pmc(LVT_DELIVERY_NMI);
+    Msr::IA32_FIXED_CTR_CTRL.write((FIXED_CTRL_OS | FIXED_CTRL_PMI) << (4 * counter));
+    Msr::IA32_PERF_GLOBAL_CTRL.write(1 << (32 + counter));
+}
+
+// the calling cpu right away, the others through an ipi
+fn broadcast() {
+    unsafe { apply() };
+    ipi(IpiKind::Profile, IpiTarget::Other);
+}
+
+/// start sampling every `period` events of `event` (`PROFILE_EVENT_*`) on all cpus, clears the rings
+pub fn start(event: usize, period: u64) -> KResult<()> {
+    let pmu = pmu().ok_or(KError::new(ENODEV))?;
+    let counter = match event {
+        PROFILE_EVENT_INSTRUCTIONS => FIXED_INSTRUCTIONS,
+        PROFILE_EVENT_CYCLES => FIXED_CYCLES,
+        _ => return Err(KError::new(EINVAL)),
+    };
+    if counter >= pmu.fixed_counters || period < MIN_PERIOD || (pmu.width < 64 && period >= 1 << pmu.width) {
+        return Err(KError::new(EINVAL));
+    }
+    if RUNNING.load(Ordering::SeqCst) {
+        return Err(KError::new(EBUSY));
+    }
+    for ring in RINGS.iter() {
+        ring.head.store(0, Ordering::SeqCst);
+    }
+    COUNTER.store(counter as u64, Ordering::SeqCst);
+    PERIOD.store(period, Ordering::SeqCst);
+    RUNNING.store(true, Ordering::SeqCst);
+    broadcast();
+    Ok(())
+}
+
+/// stop sampling on all cpus, the rings are kept for dumping
+pub fn stop() -> KResult<()> {
+    pmu().ok_or(KError::new(ENODEV))?;
+    RUNNING.store(false, Ordering::SeqCst);
+    broadcast();
+    Ok(())
+}
+
+/// visit the samples of every cpu as `(cpu, rip)`, oldest first per cpu
+pub fn for_each_sample(mut f: impl FnMut(usize, u64) -> bool) {
+    let cpu_count = (CPU_COUNT.load(Ordering::SeqCst) as usize).min(MAX_CPUS);
+    for (cpu, ring) in RINGS[..cpu_count].iter().enumerate() {
+        let head = ring.head.load(Ordering::Acquire);
+        for index in head.saturating_sub(SAMPLE_ENTRIES)..head {
+            let rip = ring.rips[index % SAMPLE_ENTRIES].load(Ordering::Relaxed);
+            if rip != 0 && !f(cpu, rip) {
+                return;
+            }
+        }
+    }
+}
+
+/// called by the nmi handler, records a sample and returns true if the nmi is a pmi
+pub unsafe fn handle_pmi(iret: &IretRegisters) -> bool {
+    // never initializes, the nmi may have interrupted that
+    let Some(Some(pmu)) = PMU.get() else { return false };
+    let counter = COUNTER.load(Ordering::Relaxed) as u32;
+    let overflow = 1 << (32 + counter);
+    if Msr::IA32_PERF_GLOBAL_STATUS.read() & overflow == 0 {
+        return false;
+    }
+    let ring = &RINGS[PercpuBlock::current().cpu_id.0 as usize];
+    let index = ring.head.fetch_add(1, Ordering::Relaxed);
+    ring.rips[index % SAMPLE_ENTRIES].store(iret.rip as u64, Ordering::Release);
+
+    fixed_counter(counter).write(preload(pmu, PERIOD.load(Ordering::Relaxed)));
+    Msr::IA32_PERF_GLOBAL_OVF_CTRL.write(overflow);
+    // the lvt masks itself on delivery
+    if RUNNING.load(Ordering::Relaxed) {
+        LOCAL_APIC.set_lvt_pmc(LVT_DELIVERY_NMI);
+    }
+    true
+}
@@ -1,11 +1,100 @@
-use x86_64::instructions::port::Port;
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+use core::ops::Range;
+use x86_64::instructions::port::{Port, PortRead, PortWrite};
+use libvdso::error::{EBUSY, EINVAL, KError, KResult};
+use crate::sync::IrqSpinlock;
+use crate::warnhart;
 
-#[inline]
-pub unsafe fn inb(port: u16) -> u8 {
-    Port::new(port).read()
+/**
+ *  i/o port ownership.
+ *
+ *  a driver claims the port range of its device with [`request_region`] and
+ *  gets typed ports only from the returned [`IoRegion`], so two drivers can not
+ *  silently poke the same device. overlapping claims are refused and logged.
+ */
+
+struct Claim {
+    ports: Range<u32>,
+    owner: &'static str,
+}
+
+// claimed from interrupt handlers too, e.g. first debug print
+static CLAIMS: IrqSpinlock<Vec<Claim>> = IrqSpinlock::new(Vec::new());
+
+/// ports `base..base + len` owned by one driver
+#[derive(Debug)]
+pub struct IoRegion {
+    base: u16,
+    len: u16,
+}
+
+/// claim `len` ports from `base` for `owner`, `EBUSY` if any of them is claimed already
+pub fn request_region(base: u16, len: u16, owner: &'static str) -> KResult<IoRegion> {
+    let end = base as u32 + len as u32;
+    if len == 0 || end > u16::MAX as u32 + 1 {
+        return Err(KError::new(EINVAL));
+    }
+    let ports = base as u32..end;
+
+    let mut claims = CLAIMS.lock();
+    if let Some(other) = claims.iter().find(|c| c.ports.start < ports.end && ports.start < c.ports.end) {
+        warnhart!(
+            "ioport: {} requested {:#x}-{:#x}, already owned by {} ({:#x}-{:#x})",
+            owner, ports.start, ports.end - 1, other.owner, other.ports.start, other.ports.end - 1
+        );
+        return Err(KError::new(EBUSY));
+    }
+    claims.push(Claim { ports, owner });
+    Ok(IoRegion { base, len })
 }
 
-#[inline]
-pub unsafe fn outb(port: u16, value: u8) {
-    Port::new(port).write(value)
-}
\ No newline at end of file
+/// give the ports back, e.g. a driver that failed to probe its device
+pub fn release_region(region: IoRegion) {
+    let start = region.base as u32;
+    CLAIMS.lock().retain(|c| c.ports.start != start);
+}
+
+/// claimed ranges sorted by port, for introspection
+pub fn claimed_regions() -> Vec<(Range<u32>, &'static str)> {
+    let mut regions: Vec<_> = CLAIMS.lock().iter().map(|c| (c.ports.clone(), c.owner)).collect();
+    regions.sort_by_key(|(ports, _)| ports.start);
+    regions
+}
+
+impl IoRegion {
+    pub fn base(&self) -> u16 {
+        self.base
+    }
+
+    /// port at `offset` inside this region
+    pub fn port<T>(&self, offset: u16) -> IoPort<T> {
+        assert!(
+            offset as usize + core::mem::size_of::<T>() <= self.len as usize,
+            "port offset {:#x} out of region {:#x}+{:#x}", offset, self.base, self.len
+        );
+        IoPort { port: self.base + offset, _marker: PhantomData }
+    }
+}
+
+/// a port inside a claimed [`IoRegion`], `T` is the access width
+#[derive(Debug, Clone, Copy)]
+pub struct IoPort<T> {
+    port: u16,
+    _marker: PhantomData<T>,
+}
+
+impl<T: PortRead> IoPort<T> {
+    /// side effects of reading depend on the device
+    #[inline]
+    pub unsafe fn read(&self) -> T {
+        Port::<T>::new(self.port).read()
+    }
+}
+
+impl<T: PortWrite> IoPort<T> {
+    #[inline]
+    pub unsafe fn write(&self, value: T) {
+        Port::<T>::new(self.port).write(value)
+    }
+}
@@ -0,0 +1,131 @@
+use core::mem::{align_of, size_of};
+use core::ptr;
+use shared::arg::TlsTemplate;
+use x86_64::instructions::interrupts;
+use crate::arch_spec::fsgsbase::{fsbase, set_fsbase};
+use crate::cpu::PercpuBlock;
+use crate::initcall;
+use crate::initcall::{kernel_arg, InitCpuArg};
+use crate::mem::frame_allocator::frame_alloc_n;
+use crate::mem::PAGE_SIZE;
+use crate::syscall::IretRegisters;
+
+/**
+ *  kernel thread locals.
+ *
+ *  the bootloader passes the PT_TLS segment of the kernel image as
+ *  `KernelArg::tls_template`. every cpu gets a block built from it in the
+ *  x86_64 variant II layout: the initialized image, zero filled up to its
+ *  memory size, right below the thread pointer, which holds its own address.
+ *  the kernel is linked local-exec, `#[thread_local]` statics are reached at
+ *  fixed negative offsets from fs. fs does not follow contexts, a kernel
+ *  thread local belongs to the cpu and like `PercpuBlock` only stays the same
+ *  one while preemption is off.
+ *
+ *  IA32_FS_BASE is the thread pointer whenever the kernel runs on behalf of
+ *  an entry. entries from ring 3 park the user fs base in
+ *  `PercpuBlock::user_fsbase` first and returns to ring 3 load it back, the
+ *  context switch and `fsgsbase` work on the parked copy. paranoid handlers
+ *  (nmi, #mc, #db) may interrupt an entry before the swap and must not use
+ *  thread locals.
+ */
+
+/// distance between the start of the image and the thread pointer, the
+/// linker resolves local-exec offsets against the size rounded to the segment alignment
+fn image_distance(template: &TlsTemplate) -> usize {
+    template.mem_size.next_multiple_of(template.align.max(1))
+}
+
+/// bytes of a block for `template`, the offset of the thread pointer and the offset of the image in it
+fn block_layout(template: &TlsTemplate) -> (usize, usize, usize) {
+    // the tcb is a single self pointer
+    let align = template.align.max(align_of::<usize>());
+    let tp_offset = image_distance(template).next_multiple_of(align);
+    (tp_offset + size_of::<usize>(), tp_offset, tp_offset - image_distance(template))
+}
+
+unsafe fn tls_initcall(_arg: &InitCpuArg) {
+    init_tls(&kernel_arg().tls_template);
+}
+// fsgsbase is enabled by order 1
+initcall!(arch, All, tls_initcall, order = 2);
+
+#[cold]
+pub unsafe fn init_tls(template: &TlsTemplate) {
+    // frames are page aligned, which covers any sane segment alignment
+    assert!(template.align <= PAGE_SIZE, "kernel tls alignment {:#x} is above a page", template.align);
+    let (size, tp_offset, image_offset) = block_layout(template);
+    let block = frame_alloc_n(size.div_ceil(PAGE_SIZE))
+        .expect("failed to allocate phys frame for kernel tls")
+        .start_address().as_u64() as *mut u8;
+
+    // the image ends with .tbss, only zeroed
+    let start = block.add(image_offset);
+    if template.file_size > 0 {
+        ptr::copy_nonoverlapping(template.start_virt_addr as *const u8, start, template.file_size);
+    }
+    start.add(template.file_size).write_bytes(0, template.mem_size - template.file_size);
+
+    let tp = block.add(tp_offset).cast::<usize>();
+    tp.write(tp as usize);
+
+    PercpuBlock::current().tls.set(tp as usize);
+    set_fsbase(tp as usize);
+}
+
+/// first thing of an entry from ring 3, before any thread local is touched
+#[inline(always)]
+pub unsafe fn enter_from_user() {
+    let percpu = PercpuBlock::current();
+    percpu.user_fsbase.set(fsbase());
+    set_fsbase(percpu.tls.get());
+}
+
+/// last thing before a return to ring 3, also called by the asm of the
+/// syscall return path. an interrupt taken after it would run on the user fs,
+/// so interrupts stay off, the return restores the rflags of the frame
+pub unsafe extern "C" fn exit_to_user() {
+    interrupts::disable();
+    set_fsbase(PercpuBlock::current().user_fsbase.get());
+}
+
+/// [`enter_from_user`] if the entry came from ring 3
+#[inline(always)]
+pub unsafe fn enter(iret: &IretRegisters) {
+    if iret.cs & 3 == 3 {
+        enter_from_user();
+    }
+}
+
+/// [`exit_to_user`] if the entry returns to ring 3
+#[inline(always)]
+pub unsafe fn exit(iret: &IretRegisters) {
+    if iret.cs & 3 == 3 {
+        exit_to_user();
+    }
+}
+
+#[cfg(test)]
+#[thread_local]
+static TEST_TLS: core::cell::Cell<usize> = core::cell::Cell::new(0x5a5a);
+
+#[test_case]
+pub(crate) fn test_kernel_tls() {
+    let template = |mem_size: usize, align: usize| TlsTemplate { start_virt_addr: 0, mem_size, file_size: 0, align };
+    assert_eq!(block_layout(&template(0, 0)), (8, 0, 0));
+    assert_eq!(block_layout(&template(12, 1)), (24, 16, 4));
+    assert_eq!(block_layout(&template(12, 16)), (24, 16, 0));
+    assert_eq!(block_layout(&template(12, 64)), (72, 64, 0));
+
+    // the image was copied and the static sits in the block of this cpu
+    interrupts::without_interrupts(|| {
+        let tp = PercpuBlock::current().tls.get();
+        let addr = TEST_TLS.as_ptr() as usize;
+        assert_eq!(unsafe { *(tp as *const usize) }, tp);
+        assert!(addr < tp && addr >= tp - image_distance(&kernel_arg().tls_template));
+        assert_eq!(TEST_TLS.get(), 0x5a5a);
+        TEST_TLS.set(1);
+        assert_eq!(TEST_TLS.get(), 1);
+        TEST_TLS.set(0x5a5a);
+    });
+}
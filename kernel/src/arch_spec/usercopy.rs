@@ -0,0 +1,112 @@
+use core::arch::{asm, global_asm};
+use x86_64::registers::control::{Cr4, Cr4Flags};
+use libvdso::error::{EFAULT, KError, KResult};
+use shared::layout::USER_SPACE_END;
+use crate::syscall::InterruptStack;
+
+/**
+ *  raw copies from and to user memory.
+ *
+ *  all of them end in one `rep movsb`, fast strings (erms) make it the quickest
+ *  copy for any length on recent cpus. a page fault on that instruction in ring 0
+ *  is caught by [`user_copy_fixup`], the copy stops and reports `EFAULT` instead
+ *  of taking the kernel down. user accesses are wrapped in stac/clac, only if
+ *  smap is on: both instructions are #UD on cpus without it.
+ */
+
+extern "sysv64" {
+    // returns bytes left uncopied, 0 on success
+    fn user_copy_raw(dst: *mut u8, src: *const u8, len: usize) -> usize;
+    static user_copy_insn: u8;
+    static user_copy_done: u8;
+}
+
+// rcx counts down while `rep movsb` runs, it is still the remaining length when it faults
+global_asm!(
+    ".global user_copy_raw",
+    ".global user_copy_insn",
+    ".global user_copy_done",
+    "user_copy_raw:",
+    "    mov rcx, rdx",
+    "user_copy_insn:",
+    "    rep movsb",
+    "user_copy_done:",
+    "    mov rax, rcx",
+    "    ret",
+);
+
+/// called by #PF handler, resumes a faulting kernel copy after the copy instruction.
+/// returns false if the fault did not come from a user copy.
+pub fn user_copy_fixup(stack: &mut InterruptStack) -> bool {
+    let insn = unsafe { &user_copy_insn as *const u8 as usize };
+    if stack.iret.cs & 3 != 0 || stack.iret.rip != insn {
+        return false;
+    }
+    stack.iret.rip = unsafe { &user_copy_done as *const u8 as usize };
+    true
+}
+
+fn smap_enabled() -> bool {
+    Cr4::read().contains(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION)
+}
+
+unsafe fn copy_with_user_access(dst: *mut u8, src: *const u8, len: usize) -> KResult<()> {
+    let smap = smap_enabled();
+    if smap {
+        asm!("stac", options(nomem, nostack));
+    }
+    let left = user_copy_raw(dst, src, len);
+    if smap {
+        asm!("clac", options(nomem, nostack));
+    }
+    if left == 0 { Ok(()) } else { Err(KError::new(EFAULT)) }
+}
+
+fn check_user_range(addr: usize, len: usize) -> KResult<()> {
+    match addr.checked_add(len) {
+        Some(end) if end as u64 <= USER_SPACE_END => Ok(()),
+        _ => Err(KError::new(EFAULT)),
+    }
+}
+
+/// copy `len` bytes at user address `src` of current address space to `dst`
+pub unsafe fn user_copy_in(dst: *mut u8, src: usize, len: usize) -> KResult<()> {
+    check_user_range(src, len)?;
+    copy_with_user_access(dst, src as *const u8, len)
+}
+
+/// copy `len` bytes at `src` to user address `dst` of current address space
+pub unsafe fn user_copy_out(dst: usize, src: *const u8, len: usize) -> KResult<()> {
+    check_user_range(dst, len)?;
+    copy_with_user_access(dst as *mut u8, src, len)
+}
+
+/// copy between kernel addresses of user memory, e.g. frames resolved through a
+/// page table walk. `EFAULT` if either side turns out unmapped.
+pub unsafe fn user_copy_nonoverlapping(dst: *mut u8, src: *const u8, len: usize) -> KResult<()> {
+    if user_copy_raw(dst, src, len) == 0 { Ok(()) } else { Err(KError::new(EFAULT)) }
+}
+
+#[test_case]
+fn test_user_copy_across_pages() {
+    use alloc::vec;
+    use crate::mem::PAGE_SIZE;
+
+    let src: alloc::vec::Vec<u8> = (0..3 * PAGE_SIZE).map(|i| i as u8).collect();
+    let mut dst = vec![0u8; 3 * PAGE_SIZE];
+    // longer than a page, crosses a page boundary wherever the buffers are
+    let (offset, len) = (PAGE_SIZE - 7, PAGE_SIZE + 19);
+    unsafe { user_copy_nonoverlapping(dst[offset..].as_mut_ptr(), src[offset..].as_ptr(), len) }.unwrap();
+
+    assert_eq!(&dst[offset..offset + len], &src[offset..offset + len]);
+    assert!(dst[..offset].iter().all(|b| *b == 0));
+    assert!(dst[offset + len..].iter().all(|b| *b == 0));
+}
+
+#[test_case]
+fn test_user_copy_rejects_kernel_range() {
+    // ranges are checked before anything is touched
+    let mut byte = 0u8;
+    assert_eq!(unsafe { user_copy_in(&mut byte, USER_SPACE_END as usize - 1, 2) }, Err(KError::new(EFAULT)));
+    assert_eq!(unsafe { user_copy_out(usize::MAX, &byte, 1) }, Err(KError::new(EFAULT)));
+}
@@ -1,25 +1,51 @@
 ; redox kernel trampoline for ap of x86_64
 ; trampoline for bringing up APs
-; compiled with nasm by build.rs, and included in src/acpi/madt.rs
-
-ORG 0x8000
+; compiled with nasm by build.rs, and included in src/acpi/ap_startup.rs
+;
+; assembled at 0 and copied to a page below 1 MiB picked at runtime.
+; real mode code addresses relative to cs, long mode code is rip relative,
+; the few absolute linear addresses are listed in the header and patched by kernel.
+;
+; header offsets are TRAMPOLINE_* defines from src/acpi/trampoline_layout.rs,
+; passed by build.rs.
+
+; fails to assemble unless the current offset is %1, with a negative times
+%macro at_offset 1
+    times ($ - trampoline) - (%1) db 0
+    times (%1) - ($ - trampoline) db 0
+%endmacro
+
+ORG 0
 SECTION .text
 USE16
 
 trampoline:
+    at_offset TRAMPOLINE_JUMP
     jmp short startup_ap
-    times 8 - ($ - trampoline) nop
+    times TRAMPOLINE_READY - ($ - trampoline) nop
+    at_offset TRAMPOLINE_READY
     .ready: dq 0
+    at_offset TRAMPOLINE_CPU_ID
     .cpu_id: dq 0
+    at_offset TRAMPOLINE_PAGE_TABLE
     .page_table: dq 0
+    at_offset TRAMPOLINE_STACK_START
     .stack_start: dq 0
+    at_offset TRAMPOLINE_STACK_END
     .stack_end: dq 0
+    at_offset TRAMPOLINE_CODE
     .code: dq 0
+    ; offsets of dwords the kernel adds load address to
+    at_offset TRAMPOLINE_RELOCS
+    .reloc_gdtr: dw gdtr.base
+    .reloc_long_mode: dw long_mode_ptr
+    at_offset TRAMPOLINE_RELOCS + 2 * TRAMPOLINE_RELOC_COUNT
 
 startup_ap:
     cli
 
-    xor ax, ax
+    ; sipi starts us at vector:0
+    mov ax, cs
     mov ds, ax
     mov es, ax
     mov ss, ax
@@ -66,9 +92,14 @@ startup_ap:
     mov cr0, ebx
 
     ; far jump to enable Long Mode and load CS with 64 bit segment
-    jmp gdt.kernel_code:long_mode_ap
+    jmp dword far [long_mode_ptr]
+
+long_mode_ptr:
+    dd long_mode_ap
+    dw gdt.kernel_code
 
 USE64
+DEFAULT REL
 long_mode_ap:
     mov rax, gdt.kernel_data
     mov ds, rax
@@ -80,7 +111,7 @@ long_mode_ap:
     mov rcx, [trampoline.stack_end]
     lea rsp, [rcx - 256]
 
-    mov rdi, trampoline.cpu_id
+    lea rdi, [trampoline.cpu_id]
 
     mov rax, [trampoline.code]
     mov qword [trampoline.ready], 1
@@ -144,6 +175,7 @@ flags:
 
 gdtr:
     dw gdt.end + 1  ; size
+.base:
     dq gdt          ; offset
 
 gdt:
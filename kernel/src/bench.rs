@@ -0,0 +1,271 @@
+use core::arch::x86_64::_rdtsc;
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use crate::arch::{ArchInterrupts, CurrentArch};
+use crate::arch_spec::memcopy::{path_for, time_copy, CopyPath, BENCH_SIZES};
+use crate::config::MAX_CPUS;
+use crate::context::list::{context_storage, context_storage_mut};
+use crate::context::sleep::sleep_until;
+use crate::context::spawn::{SpawnEntry, SpawnOptions};
+use crate::context::status::Status;
+use crate::context::switch::switch_context;
+use crate::cpu::{LogicalCpuId, PercpuBlock};
+use crate::device::tsc::{monotonic_ns, tsc_hz};
+use crate::initcall;
+use crate::initcall::InitCpuArg;
+use crate::interrupt::irq_count;
+use crate::ipi::{ipi_single, IpiKind};
+use crate::mem::vmalloc::{vfree, vmalloc};
+use crate::qemu_println;
+use crate::CPU_COUNT;
+
+/**
+ *  tsc based benchmarking, built with feature `bench`.
+ *
+ *  context switches and syscalls are timed on every occurrence: a switch from
+ *  picking the next context until it resumes in `post_switch_context`, a
+ *  syscall from entering the dispatcher until the return value is stored
+ *  (blocking syscalls include the time they slept, look at min and avg).
+ *  ipi round trips are only measured by the boot run, bsp pings every ap and
+ *  waits for the handler to count it. the copy paths of `memcopy` are timed
+ *  once on every size the boot run picks from.
+ *
+ *  samples are kept per cpu, the report goes to the debug console after the
+ *  boot run and then periodically while userspace keeps making syscalls.
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BenchKind {
+    Switch,
+    Syscall,
+    Ipi,
+}
+
+impl BenchKind {
+    const ALL: [BenchKind; 3] = [BenchKind::Switch, BenchKind::Syscall, BenchKind::Ipi];
+
+    fn name(self) -> &'static str {
+        match self {
+            BenchKind::Switch => "switch",
+            BenchKind::Syscall => "syscall",
+            BenchKind::Ipi => "ipi rtt",
+        }
+    }
+}
+
+const KINDS: usize = BenchKind::ALL.len();
+// yields of each of the two ping-pong contexts
+const SWITCH_ROUNDS: usize = 1000;
+const IPI_ROUNDS: usize = 100;
+// ipi round trips slower than this are lost, the ap is not answering
+const IPI_TIMEOUT_NS: u64 = 10_000_000;
+const REPORT_INTERVAL_NS: u64 = 10_000_000_000;
+
+struct BenchStat {
+    count: AtomicU64,
+    total: AtomicU64,
+    min: AtomicU64,
+    max: AtomicU64,
+}
+
+impl BenchStat {
+    const fn new() -> Self {
+        Self { count: AtomicU64::new(0), total: AtomicU64::new(0), min: AtomicU64::new(u64::MAX), max: AtomicU64::new(0) }
+    }
+
+    fn add(&self, ticks: u64) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.total.fetch_add(ticks, Ordering::Relaxed);
+        self.min.fetch_min(ticks, Ordering::Relaxed);
+        self.max.fetch_max(ticks, Ordering::Relaxed);
+    }
+}
+
+const STAT_INIT: BenchStat = BenchStat::new();
+const CPU_STATS_INIT: [BenchStat; KINDS] = [STAT_INIT; KINDS];
+static STATS: [[BenchStat; KINDS]; MAX_CPUS] = [CPU_STATS_INIT; MAX_CPUS];
+
+const ZERO: AtomicU64 = AtomicU64::new(0);
+// tsc when current cpu started switching, 0 if no switch is in flight
+static SWITCH_START: [AtomicU64; MAX_CPUS] = [ZERO; MAX_CPUS];
+
+#[inline(always)]
+pub fn timestamp() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+fn record_on(cpu_id: LogicalCpuId, kind: BenchKind, ticks: u64) {
+    STATS[cpu_id.0 as usize][kind as usize].add(ticks);
+}
+
+/// account tsc ticks since `start` to `kind` on current cpu
+pub fn record(kind: BenchKind, start: u64) {
+    record_on(PercpuBlock::current().cpu_id, kind, timestamp().saturating_sub(start));
+}
+
+/// called by `switch_context` right before the registers are swapped
+pub fn switch_begin() {
+    SWITCH_START[PercpuBlock::current().cpu_id.0 as usize].store(timestamp(), Ordering::Relaxed);
+}
+
+/// called by `post_switch_context` on the new stack
+pub fn switch_end() {
+    let cpu_id = PercpuBlock::current().cpu_id;
+    match SWITCH_START[cpu_id.0 as usize].swap(0, Ordering::Relaxed) {
+        0 => {}
+        start => record_on(cpu_id, BenchKind::Switch, timestamp().saturating_sub(start)),
+    }
+}
+
+fn ticks_to_ns(cpu_id: LogicalCpuId, ticks: u64) -> u64 {
+    match tsc_hz(cpu_id) {
+        0 => 0,
+        hz => (ticks as u128 * 1_000_000_000 / hz as u128) as u64,
+    }
+}
+
+// the reporter switches itself, only syscalls tell whether anything else happened
+fn syscall_samples() -> u64 {
+    STATS.iter().map(|cpu| cpu[BenchKind::Syscall as usize].count.load(Ordering::Relaxed)).sum()
+}
+
+/// print statistics of every cpu with samples
+pub fn report() {
+    qemu_println!("bench: {:<4} {:<8} {:>10} {:>10} {:>10} {:>10}", "cpu", "kind", "count", "min ns", "avg ns", "max ns");
+    for cpu in 0..CPU_COUNT.load(Ordering::SeqCst) {
+        let cpu_id = LogicalCpuId(cpu);
+        for kind in BenchKind::ALL {
+            let stat = &STATS[cpu as usize][kind as usize];
+            let count = stat.count.load(Ordering::Relaxed);
+            if count == 0 {
+                continue;
+            }
+            qemu_println!(
+                "bench: {:<4} {:<8} {:>10} {:>10} {:>10} {:>10}",
+                cpu, kind.name(), count,
+                ticks_to_ns(cpu_id, stat.min.load(Ordering::Relaxed)),
+                ticks_to_ns(cpu_id, stat.total.load(Ordering::Relaxed) / count),
+                ticks_to_ns(cpu_id, stat.max.load(Ordering::Relaxed))
+            );
+        }
+    }
+}
+
+// two contexts yielding to each other, idle of bsp only gets back once both exited
+fn bench_switch() {
+    static EXITED: AtomicUsize = AtomicUsize::new(0);
+    extern "C" fn pingpong_entry() {
+        for _ in 0..SWITCH_ROUNDS {
+            unsafe {
+                CurrentArch::disable_interrupts();
+                switch_context();
+            }
+        }
+        EXITED.fetch_add(1, Ordering::SeqCst);
+    }
+
+    let mut spawned = 0;
+    {
+        let mut storage = context_storage_mut();
+        for _ in 0..2 {
+            match storage.spawn(&SpawnOptions::kernel("bench switch"), SpawnEntry::Func(pingpong_entry)) {
+                Ok(lock) => {
+                    lock.write().set_status(Status::Runnable);
+                    spawned += 1;
+                }
+                Err(err) => qemu_println!("bench: failed to spawn switch context: {}", err),
+            }
+        }
+    }
+
+    while EXITED.load(Ordering::SeqCst) < spawned {
+        unsafe {
+            CurrentArch::disable_interrupts();
+            switch_context();
+            CurrentArch::enable_interrupts();
+        }
+    }
+}
+
+fn bench_ipi() {
+    for cpu in 1..CPU_COUNT.load(Ordering::SeqCst) {
+        let target = LogicalCpuId(cpu);
+        for _ in 0..IPI_ROUNDS {
+            let before = irq_count(IpiKind::Pit as usize);
+            let deadline = monotonic_ns() + IPI_TIMEOUT_NS;
+            let start = timestamp();
+            ipi_single(IpiKind::Pit, target);
+            while irq_count(IpiKind::Pit as usize) == before {
+                if monotonic_ns() > deadline {
+                    qemu_println!("bench: cpu {} does not answer ipi", cpu);
+                    return;
+                }
+                spin_loop()
+            }
+            // round trips are accounted to the cpu answering them
+            record_on(target, BenchKind::Ipi, timestamp() - start);
+        }
+    }
+}
+
+// both copy paths on every size the boot run picks from, with caches warm
+fn bench_copy() {
+    let len = 2 * BENCH_SIZES[BENCH_SIZES.len() - 1];
+    let Ok(buf) = vmalloc(len) else {
+        qemu_println!("bench: no memory for copy buffers");
+        return;
+    };
+    let bytes = unsafe { core::slice::from_raw_parts_mut(buf.as_mut_ptr::<u8>(), len) };
+    let cpu_id = PercpuBlock::current().cpu_id;
+    qemu_println!("bench: {:<10} {:>10} {:>10} {:>10}", "copy", "bytes", "ns", "MiB/s");
+    for size in BENCH_SIZES {
+        for path in [CopyPath::Strings, CopyPath::NonTemporal] {
+            let ns = ticks_to_ns(cpu_id, time_copy(path, bytes, size)).max(1);
+            let marker = if path_for(size) == path { " *" } else { "" };
+            qemu_println!("bench: {:<10} {:>10} {:>10} {:>10}{}", path.name(), size, ns, size as u64 * 1_000_000_000 / ns >> 20, marker);
+        }
+    }
+    vfree(buf);
+}
+
+extern "C" fn bench_reporter() {
+    unsafe { CurrentArch::enable_interrupts(); }
+
+    let mut reported = syscall_samples();
+    loop {
+        {
+            let contexts = context_storage();
+            let mut context = contexts.current()
+                .expect("failed to get bench reporter context")
+                .write();
+            sleep_until(&mut context, monotonic_ns() + REPORT_INTERVAL_NS);
+        }
+        unsafe {
+            CurrentArch::disable_interrupts();
+            switch_context();
+            CurrentArch::enable_interrupts();
+        }
+
+        let samples = syscall_samples();
+        if samples != reported {
+            reported = samples;
+            report();
+        }
+    }
+}
+
+unsafe fn bench_initcall(_: &InitCpuArg) {
+    qemu_println!("bench: measuring context switch and ipi round trip");
+    bench_switch();
+    bench_ipi();
+    report();
+    // the path picked at boot is marked
+    bench_copy();
+
+    match context_storage_mut().spawn(&SpawnOptions::kernel("kbench"), SpawnEntry::Func(bench_reporter)) {
+        Ok(lock) => lock.write().set_status(Status::Runnable),
+        Err(err) => qemu_println!("bench: failed to spawn reporter: {}", err),
+    }
+}
+// after log flusher, it competes with the ping-pong contexts like any other context
+initcall!(late, Bsp, bench_initcall, order = 250);
@@ -0,0 +1,40 @@
+use alloc::string::String;
+use core::str;
+use spin::Once;
+use shared::arg::{KernelArg, MAX_CMDLINE_LEN};
+use crate::infohart;
+
+// kernel command line from `cmdline=` of boot.cfg, space separated `key=value` or `key`
+static CMDLINE: Once<&'static str> = Once::new();
+
+pub fn init_cmdline(arg: &KernelArg) {
+    let bytes = &arg.cmdline[..arg.cmdline_len.min(MAX_CMDLINE_LEN)];
+    // bootloader may cut a multibyte char at the end
+    let cmdline = match str::from_utf8(bytes) {
+        Ok(s) => s,
+        Err(e) => unsafe { str::from_utf8_unchecked(&bytes[..e.valid_up_to()]) }
+    };
+
+    // kernel arg is not mapped in user address spaces, keep a copy
+    let cmdline = CMDLINE.call_once(|| String::from(cmdline).leak());
+    infohart!("kernel command line: {}", cmdline);
+}
+
+pub fn cmdline() -> &'static str {
+    CMDLINE.get().copied().unwrap_or("")
+}
+
+/// value of `key=value` in command line
+pub fn cmdline_value(key: &str) -> Option<&'static str> {
+    cmdline()
+        .split_ascii_whitespace()
+        .find_map(|token| match token.split_once('=') {
+            Some((k, v)) if k == key => Some(v),
+            _ => None
+        })
+}
+
+/// whether bare `key` presents in command line
+pub fn cmdline_flag(key: &str) -> bool {
+    cmdline().split_ascii_whitespace().any(|token| token == key)
+}
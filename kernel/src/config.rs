@@ -0,0 +1,99 @@
+use core::str::FromStr;
+use log::LevelFilter;
+use spin::Once;
+use crate::acpi::local_apic::{MAX_TICK_HZ, MIN_TICK_HZ};
+use crate::cmdline::{cmdline_flag, cmdline_value};
+use crate::logger::filter::set_global_level;
+use crate::{infohart, warnhart};
+
+/**
+ *  kernel configuration.
+ *
+ *  build time values come from `kernel.toml` through build.rs as constants,
+ *  [`config`] holds the values in effect after applying cmdline overrides.
+ *  code sizing static tables uses the constants, everything else asks [`config`].
+ */
+
+mod build {
+    include!(concat!(env!("OUT_DIR"), "/config.rs"));
+}
+
+pub use build::{HEAP_SIZE, MAX_CPUS};
+
+#[derive(Debug, Clone, Copy)]
+pub struct KernelConfig {
+    pub smp: bool,
+    pub max_cpus: usize,
+    // timer ticks of a context before preemption
+    pub quantum: usize,
+    // system tick rate at boot, see `local_apic::set_tick_hz` for changing it later
+    pub tick_hz: usize,
+    pub log_level: LevelFilter,
+    pub kpti: bool,
+    pub mitigations: bool,
+}
+
+impl KernelConfig {
+    fn build() -> Self {
+        Self {
+            smp: build::SMP,
+            max_cpus: build::MAX_CPUS,
+            quantum: build::SCHED_QUANTUM,
+            tick_hz: build::SCHED_TICK_HZ,
+            // checked by build.rs
+            log_level: LevelFilter::from_str(build::LOG_LEVEL).unwrap_or(LevelFilter::Debug),
+            kpti: build::KPTI,
+            mitigations: build::MITIGATIONS,
+        }
+    }
+}
+
+static CONFIG: Once<KernelConfig> = Once::new();
+
+/// resolve runtime config, after cmdline is initialized
+pub fn init_config() {
+    let mut config = KernelConfig::build();
+
+    if cmdline_flag("nosmp") {
+        config.smp = false;
+    }
+    if let Some(n) = cmdline_value("maxcpus").and_then(|v| v.parse::<usize>().ok()) {
+        config.max_cpus = n.clamp(1, MAX_CPUS);
+    }
+    if let Some(n) = cmdline_value("quantum").and_then(|v| v.parse::<usize>().ok()) {
+        config.quantum = n.max(1);
+    }
+    if let Some(n) = cmdline_value("tick_hz").and_then(|v| v.parse::<usize>().ok()) {
+        config.tick_hz = n.clamp(MIN_TICK_HZ as usize, MAX_TICK_HZ as usize);
+    }
+    if let Some(level) = cmdline_value("loglevel") {
+        match LevelFilter::from_str(level) {
+            Ok(level) => config.log_level = level,
+            Err(_) => warnhart!("config: unknown loglevel {}", level),
+        }
+    }
+    match cmdline_value("pti") {
+        Some("on") => config.kpti = true,
+        Some("off") => config.kpti = false,
+        _ => {}
+    }
+    if cmdline_value("mitigations") == Some("off") {
+        config.mitigations = false;
+    }
+
+    if config.kpti {
+        warnhart!("config: kpti is not supported, ignored");
+    }
+    if config.mitigations {
+        warnhart!("config: speculative execution mitigations are not supported, ignored");
+    }
+
+    set_global_level(config.log_level);
+    let config = CONFIG.call_once(|| config);
+    infohart!("kernel config: {:?}", config);
+}
+
+/// config in effect, build time values before [`init_config`]
+pub fn config() -> KernelConfig {
+    CONFIG.get().copied().unwrap_or_else(KernelConfig::build)
+}
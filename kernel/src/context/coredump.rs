@@ -0,0 +1,317 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::slice;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+use crate::arch::ArchContextRegs;
+use crate::arch_spec::frame_check::frame_issue;
+use crate::arch_spec::fsgsbase::save_user_bases;
+use crate::cmdline::cmdline_flag;
+use crate::context::list::context_storage;
+use crate::context::switch::switch_context;
+use crate::context::{exit_context, ContextId};
+use crate::device::com::COM1;
+use crate::logger::audit::{audit, AuditKind};
+use crate::mem::user_addr_space::{MappedRegion, UserAddrSpace};
+use crate::sync::{IrqContextGuard, Spinlock};
+use crate::syscall::InterruptStack;
+use crate::{errorhart, infohart, warnhart};
+
+/**
+ *  core dumps of userspace contexts killed by a fault.
+ *
+ *  the dump is an elf core file readable by gdb: one `NT_PRSTATUS` note with
+ *  registers in linux `user_regs_struct` order, one `PT_LOAD` per mapped user
+ *  region. the latest dump is kept in memory and exposed as `/proc/core`,
+ *  with `coredump_serial` in cmdline it is also streamed to com1 as hex lines
+ *  between `coredump: begin` and `coredump: end` markers.
+ */
+
+// bigger address spaces are truncated, the dump lives in kernel heap
+pub const CORE_MAX_BYTES: usize = 8 << 20;
+const SERIAL_LINE_BYTES: usize = 32;
+
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+const PRSTATUS_SIZE: usize = 336;
+// offset of pr_reg inside elf_prstatus
+const PRSTATUS_REGS_OFFSET: usize = 112;
+const NOTE_NAME: &[u8; 8] = b"CORE\0\0\0\0";
+const NOTE_SIZE: usize = 12 + NOTE_NAME.len() + PRSTATUS_SIZE;
+
+const ET_CORE: u16 = 4;
+const EM_X86_64: u16 = 62;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const NT_PRSTATUS: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+// registers of `put_prstatus` up to gsbase, the segment registers are zero
+const PR_REG_COUNT: usize = 23;
+
+static LAST_CORE: Spinlock<Option<(ContextId, Arc<Vec<u8>>)>> = Spinlock::new(None);
+
+/// latest core dump and the context it belongs to
+pub fn last_core_dump() -> Option<(ContextId, Arc<Vec<u8>>)> {
+    LAST_CORE.lock().clone()
+}
+
+fn put_u16(out: &mut Vec<u8>, value: u16) { out.extend_from_slice(&value.to_le_bytes()) }
+fn put_u32(out: &mut Vec<u8>, value: u32) { out.extend_from_slice(&value.to_le_bytes()) }
+fn put_u64(out: &mut Vec<u8>, value: u64) { out.extend_from_slice(&value.to_le_bytes()) }
+
+fn put_phdr(out: &mut Vec<u8>, kind: u32, flags: u32, offset: u64, vaddr: u64, filesz: u64, memsz: u64, align: u64) {
+    put_u32(out, kind);
+    put_u32(out, flags);
+    put_u64(out, offset);
+    put_u64(out, vaddr);
+    put_u64(out, 0); // paddr
+    put_u64(out, filesz);
+    put_u64(out, memsz);
+    put_u64(out, align);
+}
+
+// elf_prstatus, only signal, pid and registers are filled
+fn put_prstatus(out: &mut Vec<u8>, id: ContextId, signal: usize, stack: &InterruptStack, fsbase: usize, gsbase: usize) {
+    let start = out.len();
+    put_u32(out, signal as u32); // si_signo
+    out.resize(start + 12, 0);
+    put_u16(out, signal as u16); // pr_cursig
+    out.resize(start + 32, 0);
+    put_u32(out, id.get() as u32); // pr_pid
+    out.resize(start + PRSTATUS_REGS_OFFSET, 0);
+
+    let (p, s, i) = (&stack.preserved, &stack.scratch, &stack.iret);
+    let regs = [
+        p.r15, p.r14, p.r13, p.r12, p.rbp, p.rbx,
+        s.r11, s.r10, s.r9, s.r8, s.rax, s.rcx, s.rdx, s.rsi, s.rdi,
+        usize::MAX, // orig_rax, not in a syscall
+        i.rip, i.cs, i.rflags, i.rsp, i.ss,
+        fsbase, gsbase,
+        0, 0, 0, 0, // ds es fs gs
+    ];
+    regs.into_iter().for_each(|reg| put_u64(out, reg as u64));
+    out.resize(start + PRSTATUS_SIZE, 0);
+}
+
+fn segment_flags(region: &MappedRegion) -> u32 {
+    let mut flags = PF_R;
+    if region.flags.contains(PageTableFlags::WRITABLE) { flags |= PF_W }
+    if !region.flags.contains(PageTableFlags::NO_EXECUTE) { flags |= PF_X }
+    flags
+}
+
+// copy `len` bytes of user memory from `start`, unreadable pages are zero
+fn read_user(addrsp: &UserAddrSpace, start: u64, len: u64, out: &mut Vec<u8>) {
+    let end = start + len;
+    let mut addr = start;
+    while addr < end {
+        match addrsp.translate_user(VirtAddr::new(addr), false) {
+            Ok((phys, left)) => {
+                let chunk = left.min(end - addr);
+                out.extend_from_slice(unsafe { slice::from_raw_parts(phys.as_u64() as *const u8, chunk as usize) });
+                addr += chunk;
+            }
+            Err(_) => {
+                let chunk = (4096 - (addr & 4095)).min(end - addr);
+                out.resize(out.len() + chunk as usize, 0);
+                addr += chunk;
+            }
+        }
+    }
+}
+
+/// build an elf core file of context `id` stopped at `stack`
+pub fn build_core_dump(id: ContextId, signal: usize, stack: &InterruptStack, fsbase: usize, gsbase: usize, addrsp: &mut UserAddrSpace) -> Vec<u8> {
+    let mut regions = addrsp.mapped_regions();
+    let mut total = 0;
+    regions.retain(|region| {
+        total += region.len as usize;
+        total <= CORE_MAX_BYTES
+    });
+
+    let phnum = 1 + regions.len();
+    let note_offset = EHDR_SIZE + phnum * PHDR_SIZE;
+    let data_offset = note_offset + NOTE_SIZE;
+
+    let mut out = Vec::with_capacity(data_offset + total.min(CORE_MAX_BYTES));
+    // elf header
+    out.extend_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+    out.resize(16, 0);
+    put_u16(&mut out, ET_CORE);
+    put_u16(&mut out, EM_X86_64);
+    put_u32(&mut out, 1); // version
+    put_u64(&mut out, 0); // entry
+    put_u64(&mut out, EHDR_SIZE as u64); // phoff
+    put_u64(&mut out, 0); // shoff
+    put_u32(&mut out, 0); // flags
+    put_u16(&mut out, EHDR_SIZE as u16);
+    put_u16(&mut out, PHDR_SIZE as u16);
+    put_u16(&mut out, phnum as u16);
+    put_u16(&mut out, 0); // shentsize
+    put_u16(&mut out, 0); // shnum
+    put_u16(&mut out, 0); // shstrndx
+
+    // program headers
+    put_phdr(&mut out, PT_NOTE, 0, note_offset as u64, 0, NOTE_SIZE as u64, 0, 4);
+    let mut offset = data_offset as u64;
+    for region in regions.iter() {
+        put_phdr(&mut out, PT_LOAD, segment_flags(region), offset, region.start.as_u64(), region.len, region.len, 4096);
+        offset += region.len;
+    }
+
+    // notes
+    put_u32(&mut out, 5); // namesz, "CORE\0"
+    put_u32(&mut out, PRSTATUS_SIZE as u32);
+    put_u32(&mut out, NT_PRSTATUS);
+    out.extend_from_slice(NOTE_NAME);
+    put_prstatus(&mut out, id, signal, stack, fsbase, gsbase);
+
+    // memory
+    for region in regions.iter() {
+        read_user(addrsp, region.start.as_u64(), region.len, &mut out);
+    }
+    out
+}
+
+fn get_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(bytes.get(offset..offset + 2)?.try_into().ok()?))
+}
+fn get_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(offset..offset + 4)?.try_into().ok()?))
+}
+fn get_u64(bytes: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(bytes.get(offset..offset + 8)?.try_into().ok()?))
+}
+
+/// a `PT_LOAD` segment of a core file
+pub struct CoreSegment<'a> {
+    pub start: u64,
+    /// user page flags the segment was mapped with
+    pub flags: PageTableFlags,
+    pub bytes: &'a [u8],
+}
+
+/// registers and memory of a core file from [`build_core_dump`]
+pub struct CoreImage<'a> {
+    // `put_prstatus` order
+    regs: [u64; PR_REG_COUNT],
+    pub segments: Vec<CoreSegment<'a>>,
+}
+
+impl<'a> CoreImage<'a> {
+    /// None if `core` is not laid out like [`build_core_dump`] writes it
+    pub fn parse(core: &'a [u8]) -> Option<Self> {
+        if core.get(..4)? != b"\x7fELF" || get_u16(core, 16)? != ET_CORE {
+            return None;
+        }
+        let phoff = get_u64(core, 32)? as usize;
+        let phnum = get_u16(core, 56)? as usize;
+        let mut regs = None;
+        let mut segments = Vec::new();
+        for index in 0..phnum {
+            let phdr = phoff + index * PHDR_SIZE;
+            let offset = get_u64(core, phdr + 8)? as usize;
+            let filesz = get_u64(core, phdr + 32)? as usize;
+            let bytes = core.get(offset..offset.checked_add(filesz)?)?;
+            match get_u32(core, phdr)? {
+                PT_NOTE if get_u32(bytes, 8)? == NT_PRSTATUS => {
+                    let pr_reg = 12 + NOTE_NAME.len() + PRSTATUS_REGS_OFFSET;
+                    let mut values = [0; PR_REG_COUNT];
+                    for (reg, value) in values.iter_mut().enumerate() {
+                        *value = get_u64(bytes, pr_reg + reg * 8)?;
+                    }
+                    regs = Some(values);
+                }
+                PT_LOAD => {
+                    let pf = get_u32(core, phdr + 4)?;
+                    let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+                    if pf & PF_W != 0 { flags |= PageTableFlags::WRITABLE }
+                    if pf & PF_X == 0 { flags |= PageTableFlags::NO_EXECUTE }
+                    segments.push(CoreSegment { start: get_u64(core, phdr + 16)?, flags, bytes });
+                }
+                _ => {}
+            }
+        }
+        Some(Self { regs: regs?, segments })
+    }
+
+    /// load general purpose registers, rip, rflags and rsp into `stack`,
+    /// the segment selectors are left alone
+    pub fn load_regs(&self, stack: &mut InterruptStack) {
+        let [r15, r14, r13, r12, rbp, rbx, r11, r10, r9, r8, rax, rcx, rdx, rsi, rdi, _, rip, _, rflags, rsp, ..] =
+            self.regs.map(|reg| reg as usize);
+        let (p, s, i) = (&mut stack.preserved, &mut stack.scratch, &mut stack.iret);
+        (p.r15, p.r14, p.r13, p.r12, p.rbp, p.rbx) = (r15, r14, r13, r12, rbp, rbx);
+        (s.r11, s.r10, s.r9, s.r8, s.rax, s.rcx, s.rdx, s.rsi, s.rdi) = (r11, r10, r9, r8, rax, rcx, rdx, rsi, rdi);
+        (i.rip, i.rflags, i.rsp) = (rip, rflags, rsp);
+    }
+
+    pub fn fsbase(&self) -> usize {
+        self.regs[21] as usize
+    }
+
+    pub fn gsbase(&self) -> usize {
+        self.regs[22] as usize
+    }
+}
+
+fn dump_to_serial(id: ContextId, core: &[u8]) {
+    let mut com = COM1.lock();
+    let _ = writeln!(com, "coredump: begin context {} {} bytes", id.get(), core.len());
+    for line in core.chunks(SERIAL_LINE_BYTES) {
+        line.iter().for_each(|byte| { let _ = write!(com, "{:02x}", byte); });
+        let _ = writeln!(com);
+    }
+    let _ = writeln!(com, "coredump: end");
+}
+
+/// called by exception handlers, kills the current context with `signal` if
+/// the fault comes from userspace. returns only for kernel faults.
+pub unsafe fn user_fault(stack: &InterruptStack, signal: usize, fault: &str) {
+    if stack.iret.cs & 3 != 3 {
+        return;
+    }
+    let context_lock = match context_storage().current() {
+        Some(context) => Arc::clone(context),
+        None => return,
+    };
+    // the handler never returns, its guard is never dropped
+    IrqContextGuard::abandon();
+
+    let (id, addrsp, fsbase, gsbase) = {
+        let mut context = context_lock.write();
+        // the running context, its saved bases are from the last switch
+        save_user_bases(&mut context.ctx_regs);
+        errorhart!("context {} killed by {} at {:#x}", context.display(), fault, stack.instr_pointer());
+        audit(AuditKind::Kill, format_args!("{} by {} at {:#x}, signal {}", context.display(), fault, stack.instr_pointer(), signal));
+        if let Some(issue) = frame_issue(&stack.iret) {
+            let iret = &stack.iret;
+            errorhart!(
+                "context {}: {}, rip {:#x} rsp {:#x} rflags {:#x} cs {:#x} ss {:#x}",
+                context.display(), issue, iret.rip, iret.rsp, iret.rflags, iret.cs, iret.ss,
+            );
+        }
+        (context.id, context.addrsp.clone(), context.ctx_regs.fsbase, context.ctx_regs.gsbase)
+    };
+
+    match addrsp {
+        Some(addrsp) => {
+            let core = build_core_dump(id, signal, stack, fsbase, gsbase, &mut addrsp.acquire_write());
+            infohart!("coredump: {} bytes for context {}, read /proc/core", core.len(), id.get());
+            if cmdline_flag("coredump_serial") {
+                dump_to_serial(id, &core);
+            }
+            *LAST_CORE.lock() = Some((id, Arc::new(core)));
+        }
+        None => warnhart!("coredump: context {} has no address space", id.get()),
+    }
+
+    exit_context(&context_lock, 128 + signal);
+    drop(context_lock);
+    loop {
+        switch_context();
+    }
+}
@@ -0,0 +1,86 @@
+use libvdso::error::{EPERM, ESRCH, KError, KResult};
+use libvdso::flag::{CAP_ADMIN, CAP_ALL, CAP_IO};
+use crate::context::list::context_storage;
+use crate::logger::audit::{audit, cap_name, AuditKind};
+
+/**
+ *  credentials of a context.
+ *
+ *  uid/gid are plain numbers without meaning to the kernel yet, privileged
+ *  syscalls only look at the capability bits:
+ *      CAP_IO      ioperm, iopl, map_device, irq_register
+ *      CAP_ADMIN   reboot, kexec, setuid/setgid to another id, log_level,
+ *                  profile, watchpoint, audit_read, mount once there is one
+ *
+ *  kernel contexts are root with every capability, a spawned context copies
+ *  the credentials of its parent. capabilities can only be dropped, and
+ *  leaving uid 0 drops all of them.
+ */
+
+pub const ROOT_UID: u32 = 0;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Credentials {
+    pub uid: u32,
+    pub gid: u32,
+    /// bitset of `CAP_*`
+    pub caps: u64,
+}
+
+impl Credentials {
+    pub const fn root() -> Self {
+        Self { uid: ROOT_UID, gid: 0, caps: CAP_ALL }
+    }
+
+    pub fn has(&self, cap: u64) -> bool {
+        self.caps & cap == cap
+    }
+
+    pub fn set_uid(&mut self, uid: u32) -> KResult<()> {
+        if uid != self.uid && !self.has(CAP_ADMIN) {
+            return Err(KError::new(EPERM));
+        }
+        self.uid = uid;
+        if uid != ROOT_UID {
+            self.caps = 0;
+        }
+        Ok(())
+    }
+
+    pub fn set_gid(&mut self, gid: u32) -> KResult<()> {
+        if gid != self.gid && !self.has(CAP_ADMIN) {
+            return Err(KError::new(EPERM));
+        }
+        self.gid = gid;
+        Ok(())
+    }
+}
+
+/// `EPERM` unless the calling context has every bit of `cap`
+pub fn require_cap(cap: u64) -> KResult<()> {
+    let contexts = context_storage();
+    let context = contexts.current().ok_or(KError::new(ESRCH))?.read();
+    let granted = context.cred.has(cap);
+    audit(AuditKind::Cap, format_args!("{} {}", cap_name(cap), if granted { "granted" } else { "denied" }));
+    if granted {
+        Ok(())
+    } else {
+        Err(KError::new(EPERM))
+    }
+}
+
+#[test_case]
+pub(crate) fn test_credentials_drop() {
+    let mut cred = Credentials::root();
+    assert!(cred.has(CAP_IO | CAP_ADMIN));
+    cred.set_gid(100).unwrap();
+
+    // leaving root drops everything, there is no way back
+    cred.set_uid(1000).unwrap();
+    assert_eq!(cred.caps, 0);
+    assert!(cred.set_uid(ROOT_UID).is_err());
+    assert!(cred.set_gid(0).is_err());
+    // setting the same id is always allowed
+    cred.set_uid(1000).unwrap();
+    cred.set_gid(100).unwrap();
+}
@@ -0,0 +1,135 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::any::{Any, TypeId};
+use shared::print_panic::PrintPanic;
+
+/**
+ *  per-context state of subsystems that are not part of `Context` itself.
+ *
+ *  a subsystem declares a type implementing `ContextExt` and keeps its state
+ *  in `Context::ext`, keyed by that type. there is at most one value of each
+ *  type per context, it is guarded by the lock of the context like every
+ *  other field, so adding a subsystem does not touch the struct or its locking.
+ *
+ *  a spawned context gets whatever the values of its parent return from
+ *  `inherit`, nothing by default. values are dropped with the context.
+ */
+
+pub trait ContextExt: Any + Send + Sync + Sized {
+    /// value for a context spawned by the owner, None to leave it out
+    fn inherit(&self) -> Option<Self> {
+        None
+    }
+}
+
+struct Slot {
+    id: TypeId,
+    value: Box<dyn Any + Send + Sync>,
+    inherit: fn(&(dyn Any + Send + Sync)) -> Option<Box<dyn Any + Send + Sync>>,
+}
+
+fn inherit_slot<T: ContextExt>(value: &(dyn Any + Send + Sync)) -> Option<Box<dyn Any + Send + Sync>> {
+    let value = value.downcast_ref::<T>()?;
+    value.inherit().map(|value| Box::new(value) as Box<dyn Any + Send + Sync>)
+}
+
+#[derive(Default)]
+pub struct Extensions {
+    // a handful of subsystems at most, a linear scan beats a map
+    slots: Vec<Slot>,
+}
+
+impl Extensions {
+    pub const fn new() -> Self {
+        Self { slots: Vec::new() }
+    }
+
+    fn position<T: ContextExt>(&self) -> Option<usize> {
+        self.slots.iter().position(|slot| slot.id == TypeId::of::<T>())
+    }
+
+    pub fn get<T: ContextExt>(&self) -> Option<&T> {
+        self.slots.iter()
+            .find(|slot| slot.id == TypeId::of::<T>())
+            .and_then(|slot| slot.value.downcast_ref())
+    }
+
+    pub fn get_mut<T: ContextExt>(&mut self) -> Option<&mut T> {
+        self.slots.iter_mut()
+            .find(|slot| slot.id == TypeId::of::<T>())
+            .and_then(|slot| slot.value.downcast_mut())
+    }
+
+    /// set the value of `T`, returns the one it replaced
+    pub fn insert<T: ContextExt>(&mut self, value: T) -> Option<T> {
+        let old = self.remove::<T>();
+        self.slots.push(Slot { id: TypeId::of::<T>(), value: Box::new(value), inherit: inherit_slot::<T> });
+        old
+    }
+
+    pub fn remove<T: ContextExt>(&mut self) -> Option<T> {
+        let index = self.position::<T>()?;
+        self.slots.swap_remove(index).value.downcast().ok().map(|value| *value)
+    }
+
+    pub fn get_or_insert_with<T: ContextExt>(&mut self, init: impl FnOnce() -> T) -> &mut T {
+        if self.position::<T>().is_none() {
+            self.insert(init());
+        }
+        self.get_mut().or_panic("extension missing after insert")
+    }
+
+    /// extensions of a context spawned by the owner
+    pub fn inherit(&self) -> Self {
+        let slots = self.slots.iter()
+            .filter_map(|slot| {
+                let value = (slot.inherit)(&*slot.value)?;
+                Some(Slot { id: slot.id, value, inherit: slot.inherit })
+            })
+            .collect();
+        Self { slots }
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+}
+
+#[test_case]
+pub(crate) fn test_context_extensions() {
+    #[derive(Debug, PartialEq)]
+    struct Counter(u32);
+    impl ContextExt for Counter {}
+
+    #[derive(Debug, PartialEq)]
+    struct Flags(u64);
+    impl ContextExt for Flags {
+        fn inherit(&self) -> Option<Self> {
+            Some(Flags(self.0))
+        }
+    }
+
+    let mut ext = Extensions::new();
+    assert!(ext.get::<Counter>().is_none());
+    ext.get_or_insert_with(|| Counter(0)).0 += 2;
+    assert_eq!(ext.insert(Flags(0b101)), None);
+    assert_eq!(ext.get::<Counter>(), Some(&Counter(2)));
+
+    // one value per type, inserting again replaces it
+    assert_eq!(ext.insert(Counter(7)), Some(Counter(2)));
+    assert_eq!(ext.len(), 2);
+
+    // only values that opt in are inherited
+    let child = ext.inherit();
+    assert_eq!(child.len(), 1);
+    assert_eq!(child.get::<Flags>(), Some(&Flags(0b101)));
+    assert!(child.get::<Counter>().is_none());
+
+    assert_eq!(ext.remove::<Flags>(), Some(Flags(0b101)));
+    assert_eq!(ext.remove::<Flags>(), None);
+    assert_eq!(ext.get::<Counter>(), Some(&Counter(7)));
+}
@@ -0,0 +1,79 @@
+use alloc::boxed::Box;
+use alloc::vec;
+use core::ops::Range;
+
+/**
+ *  userspace i/o port permission.
+ *
+ *  a context granted ports with `ioperm` owns a bitmap in tss format, a clear
+ *  bit allows the port. the bitmap is copied into the pcr of the cpu on switch,
+ *  contexts without one run with the tss bitmap disabled so every `in`/`out`
+ *  from ring 3 faults.
+ *
+ *  a context usually holds a handful of ports, so the bitmap keeps the span of
+ *  bytes that ever allowed one. the switch only sets the span of the previous
+ *  bitmap back to all ones and copies the span of the next, not 8 KiB.
+ */
+
+pub const IO_PORTS: usize = 65536;
+pub const IO_BITMAP_BYTES: usize = IO_PORTS / 8;
+
+pub struct IoBitmap {
+    bits: Box<[u8]>,
+    // bytes that may have a clear bit, all ones outside
+    span: Range<usize>,
+}
+
+impl IoBitmap {
+    /// every port denied
+    pub fn new() -> Self {
+        // through vec, 8 KiB array does not fit well on kernel stack
+        Self { bits: vec![0xff; IO_BITMAP_BYTES].into_boxed_slice(), span: 0..0 }
+    }
+
+    pub fn set(&mut self, from: u16, num: u16, allowed: bool) {
+        if allowed && num > 0 {
+            let bytes = from as usize / 8..(from as usize + num as usize).div_ceil(8);
+            self.span = if self.span.is_empty() { bytes } else { self.span.start.min(bytes.start)..self.span.end.max(bytes.end) };
+        }
+        for port in from as usize..from as usize + num as usize {
+            let mask = 1 << (port % 8);
+            if allowed {
+                self.bits[port / 8] &= !mask;
+            } else {
+                self.bits[port / 8] |= mask;
+            }
+        }
+    }
+
+    /// no port allowed any more, the bitmap can be dropped
+    pub fn is_empty(&self) -> bool {
+        self.bits[self.span.clone()].iter().all(|byte| *byte == 0xff)
+    }
+
+    /// bytes to copy into the tss, the rest denies every port
+    pub fn span(&self) -> Range<usize> {
+        self.span.clone()
+    }
+
+    pub fn bits(&self) -> &[u8] {
+        &self.bits
+    }
+}
+
+#[test_case]
+pub(crate) fn test_io_bitmap_span() {
+    let mut bitmap = IoBitmap::new();
+    assert!(bitmap.is_empty());
+    bitmap.set(0x3f8, 8, true);
+    bitmap.set(0x60, 1, true);
+    assert_eq!(bitmap.span(), 0x60 / 8..0x400 / 8);
+    assert_eq!(bitmap.bits()[0x3f8 / 8], 0);
+    assert_eq!(bitmap.bits()[0x60 / 8], 0xfe);
+
+    // denying keeps the span, the bytes in it are all ones again
+    bitmap.set(0x3f8, 8, false);
+    bitmap.set(0x60, 1, false);
+    assert!(bitmap.is_empty());
+    assert!(bitmap.bits()[bitmap.span()].iter().all(|byte| *byte == 0xff));
+}
@@ -1,89 +1,106 @@
 use alloc::collections::{BTreeMap, VecDeque};
 use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
 use core::cell::{Cell, RefCell};
-use core::hint::spin_loop;
-use core::mem::{offset_of, size_of};
-use core::ops::{Add, Index, RangeBounds};
+use core::mem::size_of;
+use core::ops::{Add, Index};
 use core::ptr;
-use core::ptr::{slice_from_raw_parts, slice_from_raw_parts_mut};
+use core::ptr::slice_from_raw_parts;
 use core::slice::from_raw_parts;
-use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use lazy_static::lazy_static;
 use log::info;
-use spin::{RwLock, RwLockReadGuard, RwLockWriteGuard};
-use spinning_top::RwSpinlock;
-use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame, Size4KiB};
-use x86_64::{PhysAddr, VirtAddr};
+use x86_64::structures::paging::{Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
 use x86_64::structures::paging::mapper::TranslateResult;
+use shared::layout::{USER_BASE, USER_STACK_SLOT_SIZE};
 use shared::print_panic::PrintPanic;
-use shared::uni_processor::UPSafeCell;
-use crate::context::{Context, ContextId};
-use crate::{CPU_COUNT, infohart, qemu_println, warnhart};
+use crate::context::{context_id, init_context, ready, Context, ContextId};
+use crate::context::status::Status;
+use crate::context::spawn::{context_entry_trampoline, kernel_context_return, SpawnEntry, SpawnOptions};
+use crate::{infohart, qemu_println, warnhart};
+use crate::config::MAX_CPUS;
 use crate::mem::aligned_box::AlignedBox;
 use crate::mem::heap::OutOfMemory;
+use crate::arch::ArchContextRegs;
+use crate::arch_spec::memcopy;
+use crate::mem::frame_allocator::{frame_dealloc, try_frame_alloc};
 use crate::mem::PAGE_SIZE;
-use crate::syscall::{enter_usermode, InterruptStack, IretRegisters};
-use libvdso::error::{EAGAIN, ENOMEM};
-use crate::mem::frame_allocator::frame_alloc_n;
-use crate::mem::user_addr_space::RwLockUserAddrSpace;
+use crate::syscall::{enter_usermode, InterruptStack};
+use libvdso::error::{EAGAIN, EINVAL, ENOMEM, KError, KResult};
+use libvdso::rlimit::RLIMIT_CHILDREN;
+use crate::mem::kstack::{kstack_alloc, kstack_free};
+use crate::mem::user_addr_space::{stack_slot_base, RwLockUserAddrSpace};
+use crate::sync::{RwSpinlock, RwSpinlockReadGuard, RwSpinlockWriteGuard};
 
 lazy_static! {
-    static ref CONTEXT_STORAGE: RwLock<ContextStorage> = {
-        let cpu_count = CPU_COUNT.load(Ordering::Relaxed);
-        RwLock::new(ContextStorage::new(cpu_count as usize))
-    };
+    static ref CONTEXT_STORAGE: RwSpinlock<ContextStorage> = RwSpinlock::new(ContextStorage::new());
 }
 
+// ids below belong to per-cpu idle contexts, `id == cpu id`
+pub(super) const PERCPU_CONTEXT_IDS: usize = MAX_CPUS;
+const CONTEXT_ID_LIMIT: usize = 1 << 16;
+
+/// hands out context ids in `base..limit`.
+/// freed ids are reused in fifo order once fresh ids run out, so a stale id
+/// does not name a new context right away.
 struct ContextIdAllocator {
-    lock: AtomicBool,
-    head: UPSafeCell<usize>,
-    recycled: UPSafeCell<VecDeque<usize>>
+    base: usize,
+    limit: usize,
+    // next never handed out id
+    next: usize,
+    recycled: VecDeque<usize>,
+    // bit `id - base` is set while the id is allocated
+    live: Vec<u64>,
 }
 
 impl ContextIdAllocator {
-    pub fn new(init_id_exclusive: usize) -> Self {
+    pub fn new(base: usize, limit: usize) -> Self {
         ContextIdAllocator {
-            lock: AtomicBool::new(false),
-            head: unsafe { UPSafeCell::new(init_id_exclusive) },
-            recycled: unsafe { UPSafeCell::new(VecDeque::new()) }
+            base,
+            limit,
+            next: base,
+            recycled: VecDeque::new(),
+            live: vec![0; (limit - base + 63) / 64],
         }
     }
 
-    pub fn dealloc(&self, id: usize) -> usize {
-        while self.lock.compare_exchange_weak(false, true, Ordering::SeqCst, Ordering::Relaxed).is_err() {
-            spin_loop()
-        }
-
-        let mut queue_mut = self.recycled.inner_exclusive_mut();
-        let result = match queue_mut.iter().find(|i| *i == &id) {
-            None => {
-                queue_mut.push_back(id);
-                id
-            }
-            Some(_) => 0
-        };
-
-        self.lock.store(false, Ordering::SeqCst);
-        result
+    fn is_live(&self, id: usize) -> bool {
+        let bit = id - self.base;
+        self.live[bit / 64] & 1 << (bit % 64) != 0
     }
 
-    pub fn alloc(&self) -> usize {
-        while self.lock.compare_exchange_weak(false, true, Ordering::SeqCst, Ordering::Relaxed).is_err() {
-            spin_loop()
+    fn set_live(&mut self, id: usize, live: bool) {
+        let bit = id - self.base;
+        if live {
+            self.live[bit / 64] |= 1 << (bit % 64);
+        } else {
+            self.live[bit / 64] &= !(1 << (bit % 64));
         }
+    }
 
-        let mut queue_mut = self.recycled.inner_exclusive_mut();
-        let result = match queue_mut.pop_front() {
-            None => {
-                let mut head = self.head.inner_exclusive_mut();
-                *head = *head + 1;
-                *head
-            }
-            Some(id) => id,
+    pub fn alloc(&mut self) -> Option<usize> {
+        let id = if self.next < self.limit {
+            self.next += 1;
+            self.next - 1
+        } else {
+            self.recycled.pop_front()?
         };
+        debug_assert!(!self.is_live(id), "context id {} allocated twice", id);
+        self.set_live(id, true);
+        Some(id)
+    }
 
-        self.lock.store(false, Ordering::SeqCst);
-        result
+    /// give `id` back, per-cpu ids are not managed here and ignored
+    pub fn dealloc(&mut self, id: usize) {
+        if id < self.base || id >= self.limit {
+            return;
+        }
+        debug_assert!(self.is_live(id), "context id {} freed twice", id);
+        if self.is_live(id) {
+            self.set_live(id, false);
+            self.recycled.push_back(id);
+        }
     }
 }
 
@@ -93,10 +110,10 @@ pub struct ContextStorage {
 }
 
 impl ContextStorage {
-    pub fn new(init_id_exclusive: usize) -> Self {
+    pub fn new() -> Self {
         ContextStorage {
             map: BTreeMap::new(),
-            id_allocator: ContextIdAllocator::new(init_id_exclusive),
+            id_allocator: ContextIdAllocator::new(PERCPU_CONTEXT_IDS, CONTEXT_ID_LIMIT),
         }
     }
     /// Get the current context.
@@ -104,94 +121,212 @@ impl ContextStorage {
         self.map.get(&super::context_id())
     }
 
-    pub fn insert_context(&mut self, id: ContextId) -> Result<&Arc<RwSpinlock<Context>>, i32> {
+    pub fn get(&self, id: ContextId) -> Option<&Arc<RwSpinlock<Context>>> {
+        self.map.get(&id)
+    }
+
+    /// insert the idle context of a cpu, other contexts are created by [`Self::new_context`]
+    pub fn insert_context(&mut self, id: ContextId) -> KResult<&Arc<RwSpinlock<Context>>> {
+        debug_assert!(id.get() < PERCPU_CONTEXT_IDS, "context id {} is not a per-cpu id", id.get());
         let old = self.map.insert(id, Arc::new(RwSpinlock::new(Context::new(id))));
         if old.is_some() {
             warnhart!("insert duplicated context id: {}", id.0);
-            Err(EAGAIN)
+            Err(KError::new(EAGAIN))
         } else {
             Ok(self.map.get(&id).or_panic("failed to get newly inserted context"))
         }
     }
 
     pub fn remove(&mut self, id: ContextId) -> Option<Arc<RwSpinlock<Context>>> {
-        self.map.remove(&id)
+        let removed = self.map.remove(&id)?;
+        ready::dequeue(id);
+        let (ppid, children) = {
+            let mut context = removed.write();
+            debug_assert!(!context.running, "removing running context {}", id.get());
+            context.release_thread_stack();
+            if let Some(kstack) = context.kstack.take() {
+                kstack_free(kstack);
+            }
+            (context.ppid.take(), core::mem::take(&mut context.children))
+        };
+        if let Some(parent) = ppid.and_then(|ppid| self.map.get(&ppid)) {
+            parent.write().children.retain(|child| *child != id);
+        }
+        // only if it never went through `exit_context`
+        if !children.is_empty() {
+            self.adopt_orphans(id, &children);
+        }
+        self.id_allocator.dealloc(id.get());
+        Some(removed)
     }
 
-    pub fn new_context(&mut self) -> Result<&Arc<RwSpinlock<Context>>, i32> {
-        self.insert_context(ContextId::from(self.id_allocator.alloc()))
+    /// remove an exited child of `parent` and return its exit code, `None` if
+    /// `id` is not such a child or still running
+    pub fn reap(&mut self, parent: ContextId, id: ContextId) -> Option<usize> {
+        let code = {
+            let context = self.map.get(&id)?.read();
+            match context.status {
+                Status::Existed(code) if context.ppid == Some(parent) && !context.running => code,
+                _ => return None,
+            }
+        };
+        self.remove(id);
+        Some(code)
     }
 
+    /// reparent `orphans` of `parent` to init. without init, or if init itself
+    /// is gone, they have no parent and whoever removes them reaps them.
+    pub fn adopt_orphans(&self, parent: ContextId, orphans: &[ContextId]) {
+        let init = init_context()
+            .filter(|init| *init != parent)
+            .and_then(|init| self.map.get(&init).map(|lock| (init, lock)));
+        let mut new_ppid = None;
+        if let Some((init, lock)) = init {
+            let mut init_context = lock.write();
+            if init_context.children.try_reserve(orphans.len()).is_ok() {
+                init_context.children.extend_from_slice(orphans);
+                new_ppid = Some(init);
+            } else {
+                warnhart!("out of memory adopting {} orphans of context {}", orphans.len(), parent.get());
+            }
+        }
+        for orphan in orphans {
+            if let Some(lock) = self.map.get(orphan) {
+                lock.write().ppid = new_ppid;
+            }
+        }
+    }
+
+    pub fn new_context(&mut self) -> KResult<&Arc<RwSpinlock<Context>>> {
+        let id = ContextId::from(self.id_allocator.alloc().ok_or(KError::new(EAGAIN))?);
+        // contexts are woken from irq handlers too, queueing must not allocate
+        if let Err(err) = ready::reserve(id) {
+            self.id_allocator.dealloc(id.get());
+            return Err(err.into());
+        }
+        let Ok(context) = Arc::try_new(RwSpinlock::new(Context::new(id))) else {
+            self.id_allocator.dealloc(id.get());
+            return Err(KError::new(ENOMEM));
+        };
+        let old = self.map.insert(id, context);
+        assert!(old.is_none(), "context id {} is in use", id.get());
+        Ok(self.map.get(&id).or_panic("failed to get newly inserted context"))
+    }
+
+    /// create a context starting at `entry`, it stays blocked until the caller marks it runnable
     pub fn spawn(
         &mut self,
-        userspace_allowed: bool,
-        func: extern "C" fn()
-    ) -> Result<&Arc<RwSpinlock<Context>>, i32> {
-        let mut stack = match frame_alloc_n(64) {
-            Some(frame) => unsafe {
-                let ptr = frame.start_address().as_u64() as *mut u8;
-                ptr::write_bytes(ptr, 0, PAGE_SIZE * 64);
-                slice_from_raw_parts_mut(ptr, PAGE_SIZE * 64)
+        options: &SpawnOptions,
+        entry: SpawnEntry
+    ) -> KResult<&Arc<RwSpinlock<Context>>> {
+        let parent = context_id();
+        let stack_pages = options.stack_pages();
+        let stack = kstack_alloc(stack_pages).ok_or(OutOfMemory)?;
+
+        let new_context_lock = match self.new_context() {
+            Ok(lock) => Arc::clone(lock),
+            Err(err) => {
+                kstack_free(stack);
+                return Err(err);
             }
-            None => return Err(ENOMEM)
         };
-
-        let new_context_lock = self.new_context()?;
         let mut new_context = new_context_lock.write();
-        let addrsp = unsafe { RwLockUserAddrSpace::new(&new_context_lock, 0x1000) };
-
-        {   // make kernel stack accessible for user space
-            let mut rsp_cloned = Arc::clone(&addrsp);
-            let mut rsp_guard = rsp_cloned.acquire_write();
-            // 0x7fc0000000 是 PageTable[0][510] 1gb 页的起始虚拟地址
-            let kstack_start_page = Page::<Size4KiB>::containing_address(VirtAddr::new(0x7f_8000_0000));
-            let kstack_start_frame = PhysFrame::containing_address(PhysAddr::new(stack.as_mut_ptr() as u64));
-            // stack start may not 4k aligned, so update one more page
-            for page in Page::range(kstack_start_page, kstack_start_page + 64) {
-                unsafe {
-                    rsp_guard.raw_map_to(
-                        page,
-                        kstack_start_frame + (page - kstack_start_page),
-                        PageTableFlags::PRESENT |
-                            PageTableFlags::USER_ACCESSIBLE |
-                            PageTableFlags::WRITABLE |
-                            PageTableFlags::NO_EXECUTE
-                    )
-                }
-            }
+        let id = new_context.id;
+        new_context.set_name(options.name);
+        new_context.priority = options.priority;
+
+        // a thread gets its user stack in the next free slot of the shared space,
+        // a fresh address space hands out the first one
+        let addrsp = if (stack_pages * PAGE_SIZE) as u64 > USER_STACK_SLOT_SIZE {
+            Err(KError::new(EINVAL))
+        } else if let Some(addrsp) = options.addr_space {
+            Ok(Arc::clone(addrsp))
+        } else {
+            unsafe { RwLockUserAddrSpace::new(&new_context_lock, USER_BASE as usize) }
         }
+            .and_then(|addrsp| addrsp.alloc_stack_slot().map(|slot| (addrsp, slot)))
+            .and_then(|(addrsp, slot)| map_user_stack(&addrsp, stack_pages, slot).map(|_| (addrsp, slot)));
+        let (addrsp, stack_slot) = match addrsp {
+            Ok(addrsp) => addrsp,
+            Err(err) => {
+                drop(new_context);
+                kstack_free(stack);
+                self.remove(id);
+                return Err(err);
+            }
+        };
 
         new_context.set_addr_space(Some(addrsp));
+        // only a thread gives its slot back, see `Context::release_thread_stack`
+        new_context.stack_slot = options.addr_space.map(|_| stack_slot);
 
         infohart!("stack: {:x}", stack.as_mut_ptr() as u64);
-        let mut stack_top = unsafe { stack.as_mut_ptr().add(PAGE_SIZE * 64) };
+        let mut stack_top = unsafe { stack.as_mut_ptr().add(stack.len()) };
         infohart!("stack: {:x}", stack_top as u64);
         const INT_REGS_SIZE: usize = size_of::<InterruptStack>();
 
         unsafe {
-            if userspace_allowed {
+            if options.userspace {
                 // Zero-initialize InterruptStack registers.
                 stack_top = stack_top.sub(INT_REGS_SIZE);
                 stack_top.write_bytes(0_u8, INT_REGS_SIZE);
                 let intr_stack = &mut *stack_top.cast::<InterruptStack>();
                 intr_stack.init();
-                let rsp_field_offset = offset_of!(InterruptStack, iret) + offset_of!(IretRegisters, rsp);
-                intr_stack.set_stack_pointer(0x7f_8000_0000 + PAGE_SIZE * 64 - INT_REGS_SIZE + rsp_field_offset + size_of::<usize>());
+                intr_stack.set_stack_pointer(stack_slot_base(stack_slot) as usize + PAGE_SIZE * stack_pages);
 
                 stack_top = stack_top.sub(size_of::<usize>());
                 stack_top.cast::<usize>().write(enter_usermode as usize);
+            } else {
+                stack_top = stack_top.sub(size_of::<usize>());
+                stack_top.cast::<usize>().write(kernel_context_return as usize);
             }
 
+            // first switch returns into the trampoline, entry returns to the slot above
             stack_top = stack_top.sub(size_of::<usize>());
-            stack_top.cast::<usize>().write(func as usize);
+            stack_top.cast::<usize>().write(context_entry_trampoline as usize);
+        }
+        // from here on `remove` frees the stack and unmaps it from a shared space
+        new_context.kstack = Some(stack);
+
+        // reserve while failing is still cheap, the entry is consumed below
+        if let Some(parent_lock) = self.map.get(&parent) {
+            let mut parent_context = parent_lock.write();
+            let err = if parent_context.children.len() >= parent_context.rlimits.cur(RLIMIT_CHILDREN) {
+                Some(KError::new(EAGAIN))
+            } else if parent_context.children.try_reserve(1).is_err() {
+                Some(KError::new(ENOMEM))
+            } else {
+                None
+            };
+            if let Some(err) = err {
+                drop(parent_context);
+                drop(new_context);
+                self.remove(id);
+                return Err(err);
+            }
+            new_context.cred = parent_context.cred;
+            new_context.rlimits = parent_context.rlimits;
+            new_context.ext = parent_context.ext.inherit();
         }
 
+        let (entry, arg) = match entry.into_raw() {
+            Ok(raw) => raw,
+            Err(err) => {
+                drop(new_context);
+                self.remove(id);
+                return Err(err.into());
+            }
+        };
+        new_context.ctx_regs.set_entry(entry, arg);
         new_context.ctx_regs.set_stack_pointer(stack_top as usize);
-        new_context.kstack = Some(unsafe { &*stack });
-        new_context.userspace = userspace_allowed;
-
+        new_context.userspace = options.userspace;
+        new_context.ppid = Some(parent);
         drop(new_context);
-        Ok(new_context_lock)
+        if let Some(parent_lock) = self.map.get(&parent) {
+            parent_lock.write().children.push(id);
+        }
+
+        Ok(self.map.get(&id).or_panic("failed to get spawned context"))
     }
 
 
@@ -200,13 +335,37 @@ impl ContextStorage {
     ) -> ::alloc::collections::btree_map::Iter<ContextId, Arc<RwSpinlock<Context>>> {
         self.map.iter()
     }
+}
 
-    pub fn range(
-        &self,
-        range: impl RangeBounds<ContextId>,
-    ) -> ::alloc::collections::btree_map::Range<'_, ContextId, Arc<RwSpinlock<Context>>> {
-        self.map.range(range)
+/// back stack slot `slot` with `pages` zeroed frames as user stack, the slot
+/// is released again if that fails. the kernel stack is never mapped for user
+/// space, a thread sharing the space could rewrite it while its owner is inside the kernel
+fn map_user_stack(addrsp: &Arc<RwLockUserAddrSpace>, pages: usize, slot: usize) -> KResult<()> {
+    let mut mapped = 0;
+    let result = {
+        let mut rsp_guard = addrsp.acquire_write();
+        let start_page = Page::<Size4KiB>::containing_address(VirtAddr::new(stack_slot_base(slot)));
+        Page::range(start_page, start_page + pages as u64).try_for_each(|page| {
+            let frame = try_frame_alloc()?;
+            unsafe {
+                memcopy::zero(frame.start_address().as_u64() as *mut u8, PAGE_SIZE);
+                let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+                // only mapped frames are tracked, see `UserAddrSpace::drop`
+                if let Err(err) = rsp_guard.raw_map_to(page, frame, flags) {
+                    frame_dealloc(frame);
+                    return Err(err);
+                }
+                rsp_guard.push_tracked_frame(frame);
+            }
+            mapped += 1;
+            Ok(())
+        })
+    };
+    // a shared address space outlives the failed spawn
+    if result.is_err() {
+        addrsp.release_stack_slot(slot, mapped);
     }
+    result
 }
 
 impl Index<ContextId> for ContextStorage {
@@ -218,36 +377,92 @@ impl Index<ContextId> for ContextStorage {
 }
 
 /// Get the global context list, const
-pub fn context_storage() -> RwLockReadGuard<'static, ContextStorage> {
+pub fn context_storage() -> RwSpinlockReadGuard<'static, ContextStorage> {
     CONTEXT_STORAGE.read()
 }
 
+/// non-blocking [`context_storage`], for paths that may interrupt a holder of the write lock
+pub fn try_context_storage() -> Option<RwSpinlockReadGuard<'static, ContextStorage>> {
+    CONTEXT_STORAGE.try_read()
+}
+
 /// Get the global context list, mutable
-pub fn context_storage_mut() -> RwLockWriteGuard<'static, ContextStorage> {
+pub fn context_storage_mut() -> RwSpinlockWriteGuard<'static, ContextStorage> {
     CONTEXT_STORAGE.write()
 }
 
 #[test_case]
 pub(crate) fn test_context_id_allocator() {
-    let allocator = ContextIdAllocator::new(0);
-
-    assert_eq!(allocator.alloc(), 1);
-    assert_eq!(allocator.alloc(), 2);
-    assert_eq!(allocator.alloc(), 3);
-    assert_eq!(allocator.alloc(), 4);
-    assert_eq!(allocator.alloc(), 5);
-    assert_eq!(allocator.alloc(), 6);
-    assert_eq!(allocator.alloc(), 7);
-    assert_eq!(allocator.alloc(), 8);
-    assert_eq!(allocator.alloc(), 9);
-
-    assert_eq!(allocator.dealloc(10), 0);
-    assert_eq!(allocator.dealloc(3), 3);
-    assert_eq!(allocator.dealloc(4), 5);
-    assert_eq!(allocator.dealloc(4), 5);
-
-    assert_eq!(allocator.alloc(), 3);
-    assert_eq!(allocator.alloc(), 4);
-    assert_eq!(allocator.alloc(), 5);
-    assert_eq!(allocator.alloc(), 10);
-}
\ No newline at end of file
+    let mut allocator = ContextIdAllocator::new(4, 64);
+
+    for id in 4..10 {
+        assert_eq!(allocator.alloc(), Some(id));
+    }
+    // per-cpu and out of range ids are ignored
+    allocator.dealloc(0);
+    allocator.dealloc(100);
+
+    // fresh ids are handed out before recycled ones
+    allocator.dealloc(5);
+    allocator.dealloc(7);
+    assert_eq!(allocator.alloc(), Some(10));
+    assert!(allocator.is_live(10));
+    assert!(!allocator.is_live(5));
+}
+
+#[test_case]
+pub(crate) fn test_context_id_allocator_wraparound() {
+    let mut allocator = ContextIdAllocator::new(2, 8);
+
+    for id in 2..8 {
+        assert_eq!(allocator.alloc(), Some(id));
+    }
+    assert_eq!(allocator.alloc(), None);
+
+    // recycled in the order they were freed
+    allocator.dealloc(6);
+    allocator.dealloc(3);
+    allocator.dealloc(4);
+    assert_eq!(allocator.alloc(), Some(6));
+    assert_eq!(allocator.alloc(), Some(3));
+    allocator.dealloc(6);
+    assert_eq!(allocator.alloc(), Some(4));
+    assert_eq!(allocator.alloc(), Some(6));
+    assert_eq!(allocator.alloc(), None);
+}
+
+#[test_case]
+pub(crate) fn test_spawn_thread_stack_slots() {
+    extern "C" fn never_run() {}
+    let mut contexts = context_storage_mut();
+    let owner = Arc::clone(contexts.spawn(&SpawnOptions::userspace("owner"), SpawnEntry::Func(never_run)).unwrap());
+    let (owner_id, addrsp) = {
+        let owner = owner.read();
+        assert_eq!(owner.stack_slot, None);
+        (owner.id, owner.addrsp.clone().unwrap())
+    };
+
+    let options = SpawnOptions::userspace("thread").share_addr_space(&addrsp);
+    let thread_id = {
+        let thread = contexts.spawn(&options, SpawnEntry::Func(never_run)).unwrap().read();
+        assert!(Arc::ptr_eq(thread.addrsp.as_ref().unwrap(), &addrsp));
+        assert_eq!(thread.stack_slot, Some(1));
+        thread.id
+    };
+    let slot_base = VirtAddr::new(stack_slot_base(1));
+    let (phys_addr, _) = addrsp.translate_user(slot_base, true).unwrap();
+    // the slot has frames of its own, the kernel stack stays out of user space
+    let kstack_frames = contexts[thread_id].read().kstack.as_ref().unwrap().frames().to_vec();
+    assert!(kstack_frames.iter().all(|frame| frame.start_address() != phys_addr));
+
+    // a stack does not spill into the next slot
+    let too_big = SpawnOptions::userspace("thread").share_addr_space(&addrsp).stack_size(USER_STACK_SLOT_SIZE as usize + PAGE_SIZE);
+    assert_eq!(contexts.spawn(&too_big, SpawnEntry::Func(never_run)).err(), Some(KError::new(EINVAL)));
+
+    // the space outlives the thread, its slot is unmapped and handed out again
+    contexts.remove(thread_id);
+    assert!(addrsp.translate_user(slot_base, false).is_err());
+    assert_eq!(addrsp.alloc_stack_slot().ok(), Some(1));
+    addrsp.release_stack_slot(1, 0);
+    contexts.remove(owner_id);
+}
@@ -1,31 +1,62 @@
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 use core::mem;
 use core::sync::atomic::AtomicUsize;
 use bitflags::Flags;
-use x86_64::PhysAddr;
-use x86_64::registers::control::{Cr3, Cr3Flags};
-use x86_64::structures::paging::PhysFrame;
-use crate::context::list::context_storage_mut;
+use crate::arch::{ArchPaging, CurrentArch};
+use alloc::format;
+use crate::context::list::{context_storage, context_storage_mut, PERCPU_CONTEXT_IDS};
 use crate::mem::aligned_box::AlignedBox;
+use crate::mem::kstack::KernelStack;
+use crate::context::io::IoBitmap;
+use crate::context::cred::Credentials;
+use crate::context::ext::Extensions;
+use crate::context::rlimit::ResourceLimits;
 use crate::context::signal::SignalState;
+use crate::context::spawn::DEFAULT_PRIORITY;
 use crate::context::status::{HardBlockedReason, Status};
+use crate::context::trace::TraceEvent;
+use crate::sync::RwSpinlock;
 use crate::cpu::{LogicalCpuId, PercpuBlock};
+use crate::device::tsc::monotonic_ns;
+use crate::fs::aio;
 use crate::{infohart, int_like};
 use crate::mem::{get_kernel_pml4_page_table_addr, PAGE_SIZE};
 use crate::mem::user_addr_space::{RwLockUserAddrSpace, UserAddrSpace};
 use crate::syscall::InterruptStack;
+use crate::initcall;
+use crate::initcall::InitCpuArg;
+use spin::Once;
 
 pub mod list;
 pub mod switch;
 pub mod status;
+pub mod sleep;
+pub mod io;
+pub mod coredump;
+pub mod spawn;
+pub mod preempt;
+pub mod cred;
+pub mod ext;
+pub mod rlimit;
+pub mod trace;
+mod ready;
 mod signal;
 
 int_like!(ContextId, AtomicContextId, usize, AtomicUsize);
 
+pub const CONTEXT_NAME_LEN: usize = 16;
+
 // A task context, identifies either a process control lock or task control block
 pub struct Context {
     // the unique id of this context
     pub id: ContextId,
+    // context that spawned this one, None for idle contexts and orphans without init
+    pub ppid: Option<ContextId>,
+    // spawned contexts not reaped yet, exited ones included
+    pub children: Vec<ContextId>,
+    // debug name, nul padded utf-8
+    name: [u8; CONTEXT_NAME_LEN],
     // if the context is running
     pub running: bool,
     // underlying cpu id if running
@@ -33,42 +64,107 @@ pub struct Context {
     // is the context in syscall_module
     pub inside_syscall: bool,
     // kernel stack
-    pub kstack: Option<&'static [u8]>,
-    // context status
+    pub kstack: Option<KernelStack>,
+    // context status, changed through `set_status` which keeps the ready lists in sync
     pub status: Status,
+    // wake deadline in monotonic nanoseconds while sleeping
+    pub wake: Option<u64>,
     // signal state
     pub signal: SignalState,
+    // uid, gid and capabilities
+    pub cred: Credentials,
+    // limits on address space, children, cpu time
+    pub rlimits: ResourceLimits,
     // registers
     pub ctx_regs: ContextRegisters,
     // All contexts except kmain will primarily live in userspace, and enter the kernel only when
     // interrupts or syscall occur. This flag is set for all contexts but kmain.
     pub userspace: bool,
-    // address space
+    // address space, shared with the threads spawned into it
     pub addrsp: Option<Arc<RwLockUserAddrSpace>>,
+    // stack slot of a thread in a shared address space, None for the owner
+    pub stack_slot: Option<usize>,
+    // ports granted by ioperm, loaded into tss on switch
+    pub io_bitmap: Option<IoBitmap>,
+    // scheduling priority, higher is more important
+    pub priority: u8,
+    // nanoseconds spent running, updated when switched out
+    pub cpu_time: u64,
+    // state of subsystems outside this struct, see `ext`
+    pub ext: Extensions,
 }
 
 impl Context {
     pub fn new(id: ContextId) -> Self {
         Context {
             id,
+            ppid: None,
+            children: Vec::new(),
+            name: [0; CONTEXT_NAME_LEN],
             running: false,
             cpu_id: None,
             inside_syscall: false,
             kstack: None,
             status: Status::HardBlocked { reason: HardBlockedReason::NotYetStarted },
+            wake: None,
             signal: SignalState {
                 pending: 0,
                 procmask: !0
             },
+            cred: Credentials::root(),
+            rlimits: ResourceLimits::unlimited(),
             ctx_regs: ContextRegisters::new(),
             userspace: false,
-            addrsp: None
+            addrsp: None,
+            stack_slot: None,
+            io_bitmap: None,
+            priority: DEFAULT_PRIORITY,
+            cpu_time: 0,
+            ext: Extensions::new(),
+        }
+    }
+    pub fn name(&self) -> &str {
+        let len = self.name.iter().position(|b| *b == 0).unwrap_or(CONTEXT_NAME_LEN);
+        // only set through `set_name`, which keeps utf-8 boundaries
+        unsafe { core::str::from_utf8_unchecked(&self.name[..len]) }
+    }
+
+    /// set debug name, truncated to [`CONTEXT_NAME_LEN`] bytes at a char boundary
+    pub fn set_name(&mut self, name: &str) {
+        let mut len = name.len().min(CONTEXT_NAME_LEN);
+        while !name.is_char_boundary(len) {
+            len -= 1;
+        }
+        self.name = [0; CONTEXT_NAME_LEN];
+        self.name[..len].copy_from_slice(&name.as_bytes()[..len]);
+    }
+
+    /// idle context of a cpu, never in the run queue
+    pub fn is_idle(&self) -> bool {
+        self.id.get() < PERCPU_CONTEXT_IDS
+    }
+
+    /// `#id` or `#id (name)` for logs
+    pub fn display(&self) -> ContextDisplay<'_> {
+        ContextDisplay { id: self.id, name: self.name() }
+    }
+
+    /// change the status, queues the context for the scheduler once it is
+    /// runnable and dequeues it once it is not
+    pub fn set_status(&mut self, status: Status) {
+        self.status = status;
+        if self.status.is_runnable() {
+            ready::enqueue(self);
+        } else {
+            ready::dequeue(self.id);
         }
     }
+
     /// Block the context, and return true if it was runnable before being blocked
     pub fn soft_block(&mut self, reason: &'static str) -> bool {
         if self.status.is_runnable() {
-            self.status = Status::SoftBlocked { reason };
+            self.set_status(Status::SoftBlocked { reason });
+            trace::record(TraceEvent::Block, self.id);
             true
         } else {
             false
@@ -77,7 +173,8 @@ impl Context {
 
     pub fn hard_block(&mut self, reason: HardBlockedReason) -> bool {
         if self.status.is_runnable() {
-            self.status = Status::HardBlocked { reason };
+            self.set_status(Status::HardBlocked { reason });
+            trace::record(TraceEvent::Block, self.id);
             true
         } else {
             false
@@ -87,11 +184,13 @@ impl Context {
     /// Unblock context, and return true if it was blocked before being marked runnable
     pub fn unblock(&mut self) -> bool {
         if self.unblock_no_ipi() {
-            if let Some(cpu_id) = self.cpu_id {
-                if cpu_id != PercpuBlock::current().cpu_id {
+            match self.cpu_id {
+                Some(cpu_id) if cpu_id != PercpuBlock::current().cpu_id => {
                     // Send IPI if not on current CPU
                     //ipi(IpiKind::Wakeup, IpiTarget::Other);
                 }
+                // run it soon, on the next interrupt exit
+                _ => PercpuBlock::current().context_switch.set_need_resched(),
             }
 
             true
@@ -103,7 +202,8 @@ impl Context {
     /// Unblock context without IPI, and return true if it was blocked before being marked runnable
     pub fn unblock_no_ipi(&mut self) -> bool {
         if self.status.is_soft_blocked() {
-            self.status = Status::Runnable;
+            self.set_status(Status::Runnable);
+            trace::record(TraceEvent::Wake, self.id);
             true
         } else {
             false
@@ -119,11 +219,9 @@ impl Context {
 
         if self.id == context_id() {
             if let Some(ref new) = addrsp {
-                let mut new_addrsp = new.acquire_write();
-                unsafe { new_addrsp.validate(); }
+                unsafe { new.validate(); }
             } else {
-                let phys_addr = PhysAddr::new(get_kernel_pml4_page_table_addr());
-                unsafe { Cr3::write(PhysFrame::containing_address(phys_addr), Cr3Flags::empty()); }
+                unsafe { CurrentArch::switch_page_table(get_kernel_pml4_page_table_addr()); }
             }
         } else {
             assert!(!self.running);
@@ -132,6 +230,14 @@ impl Context {
         mem::replace(&mut self.addrsp, addrsp)
     }
 
+    /// unmap the user stack slot of a thread from the address space it shares,
+    /// which lives on. the slot of the owner goes away with the space
+    pub fn release_thread_stack(&mut self) {
+        if let (Some(slot), Some(addrsp), Some(kstack)) = (self.stack_slot.take(), &self.addrsp, &self.kstack) {
+            addrsp.release_stack_slot(slot, kstack.pages());
+        }
+    }
+
     fn can_access_regs(&self) -> bool {
         self.userspace
     }
@@ -143,6 +249,7 @@ impl Context {
         let Some(ref kstack) = self.kstack else {
             return None;
         };
+        let kstack = kstack.as_slice();
         let range = kstack.len().checked_sub(mem::size_of::<InterruptStack>())?..;
         Some(unsafe { &*kstack.get(range)?.as_ptr().cast() })
     }
@@ -151,9 +258,10 @@ impl Context {
         if !self.can_access_regs() {
             return None;
         }
-        let Some(ref mut kstack) = self.kstack else {
+        let Some(ref kstack) = self.kstack else {
             return None;
         };
+        let kstack = kstack.as_slice();
         let range = kstack.len().checked_sub(mem::size_of::<InterruptStack>())?..;
         let stack = kstack.get(range)?.as_ptr() as *const _ as u64 as *mut u8;
         Some(unsafe { &mut *stack.cast() })
@@ -181,15 +289,15 @@ pub struct ContextRegisters {
     pub rsp: usize,
     /// FSBASE.
     ///
-    /// NOTE: Same fsgsbase behavior as with gsbase.
+    /// NOTE: Same behavior as with gsbase.
     pub fsbase: usize,
-    /// GSBASE.
+    /// GSBASE of userspace.
     ///
-    /// NOTE: Without fsgsbase, this register will strictly be equal to the register value when
-    /// running. With fsgsbase, this is neither saved nor restored upon every syscall (there is no
-    /// need to!), and thus it must be re-read from the register before copying this struct.
+    /// NOTE: Saved on context switch only. Userspace may change it with fsgsbase, and it is not
+    /// saved upon every syscall (there is no need to!), so for the running context it must be
+    /// re-read with [`save_user_bases`](crate::arch_spec::fsgsbase::save_user_bases) first.
     pub gsbase: usize,
-    userspace_io_allowed: bool,
+    pub userspace_io_allowed: bool,
 }
 
 impl ContextRegisters {
@@ -212,28 +320,84 @@ impl ContextRegisters {
     pub fn set_stack_pointer(&mut self, address: usize) {
         self.rsp = address;
     }
+
+    /// callee saved registers picked up by `context_entry_trampoline` of a new context
+    pub fn set_entry(&mut self, entry: usize, arg: usize) {
+        self.r12 = entry;
+        self.rbx = arg;
+    }
+}
+
+pub struct ContextDisplay<'a> {
+    id: ContextId,
+    name: &'a str,
+}
+
+impl core::fmt::Display for ContextDisplay<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        if self.name.is_empty() {
+            write!(f, "#{}", self.id.get())
+        } else {
+            write!(f, "#{} ({})", self.id.get(), self.name)
+        }
+    }
 }
 
 pub fn context_id() -> ContextId {
     PercpuBlock::current().context_switch.context_id()
 }
 
-pub fn init_context() {
+// adopts orphans, the first userspace context
+static INIT_CONTEXT: Once<ContextId> = Once::new();
+
+pub fn set_init_context(id: ContextId) {
+    INIT_CONTEXT.call_once(|| id);
+}
+
+pub fn init_context() -> Option<ContextId> {
+    INIT_CONTEXT.get().copied()
+}
+
+/// mark `context` exited with `code` and hand its children to init.
+/// it stays in the context list until reaped, the caller switches away for good.
+pub fn exit_context(context: &Arc<RwSpinlock<Context>>, code: usize) {
+    let (id, children) = {
+        let mut context = context.write();
+        context.set_status(Status::Existed(code));
+        // the other threads keep using the address space
+        context.release_thread_stack();
+        (context.id, mem::take(&mut context.children))
+    };
+    if !children.is_empty() {
+        context_storage().adopt_orphans(id, &children);
+    }
+    aio::exit(id);
+}
+
+unsafe fn context_initcall(_: &InitCpuArg) {
+    init_idle_context();
+}
+initcall!(late, Bsp, context_initcall);
+
+/// turn the boot flow of current cpu into its idle context, aps call it from `_start_ap`
+pub fn init_idle_context() {
     let percpu = PercpuBlock::current();
     let mut contexts = context_storage_mut();
     let id = ContextId::from(percpu.cpu_id.0 as usize);
 
     let context_lock = contexts.insert_context(id)
-        .expect("failed to initialize first context");
+        .expect("failed to initialize idle context");
     let mut context = context_lock.write();
 
+    context.set_name(&format!("idle/{}", percpu.cpu_id.0));
     context.signal.procmask = 0;
-    context.status = Status::Runnable;
+    context.set_status(Status::Runnable);
     context.running = true;
     context.cpu_id = Some(percpu.cpu_id);
 
     unsafe {
         percpu.context_switch.set_context_id(context.id);
         percpu.context_switch.set_idle_id(context.id);
+        percpu.context_switch.set_switch_time(monotonic_ns());
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,43 @@
+use crate::context::list::try_context_storage;
+use crate::context::switch::{switch_context, switch_in_progress};
+use crate::cpu::PercpuBlock;
+use crate::sync::preemptible;
+use crate::syscall::IretRegisters;
+
+/**
+ *  kernel preemption on interrupt exit.
+ *
+ *  a timer tick sets `need_resched` of the cpu once the running context used
+ *  up its quantum (`config().quantum` ticks), wakeups and reschedule ipis set
+ *  it right away. the exit path of every non-paranoid handler calls
+ *  [`irq_exit`] once the handler is done. it switches away if the interrupted code, userspace or kernel, is outside a
+ *  critical section: interrupts were enabled, no nested handler, no spinlock
+ *  held, and neither the context list nor the current context is locked.
+ *  otherwise the request stays pending until the next interrupt exit or an
+ *  explicit [`switch_context`].
+ */
+
+const RFLAGS_IF: usize = 1 << 9;
+
+/// called by interrupt macros after the handler returned, with interrupts disabled
+pub unsafe fn irq_exit(iret: &IretRegisters) {
+    let percpu = PercpuBlock::current();
+    if !percpu.context_switch.need_resched() {
+        return;
+    }
+    // interrupted code had interrupts masked, it is a critical section by itself
+    if iret.rflags & RFLAGS_IF == 0 || !preemptible() || switch_in_progress() {
+        return;
+    }
+
+    // the interrupted code may hold these, switching would spin on them forever
+    {
+        let Some(contexts) = try_context_storage() else { return };
+        match contexts.current() {
+            Some(current) if !current.is_locked() => {}
+            _ => return,
+        }
+    }
+
+    switch_context();
+}
@@ -0,0 +1,159 @@
+use alloc::vec::Vec;
+use crate::config::MAX_CPUS;
+use crate::context::{Context, ContextId};
+use crate::cpu::{LogicalCpuId, PercpuBlock};
+use crate::mem::heap::OutOfMemory;
+use crate::sync::IrqSpinlock;
+
+/**
+ *  ready lists of the scheduler.
+ *
+ *  every cpu has a fifo of the contexts it may switch to: runnable, not
+ *  running, not idle. the links live in one table indexed by context id, so
+ *  queueing, dequeueing and picking never allocate and never look at any
+ *  other context. the table grows in `ContextStorage::new_context`, outside
+ *  of interrupt handlers which also wake contexts.
+ *
+ *  a context is queued on the cpu it ran on last, a never started one on the
+ *  cpu that made it runnable. `Context::set_status` keeps the lists in sync
+ *  with the status, the switch requeues the context it leaves. whoever holds
+ *  this lock only try-locks contexts, the other order is the common one.
+ */
+
+const NIL: usize = usize::MAX;
+
+#[derive(Clone, Copy)]
+struct Link {
+    prev: usize,
+    next: usize,
+    // list the context is on, NIL while not queued
+    cpu: usize,
+}
+
+impl Link {
+    const UNQUEUED: Link = Link { prev: NIL, next: NIL, cpu: NIL };
+}
+
+#[derive(Clone, Copy)]
+struct Queue {
+    head: usize,
+    tail: usize,
+}
+
+struct ReadyLists {
+    links: Vec<Link>,
+    queues: [Queue; MAX_CPUS],
+}
+
+static READY: IrqSpinlock<ReadyLists> = IrqSpinlock::new(ReadyLists::new());
+
+impl ReadyLists {
+    const fn new() -> Self {
+        Self { links: Vec::new(), queues: [Queue { head: NIL, tail: NIL }; MAX_CPUS] }
+    }
+
+    fn reserve(&mut self, id: usize) -> Result<(), OutOfMemory> {
+        if id < self.links.len() {
+            return Ok(());
+        }
+        self.links.try_reserve(id + 1 - self.links.len()).map_err(|_| OutOfMemory)?;
+        self.links.resize(id + 1, Link::UNQUEUED);
+        Ok(())
+    }
+
+    fn is_queued(&self, id: usize) -> bool {
+        self.links.get(id).is_some_and(|link| link.cpu != NIL)
+    }
+
+    fn push_back(&mut self, id: usize, cpu: usize) {
+        debug_assert!(!self.is_queued(id), "context {} queued twice", id);
+        let queue = &mut self.queues[cpu];
+        self.links[id] = Link { prev: queue.tail, next: NIL, cpu };
+        match queue.tail {
+            NIL => queue.head = id,
+            tail => self.links[tail].next = id,
+        }
+        queue.tail = id;
+    }
+
+    fn remove(&mut self, id: usize) -> bool {
+        if !self.is_queued(id) {
+            return false;
+        }
+        let Link { prev, next, cpu } = self.links[id];
+        let queue = &mut self.queues[cpu];
+        match prev {
+            NIL => queue.head = next,
+            prev => self.links[prev].next = next,
+        }
+        match next {
+            NIL => queue.tail = prev,
+            next => self.links[next].prev = prev,
+        }
+        self.links[id] = Link::UNQUEUED;
+        true
+    }
+
+    /// oldest context of `cpu` that `take` accepts, dequeued
+    fn pick(&mut self, cpu: usize, mut take: impl FnMut(ContextId) -> bool) -> Option<ContextId> {
+        let mut id = self.queues[cpu].head;
+        while id != NIL {
+            if take(ContextId::from(id)) {
+                self.remove(id);
+                return Some(ContextId::from(id));
+            }
+            id = self.links[id].next;
+        }
+        None
+    }
+}
+
+/// room for `id` in the link table, called before the context can be woken
+pub(super) fn reserve(id: ContextId) -> Result<(), OutOfMemory> {
+    READY.lock().reserve(id.get())
+}
+
+/// put `context` at the back of its list if it may be switched to
+pub(super) fn enqueue(context: &Context) {
+    if !context.status.is_runnable() || context.running || context.is_idle() {
+        return;
+    }
+    let cpu = context.cpu_id.unwrap_or(PercpuBlock::current().cpu_id);
+    let mut ready = READY.lock();
+    if !ready.is_queued(context.id.get()) {
+        ready.push_back(context.id.get(), cpu.0 as usize);
+    }
+}
+
+/// take `id` off its list, if it is on one
+pub(super) fn dequeue(id: ContextId) {
+    READY.lock().remove(id.get());
+}
+
+/// dequeue the first context on the list of `cpu` that `take` accepts.
+/// `take` runs under the list lock and may only try-lock the context
+pub(super) fn pick(cpu: LogicalCpuId, take: impl FnMut(ContextId) -> bool) -> Option<ContextId> {
+    READY.lock().pick(cpu.0 as usize, take)
+}
+
+#[test_case]
+pub(crate) fn test_ready_lists() {
+    let mut ready = ReadyLists::new();
+    ready.reserve(8).unwrap();
+    ready.push_back(5, 0);
+    ready.push_back(7, 0);
+    ready.push_back(6, 0);
+    ready.push_back(8, 1);
+
+    // unlinked from the middle, the order of the rest is kept
+    assert!(ready.remove(7));
+    assert!(!ready.remove(7));
+    assert_eq!(ready.pick(0, |_| true), Some(ContextId::from(5)));
+
+    // rejected ones stay queued, lists of other cpus are not touched
+    assert_eq!(ready.pick(0, |id| id.get() != 6), None);
+    assert!(ready.is_queued(6));
+    assert_eq!(ready.pick(1, |_| true), Some(ContextId::from(8)));
+    assert_eq!(ready.pick(0, |_| true), Some(ContextId::from(6)));
+    assert_eq!((ready.queues[0].head, ready.queues[0].tail), (NIL, NIL));
+}
@@ -0,0 +1,48 @@
+use libvdso::error::{EINVAL, EPERM, KError, KResult};
+use libvdso::flag::CAP_ADMIN;
+use libvdso::rlimit::{RLimit, RLIMIT_COUNT};
+use crate::context::cred::Credentials;
+
+/**
+ *  resource limits of a context, inherited by contexts it spawns.
+ *
+ *  enforced where the resource is taken:
+ *      RLIMIT_AS        `RwLockUserAddrSpace::alloc`, ENOMEM
+ *      RLIMIT_CHILDREN  `ContextStorage::spawn`, EAGAIN
+ *      RLIMIT_CPU       cpu time accounting of `switch_context`, the context
+ *                       is killed with SIGXCPU once it passes `cur` in userspace
+ *      RLIMIT_NOFILE    `file_table::open`, EMFILE
+ */
+
+#[derive(Clone, Copy, Debug)]
+pub struct ResourceLimits {
+    limits: [RLimit; RLIMIT_COUNT],
+}
+
+impl ResourceLimits {
+    pub const fn unlimited() -> Self {
+        Self { limits: [RLimit::INFINITY; RLIMIT_COUNT] }
+    }
+
+    pub fn get(&self, resource: usize) -> KResult<RLimit> {
+        self.limits.get(resource).copied().ok_or(KError::new(EINVAL))
+    }
+
+    /// enforced limit of `resource`, which must be valid
+    pub fn cur(&self, resource: usize) -> usize {
+        self.limits[resource].cur
+    }
+
+    /// lowering is always allowed, raising `max` needs CAP_ADMIN
+    pub fn set(&mut self, resource: usize, limit: RLimit, cred: &Credentials) -> KResult<()> {
+        let old = self.limits.get_mut(resource).ok_or(KError::new(EINVAL))?;
+        if limit.cur > limit.max {
+            return Err(KError::new(EINVAL));
+        }
+        if limit.max > old.max && !cred.has(CAP_ADMIN) {
+            return Err(KError::new(EPERM));
+        }
+        *old = limit;
+        Ok(())
+    }
+}
@@ -2,7 +2,8 @@ use libvdso::flag::{SIGKILL, SIGSTOP};
 
 #[derive(Clone, Copy, Debug)]
 pub struct SignalState {
-    /// Bitset of pending signals.
+    /// Bitset of pending signals. Whoever sets a bit wakes a soft blocked
+    /// context, the scheduler only looks at ready ones.
     pub pending: u64,
     /// Bitset of procmasked signals.
     pub procmask: u64,
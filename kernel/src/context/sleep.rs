@@ -0,0 +1,69 @@
+use alloc::collections::BTreeSet;
+use core::ops::Bound;
+use crate::context::list::ContextStorage;
+use crate::context::{Context, ContextId};
+use crate::sync::IrqSpinlock;
+
+/**
+ *  sleep queue.
+ *
+ *  a sleeping context is soft blocked with `wake` set to its deadline in
+ *  monotonic nanoseconds. the queue orders deadlines, the scheduler unblocks
+ *  the expired ones from its front in `wake_expired` and a timer can be
+ *  programmed for the earliest one.
+ */
+
+pub const SLEEP_BLOCK_REASON: &str = "sleep";
+
+// (deadline, context)
+static SLEEP_QUEUE: IrqSpinlock<BTreeSet<(u64, ContextId)>> = IrqSpinlock::new(BTreeSet::new());
+
+/// block `context` until monotonic time `deadline`, caller switches away afterwards
+pub fn sleep_until(context: &mut Context, deadline: u64) {
+    if let Some(old) = context.wake.replace(deadline) {
+        SLEEP_QUEUE.lock().remove(&(old, context.id));
+    }
+    SLEEP_QUEUE.lock().insert((deadline, context.id));
+    context.soft_block(SLEEP_BLOCK_REASON);
+}
+
+/// drop pending deadline of `context`, it is woken by something else or exiting
+pub fn cancel_sleep(context: &mut Context) {
+    if let Some(deadline) = context.wake.take() {
+        SLEEP_QUEUE.lock().remove(&(deadline, context.id));
+    }
+}
+
+/// unblock `context` if its deadline passed at `now`, returns true if woken
+pub fn wake_if_expired(context: &mut Context, now: u64) -> bool {
+    match context.wake {
+        Some(deadline) if deadline <= now => {
+            cancel_sleep(context);
+            context.unblock_no_ipi();
+            true
+        }
+        _ => false,
+    }
+}
+
+/// unblock sleepers whose deadline passed at `now`. a sleeper locked by
+/// someone else is left for the next call, so is one woken concurrently
+pub fn wake_expired(contexts: &ContextStorage, now: u64) {
+    let mut cursor = Bound::Unbounded;
+    loop {
+        // the queue lock is dropped before waking, that removes the entry
+        let Some((deadline, id)) = SLEEP_QUEUE.lock().range((cursor, Bound::Unbounded)).next().copied() else { return };
+        if deadline > now {
+            return;
+        }
+        cursor = Bound::Excluded((deadline, id));
+        if let Some(mut context) = contexts.get(id).and_then(|lock| lock.try_write()) {
+            wake_if_expired(&mut context, now);
+        }
+    }
+}
+
+/// earliest deadline of all sleepers, for programming the next timer interrupt
+pub fn next_deadline() -> Option<u64> {
+    SLEEP_QUEUE.lock().first().map(|(deadline, _)| *deadline)
+}
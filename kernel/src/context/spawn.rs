@@ -0,0 +1,130 @@
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::arch::global_asm;
+use crate::arch::{ArchInterrupts, CurrentArch};
+use crate::context::list::context_storage;
+use crate::context::exit_context;
+use crate::context::switch::switch_context;
+use crate::mem::heap::{try_box, OutOfMemory};
+use crate::mem::stack::stack_config;
+use crate::mem::user_addr_space::RwLockUserAddrSpace;
+use crate::mem::PAGE_SIZE;
+
+/**
+ *  how a new context starts.
+ *
+ *  [`ContextStorage::spawn`](super::list::ContextStorage::spawn) crafts the
+ *  kernel stack so the first switch returns into `context_entry_trampoline`,
+ *  which moves the argument (kept in rbx) into rdi and jumps to the entry
+ *  (kept in r12). when the entry returns, a userspace context drops to ring 3
+ *  through `enter_usermode`, a kernel context exits.
+ *
+ *  a thread runs in the address space of its owner, see
+ *  `SpawnOptions::share_addr_space`. its stack goes into a slot of the user
+ *  stack region of its own and is unmapped again when it exits.
+ */
+
+pub const DEFAULT_PRIORITY: u8 = 20;
+
+/// options of a new context
+pub struct SpawnOptions<'a> {
+    pub name: &'a str,
+    /// drop to userspace when the entry returns
+    pub userspace: bool,
+    /// kernel stack size in bytes, 0 takes the configured default
+    pub stack_size: usize,
+    /// scheduling priority, higher is more important
+    pub priority: u8,
+    /// address space to run in as a thread, None creates a fresh one
+    pub addr_space: Option<&'a Arc<RwLockUserAddrSpace>>,
+}
+
+impl<'a> SpawnOptions<'a> {
+    pub fn kernel(name: &'a str) -> Self {
+        Self { name, userspace: false, stack_size: 0, priority: DEFAULT_PRIORITY, addr_space: None }
+    }
+
+    pub fn userspace(name: &'a str) -> Self {
+        Self { userspace: true, ..Self::kernel(name) }
+    }
+
+    pub fn stack_size(self, stack_size: usize) -> Self {
+        Self { stack_size, ..self }
+    }
+
+    pub fn priority(self, priority: u8) -> Self {
+        Self { priority, ..self }
+    }
+
+    /// spawn a thread of the owner of `addrsp`, it gets its own stack slot
+    pub fn share_addr_space(self, addrsp: &'a Arc<RwLockUserAddrSpace>) -> Self {
+        Self { addr_space: Some(addrsp), ..self }
+    }
+
+    pub(super) fn stack_pages(&self) -> usize {
+        match self.stack_size {
+            0 => stack_config().context_pages(),
+            size => (size + PAGE_SIZE - 1) / PAGE_SIZE,
+        }
+    }
+}
+
+/// entry point of a new context
+pub enum SpawnEntry {
+    Func(extern "C" fn()),
+    /// the argument is passed in rdi
+    WithArg(extern "C" fn(usize), usize),
+    /// kernel thread closure, boxed until the context starts
+    Closure(Box<dyn FnOnce() + Send + 'static>),
+}
+
+impl SpawnEntry {
+    pub fn closure(f: impl FnOnce() + Send + 'static) -> Self {
+        SpawnEntry::Closure(Box::new(f))
+    }
+
+    /// (entry, argument) loaded by the trampoline
+    pub(super) fn into_raw(self) -> Result<(usize, usize), OutOfMemory> {
+        Ok(match self {
+            SpawnEntry::Func(func) => (func as usize, 0),
+            SpawnEntry::WithArg(func, arg) => (func as usize, arg),
+            // fat pointer does not fit a register, box it again
+            SpawnEntry::Closure(f) => (closure_entry as usize, Box::into_raw(try_box(f)?) as usize),
+        })
+    }
+}
+
+extern "C" {
+    pub(super) fn context_entry_trampoline();
+}
+
+global_asm!(
+    ".global context_entry_trampoline",
+    "context_entry_trampoline:",
+    "    mov rdi, rbx",
+    "    jmp r12",
+);
+
+extern "C" fn closure_entry(arg: usize) {
+    let f = unsafe { Box::from_raw(arg as *mut Box<dyn FnOnce() + Send + 'static>) };
+    f();
+}
+
+/// return address of kernel context entries
+pub(super) extern "C" fn kernel_context_return() -> ! {
+    exit_current(0)
+}
+
+/// exit the current context with `code` from kernel mode, it never runs again
+pub fn exit_current(code: usize) -> ! {
+    let context = context_storage().current().cloned();
+    if let Some(context) = context {
+        exit_context(&context, code);
+    }
+    loop {
+        unsafe {
+            CurrentArch::disable_interrupts();
+            switch_context();
+        }
+    }
+}
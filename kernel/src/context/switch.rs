@@ -4,20 +4,27 @@ use core::cell::Cell;
 use core::hint::spin_loop;
 use core::mem::transmute;
 use core::mem::offset_of;
-use core::ops::Bound;
-use core::ptr::{addr_of, addr_of_mut};
 use core::sync::atomic::{AtomicBool, Ordering};
 use log::info;
-use spin::RwLockWriteGuard;
-use spinning_top::guard::ArcRwSpinlockWriteGuard;
 use shared::print_panic::PrintPanic;
-use crate::context::{Context, ContextId, ContextRegisters};
+use libvdso::flag::SIGXCPU;
+use libvdso::rlimit::RLIMIT_CPU;
+use crate::arch_spec::fsgsbase::switch_user_bases;
+use crate::config::config;
+use crate::context::{ready, Context, ContextId, ContextRegisters};
 use crate::context::list::context_storage;
-use crate::cpu::{LogicalCpuId, PercpuBlock};
+use crate::context::sleep::{next_deadline, wake_expired};
+use crate::context::status::Status;
+use crate::context::trace::{self, TraceEvent};
+use crate::mem::kstack::kstack_free;
+use crate::device::tsc::monotonic_ns;
+use crate::cpu::PercpuBlock;
 use crate::device::qemu::{exit_qemu, QemuExitCode};
 use crate::gdt::pcr;
-use crate::{infohart, qemu_println};
+use crate::logger::audit::{audit, AuditKind};
+use crate::{infohart, qemu_println, warnhart};
 use crate::mem::user_addr_space::RwLockUserAddrSpace;
+use crate::sync::ArcRwSpinlockWriteGuard;
 
 // if is in context switch, preventing multiple call to [`switch_context`]
 static CONTEXT_SWITCH_LOCK: AtomicBool = AtomicBool::new(false);
@@ -30,12 +37,17 @@ struct SwitchResultInner {
 #[derive(Default)]
 pub struct ContextSwitchPercpu {
     switch_result: Cell<Option<SwitchResultInner>>,
+    // timer ticks since the current context was switched in, pit or lapic timer
     pit_ticks: Cell<usize>,
     /// Unique ID of the currently running context.
     context_id: Cell<ContextId>,
     // The ID of the idle process
     idle_id: Cell<ContextId>,
     switch_signal: Cell<bool>,
+    // monotonic ns when the current context was switched in
+    switch_time: Cell<u64>,
+    // set by timer ticks and reschedule ipis, consumed on interrupt exit
+    need_resched: Cell<bool>,
 }
 
 impl ContextSwitchPercpu {
@@ -51,6 +63,28 @@ impl ContextSwitchPercpu {
     pub unsafe fn set_idle_id(&self, new: ContextId) {
         self.idle_id.set(new)
     }
+    pub unsafe fn set_switch_time(&self, now: u64) {
+        self.switch_time.set(now)
+    }
+    pub fn need_resched(&self) -> bool {
+        self.need_resched.get()
+    }
+    pub fn set_need_resched(&self) {
+        self.need_resched.set(true)
+    }
+    /// system tick, asks for a reschedule once the current context ran for its
+    /// quantum or a sleeper is due, which only the switch wakes
+    pub fn tick(&self) {
+        let ticks = self.pit_ticks.get() + 1;
+        self.pit_ticks.set(ticks);
+        let quantum = config().quantum;
+        if ticks == quantum {
+            trace::record(TraceEvent::Preempt, self.context_id());
+        }
+        if ticks >= quantum || next_deadline().is_some_and(|deadline| deadline <= monotonic_ns()) {
+            self.set_need_resched();
+        }
+    }
 }
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -59,28 +93,9 @@ pub enum SwitchResult {
     AllContextsIdle,
 }
 
-unsafe fn upgrade_runnable(context: &mut Context, cpu_id: LogicalCpuId) -> Result<bool, ()> {
-    if context.running {
-        return Err(())
-    }
-    // 支持调度到其他核
-    if !context.cpu_id.map_or(true, |x| x == cpu_id) {
-        return Err(())
-    }
-
-    let signal_deliverable = context.signal.deliverable() != 0;
-
-    if context.status.is_soft_blocked() && signal_deliverable {
-        context.unblock_no_ipi();
-    }
-
-    // TODO: userspace sleep wake
-
-    if context.status.is_runnable() {
-        Ok(signal_deliverable)
-    } else {
-        Err(())
-    }
+/// whether a cpu is inside [`switch_context`]
+pub fn switch_in_progress() -> bool {
+    CONTEXT_SWITCH_LOCK.load(Ordering::SeqCst)
 }
 
 /// Switch to the next context, picked by the scheduler.
@@ -88,53 +103,71 @@ unsafe fn upgrade_runnable(context: &mut Context, cpu_id: LogicalCpuId) -> Resul
 /// This is not memory-unsafe to call, but do NOT call this while holding locks!
 pub unsafe fn switch_context() -> SwitchResult {
     let percpu = PercpuBlock::current();
-    //set PIT Interrupt counter to 0, giving each process same amount of PIT ticks
+    // the next context starts a fresh quantum, so does prev if it keeps running
     percpu.context_switch.pit_ticks.set(0);
+    percpu.context_switch.need_resched.set(false);
 
     while CONTEXT_SWITCH_LOCK.compare_exchange_weak(false, true, Ordering::SeqCst, Ordering::Relaxed).is_err() {
         spin_loop()
     }
 
+    let now = monotonic_ns();
     let mut selected_switch_context = None;
     {
         let contexts = context_storage();
 
+        // before locking prev, a sleeper whose time is up keeps running instead of idling
+        wake_expired(&contexts, now);
+
         let prev_context_lock = contexts.current()
             .or_panic("failed to get current context");
-        let prev_context = prev_context_lock.write_arc();
+        let mut prev_context = prev_context_lock.write_arc();
+
+        // over its cpu quota, killed before picking so it is not picked again.
+        // only when preempted in userspace, a syscall may be holding resources.
+        // orphans are handed to init once it is reaped, the context list is locked here
+        let ran = prev_context.cpu_time + now.saturating_sub(percpu.context_switch.switch_time.get());
+        if prev_context.userspace && !percpu.inside_syscall.get() && prev_context.status.is_runnable()
+            && ran as usize > prev_context.rlimits.cur(RLIMIT_CPU) {
+            warnhart!("context {} killed, {} ns of cpu time is over its limit", prev_context.display(), ran);
+            audit(AuditKind::Kill, format_args!("{} by SIGXCPU after {} ns", prev_context.display(), ran));
+            prev_context.set_status(Status::Existed(128 + SIGXCPU));
+        }
 
         let idle_id = percpu.context_switch.idle_id();
-        let mut skip_idle = false;
-
-        let contexts_iter = contexts
-            .range((Bound::Excluded(prev_context.id), Bound::Unbounded))
-            .chain(contexts.range(..prev_context.id))
-            .chain(contexts.range(idle_id..idle_id));
-
-        for (cid, ctx_lock) in contexts_iter {
-            if cid == &idle_id && skip_idle {
-                // Skip idle process the first time it shows up
-                skip_idle = false;
-                continue;
-            }
-
-            let mut ctx = ctx_lock.write_arc();
 
-            if let Ok(signal_deliverable) = upgrade_runnable(&mut *ctx, percpu.cpu_id) {
-                infohart!("selected: prev: {:?}, curr: {:?}", prev_context.id, ctx.id);
-                selected_switch_context = Some((prev_context, ctx));
-                percpu.context_switch.switch_signal.set(signal_deliverable);
+        // round robin over the ready list of this cpu, idle contexts are never on it
+        let mut next_context = None;
+        ready::pick(percpu.cpu_id, |id| {
+            // locked by the code we preempted or by another cpu, try it next round
+            let Some(ctx) = contexts.get(id).and_then(|lock| lock.try_write_arc()) else { return false };
+            debug_assert!(ctx.status.is_runnable() && !ctx.running, "context {} is ready but not runnable", id.get());
+            next_context = Some(ctx);
+            true
+        });
+        if let Some(ctx) = &next_context {
+            infohart!("selected: prev: {}, curr: {}", prev_context.display(), ctx.display());
+            percpu.context_switch.switch_signal.set(ctx.signal.deliverable() != 0);
+        }
 
-                break
-            }
+        // nothing else to run, a blocked or exited context falls back to idle of this cpu
+        if next_context.is_none() && prev_context.id != idle_id && !prev_context.status.is_runnable() {
+            let idle_lock = contexts.get(idle_id)
+                .or_panic("failed to get idle context");
+            next_context = Some(idle_lock.write_arc());
+            percpu.context_switch.switch_signal.set(false);
         }
+
+        selected_switch_context = next_context.map(|next| (prev_context, next));
     }
 
     if let Some((mut prev_ctx_guard, mut next_ctx_guard)) = selected_switch_context {
         // Set old context as not running and update CPU time
         let prev_ctx = &mut *prev_ctx_guard;
         prev_ctx.running = false;
-        // todo: add prev ctx cpu time
+        // back of the list if still runnable, picked again after the others
+        ready::enqueue(prev_ctx);
+        prev_ctx.cpu_time += now.saturating_sub(percpu.context_switch.switch_time.replace(now));
 
         // Set new context as running and set switch time
         let next_ctx = &mut *next_ctx_guard;
@@ -142,6 +175,7 @@ pub unsafe fn switch_context() -> SwitchResult {
         next_ctx.cpu_id = Some(percpu.cpu_id);
 
         percpu.context_switch.context_id.set(next_ctx.id);
+        trace::record(TraceEvent::Switch, next_ctx.id);
 
         // context guard 要保存起来防止被 RAII 释放
         // 下面 switch 后会改变程序流，所以把 guard 所有权交给 percpu block
@@ -161,39 +195,18 @@ pub unsafe fn switch_context() -> SwitchResult {
         // switch
         let pcr = pcr();
         if let Some(ref stack) = next_ctx_unguarded.kstack {
-            pcr.set_tss_stack((stack.as_ptr() as usize + stack.len()) as u64);
+            pcr.set_tss_stack((stack.as_mut_ptr() as usize + stack.len()) as u64);
+        }
+        if let Some(ref bitmap) = next_ctx_unguarded.io_bitmap {
+            pcr.load_io_bitmap(bitmap);
         }
         pcr.set_userspace_io_allowed(next_ctx_unguarded.ctx_regs.userspace_io_allowed);
 
         // save gs and fs
-        asm!(
-            "
-            mov ecx, {MSR_FSBASE}
-            rdmsr
-            mov [{prev}+{fsbase_off}], eax
-            mov rdx, [{next}+{fsbase_off}]
-            mov eax, edx
-            shr rdx, 32
-            wrmsr
-
-            mov ecx, {MSR_KERNEL_GSBASE}
-            rdmsr
-            mov [{prev}+{gsbase_off}], eax
-            mov rdx, [{next}+{gsbase_off}]
-            mov eax, edx
-            shr rdx, 32
-            wrmsr
-            ",
-            out("rax") _,
-            out("rdx") _,
-            out("ecx") _,
-            prev = in(reg) addr_of!(prev_ctx_unguarded.ctx_regs),
-            next = in(reg) addr_of!(next_ctx_unguarded.ctx_regs),
-            MSR_FSBASE = const 0xc0000100u32, // IA32_FS_BASE,
-            MSR_KERNEL_GSBASE = const 0xc0000102u32, // IA32_KERNEL_GSBASE,
-            gsbase_off = const offset_of!(ContextRegisters, gsbase),
-            fsbase_off = const offset_of!(ContextRegisters, fsbase),
-        );
+        switch_user_bases(&mut prev_ctx_unguarded.ctx_regs, &next_ctx_unguarded.ctx_regs);
+
+        #[cfg(feature = "bench")]
+        crate::bench::switch_begin();
 
         switch_context_inner(&mut prev_ctx_unguarded.ctx_regs, &mut next_ctx_unguarded.ctx_regs);
 
@@ -283,12 +296,22 @@ unsafe extern "sysv64" fn switch_context_inner(
 }
 
 pub unsafe extern "C" fn post_switch_context() {
+    #[cfg(feature = "bench")]
+    crate::bench::switch_end();
+
     let percpu = PercpuBlock::current();
     let switch_result = percpu.context_switch.switch_result.take();
 
     CONTEXT_SWITCH_LOCK.store(false, Ordering::SeqCst);
 
-    if let Some(result) = switch_result {
+    if let Some(mut result) = switch_result {
+        // an exited context never runs again, its stack is free once we left it
+        if let Status::Existed(_) = result.prev_ctx.status {
+            if let Some(stack) = result.prev_ctx.kstack.take() {
+                kstack_free(stack);
+            }
+        }
+
         let cmp = match (&result.prev_ctx.addrsp, &result.next_ctx.addrsp) {
             (Some(ref p), Some(ref n)) => Arc::ptr_eq(p, n),
             (Some(_), None) | (None, Some(_)) => false,
@@ -299,8 +322,7 @@ pub unsafe extern "C" fn post_switch_context() {
 
         let next_ctx_guard = result.next_ctx;
         if let Some(addrsp) = &next_ctx_guard.addrsp {
-            let mut write = addrsp.acquire_write();
-            write.validate();
+            addrsp.validate();
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,138 @@
+use core::arch::x86_64::_rdtsc;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use crate::config::MAX_CPUS;
+use crate::context::{context_id, ContextId};
+use crate::context::list::try_context_storage;
+use crate::cpu::PercpuBlock;
+use crate::device::com::COM1;
+use crate::device::tsc::current_tsc_hz;
+use crate::CPU_COUNT;
+
+/**
+ *  scheduler event trace.
+ *
+ *  every cpu records context switches, blocks, wakes, expired quanta and
+ *  sent ipis into its own fixed ring of `{tsc, event, context id}`, the
+ *  oldest entries are overwritten. only the owning cpu writes a ring, a slot
+ *  is claimed by bumping the head so an interrupt recording in between gets
+ *  its own.
+ *
+ *  `dump` streams the rings over com1 as text lines, recording is paused
+ *  meanwhile:
+ *
+ *      STRACE begin <cpu count> <tsc hz>
+ *      STRACE ctx <id> <name>                      per live context
+ *      STRACE ev <cpu> <tsc> <event> <ctx id>      oldest first per cpu
+ *      STRACE end <event count>
+ *
+ *  `build-image sched-trace <serial log>` turns it into chrome trace json.
+ */
+
+pub const TRACE_ENTRIES: usize = 1024;
+const EVENT_SHIFT: u32 = 56;
+const CTX_MASK: u64 = (1 << EVENT_SHIFT) - 1;
+
+#[derive(Clone, Copy, Debug)]
+#[repr(u8)]
+pub enum TraceEvent {
+    // context id is the one switched to
+    Switch = 1,
+    Block = 2,
+    Wake = 3,
+    // context id is the sender
+    Ipi = 4,
+    // context id is the one whose quantum ran out
+    Preempt = 5,
+}
+
+impl TraceEvent {
+    fn name(self) -> &'static str {
+        match self {
+            TraceEvent::Switch => "switch",
+            TraceEvent::Block => "block",
+            TraceEvent::Wake => "wake",
+            TraceEvent::Ipi => "ipi",
+            TraceEvent::Preempt => "preempt",
+        }
+    }
+
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(TraceEvent::Switch),
+            2 => Some(TraceEvent::Block),
+            3 => Some(TraceEvent::Wake),
+            4 => Some(TraceEvent::Ipi),
+            5 => Some(TraceEvent::Preempt),
+            _ => None,
+        }
+    }
+}
+
+struct TraceEntry {
+    tsc: AtomicU64,
+    // event in the top byte, context id below
+    what: AtomicU64,
+}
+
+struct TraceRing {
+    head: AtomicUsize,
+    entries: [TraceEntry; TRACE_ENTRIES],
+}
+
+const EMPTY_ENTRY: TraceEntry = TraceEntry { tsc: AtomicU64::new(0), what: AtomicU64::new(0) };
+const EMPTY_RING: TraceRing = TraceRing { head: AtomicUsize::new(0), entries: [EMPTY_ENTRY; TRACE_ENTRIES] };
+
+static RINGS: [TraceRing; MAX_CPUS] = [EMPTY_RING; MAX_CPUS];
+static RECORDING: AtomicBool = AtomicBool::new(true);
+
+/// append `event` of context `ctx` to the ring of the current cpu
+#[inline]
+pub fn record(event: TraceEvent, ctx: ContextId) {
+    if !RECORDING.load(Ordering::Relaxed) {
+        return;
+    }
+    let ring = &RINGS[PercpuBlock::current().cpu_id.0 as usize];
+    let entry = &ring.entries[ring.head.fetch_add(1, Ordering::Relaxed) % TRACE_ENTRIES];
+    entry.tsc.store(unsafe { _rdtsc() }, Ordering::Relaxed);
+    entry.what.store((event as u64) << EVENT_SHIFT | (ctx.get() as u64 & CTX_MASK), Ordering::Release);
+}
+
+/// record an ipi sent by the current context
+#[inline]
+pub fn record_ipi() {
+    record(TraceEvent::Ipi, context_id());
+}
+
+/// stream every ring to com1, returns the number of events written
+pub fn dump() -> usize {
+    let was_recording = RECORDING.swap(false, Ordering::SeqCst);
+    let cpu_count = (CPU_COUNT.load(Ordering::SeqCst) as usize).min(MAX_CPUS);
+    let mut events = 0;
+    {
+        let mut com = COM1.lock();
+        let _ = writeln!(com, "STRACE begin {} {}", cpu_count, current_tsc_hz());
+        if let Some(contexts) = try_context_storage() {
+            for (id, context) in contexts.iter() {
+                // never spins, a context locked by another cpu is left unnamed
+                if let Some(context) = context.try_read() {
+                    let _ = writeln!(com, "STRACE ctx {} {}", id.get(), context.name());
+                }
+            }
+        }
+        for (cpu, ring) in RINGS[..cpu_count].iter().enumerate() {
+            let head = ring.head.load(Ordering::Acquire);
+            for index in head.saturating_sub(TRACE_ENTRIES)..head {
+                let entry = &ring.entries[index % TRACE_ENTRIES];
+                let what = entry.what.load(Ordering::Acquire);
+                let Some(event) = TraceEvent::from_u8((what >> EVENT_SHIFT) as u8) else { continue };
+                let tsc = entry.tsc.load(Ordering::Relaxed);
+                let _ = writeln!(com, "STRACE ev {} {} {} {}", cpu, tsc, event.name(), what & CTX_MASK);
+                events += 1;
+            }
+        }
+        let _ = writeln!(com, "STRACE end {}", events);
+    }
+    RECORDING.store(was_recording, Ordering::SeqCst);
+    events
+}
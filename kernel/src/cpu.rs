@@ -4,7 +4,7 @@ use crate::gdt::pcr;
 
 // represents a physical cpu
 #[derive(Clone, Copy, Eq, PartialEq, Hash, PartialOrd, Ord)]
-pub struct LogicalCpuId(pub u8);
+pub struct LogicalCpuId(pub u32);
 
 impl LogicalCpuId {
     pub(crate) const BSP: LogicalCpuId = LogicalCpuId(0);
@@ -24,7 +24,17 @@ impl core::fmt::Display for LogicalCpuId {
 pub struct PercpuBlock {
     pub cpu_id: LogicalCpuId,
     pub context_switch: ContextSwitchPercpu,
-    pub inside_syscall: Cell<bool>
+    pub inside_syscall: Cell<bool>,
+    // nesting level of interrupt handlers running on this cpu
+    pub irq_depth: Cell<usize>,
+    // held spinlocks and explicit preempt_disable sections, no preemption while non-zero
+    pub preempt_count: Cell<usize>,
+    // innermost panic recovery frame of a running irq handler, see `sync::irq_guard`
+    pub irq_guard: Cell<usize>,
+    // thread pointer of the kernel tls block of this cpu, see `arch_spec::tls`
+    pub tls: Cell<usize>,
+    // fs base of the user side while in kernel mode, the live one is `tls`
+    pub user_fsbase: Cell<usize>,
 }
 
 impl PercpuBlock {
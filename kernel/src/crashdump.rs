@@ -0,0 +1,246 @@
+use core::arch::asm;
+use core::fmt::Write;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+use crate::config::MAX_CPUS;
+use crate::cpu::PercpuBlock;
+use crate::device::com::COM1;
+use crate::device::tsc::monotonic_ns;
+use crate::initcall::kernel_arg;
+use crate::logger::ring::LOG_RING;
+use crate::power::park_other_cpus;
+use crate::sync::IrqSpinlock;
+use crate::syscall::InterruptStack;
+use crate::CPU_COUNT;
+
+/**
+ *  kdump-lite, a compact crash record streamed over com1 on kernel panic.
+ *
+ *  there is no disk driver, serial is the only sink. the record is a sequence
+ *  of text frames mixed into the normal serial output:
+ *
+ *      KDUMP <kind, 2 hex> <payload, hex> <crc32 of kind byte and payload, 8 hex>
+ *
+ *  payload fields are little endian:
+ *      01 begin   u32 version, u32 cpu, u32 cpu count, u64 uptime ns, panic message
+ *      02 cpu     u32 cpu, u32 state (0 panicking, 1 parked, 2 no response),
+ *                 u64 x 24 registers in `CPU_REG_NAMES` order
+ *      03 memmap  { u64 start, u64 length, u8 name len, name } per unavailable region
+ *      04 log     chunk of the log ring tail, utf-8
+ *      ff end     u32 frame count, begin and end excluded
+ *
+ *  `build-image crashdump <serial log>` decodes it on host.
+ */
+
+const KDUMP_VERSION: u32 = 1;
+// payload bytes per frame, lines stay short enough for any terminal log
+const FRAME_PAYLOAD_MAX: usize = 512;
+const LOG_TAIL_BYTES: usize = 8 * 1024;
+
+const KIND_BEGIN: u8 = 0x01;
+const KIND_CPU: u8 = 0x02;
+const KIND_MEMMAP: u8 = 0x03;
+const KIND_LOG: u8 = 0x04;
+const KIND_END: u8 = 0xff;
+
+const STATE_PANICKING: u32 = 0;
+const STATE_PARKED: u32 = 1;
+const STATE_NO_RESPONSE: u32 = 2;
+
+pub const CPU_REG_COUNT: usize = 24;
+pub const CPU_REG_NAMES: [&str; CPU_REG_COUNT] = [
+    "rax", "rbx", "rcx", "rdx", "rsi", "rdi", "rbp", "rsp",
+    "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15",
+    "rip", "rflags", "cs", "ss", "cr0", "cr2", "cr3", "cr4",
+];
+
+type CpuRegs = [u64; CPU_REG_COUNT];
+
+const NO_REGS: IrqSpinlock<Option<CpuRegs>> = IrqSpinlock::new(None);
+// filled by halt ipi handler before the cpu parks
+static CPU_REGS: [IrqSpinlock<Option<CpuRegs>>; MAX_CPUS] = [NO_REGS; MAX_CPUS];
+
+static DUMPING: AtomicBool = AtomicBool::new(false);
+
+fn control_regs() -> [u64; 4] {
+    [Cr0::read_raw(), Cr2::read().as_u64(), Cr3::read().0.start_address().as_u64(), Cr4::read_raw()]
+}
+
+/// record registers of current cpu interrupted at `stack`, called before parking
+pub fn save_cpu_regs(stack: &InterruptStack) {
+    let (p, s, i) = (&stack.preserved, &stack.scratch, &stack.iret);
+    let [cr0, cr2, cr3, cr4] = control_regs();
+    let regs = [
+        s.rax, s.rcx, s.rdx, p.rbx, s.rsi, s.rdi, p.rbp, i.rsp,
+        s.r8, s.r9, s.r10, s.r11, p.r12, p.r13, p.r14, p.r15,
+        i.rip, i.rflags, i.cs, i.ss,
+    ].map(|reg| reg as u64);
+    let mut all = [0; CPU_REG_COUNT];
+    all[..20].copy_from_slice(&regs);
+    all[20..].copy_from_slice(&[cr0, cr2, cr3, cr4]);
+
+    let cpu = PercpuBlock::current().cpu_id.0 as usize;
+    if let Some(slot) = CPU_REGS.get(cpu) {
+        *slot.lock() = Some(all);
+    }
+}
+
+/// code segment `cpu` was interrupted in by the halt ipi, None if it did not report
+pub fn parked_cs(cpu: usize) -> Option<u64> {
+    CPU_REGS.get(cpu)?.try_lock().and_then(|regs| *regs).map(|regs| regs[18])
+}
+
+// registers of the panicking cpu at this call, general purpose ones are the caller's
+#[inline(always)]
+fn current_regs() -> CpuRegs {
+    let mut regs = [0u64; CPU_REG_COUNT];
+    unsafe {
+        asm!(
+            "mov [{0} + 0x08], rbx",
+            "mov [{0} + 0x30], rbp",
+            "mov [{0} + 0x38], rsp",
+            "mov [{0} + 0x60], r12",
+            "mov [{0} + 0x68], r13",
+            "mov [{0} + 0x70], r14",
+            "mov [{0} + 0x78], r15",
+            "lea rax, [rip]",
+            "mov [{0} + 0x80], rax",
+            "pushfq",
+            "pop rax",
+            "mov [{0} + 0x88], rax",
+            "mov rax, cs",
+            "mov [{0} + 0x90], rax",
+            "mov rax, ss",
+            "mov [{0} + 0x98], rax",
+            in(reg) regs.as_mut_ptr(),
+            out("rax") _,
+        );
+    }
+    regs[20..].copy_from_slice(&control_regs());
+    regs
+}
+
+/// crc32 (ieee) of `bytes` continuing from `crc`, 0 to start
+pub(crate) fn crc32(crc: u32, bytes: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { crc >> 1 ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+struct FrameWriter<'a, W: Write> {
+    out: &'a mut W,
+    frames: u32,
+}
+
+impl<W: Write> FrameWriter<'_, W> {
+    fn frame(&mut self, kind: u8, payload: &[u8]) {
+        let crc = crc32(crc32(0, &[kind]), payload);
+        let _ = write!(self.out, "KDUMP {:02x} ", kind);
+        payload.iter().for_each(|byte| { let _ = write!(self.out, "{:02x}", byte); });
+        let _ = writeln!(self.out, " {:08x}", crc);
+        if kind != KIND_BEGIN && kind != KIND_END {
+            self.frames += 1;
+        }
+    }
+}
+
+// fixed buffer for one payload, longer content is truncated
+struct Payload {
+    buf: [u8; FRAME_PAYLOAD_MAX],
+    len: usize,
+}
+
+impl Payload {
+    fn new() -> Self {
+        Self { buf: [0; FRAME_PAYLOAD_MAX], len: 0 }
+    }
+
+    fn room(&self) -> usize {
+        FRAME_PAYLOAD_MAX - self.len
+    }
+
+    fn put(&mut self, bytes: &[u8]) {
+        let take = bytes.len().min(self.room());
+        self.buf[self.len..self.len + take].copy_from_slice(&bytes[..take]);
+        self.len += take;
+    }
+
+    fn bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl Write for Payload {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.put(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// park other cpus and stream the crash record, called once by panic handler
+pub fn write_crash_dump(info: &PanicInfo) {
+    if DUMPING.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    park_other_cpus();
+
+    // the panicking code may hold com1, the record is lost then
+    let Some(mut com) = COM1.try_lock() else { return };
+    let mut out = FrameWriter { out: &mut *com, frames: 0 };
+    let cpu = PercpuBlock::current().cpu_id.0;
+    let cpu_count = CPU_COUNT.load(Ordering::SeqCst);
+
+    let mut begin = Payload::new();
+    begin.put(&KDUMP_VERSION.to_le_bytes());
+    begin.put(&cpu.to_le_bytes());
+    begin.put(&cpu_count.to_le_bytes());
+    begin.put(&monotonic_ns().to_le_bytes());
+    let _ = write!(begin, "{}", info);
+    out.frame(KIND_BEGIN, begin.bytes());
+
+    for id in 0..cpu_count.min(MAX_CPUS as u32) {
+        let (state, regs) = if id == cpu {
+            (STATE_PANICKING, current_regs())
+        } else {
+            match CPU_REGS[id as usize].try_lock().and_then(|regs| *regs) {
+                Some(regs) => (STATE_PARKED, regs),
+                None => (STATE_NO_RESPONSE, [0; CPU_REG_COUNT]),
+            }
+        };
+        let mut payload = Payload::new();
+        payload.put(&id.to_le_bytes());
+        payload.put(&state.to_le_bytes());
+        regs.iter().for_each(|reg| payload.put(&reg.to_le_bytes()));
+        out.frame(KIND_CPU, payload.bytes());
+    }
+
+    let arg = kernel_arg();
+    let mut memmap = Payload::new();
+    for region in &arg.unav_phys_mem_regions[..arg.unav_phys_mem_regions_len] {
+        let name = region.kind.name().as_bytes();
+        if memmap.room() < 17 + name.len() {
+            out.frame(KIND_MEMMAP, memmap.bytes());
+            memmap = Payload::new();
+        }
+        memmap.put(&region.start.to_le_bytes());
+        memmap.put(&region.length.to_le_bytes());
+        memmap.put(&[name.len() as u8]);
+        memmap.put(name);
+    }
+    out.frame(KIND_MEMMAP, memmap.bytes());
+
+    let mut tail = [0u8; LOG_TAIL_BYTES];
+    let len = LOG_RING.try_lock().map(|ring| ring.recent(&mut tail)).unwrap_or(0);
+    for chunk in tail[..len].chunks(FRAME_PAYLOAD_MAX) {
+        out.frame(KIND_LOG, chunk);
+    }
+
+    let frames = out.frames;
+    out.frame(KIND_END, &frames.to_le_bytes());
+}
@@ -1,13 +1,215 @@
 use lazy_static::lazy_static;
-use spin::Mutex;
+use spin::Once;
+use crate::sync::IrqSpinlock;
 use uart_16550::SerialPort;
+use crate::arch_spec::port::{request_region, IoPort};
+use crate::device::keyboard::push_key;
+use crate::fs::console::line_ready;
+use crate::initcall;
+use crate::initcall::InitCpuArg;
+
+/**
+ *  com1/com2 uarts.
+ *
+ *  output goes through `uart_16550`, which also enables the rx interrupt in
+ *  `init`. the irq handlers of both lines drain the rx fifo into a per-port
+ *  queue, cr is turned into lf. `read_line` takes whole lines out of it and
+ *  backs the serial console file, which is also told of new input for its
+ *  async reads. bytes from com1 are also fed to the keyboard queue so the
+ *  debug shell takes input over `-serial stdio`.
+ *
+ *  a line registered by a userspace driver is left alone, it owns the fifo.
+ */
+
+const COM1_BASE: u16 = 0x3F8;
+const COM2_BASE: u16 = 0x2F8;
+
+// receive buffer and line status registers
+const REG_DATA: u16 = 0;
+const REG_LINE_STATUS: u16 = 5;
+const LSR_DATA_READY: u8 = 1;
+
+const RX_QUEUE_SIZE: usize = 1024;
+// 16550 fifo depth, a full one is drained per lock
+const RX_BURST: usize = 16;
 
 lazy_static! {
-    pub static ref COM1: Mutex<SerialPort> = unsafe { Mutex::new(SerialPort::new(0x3F8)) };
-    pub static ref COM2: Mutex<SerialPort> = unsafe { Mutex::new(SerialPort::new(0x2F8)) };
+    // also the debug console fallback, usable before com_initcall
+    pub static ref COM1: IrqSpinlock<SerialPort> = unsafe { IrqSpinlock::new(init_port(COM1_BASE)) };
+    pub static ref COM2: IrqSpinlock<SerialPort> = unsafe { IrqSpinlock::new(init_port(COM2_BASE)) };
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Com {
+    Com1,
+    Com2,
+}
+
+impl Com {
+    pub fn port(self) -> &'static IrqSpinlock<SerialPort> {
+        match self {
+            Com::Com1 => &COM1,
+            Com::Com2 => &COM2,
+        }
+    }
+
+    fn rx_ports(self) -> Option<&'static RxPorts> {
+        match self {
+            Com::Com1 => COM1_RX_PORTS.get(),
+            Com::Com2 => COM2_RX_PORTS.get(),
+        }
+    }
+
+    fn rx(self) -> &'static IrqSpinlock<RxQueue> {
+        match self {
+            Com::Com1 => &COM1_RX,
+            Com::Com2 => &COM2_RX,
+        }
+    }
+}
+
+struct RxPorts {
+    data: IoPort<u8>,
+    line_status: IoPort<u8>,
+}
+
+static COM1_RX_PORTS: Once<RxPorts> = Once::new();
+static COM2_RX_PORTS: Once<RxPorts> = Once::new();
+static COM1_RX: IrqSpinlock<RxQueue> = IrqSpinlock::new(RxQueue::new());
+static COM2_RX: IrqSpinlock<RxQueue> = IrqSpinlock::new(RxQueue::new());
+
+struct RxQueue {
+    buf: [u8; RX_QUEUE_SIZE],
+    // monotonic positions, `head - tail <= RX_QUEUE_SIZE`
+    head: usize,
+    tail: usize,
+    // complete lines in the queue
+    lines: usize,
+}
+
+impl RxQueue {
+    const fn new() -> Self {
+        Self { buf: [0; RX_QUEUE_SIZE], head: 0, tail: 0, lines: 0 }
+    }
+
+    fn push(&mut self, byte: u8) {
+        // keep one slot for the newline so a full queue still completes its line
+        let free = RX_QUEUE_SIZE - (self.head - self.tail);
+        if free == 0 || (free == 1 && byte != b'\n') {
+            return;
+        }
+        self.buf[self.head % RX_QUEUE_SIZE] = byte;
+        self.head += 1;
+        if byte == b'\n' {
+            self.lines += 1;
+        }
+    }
+
+    // oldest line into `out` with its newline, truncated to `out.len()`
+    fn pop_line(&mut self, out: &mut [u8]) -> Option<usize> {
+        if self.lines == 0 {
+            return None;
+        }
+        let mut len = 0;
+        while self.tail != self.head {
+            let byte = self.buf[self.tail % RX_QUEUE_SIZE];
+            self.tail += 1;
+            if len < out.len() {
+                out[len] = byte;
+                len += 1;
+            }
+            if byte == b'\n' {
+                self.lines -= 1;
+                break;
+            }
+        }
+        Some(len)
+    }
+}
+
+unsafe fn init_port(base: u16) -> SerialPort {
+    let mut port = SerialPort::new(base);
+    port.init();
+    port
+}
+
+unsafe fn com_initcall(_: &InitCpuArg) {
+    init_com();
+}
+initcall!(device, Bsp, com_initcall);
+
 pub unsafe fn init_com() {
-    COM1.lock().init();
-    COM2.lock().init();
-}
\ No newline at end of file
+    // uart_16550 owns the ports, the claims keep other drivers away and give
+    // the rx path its registers
+    if let Ok(region) = request_region(COM1_BASE, 8, "com1") {
+        lazy_static::initialize(&COM1);
+        COM1_RX_PORTS.call_once(|| RxPorts { data: region.port(REG_DATA), line_status: region.port(REG_LINE_STATUS) });
+    }
+    if let Ok(region) = request_region(COM2_BASE, 8, "com2") {
+        lazy_static::initialize(&COM2);
+        COM2_RX_PORTS.call_once(|| RxPorts { data: region.port(REG_DATA), line_status: region.port(REG_LINE_STATUS) });
+    }
+}
+
+/// called by the irq handler of `com` unless a userspace driver owns the line,
+/// drains the rx fifo and returns how many bytes came in
+pub fn handle_irq(com: Com) -> usize {
+    let Some(ports) = com.rx_ports() else { return 0 };
+    let mut total = 0;
+    loop {
+        let mut burst = [0u8; RX_BURST];
+        let mut len = 0;
+        {
+            // uart_16550 touches the same uart from other cpus
+            let _port = com.port().lock();
+            while len < RX_BURST && unsafe { ports.line_status.read() } & LSR_DATA_READY != 0 {
+                burst[len] = unsafe { ports.data.read() };
+                len += 1;
+            }
+        }
+        if len == 0 {
+            return total;
+        }
+        total += len;
+
+        let mut rx = com.rx().lock();
+        for byte in burst[..len].iter().map(|&byte| if byte == b'\r' { b'\n' } else { byte }) {
+            rx.push(byte);
+        }
+        drop(rx);
+        line_ready(com);
+        // wakes the shell, takes context locks only with try
+        if com == Com::Com1 {
+            burst[..len].iter().filter(|byte| byte.is_ascii()).for_each(|&byte| push_key(byte as char));
+        }
+    }
+}
+
+/// oldest complete line received on `com` into `out`, newline included.
+/// a line longer than `out` is truncated, the rest of it is dropped
+pub fn read_line(com: Com, out: &mut [u8]) -> Option<usize> {
+    com.rx().lock().pop_line(out)
+}
+
+#[test_case]
+pub(crate) fn test_rx_queue_lines() {
+    let mut rx = RxQueue::new();
+    let mut out = [0u8; 8];
+    b"ls\npartial".iter().for_each(|&byte| rx.push(byte));
+    assert_eq!(rx.pop_line(&mut out), Some(3));
+    assert_eq!(&out[..3], b"ls\n");
+    // no newline yet
+    assert_eq!(rx.pop_line(&mut out), None);
+
+    // the rest of a line longer than `out` is dropped
+    b" and more\n".iter().for_each(|&byte| rx.push(byte));
+    assert_eq!(rx.pop_line(&mut out), Some(8));
+    assert_eq!(&out, b"partial ");
+    assert_eq!(rx.head, rx.tail);
+
+    // a full queue keeps room to end its line
+    (0..RX_QUEUE_SIZE).for_each(|_| rx.push(b'x'));
+    assert_eq!(rx.head - rx.tail, RX_QUEUE_SIZE - 1);
+    rx.push(b'\n');
+    assert_eq!(rx.lines, 1);
+}
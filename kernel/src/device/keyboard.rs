@@ -0,0 +1,77 @@
+use spin::Once;
+use crate::context::ContextId;
+use crate::context::list::try_context_storage;
+use crate::sync::IrqSpinlock;
+
+/**
+ *  keyboard input queue.
+ *
+ *  the keyboard interrupt decodes scancodes and pushes characters here, a
+ *  single reader context takes them out. the reader soft blocks while the
+ *  queue is empty and the interrupt unblocks it, the same way `log()` wakes
+ *  the log flusher. keys pressed while the queue is full are dropped.
+ */
+
+const INPUT_QUEUE_SIZE: usize = 256;
+
+static INPUT: IrqSpinlock<InputQueue> = IrqSpinlock::new(InputQueue::new());
+static READER: Once<ContextId> = Once::new();
+
+struct InputQueue {
+    buf: [char; INPUT_QUEUE_SIZE],
+    // monotonic positions, `head - tail <= INPUT_QUEUE_SIZE`
+    head: usize,
+    tail: usize,
+}
+
+impl InputQueue {
+    const fn new() -> Self {
+        Self { buf: ['\0'; INPUT_QUEUE_SIZE], head: 0, tail: 0 }
+    }
+
+    fn push(&mut self, c: char) -> bool {
+        if self.head - self.tail == INPUT_QUEUE_SIZE {
+            return false;
+        }
+        self.buf[self.head % INPUT_QUEUE_SIZE] = c;
+        self.head += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<char> {
+        if self.head == self.tail {
+            return None;
+        }
+        let c = self.buf[self.tail % INPUT_QUEUE_SIZE];
+        self.tail += 1;
+        Some(c)
+    }
+}
+
+/// called by keyboard interrupt
+pub fn push_key(c: char) {
+    if !INPUT.lock().push(c) {
+        return;
+    }
+    // never spins on context locks, we may have interrupted their holder
+    let Some(id) = READER.get() else { return };
+    let Some(contexts) = try_context_storage() else { return };
+    let Some(context) = contexts.get(*id) else { return };
+    if let Some(mut context) = context.try_write() {
+        context.unblock_no_ipi();
+    }
+}
+
+pub fn pop_key() -> Option<char> {
+    INPUT.lock().pop()
+}
+
+pub fn has_key() -> bool {
+    let input = INPUT.lock();
+    input.head != input.tail
+}
+
+/// context woken when a key comes in, only the first one is kept
+pub fn set_input_reader(id: ContextId) {
+    READER.call_once(|| id);
+}
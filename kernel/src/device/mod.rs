@@ -1,2 +1,7 @@
 pub mod qemu;
 pub mod com;
+pub mod tsc;
+pub mod keyboard;
+pub mod pic;
+pub mod pit;
+pub mod user_irq;
@@ -0,0 +1,110 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Once;
+use shared::print_panic::PrintPanic;
+use crate::arch_spec::port::{request_region, IoPort};
+
+/**
+ *  8259 programmable interrupt controllers.
+ *
+ *  with io apics the pics are only masked. firmware without io apics (no madt
+ *  and no mp table) leaves them as the only way legacy irqs come in: they are
+ *  remapped to vectors 32..48 like io apic routed irqs and every line is
+ *  unmasked, the local apic gets them through lint0 in extint mode. handlers
+ *  of legacy irqs ack through [`eoi`] then.
+ */
+
+const PIC1_VECTOR: u8 = 32;
+const PIC2_VECTOR: u8 = 40;
+// slave is wired to master line 2
+const CASCADE_IRQ: u8 = 2;
+
+const ICW1_INIT: u8 = 0x11;
+const ICW4_8086: u8 = 0x01;
+const OCW2_EOI: u8 = 0x20;
+const OCW3_READ_ISR: u8 = 0x0b;
+
+struct Pics {
+    pic1_cmd: IoPort<u8>,
+    pic1_data: IoPort<u8>,
+    pic2_cmd: IoPort<u8>,
+    pic2_data: IoPort<u8>,
+}
+
+static PICS: Once<Pics> = Once::new();
+static ROUTED: AtomicBool = AtomicBool::new(false);
+
+fn pics() -> &'static Pics {
+    PICS.call_once(|| {
+        let pic1 = request_region(0x20, 2, "pic1").or_panic("failed to claim pic1 ports");
+        let pic2 = request_region(0xa0, 2, "pic2").or_panic("failed to claim pic2 ports");
+        Pics { pic1_cmd: pic1.port(0), pic1_data: pic1.port(1), pic2_cmd: pic2.port(0), pic2_data: pic2.port(1) }
+    })
+}
+
+/// mask every line, the pics stay owned by us
+pub unsafe fn mask_all() {
+    let pics = pics();
+    pics.pic1_data.write(0xff);
+    pics.pic2_data.write(0xff);
+}
+
+/// remap the pics above the exception vectors and deliver every legacy irq through them
+pub unsafe fn route_legacy_irqs() {
+    let pics = pics();
+    pics.pic1_cmd.write(ICW1_INIT);
+    pics.pic2_cmd.write(ICW1_INIT);
+    pics.pic1_data.write(PIC1_VECTOR);
+    pics.pic2_data.write(PIC2_VECTOR);
+    pics.pic1_data.write(1 << CASCADE_IRQ);
+    pics.pic2_data.write(CASCADE_IRQ);
+    pics.pic1_data.write(ICW4_8086);
+    pics.pic2_data.write(ICW4_8086);
+    pics.pic1_data.write(0);
+    pics.pic2_data.write(0);
+    ROUTED.store(true, Ordering::SeqCst);
+}
+
+/// whether legacy irqs come through the pics instead of io apics
+pub fn routed() -> bool {
+    ROUTED.load(Ordering::Relaxed)
+}
+
+// a line dropped before the cpu acked it shows up as irq 7 or 15 without its isr bit
+unsafe fn spurious(cmd: &IoPort<u8>, line: u8) -> bool {
+    cmd.write(OCW3_READ_ISR);
+    cmd.read() & 1 << line == 0
+}
+
+/// end of interrupt for legacy `irq`
+pub unsafe fn eoi(irq: u8) {
+    let pics = pics();
+    if irq >= 8 {
+        if irq == 15 && spurious(&pics.pic2_cmd, 7) {
+            // the master did see the cascade line
+            pics.pic1_cmd.write(OCW2_EOI);
+            return;
+        }
+        pics.pic2_cmd.write(OCW2_EOI);
+    } else if irq == 7 && spurious(&pics.pic1_cmd, 7) {
+        return;
+    }
+    pics.pic1_cmd.write(OCW2_EOI);
+}
+
+/// end of interrupt for the highest priority irq in service, if any
+pub unsafe fn eoi_in_service() {
+    let pics = pics();
+    pics.pic1_cmd.write(OCW3_READ_ISR);
+    let master = pics.pic1_cmd.read();
+    // lowest line is the highest priority, the cascade line stands for the slave
+    let Some(line) = (0..8).find(|line| master & 1 << line != 0) else { return };
+    if line != CASCADE_IRQ {
+        pics.pic1_cmd.write(OCW2_EOI);
+        return;
+    }
+    pics.pic2_cmd.write(OCW3_READ_ISR);
+    if pics.pic2_cmd.read() != 0 {
+        pics.pic2_cmd.write(OCW2_EOI);
+    }
+    pics.pic1_cmd.write(OCW2_EOI);
+}
@@ -0,0 +1,128 @@
+use crate::arch_spec::port::{request_region, IoPort, IoRegion};
+use crate::sync::Spinlock;
+use shared::print_panic::PrintPanic;
+
+/**
+ *  8254 programmable interval timer.
+ *
+ *  channel 0 drives irq 0 and is the system tick when the lapic timer is not
+ *  usable, periodic (mode 2, rate generator) or one-shot (mode 0). channel 2
+ *  never interrupts, it is gated through system control port b and its OUT2
+ *  is polled to time the calibration windows of other clocks, see
+ *  [`calibrate`]. the ports are a single global device, claimed on first use.
+ */
+
+pub const PIT_FREQUENCY: u64 = 1_193_182;
+
+// access lobyte/hibyte, binary counting
+const COMMAND_CHANNEL0: u8 = 0b0011_0000;
+const COMMAND_CHANNEL2: u8 = 0b1011_0000;
+const MODE_ONE_SHOT: u8 = 0b000 << 1;
+const MODE_RATE_GENERATOR: u8 = 0b010 << 1;
+// system control port b
+const CONTROL_B_GATE2: u8 = 1 << 0;
+const CONTROL_B_SPEAKER: u8 = 1 << 1;
+const CONTROL_B_OUT2: u8 = 1 << 5;
+
+static PIT: Spinlock<Option<PitPorts>> = Spinlock::new(None);
+
+struct PitPorts {
+    channel0: IoPort<u8>,
+    channel2: IoPort<u8>,
+    command: IoPort<u8>,
+    // channel 2 gate, speaker, OUT2 status
+    control_b: IoPort<u8>,
+    _pit: IoRegion,
+    _control_b: IoRegion,
+}
+
+impl PitPorts {
+    fn claim() -> Self {
+        let pit = request_region(0x40, 4, "pit").or_panic("failed to claim pit ports");
+        let control_b = request_region(0x61, 1, "system control b").or_panic("failed to claim port 0x61");
+        Self {
+            channel0: pit.port(0),
+            channel2: pit.port(2),
+            command: pit.port(3),
+            control_b: control_b.port(0),
+            _pit: pit,
+            _control_b: control_b,
+        }
+    }
+
+    unsafe fn load(&self, channel: &IoPort<u8>, count: u16) {
+        channel.write((count & 0xff) as u8);
+        channel.write((count >> 8) as u8);
+    }
+}
+
+fn with_pit<R>(f: impl FnOnce(&PitPorts) -> R) -> R {
+    let mut pit = PIT.lock();
+    f(pit.get_or_insert_with(PitPorts::claim))
+}
+
+// reload value for `ticks` pit ticks, 0 counts 65536
+fn reload(ticks: u64) -> u16 {
+    match ticks.clamp(1, 0x10000) {
+        0x10000 => 0,
+        ticks => ticks as u16,
+    }
+}
+
+/// irq 0 every 1/`hz` seconds, returns the rate the divisor really gives
+pub fn set_periodic(hz: u32) -> u32 {
+    let count = reload(PIT_FREQUENCY / u64::from(hz.max(1)));
+    with_pit(|pit| unsafe {
+        pit.command.write(COMMAND_CHANNEL0 | MODE_RATE_GENERATOR);
+        pit.load(&pit.channel0, count);
+    });
+    let divisor = if count == 0 { 0x10000 } else { u64::from(count) };
+    (PIT_FREQUENCY / divisor) as u32
+}
+
+/// irq 0 once after `ns` nanoseconds, at most 55ms ahead
+pub fn set_one_shot(ns: u64) {
+    let count = reload(ns.saturating_mul(PIT_FREQUENCY) / 1_000_000_000);
+    with_pit(|pit| unsafe {
+        pit.command.write(COMMAND_CHANNEL0 | MODE_ONE_SHOT);
+        pit.load(&pit.channel0, count);
+    });
+}
+
+/// silence irq 0, a one-shot channel waits for its count and never fires
+pub fn stop() {
+    with_pit(|pit| unsafe { pit.command.write(COMMAND_CHANNEL0 | MODE_ONE_SHOT) });
+}
+
+/// ticks per second of `counter`, an increasing clock read by the closure,
+/// measured against pit channel 2 over the shortest of `rounds` windows of `window_ms`
+pub fn calibrate(window_ms: u64, rounds: usize, mut counter: impl FnMut() -> u64) -> u64 {
+    let count = reload(PIT_FREQUENCY * window_ms / 1000);
+    let best = with_pit(|pit| {
+        (0..rounds)
+            .map(|_| unsafe { measure(pit, count, &mut counter) })
+            .min()
+            .unwrap_or(0)
+    });
+    best * 1000 / window_ms
+}
+
+// `counter` ticks spent while pit channel 2 counts down `count`
+unsafe fn measure(pit: &PitPorts, count: u16, counter: &mut impl FnMut() -> u64) -> u64 {
+    // gate channel 2 on, speaker off
+    pit.control_b.write((pit.control_b.read() & !CONTROL_B_SPEAKER) | CONTROL_B_GATE2);
+    // mode 0 raises OUT2 at terminal count
+    pit.command.write(COMMAND_CHANNEL2 | MODE_ONE_SHOT);
+    pit.load(&pit.channel2, count);
+
+    // restart counting by toggling gate
+    let gate = pit.control_b.read() & !CONTROL_B_GATE2;
+    pit.control_b.write(gate);
+    pit.control_b.write(gate | CONTROL_B_GATE2);
+
+    let start = counter();
+    while pit.control_b.read() & CONTROL_B_OUT2 == 0 {
+        core::hint::spin_loop()
+    }
+    counter().wrapping_sub(start)
+}
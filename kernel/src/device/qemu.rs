@@ -1,13 +1,68 @@
-use lazy_static::lazy_static;
-use spin::Mutex;
-use uart_16550::SerialPort;
+use core::fmt;
+use spin::Once;
+use crate::arch_spec::port::{release_region, request_region, IoPort};
+use crate::device::com::COM1;
 
-lazy_static! {
-    pub static ref STDIO_PORT: Mutex<SerialPort> = unsafe { 
-        let mut port = SerialPort::new(0x3F8);
-        port.init();
-        Mutex::new(port)
-    };
+// isa-debug-exit, `-device isa-debug-exit,iobase=0xf4,iosize=0x04`
+const DEBUG_EXIT_PORT: u16 = 0xf4;
+// debugcon, `-debugcon stdio`. read back returns 0xe9 if present.
+const DEBUG_CON_PORT: u16 = 0x402;
+const DEBUG_CON_READBACK: u8 = 0xe9;
+
+static QEMU_DEVICES: Once<QemuDebugDevices> = Once::new();
+// claimed by detection, only set if the device is present
+static DEBUG_EXIT: Once<IoPort<u32>> = Once::new();
+static DEBUG_CON: Once<IoPort<u8>> = Once::new();
+
+/// qemu debug devices detected at runtime.
+/// always absent if the kernel is built without feature `qemu-debug`.
+#[derive(Debug, Clone, Copy)]
+pub struct QemuDebugDevices {
+    pub debug_exit: bool,
+    pub debug_con: bool,
+}
+
+impl QemuDebugDevices {
+    #[cfg(feature = "qemu-debug")]
+    fn detect() -> Self {
+        // isa-debug-exit can't be probed, trust it if we are running on qemu (tcg or kvm).
+        let on_qemu = unsafe {
+            use core::arch::x86_64::__cpuid;
+
+            let hypervisor_present = __cpuid(1).ecx & (1 << 31) != 0;
+            let leaf = __cpuid(0x4000_0000);
+            let mut vendor = [0_u8; 12];
+            vendor[0..4].copy_from_slice(&leaf.ebx.to_le_bytes());
+            vendor[4..8].copy_from_slice(&leaf.ecx.to_le_bytes());
+            vendor[8..12].copy_from_slice(&leaf.edx.to_le_bytes());
+
+            hypervisor_present && (&vendor == b"TCGTCGTCGTCG" || &vendor == b"KVMKVMKVM\0\0\0")
+        };
+        if on_qemu {
+            if let Ok(region) = request_region(DEBUG_EXIT_PORT, 4, "qemu debug exit") {
+                DEBUG_EXIT.call_once(|| region.port(0));
+            }
+        }
+        if let Ok(region) = request_region(DEBUG_CON_PORT, 1, "qemu debugcon") {
+            let port = region.port::<u8>(0);
+            if unsafe { port.read() } == DEBUG_CON_READBACK {
+                DEBUG_CON.call_once(|| port);
+            } else {
+                release_region(region);
+            }
+        }
+
+        Self { debug_exit: DEBUG_EXIT.get().is_some(), debug_con: DEBUG_CON.get().is_some() }
+    }
+
+    #[cfg(not(feature = "qemu-debug"))]
+    fn detect() -> Self {
+        Self { debug_exit: false, debug_con: false }
+    }
+}
+
+pub fn qemu_devices() -> &'static QemuDebugDevices {
+    QEMU_DEVICES.call_once(QemuDebugDevices::detect)
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -17,31 +72,54 @@ pub enum QemuExitCode {
     Failed = 0x11,
 }
 
+/// exit qemu if isa-debug-exit is available, otherwise halt forever.
 pub fn exit_qemu(exit_code: QemuExitCode) -> ! {
-    use x86_64::instructions::{nop, port::Port};
+    use x86_64::instructions::hlt;
 
-    unsafe {
-        let mut port = Port::new(0xf4);
-        port.write(exit_code as u32);
+    qemu_devices();
+    if let Some(port) = DEBUG_EXIT.get() {
+        unsafe { port.write(exit_code as u32) }
     }
 
     loop {
-        nop();
+        hlt();
+    }
+}
+
+struct DebugConWriter(IoPort<u8>);
+
+impl fmt::Write for DebugConWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let port = self.0;
+        for byte in s.bytes() {
+            unsafe { port.write(byte) }
+        }
+        Ok(())
+    }
+}
+
+// debug console: qemu debugcon if present, falls back to com1.
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    use fmt::Write;
+
+    qemu_devices();
+    match DEBUG_CON.get() {
+        Some(port) => { let _ = DebugConWriter(*port).write_fmt(args); }
+        None => { let _ = COM1.lock().write_fmt(args); }
     }
 }
 
 #[macro_export]
 macro_rules! qemu_print {
     ($fmt: literal $(, $($arg: tt)+)?) => {{
-        use ::core::fmt::Write;
-        $crate::device::qemu::STDIO_PORT.lock().write_fmt(format_args!($fmt $(, $($arg)+)?));
+        $crate::device::qemu::_print(format_args!($fmt $(, $($arg)+)?));
     }};
 }
 
 #[macro_export]
 macro_rules! qemu_println {
     ($fmt: literal $(, $($arg: tt)+)?) => {{
-        use ::core::fmt::Write;
-        $crate::device::qemu::STDIO_PORT.lock().write_fmt(format_args!(concat!($fmt, "\n") $(, $($arg)+)?));
+        $crate::device::qemu::_print(format_args!(concat!($fmt, "\n") $(, $($arg)+)?));
     }};
-}
\ No newline at end of file
+}
@@ -0,0 +1,125 @@
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use crate::arch_spec::cpuid::{cpu_features, cpuid, CpuFeatures};
+use crate::config::MAX_CPUS;
+use crate::cpu::{LogicalCpuId, PercpuBlock};
+use crate::device::pit;
+use crate::{infohart, warnhart};
+use crate::initcall;
+use crate::initcall::InitCpuArg;
+
+/**
+ *  tsc frequency calibration.
+ *
+ *  sources in order of preference:
+ *  1. hypervisor timing leaf 0x4000_0010 (kvm with invtsc, vmware)
+ *  2. cpuid leaf 0x15, crystal clock * tsc / crystal ratio
+ *  3. pit channel 2 one-shot, best of a few 10ms windows
+ *
+ *  with invariant tsc all cpus share the frequency measured on bsp,
+ *  otherwise every cpu calibrates on its own.
+ */
+
+const PIT_CALIBRATE_MS: u64 = 10;
+const PIT_CALIBRATE_ROUNDS: usize = 3;
+
+const ZERO_HZ: AtomicU64 = AtomicU64::new(0);
+static TSC_HZ: [AtomicU64; MAX_CPUS] = [ZERO_HZ; MAX_CPUS];
+static TSC_INVARIANT: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy)]
+enum TscSource {
+    Hypervisor,
+    Cpuid,
+    Pit,
+}
+
+unsafe fn tsc_initcall(arg: &InitCpuArg) {
+    if arg.cpu_id == LogicalCpuId::BSP {
+        let invariant = cpu_features().contains(CpuFeatures::INVARIANT_TSC);
+        TSC_INVARIANT.store(invariant, Ordering::SeqCst);
+        if !invariant {
+            warnhart!("tsc is not invariant, frequency may drift with p-states, calibrating every cpu");
+        }
+    } else if TSC_INVARIANT.load(Ordering::SeqCst) {
+        let hz = TSC_HZ[LogicalCpuId::BSP.0 as usize].load(Ordering::SeqCst);
+        TSC_HZ[arg.cpu_id.0 as usize].store(hz, Ordering::SeqCst);
+        return;
+    }
+
+    calibrate_current();
+}
+initcall!(arch, All, tsc_initcall, order = 40);
+
+/// measure tsc frequency of current cpu again, e.g. after a p-state change without invariant tsc
+pub fn recalibrate_tsc() -> u64 {
+    calibrate_current()
+}
+
+fn calibrate_current() -> u64 {
+    let cpu_id = PercpuBlock::current().cpu_id;
+    let (hz, source) = match hypervisor_tsc_hz() {
+        Some(hz) => (hz, TscSource::Hypervisor),
+        None => match cpuid_tsc_hz() {
+            Some(hz) => (hz, TscSource::Cpuid),
+            None => (pit_tsc_hz(), TscSource::Pit),
+        }
+    };
+
+    TSC_HZ[cpu_id.0 as usize].store(hz, Ordering::SeqCst);
+    infohart!("tsc frequency: {}.{:03} MHz ({:?})", hz / 1_000_000, hz / 1000 % 1000, source);
+    hz
+}
+
+fn hypervisor_tsc_hz() -> Option<u64> {
+    let hypervisor = cpuid().get_feature_info().map_or(false, |f| f.has_hypervisor());
+    if !hypervisor {
+        return None;
+    }
+    let max_leaf = unsafe { __cpuid(0x4000_0000) }.eax;
+    if max_leaf < 0x4000_0010 {
+        return None;
+    }
+    // eax: tsc frequency in khz
+    match unsafe { __cpuid(0x4000_0010) }.eax {
+        0 => None,
+        khz => Some(khz as u64 * 1000),
+    }
+}
+
+fn cpuid_tsc_hz() -> Option<u64> {
+    let info = cpuid().get_tsc_info()?;
+    // crystal frequency is not enumerated on many parts, pit is more accurate than guessing it
+    info.tsc_frequency().filter(|hz| *hz != 0)
+}
+
+/// measure tsc frequency against pit channel 2, regardless of the calibration source
+pub(crate) fn pit_tsc_hz() -> u64 {
+    pit::calibrate(PIT_CALIBRATE_MS, PIT_CALIBRATE_ROUNDS, || unsafe { _rdtsc() })
+}
+
+pub fn tsc_invariant() -> bool {
+    TSC_INVARIANT.load(Ordering::Relaxed)
+}
+
+/// tsc frequency of `cpu_id` in hz, 0 if not calibrated yet
+pub fn tsc_hz(cpu_id: LogicalCpuId) -> u64 {
+    TSC_HZ[cpu_id.0 as usize].load(Ordering::Relaxed)
+}
+
+pub fn current_tsc_hz() -> u64 {
+    tsc_hz(PercpuBlock::current().cpu_id)
+}
+
+/// nanoseconds since reset, 0 before tsc is calibrated
+pub fn monotonic_ns() -> u64 {
+    tsc_to_ns(unsafe { _rdtsc() }).unwrap_or(0)
+}
+
+/// convert tsc ticks of current cpu to nanoseconds
+pub fn tsc_to_ns(ticks: u64) -> Option<u64> {
+    match current_tsc_hz() {
+        0 => None,
+        hz => Some((ticks as u128 * 1_000_000_000 / hz as u128) as u64),
+    }
+}
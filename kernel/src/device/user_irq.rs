@@ -0,0 +1,142 @@
+use core::hint::spin_loop;
+use core::mem;
+use libvdso::error::{EBUSY, EINVAL, ESRCH, KError, KResult};
+use crate::arch::{ArchInterrupts, CurrentArch};
+use crate::context::{context_id, ContextId};
+use crate::context::list::{context_storage, try_context_storage};
+use crate::context::status::Status;
+use crate::context::switch::{switch_context, SwitchResult};
+use crate::sync::IrqSpinlock;
+
+/**
+ *  legacy irqs handed to userspace drivers.
+ *
+ *  a context registers an irq line and waits on it. the kernel handler of
+ *  the line counts the interrupt as pending for the owner, wakes it and acks
+ *  EOI itself, the driver deals with the device once `wait` returns the
+ *  pending count. isa lines are edge triggered so nothing is masked in
+ *  between, interrupts coming in while the driver is busy add up.
+ *
+ *  the pit, keyboard and cascade lines stay with the kernel. com lines go
+ *  back to the kernel serial driver while nobody owns them. a line of an
+ *  exited owner is free for the next registration.
+ */
+
+pub const USER_IRQ_BLOCK_REASON: &str = "irq wait";
+
+const LEGACY_IRQS: usize = 16;
+// pit, keyboard and cascade
+const KERNEL_IRQS: [usize; 3] = [0, 1, 2];
+
+#[derive(Clone, Copy)]
+struct UserIrq {
+    owner: ContextId,
+    pending: usize,
+}
+
+static USER_IRQS: IrqSpinlock<[Option<UserIrq>; LEGACY_IRQS]> = IrqSpinlock::new([None; LEGACY_IRQS]);
+
+fn is_alive(id: ContextId) -> bool {
+    context_storage().get(id)
+        .is_some_and(|context| !matches!(context.read().status, Status::Existed(_)))
+}
+
+/// give `irq` to the calling context, `EBUSY` if a live context owns it
+pub fn register(irq: usize) -> KResult<()> {
+    if irq >= LEGACY_IRQS || KERNEL_IRQS.contains(&irq) {
+        return Err(KError::new(EINVAL));
+    }
+    let me = context_id();
+    let owner = USER_IRQS.lock()[irq].map(|user_irq| user_irq.owner);
+    // checked without the irq lock, context locks are never taken under it
+    if owner.is_some_and(|owner| owner != me && is_alive(owner)) {
+        return Err(KError::new(EBUSY));
+    }
+
+    let mut irqs = USER_IRQS.lock();
+    if irqs[irq].map(|user_irq| user_irq.owner) != owner {
+        return Err(KError::new(EBUSY));
+    }
+    irqs[irq] = Some(UserIrq { owner: me, pending: 0 });
+    Ok(())
+}
+
+/// give `irq` back, `EINVAL` unless the calling context owns it
+pub fn release(irq: usize) -> KResult<()> {
+    let mut irqs = USER_IRQS.lock();
+    match irqs.get(irq).copied().flatten() {
+        Some(user_irq) if user_irq.owner == context_id() => {
+            irqs[irq] = None;
+            Ok(())
+        }
+        _ => Err(KError::new(EINVAL)),
+    }
+}
+
+// pending count of `irq` owned by `owner` is taken
+fn take_pending(irq: usize, owner: ContextId) -> KResult<usize> {
+    match USER_IRQS.lock().get_mut(irq).and_then(Option::as_mut) {
+        Some(user_irq) if user_irq.owner == owner => Ok(mem::take(&mut user_irq.pending)),
+        _ => Err(KError::new(EINVAL)),
+    }
+}
+
+/// block until `irq` fired, returns how many times it did since the last wait.
+/// `EINVAL` unless the calling context owns it.
+pub fn wait(irq: usize) -> KResult<usize> {
+    let me = context_id();
+    let context_lock = context_storage().current().cloned().ok_or(KError::new(ESRCH))?;
+    loop {
+        let pending = take_pending(irq, me)?;
+        if pending != 0 {
+            return Ok(pending);
+        }
+        {
+            let mut context = context_lock.write();
+            // block before checking, an interrupt after the check finds us blocked and wakes us
+            context.soft_block(USER_IRQ_BLOCK_REASON);
+            if USER_IRQS.lock()[irq].is_some_and(|user_irq| user_irq.pending != 0) {
+                context.unblock_no_ipi();
+            }
+        }
+
+        // interrupts are masked on syscall entry
+        loop {
+            match unsafe { switch_context() } {
+                SwitchResult::Switched { .. } => {
+                    if context_lock.read().status.is_runnable() {
+                        break;
+                    }
+                }
+                // nothing else to run on this cpu, let the interrupt in
+                SwitchResult::AllContextsIdle => {
+                    unsafe {
+                        CurrentArch::enable_interrupts_and_nop();
+                        CurrentArch::disable_interrupts();
+                    }
+                    if context_lock.read().status.is_runnable() {
+                        break;
+                    }
+                    spin_loop();
+                }
+            }
+        }
+    }
+}
+
+/// called by the handler of legacy `irq` before EOI, returns whether a userspace driver owns it
+pub fn deliver(irq: usize) -> bool {
+    let owner = {
+        let mut irqs = USER_IRQS.lock();
+        let Some(user_irq) = irqs[irq].as_mut() else { return false };
+        user_irq.pending += 1;
+        user_irq.owner
+    };
+    // never spins on context locks, we may have interrupted their holder
+    let Some(contexts) = try_context_storage() else { return true };
+    let Some(context) = contexts.get(owner) else { return true };
+    if let Some(mut context) = context.try_write() {
+        context.unblock_no_ipi();
+    }
+    true
+}
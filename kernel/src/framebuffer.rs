@@ -1,25 +1,88 @@
-use core::mem::MaybeUninit;
-
 use lazy_static::lazy_static;
-use shared::{arg::KernelArg, framebuffer::{FBPixelFormat, Framebuffer}, uni_processor::UPSafeCell};
+use shared::{arg::KernelArg, boot_progress::{self, BootStage}, framebuffer::{FBPixelFormat, Framebuffer, PixelMask}, uni_processor::UPSafeCell};
 use spin::mutex::Mutex;
 
+/**
+ *  kernel framebuffer, set up from the one the bootloader got from gop.
+ *
+ *  firmware without gop (serial consoles, some servers) leaves it out of
+ *  `KernelArg` and the kernel runs headless: the console goes to com1, boot
+ *  progress is logged and userspace asking for the framebuffer gets
+ *  `ENODEV`. so does a framebuffer in a pixel format nothing here draws,
+ *  rgb, bgr and gop bitmasks of 1 to 4 bytes a pixel are.
+ */
 
 lazy_static! {
-    pub static ref FRAMEBUFFER: UPSafeCell<Mutex<MaybeUninit<Framebuffer>>> = unsafe { UPSafeCell::new(Mutex::new(MaybeUninit::uninit())) };
+    // `None` when headless
+    pub static ref FRAMEBUFFER: UPSafeCell<Mutex<Option<Framebuffer>>> = unsafe { UPSafeCell::new(Mutex::new(None)) };
 }
 
 pub fn init_framebuffer(kernel_arg: &KernelArg) {
-    // initialize framebuffer
     let framebuffer_mutex = FRAMEBUFFER.inner_exclusive_mut();
     let mut framebuffer = framebuffer_mutex.lock();
-    
-    framebuffer.write(Framebuffer::new(
-        kernel_arg.framebuffer_addr as *mut u8, 
-        kernel_arg.framebuffer_len, 
-        kernel_arg.framebuffer_width, 
-        kernel_arg.framebuffer_height, 
-        kernel_arg.framebuffer_stride, 
-        FBPixelFormat::RGB
-    ));
-}
\ No newline at end of file
+
+    // bootloader leaves everything 0 if gop was not found
+    if kernel_arg.framebuffer_addr == 0 || kernel_arg.framebuffer_len == 0
+        || kernel_arg.framebuffer_width == 0 || kernel_arg.framebuffer_height == 0 {
+        *framebuffer = None;
+        return;
+    }
+    let fb = Framebuffer::new(
+        kernel_arg.framebuffer_addr as *mut u8,
+        kernel_arg.framebuffer_len,
+        kernel_arg.framebuffer_width,
+        kernel_arg.framebuffer_height,
+        kernel_arg.framebuffer_stride,
+        FBPixelFormat::from_bits_retain(kernel_arg.framebuffer_pixel_format),
+        kernel_arg.framebuffer_pixel_mask
+    );
+    // a format this kernel can not draw, or a pixel size that disagrees with the masks
+    if !fb.drawable() || fb.bytes_per_pixel != kernel_arg.framebuffer_bytes_per_pixel {
+        *framebuffer = None;
+        return;
+    }
+    *framebuffer = Some(fb);
+}
+
+/// the framebuffer, `None` when headless
+pub fn framebuffer() -> Option<&'static Framebuffer> {
+    let framebuffer_mutex = FRAMEBUFFER.inner_exclusive_mut();
+    let framebuffer = framebuffer_mutex.lock();
+    // only written once in `init_framebuffer` before anyone borrows it, the static lives forever
+    framebuffer.as_ref().map(|framebuffer| unsafe { &*(framebuffer as *const Framebuffer) })
+}
+
+pub fn headless() -> bool {
+    framebuffer().is_none()
+}
+
+// render boot progress to kernel framebuffer, or log it if there is no framebuffer.
+pub fn report_boot_stage(stage: BootStage) {
+    boot_progress::report_boot_stage(framebuffer(), stage);
+}
+
+#[test_case]
+pub(crate) fn test_framebuffer_pixel_formats() {
+    // 2x2 pixels of at most 4 bytes, nothing is drawn
+    let mut pixels = [0u8; 4 * 4];
+    let ptr = pixels.as_mut_ptr();
+    let fb = |format, mask| Framebuffer::new(ptr, 16, 2, 2, 2, format, mask);
+
+    let bgr = fb(FBPixelFormat::BGR, PixelMask::default());
+    assert_eq!((bgr.bytes_per_pixel, bgr.encode(1, 2, 3)), (4, [3, 2, 1, 0]));
+    assert_eq!(bgr.pixel_offset(1, 1), 12);
+
+    // 16 bit 5:6:5, full red and half blue
+    let rgb565 = fb(FBPixelFormat::BITMASK, PixelMask { red: 0xf800, green: 0x07e0, blue: 0x001f, reserved: 0 });
+    assert!(rgb565.drawable());
+    assert_eq!(rgb565.bytes_per_pixel, 2);
+    assert_eq!(rgb565.encode(0xff, 0, 0x80), (0xf800u32 | 0x0f).to_le_bytes());
+
+    // 24 bit, no reserved byte
+    let packed = fb(FBPixelFormat::BITMASK, PixelMask { red: 0xff, green: 0xff00, blue: 0xff_0000, reserved: 0 });
+    assert_eq!(packed.bytes_per_pixel, 3);
+    assert!(packed.drawable());
+    // no channel bits, or rows that overrun the buffer
+    assert!(!fb(FBPixelFormat::BITMASK, PixelMask::default()).drawable());
+    assert!(!Framebuffer::new(ptr, 8, 2, 2, 2, FBPixelFormat::RGB, PixelMask::default()).drawable());
+}
@@ -0,0 +1,314 @@
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::hint::spin_loop;
+use core::mem::{offset_of, size_of};
+use libvdso::aio::{
+    AioCompletion, AioRing, AioSubmission, AIO_FD_CONSOLE, AIO_OP_NOP, AIO_OP_READ, AIO_OP_WRITE, AIO_RING_ENTRIES,
+};
+use libvdso::error::{EBADF, EBUSY, EINVAL, ESRCH, KError, KResult};
+use crate::arch::{ArchInterrupts, CurrentArch};
+use crate::context::{context_id, ContextId};
+use crate::context::list::{context_storage, try_context_storage};
+use crate::context::switch::{switch_context, SwitchResult};
+use crate::device::com::Com;
+use crate::fs::console::{cancel_parked, SerialConsole};
+use crate::fs::{file_table, File};
+use crate::mem::user_addr_space::RwLockUserAddrSpace;
+use crate::mem::user_buffer::UserBuffer;
+use crate::mem::user_ptr::{UserPtr, UserSlice};
+use crate::sync::IrqSpinlock;
+
+/**
+ *  asynchronous file io over rings shared with userspace.
+ *
+ *  a context maps one [`AioRing`] and queues submissions in it, `aio_enter`
+ *  hands them to the files through [`File::aread`] and [`File::awrite`] and
+ *  waits for completions. a file finishes a request through its [`AioDone`],
+ *  right away in the submitter or later from wherever the data shows up,
+ *  an irq handler included. finishing only queues the outcome and wakes the
+ *  submitter, completions and data of deferred reads are copied into user
+ *  memory by the submitter itself on its next `aio_enter`, so nothing ever
+ *  touches an address space from a foreign context.
+ *
+ *  requests in flight, outcomes not posted yet and completions not consumed
+ *  yet never exceed the ring size: the completion queue can not overflow and
+ *  finishing a request never allocates.
+ */
+
+pub const AIO_BLOCK_REASON: &str = "aio wait";
+
+enum Outcome {
+    /// the file moved the data itself
+    InPlace(KResult<usize>),
+    /// data of a deferred read, copied out when posted
+    Data(KResult<Vec<u8>>),
+}
+
+struct Finished {
+    user_data: u64,
+    buf: usize,
+    len: usize,
+    outcome: Outcome,
+}
+
+struct AioState {
+    /// user address of the ring
+    ring: usize,
+    in_flight: usize,
+    finished: VecDeque<Finished>,
+    buffer: Arc<UserBuffer>,
+    /// address space the ring is mapped in
+    addrsp: Weak<RwLockUserAddrSpace>,
+}
+
+impl AioState {
+    /// unmap the ring, nothing to do once its address space is gone.
+    /// never called with AIO held, `free` takes the locks of the address space
+    fn free(self) {
+        if let Some(addrsp) = self.addrsp.upgrade() {
+            let _ = addrsp.free(&self.buffer);
+        }
+    }
+}
+
+static AIO: IrqSpinlock<BTreeMap<ContextId, AioState>> = IrqSpinlock::new(BTreeMap::new());
+
+/// finishes one request, a file keeps it until the request is done
+pub struct AioDone {
+    owner: ContextId,
+    user_data: u64,
+    buf: usize,
+    len: usize,
+}
+
+impl AioDone {
+    /// context that submitted the request
+    pub fn owner(&self) -> ContextId {
+        self.owner
+    }
+
+    /// finish with the bytes the file moved itself
+    pub fn complete(self, result: KResult<usize>) {
+        self.finish(Outcome::InPlace(result));
+    }
+
+    /// finish a read with its data, cut to the length of the request
+    pub fn complete_with(self, result: KResult<Vec<u8>>) {
+        self.finish(Outcome::Data(result));
+    }
+
+    fn finish(self, outcome: Outcome) {
+        {
+            let mut states = AIO.lock();
+            // the owner exited meanwhile
+            let Some(state) = states.get_mut(&self.owner) else { return };
+            state.in_flight -= 1;
+            state.finished.push_back(Finished { user_data: self.user_data, buf: self.buf, len: self.len, outcome });
+        }
+        // may run in an irq, never spins on context locks
+        let Some(contexts) = try_context_storage() else { return };
+        if let Some(mut context) = contexts.get(self.owner).and_then(|context| context.try_write()) {
+            context.unblock_no_ipi();
+        }
+    }
+}
+
+fn open(fd: u32) -> KResult<Arc<dyn File>> {
+    match fd {
+        AIO_FD_CONSOLE => Ok(SerialConsole::open(Com::Com2)),
+        fd => file_table::get(fd as usize),
+    }
+}
+
+/// map a ring into the calling context, returns its user address
+pub fn setup() -> KResult<usize> {
+    let me = context_id();
+    if AIO.lock().contains_key(&me) {
+        return Err(KError::new(EBUSY));
+    }
+
+    let addrsp = context_storage().current().ok_or(KError::new(ESRCH))?.read().addrsp.clone().ok_or(KError::new(EINVAL))?;
+    let buffer = addrsp.alloc_and_copy_from(&vec![0; size_of::<AioRing>()])?;
+    let ring = buffer.ptr() as usize;
+    let state = AioState {
+        ring,
+        in_flight: 0,
+        finished: VecDeque::with_capacity(AIO_RING_ENTRIES),
+        buffer,
+        addrsp: Arc::downgrade(&addrsp),
+    };
+    AIO.lock().insert(me, state);
+    Ok(ring)
+}
+
+fn ring_index(ring: usize, offset: usize) -> KResult<UserPtr<u32>> {
+    UserPtr::<u32>::rw(ring + offset)
+}
+
+// completions in the ring the caller has not consumed yet
+fn unconsumed(ring: usize) -> KResult<usize> {
+    let head = ring_index(ring, offset_of!(AioRing, cq_head))?.read()?;
+    let tail = ring_index(ring, offset_of!(AioRing, cq_tail))?.read()?;
+    Ok(tail.wrapping_sub(head) as usize)
+}
+
+fn start(submission: AioSubmission, done: AioDone) {
+    let buf = UserBuffer::new(submission.buf as u64, submission.len);
+    match (submission.op, open(submission.fd)) {
+        (AIO_OP_NOP, _) => done.complete(Ok(0)),
+        (AIO_OP_READ | AIO_OP_WRITE, Err(err)) => done.complete(Err(err)),
+        (AIO_OP_READ, Ok(file)) if file.readable() => file.aread(buf, done),
+        (AIO_OP_WRITE, Ok(file)) if file.writable() => file.awrite(buf, done),
+        (AIO_OP_READ | AIO_OP_WRITE, Ok(_)) => done.complete(Err(KError::new(EBADF))),
+        _ => done.complete(Err(KError::new(EINVAL))),
+    }
+}
+
+/// unmap the ring of exiting `id` and drop its requests parked in files,
+/// requests finishing later find no ring and are dropped
+pub fn exit(id: ContextId) {
+    cancel_parked(id);
+    let state = AIO.lock().remove(&id);
+    if let Some(state) = state {
+        state.free();
+    }
+}
+
+// move finished requests of `me` into the completion queue while it has room
+fn post(me: ContextId, ring: usize) -> KResult<()> {
+    while unconsumed(ring)? < AIO_RING_ENTRIES {
+        let Some(finished) = AIO.lock().get_mut(&me).and_then(|state| state.finished.pop_front()) else { break };
+        let result = match finished.outcome {
+            Outcome::InPlace(result) => result,
+            Outcome::Data(data) => data.and_then(|data| UserSlice::rw(finished.buf, finished.len)?.copy_from_kernel(&data)),
+        };
+
+        let tail = ring_index(ring, offset_of!(AioRing, cq_tail))?;
+        let slot = tail.read()?;
+        let completion = ring + offset_of!(AioRing, cq) + slot as usize % AIO_RING_ENTRIES * size_of::<AioCompletion>();
+        UserPtr::<AioCompletion>::rw(completion)?.write(AioCompletion { user_data: finished.user_data, result: KError::mux(result) })?;
+        tail.write(slot.wrapping_add(1))?;
+    }
+    Ok(())
+}
+
+// take submissions while the ring has a completion slot for each, returns how many
+fn submit(me: ContextId, ring: usize) -> KResult<usize> {
+    let head_ptr = ring_index(ring, offset_of!(AioRing, sq_head))?;
+    let tail_ptr = ring_index(ring, offset_of!(AioRing, sq_tail))?;
+    let mut taken = 0;
+    loop {
+        let head = head_ptr.read()?;
+        if head == tail_ptr.read()? {
+            return Ok(taken);
+        }
+        let used = AIO.lock().get(&me).map_or(0, |state| state.in_flight + state.finished.len());
+        if used + unconsumed(ring)? >= AIO_RING_ENTRIES {
+            return Ok(taken);
+        }
+
+        let slot = ring + offset_of!(AioRing, sq) + head as usize % AIO_RING_ENTRIES * size_of::<AioSubmission>();
+        let submission = UserPtr::<AioSubmission>::ro(slot)?.read()?;
+        head_ptr.write(head.wrapping_add(1))?;
+
+        AIO.lock().get_mut(&me).ok_or(KError::new(EINVAL))?.in_flight += 1;
+        start(submission, AioDone { owner: me, user_data: submission.user_data, buf: submission.buf, len: submission.len });
+        taken += 1;
+    }
+}
+
+// sleep until a request of `me` finishes
+fn wait_finished(me: ContextId) -> KResult<()> {
+    let context_lock = context_storage().current().cloned().ok_or(KError::new(ESRCH))?;
+    {
+        let mut context = context_lock.write();
+        // block before checking, a request finishing after the check finds us blocked and wakes us
+        context.soft_block(AIO_BLOCK_REASON);
+        if AIO.lock().get(&me).is_some_and(|state| !state.finished.is_empty()) {
+            context.unblock_no_ipi();
+        }
+    }
+
+    // interrupts are masked on syscall entry
+    loop {
+        match unsafe { switch_context() } {
+            SwitchResult::Switched { .. } => {
+                if context_lock.read().status.is_runnable() {
+                    return Ok(());
+                }
+            }
+            // nothing else to run on this cpu, let the interrupt in
+            SwitchResult::AllContextsIdle => {
+                unsafe {
+                    CurrentArch::enable_interrupts_and_nop();
+                    CurrentArch::disable_interrupts();
+                }
+                if context_lock.read().status.is_runnable() {
+                    return Ok(());
+                }
+                spin_loop();
+            }
+        }
+    }
+}
+
+/// take the queued submissions of the calling context and wait for
+/// `min_complete` completions in its ring, or until nothing is in flight
+pub fn enter(min_complete: usize) -> KResult<usize> {
+    if min_complete > AIO_RING_ENTRIES {
+        return Err(KError::new(EINVAL));
+    }
+    let me = context_id();
+    let ring = AIO.lock().get(&me).map(|state| state.ring).ok_or(KError::new(EINVAL))?;
+
+    // completions posted first make room for submissions
+    post(me, ring)?;
+    let taken = submit(me, ring)?;
+    post(me, ring)?;
+    while unconsumed(ring)? < min_complete {
+        let in_flight = AIO.lock().get(&me).map_or(0, |state| state.in_flight);
+        if in_flight == 0 {
+            break;
+        }
+        wait_finished(me)?;
+        post(me, ring)?;
+    }
+    Ok(taken)
+}
+
+#[test_case]
+pub(crate) fn test_aio_done_queues() {
+    // an owner id no context has, with a ring nobody posts to
+    let owner = ContextId::new(usize::MAX);
+    let state = AioState { ring: 0, in_flight: 2, finished: VecDeque::new(), buffer: Arc::new(UserBuffer::new(0, 0)), addrsp: Weak::new() };
+    AIO.lock().insert(owner, state);
+
+    start(AioSubmission { op: AIO_OP_NOP, user_data: 7, ..Default::default() }, AioDone { owner, user_data: 7, buf: 0, len: 0 });
+    start(AioSubmission { op: AIO_OP_READ, fd: 9, user_data: 8, ..Default::default() }, AioDone { owner, user_data: 8, buf: 0, len: 0 });
+    let state = AIO.lock().remove(&owner).unwrap();
+    assert_eq!(state.in_flight, 0);
+    let outcomes: Vec<(u64, usize)> = state.finished.into_iter().map(|finished| match finished.outcome {
+        Outcome::InPlace(result) => (finished.user_data, KError::mux(result)),
+        Outcome::Data(_) => panic!("no data without a file"),
+    }).collect();
+    assert_eq!(outcomes, [(7, 0), (8, -EBADF as usize)]);
+
+    // finishing for an owner without a ring is dropped
+    AioDone { owner, user_data: 9, buf: 0, len: 0 }.complete(Ok(1));
+    assert!(!AIO.lock().contains_key(&owner));
+}
+
+#[test_case]
+pub(crate) fn test_aio_exit_drops_ring() {
+    let owner = ContextId::new(usize::MAX - 1);
+    let state = AioState { ring: 0, in_flight: 1, finished: VecDeque::new(), buffer: Arc::new(UserBuffer::new(0, 0)), addrsp: Weak::new() };
+    AIO.lock().insert(owner, state);
+
+    exit(owner);
+    assert!(!AIO.lock().contains_key(&owner));
+    // the request still in flight finishes into nothing
+    AioDone { owner, user_data: 1, buf: 0, len: 0 }.complete(Ok(0));
+    assert!(!AIO.lock().contains_key(&owner));
+}
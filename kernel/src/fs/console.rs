@@ -0,0 +1,106 @@
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use libvdso::error::{EAGAIN, KError, KResult};
+use crate::context::ContextId;
+use crate::device::com::{read_line, Com};
+use crate::fs::aio::AioDone;
+use crate::fs::File;
+use crate::mem::user_buffer::UserBuffer;
+use crate::mem::user_ptr::UserSlice;
+use crate::sync::IrqSpinlock;
+
+/**
+ *  serial console, a line-buffered file over a com port.
+ *
+ *  a read takes the oldest complete line received on the port, newline
+ *  included, and fails with `EAGAIN` while none is complete. a line longer
+ *  than the buffer is truncated. writes go straight out of the uart.
+ *
+ *  an async read waits for its line instead, parked until the irq handler
+ *  of the port reports one complete.
+ */
+
+// longest line a read hands out, the rx queue holds at most this much anyway
+const CONSOLE_LINE_MAX: usize = 1024;
+
+// async read waiting for a line, `line` is its buffer so the irq allocates nothing
+struct ParkedRead {
+    com: Com,
+    line: Vec<u8>,
+    done: AioDone,
+}
+
+static PARKED: IrqSpinlock<VecDeque<ParkedRead>> = IrqSpinlock::new(VecDeque::new());
+
+/// hand complete lines of `com` to parked async reads in arrival order, called by its irq handler
+pub fn line_ready(com: Com) {
+    let mut parked = PARKED.lock();
+    while let Some(index) = parked.iter().position(|read| read.com == com) {
+        let Some(len) = read_line(com, &mut parked[index].line) else { return };
+        let Some(mut read) = parked.remove(index) else { return };
+        read.line.truncate(len);
+        read.done.complete_with(Ok(read.line));
+    }
+}
+
+/// drop the async reads `owner` parked, it exits
+pub fn cancel_parked(owner: ContextId) {
+    let mut cancelled = Vec::new();
+    let mut parked = PARKED.lock();
+    while let Some(index) = parked.iter().position(|read| read.done.owner() == owner) {
+        cancelled.extend(parked.remove(index));
+    }
+    drop(parked);
+    // line buffers are freed outside the irq lock
+    drop(cancelled);
+}
+
+pub struct SerialConsole {
+    com: Com,
+}
+
+impl SerialConsole {
+    pub fn open(com: Com) -> Arc<dyn File> {
+        Arc::new(SerialConsole { com })
+    }
+}
+
+impl File for SerialConsole {
+    fn readable(&self) -> bool {
+        true
+    }
+
+    fn writable(&self) -> bool {
+        true
+    }
+
+    fn read(&self, buf: UserBuffer) -> KResult<usize> {
+        let target = UserSlice::rw(buf.ptr() as usize, buf.len())?;
+        let mut line = vec![0; buf.len().min(CONSOLE_LINE_MAX)];
+        let len = read_line(self.com, &mut line).ok_or(KError::new(EAGAIN))?;
+        target.copy_from_kernel(&line[..len])
+    }
+
+    fn aread(&self, buf: UserBuffer, done: AioDone) {
+        let mut line = vec![0; buf.len().min(CONSOLE_LINE_MAX)];
+        // checked under PARKED, a line completing meanwhile finds the read parked
+        let mut parked = PARKED.lock();
+        match read_line(self.com, &mut line) {
+            Some(len) => {
+                drop(parked);
+                line.truncate(len);
+                done.complete_with(Ok(line));
+            }
+            None => parked.push_back(ParkedRead { com: self.com, line, done }),
+        }
+    }
+
+    fn write(&self, buf: UserBuffer) -> KResult<usize> {
+        let bytes = UserSlice::ro(buf.ptr() as usize, buf.len())?.read_to_vec()?;
+        let mut port = self.com.port().lock();
+        bytes.iter().for_each(|&byte| port.send(byte));
+        Ok(bytes.len())
+    }
+}
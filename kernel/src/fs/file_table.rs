@@ -0,0 +1,98 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use libvdso::error::{EBADF, EMFILE, ESRCH, KError, KResult};
+use libvdso::rlimit::RLIMIT_NOFILE;
+use crate::context::ext::ContextExt;
+use crate::context::list::context_storage;
+use crate::fs::{self, File};
+
+/**
+ *  open files of a context.
+ *
+ *  descriptors below [`FIRST_FD`] are not in the table, `write` sends 1 and 2
+ *  to the debug console, so the first file opened gets 3. a closed slot is
+ *  reused by the next open. RLIMIT_NOFILE caps the files open at once, a
+ *  spawned context starts without any.
+ */
+
+pub const FIRST_FD: usize = 3;
+
+#[derive(Default)]
+pub struct FileTable {
+    files: Vec<Option<Arc<dyn File>>>,
+}
+
+impl ContextExt for FileTable {}
+
+impl FileTable {
+    fn insert(&mut self, file: Arc<dyn File>, limit: usize) -> KResult<usize> {
+        if self.files.iter().flatten().count() >= limit {
+            return Err(KError::new(EMFILE));
+        }
+        let index = match self.files.iter().position(Option::is_none) {
+            Some(index) => index,
+            None => {
+                self.files.push(None);
+                self.files.len() - 1
+            }
+        };
+        self.files[index] = Some(file);
+        Ok(index + FIRST_FD)
+    }
+
+    fn get(&self, fd: usize) -> KResult<Arc<dyn File>> {
+        let index = fd.checked_sub(FIRST_FD).ok_or(KError::new(EBADF))?;
+        self.files.get(index).cloned().flatten().ok_or(KError::new(EBADF))
+    }
+
+    fn remove(&mut self, fd: usize) -> KResult<Arc<dyn File>> {
+        let index = fd.checked_sub(FIRST_FD).ok_or(KError::new(EBADF))?;
+        self.files.get_mut(index).and_then(Option::take).ok_or(KError::new(EBADF))
+    }
+}
+
+/// open `path` in the calling context, returns its descriptor
+pub fn open(path: &str) -> KResult<usize> {
+    // resolved before taking the context lock, procfs reads the context list
+    let file = fs::open(path)?;
+    let contexts = context_storage();
+    let mut context = contexts.current().ok_or(KError::new(ESRCH))?.write();
+    let limit = context.rlimits.cur(RLIMIT_NOFILE);
+    context.ext.get_or_insert_with(FileTable::default).insert(file, limit)
+}
+
+/// the file behind `fd` of the calling context
+pub fn get(fd: usize) -> KResult<Arc<dyn File>> {
+    let contexts = context_storage();
+    let context = contexts.current().ok_or(KError::new(ESRCH))?.read();
+    context.ext.get::<FileTable>().ok_or(KError::new(EBADF))?.get(fd)
+}
+
+/// close `fd` of the calling context, the file goes away with its last user
+pub fn close(fd: usize) -> KResult<()> {
+    let file = {
+        let contexts = context_storage();
+        let mut context = contexts.current().ok_or(KError::new(ESRCH))?.write();
+        context.ext.get_mut::<FileTable>().ok_or(KError::new(EBADF))?.remove(fd)?
+    };
+    drop(file);
+    Ok(())
+}
+
+#[test_case]
+pub(crate) fn test_file_table() {
+    use crate::fs::procfs::ProcFs;
+
+    let mut table = FileTable::default();
+    let file = || ProcFs::open("/version").unwrap();
+    assert_eq!(table.insert(file(), 2), Ok(FIRST_FD));
+    assert_eq!(table.insert(file(), 2), Ok(FIRST_FD + 1));
+    assert_eq!(table.insert(file(), 2), Err(KError::new(EMFILE)));
+
+    // a closed slot is reused, stdio and unknown descriptors are not in the table
+    assert!(table.remove(FIRST_FD).is_ok());
+    assert!(table.get(FIRST_FD).is_err());
+    assert_eq!(table.insert(file(), 2), Ok(FIRST_FD));
+    assert!(table.get(1).is_err());
+    assert!(table.remove(FIRST_FD + 2).is_err());
+}
@@ -1,11 +1,37 @@
-use libvdso::error::KResult;
+use alloc::sync::Arc;
+use libvdso::error::{ENOENT, KError, KResult};
+use crate::fs::aio::AioDone;
+use crate::fs::procfs::ProcFs;
 use crate::mem::user_buffer::UserBuffer;
 
+pub mod aio;
+pub mod console;
+pub mod file_table;
+pub mod procfs;
+
 pub trait File: Send + Sync {
     fn readable(&self) -> bool;
     fn writable(&self) -> bool;
-    fn read(&self, buf: UserBuffer) -> KResult<()>;
-    fn write(&self, buf: UserBuffer) -> Result<usize, isize>;
-    //fn awrite(&self, buf: UserBuffer, pid: usize, key: usize) -> Pin<Box<dyn Future<Output = ()> + 'static + Send + Sync>>;
-    //fn aread(&self, buf: UserBuffer, cid: usize, pid: usize, key: usize) -> Pin<Box<dyn Future<Output = ()> + 'static + Send + Sync>>;
-}
\ No newline at end of file
+    /// bytes read into `buf`, 0 at the end of the file
+    fn read(&self, buf: UserBuffer) -> KResult<usize>;
+    fn write(&self, buf: UserBuffer) -> KResult<usize>;
+
+    /// start a read into `buf` that finishes through `done`, maybe later and from an irq.
+    /// the default reads in place
+    fn aread(&self, buf: UserBuffer, done: AioDone) {
+        done.complete(self.read(buf));
+    }
+
+    /// start a write of `buf` that finishes through `done`, the default writes in place
+    fn awrite(&self, buf: UserBuffer, done: AioDone) {
+        done.complete(self.write(buf));
+    }
+}
+
+/// the file at absolute `path`, only /proc is mounted
+pub fn open(path: &str) -> KResult<Arc<dyn File>> {
+    match path.strip_prefix("/proc") {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => ProcFs::open(rest),
+        _ => Err(KError::new(ENOENT)),
+    }
+}
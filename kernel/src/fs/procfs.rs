@@ -0,0 +1,394 @@
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::sync::atomic::Ordering;
+use x86_64::structures::paging::PageTableFlags;
+use libvdso::error::{EBADF, EINVAL, EIO, ENOENT, KError, KResult};
+use libvdso::rlimit::{RLIMIT_AS, RLIMIT_CHILDREN, RLIMIT_CPU, RLIMIT_NOFILE, RLIM_INFINITY};
+use crate::acpi::io_apic::routing_table;
+use crate::acpi::local_apic::{lapic_timer_hz, tick_hz};
+use crate::arch::{ArchTimer, CurrentArch};
+use crate::context::ContextId;
+use crate::context::coredump::last_core_dump;
+use crate::context::list::context_storage;
+use crate::cpu::LogicalCpuId;
+use crate::device::tsc::{current_tsc_hz, tsc_invariant, tsc_to_ns};
+use crate::fs::File;
+use crate::idle::{idle_method, idle_stats, IdleMethod};
+use crate::interrupt::irq_count;
+use crate::mem::frame_allocator::{used_frame_count, PHYS_MEM_SIZE};
+use crate::mem::kstack::kstack_pool_stats;
+use crate::mem::ksm::ksm_stats;
+use crate::mem::kvm::kvm_stats;
+use crate::mem::vmalloc::vmalloc_stats;
+use crate::mem::PAGE_SIZE;
+use crate::mem::stack::{boot_stack_high_water_mark, context_stack_peak, stack_config, stack_high_water_mark};
+use crate::mem::user_buffer::UserBuffer;
+use crate::mem::user_ptr::UserSlice;
+use crate::sync::Spinlock;
+use crate::CPU_COUNT;
+
+/**
+ *  procfs-like introspection of kernel state.
+ *
+ *  mounted at /proc, userspace opens it through the file table. layout:
+ *      /meminfo /interrupts /ioapic /uptime /version /stacks /cpuidle /ps /core
+ *      /<context id>/status /<context id>/maps
+ *
+ *  content is generated when a read starts at offset 0,
+ *  subsequent reads continue from the snapshot, the read after its last byte
+ *  returns 0 and rewinds. /core is the latest elf core dump of a faulted
+ *  context, empty if none. directories read as their entry names, one a line.
+ */
+pub struct ProcFs;
+
+#[derive(Clone, Copy)]
+enum ProcEntry {
+    MemInfo,
+    Interrupts,
+    IoApic,
+    Uptime,
+    Version,
+    Stacks,
+    CpuIdle,
+    Ps,
+    Core,
+    Kvm,
+    Root,
+    ContextDir(ContextId),
+    Status(ContextId),
+    Maps(ContextId),
+}
+
+const KERNEL_ENTRIES: [&str; 10] = ["meminfo", "interrupts", "ioapic", "uptime", "version", "stacks", "cpuidle", "ps", "core", "kvm"];
+const CONTEXT_ENTRIES: [&str; 2] = ["status", "maps"];
+
+impl ProcFs {
+    pub fn open(path: &str) -> KResult<Arc<dyn File>> {
+        let entry = Self::lookup(path)?;
+        Ok(Arc::new(ProcFile { entry, state: Spinlock::new(ProcFileState { content: Vec::new(), offset: 0, eof: false }) }))
+    }
+
+    // list entries under directory `path`, "/" is the root
+    pub fn read_dir(path: &str) -> KResult<Vec<String>> {
+        let path = path.trim_matches('/');
+        if path.is_empty() {
+            let mut entries: Vec<String> = KERNEL_ENTRIES.iter().map(|e| e.to_string()).collect();
+            entries.extend(context_storage().iter().map(|(id, _)| format!("{}", id.get())));
+            return Ok(entries);
+        }
+
+        let id = Self::parse_context_id(path)?;
+        if context_storage().iter().any(|(cid, _)| *cid == id) {
+            Ok(CONTEXT_ENTRIES.iter().map(|e| e.to_string()).collect())
+        } else {
+            Err(KError::new(ENOENT))
+        }
+    }
+
+    fn lookup(path: &str) -> KResult<ProcEntry> {
+        let mut parts = path.trim_matches('/').split('/');
+
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(""), None, _) => Ok(ProcEntry::Root),
+            (Some("meminfo"), None, _) => Ok(ProcEntry::MemInfo),
+            (Some("interrupts"), None, _) => Ok(ProcEntry::Interrupts),
+            (Some("ioapic"), None, _) => Ok(ProcEntry::IoApic),
+            (Some("uptime"), None, _) => Ok(ProcEntry::Uptime),
+            (Some("version"), None, _) => Ok(ProcEntry::Version),
+            (Some("stacks"), None, _) => Ok(ProcEntry::Stacks),
+            (Some("cpuidle"), None, _) => Ok(ProcEntry::CpuIdle),
+            (Some("ps"), None, _) => Ok(ProcEntry::Ps),
+            (Some("core"), None, _) => Ok(ProcEntry::Core),
+            (Some("kvm"), None, _) => Ok(ProcEntry::Kvm),
+            (Some(id), None, _) => {
+                let id = Self::parse_context_id(id).map_err(|_| KError::new(ENOENT))?;
+                Self::read_dir(&format!("{}", id.get()))?;
+                Ok(ProcEntry::ContextDir(id))
+            }
+            (Some(id), Some(file), None) => {
+                let id = Self::parse_context_id(id)?;
+                if !context_storage().iter().any(|(cid, _)| *cid == id) {
+                    return Err(KError::new(ENOENT));
+                }
+                match file {
+                    "status" => Ok(ProcEntry::Status(id)),
+                    "maps" => Ok(ProcEntry::Maps(id)),
+                    _ => Err(KError::new(ENOENT))
+                }
+            }
+            _ => Err(KError::new(ENOENT))
+        }
+    }
+
+    fn parse_context_id(s: &str) -> KResult<ContextId> {
+        s.parse::<usize>().map(ContextId::from).map_err(|_| KError::new(EINVAL))
+    }
+}
+
+struct ProcFileState {
+    content: Vec<u8>,
+    offset: usize,
+    // the snapshot was read to its end, the next read returns 0
+    eof: bool,
+}
+
+struct ProcFile {
+    entry: ProcEntry,
+    state: Spinlock<ProcFileState>,
+}
+
+impl ProcFile {
+    fn generate(&self) -> KResult<Vec<u8>> {
+        // binary, not text
+        if let ProcEntry::Core = self.entry {
+            return Ok(last_core_dump().map(|(_, core)| core.to_vec()).unwrap_or_default());
+        }
+        let dir = match self.entry {
+            ProcEntry::Root => Some(ProcFs::read_dir("/")?),
+            ProcEntry::ContextDir(id) => Some(ProcFs::read_dir(&format!("{}", id.get()))?),
+            _ => None,
+        };
+        if let Some(entries) = dir {
+            return Ok(entries.iter().flat_map(|entry| entry.bytes().chain([b'\n'])).collect());
+        }
+        let mut out = String::new();
+
+        // writing to String never fails
+        let _ = match self.entry {
+            ProcEntry::MemInfo => gen_meminfo(&mut out),
+            ProcEntry::Interrupts => gen_interrupts(&mut out),
+            ProcEntry::IoApic => gen_ioapic(&mut out),
+            ProcEntry::Uptime => gen_uptime(&mut out),
+            ProcEntry::Version => gen_version(&mut out),
+            ProcEntry::Stacks => gen_stacks(&mut out),
+            ProcEntry::CpuIdle => gen_cpuidle(&mut out),
+            ProcEntry::Ps => gen_ps(&mut out),
+            ProcEntry::Core | ProcEntry::Root | ProcEntry::ContextDir(_) => Ok(()),
+            ProcEntry::Kvm => gen_kvm(&mut out),
+            ProcEntry::Status(id) => Ok(gen_status(&mut out, id)?),
+            ProcEntry::Maps(id) => Ok(gen_maps(&mut out, id)?),
+        };
+
+        Ok(out.into_bytes())
+    }
+}
+
+impl File for ProcFile {
+    fn readable(&self) -> bool {
+        true
+    }
+
+    fn writable(&self) -> bool {
+        false
+    }
+
+    fn read(&self, buf: UserBuffer) -> KResult<usize> {
+        let mut state = self.state.lock();
+        // end of the snapshot, rewind so the next read regenerates it
+        if state.eof {
+            state.eof = false;
+            state.offset = 0;
+            return Ok(0);
+        }
+        if state.offset == 0 {
+            state.content = self.generate()?;
+        }
+
+        let target = UserSlice::rw(buf.ptr() as usize, buf.len())?;
+        let offset = state.offset;
+        let copied = target.copy_from_kernel(&state.content[offset..])?;
+        state.offset += copied;
+        state.eof = copied != 0 && state.offset == state.content.len();
+        Ok(copied)
+    }
+
+    fn write(&self, _buf: UserBuffer) -> KResult<usize> {
+        Err(KError::new(EBADF))
+    }
+}
+
+pub(crate) fn gen_meminfo(out: &mut String) -> core::fmt::Result {
+    let total = *PHYS_MEM_SIZE.get().unwrap_or(&0);
+    let used = (used_frame_count() * PAGE_SIZE) as u64;
+
+    writeln!(out, "MemTotal:  {:>12} kB", total / 1024)?;
+    writeln!(out, "MemUsed:   {:>12} kB", used / 1024)?;
+    writeln!(out, "MemFree:   {:>12} kB", total.saturating_sub(used) / 1024)?;
+    let (areas, vmalloc_bytes) = vmalloc_stats();
+    writeln!(out, "Vmalloc:   {:>12} kB in {} areas", vmalloc_bytes / 1024, areas)?;
+    // every page sharing a frame but the first one saves it
+    let (frames, sharing) = ksm_stats();
+    writeln!(out, "KsmSaved:  {:>12} kB, {} pages share {} frames", sharing.saturating_sub(frames) * PAGE_SIZE / 1024, sharing, frames)
+}
+
+fn gen_interrupts(out: &mut String) -> core::fmt::Result {
+    for vector in 0..256 {
+        let count = irq_count(vector);
+        if count != 0 {
+            writeln!(out, "{:>3}: {:>12}", vector, count)?;
+        }
+    }
+    Ok(())
+}
+
+// masked entries without a vector were never routed
+fn gen_ioapic(out: &mut String) -> core::fmt::Result {
+    writeln!(out, "{:>4} {:>6} {:>6} {:>4} {:<8} {:<7} {:<10} {}", "gsi", "ioapic", "vector", "dest", "delivery", "trigger", "polarity", "masked")?;
+    for route in routing_table() {
+        match route.info {
+            Some(info) if info.mask && info.vector == 0 => continue,
+            Some(info) => writeln!(
+                out, "{:>4} {:>6} {:>6} {:>4} {:<8} {:<7} {:<10} {}",
+                route.gsi, route.ioapic_id, info.vector, info.dest, format!("{:?}", info.delivery_mode),
+                format!("{:?}", info.trigger_mode), format!("{:?}", info.polarity), info.mask
+            )?,
+            None => writeln!(out, "{:>4} {:>6} reserved delivery mode", route.gsi, route.ioapic_id)?,
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn gen_uptime(out: &mut String) -> core::fmt::Result {
+    let tsc = CurrentArch::timestamp();
+    if let Some(ns) = tsc_to_ns(tsc) {
+        writeln!(out, "seconds: {}.{:03}", ns / 1_000_000_000, ns / 1_000_000 % 1000)?;
+    }
+    writeln!(out, "pit_ticks: {}", irq_count(32))?;
+    writeln!(out, "tsc: {}", tsc)?;
+    writeln!(out, "tsc_hz: {}", current_tsc_hz())?;
+    writeln!(out, "tsc_invariant: {}", tsc_invariant())?;
+    writeln!(out, "tick_hz: {}", tick_hz())?;
+    for cpu in 0..CPU_COUNT.load(Ordering::SeqCst) {
+        writeln!(out, "cpu{}_lapic_timer_hz: {}", cpu, lapic_timer_hz(LogicalCpuId(cpu)))?;
+    }
+    Ok(())
+}
+
+fn gen_version(out: &mut String) -> core::fmt::Result {
+    writeln!(out, "{} {} ({})", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"), env!("KERNEL_GIT_HASH"))
+}
+
+fn gen_cpuidle(out: &mut String) -> core::fmt::Result {
+    let method = idle_method();
+    match method {
+        IdleMethod::Mwait { hint } => writeln!(out, "method: mwait 0x{:x}", hint)?,
+        _ => writeln!(out, "method: {}", method.name())?,
+    }
+    writeln!(out, "{:>4} {:>12} {:>20} {:>16}", "cpu", "entries", "residency_tsc", "idle_ns")?;
+    let contexts = context_storage();
+    for cpu in 0..CPU_COUNT.load(Ordering::SeqCst) {
+        let stats = idle_stats(LogicalCpuId(cpu));
+        // idle context id is the cpu id, its time is updated when switched out
+        let idle_ns = contexts.get(ContextId::from(cpu as usize)).map_or(0, |idle| idle.read().cpu_time);
+        writeln!(out, "{:>4} {:>12} {:>20} {:>16}", cpu, stats.entries, stats.residency, idle_ns)?;
+    }
+    Ok(())
+}
+
+fn gen_stacks(out: &mut String) -> core::fmt::Result {
+    let config = stack_config();
+    writeln!(out, "boot: {} / {} bytes", boot_stack_high_water_mark(), config.boot)?;
+    writeln!(out, "context: peak {} / {} bytes", context_stack_peak(), config.context)?;
+    writeln!(out, "ap: {} bytes", config.ap)?;
+    let (pooled, pooled_bytes) = kstack_pool_stats();
+    writeln!(out, "pool: {} stacks, {} bytes", pooled, pooled_bytes)
+}
+
+pub(crate) fn gen_kvm(out: &mut String) -> core::fmt::Result {
+    writeln!(out, "{:<8} {:>18} {:>14} {:>14}", "region", "base", "allocated", "reserved")?;
+    for stats in kvm_stats() {
+        writeln!(out, "{:<8} {:>#18x} {:>14} {:>14}", stats.region.name(), stats.region.base().as_u64(), stats.allocated, stats.reserved)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn gen_ps(out: &mut String) -> core::fmt::Result {
+    writeln!(out, "{:>6} {:>6} {:<16} {:>5} {:>7} {}", "id", "ppid", "name", "cpu", "pages", "status")?;
+    for (id, lock) in context_storage().iter() {
+        let context = lock.read();
+        let cpu = match context.cpu_id {
+            Some(cpu_id) => format!("{}", cpu_id),
+            None => "-".to_string(),
+        };
+        let ppid = match context.ppid {
+            Some(ppid) => format!("{}", ppid.get()),
+            None => "-".to_string(),
+        };
+        let pages = match context.addrsp {
+            Some(ref addrsp) => format!("{}", addrsp.acquire_read().stats().mapped_pages),
+            None => "-".to_string(),
+        };
+        writeln!(out, "{:>6} {:>6} {:<16} {:>5} {:>7} {:?}", id.get(), ppid, context.name(), cpu, pages, context.status)?;
+    }
+    Ok(())
+}
+
+fn gen_status(out: &mut String, id: ContextId) -> KResult<()> {
+    let contexts = context_storage();
+    let context = contexts.get(id).ok_or(KError::new(ENOENT))?.read();
+
+    (|| {
+        writeln!(out, "id: {}", context.id.get())?;
+        writeln!(out, "name: {}", context.name())?;
+        match context.ppid {
+            Some(ppid) => writeln!(out, "ppid: {}", ppid.get())?,
+            None => writeln!(out, "ppid: -")?,
+        }
+        writeln!(out, "uid: {}", context.cred.uid)?;
+        writeln!(out, "gid: {}", context.cred.gid)?;
+        writeln!(out, "caps: {:#x}", context.cred.caps)?;
+        for (resource, name) in [(RLIMIT_AS, "as"), (RLIMIT_NOFILE, "nofile"), (RLIMIT_CHILDREN, "children"), (RLIMIT_CPU, "cpu")] {
+            match context.rlimits.cur(resource) {
+                RLIM_INFINITY => writeln!(out, "rlimit_{}: unlimited", name)?,
+                cur => writeln!(out, "rlimit_{}: {}", name, cur)?,
+            }
+        }
+        writeln!(out, "children: {:?}", context.children.iter().map(|id| id.get()).collect::<Vec<_>>())?;
+        writeln!(out, "status: {:?}", context.status)?;
+        writeln!(out, "priority: {}", context.priority)?;
+        writeln!(out, "idle: {}", context.is_idle())?;
+        writeln!(out, "cpu_time: {} ns", context.cpu_time)?;
+        writeln!(out, "running: {}", context.running)?;
+        match context.cpu_id {
+            Some(cpu_id) => writeln!(out, "cpu: {}", cpu_id)?,
+            None => writeln!(out, "cpu: -")?,
+        }
+        writeln!(out, "userspace: {}", context.userspace)?;
+        writeln!(out, "inside_syscall: {}", context.inside_syscall)?;
+        writeln!(out, "kstack: {} bytes", context.kstack.as_ref().map_or(0, |s| s.len()))?;
+        writeln!(out, "kstack_used: {} bytes", context.kstack.as_ref().map_or(0, |s| stack_high_water_mark(s.as_slice())))?;
+        if let Some(ref addrsp) = context.addrsp {
+            let stats = addrsp.acquire_read().stats();
+            writeln!(out, "addrsp_pages: {} (peak {})", stats.mapped_pages, stats.peak_mapped_pages)?;
+            writeln!(out, "addrsp_frames: {} tracked, {} page tables", stats.tracked_frames, stats.pte_frames)?;
+            writeln!(out, "addrsp_buffers: {} slab in {} pages, {} large",
+                stats.slab_buffers, stats.slab_pages, stats.large_buffers)?;
+        }
+        writeln!(out, "signal_pending: {:#x}", context.signal.pending)?;
+        writeln!(out, "signal_procmask: {:#x}", context.signal.procmask)
+    })().map_err(|_| KError::new(EIO))
+}
+
+fn gen_maps(out: &mut String, id: ContextId) -> KResult<()> {
+    let addrsp = match context_storage().get(id).ok_or(KError::new(ENOENT))?.read().addrsp {
+        Some(ref addrsp) => Arc::clone(addrsp),
+        None => return Ok(()) // kernel context
+    };
+    let regions = addrsp.acquire_write().mapped_regions();
+
+    (|| {
+        for region in regions.iter() {
+            writeln!(
+                out, "{:016x}-{:016x} r{}{}",
+                region.start.as_u64(),
+                region.start.as_u64() + region.len,
+                if region.flags.contains(PageTableFlags::WRITABLE) { 'w' } else { '-' },
+                if region.flags.contains(PageTableFlags::NO_EXECUTE) { '-' } else { 'x' },
+            )?;
+        }
+        Ok(())
+    })().map_err(|_| KError::new(EIO))
+}
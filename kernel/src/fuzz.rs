@@ -0,0 +1,170 @@
+use core::arch::x86_64::_rdtsc;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use alloc::sync::Arc;
+use libvdso::syscall_number::{
+    SYS_FRAMEBUFFER_INFO, SYS_GETGID, SYS_GETPID, SYS_GETPPID, SYS_GETRLIMIT, SYS_GETUID, SYS_IOPERM, SYS_IOPL,
+    SYS_IRQ_REGISTER, SYS_IRQ_RELEASE, SYS_MAP_DEVICE, SYS_PROFILE, SYS_SETRLIMIT, SYS_SET_NAME, SYS_SYSINFO,
+    SYS_THREAD_SPAWN, SYS_TSC_KHZ, SYS_UNAME, SYS_UNMAP_DEVICE, SYS_WRITE,
+};
+use shared::layout::{KERNEL_BASE, PHYS_MAP_HIGH_BASE, USER_SPACE_END, USER_STACK_BASE};
+use crate::arch::{ArchInterrupts, CurrentArch};
+use crate::cmdline::cmdline_value;
+use crate::context::list::{context_storage, context_storage_mut};
+use crate::context::spawn::{SpawnEntry, SpawnOptions};
+use crate::context::status::Status;
+use crate::context::switch::switch_context;
+use crate::cpu::PercpuBlock;
+use crate::device::qemu::{exit_qemu, QemuExitCode};
+use crate::mem::PAGE_SIZE;
+use crate::qemu_println;
+
+/**
+ *  syscall fuzzer, built with feature `fuzz`.
+ *
+ *  replaces bootstrap with a kernel context that calls the syscall dispatcher
+ *  with random numbers and adversarial arguments: null, unaligned and
+ *  non-canonical pointers, kernel addresses, ranges running off the end of a
+ *  mapped user buffer and huge lengths. the context owns a user address space
+ *  like any other, so user pointers are resolved against it.
+ *
+ *  a run is reproducible from `fuzz.seed=<n>` on the command line,
+ *  `fuzz.iters=<n>` sets its length. qemu exits with success once every
+ *  iteration returned, a kernel panic prints the seed and the call in flight
+ *  and exits with failure.
+ */
+
+const DEFAULT_ITERS: usize = 100_000;
+const BUFFER_SIZE: usize = 4 * PAGE_SIZE;
+// share of calls with a random syscall number instead of a known one, out of 16
+const UNKNOWN_SHARE: u64 = 2;
+
+// calls that block, stop the machine or drop privileges of the fuzzer are left out,
+// the last ones would turn the rest of the run into EPERM
+const SYSCALLS: &[usize] = &[
+    SYS_WRITE, SYS_TSC_KHZ, SYS_SET_NAME, SYS_GETPID, SYS_GETPPID, SYS_GETUID, SYS_GETGID, SYS_GETRLIMIT,
+    SYS_SETRLIMIT, SYS_IOPERM, SYS_IOPL, SYS_MAP_DEVICE, SYS_UNMAP_DEVICE, SYS_IRQ_REGISTER, SYS_IRQ_RELEASE,
+    SYS_FRAMEBUFFER_INFO, SYS_PROFILE, SYS_UNAME, SYS_SYSINFO, SYS_THREAD_SPAWN,
+];
+
+static SEED: AtomicU64 = AtomicU64::new(0);
+static ITER: AtomicUsize = AtomicUsize::new(0);
+// syscall number and arguments of the call in flight
+static LAST_CALL: [AtomicUsize; 6] = [const { AtomicUsize::new(0) }; 6];
+
+// xorshift64*, never seeded with 0
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Self(seed.max(1))
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
+
+// pointer or length, `buffer` is a mapped user buffer of BUFFER_SIZE bytes
+fn arg(rng: &mut Rng, buffer: usize) -> usize {
+    match rng.below(14) {
+        0 => 0,
+        1 => 1,
+        2 => usize::MAX,
+        3 => rng.next() as usize,
+        4 => 1 << rng.below(64),
+        5 => rng.below(64) as usize,
+        6 => rng.below(2 * BUFFER_SIZE as u64) as usize,
+        7 => buffer,
+        8 => buffer + rng.below(BUFFER_SIZE as u64) as usize,
+        // last byte of the buffer, any longer range crosses into unmapped memory
+        9 => buffer + BUFFER_SIZE - 1,
+        10 => USER_SPACE_END as usize - 1 - rng.below(PAGE_SIZE as u64) as usize,
+        11 => USER_STACK_BASE as usize + rng.below(BUFFER_SIZE as u64) as usize,
+        12 => KERNEL_BASE as usize + rng.below(BUFFER_SIZE as u64) as usize,
+        _ => PHYS_MAP_HIGH_BASE as usize + rng.below(BUFFER_SIZE as u64) as usize,
+    }
+}
+
+pub fn run() -> ! {
+    let seed = cmdline_value("fuzz.seed")
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or_else(|| unsafe { _rdtsc() });
+    let iters = cmdline_value("fuzz.iters")
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(DEFAULT_ITERS);
+    SEED.store(seed, Ordering::SeqCst);
+
+    qemu_println!("fuzz: seed {} iters {}", seed, iters);
+    match context_storage_mut().spawn(&SpawnOptions::kernel("fuzz"), SpawnEntry::closure(move || fuzz(seed, iters))) {
+        Ok(lock) => lock.write().set_status(Status::Runnable),
+        Err(_) => {
+            qemu_println!("fuzz: failed to spawn the fuzzer context");
+            exit_qemu(QemuExitCode::Failed)
+        }
+    }
+
+    // we are the idle context of bsp, the fuzzer exits qemu when it is done
+    loop {
+        unsafe {
+            CurrentArch::disable_interrupts();
+            switch_context();
+            CurrentArch::enable_interrupts();
+            CurrentArch::halt();
+        }
+    }
+}
+
+fn fuzz(seed: u64, iters: usize) {
+    let addrsp = context_storage().current()
+        .and_then(|lock| lock.read().addrsp.as_ref().map(Arc::clone));
+    let Some((addrsp, buffer)) = addrsp.and_then(|addrsp| addrsp.alloc(BUFFER_SIZE).ok().map(|buffer| (addrsp, buffer))) else {
+        qemu_println!("fuzz: failed to allocate the user buffer");
+        exit_qemu(QemuExitCode::Failed)
+    };
+    let base = buffer.ptr() as usize;
+
+    let mut rng = Rng::new(seed);
+    for iter in 0..iters {
+        let number = if rng.below(16) < UNKNOWN_SHARE {
+            rng.next() as usize
+        } else {
+            SYSCALLS[rng.below(SYSCALLS.len() as u64) as usize]
+        };
+        let call = [number, arg(&mut rng, base), arg(&mut rng, base), arg(&mut rng, base), arg(&mut rng, base), arg(&mut rng, base)];
+        ITER.store(iter, Ordering::SeqCst);
+        LAST_CALL.iter().zip(call).for_each(|(slot, value)| slot.store(value, Ordering::SeqCst));
+
+        PercpuBlock::current().inside_syscall.set(true);
+        let _ = crate::syscall::syscall(call[0], call[1], call[2], call[3], call[4], call[5]);
+        PercpuBlock::current().inside_syscall.set(false);
+
+        if (iter + 1) % (iters / 10).max(1) == 0 {
+            qemu_println!("fuzz: {} of {} calls done", iter + 1, iters);
+        }
+    }
+
+    // the buffer allocator has to be intact after all the calls
+    if let Err(err) = addrsp.free(&buffer) {
+        qemu_println!("fuzz: failed to free the user buffer: {:?}", err);
+        exit_qemu(QemuExitCode::Failed)
+    }
+    qemu_println!("fuzz: seed {} survived {} calls", seed, iters);
+    exit_qemu(QemuExitCode::Success)
+}
+
+/// called by the panic handler, reports the call in flight and fails the run
+pub fn report_panic() -> ! {
+    let call = LAST_CALL.each_ref().map(|slot| slot.load(Ordering::SeqCst));
+    qemu_println!(
+        "fuzz: kernel panic at call {} of seed {}: syscall {:#x} ({:#x}, {:#x}, {:#x}, {:#x}, {:#x})",
+        ITER.load(Ordering::SeqCst), SEED.load(Ordering::SeqCst), call[0], call[1], call[2], call[3], call[4], call[5]
+    );
+    exit_qemu(QemuExitCode::Failed)
+}
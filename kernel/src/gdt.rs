@@ -1,25 +1,22 @@
+use core::cell::Cell;
 use core::mem::{offset_of, size_of};
+use core::ops::Range;
 use core::ptr;
 
 
 use log::info;
-use spin::Once;
+use shared::gdt::{gdt_index, GdtEntry, GDT_LAYOUT, KERNEL_CODE_SELECTOR, KERNEL_DATA_SELECTOR, TSS_SELECTOR};
 
 use x86_64::{instructions::{tables::load_tss}, registers::{control::{Cr0, Cr0Flags}, segmentation::{Segment, CS, DS, ES, GS, SS}}, structures::{gdt::{Descriptor, DescriptorFlags, GlobalDescriptorTable, SegmentSelector}, tss::TaskStateSegment}, VirtAddr};
 
-use crate::{arch_spec::msr::wrmsr, cpu::LogicalCpuId, infohart, loghart, mem::{frame_allocator::{frame_alloc_n}, PAGE_SIZE}};
+use crate::{arch_spec::msr::Msr, cpu::LogicalCpuId, infohart, loghart, mem::{frame_allocator::{frame_alloc_n}, PAGE_SIZE}};
+use crate::context::io::IoBitmap;
 use crate::cpu::PercpuBlock;
+use crate::initcall;
+use crate::initcall::InitCpuArg;
 
-const STACK_SIZE: usize = 10 * 0x1000; // 10 KiB
 const IOBITMAP_SIZE: u32 = 65536 / 8;
 
-// TODO: each cpu should has its own interrupt stack
-static mut DOUBLE_FAULT_STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-pub static GDT_KERNEL_CODE32: Once<SegmentSelector> = Once::new();
-pub static GDT_USER_CODE64: Once<SegmentSelector> = Once::new();
-pub static GDT_USER_DATA: Once<SegmentSelector> = Once::new();
-
 #[repr(C, align(4096))]
 pub struct ProcessorControlRegion {
     pub self_ref: usize,
@@ -27,11 +24,13 @@ pub struct ProcessorControlRegion {
     pub user_rsp_tmp: usize,
     pub gdt: GlobalDescriptorTable,
     pub percpu: PercpuBlock,
+    // bytes of `_iobitmap` the last loaded bitmap may have cleared, all ones outside
+    io_bitmap_span: Range<usize>,
     _rsvd: Align,
     pub tss: TaskStateSegment,
 
-    // These two fields are read by the CPU, but not currently modified by the kernel. Instead, the
-    // kernel sets the `iom ap_base` field in the TSS, to either point to this bitmap, or outside
+    // These two fields are read by the CPU. The bitmap of the next context is copied in on switch,
+    // and the kernel sets the `iomap_base` field in the TSS, to either point to this bitmap, or outside
     // the TSS, in which case userspace is not granted port IO access.
     pub _iobitmap: [u8; IOBITMAP_SIZE as usize],
     pub _all_ones: u8,
@@ -49,8 +48,22 @@ impl ProcessorControlRegion {
         ptr::addr_of_mut!((*self).tss.iomap_base)
             .write_unaligned(if allowed { u16::try_from(size_of::<TaskStateSegment>()).unwrap() } else { 0xFFFF });
     }
+
+    /// copy only the span of `bitmap` that allows ports, after denying the span of the previous one again
+    pub unsafe fn load_io_bitmap(self: *mut Self, bitmap: &IoBitmap) {
+        let loaded = ptr::addr_of_mut!((*self)._iobitmap).cast::<u8>();
+        let previous = ptr::addr_of_mut!((*self).io_bitmap_span).replace(bitmap.span());
+        loaded.add(previous.start).write_bytes(0xff, previous.len());
+        let span = bitmap.span();
+        loaded.add(span.start).copy_from_nonoverlapping(bitmap.bits()[span.clone()].as_ptr(), span.len());
+    }
 }
 
+unsafe fn gdt_initcall(arg: &InitCpuArg) {
+    init_gdt(arg.cpu_id, arg.stack_top);
+}
+initcall!(arch, All, gdt_initcall, order = 0);
+
 // from redox-os kernel
 #[cold]
 pub unsafe fn init_gdt(cpu_id: LogicalCpuId, kernel_stack_top: u64) {
@@ -65,39 +78,63 @@ pub unsafe fn init_gdt(cpu_id: LogicalCpuId, kernel_stack_top: u64) {
     (pcr as *mut ProcessorControlRegion).set_tss_stack(kernel_stack_top);
 
     pcr.tss.iomap_base = 0xffff;
+    pcr._iobitmap.fill(0xff);
+    ptr::addr_of_mut!(pcr.io_bitmap_span).write(0..0);
     pcr._all_ones = 0xff;
 
-    // GDT[0] = NULL
-    let code_selector = pcr.gdt.add_entry(Descriptor::kernel_code_segment()); // GDT[1] = KERNEL_CODE,
-    let data_selector = pcr.gdt.add_entry(Descriptor::kernel_data_segment()); // GDT[2] = KERNEL_DATA
-    GDT_KERNEL_CODE32.call_once(|| pcr.gdt.add_entry(Descriptor::UserSegment(DescriptorFlags::KERNEL_CODE32.bits()))); // GDT[3] = KERNEL_CODE_32
-    GDT_USER_DATA.call_once(|| pcr.gdt.add_entry(Descriptor::user_data_segment())); // GDT[4] = USER_DATA
-    GDT_USER_CODE64.call_once(|| pcr.gdt.add_entry(Descriptor::user_code_segment())); // GDT[5] = USER_CODE
-    let tss_selector = pcr.gdt.add_entry(Descriptor::tss_segment(&pcr.tss)); // GDT[6..8] = TSS
+    // GDT[0] = NULL, the rest in the order of shared::gdt
+    for entry in GDT_LAYOUT {
+        let descriptor = match entry {
+            GdtEntry::KernelCode => Descriptor::kernel_code_segment(),
+            GdtEntry::KernelData => Descriptor::kernel_data_segment(),
+            GdtEntry::KernelCode32 => Descriptor::UserSegment(DescriptorFlags::KERNEL_CODE32.bits()),
+            GdtEntry::UserData => Descriptor::user_data_segment(),
+            GdtEntry::UserCode => Descriptor::user_code_segment(),
+            GdtEntry::Tss => tss_descriptor(&pcr.tss),
+        };
+        assert_eq!(pcr.gdt.add_entry(descriptor).index(), gdt_index(entry), "gdt entry {:?} is out of place", entry);
+    }
 
     pcr.gdt.load_unsafe();
     
     unsafe {
-        CS::set_reg(code_selector);
-        SS::set_reg(data_selector);
+        CS::set_reg(SegmentSelector(KERNEL_CODE_SELECTOR));
+        SS::set_reg(SegmentSelector(KERNEL_DATA_SELECTOR));
         DS::set_reg(SegmentSelector(0));
         ES::set_reg(SegmentSelector(0));
         GS::set_reg(SegmentSelector(0));
     }
     
-    wrmsr(0xc0000101, pcr as *const _ as usize as u64); // IA32_GS_BASE
-    wrmsr(0xc0000102, 0); // IA32_KERNEL_GSBASE
-    wrmsr(0xc0000100, 0); // IA32_FS_BASE
+    Msr::IA32_GS_BASE.write(pcr as *const _ as usize as u64);
+    Msr::IA32_KERNEL_GS_BASE.write(0);
+    Msr::IA32_FS_BASE.write(0);
 
-    load_tss(tss_selector);
+    load_tss(SegmentSelector(TSS_SELECTOR));
 
     Cr0::update(|cr0| *cr0 |= Cr0Flags::PROTECTED_MODE_ENABLE);
 
     pcr.percpu.cpu_id = cpu_id;
+    pcr.percpu.irq_depth = Cell::new(0);
+    pcr.percpu.preempt_count = Cell::new(0);
+    pcr.percpu.irq_guard = Cell::new(0);
+    pcr.percpu.tls = Cell::new(0);
+    pcr.percpu.user_fsbase = Cell::new(0);
 
     infohart!("global descriptor table is initialized, pcr base: 0x{:x}", pcr as *const _ as u64);
 }
 
+// tss_segment limits the segment to the tss itself, cpu would never read the io bitmap
+fn tss_descriptor(tss: &'static TaskStateSegment) -> Descriptor {
+    let Descriptor::SystemSegment(mut low, high) = Descriptor::tss_segment(tss) else {
+        unreachable!("tss descriptor is a system segment");
+    };
+    // last byte is the trailing all ones byte after the bitmap
+    let limit = (offset_of!(ProcessorControlRegion, _all_ones) - offset_of!(ProcessorControlRegion, tss)) as u64;
+    low &= !(0xffff | 0xf << 48);
+    low |= (limit & 0xffff) | (limit >> 16 & 0xf) << 48;
+    Descriptor::SystemSegment(low, high)
+}
+
 pub unsafe fn pcr() -> *mut ProcessorControlRegion {
     // Primitive benchmarking of RDFSBASE and RDGSBASE in userspace, appears to indicate that
     // obtaining FSBASE/GSBASE using mov gs:[gs_self_ref] is faster than using the (probably
@@ -0,0 +1,155 @@
+use core::arch::asm;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use spin::Once;
+use crate::arch::{ArchInterrupts, ArchTimer, CurrentArch};
+use crate::arch_spec::cpuid::cpuid;
+use crate::cmdline::cmdline_value;
+use crate::config::MAX_CPUS;
+use crate::cpu::{LogicalCpuId, PercpuBlock};
+use crate::infohart;
+use crate::initcall;
+use crate::initcall::InitCpuArg;
+
+/**
+ *  cpu idle driver.
+ *
+ *  prefers MONITOR/MWAIT with the deepest advertised c-state hint, falls back to
+ *  `sti; hlt`. override with `idle=hlt|mwait|poll` and `max_cstate=<n>` in cmdline.
+ */
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdleMethod {
+    // busy loop with interrupts enabled, lowest wake latency
+    Poll,
+    Hlt,
+    // eax hint of mwait: c-state in [7:4], sub c-state in [3:0]
+    Mwait { hint: u32 },
+}
+
+impl IdleMethod {
+    pub fn name(&self) -> &'static str {
+        match self {
+            IdleMethod::Poll => "poll",
+            IdleMethod::Hlt => "hlt",
+            IdleMethod::Mwait { .. } => "mwait",
+        }
+    }
+}
+
+// one cache line per cpu, `wakeup` is the monitored address
+#[repr(align(64))]
+struct IdleState {
+    wakeup: AtomicUsize,
+    entries: AtomicU64,
+    // in timestamp counter ticks
+    residency: AtomicU64,
+}
+
+const IDLE_STATE_INIT: IdleState = IdleState {
+    wakeup: AtomicUsize::new(0),
+    entries: AtomicU64::new(0),
+    residency: AtomicU64::new(0),
+};
+
+static IDLE_STATES: [IdleState; MAX_CPUS] = [IDLE_STATE_INIT; MAX_CPUS];
+static IDLE_METHOD: Once<IdleMethod> = Once::new();
+
+pub struct IdleStats {
+    pub entries: u64,
+    pub residency: u64,
+}
+
+unsafe fn idle_initcall(_: &InitCpuArg) {
+    let method = IDLE_METHOD.call_once(detect_idle_method);
+    match method {
+        IdleMethod::Mwait { hint } => infohart!("idle: mwait, hint 0x{:x}", hint),
+        _ => infohart!("idle: {}", method.name()),
+    }
+}
+initcall!(early, Bsp, idle_initcall);
+
+fn detect_idle_method() -> IdleMethod {
+    let max_cstate = cmdline_value("max_cstate").and_then(|v| v.parse::<u32>().ok());
+    match cmdline_value("idle") {
+        Some("poll") => return IdleMethod::Poll,
+        Some("hlt") => return IdleMethod::Hlt,
+        _ => {}
+    }
+    // c0 means never leave c0, mwait has nothing to offer
+    if max_cstate == Some(0) {
+        return IdleMethod::Hlt;
+    }
+
+    let cpuid = cpuid();
+    let has_monitor = cpuid.get_feature_info().map_or(false, |f| f.has_monitor_mwait());
+    let Some(info) = cpuid.get_monitor_mwait_info().filter(|_| has_monitor) else {
+        return IdleMethod::Hlt;
+    };
+    // we need to wake on interrupts delivered right after `sti`
+    if !info.extensions_supported() || !info.interrupts_as_break_event() {
+        return IdleMethod::Hlt;
+    }
+
+    let substates = [
+        info.supported_c1_states(),
+        info.supported_c2_states(),
+        info.supported_c3_states(),
+        info.supported_c4_states(),
+        info.supported_c5_states(),
+        info.supported_c6_states(),
+        info.supported_c7_states(),
+    ];
+    let limit = max_cstate.map_or(substates.len(), |c| (c as usize).min(substates.len()));
+
+    // deepest c-state with any sub-state, mwait hint of cN is N - 1
+    match substates[..limit].iter().rposition(|&n| n != 0) {
+        Some(index) => IdleMethod::Mwait {
+            hint: ((index as u32) << 4) | (substates[index] as u32 - 1),
+        },
+        None => IdleMethod::Hlt,
+    }
+}
+
+pub fn idle_method() -> IdleMethod {
+    IDLE_METHOD.get().copied().unwrap_or(IdleMethod::Hlt)
+}
+
+/// enter idle on current cpu with interrupts disabled, returns with interrupts enabled
+/// after the next interrupt is handled.
+pub unsafe fn enter_idle() {
+    let cpu_id = PercpuBlock::current().cpu_id;
+    let state = &IDLE_STATES[cpu_id.0 as usize];
+    let start = CurrentArch::timestamp();
+
+    match idle_method() {
+        IdleMethod::Poll => {
+            // caller loops back into scheduler right away
+            CurrentArch::enable_interrupts_and_nop();
+            core::hint::spin_loop();
+        }
+        IdleMethod::Hlt => CurrentArch::enable_interrupts_and_halt(),
+        IdleMethod::Mwait { hint } => {
+            asm!("monitor", in("rax") &state.wakeup as *const _ as usize, in("ecx") 0, in("edx") 0, options(nostack));
+            // mwait sits in the shadow of sti, same as `sti; hlt`
+            asm!("sti; mwait", in("eax") hint, in("ecx") 1, options(nomem, nostack));
+        }
+    }
+
+    state.entries.fetch_add(1, Ordering::Relaxed);
+    state.residency.fetch_add(CurrentArch::timestamp().wrapping_sub(start), Ordering::Relaxed);
+}
+
+/// wake `cpu_id` if it is waiting in mwait, cheaper than an ipi.
+/// hlt waiters need an ipi instead.
+pub fn kick_idle(cpu_id: LogicalCpuId) {
+    IDLE_STATES[cpu_id.0 as usize].wakeup.fetch_add(1, Ordering::Release);
+}
+
+pub fn idle_stats(cpu_id: LogicalCpuId) -> IdleStats {
+    let state = &IDLE_STATES[cpu_id.0 as usize];
+    IdleStats {
+        entries: state.entries.load(Ordering::Relaxed),
+        residency: state.residency.load(Ordering::Relaxed),
+    }
+}
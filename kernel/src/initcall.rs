@@ -0,0 +1,149 @@
+use alloc::vec::Vec;
+use core::slice;
+use spin::Once;
+use shared::arg::KernelArg;
+use shared::print_panic::PrintPanic;
+use crate::cpu::LogicalCpuId;
+use crate::infohart;
+
+/**
+ *  static initcalls.
+ *
+ *  subsystems register an init function with [`initcall!`], it is placed into a
+ *  linker section of its level and `_start` / `_start_ap` run the levels in order.
+ *  lld provides `__start_<section>` / `__stop_<section>` for sections whose name is
+ *  a valid c identifier, so no linker script is needed.
+ *
+ *  inside a level, initcalls run by ascending `order`, then by name.
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum InitLevel {
+    // memory and early kernel state, bsp only, interrupts disabled
+    Early,
+    // per-cpu architecture state: gdt, idt, apic, syscall
+    Arch,
+    // device drivers, after smp bring-up
+    Device,
+    Fs,
+    Late,
+}
+
+/// which cpus run the initcall
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitCpus {
+    Bsp,
+    All,
+}
+
+pub struct InitCpuArg {
+    pub cpu_id: LogicalCpuId,
+    pub stack_top: u64,
+}
+
+pub struct InitCall {
+    pub name: &'static str,
+    pub level: InitLevel,
+    pub cpus: InitCpus,
+    pub order: u8,
+    pub func: unsafe fn(&InitCpuArg),
+}
+
+static KERNEL_ARG: Once<&'static KernelArg> = Once::new();
+
+/// must be called before any initcall runs
+pub fn set_kernel_arg(arg: &'static KernelArg) {
+    KERNEL_ARG.call_once(|| arg);
+}
+
+/// kernel argument passed by bootloader, available to initcalls of every level
+pub fn kernel_arg() -> &'static KernelArg {
+    KERNEL_ARG.get().or_panic("kernel arg is not set")
+}
+
+#[macro_export]
+macro_rules! initcall {
+    (early, $cpus:ident, $func:path $(, order = $order:literal)?) => {
+        $crate::__initcall!("initcall_early", Early, $cpus, $func $(, $order)?);
+    };
+    (arch, $cpus:ident, $func:path $(, order = $order:literal)?) => {
+        $crate::__initcall!("initcall_arch", Arch, $cpus, $func $(, $order)?);
+    };
+    (device, $cpus:ident, $func:path $(, order = $order:literal)?) => {
+        $crate::__initcall!("initcall_device", Device, $cpus, $func $(, $order)?);
+    };
+    (fs, $cpus:ident, $func:path $(, order = $order:literal)?) => {
+        $crate::__initcall!("initcall_fs", Fs, $cpus, $func $(, $order)?);
+    };
+    (late, $cpus:ident, $func:path $(, order = $order:literal)?) => {
+        $crate::__initcall!("initcall_late", Late, $cpus, $func $(, $order)?);
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __initcall {
+    ($section:literal, $level:ident, $cpus:ident, $func:path) => {
+        $crate::__initcall!($section, $level, $cpus, $func, 128);
+    };
+    ($section:literal, $level:ident, $cpus:ident, $func:path, $order:literal) => {
+        const _: () = {
+            #[used]
+            #[link_section = $section]
+            static INITCALL: $crate::initcall::InitCall = $crate::initcall::InitCall {
+                name: stringify!($func),
+                level: $crate::initcall::InitLevel::$level,
+                cpus: $crate::initcall::InitCpus::$cpus,
+                order: $order,
+                func: $func,
+            };
+        };
+    };
+}
+
+// bounds are weak so that a level without any initcall still links, they are null then
+macro_rules! section_slice {
+    ($start:ident, $stop:ident) => {{
+        extern "C" {
+            #[linkage = "extern_weak"]
+            static $start: *const InitCall;
+            #[linkage = "extern_weak"]
+            static $stop: *const InitCall;
+        }
+        if $start.is_null() {
+            &[]
+        } else {
+            slice::from_raw_parts($start, $stop.offset_from($start) as usize)
+        }
+    }};
+}
+
+fn initcalls(level: InitLevel) -> &'static [InitCall] {
+    unsafe {
+        match level {
+            InitLevel::Early => section_slice!(__start_initcall_early, __stop_initcall_early),
+            InitLevel::Arch => section_slice!(__start_initcall_arch, __stop_initcall_arch),
+            InitLevel::Device => section_slice!(__start_initcall_device, __stop_initcall_device),
+            InitLevel::Fs => section_slice!(__start_initcall_fs, __stop_initcall_fs),
+            InitLevel::Late => section_slice!(__start_initcall_late, __stop_initcall_late),
+        }
+    }
+}
+
+/// run initcalls of `level` that apply to cpu of `arg`
+pub unsafe fn run_initcalls(level: InitLevel, arg: &InitCpuArg) {
+    let is_bsp = arg.cpu_id == LogicalCpuId::BSP;
+
+    let mut calls: Vec<&InitCall> = initcalls(level).iter()
+        .filter(|call| is_bsp || call.cpus == InitCpus::All)
+        .collect();
+    calls.sort_by_key(|call| (call.order, call.name));
+
+    for call in calls {
+        debug_assert_eq!(call.level, level);
+        if is_bsp {
+            infohart!("initcall {:?}: {}", level, call.name);
+        }
+        (call.func)(arg);
+    }
+}
@@ -6,41 +6,74 @@ use shared::{print_panic::PrintPanic};
 use spin::{Mutex, RwLock, RwLockReadGuard};
 use x86_64::{PhysAddr, registers::control::Cr2, structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode}, VirtAddr};
 use core::{fmt::Write};
+use crate::arch_spec::msr::msr_probe_fixup;
+use crate::arch_spec::debug as watchpoints;
+use crate::arch_spec::pmu;
+use crate::arch_spec::usercopy::user_copy_fixup;
+use crate::mem::{ksm, tlb};
 use core::arch::asm;
 use core::hint::spin_loop;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use core::slice::from_raw_parts;
 use x86_64::instructions::interrupts;
+use crate::arch::{ArchInterrupts, CurrentArch};
 use x86_64::structures::paging::{PhysFrame, Size4KiB};
 use x86_64::structures::paging::mapper::TranslateResult;
 
-use crate::{acpi::local_apic::LOCAL_APIC, cpu::LogicalCpuId, device::qemu::exit_qemu, gdt::{pcr}, halt, infohart, interrupt, interrupt_error, interrupt_stack, mem::{frame_allocator::frame_alloc_n, PAGE_SIZE}, qemu_print, qemu_println};
-use crate::arch_spec::port::inb;
+use crate::{acpi::local_apic::{retune_tick, LOCAL_APIC}, cpu::LogicalCpuId, device::qemu::exit_qemu, gdt::{pcr}, halt, infohart, interrupt, interrupt_error, interrupt_stack, mem::{frame_allocator::frame_alloc_n, PAGE_SIZE}, qemu_print, qemu_println};
+use crate::arch_spec::port::{request_region, IoPort};
+use crate::cpu::PercpuBlock;
 use crate::ipi::IpiKind;
-use crate::{push_preserved, push_scratch, pop_preserved, pop_scratch, swapgs_iff_ring3_fast, swapgs_iff_ring3_fast_errorcode, nop, conditional_swapgs_back_paranoid, conditional_swapgs_paranoid};
+use crate::initcall;
+use crate::initcall::InitCpuArg;
+use crate::{push_preserved, push_scratch, pop_preserved, pop_scratch, swapgs_iff_ring3_fast, swapgs_iff_ring3_fast_errorcode, nop, conditional_swapgs_back_paranoid, conditional_swapgs_paranoid, nmi_enter, nmi_exit};
 use crate::context::list::{context_storage, ContextStorage};
+use crate::context::coredump::user_fault;
+use crate::device::com::{self, Com};
+use crate::device::pic;
+use crate::device::user_irq::deliver as deliver_user_irq;
+use libvdso::flag::{SIGBUS, SIGFPE, SIGILL, SIGSEGV};
 
 const DEPENDENT_STACK_SIZE: usize = 65536;
+pub const NMI_STACK_SIZE: usize = 65536;
+const MACHINE_CHECK_STACK_SIZE: usize = 65536;
+// tss interrupt stack table slots, every cpu has its own stacks
+const DOUBLE_FAULT_IST: u8 = 0;
+const NMI_IST: u8 = 1;
+const MACHINE_CHECK_IST: u8 = 2;
 pub const LAPIC_TIMER_HANDLER_IDT: u32 = 48;
 
+const IRQ_COUNTER_INIT: AtomicUsize = AtomicUsize::new(0);
+// interrupt counters indexed by idt vector, shared by all cpus
+static IRQ_COUNTERS: [AtomicUsize; 256] = [IRQ_COUNTER_INIT; 256];
+
 lazy_static! {
     static ref IDTS: RwLock<BTreeMap<LogicalCpuId, &'static mut InterruptDescriptorTable>> = RwLock::new(BTreeMap::new());
 }
 
+unsafe fn idt_initcall(arg: &InitCpuArg) {
+    init_idt(arg.cpu_id);
+}
+initcall!(arch, All, idt_initcall, order = 10);
+
 pub unsafe fn init_idt(cpu_id: LogicalCpuId) {
     let mut idts_guard = IDTS.write();
     idts_guard.insert(cpu_id, Box::leak(Box::new(InterruptDescriptorTable::new())));
     let idt = idts_guard.get_mut(&cpu_id).or_panic("failed to get idt");
 
-    let dependent_ist = {
-        let index = 0_u8;
-        let stack = frame_alloc_n(DEPENDENT_STACK_SIZE / PAGE_SIZE)
+    let alloc_ist = |index: u8, size: usize| {
+        let stack = frame_alloc_n(size / PAGE_SIZE)
             .or_panic("failed to allocate backup dependent stack");
 
         (*pcr()).tss.interrupt_stack_table[usize::from(index)] = 
-            VirtAddr::new(stack.start_address().as_u64()) + DEPENDENT_STACK_SIZE;
+            VirtAddr::new(stack.start_address().as_u64()) + size;
 
         index
     };
+    let dependent_ist = alloc_ist(DOUBLE_FAULT_IST, DEPENDENT_STACK_SIZE);
+    // may hit the paranoid swapgs window or a kernel stack about to overflow
+    let nmi_ist = alloc_ist(NMI_IST, NMI_STACK_SIZE);
+    let machine_check_ist = alloc_ist(MACHINE_CHECK_IST, MACHINE_CHECK_STACK_SIZE);
 
     // exceptions
     idt.breakpoint.set_handler_addr(VirtAddr::new(breakpoint as u64))
@@ -52,13 +85,15 @@ pub unsafe fn init_idt(cpu_id: LogicalCpuId) {
 
     idt.divide_error.set_handler_addr(VirtAddr::new(divide_error as u64));
     idt.debug.set_handler_addr(VirtAddr::new(debug as u64));
-    idt.non_maskable_interrupt.set_handler_addr(VirtAddr::new(non_maskable_interrupt as u64));
+    idt.non_maskable_interrupt.set_handler_addr(VirtAddr::new(non_maskable_interrupt as u64))
+        .set_stack_index(nmi_ist.into());
     idt.overflow.set_handler_addr(VirtAddr::new(overflow as u64));
     idt.bound_range_exceeded.set_handler_addr(VirtAddr::new(bound_range_exceeded as u64));
     idt.invalid_opcode.set_handler_addr(VirtAddr::new(invalid_opcode as u64));
     idt.device_not_available.set_handler_addr(VirtAddr::new(device_not_available as u64));
     idt.hv_injection_exception.set_handler_addr(VirtAddr::new(hv_injection_exception as u64));
-    idt.machine_check.set_handler_addr(VirtAddr::new(machine_check as u64));
+    idt.machine_check.set_handler_addr(VirtAddr::new(machine_check as u64))
+        .set_stack_index(machine_check_ist.into());
     idt.simd_floating_point.set_handler_addr(VirtAddr::new(simd_floating_point as u64));
     idt.virtualization.set_handler_addr(VirtAddr::new(virtualization as u64));
     idt.x87_floating_point.set_handler_addr(VirtAddr::new(x87_floating_point as u64));
@@ -97,6 +132,10 @@ pub unsafe fn init_idt(cpu_id: LogicalCpuId) {
     idt[IpiKind::Wakeup as usize].set_handler_addr(VirtAddr::new(ipi_wakeup as u64));
     idt[IpiKind::Switch as usize].set_handler_addr(VirtAddr::new(ipi_switch as u64));
     idt[IpiKind::Pit as usize].set_handler_addr(VirtAddr::new(ipi_pit as u64));
+    idt[IpiKind::Halt as usize].set_handler_addr(VirtAddr::new(ipi_halt as u64));
+    idt[IpiKind::Profile as usize].set_handler_addr(VirtAddr::new(ipi_profile as u64));
+    idt[IpiKind::Debug as usize].set_handler_addr(VirtAddr::new(ipi_debug as u64));
+    idt[IpiKind::TlbShootdown as usize].set_handler_addr(VirtAddr::new(ipi_tlb_shootdown as u64));
 
     idt.load_unsafe();
     infohart!("interrupt descriptor table is initialized.")
@@ -109,12 +148,22 @@ pub unsafe fn write_idt_gate(cpu_id: LogicalCpuId, entry_index: usize, handler:
     idt[entry_index].set_handler_addr(VirtAddr::new(handler));
 }
 
+#[inline(always)]
+fn count_irq(vector: usize) {
+    IRQ_COUNTERS[vector].fetch_add(1, Ordering::Relaxed);
+}
+
+/// how many times the interrupt of `vector` has been triggered since boot
+pub fn irq_count(vector: usize) -> usize {
+    IRQ_COUNTERS[vector].load(Ordering::Relaxed)
+}
+
 /// Set interrupts and halt
 /// This will atomically wait for the next interrupt
 /// Performing enable followed by halt is not guaranteed to be atomic, use this instead!
 #[inline(always)]
 pub unsafe fn enable_and_halt() {
-    core::arch::asm!("sti; hlt", options(nomem, nostack));
+    CurrentArch::enable_interrupts_and_halt()
 }
 
 /// Set interrupts and nop
@@ -122,27 +171,46 @@ pub unsafe fn enable_and_halt() {
 /// Simply enabling interrupts does not gurantee that they will trigger, use this instead!
 #[inline(always)]
 pub unsafe fn enable_and_nop() {
-    core::arch::asm!("sti; nop", options(nomem, nostack));
+    CurrentArch::enable_interrupts_and_nop()
 }
 
 // exceptions
-interrupt_stack!(divide_error, |stack| { qemu_println!("divide_error: stack: {:?}", stack) });
-interrupt_stack!(debug, @paranoid, |stack| { qemu_println!("debug: stack: {:?}", stack) });
-interrupt_stack!(non_maskable_interrupt, @paranoid, |stack| { qemu_println!("non_maskable_interrupt: stack: {:?}", stack) });
+interrupt_stack!(divide_error, |stack| { user_fault(stack, SIGFPE, "divide error"); qemu_println!("divide_error: stack: {:?}", stack) });
+interrupt_stack!(debug, @paranoid, |stack| {
+    if watchpoints::handle_debug(&stack.iret) {
+        return;
+    }
+    qemu_println!("debug: stack: {:?}", stack)
+});
+interrupt_stack!(non_maskable_interrupt, @nmi, |stack| {
+    if pmu::handle_pmi(&stack.iret) {
+        return;
+    }
+    qemu_println!("non_maskable_interrupt: stack: {:?}", stack)
+});
 interrupt_stack!(breakpoint, |stack| { qemu_println!("breakpoint: stack: {:?}", stack) });
-interrupt_stack!(overflow, |stack| { qemu_println!("overflow: stack: {:?}", stack) });
-interrupt_stack!(bound_range_exceeded, |stack| { qemu_println!("bound_range_exceeded: stack: {:?}", stack) });
-interrupt_stack!(invalid_opcode, |stack| { qemu_println!("invalid_opcode: stack: {:?}", stack) });
+interrupt_stack!(overflow, |stack| { user_fault(stack, SIGSEGV, "overflow"); qemu_println!("overflow: stack: {:?}", stack) });
+interrupt_stack!(bound_range_exceeded, |stack| { user_fault(stack, SIGSEGV, "bound range exceeded"); qemu_println!("bound_range_exceeded: stack: {:?}", stack) });
+interrupt_stack!(invalid_opcode, |stack| { user_fault(stack, SIGILL, "invalid opcode"); qemu_println!("invalid_opcode: stack: {:?}", stack) });
 interrupt_stack!(device_not_available, |stack| { qemu_println!("device_not_available: stack: {:?}", stack) });
 interrupt_stack!(hv_injection_exception, |stack| { qemu_println!("hv_injection_exception: stack: {:?}", stack) });
-interrupt_stack!(machine_check, |stack| { qemu_println!("machine_check: stack: {:?}", stack) });
-interrupt_stack!(simd_floating_point, |stack| { qemu_println!("simd_floating_point: stack: {:?}", stack) });
+interrupt_stack!(machine_check, @paranoid, |stack| { qemu_println!("machine_check: stack: {:?}", stack) });
+interrupt_stack!(simd_floating_point, |stack| { user_fault(stack, SIGFPE, "simd floating point"); qemu_println!("simd_floating_point: stack: {:?}", stack) });
 interrupt_stack!(virtualization, |stack| { qemu_println!("virtualization: stack: {:?}", stack) });
-interrupt_stack!(x87_floating_point, |stack| { qemu_println!("x87_floating_point: stack: {:?}", stack) });
+interrupt_stack!(x87_floating_point, |stack| { user_fault(stack, SIGFPE, "x87 floating point"); qemu_println!("x87_floating_point: stack: {:?}", stack) });
 interrupt_stack!(cp_protection_exception, |stack| { qemu_println!("page_fault, stack: {:?}", stack) });
 interrupt_stack!(vmm_communication_exception, |stack| { qemu_println!("page_fault, stack: {:?}", stack) });
 
 interrupt_error!(page_fault, |stack, code| {
+    // a write to a merged page, it has its own frame now
+    if ksm::write_fault(code) {
+        return;
+    }
+    // kernel copying from or to user memory
+    if user_copy_fixup(stack) {
+        return;
+    }
+    user_fault(stack, SIGSEGV, "page fault");
     let slice = from_raw_parts((stack.iret.rsp - 0x48) as *const u8, 0x48usize);
     qemu_println!("calle stacks: {:02x?}", slice);
 
@@ -152,14 +220,49 @@ interrupt_error!(page_fault, |stack, code| {
 interrupt_error!(invalid_tss, |stack, code| { qemu_println!("invalid_tss: {}, stack: {:?}", code, stack) });
 interrupt_error!(double_fault, |stack, code| { qemu_println!("double_fault: {}, stack: {:?}", code, stack) });
 interrupt_error!(segment_not_present, |stack, code| { qemu_println!("segment_not_present: {}, stack: {:?}", code, stack) });
-interrupt_error!(stack_segment_fault, |stack, code| { qemu_println!("stack_segment_fault: {}, stack: {:?}", code, stack) });
-interrupt_error!(general_protection_fault, |stack, code| { qemu_println!("general_protection_fault: {}, stack: {:?}", code, stack) });
-interrupt_error!(alignment_check, |stack, code| { qemu_println!("alignment_check: {}, stack: {:?}", code, stack) });
+interrupt_error!(stack_segment_fault, |stack, code| { user_fault(stack, SIGBUS, "stack segment fault"); qemu_println!("stack_segment_fault: {}, stack: {:?}", code, stack) });
+interrupt_error!(general_protection_fault, |stack, code| {
+    // probing an unimplemented msr
+    if msr_probe_fixup(stack) {
+        return;
+    }
+    user_fault(stack, SIGSEGV, "general protection fault");
+    qemu_println!("general_protection_fault: {}, stack: {:?}", code, stack)
+});
+interrupt_error!(alignment_check, |stack, code| { user_fault(stack, SIGBUS, "alignment check"); qemu_println!("alignment_check: {}, stack: {:?}", code, stack) });
 interrupt_error!(security_exception, |stack, code| { qemu_println!("security_exception: {}, stack: {:?}", code, stack) });
 
-// legacy irqs
-interrupt!(pit_stack, || { LOCAL_APIC.eoi() });
+// legacy irqs, acked at the pic when no io apic routes them
+unsafe fn legacy_eoi(irq: u8) {
+    if pic::routed() {
+        pic::eoi(irq)
+    } else {
+        LOCAL_APIC.eoi()
+    }
+}
+
+interrupt!(pit_stack, || {
+    count_irq(32);
+    // a reschedule once the quantum is used up, served on interrupt exit
+    PercpuBlock::current().context_switch.tick();
+    legacy_eoi(0)
+});
+// i8042 data port, claimed by `keyboard_initcall`
+static KEYBOARD_DATA: spin::Once<IoPort<u8>> = spin::Once::new();
+
+unsafe fn keyboard_initcall(_: &InitCpuArg) {
+    if let Ok(region) = request_region(0x60, 1, "i8042 data") {
+        KEYBOARD_DATA.call_once(|| region.port(0));
+    }
+}
+initcall!(device, Bsp, keyboard_initcall);
+
 interrupt!(keyboard, || {
+    count_irq(33);
+    let Some(data_port) = KEYBOARD_DATA.get() else {
+        legacy_eoi(1);
+        return;
+    };
     use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
     use spin::Mutex;
 
@@ -168,43 +271,141 @@ interrupt!(keyboard, || {
             Mutex::new(Keyboard::new(ScancodeSet1::new(), layouts::Us104Key, HandleControl::Ignore));
     };
 
-    let data: u8 = inb(0x60);
-    LOCAL_APIC.eoi();
+    let data: u8 = data_port.read();
+    legacy_eoi(1);
 
     let mut keyboard = KB.lock();
     if let Ok(Some(key_event)) = keyboard.add_byte(data) {
         if let Some(key) = keyboard.process_keyevent(key_event) {
             match key {
-                DecodedKey::Unicode(character) => qemu_print!("{}", character),
+                DecodedKey::Unicode(character) => {
+                    qemu_print!("{}", character);
+                    crate::device::keyboard::push_key(character);
+                }
                 DecodedKey::RawKey(key) => qemu_print!("{:?}", key),
             }
         }
     }
 });
-interrupt!(cascade, || { LOCAL_APIC.eoi() });
-interrupt!(com2, || { LOCAL_APIC.eoi() });
-interrupt!(com1, || { LOCAL_APIC.eoi() });
-interrupt!(lpt2, || { LOCAL_APIC.eoi() });
-interrupt!(floppy, || { LOCAL_APIC.eoi() });
-interrupt!(lpt1, || { LOCAL_APIC.eoi() });
-interrupt!(rtc, || { LOCAL_APIC.eoi() });
-interrupt!(pci1, || { LOCAL_APIC.eoi() });
-interrupt!(pci2, || { LOCAL_APIC.eoi() });
-interrupt!(pci3, || { LOCAL_APIC.eoi() });
-interrupt!(mouse, || { LOCAL_APIC.eoi() });
-interrupt!(fpu, || { LOCAL_APIC.eoi() });
-interrupt!(ata1, || { LOCAL_APIC.eoi() });
-interrupt!(ata2, || { LOCAL_APIC.eoi() });
-interrupt!(lapic_timer, || { LOCAL_APIC.eoi() });
-interrupt!(lapic_error, || { });
+interrupt!(cascade, || {
+    count_irq(34);
+    legacy_eoi(2)
+});
+interrupt!(com2, || {
+    count_irq(35);
+    if !deliver_user_irq(3) {
+        com::handle_irq(Com::Com2);
+    }
+    legacy_eoi(3)
+});
+interrupt!(com1, || {
+    count_irq(36);
+    if !deliver_user_irq(4) {
+        com::handle_irq(Com::Com1);
+    }
+    legacy_eoi(4)
+});
+interrupt!(lpt2, || {
+    count_irq(37);
+    deliver_user_irq(5);
+    legacy_eoi(5)
+});
+interrupt!(floppy, || {
+    count_irq(38);
+    deliver_user_irq(6);
+    legacy_eoi(6)
+});
+interrupt!(lpt1, || {
+    count_irq(39);
+    deliver_user_irq(7);
+    legacy_eoi(7)
+});
+interrupt!(rtc, || {
+    count_irq(40);
+    deliver_user_irq(8);
+    legacy_eoi(8)
+});
+interrupt!(pci1, || {
+    count_irq(41);
+    deliver_user_irq(9);
+    legacy_eoi(9)
+});
+interrupt!(pci2, || {
+    count_irq(42);
+    deliver_user_irq(10);
+    legacy_eoi(10)
+});
+interrupt!(pci3, || {
+    count_irq(43);
+    deliver_user_irq(11);
+    legacy_eoi(11)
+});
+interrupt!(mouse, || {
+    count_irq(44);
+    deliver_user_irq(12);
+    legacy_eoi(12)
+});
+interrupt!(fpu, || {
+    count_irq(45);
+    deliver_user_irq(13);
+    legacy_eoi(13)
+});
+interrupt!(ata1, || {
+    count_irq(46);
+    deliver_user_irq(14);
+    legacy_eoi(14)
+});
+interrupt!(ata2, || {
+    count_irq(47);
+    deliver_user_irq(15);
+    legacy_eoi(15)
+});
+interrupt!(lapic_timer, || {
+    count_irq(LAPIC_TIMER_HANDLER_IDT as usize);
+    // system tick unless the pit ticks, see `local_apic::setup_tick`
+    PercpuBlock::current().context_switch.tick();
+    retune_tick();
+    LOCAL_APIC.eoi()
+});
+interrupt!(lapic_error, || { count_irq(49) });
 
 // ipis
 interrupt!(ipi_wakeup, || {
+    count_irq(IpiKind::Wakeup as usize);
     infohart!("ipi wakeup");
     LOCAL_APIC.eoi()
 });
-interrupt!(ipi_switch, || { LOCAL_APIC.eoi() });
-interrupt!(ipi_pit, || { LOCAL_APIC.eoi() });
+interrupt!(ipi_switch, || {
+    count_irq(IpiKind::Switch as usize);
+    PercpuBlock::current().context_switch.set_need_resched();
+    LOCAL_APIC.eoi()
+});
+interrupt!(ipi_pit, || {
+    count_irq(IpiKind::Pit as usize);
+    LOCAL_APIC.eoi()
+});
+interrupt!(ipi_profile, || {
+    count_irq(IpiKind::Profile as usize);
+    pmu::apply();
+    LOCAL_APIC.eoi()
+});
+interrupt!(ipi_debug, || {
+    count_irq(IpiKind::Debug as usize);
+    watchpoints::apply();
+    LOCAL_APIC.eoi()
+});
+interrupt!(ipi_tlb_shootdown, || {
+    count_irq(IpiKind::TlbShootdown as usize);
+    tlb::flush_all();
+    LOCAL_APIC.eoi()
+});
+// another cpu is rebooting or panicking, never returns
+interrupt_stack!(ipi_halt, |stack| {
+    count_irq(IpiKind::Halt as usize);
+    LOCAL_APIC.eoi();
+    crate::crashdump::save_cpu_regs(stack);
+    crate::power::park_cpu()
+});
 
 
 #[test_case]
@@ -114,7 +114,7 @@ macro_rules! conditional_swapgs_paranoid {
     // if the interrupt handler should be allowed to context switch, which the current #DB handler
     // may do.)
     //
-    // TODO: Handle nested NMIs like Linux does (https://lwn.net/Articles/484932/)?.
+    // Nested NMIs are caught before this runs, see `nmi_enter`.
 
     () => { concat!(
         // Put the GDT base pointer in RDI.
@@ -159,10 +159,104 @@ macro_rules! conditional_swapgs_back_paranoid {
     };
 }
 #[macro_export]
+macro_rules! nmi_enter {
+    // NMIs run on their own IST stack, and the IST always resets RSP to its top. NMIs stay blocked
+    // until the next IRETQ, but any exception taken by the NMI handler returns with IRETQ, after
+    // which a second NMI would overwrite the return frame of the first one. Like Linux
+    // (https://lwn.net/Articles/484932/), the first NMI keeps two copies of its frame below the
+    // hardware one and returns through the lower copy. A nested NMI leaves the copies alone and
+    // only redirects the lower one to `repeat`, the first NMI then runs the handler once more
+    // from the untouched upper copy instead of returning. Further NMIs are coalesced into that.
+    //
+    // Layout relative to the IST top T:
+    //
+    //     T-40  .. T      hardware frame, overwritten by nested NMIs
+    //     T-48            saved RDX
+    //     T-56            "executing" flag
+    //     T-96  .. T-56   outermost frame copy
+    //     T-136 .. T-96   IRETQ frame copy, RIP redirected by nested NMIs
+    () => {
+        "
+        push rdx
+
+        // NMIs from usermode are never nested.
+        test QWORD PTR [rsp + 16], 0x3
+        jnz 64f
+
+        // Interrupted `repeat` itself, the first NMI is about to run the handler again.
+        lea rdx, [rip + 62f]
+        cmp [rsp + 8], rdx
+        jb 60f
+        lea rdx, [rip + 63f]
+        cmp [rsp + 8], rdx
+        jb 61f
+        60:
+
+        cmp QWORD PTR [rsp - 8], 0
+        jne 65f
+
+        // Interrupted on this stack, between clearing the flag and the final IRETQ.
+        lea rdx, [rsp + 48]
+        cmp [rsp + 32], rdx
+        ja 64f
+        sub rdx, {NMI_STACK_SIZE}
+        cmp [rsp + 32], rdx
+        jbe 64f
+
+        65:
+        // Nested: return from the first NMI into `repeat`, on this stack.
+        lea rdx, [rip + 62f]
+        mov [rsp - 88], rdx
+        mov rdx, cs
+        mov [rsp - 80], rdx
+        mov QWORD PTR [rsp - 72], 0x2
+        lea rdx, [rsp - 48]
+        mov [rsp - 64], rdx
+        mov rdx, ss
+        mov [rsp - 56], rdx
+
+        61:
+        pop rdx
+        iretq
+
+        64:
+        // First NMI, copy the hardware frame to the outermost frame.
+        mov QWORD PTR [rsp - 8], 1
+        sub rsp, 8
+        push QWORD PTR [rsp + 48]
+        push QWORD PTR [rsp + 48]
+        push QWORD PTR [rsp + 48]
+        push QWORD PTR [rsp + 48]
+        push QWORD PTR [rsp + 48]
+        mov rdx, [rsp + 48]
+
+        62:
+        // RSP is T-96 here, whether from above or from the nested IRETQ.
+        mov QWORD PTR [rsp + 40], 1
+        push QWORD PTR [rsp + 32]
+        push QWORD PTR [rsp + 32]
+        push QWORD PTR [rsp + 32]
+        push QWORD PTR [rsp + 32]
+        push QWORD PTR [rsp + 32]
+        63:
+    "
+    };
+}
+#[macro_export]
+macro_rules! nmi_exit {
+    // Clear the flag right before returning through the IRETQ frame copy. A nested NMI arriving
+    // in between still sees RSP on this stack.
+    () => {
+        "
+        mov QWORD PTR [rsp + 80], 0
+    "
+    };
+}
+#[macro_export]
 macro_rules! nop {
     () => {
         "
-        // Unused: {IA32_GS_BASE} {PCR_GDT_OFFSET}
+        // Unused: {IA32_GS_BASE} {PCR_GDT_OFFSET} {NMI_STACK_SIZE}
         "
     };
 }
@@ -175,9 +269,24 @@ macro_rules! interrupt_stack {
         #[naked]
         pub unsafe extern "C" fn $name() {
             unsafe extern "C" fn inner($stack: &mut $crate::syscall::InterruptStack) {
-                #[allow(unused_unsafe)]
-                unsafe {
-                    $code
+                $crate::arch_spec::frame_check::debug_check_entry(&$stack.iret);
+                // paranoid handlers may interrupt an entry before its fs swap
+                if !$is_paranoid {
+                    $crate::arch_spec::tls::enter(&$stack.iret);
+                }
+                // handlers may return early, the closure keeps the exit path below reachable
+                #[allow(unused_unsafe, unreachable_code, clippy::redundant_closure_call)]
+                (|| {
+                    let _irq = $crate::sync::IrqContextGuard::enter();
+                    unsafe {
+                        $code
+                    }
+                })();
+                // paranoid handlers may interrupt anything, including a switch
+                #[allow(unreachable_code)]
+                if !$is_paranoid {
+                    $crate::context::preempt::irq_exit(&$stack.iret);
+                    $crate::arch_spec::tls::exit(&$stack.iret);
                 }
             }
             core::arch::asm!(concat!(
@@ -209,9 +318,10 @@ macro_rules! interrupt_stack {
             ),
 
             inner = sym inner,
-            IA32_GS_BASE = const 0xc0000101u32,
+            IA32_GS_BASE = const $crate::arch_spec::msr::Msr::IA32_GS_BASE.number(),
 
             PCR_GDT_OFFSET = const(core::mem::offset_of!(crate::gdt::ProcessorControlRegion, gdt)),
+            NMI_STACK_SIZE = const $crate::interrupt::NMI_STACK_SIZE,
 
             options(noreturn),
 
@@ -219,7 +329,8 @@ macro_rules! interrupt_stack {
         }
     };
     ($name:ident, |$stack:ident| $code:block) => { interrupt_stack!($name, swapgs_iff_ring3_fast!, nop!, nop!, swapgs_iff_ring3_fast!, is_paranoid: false, |$stack| $code); };
-    ($name:ident, @paranoid, |$stack:ident| $code:block) => { interrupt_stack!($name, nop!, conditional_swapgs_paranoid!, conditional_swapgs_back_paranoid!, nop!, is_paranoid: true, |$stack| $code); }
+    ($name:ident, @paranoid, |$stack:ident| $code:block) => { interrupt_stack!($name, nop!, conditional_swapgs_paranoid!, conditional_swapgs_back_paranoid!, nop!, is_paranoid: true, |$stack| $code); };
+    ($name:ident, @nmi, |$stack:ident| $code:block) => { interrupt_stack!($name, nmi_enter!, conditional_swapgs_paranoid!, conditional_swapgs_back_paranoid!, nmi_exit!, is_paranoid: true, |$stack| $code); }
 }
 
 #[macro_export]
@@ -227,8 +338,17 @@ macro_rules! interrupt {
     ($name:ident, || $code:block) => {
         #[naked]
         pub unsafe extern "C" fn $name() {
-            unsafe extern "C" fn inner() {
-                $code
+            unsafe extern "C" fn inner(iret: &$crate::syscall::IretRegisters) {
+                $crate::arch_spec::frame_check::debug_check_entry(iret);
+                $crate::arch_spec::tls::enter(iret);
+                // handlers may return early, the closure keeps the exit path below reachable.
+                // a panic inside may be recovered, the closure then returns right away
+                $crate::sync::irq_guard::guarded(stringify!($name), || {
+                    let _irq = $crate::sync::IrqContextGuard::enter();
+                    $code
+                });
+                $crate::context::preempt::irq_exit(iret);
+                $crate::arch_spec::tls::exit(iret);
             }
 
             core::arch::asm!(concat!(
@@ -240,7 +360,8 @@ macro_rules! interrupt {
                 "push rax\n",
                 push_scratch!(),
 
-                // Call inner function with pointer to stack
+                // Call inner function with pointer to iret frame
+                "lea rdi, [rsp + {scratch_size}]\n",
                 "call {inner}\n",
 
                 // Restore all userspace registers
@@ -251,6 +372,7 @@ macro_rules! interrupt {
             ),
 
             inner = sym inner,
+            scratch_size = const(::core::mem::size_of::<$crate::syscall::ScratchRegisters>()),
 
             options(noreturn),
             );
@@ -264,10 +386,20 @@ macro_rules! interrupt_error {
         #[naked]
         pub unsafe extern "C" fn $name() {
             unsafe extern "C" fn inner($stack: &mut $crate::syscall::InterruptStack, $error_code: usize) {
-                #[allow(unused_unsafe)]
-                unsafe {
-                    $code
-                }
+                $crate::arch_spec::frame_check::debug_check_entry(&$stack.iret);
+                $crate::arch_spec::tls::enter(&$stack.iret);
+                // handlers may return early, the closure keeps the exit path below reachable
+                #[allow(unused_unsafe, unreachable_code, clippy::redundant_closure_call)]
+                (|| {
+                    let _irq = $crate::sync::IrqContextGuard::enter();
+                    unsafe {
+                        $code
+                    }
+                })();
+                #[allow(unreachable_code)]
+                $crate::context::preempt::irq_exit(&$stack.iret);
+                #[allow(unreachable_code)]
+                $crate::arch_spec::tls::exit(&$stack.iret);
             }
 
             core::arch::asm!(concat!(
@@ -1,5 +1,7 @@
 use crate::cpu::LogicalCpuId;
 use crate::acpi::local_apic::LOCAL_APIC;
+use crate::context::trace::record_ipi;
+use crate::idle::kick_idle;
 
 #[derive(Clone, Copy, Debug)]
 #[repr(u8)]
@@ -7,6 +9,13 @@ pub enum IpiKind {
     Wakeup = 0x40,
     Switch = 0x42,
     Pit = 0x43,
+    Halt = 0x44,
+    // reprogram performance counters from the profiler config
+    Profile = 0x45,
+    // reload debug registers from the published watchpoints
+    Debug = 0x46,
+    // flush the whole tlb, see `mem::tlb`
+    TlbShootdown = 0x47,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -20,12 +29,17 @@ pub enum IpiTarget {
 #[inline(always)]
 pub fn ipi(kind: IpiKind, target: IpiTarget) {
     let icr = (target as u64) << 18 | 1 << 14 | (kind as u64);
+    record_ipi();
     unsafe { LOCAL_APIC.set_icr(icr) };
 }
 
 #[inline(always)]
 pub fn ipi_single(kind: IpiKind, target: LogicalCpuId) {
+    if let IpiKind::Wakeup = kind {
+        kick_idle(target);
+    }
+    record_ipi();
     unsafe {
-        LOCAL_APIC.ipi(u32::from(target.0), kind);
+        LOCAL_APIC.ipi(target.0, kind);
     }
 }
\ No newline at end of file
@@ -0,0 +1,107 @@
+use alloc::vec::Vec;
+use core::fmt;
+use libvdso::flag::{CAP_ADMIN, CAP_IO};
+use crate::context::context_id;
+use crate::device::tsc::monotonic_ns;
+use crate::logger::ring::{LogRing, LOG_LINE_MAX};
+use crate::sync::IrqSpinlock;
+
+/**
+ *  audit log of security relevant events.
+ *
+ *  a ring of its own next to the debug log: log levels do not filter it and
+ *  chatty drivers do not evict it. a record is one line
+ *
+ *      <uptime ns> ctx <id> <kind>: <details>
+ *
+ *  with the context that caused it, kinds are
+ *      cap     a privileged syscall checked a capability, granted or denied
+ *      cred    setuid, setgid, capdrop
+ *      io      ports granted or taken back by ioperm and iopl
+ *      efault  a user pointer that failed validation
+ *      kill    a context killed by a fault or a resource limit
+ *
+ *  `audit_read` (CAP_ADMIN) hands out the records not read yet and consumes
+ *  them, records overwritten before are counted in a line of their own.
+ */
+
+static AUDIT_RING: IrqSpinlock<LogRing> = IrqSpinlock::new(LogRing::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditKind {
+    Cap,
+    Cred,
+    Io,
+    Efault,
+    Kill,
+}
+
+impl AuditKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            AuditKind::Cap => "cap",
+            AuditKind::Cred => "cred",
+            AuditKind::Io => "io",
+            AuditKind::Efault => "efault",
+            AuditKind::Kill => "kill",
+        }
+    }
+}
+
+/// name of the capability bits `cap` for records
+pub fn cap_name(cap: u64) -> &'static str {
+    match cap {
+        CAP_IO => "io",
+        CAP_ADMIN => "admin",
+        _ => "mixed",
+    }
+}
+
+/// record `args` for the current context, never blocks on context locks
+pub fn audit(kind: AuditKind, args: fmt::Arguments) {
+    let (now, id) = (monotonic_ns(), context_id().get());
+    AUDIT_RING.lock().push_fmt(format_args!("{} ctx {} {}: {}", now, id, kind.name(), args));
+}
+
+/// consume whole records that fit in `max` bytes, newlines included. a record
+/// longer than `max` is cut when it comes first, so a reader always makes progress
+pub fn take_records(max: usize) -> Vec<u8> {
+    let mut ring = AUDIT_RING.lock();
+    let mut out = Vec::new();
+    let dropped = ring.take_dropped();
+    if dropped != 0 {
+        out.extend_from_slice(alloc::format!("{} records dropped\n", dropped).as_bytes());
+    }
+    let mut line = [0u8; LOG_LINE_MAX];
+    while let Some(len) = ring.front_len() {
+        if out.len() + len + 1 > max && !out.is_empty() {
+            break;
+        }
+        let len = ring.pop_line(&mut line).unwrap_or(0);
+        out.extend_from_slice(&line[..len]);
+        out.push(b'\n');
+    }
+    out.truncate(max);
+    out
+}
+
+#[test_case]
+pub(crate) fn test_audit_records() {
+    take_records(usize::MAX);
+    audit(AuditKind::Cap, format_args!("{} granted", cap_name(CAP_IO)));
+    audit(AuditKind::Kill, format_args!("context 7 by SIGXCPU"));
+
+    // one record at a time when only one fits
+    let len = AUDIT_RING.lock().front_len().unwrap();
+    let first = take_records(len + 1);
+    let first = core::str::from_utf8(&first).unwrap();
+    assert!(first.ends_with(" cap: io granted\n"), "{}", first);
+    let second = take_records(usize::MAX);
+    assert!(second.ends_with(b" kill: context 7 by SIGXCPU\n"));
+    assert_eq!(second.iter().filter(|byte| **byte == b'\n').count(), 1);
+
+    // a record longer than the buffer is cut, not stuck
+    audit(AuditKind::Efault, format_args!("{:#x} + {:#x}", 0x1000, 8));
+    assert_eq!(take_records(4).len(), 4);
+    assert!(take_records(usize::MAX).is_empty());
+}
@@ -0,0 +1,127 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+use log::{LevelFilter, Metadata};
+use libvdso::error::{EINVAL, ENOSPC, KError, KResult};
+use crate::sync::IrqSpinlock;
+
+/**
+ *  runtime log level and per-target filters.
+ *
+ *  the global level applies to every target without a filter. a filter
+ *  matches its target and every module below it (`kernel::mem` covers
+ *  `kernel::mem::paging`), the longest matching filter wins. the max level of
+ *  `log` is kept at the most verbose of all of them so records reach the
+ *  logger, which drops the ones their filter rejects.
+ *
+ *  set from cmdline `loglevel=`, the `log_level` syscall and `loglevel` in
+ *  the debug shell.
+ */
+
+pub const MAX_FILTERS: usize = 16;
+pub const FILTER_TARGET_LEN: usize = 48;
+
+#[derive(Clone, Copy)]
+struct Filter {
+    target: [u8; FILTER_TARGET_LEN],
+    target_len: usize,
+    level: LevelFilter,
+}
+
+impl Filter {
+    fn target(&self) -> &str {
+        // only built from a &str, cut at a char boundary
+        unsafe { core::str::from_utf8_unchecked(&self.target[..self.target_len]) }
+    }
+
+    fn matches(&self, target: &str) -> bool {
+        target.strip_prefix(self.target())
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+    }
+}
+
+static GLOBAL_LEVEL: AtomicUsize = AtomicUsize::new(LevelFilter::Debug as usize);
+static FILTERS: IrqSpinlock<[Option<Filter>; MAX_FILTERS]> = IrqSpinlock::new([None; MAX_FILTERS]);
+
+fn level_from_usize(level: usize) -> Option<LevelFilter> {
+    match level {
+        0 => Some(LevelFilter::Off),
+        1 => Some(LevelFilter::Error),
+        2 => Some(LevelFilter::Warn),
+        3 => Some(LevelFilter::Info),
+        4 => Some(LevelFilter::Debug),
+        5 => Some(LevelFilter::Trace),
+        _ => None,
+    }
+}
+
+pub fn global_level() -> LevelFilter {
+    level_from_usize(GLOBAL_LEVEL.load(Ordering::Relaxed)).unwrap_or(LevelFilter::Debug)
+}
+
+// `log` drops records above its max level before they reach the logger
+fn update_max_level(filters: &[Option<Filter>]) {
+    let max = filters.iter().flatten()
+        .map(|filter| filter.level)
+        .fold(global_level(), Ord::max);
+    log::set_max_level(max);
+}
+
+/// set the level of targets without a filter, returns the previous one
+pub fn set_global_level(level: LevelFilter) -> LevelFilter {
+    let filters = FILTERS.lock();
+    let prev = global_level();
+    GLOBAL_LEVEL.store(level as usize, Ordering::Relaxed);
+    update_max_level(&*filters);
+    prev
+}
+
+/// level in effect for `target`
+pub fn target_level(target: &str) -> LevelFilter {
+    FILTERS.lock().iter().flatten()
+        .filter(|filter| filter.matches(target))
+        .max_by_key(|filter| filter.target_len)
+        .map_or_else(global_level, |filter| filter.level)
+}
+
+/// whether the logger keeps a record with `metadata`
+pub fn enabled(metadata: &Metadata) -> bool {
+    metadata.level() <= target_level(metadata.target())
+}
+
+/// filter `target` and the modules below it by `level`, or drop its filter with `None`.
+/// returns the level the target had before.
+pub fn set_target_level(target: &str, level: Option<LevelFilter>) -> KResult<LevelFilter> {
+    if target.is_empty() || target.len() > FILTER_TARGET_LEN {
+        return Err(KError::new(EINVAL));
+    }
+    let prev = target_level(target);
+    let mut filters = FILTERS.lock();
+    let slot = filters.iter().position(|filter| filter.is_some_and(|filter| filter.target() == target));
+    match (slot, level) {
+        (Some(slot), level) => match (&mut filters[slot], level) {
+            (Some(filter), Some(level)) => filter.level = level,
+            (filter, _) => *filter = None,
+        },
+        (None, Some(level)) => {
+            let free = filters.iter().position(Option::is_none).ok_or(KError::new(ENOSPC))?;
+            let mut filter = Filter { target: [0; FILTER_TARGET_LEN], target_len: target.len(), level };
+            filter.target[..target.len()].copy_from_slice(target.as_bytes());
+            filters[free] = Some(filter);
+        }
+        (None, None) => {}
+    }
+    update_max_level(&*filters);
+    Ok(prev)
+}
+
+/// visit every filter as `(target, level)`
+pub fn for_each_filter(mut f: impl FnMut(&str, LevelFilter)) {
+    let filters = *FILTERS.lock();
+    for filter in filters.iter().flatten() {
+        f(filter.target(), filter.level);
+    }
+}
+
+/// parse a level number as passed by syscalls
+pub fn parse_level(level: usize) -> KResult<LevelFilter> {
+    level_from_usize(level).ok_or(KError::new(EINVAL))
+}
@@ -0,0 +1,89 @@
+use spin::Once;
+use crate::arch::{ArchInterrupts, CurrentArch};
+use crate::context::ContextId;
+use crate::context::list::{context_storage, context_storage_mut, try_context_storage};
+use crate::context::spawn::{SpawnEntry, SpawnOptions};
+use crate::context::status::Status;
+use crate::context::switch::switch_context;
+use crate::initcall;
+use crate::initcall::InitCpuArg;
+use crate::logger::flush_log;
+use crate::logger::ring::LOG_RING;
+
+/**
+ *  kernel thread rendering log ring to framebuffer.
+ *
+ *  `log()` only appends to the ring and wakes this thread, so interrupt handlers
+ *  never render glyphs. the thread renders a batch of lines then yields, a
+ *  burst of logs is spread over several scheduling rounds instead of holding
+ *  the cpu until the ring is drained.
+ */
+
+// lines rendered per scheduling round
+const FLUSH_BATCH_LINES: usize = 16;
+const FLUSHER_BLOCK_REASON: &str = "log flusher";
+
+static FLUSHER_ID: Once<ContextId> = Once::new();
+
+unsafe fn log_flusher_initcall(_: &InitCpuArg) {
+    // render what early boot left in ring before handing over
+    flush_log(usize::MAX);
+
+    match context_storage_mut().spawn(&SpawnOptions::kernel("klogd"), SpawnEntry::Func(log_flusher)) {
+        Ok(lock) => {
+            let mut context = lock.write();
+            context.set_status(Status::Runnable);
+            FLUSHER_ID.call_once(|| context.id);
+        }
+        Err(err) => panic!("failed to spawn log flusher: {:?}", err),
+    }
+}
+initcall!(late, Bsp, log_flusher_initcall, order = 200);
+
+pub fn flusher_started() -> bool {
+    FLUSHER_ID.get().is_some()
+}
+
+/// unblock flusher if log ring has pending lines.
+///
+/// never spins on context locks, it is called from `log()` in any context, including
+/// while the scheduler holds them. a missed wakeup is retried by the next log or by
+/// the scheduler loop.
+pub fn wake_log_flusher() {
+    let Some(id) = FLUSHER_ID.get() else { return };
+    if LOG_RING.lock().is_empty() {
+        return;
+    }
+    let Some(contexts) = try_context_storage() else { return };
+    let Some(context) = contexts.get(*id) else { return };
+    if let Some(mut context) = context.try_write() {
+        context.unblock_no_ipi();
+    }
+}
+
+extern "C" fn log_flusher() {
+    // new contexts start with interrupts disabled
+    unsafe { CurrentArch::enable_interrupts(); }
+
+    loop {
+        flush_log(FLUSH_BATCH_LINES);
+
+        {
+            let contexts = context_storage();
+            let mut context = contexts.current()
+                .expect("failed to get log flusher context")
+                .write();
+            // block before checking, a log pushed after the check finds us blocked and wakes us
+            context.soft_block(FLUSHER_BLOCK_REASON);
+            if !LOG_RING.lock().is_empty() {
+                context.unblock_no_ipi();
+            }
+        }
+
+        unsafe {
+            CurrentArch::disable_interrupts();
+            switch_context();
+            CurrentArch::enable_interrupts();
+        }
+    }
+}
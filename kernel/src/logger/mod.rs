@@ -1,44 +1,137 @@
-use log::{info, Log, log};
-use shared::{framebuffer::Framebuffer, framebuffer_writer::FrameBufferWriter, uni_processor::UPSafeCell};
-use spin::Mutex;
-use core::{fmt::Write, mem::MaybeUninit};
+use log::{info, log, warn};
+use shared::{font::FontConfig, framebuffer::Framebuffer, framebuffer_writer::{level_sgr, FrameBufferWriter, SGR_RESET}, uni_processor::UPSafeCell};
+use alloc::string::String;
+use core::{fmt::{self, Write}, mem::MaybeUninit};
 use lazy_static::lazy_static;
+use spin::Once;
 
-use crate::{device::qemu::exit_qemu, framebuffer::FRAMEBUFFER, qemu_println};
+use crate::{device::qemu::exit_qemu, framebuffer::framebuffer, qemu_println};
+use crate::device::com::COM1;
 use crate::gdt::pcr;
+use crate::logger::ring::{LOG_LINE_MAX, LOG_RING};
+use crate::sync::{in_irq, IrqSpinlock};
+
+pub mod audit;
+pub mod filter;
+pub mod flusher;
+pub mod ring;
 
 lazy_static! {
     pub static ref FRAMEBUFFER_LOGGER: UPSafeCell<MaybeUninit<FramebufferLogger<'static>>> = unsafe { UPSafeCell::new(MaybeUninit::uninit()) };
 }
 
+// shared reference for renderers, FRAMEBUFFER_LOGGER cell can not be borrowed concurrently
+static LOGGER: Once<&'static FramebufferLogger<'static>> = Once::new();
+
+/// where the console goes, com1 when headless
+pub enum Console<'a> {
+    Framebuffer(FrameBufferWriter<'a>),
+    Serial,
+}
+
+impl Write for Console<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        match self {
+            Console::Framebuffer(writer) => writer.write_str(s),
+            Console::Serial => COM1.lock().write_str(s),
+        }
+    }
+}
+
 pub struct FramebufferLogger<'a> {
-    pub writer: Mutex<FrameBufferWriter<'a>>,
+    // handlers log too
+    pub writer: IrqSpinlock<Console<'a>>,
 }
 
 impl <'a> FramebufferLogger<'a> {
-    pub fn new(framebuffer: &'a Framebuffer) -> Self {
+    pub fn new(framebuffer: &'a Framebuffer, font: FontConfig) -> Self {
         Self {
-            writer: Mutex::new(FrameBufferWriter::new(framebuffer))
+            writer: IrqSpinlock::new(Console::Framebuffer(FrameBufferWriter::with_font(framebuffer, font)))
         }
     }
+
+    pub fn serial() -> Self {
+        Self { writer: IrqSpinlock::new(Console::Serial) }
+    }
 }
 
 impl log::Log for FramebufferLogger<'_> {
-    fn enabled(&self, _metadata: &log::Metadata) -> bool {
-        true
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        filter::enabled(metadata)
     }
 
     fn log(&self, record: &log::Record) {
-        let mut fb_writter = self.writer.lock();
-        
-        let _ = writeln!(fb_writter, "[{:5}]{}", record.level(), record.args());
+        if !filter::enabled(record.metadata()) {
+            return;
+        }
+        LOG_RING.lock().push_fmt(format_args!("[{:5}]{}", record.level(), record.args()));
+
+        if flusher::flusher_started() {
+            flusher::wake_log_flusher();
+        } else if !in_irq() {
+            // early boot, nobody else would render it
+            flush_log(usize::MAX);
+        }
     }
 
     fn flush(&self) {
-        
+        flush_log(usize::MAX);
     }
 }
 
+/// render at most `max_lines` pending lines of log ring to screen, returns rendered lines
+pub fn flush_log(max_lines: usize) -> usize {
+    let Some(logger) = LOGGER.get() else { return 0 };
+    let mut writer = logger.writer.lock();
+    render_lines(&mut writer, max_lines)
+}
+
+/// write to the console bypassing log ring, pending lines are rendered first to keep the order
+pub fn console_write(args: fmt::Arguments) {
+    let Some(logger) = LOGGER.get() else { return };
+    let mut writer = logger.writer.lock();
+    render_lines(&mut writer, usize::MAX);
+    let _ = writer.write_fmt(args);
+}
+
+/// drain log ring on panic, the writer may be held by the panicking code
+pub fn panic_flush_log() {
+    let Some(logger) = LOGGER.get() else { return };
+    if let Some(mut writer) = logger.writer.try_lock() {
+        render_lines(&mut writer, usize::MAX);
+    }
+}
+
+fn render_lines(writer: &mut Console, max_lines: usize) -> usize {
+    let mut line = [0u8; LOG_LINE_MAX];
+    let mut rendered = 0;
+    while rendered < max_lines {
+        // ring lock is only held for the copy, rendering runs with it released
+        let (len, dropped) = {
+            let mut ring = LOG_RING.lock();
+            match ring.pop_line(&mut line) {
+                Some(len) => (len, ring.take_dropped()),
+                None => break,
+            }
+        };
+        if dropped != 0 {
+            let warn = level_sgr(log::Level::Warn);
+            let _ = writeln!(writer, "{}[ WARN]log ring overflowed, {} lines dropped{}", warn, dropped, SGR_RESET);
+        }
+        let text = String::from_utf8_lossy(&line[..len]);
+        let _ = writeln!(writer, "{}{}{}", line_sgr(&text), text, SGR_RESET);
+        rendered += 1;
+    }
+    rendered
+}
+
+// lines in ring start with `[LEVEL]`, color is only added on screen
+fn line_sgr(line: &str) -> &'static str {
+    line.get(1..6)
+        .and_then(|level| level.trim().parse::<log::Level>().ok())
+        .map_or("", level_sgr)
+}
+
 #[macro_export]
 macro_rules! loghart {
     ($lvl:expr, $($arg:tt)+) => {
@@ -67,21 +160,25 @@ macro_rules! errorhart {
     ($target:expr, $($arg:tt)+) => ($crate::loghart!(::log::Level::Error, $target, $($arg)+));
 }
 
-pub fn init_framebuffer_logger() {
-    let framebuffer = FRAMEBUFFER.inner_exclusive_mut();
-    let framebuffer = framebuffer.lock();
-    let framebuffer = unsafe { framebuffer.assume_init_ref() };
-
+/// log to the framebuffer console, or to com1 when headless
+pub fn init_framebuffer_logger(font: FontConfig) {
     let mut logger = FRAMEBUFFER_LOGGER.inner_exclusive_mut();
-    let logger_ref = logger.write(
-        FramebufferLogger::new(unsafe { &*(framebuffer as *const Framebuffer) })
-    );
+    let logger_ref = logger.write(match framebuffer() {
+        Some(framebuffer) => FramebufferLogger::new(framebuffer, font),
+        None => FramebufferLogger::serial(),
+    });
+
+    let logger_ref: &'static FramebufferLogger<'static> = unsafe { &*(logger_ref as *const _) };
+    LOGGER.call_once(|| logger_ref);
 
-    if let Err(err) = log::set_logger(unsafe { &*(logger_ref as *const dyn Log) }) {
+    if let Err(err) = log::set_logger(logger_ref) {
         qemu_println!("kernel failed to initialize framebuffer logger: {}", err);
         exit_qemu(crate::device::qemu::QemuExitCode::Success);
     };
-    log::set_max_level(log::LevelFilter::Debug);
+    filter::set_global_level(crate::config::config().log_level);
 
-    info!("kernel framebuffer logger is initialized.");
+    match framebuffer() {
+        Some(_) => info!("kernel framebuffer logger is initialized."),
+        None => warn!("no framebuffer, running headless with console on com1."),
+    }
 }
\ No newline at end of file
@@ -0,0 +1,148 @@
+use core::fmt;
+use crate::sync::IrqSpinlock;
+
+/**
+ *  kernel log ring buffer.
+ *
+ *  records are stored as `\n` terminated lines. writers never block on the
+ *  consumer: when the ring is full the oldest lines are dropped and counted.
+ */
+
+pub const LOG_RING_SIZE: usize = 64 * 1024;
+// longer records are truncated, so one record never evicts the whole ring
+pub const LOG_LINE_MAX: usize = 512;
+
+pub static LOG_RING: IrqSpinlock<LogRing> = IrqSpinlock::new(LogRing::new());
+
+pub struct LogRing {
+    buf: [u8; LOG_RING_SIZE],
+    // monotonic byte positions, `head - tail <= LOG_RING_SIZE`
+    head: u64,
+    tail: u64,
+    // lines overwritten before they were consumed, reported once by `take_dropped`
+    dropped: u64,
+}
+
+impl LogRing {
+    pub const fn new() -> Self {
+        Self { buf: [0; LOG_RING_SIZE], head: 0, tail: 0, dropped: 0 }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.head == self.tail
+    }
+
+    /// bytes not consumed yet
+    pub fn pending(&self) -> usize {
+        (self.head - self.tail) as usize
+    }
+
+    fn byte_at(&self, pos: u64) -> u8 {
+        self.buf[pos as usize % LOG_RING_SIZE]
+    }
+
+    // evict whole lines from tail until `len` more bytes fit
+    fn make_room(&mut self, len: usize) {
+        while LOG_RING_SIZE - self.pending() < len {
+            while self.tail < self.head {
+                let byte = self.byte_at(self.tail);
+                self.tail += 1;
+                if byte == b'\n' {
+                    break;
+                }
+            }
+            self.dropped += 1;
+        }
+    }
+
+    fn push_bytes(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            let pos = self.head as usize % LOG_RING_SIZE;
+            self.buf[pos] = byte;
+            self.head += 1;
+        }
+    }
+
+    /// append one formatted record, a trailing newline is added
+    pub fn push_fmt(&mut self, args: fmt::Arguments) {
+        let mut line = LineBuf { buf: [0; LOG_LINE_MAX], len: 0 };
+        let _ = fmt::write(&mut line, args);
+        // keep room for newline
+        let len = line.len.min(LOG_LINE_MAX - 1);
+        line.buf[len] = b'\n';
+
+        self.make_room(len + 1);
+        self.push_bytes(&line.buf[..=len]);
+    }
+
+    /// length of the oldest line without the newline
+    pub fn front_len(&self) -> Option<usize> {
+        (self.tail..self.head).position(|pos| self.byte_at(pos) == b'\n')
+    }
+
+    /// pop the oldest line into `dst` without the newline, returns its length.
+    /// lines longer than `dst` are truncated.
+    pub fn pop_line(&mut self, dst: &mut [u8]) -> Option<usize> {
+        if self.is_empty() {
+            return None;
+        }
+        let mut len = 0;
+        while self.tail < self.head {
+            let byte = self.byte_at(self.tail);
+            self.tail += 1;
+            if byte == b'\n' {
+                break;
+            }
+            if len < dst.len() {
+                dst[len] = byte;
+                len += 1;
+            }
+        }
+        Some(len)
+    }
+
+    /// copy the newest bytes still in the ring into `dst`, consumed lines included.
+    /// starts at a line boundary, returns copied length.
+    pub fn recent(&self, dst: &mut [u8]) -> usize {
+        let oldest = self.head.saturating_sub(LOG_RING_SIZE as u64);
+        let mut start = oldest.max(self.head.saturating_sub(dst.len() as u64));
+        // a cut line is useless, skip to the next one
+        let cut = if start > oldest { self.byte_at(start - 1) != b'\n' } else { start > 0 };
+        if cut {
+            while start < self.head {
+                start += 1;
+                if self.byte_at(start - 1) == b'\n' {
+                    break;
+                }
+            }
+        }
+        let len = (self.head - start) as usize;
+        for (i, byte) in dst[..len].iter_mut().enumerate() {
+            *byte = self.byte_at(start + i as u64);
+        }
+        len
+    }
+
+    pub fn take_dropped(&mut self) -> u64 {
+        core::mem::take(&mut self.dropped)
+    }
+}
+
+struct LineBuf {
+    buf: [u8; LOG_LINE_MAX],
+    len: usize,
+}
+
+impl fmt::Write for LineBuf {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let room = self.buf.len() - self.len;
+        let mut take = s.len().min(room);
+        // never split a utf-8 sequence
+        while !s.is_char_boundary(take) {
+            take -= 1;
+        }
+        self.buf[self.len..self.len + take].copy_from_slice(&s.as_bytes()[..take]);
+        self.len += take;
+        Ok(())
+    }
+}
@@ -10,51 +10,53 @@
 #![feature(maybe_uninit_uninit_array)]
 #![feature(step_trait)]
 #![feature(slice_ptr_get)]
+#![feature(linkage)]
+#![feature(thread_local)]
 #![test_runner(crate::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 
 use alloc::sync::Arc;
 use core::arch::asm;
 use core::hint::spin_loop;
+use core::fmt::Write;
 use core::mem::{MaybeUninit, offset_of, transmute};
 use core::ptr::addr_of_mut;
 use core::slice;
-use core::slice::from_raw_parts;
 use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
-use log::info;
+use log::{error, info};
 use spin::Once;
-use spinning_top::RwSpinlock;
-use acpi::local_apic::setup_apic;
-use gdt::init_gdt;
-use interrupt::init_idt;
 
-use mem::frame_allocator::init_frame_allocator;
-use shared::{arg::KernelArg, BOOTSTRAP_BYTES_P4};
+use shared::{arg::{KernelArg, KernelArgError}, boot_progress::BootStage};
 
-use x86_64::{instructions::{self, interrupts::{self}}, VirtAddr};
+use x86_64::{instructions::interrupts, VirtAddr};
 use x86_64::instructions::tlb;
 use x86_64::structures::paging::page_table::PageTableEntry;
-use x86_64::structures::paging::{Page, PageTable, PageTableFlags, Size4KiB};
 use shared::print_panic::PrintPanic;
 
-use crate::{arch_spec::cpuid::cpu_info, framebuffer::{init_framebuffer}, logger::{init_framebuffer_logger}};
+use crate::{arch_spec::{cpuid::{cpu_info, init_cpu_features}, verify_boot_state}, framebuffer::{init_framebuffer, report_boot_stage}, logger::{init_framebuffer_logger}};
 use crate::acpi::ap_startup::setup_ap_startup;
-use crate::acpi::io_apic::setup_io_apic;
-use crate::context::init_context;
+use crate::arch::{halt_loop, ArchContextRegs, ArchInterrupts, CurrentArch};
+use crate::cmdline::init_cmdline;
+use crate::device::com::COM1;
+use crate::config::init_config;
+use crate::idle::enter_idle;
+use crate::logger::flusher::wake_log_flusher;
+use crate::initcall::{run_initcalls, set_kernel_arg, InitCpuArg, InitLevel};
+use crate::context::{init_idle_context, set_init_context};
 use crate::context::list::{context_storage, context_storage_mut};
+use crate::context::sleep::next_deadline;
+use crate::context::spawn::{SpawnEntry, SpawnOptions};
 use crate::context::status::Status;
 use crate::context::switch::{switch_context, SwitchResult};
 use crate::cpu::{LogicalCpuId, PercpuBlock};
-use crate::device::com::init_com;
-use crate::interrupt::{enable_and_halt, enable_and_nop};
 use crate::ipi::{ipi, ipi_single, IpiKind, IpiTarget};
-use crate::mem::load_elf::elf_copy_to_addrsp;
-use crate::mem::{get_kernel_pml4_page_table_addr, PAGE_SIZE, set_kernel_pml4_page_table};
+use crate::mem::load_elf::{init_interp, load_program};
+use crate::power::hibernate::resumed;
 use crate::mem::aligned_box::AlignedBox;
 use crate::mem::heap::RT_HEAP_SPACE;
 use crate::mem::user_addr_space::RwLockUserAddrSpace;
-use crate::syscall::init_syscall;
 
+mod arch;
 mod arch_spec;
 mod panic;
 mod device;
@@ -71,6 +73,22 @@ mod common;
 mod ipi;
 mod fs;
 mod interrupt_macro;
+mod cmdline;
+mod config;
+mod initcall;
+mod idle;
+mod power;
+mod crashdump;
+mod sync;
+mod supervisor;
+#[cfg(feature = "selftest")]
+mod selftest;
+#[cfg(feature = "fuzz")]
+mod fuzz;
+#[cfg(feature = "bench")]
+mod bench;
+#[cfg(feature = "shell")]
+mod shell;
 
 extern crate alloc;
 
@@ -79,7 +97,18 @@ pub static AP_READY: AtomicBool = AtomicBool::new(false);
 static BSP_READY: AtomicBool = AtomicBool::new(false);
 
 static BOOTSTRAP: Once<&'static [u8]> = Once::new();
-static BOOTSTRAP_USR_ADDRSP_BASE: Once<u64> = Once::new();
+
+// nothing else of a rejected arg is read, not even the framebuffer unless its layout matches
+fn check_kernel_arg(arg: &KernelArg) {
+    let Err(err) = arg.verify() else { return };
+    let _ = writeln!(COM1.lock(), "kernel arg rejected: {}, bootloader and kernel are from different builds", err);
+    if !matches!(err, KernelArgError::BadMagic(_) | KernelArgError::Layout { .. } | KernelArgError::Size { .. }) {
+        init_framebuffer(arg);
+        init_framebuffer_logger(arg.framebuffer_font);
+        error!("kernel arg rejected: {}, bootloader and kernel are from different builds", err);
+    }
+    halt_loop();
+}
 
 // entry for all things
 #[no_mangle]
@@ -87,32 +116,32 @@ pub extern "C" fn _start(arg: &'static KernelArg) -> ! {
     #[cfg(test)]
     test_main();
 
+    check_kernel_arg(arg);
     init_framebuffer(arg);
-    init_framebuffer_logger();
+    init_framebuffer_logger(arg.framebuffer_font);
+    report_boot_stage(BootStage::KernelEntry);
+    verify_boot_state(arg);
+    init_cmdline(arg);
+    init_config();
 
-    cpu_info().or_panic("failed to print cpu info");
+    cpu_info();
+    init_cpu_features();
 
     BOOTSTRAP.call_once(|| unsafe {
         slice::from_raw_parts(arg.bootstrap_base as *const u8, arg.bootstrap_len)
     });
+    init_interp(arg);
 
-    set_kernel_pml4_page_table(arg.kernel_pml4_start_addr);
-    init_frame_allocator(
-        VirtAddr::new(arg.phys_mem_mapped_addr),
-        arg.phys_mem_size,
-        &arg.unav_phys_mem_regions[..arg.unav_phys_mem_regions_len]
-    );
+    set_kernel_arg(arg);
+    let bsp = InitCpuArg { cpu_id: LogicalCpuId::BSP, stack_top: arg.stack_top_addr };
 
     interrupts::disable();
 
     unsafe {
-        init_gdt(LogicalCpuId::BSP, arg.stack_top_addr);
-        init_idt(LogicalCpuId::BSP);
-
-        setup_apic(arg.acpi.local_apic_base as u64, LogicalCpuId::BSP);
-
-        init_syscall();
+        run_initcalls(InitLevel::Early, &bsp);
+        run_initcalls(InitLevel::Arch, &bsp);
     }
+    report_boot_stage(BootStage::Interrupts);
 
     interrupts::enable();
 
@@ -120,62 +149,44 @@ pub extern "C" fn _start(arg: &'static KernelArg) -> ! {
     AP_READY.store(false, Ordering::SeqCst);
     BSP_READY.store(false, Ordering::SeqCst);
 
+    report_boot_stage(BootStage::Smp);
     setup_ap_startup(
-        &arg.acpi.local_apic[..arg.acpi.local_apic_count],
+        unsafe { arg.acpi.local_apics(arg.phys_mem_mapped_addr) },
         VirtAddr::new(arg.kernel_pml4_start_addr)
     );
 
-    setup_io_apic(
-        &arg.acpi.io_apic[..arg.acpi.io_apic_count],
-        &arg.acpi.interrupt_src_override[..arg.acpi.interrupt_src_override_count]
-    );
-
-    unsafe {
-        init_com();
-    }
-
     // BSP_READY.store(true, Ordering::SeqCst);
 
     // bsp kernel main
 
-    init_context();
+    unsafe {
+        run_initcalls(InitLevel::Device, &bsp);
+        run_initcalls(InitLevel::Fs, &bsp);
+        run_initcalls(InitLevel::Late, &bsp);
+    }
+
+    #[cfg(feature = "selftest")]
+    selftest::run();
+    #[cfg(feature = "fuzz")]
+    fuzz::run();
 
-    match context_storage_mut().spawn(true, userspace_init) {
+    report_boot_stage(BootStage::Userspace);
+    // contexts of a hibernation image took the place of bootstrap
+    if resumed() {
+        unsafe { idle_loop() }
+    }
+    match context_storage_mut().spawn(&SpawnOptions::userspace("bootstrap"), SpawnEntry::Func(userspace_init)) {
         Ok(lock) => {
             let mut context = lock.write();
-            context.status = Status::Runnable;
-
-            // bootloader mapped bootstrap to KernelPageTable[BOOTSTRAP_P4][0]
-            // so we map bootstrap: KernelPageTable[BOOTSTRAP_P4][0] -> AddrspPageTable[0][511]
-            // bootstrap size bust be smaller than 1GB
-            match &context.addrsp {
-                Some(ref addrsp) => {
-                    let mut addrsp_guard = addrsp.acquire_write();
-
-                    let kpt_pml4 = unsafe { &*(get_kernel_pml4_page_table_addr() as *const PageTable) };
-                    let kpt_bsp4_pml3 = unsafe {
-                        &*((&kpt_pml4[BOOTSTRAP_BYTES_P4 as usize].addr()).as_u64() as *const PageTable)
-                    };
-
-                    let addrsp_pt = unsafe { addrsp_guard.page_table() };
-                    let addrsp_pt_0_pml3 = unsafe {
-                        &mut *((&addrsp_pt[0].addr()).as_u64() as *mut PageTable)
-                    };
-
-                    addrsp_pt_0_pml3[511] = kpt_bsp4_pml3[0].clone();
-                    // 0x7fc0000000 是 PageTable[0][511] 1gb 页的起始虚拟地址
-                    BOOTSTRAP_USR_ADDRSP_BASE.call_once(|| 0x7f_c000_0000);
-                }
-                None => panic!("user address space of bootstrap context is not found.")
-            }
-
+            context.set_status(Status::Runnable);
+            set_init_context(context.id);
         }
         Err(err) => {
             panic!("failed to spawn userspace_init: {:?}", err);
         }
     }
 
-    unsafe { run_userspace() }
+    unsafe { idle_loop() }
 }
 
 #[repr(packed)]
@@ -192,14 +203,10 @@ pub struct KernelArgAp {
 pub unsafe extern "C" fn _start_ap(arg_ptr: *const KernelArgAp) -> ! {
     unsafe {
         let arg = &*arg_ptr;
-        let cpu_id = LogicalCpuId(arg.cpu_id as u8);
-
-        init_gdt(cpu_id, arg.stack_end);
-        init_idt(cpu_id);
+        let cpu_id = LogicalCpuId(arg.cpu_id as u32);
 
-
-        setup_apic(0, cpu_id);
-        init_syscall();
+        run_initcalls(InitLevel::Arch, &InitCpuArg { cpu_id, stack_top: arg.stack_end });
+        init_idle_context();
         AP_READY.store(true, Ordering::SeqCst);
 
         interrupts::enable();
@@ -210,46 +217,31 @@ pub unsafe extern "C" fn _start_ap(arg_ptr: *const KernelArgAp) -> ! {
         spin_loop()
     }
 
-    unreachable!()
+    unsafe { idle_loop() }
 }
 
 extern "C" fn userspace_init() {
-    let contexts = context_storage();
-    let current_context = contexts.current()
-        .or_panic("failed to get userspace init context");
-    let context_read = current_context.read();
-    let addrsp = match context_read.addrsp {
-        None => panic!("failed to get address space of userspace init context"),
-        Some(ref rsp) => Arc::clone(rsp)
+    let addrsp = {
+        let contexts = context_storage();
+        let context = contexts.current()
+            .or_panic("failed to get userspace init context")
+            .read();
+        match context.addrsp {
+            None => panic!("failed to get address space of userspace init context"),
+            Some(ref rsp) => Arc::clone(rsp)
+        }
     };
-    let bootstrap_entry = unsafe {
-        let bootstrap_slice_user_addrsp = from_raw_parts(
-            *BOOTSTRAP_USR_ADDRSP_BASE
-                .get()
-                .or_panic("failed to get bootstrap base at user address space")
-                as *const u8,
-            BOOTSTRAP
-                .get()
-                .or_panic("failed to get bootstrap length")
-                .len()
-        );
-        elf_copy_to_addrsp(bootstrap_slice_user_addrsp, addrsp)
+    let (entry, stack_pointer) = unsafe {
+        // the kernel mapping of bootstrap is shared by every address space,
+        // segments are copied out of it page by page
+        let bootstrap = BOOTSTRAP.get().or_panic("failed to get bootstrap bytes");
+        // nothing to fall back to without bootstrap
+        load_program("bootstrap", bootstrap, &addrsp)
+            .or_panic("failed to load bootstrap")
     };
-    infohart!("bootstrap entry: 0x{:x}", bootstrap_entry.as_u64());
 
     // validate
-    {
-        match context_read.addrsp {
-            None => panic!("failed to get address space of userspace init context"),
-            Some(ref rsp) => unsafe {
-                let mut rsp = Arc::clone(rsp);
-                let mut rsp_guard = rsp.acquire_write();
-                rsp_guard.validate();
-            }
-        };
-    }
-
-    drop(context_read);
+    unsafe { addrsp.validate() }
 
     match context_storage().current()
         .or_panic("bootstrap was not running inside any context")
@@ -259,29 +251,36 @@ extern "C" fn userspace_init() {
     {
         ref mut regs => {
             regs.init();
-            regs.set_instr_pointer(bootstrap_entry.as_u64() as usize)
+            regs.set_stack_pointer(stack_pointer as usize);
+            regs.set_instr_pointer(entry.as_u64() as usize)
         }
     }
 }
 
-unsafe fn run_userspace() -> ! {
+// boot flow of every cpu ends here as its idle context
+unsafe fn idle_loop() -> ! {
     loop {
-        interrupts::disable();
+        // retry a log flusher wakeup lost to lock contention
+        wake_log_flusher();
+        CurrentArch::disable_interrupts();
         match switch_context() {
             SwitchResult::Switched { .. } => {
-                enable_and_nop()
+                CurrentArch::enable_interrupts_and_nop()
             }
             SwitchResult::AllContextsIdle => {
-                enable_and_halt()
+                // no timer interrupt wakes sleepers yet, keep polling while any is queued
+                if next_deadline().is_some() {
+                    CurrentArch::enable_interrupts_and_nop()
+                } else {
+                    enter_idle()
+                }
             }
         }
     }
 }
 
 fn halt() -> ! {
-    loop {
-        instructions::hlt();
-    }
+    halt_loop()
 }
 
 #[cfg(test)]
@@ -33,6 +33,20 @@ const fn layout_upgrade_align(layout: Layout, align: usize) -> Layout {
 }
 
 impl<T, const ALIGN: usize> AlignedBox<T, ALIGN> {
+    #[inline]
+    pub fn try_new(value: T) -> Result<Self, OutOfMemory> {
+        unsafe {
+            let ptr = HEAP_ALLOC.alloc(layout_upgrade_align(Layout::new::<T>(), ALIGN));
+            if ptr.is_null() {
+                return Err(OutOfMemory);
+            }
+            ptr.cast::<T>().write(value);
+            Ok(Self {
+                inner: Unique::new_unchecked(ptr.cast()),
+            })
+        }
+    }
+
     #[inline(always)]
     pub fn try_zeroed() -> Result<Self, OutOfMemory>
         where
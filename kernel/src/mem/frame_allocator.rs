@@ -1,24 +1,94 @@
-use core::{mem::{transmute, MaybeUninit}, ops::Range};
+use core::{mem::{self, size_of, transmute, MaybeUninit}, ops::Range, ptr, slice};
 use core::cell::RefMut;
+use core::sync::atomic::{AtomicU32, Ordering};
 use lazy_static::lazy_static;
 use log::{error, info};
-use shared::{arg::MemoryRegion, uni_processor::UPSafeCell};
-use spin::{Mutex, Once};
+use shared::{arg::MemoryRegion, print_panic::PrintPanic, uni_processor::UPSafeCell};
+use spin::Once;
 use x86_64::{structures::paging::{FrameAllocator, PhysFrame, Size4KiB}, PhysAddr, VirtAddr};
+use libvdso::error::{ENOMEM, KError, KResult};
 use crate::mem::PAGE_SIZE;
+use crate::mem::tlb;
+use crate::initcall;
+use crate::sync::IrqSpinlock;
+use crate::initcall::{kernel_arg, InitCpuArg};
+
+/**
+ *  physical frame allocator.
+ *
+ *  frames are handed out linearly from the usable regions, freed frames are
+ *  kept on a free list threaded through the frames themselves (physical
+ *  memory is identity mapped) and handed out first. another cpu may still
+ *  cache a translation to a freed frame, so it waits in a batch linked through
+ *  its metadata until every cpu flushed its tlb, see [`tlb`](crate::mem::tlb).
+ *
+ *  every frame up to the end of physical memory has a [`FrameMeta`] with a
+ *  reference count. allocation sets it to 1, [`frame_ref`] adds a reference
+ *  for another owner (a second mapping, a shared page) and [`frame_dealloc`]
+ *  drops one, the frame goes back to the free list with the last. freeing a
+ *  frame that is not allocated or taking a reference to a free frame panics,
+ *  a frame can't be reused while anyone still holds it.
+ */
 
 const MAX_RANGE_COUNT: usize = 512;
+const MAX_RECLAIMED_COUNT: usize = 32;
+const EMPTY_RANGE: Range<u64> = 0..0;
 pub static PHYS_MEM_SIZE: Once<u64> = Once::new();
 
+// handed out by the allocator
+pub const FRAME_ALLOCATED: u32 = 1 << 0;
+// never freed, e.g. the metadata array itself
+pub const FRAME_RESERVED: u32 = 1 << 1;
+
+pub struct FrameMeta {
+    refcount: AtomicU32,
+    flags: AtomicU32,
+    // next frame of the batch waiting for a shootdown, `BATCH_END` ends it
+    link: AtomicU32,
+}
+
+const BATCH_END: u32 = u32::MAX;
+
+// freed frames linked through `FrameMeta::link` by index
+#[derive(Clone, Copy)]
+struct FrameBatch {
+    head: u32,
+    len: usize,
+}
+
+impl FrameBatch {
+    const EMPTY: FrameBatch = FrameBatch { head: BATCH_END, len: 0 };
+}
+
+// one per frame from physical address 0, all zero is a free frame
+static FRAME_META: Once<&'static [FrameMeta]> = Once::new();
+
 lazy_static! {
-    pub static ref FRAME_ALLOCATOR: UPSafeCell<Mutex<MaybeUninit<LinearIncFrameAllocator>>> = unsafe { UPSafeCell::new(Mutex::new(MaybeUninit::uninit())) };
+    // page fault handling allocates frames, so it is shared with interrupt handlers
+    pub static ref FRAME_ALLOCATOR: UPSafeCell<IrqSpinlock<MaybeUninit<LinearIncFrameAllocator>>> = unsafe { UPSafeCell::new(IrqSpinlock::new(MaybeUninit::uninit())) };
 }
 
 pub struct LinearIncFrameAllocator {
     range_iterator: LinkedRangeIterator,
     base_address: u64,
     phys_mem_right_boundary: u64,
-    window: u64
+    window: u64,
+    // count of frames handed out since initialized
+    allocated_frames: usize,
+    // unavailable regions given back after boot, allocated first-fit before the linear range
+    reclaimed: [Range<u64>; MAX_RECLAIMED_COUNT],
+    reclaimed_len: usize,
+    // metadata of the frames, empty until `init_meta`
+    meta: &'static [FrameMeta],
+    // freed frames, the next address is stored in the first word of each, 0 ends the list
+    free_head: u64,
+    // frames on the free list and in both batches
+    free_frames: usize,
+    // freed since the last shootdown
+    deferred: FrameBatch,
+    // reused once every cpu flushed since `waiting_epoch`
+    waiting: FrameBatch,
+    waiting_epoch: u64,
 }
 
 impl LinearIncFrameAllocator {
@@ -35,8 +105,120 @@ impl LinearIncFrameAllocator {
             range_iterator: iter, 
             base_address: phys_start_addr.as_u64(), 
             phys_mem_right_boundary: phys_start_addr.as_u64() + phys_mem_size,
-            window
+            window,
+            allocated_frames: 0,
+            reclaimed: [EMPTY_RANGE; MAX_RECLAIMED_COUNT],
+            reclaimed_len: 0,
+            meta: &[],
+            free_head: 0,
+            free_frames: 0,
+            deferred: FrameBatch::EMPTY,
+            waiting: FrameBatch::EMPTY,
+            waiting_epoch: 0,
+        }
+    }
+
+    /// allocate the metadata of every frame up to `phys_mem_size`, reference counting starts here
+    unsafe fn init_meta(&mut self, phys_mem_size: u64) -> &'static [FrameMeta] {
+        let count = (phys_mem_size / self.window) as usize;
+        let pages = (count * size_of::<FrameMeta>()).div_ceil(self.window as usize);
+        let start = self.allocate_frames(pages).or_panic("failed to allocate frame metadata");
+        let meta = start.start_address().as_u64() as *mut FrameMeta;
+        // all zero atomics are valid
+        ptr::write_bytes(meta, 0, count);
+        self.meta = slice::from_raw_parts(meta, count);
+        for frame in PhysFrame::range(start, start + pages as u64) {
+            let meta = self.meta_of(frame).or_panic("frame metadata is outside physical memory");
+            meta.refcount.store(1, Ordering::Relaxed);
+            meta.flags.store(FRAME_ALLOCATED | FRAME_RESERVED, Ordering::Relaxed);
+        }
+        self.meta
+    }
+
+    fn meta_of(&self, frame: PhysFrame) -> Option<&'static FrameMeta> {
+        // physical memory is identity mapped
+        let index = frame.start_address().as_u64() / self.window;
+        self.meta.get(index as usize)
+    }
+
+    fn mark_allocated(&self, start: PhysFrame, count: usize) {
+        for frame in PhysFrame::range(start, start + count as u64) {
+            // frames are counted from `init_meta` on
+            let Some(meta) = self.meta_of(frame) else { continue };
+            let flags = meta.flags.swap(FRAME_ALLOCATED, Ordering::Relaxed);
+            assert!(flags & FRAME_ALLOCATED == 0, "frame {:#x} handed out twice", frame.start_address().as_u64());
+            meta.refcount.store(1, Ordering::Release);
+        }
+    }
+
+    // the last reference of `frame` is gone, a stale tlb entry may still write to it
+    fn release(&mut self, frame: PhysFrame) {
+        let meta = self.meta_of(frame).or_panic("released frame is outside physical memory");
+        meta.flags.store(0, Ordering::Relaxed);
+        meta.link.store(self.deferred.head, Ordering::Relaxed);
+        let index = (frame.start_address().as_u64() / self.window) as u32;
+        self.deferred = FrameBatch { head: index, len: self.deferred.len + 1 };
+        self.free_frames += 1;
+    }
+
+    // start a shootdown for the deferred batch if none is running, put the
+    // waiting batch on the free list once every cpu flushed
+    fn reap(&mut self) {
+        if self.waiting.len == 0 && self.deferred.len > 0 {
+            self.waiting = mem::replace(&mut self.deferred, FrameBatch::EMPTY);
+            self.waiting_epoch = tlb::shootdown();
+        }
+        if self.waiting.len == 0 || !tlb::flushed_since(self.waiting_epoch) {
+            return;
+        }
+        let mut index = mem::replace(&mut self.waiting, FrameBatch::EMPTY).head;
+        while index != BATCH_END {
+            let next = self.meta[index as usize].link.load(Ordering::Relaxed);
+            let addr = index as u64 * self.window;
+            unsafe { (addr as *mut u64).write(self.free_head) };
+            self.free_head = addr;
+            index = next;
+        }
+    }
+
+    fn next_free(&mut self) -> Option<PhysFrame> {
+        if self.free_head == 0 {
+            self.reap();
+        }
+        if self.free_head == 0 {
+            return None;
+        }
+        let addr = self.free_head;
+        self.free_head = unsafe { (addr as *const u64).read() };
+        self.free_frames -= 1;
+        Some(PhysFrame::containing_address(PhysAddr::new(addr)))
+    }
+
+    /// hand physical `range` out again, it must be inside an unavailable region
+    /// passed to [`new`](Self::new) and never be used by the kernel afterwards.
+    /// returns false if too many ranges were reclaimed already.
+    pub fn reclaim(&mut self, range: Range<u64>) -> bool {
+        let start = (range.start + self.window - 1) / self.window * self.window;
+        let end = range.end / self.window * self.window;
+        if start >= end {
+            return true;
+        }
+        if self.reclaimed_len == MAX_RECLAIMED_COUNT {
+            return false;
         }
+        self.reclaimed[self.reclaimed_len] = start..end;
+        self.reclaimed_len += 1;
+        true
+    }
+
+    fn next_n_reclaimed(&mut self, count: usize) -> Option<u64> {
+        let required_size = self.window * count as u64;
+        let range = self.reclaimed[..self.reclaimed_len]
+            .iter_mut()
+            .find(|r| r.end - r.start >= required_size)?;
+        let addr = range.start;
+        range.start += required_size;
+        Some(addr)
     }
 
     fn next_n(&mut self, count: usize) -> Option<u64> {
@@ -44,6 +226,23 @@ impl LinearIncFrameAllocator {
     }
 
     pub fn allocate_frames(&mut self, count: usize) -> Option<PhysFrame<Size4KiB>> {
+        let frame = self.take_frames(count)?;
+        self.allocated_frames += count;
+        self.mark_allocated(frame, count);
+        Some(frame)
+    }
+
+    fn take_frames(&mut self, count: usize) -> Option<PhysFrame<Size4KiB>> {
+        // freed frames are not contiguous, runs always come from the ranges
+        if count == 1 {
+            if let Some(frame) = self.next_free() {
+                return Some(frame);
+            }
+        }
+        if let Some(phys_addr) = self.next_n_reclaimed(count) {
+            return Some(PhysFrame::containing_address(PhysAddr::new(self.base_address + phys_addr)));
+        }
+
         let phys_addr = self.next_n(count)?;
 
         // out of memory
@@ -55,6 +254,14 @@ impl LinearIncFrameAllocator {
         let phys_addr = PhysAddr::new(self.base_address + phys_addr);
         Some(PhysFrame::containing_address(phys_addr))
     }
+
+    pub fn allocated_frames(&self) -> usize {
+        self.allocated_frames
+    }
+
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
 }
 
 unsafe impl FrameAllocator<Size4KiB> for LinearIncFrameAllocator {
@@ -178,16 +385,28 @@ impl LinkedRangeIterator {
     }
 }
 
+unsafe fn frame_allocator_initcall(_: &InitCpuArg) {
+    let arg = kernel_arg();
+    init_frame_allocator(
+        VirtAddr::new(arg.phys_mem_mapped_addr),
+        arg.phys_mem_size,
+        &arg.unav_phys_mem_regions[..arg.unav_phys_mem_regions_len]
+    );
+}
+initcall!(early, Bsp, frame_allocator_initcall, order = 10);
+
 pub fn init_frame_allocator(
     phys_start_addr: VirtAddr,
     phys_mem_size: u64,
     mem_regions: &[MemoryRegion]
 ) {
-    let allocator = LinearIncFrameAllocator::new(phys_start_addr, PAGE_SIZE as u64, phys_mem_size, mem_regions);
+    let mut allocator = LinearIncFrameAllocator::new(phys_start_addr, PAGE_SIZE as u64, phys_mem_size, mem_regions);
+    let meta = unsafe { allocator.init_meta(phys_mem_size) };
 
-    let global_alloc: RefMut<'_, Mutex<MaybeUninit<LinearIncFrameAllocator>>> = FRAME_ALLOCATOR.inner_exclusive_mut();
+    let global_alloc: RefMut<'_, IrqSpinlock<MaybeUninit<LinearIncFrameAllocator>>> = FRAME_ALLOCATOR.inner_exclusive_mut();
     let mut locked = global_alloc.lock();
     locked.write(allocator);
+    FRAME_META.call_once(|| meta);
 
     PHYS_MEM_SIZE.call_once(|| phys_mem_size);
     info!("frame allocator is initialized. phys mem size: {}", phys_mem_size);
@@ -211,9 +430,67 @@ pub fn frame_alloc_n(count: usize) -> Option<PhysFrame> {
     with_frame_alloc(|alloc: &mut LinearIncFrameAllocator| { alloc.allocate_frames(count) })
 }
 
-/// deallocate this phys frame
-pub fn frame_dealloc(_frame: PhysFrame) {
-    // unimplemented
+/// [`frame_alloc`] for callers propagating errors, `ENOMEM` if physical memory is exhausted
+pub fn try_frame_alloc() -> KResult<PhysFrame> {
+    frame_alloc().ok_or(KError::new(ENOMEM))
+}
+
+/// [`frame_alloc_n`] for callers propagating errors
+pub fn try_frame_alloc_n(count: usize) -> KResult<PhysFrame> {
+    frame_alloc_n(count).ok_or(KError::new(ENOMEM))
+}
+
+/// count of frames allocated from global frame allocator
+pub fn allocated_frame_count() -> usize {
+    with_frame_alloc(|alloc: &mut LinearIncFrameAllocator| alloc.allocated_frames())
+}
+
+/// count of frames in use, allocated ones that were freed again are not
+pub fn used_frame_count() -> usize {
+    with_frame_alloc(|alloc: &mut LinearIncFrameAllocator| alloc.allocated_frames() - alloc.free_frames())
+}
+
+fn frame_meta(frame: PhysFrame) -> &'static FrameMeta {
+    let meta = FRAME_META.get().or_panic("frame metadata is not initialized");
+    meta.get(frame.start_address().as_u64() as usize / PAGE_SIZE)
+        .unwrap_or_else(|| panic!("frame {:#x} is outside physical memory", frame.start_address().as_u64()))
+}
+
+/// add a reference to an allocated frame for another owner, dropped with [`frame_dealloc`]
+pub fn frame_ref(frame: PhysFrame) -> PhysFrame {
+    frame_meta(frame).refcount
+        .fetch_update(Ordering::Acquire, Ordering::Relaxed, |count| (count != 0).then(|| count + 1))
+        .unwrap_or_else(|_| panic!("reference to free frame {:#x}", frame.start_address().as_u64()));
+    frame
+}
+
+/// [`frame_ref`] for frames that may be free already or have no metadata, like
+/// device memory mapped for a driver. `None` if no reference was taken
+pub fn frame_try_ref(frame: PhysFrame) -> Option<PhysFrame> {
+    let meta = FRAME_META.get()?.get(frame.start_address().as_u64() as usize / PAGE_SIZE)?;
+    if meta.flags.load(Ordering::Relaxed) & FRAME_RESERVED != 0 {
+        return None;
+    }
+    meta.refcount.fetch_update(Ordering::Acquire, Ordering::Relaxed, |count| (count != 0).then(|| count + 1)).ok()?;
+    Some(frame)
+}
+
+/// references held on `frame`, 0 if it is free
+pub fn frame_refcount(frame: PhysFrame) -> u32 {
+    frame_meta(frame).refcount.load(Ordering::Relaxed)
+}
+
+/// drop a reference to this phys frame, it is freed with the last one
+pub fn frame_dealloc(frame: PhysFrame) {
+    let meta = frame_meta(frame);
+    let flags = meta.flags.load(Ordering::Relaxed);
+    assert!(flags & FRAME_RESERVED == 0, "freeing reserved frame {:#x}", frame.start_address().as_u64());
+    let previous = meta.refcount
+        .fetch_update(Ordering::AcqRel, Ordering::Relaxed, |count| count.checked_sub(1))
+        .unwrap_or_else(|_| panic!("double free of frame {:#x}", frame.start_address().as_u64()));
+    if previous == 1 {
+        with_frame_alloc(|alloc: &mut LinearIncFrameAllocator| alloc.release(frame));
+    }
 }
 
 #[test_case]
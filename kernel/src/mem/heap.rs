@@ -1,12 +1,15 @@
-use core::alloc::GlobalAlloc;
+use alloc::boxed::Box;
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::NonNull;
 
 use buddy_alloc::{BuddyAllocParam, FastAllocParam, NonThreadsafeAlloc};
 use buddy_alloc::buddy_alloc::BuddyAlloc;
 use lazy_static::lazy_static;
 use shared::uni_processor::UPSafeCell;
-use spin::Mutex;
+use libvdso::error::{ENOMEM, KError};
+use crate::sync::IrqSpinlock;
 
-const RT_HEAP_SIZE: usize = 0x100_8000;
+const RT_HEAP_SIZE: usize = crate::config::HEAP_SIZE;
 const RT_HEAP_FAST_SIZE: usize = 0x8000;
 #[link_section = ".data.heap"]
 pub static mut RT_HEAP_SPACE: [u8; RT_HEAP_SIZE] = [0; RT_HEAP_SIZE];
@@ -19,11 +22,12 @@ lazy_static! {
     };
 }
 
-struct LockedGlobalAlloc(Mutex<NonThreadsafeAlloc>);
+// interrupt handlers allocate too (page faults, logging)
+struct LockedGlobalAlloc(IrqSpinlock<NonThreadsafeAlloc>);
 
 impl LockedGlobalAlloc {
     fn new(alloc: NonThreadsafeAlloc) -> Self {
-        Self(Mutex::new(alloc))
+        Self(IrqSpinlock::new(alloc))
     }
 }
 
@@ -45,6 +49,22 @@ unsafe impl GlobalAlloc for LockedGlobalAlloc {
 #[derive(Debug)]
 pub struct OutOfMemory;
 
+impl From<OutOfMemory> for KError {
+    fn from(_: OutOfMemory) -> Self {
+        KError::new(ENOMEM)
+    }
+}
+
+/// allocate `layout` from the kernel heap, without going through the alloc error handler
+pub fn try_alloc(layout: Layout) -> Result<NonNull<u8>, OutOfMemory> {
+    NonNull::new(unsafe { HEAP_ALLOC.alloc(layout) }).ok_or(OutOfMemory)
+}
+
+/// `Box::new` which returns `Err` when the heap is exhausted
+pub fn try_box<T>(value: T) -> Result<Box<T>, OutOfMemory> {
+    Box::try_new(value).map_err(|_| OutOfMemory)
+}
+
 // delegate static global alloc
 pub(super) struct _DelegateAlloc;
 
@@ -0,0 +1,333 @@
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::slice;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::structures::paging::mapper::{MappedFrame, TranslateResult};
+use libvdso::error::KResult;
+use shared::layout::USER_SPACE_END;
+use shared::print_panic::PrintPanic;
+use crate::arch::{ArchInterrupts, CurrentArch};
+use crate::arch_spec::memcopy;
+use crate::cmdline::cmdline_flag;
+use crate::context::{context_id, Context};
+use crate::context::list::{context_storage, context_storage_mut};
+use crate::context::sleep::sleep_until;
+use crate::context::spawn::{SpawnEntry, SpawnOptions};
+use crate::context::status::Status;
+use crate::context::switch::switch_context;
+use crate::device::tsc::monotonic_ns;
+use crate::initcall;
+use crate::initcall::InitCpuArg;
+use crate::mem::frame_allocator::{frame_dealloc, frame_refcount, try_frame_alloc};
+use crate::mem::user_addr_space::{RwLockUserAddrSpace, UserAddrSpace};
+use crate::mem::PAGE_SIZE;
+use crate::sync::{IrqSpinlock, PreemptGuard};
+use crate::{infohart, warnhart};
+
+/**
+ *  samepage merging of user frames.
+ *
+ *  the `ksm` kernel thread wakes every `SCAN_INTERVAL_NS` and hashes the
+ *  writable pages every address space owns. pages with the same bytes are
+ *  remapped read-only onto one stable frame, marked `MERGED` in the pte, and
+ *  their own frames are freed. the stable frames are kept by content hash,
+ *  the bytes are compared before a page joins one. a page whose content is
+ *  new in this pass is only remembered, the second page with that content
+ *  pays for the stable frame and the first joins it on the next pass.
+ *
+ *  a write breaks the sharing: the #PF handler, and the kernel translating a
+ *  user address for writing, give the page a copy of its own and map it
+ *  writable again. the stable frame is refcounted like any other, it is
+ *  dropped once no page maps it anymore.
+ *
+ *  user pages live in the pml4 entry every address space shares and the
+ *  kernel writes them through the physical map, so a pass only runs while no
+ *  userspace context runs or sits in a syscall, and keeps all of them locked,
+ *  unscheduled, until it is done. address spaces a kernel path holds a
+ *  reference on are left alone. `noksm` on the command line turns it off.
+ */
+
+/// pte bit of a page mapping a stable frame, it is writable to its owner
+pub const MERGED: PageTableFlags = PageTableFlags::BIT_10;
+
+const SCAN_INTERVAL_NS: u64 = 2_000_000_000;
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+// content hash to stable frame, a reference on each is held here
+static STABLE: IrqSpinlock<BTreeMap<u64, PhysFrame>> = IrqSpinlock::new(BTreeMap::new());
+// pages that got their own frame back
+static UNMERGED: AtomicUsize = AtomicUsize::new(0);
+
+// physical memory is identity mapped
+unsafe fn frame_bytes<'a>(frame: PhysFrame) -> &'a [u8] {
+    slice::from_raw_parts(frame.start_address().as_u64() as *const u8, PAGE_SIZE)
+}
+
+// fnv-1a over words, a collision only costs a comparison
+fn hash_page(bytes: &[u8]) -> u64 {
+    bytes.chunks_exact(8)
+        .fold(FNV_OFFSET, |hash, word| (hash ^ u64::from_le_bytes(word.try_into().unwrap())).wrapping_mul(FNV_PRIME))
+}
+
+fn mapping(addrsp: &mut UserAddrSpace, page: Page) -> Option<(PhysFrame, PageTableFlags)> {
+    match unsafe { addrsp.raw_translate(page.start_address()) } {
+        TranslateResult::Mapped { frame: MappedFrame::Size4KiB(frame), flags, .. } => Some((frame, flags)),
+        _ => None,
+    }
+}
+
+/// remap `page` onto the stable frame with its content. `tracked` is the
+/// sorted frames `addrsp` owns, content already seen this pass is in `seen`.
+/// returns true if the page was merged
+unsafe fn merge_page(addrsp: &mut UserAddrSpace, page: Page, tracked: &mut Vec<PhysFrame>, seen: &mut BTreeSet<u64>) -> KResult<bool> {
+    let Some((frame, flags)) = mapping(addrsp, page) else { return Ok(false) };
+    // device memory is not tracked, kernel stacks and stable frames have other owners
+    if flags & (PageTableFlags::WRITABLE | MERGED) != PageTableFlags::WRITABLE
+        || tracked.binary_search(&frame).is_err() || frame_refcount(frame) != 1 {
+        return Ok(false);
+    }
+    let bytes = frame_bytes(frame);
+    let hash = hash_page(bytes);
+
+    let mut stable = STABLE.lock();
+    let shared = match stable.get(&hash) {
+        Some(shared) if frame_bytes(*shared) == bytes => *shared,
+        Some(_) => return Ok(false),
+        None if seen.insert(hash) => return Ok(false),
+        None => {
+            let shared = try_frame_alloc()?;
+            memcopy::copy(shared.start_address().as_u64() as *mut u8, bytes.as_ptr(), PAGE_SIZE);
+            stable.insert(hash, shared);
+            shared
+        }
+    };
+    // frees the page's own frame, its tables stay, the map can not fail
+    addrsp.raw_unmap(page);
+    if let Ok(index) = tracked.binary_search(&frame) {
+        tracked.remove(index);
+    }
+    addrsp.raw_map_shared(page, shared, flags & !PageTableFlags::WRITABLE | MERGED)
+        .or_panic("failed to map a merged page");
+    Ok(true)
+}
+
+/// give the merged `page` of `addrsp` a writable frame of its own again.
+/// returns false if it is not merged or owned by another address space
+pub unsafe fn unmerge(addrsp: &mut UserAddrSpace, page: Page) -> KResult<bool> {
+    let Some((shared, flags)) = mapping(addrsp, page) else { return Ok(false) };
+    if !flags.contains(MERGED) || !addrsp.tracked_frames().contains(&shared) {
+        return Ok(false);
+    }
+    let frame = try_frame_alloc()?;
+    memcopy::copy(frame.start_address().as_u64() as *mut u8, frame_bytes(shared).as_ptr(), PAGE_SIZE);
+    // drops the reference of this page on the stable frame
+    addrsp.raw_unmap(page);
+    if let Err(err) = addrsp.raw_map_to(page, frame, flags & !MERGED | PageTableFlags::WRITABLE) {
+        frame_dealloc(frame);
+        return Err(err);
+    }
+    addrsp.push_tracked_frame(frame);
+    UNMERGED.fetch_add(1, Ordering::Relaxed);
+    Ok(true)
+}
+
+/// called by the #PF handler before anything else: a write to a merged page
+/// of the current address space, by userspace or a kernel copy, unmerges it.
+/// returns true if the faulting instruction can run again
+pub unsafe fn write_fault(code: usize) -> bool {
+    let code = PageFaultErrorCode::from_bits_truncate(code as u64);
+    let addr = Cr2::read();
+    if !code.contains(PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE)
+        || addr.as_u64() >= USER_SPACE_END {
+        return false;
+    }
+    let Some(addrsp) = context_storage().current().and_then(|context| context.read().addrsp.clone()) else {
+        return false;
+    };
+    let merged = unmerge(&mut addrsp.acquire_write(), Page::containing_address(addr));
+    merged.unwrap_or(false)
+}
+
+// the address space is used by `context`
+fn uses(context: &Context, addrsp: &Arc<RwLockUserAddrSpace>) -> bool {
+    context.addrsp.as_ref().is_some_and(|used| Arc::ptr_eq(used, addrsp))
+}
+
+unsafe fn scan_addrsp(addrsp: &mut UserAddrSpace, seen: &mut BTreeSet<u64>) -> usize {
+    let mut tracked = addrsp.tracked_frames().to_vec();
+    tracked.sort_unstable();
+    let mut merged = 0;
+    for region in addrsp.mapped_regions() {
+        if !region.flags.contains(PageTableFlags::WRITABLE) {
+            continue;
+        }
+        let start = Page::<Size4KiB>::containing_address(region.start);
+        for page in Page::range(start, start + region.len / PAGE_SIZE as u64) {
+            match merge_page(addrsp, page, &mut tracked, seen) {
+                Ok(true) => merged += 1,
+                Ok(false) => {}
+                // out of frames for stable ones, try next pass
+                Err(_) => return merged,
+            }
+        }
+    }
+    merged
+}
+
+// one pass over every address space, returns pages merged
+fn scan() -> usize {
+    // the paused contexts wait for this pass, it is not switched away
+    let _preempt = PreemptGuard::new();
+    let me = context_id();
+    let locks: Vec<_> = context_storage().iter()
+        .filter(|(id, _)| **id != me)
+        .map(|(_, lock)| Arc::clone(lock))
+        .collect();
+
+    let mut paused = Vec::new();
+    for lock in locks.iter() {
+        let Some(context) = lock.try_write() else { return 0 };
+        if !context.userspace {
+            continue;
+        }
+        if context.running || context.inside_syscall {
+            return 0;
+        }
+        paused.push(context);
+    }
+
+    let mut seen = BTreeSet::new();
+    let mut merged = 0;
+    for (index, context) in paused.iter().enumerate() {
+        let Some(addrsp) = context.addrsp.as_ref() else { continue };
+        // threads share it, it is scanned through the first of them
+        if paused[..index].iter().any(|other| uses(other, addrsp)) {
+            continue;
+        }
+        // syscalls and loaders hold a reference of their own while they use it
+        if Arc::strong_count(addrsp) != paused.iter().filter(|other| uses(other, addrsp)).count() {
+            continue;
+        }
+        let Some(mut addrsp) = addrsp.try_acquire_write() else { continue };
+        merged += unsafe { scan_addrsp(&mut addrsp, &mut seen) };
+    }
+    merged
+}
+
+// stable frames no page maps anymore, returns how many were freed
+fn release_unshared() -> usize {
+    let mut stable = STABLE.lock();
+    let before = stable.len();
+    stable.retain(|_, frame| {
+        let shared = frame_refcount(*frame) > 1;
+        if !shared {
+            frame_dealloc(*frame);
+        }
+        shared
+    });
+    before - stable.len()
+}
+
+/// stable frames and the pages mapping them
+pub fn ksm_stats() -> (usize, usize) {
+    let stable = STABLE.lock();
+    let sharing = stable.values().map(|frame| frame_refcount(*frame).saturating_sub(1) as usize).sum();
+    (stable.len(), sharing)
+}
+
+unsafe fn ksm_initcall(_: &InitCpuArg) {
+    if cmdline_flag("noksm") {
+        return;
+    }
+    match context_storage_mut().spawn(&SpawnOptions::kernel("ksm"), SpawnEntry::Func(ksm_main)) {
+        Ok(lock) => lock.write().set_status(Status::Runnable),
+        Err(err) => warnhart!("ksm: failed to spawn: {}", err),
+    }
+}
+initcall!(late, Bsp, ksm_initcall, order = 230);
+
+fn sleep(ns: u64) {
+    {
+        let contexts = context_storage();
+        let mut context = contexts.current()
+            .expect("failed to get ksm context")
+            .write();
+        sleep_until(&mut context, monotonic_ns() + ns);
+    }
+    unsafe {
+        CurrentArch::disable_interrupts();
+        switch_context();
+        CurrentArch::enable_interrupts();
+    }
+}
+
+extern "C" fn ksm_main() {
+    // new contexts start with interrupts disabled
+    unsafe { CurrentArch::enable_interrupts(); }
+
+    loop {
+        sleep(SCAN_INTERVAL_NS);
+        let merged = scan();
+        let released = release_unshared();
+        if merged != 0 || released != 0 {
+            let (frames, sharing) = ksm_stats();
+            infohart!(
+                "ksm: merged {} pages, {} pages share {} frames, {} unmerged so far",
+                merged, sharing, frames, UNMERGED.load(Ordering::Relaxed)
+            );
+        }
+    }
+}
+
+#[test_case]
+pub(crate) fn test_ksm_merge() {
+    use x86_64::VirtAddr;
+
+    let mut addrsp = unsafe { UserAddrSpace::new() }.unwrap();
+    let base = Page::<Size4KiB>::containing_address(VirtAddr::new(0x4000_0000));
+    let pages = [base, base + 1, base + 2];
+    for (page, fill) in pages.iter().zip([0x5a, 0x5a, 0xa5]) {
+        let frame = try_frame_alloc().unwrap();
+        unsafe {
+            slice::from_raw_parts_mut(frame.start_address().as_u64() as *mut u8, PAGE_SIZE).fill(fill);
+            addrsp.raw_map_to(*page, frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE).unwrap();
+            addrsp.push_tracked_frame(frame);
+        }
+    }
+    let frame_of = |addrsp: &mut UserAddrSpace, page: Page| mapping(addrsp, page).unwrap().0;
+
+    unsafe {
+        let mut tracked = addrsp.tracked_frames().to_vec();
+        tracked.sort_unstable();
+        let mut seen = BTreeSet::new();
+        // the first page is remembered, the second merges onto a copy of it, the third differs
+        assert!(!merge_page(&mut addrsp, pages[0], &mut tracked, &mut seen).unwrap());
+        assert!(merge_page(&mut addrsp, pages[1], &mut tracked, &mut seen).unwrap());
+        assert!(!merge_page(&mut addrsp, pages[2], &mut tracked, &mut seen).unwrap());
+        // the next pass finds the stable frame
+        let mut tracked = addrsp.tracked_frames().to_vec();
+        tracked.sort_unstable();
+        assert!(merge_page(&mut addrsp, pages[0], &mut tracked, &mut BTreeSet::new()).unwrap());
+        let shared = frame_of(&mut addrsp, pages[0]);
+        assert_eq!(frame_of(&mut addrsp, pages[1]), shared);
+        assert_eq!(frame_refcount(shared), 3);
+        assert!(addrsp.translate_user(pages[0].start_address(), true).is_err());
+        assert!(addrsp.mapped_regions()[0].flags.contains(PageTableFlags::WRITABLE));
+
+        // a write gets the page its own copy, the other keeps the stable frame
+        assert!(unmerge(&mut addrsp, pages[1]).unwrap());
+        assert!(!unmerge(&mut addrsp, pages[2]).unwrap());
+        let (private, _) = addrsp.translate_user(pages[1].start_address(), true).unwrap();
+        assert_ne!(private.as_u64(), shared.start_address().as_u64());
+        assert!(frame_bytes(PhysFrame::containing_address(private)).iter().all(|byte| *byte == 0x5a));
+        assert_eq!(frame_refcount(shared), 2);
+    }
+    drop(addrsp);
+    release_unshared();
+}
@@ -0,0 +1,120 @@
+use alloc::vec::Vec;
+use core::slice;
+use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::VirtAddr;
+use crate::mem::frame_allocator::{frame_alloc, frame_dealloc};
+use crate::mem::kvm::{kvm_alloc, kvm_map_page, kvm_unmap_page, KvmRegion};
+use crate::mem::stack::{fill_stack_pattern, record_context_stack_usage};
+use crate::mem::PAGE_SIZE;
+use crate::sync::IrqSpinlock;
+
+/**
+ *  context kernel stacks.
+ *
+ *  stacks live in the [`KvmRegion::Kstack`] region, shared by every address
+ *  space. each stack is a run of 4k frames, not necessarily contiguous, mapped
+ *  right above an unmapped guard page, so an overflow faults instead of
+ *  corrupting the neighbour. stacks of exited contexts go to a small pool and
+ *  are handed out again to contexts asking for the same size.
+ */
+
+// stacks kept mapped for reuse, extra ones give their frames back
+const KSTACK_POOL_MAX: usize = 16;
+
+static KSTACK_POOL: IrqSpinlock<Vec<KernelStack>> = IrqSpinlock::new(Vec::new());
+
+pub struct KernelStack {
+    // lowest mapped byte, the guard page sits right below
+    base: VirtAddr,
+    frames: Vec<PhysFrame>,
+}
+
+impl KernelStack {
+    pub fn pages(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len() * PAGE_SIZE
+    }
+
+    /// backing frame of each page, from the lowest address
+    pub fn frames(&self) -> &[PhysFrame] {
+        &self.frames
+    }
+
+    pub fn as_slice(&self) -> &'static [u8] {
+        unsafe { slice::from_raw_parts(self.base.as_ptr(), self.len()) }
+    }
+
+    pub fn as_mut_ptr(&self) -> *mut u8 {
+        self.base.as_mut_ptr()
+    }
+}
+
+unsafe fn unmap_pages(start: VirtAddr, frames: &[PhysFrame]) {
+    let start = Page::<Size4KiB>::containing_address(start);
+    for (page, frame) in Page::range(start, start + frames.len() as u64).zip(frames) {
+        kvm_unmap_page(page);
+        frame_dealloc(*frame);
+    }
+}
+
+fn map_fresh(pages: usize) -> Option<KernelStack> {
+    // one more page for the guard, left unmapped
+    let base = kvm_alloc(KvmRegion::Kstack, pages + 1)? + PAGE_SIZE as u64;
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let mut frames = Vec::with_capacity(pages);
+    let start = Page::<Size4KiB>::containing_address(base);
+    for page in Page::range(start, start + pages as u64) {
+        let mapped = frame_alloc().and_then(|frame| unsafe {
+            match kvm_map_page(page, frame, flags) {
+                Ok(()) => Some(frame),
+                Err(_) => {
+                    frame_dealloc(frame);
+                    None
+                }
+            }
+        });
+        match mapped {
+            Some(frame) => frames.push(frame),
+            None => {
+                // the address range stays reserved, see `kstack_free`
+                unsafe { unmap_pages(base, &frames) };
+                return None;
+            }
+        }
+    }
+    Some(KernelStack { base, frames })
+}
+
+/// kernel stack of `pages` pages filled with the high-water mark pattern
+pub fn kstack_alloc(pages: usize) -> Option<KernelStack> {
+    let reused = {
+        let mut pool = KSTACK_POOL.lock();
+        pool.iter().position(|stack| stack.pages() == pages).map(|i| pool.swap_remove(i))
+    };
+    let stack = reused.or_else(|| map_fresh(pages))?;
+    unsafe { fill_stack_pattern(stack.as_mut_ptr(), stack.len()) };
+    Some(stack)
+}
+
+/// give back the stack of an exited context, it must not run on it anymore
+pub fn kstack_free(stack: KernelStack) {
+    record_context_stack_usage(stack.as_slice());
+    let mut pool = KSTACK_POOL.lock();
+    if pool.len() < KSTACK_POOL_MAX {
+        pool.push(stack);
+        return;
+    }
+    drop(pool);
+    // other cpus may keep stale tlb entries, the address range is never handed out again
+    unsafe { unmap_pages(stack.base, &stack.frames) };
+}
+
+/// (pooled stacks, bytes held by them)
+pub fn kstack_pool_stats() -> (usize, usize) {
+    let pool = KSTACK_POOL.lock();
+    (pool.len(), pool.iter().map(KernelStack::len).sum())
+}
@@ -0,0 +1,236 @@
+use alloc::vec::Vec;
+use core::ops::Range;
+use x86_64::structures::paging::{Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB, Translate};
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::{PhysAddr, VirtAddr};
+use libvdso::error::{EEXIST, EINVAL, ENOMEM, KError, KResult};
+use shared::print_panic::PrintPanic;
+use shared::layout::{p4_base, PML4_ENTRY_SIZE, KERNEL_HEAP_P4, KERNEL_MMIO_P4, KERNEL_PERCPU_P4, KERNEL_VMALLOC_P4, KERNEL_VSTACK_P4};
+use crate::infohart;
+use crate::initcall;
+use crate::initcall::InitCpuArg;
+use crate::mem::frame_allocator::{frame_alloc, with_frame_alloc};
+use crate::mem::{get_kernel_pml4_page_table_addr, PAGE_SIZE};
+use crate::sync::IrqSpinlock;
+
+/**
+ *  kernel virtual address space manager.
+ *
+ *  the bootloader owns the top pml4 slots (kernel image, boot stack, framebuffer,
+ *  kernel arg, bootstrap) and leaves `KERNEL_RUNTIME_P4` alone. each of those
+ *  slots is a [`KvmRegion`] of 512 GiB, this module hands out page aligned
+ *  ranges inside them and maps pages into the kernel page table.
+ *
+ *  the p3 table of every region exists from early boot, user address spaces
+ *  copy the pml4 entries once and see later mappings through them.
+ */
+
+const REGION_SIZE: u64 = PML4_ENTRY_SIZE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KvmRegion {
+    Kstack,
+    Vmalloc,
+    Mmio,
+    Heap,
+    Percpu,
+}
+
+impl KvmRegion {
+    pub const ALL: [KvmRegion; 5] = [KvmRegion::Kstack, KvmRegion::Vmalloc, KvmRegion::Mmio, KvmRegion::Heap, KvmRegion::Percpu];
+
+    pub fn p4_index(self) -> u16 {
+        match self {
+            KvmRegion::Kstack => KERNEL_VSTACK_P4,
+            KvmRegion::Vmalloc => KERNEL_VMALLOC_P4,
+            KvmRegion::Mmio => KERNEL_MMIO_P4,
+            KvmRegion::Heap => KERNEL_HEAP_P4,
+            KvmRegion::Percpu => KERNEL_PERCPU_P4,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            KvmRegion::Kstack => "kstack",
+            KvmRegion::Vmalloc => "vmalloc",
+            KvmRegion::Mmio => "mmio",
+            KvmRegion::Heap => "heap",
+            KvmRegion::Percpu => "percpu",
+        }
+    }
+
+    pub fn base(self) -> VirtAddr {
+        VirtAddr::new(p4_base(self.p4_index()))
+    }
+
+    pub fn range(self) -> Range<VirtAddr> {
+        self.base()..self.base() + REGION_SIZE
+    }
+
+    fn slot(self) -> usize {
+        self as usize
+    }
+}
+
+struct RegionState {
+    // bump pointer, offset from region base
+    next: u64,
+    // freed ranges, offsets from region base, reused first fit
+    free: Vec<Range<u64>>,
+    // bytes handed out and not freed
+    allocated: u64,
+}
+
+const REGION_INIT: RegionState = RegionState { next: 0, free: Vec::new(), allocated: 0 };
+static KVM: IrqSpinlock<[RegionState; 5]> = IrqSpinlock::new([REGION_INIT; 5]);
+
+pub struct KvmStats {
+    pub region: KvmRegion,
+    pub allocated: u64,
+    // high-water mark of the bump pointer
+    pub reserved: u64,
+}
+
+unsafe fn kernel_mapper() -> OffsetPageTable<'static> {
+    // 物理地址空间恒等映射
+    OffsetPageTable::new(&mut *(get_kernel_pml4_page_table_addr() as *mut PageTable), VirtAddr::new(0))
+}
+
+unsafe fn kvm_initcall(_: &InitCpuArg) {
+    let pml4 = &mut *(get_kernel_pml4_page_table_addr() as *mut PageTable);
+    for region in KvmRegion::ALL {
+        let entry = &mut pml4[region.p4_index() as usize];
+        assert!(entry.is_unused(), "pml4 entry {} of kvm region {} is in use", region.p4_index(), region.name());
+
+        let p3 = frame_alloc().or_panic("failed to allocate page table of kvm region");
+        (p3.start_address().as_u64() as *mut PageTable).write(PageTable::new());
+        entry.set_frame(p3, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+        infohart!("kvm: {:<8} at 0x{:x}", region.name(), region.base().as_u64());
+    }
+}
+initcall!(early, Bsp, kvm_initcall, order = 21);
+
+/// reserve `pages` pages of virtual address space in `region`, nothing is mapped
+pub fn kvm_alloc(region: KvmRegion, pages: usize) -> Option<VirtAddr> {
+    let size = (pages * PAGE_SIZE) as u64;
+    if size == 0 {
+        return None;
+    }
+    let mut kvm = KVM.lock();
+    let state = &mut kvm[region.slot()];
+
+    let offset = match state.free.iter().position(|range| range.end - range.start >= size) {
+        Some(index) => {
+            let range = &mut state.free[index];
+            let offset = range.start;
+            range.start += size;
+            if range.is_empty() {
+                state.free.swap_remove(index);
+            }
+            offset
+        }
+        None => {
+            if state.next + size > REGION_SIZE {
+                return None;
+            }
+            state.next += size;
+            state.next - size
+        }
+    };
+    state.allocated += size;
+    Some(region.base() + offset)
+}
+
+/// give back a range of [`kvm_alloc`], its pages must be unmapped and flushed on every cpu
+pub fn kvm_free(region: KvmRegion, start: VirtAddr, pages: usize) {
+    let size = (pages * PAGE_SIZE) as u64;
+    let offset = start - region.base();
+    debug_assert!(offset + size <= REGION_SIZE, "0x{:x} is not in kvm region {}", start.as_u64(), region.name());
+
+    let mut kvm = KVM.lock();
+    let state = &mut kvm[region.slot()];
+    state.allocated -= size;
+    // the top of the bump area goes back to the bump pointer
+    if offset + size == state.next {
+        state.next = offset;
+        return;
+    }
+    match state.free.iter_mut().find(|range| range.end == offset || range.start == offset + size) {
+        Some(range) if range.end == offset => range.end += size,
+        Some(range) => range.start = offset,
+        None => state.free.push(offset..offset + size),
+    }
+}
+
+/// region containing `addr`
+pub fn kvm_region_of(addr: VirtAddr) -> Option<KvmRegion> {
+    KvmRegion::ALL.into_iter().find(|region| region.range().contains(&addr))
+}
+
+/// map `page` to `frame` in the kernel page table, page tables come from the frame allocator
+pub unsafe fn kvm_map_page(page: Page, frame: PhysFrame, flags: PageTableFlags) -> KResult<()> {
+    debug_assert!(kvm_region_of(page.start_address()).is_some(), "0x{:x} is not in a kvm region", page.start_address().as_u64());
+    let table_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    with_frame_alloc(|alloc| kernel_mapper().map_to_with_table_flags(page, frame, flags, table_flags, alloc))
+        .map(|flush| flush.ignore())
+        .map_err(|err| KError::new(match err {
+            MapToError::FrameAllocationFailed => ENOMEM,
+            _ => EEXIST,
+        }))
+}
+
+/// unmap `page` from the kernel page table on current cpu, returns the frame it pointed to
+pub unsafe fn kvm_unmap_page(page: Page) -> Option<PhysFrame> {
+    let (frame, flush) = kernel_mapper().unmap(page).ok()?;
+    flush.flush();
+    Some(frame)
+}
+
+/// physical address `addr` is mapped to in the kernel page table
+pub fn kvm_translate(addr: VirtAddr) -> Option<PhysAddr> {
+    unsafe { kernel_mapper() }.translate_addr(addr)
+}
+
+/// map `len` bytes of device memory at `phys` uncached, the offset inside the page is kept
+pub fn kvm_map_mmio(phys: PhysAddr, len: usize) -> KResult<VirtAddr> {
+    if len == 0 {
+        return Err(KError::new(EINVAL));
+    }
+    let start = PhysFrame::<Size4KiB>::containing_address(phys);
+    let end = PhysFrame::<Size4KiB>::containing_address(phys + (len - 1) as u64);
+    let pages = (end - start + 1) as usize;
+    let virt = kvm_alloc(KvmRegion::Mmio, pages).ok_or(KError::new(ENOMEM))?;
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH | PageTableFlags::NO_EXECUTE;
+    let first = Page::containing_address(virt);
+    for (i, frame) in PhysFrame::range_inclusive(start, end).enumerate() {
+        if let Err(err) = unsafe { kvm_map_page(first + i as u64, frame, flags) } {
+            (0..i).for_each(|j| unsafe { kvm_unmap_page(first + j as u64); });
+            kvm_free(KvmRegion::Mmio, virt, pages);
+            return Err(err);
+        }
+    }
+    Ok(virt + (phys.as_u64() & (PAGE_SIZE as u64 - 1)))
+}
+
+/// undo [`kvm_map_mmio`]
+pub fn kvm_unmap_mmio(virt: VirtAddr, len: usize) {
+    let first = Page::<Size4KiB>::containing_address(virt);
+    let last = Page::<Size4KiB>::containing_address(virt + (len.max(1) - 1) as u64);
+    for page in Page::range_inclusive(first, last) {
+        unsafe { kvm_unmap_page(page); }
+    }
+    // only freed frames wait for a shootdown, other cpus may still cache the range so it is never reused
+}
+
+pub fn kvm_stats() -> Vec<KvmStats> {
+    let kvm = KVM.lock();
+    KvmRegion::ALL.into_iter()
+        .map(|region| KvmStats {
+            region,
+            allocated: kvm[region.slot()].allocated,
+            reserved: kvm[region.slot()].next,
+        })
+        .collect()
+}
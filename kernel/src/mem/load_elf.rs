@@ -1,35 +1,79 @@
 use alloc::sync::Arc;
 use log::{debug, info, warn};
-use x86_64::{align_up, structures::paging::{mapper::{MappedFrame, TranslateResult}, page::PageRangeInclusive, FrameAllocator, Mapper, OffsetPageTable, Page, PageTableIndex, PhysFrame, Size4KiB, Translate}, PhysAddr, VirtAddr};
+use x86_64::{align_up, structures::paging::{mapper::{MappedFrame, TranslateResult}, page::PageRangeInclusive, FrameAllocator, Mapper, OffsetPageTable, Page, PageTableIndex, PhysFrame, Size4KiB, Translate}, VirtAddr};
 use x86_64::structures::paging::page_table::PageTableFlags as PTFlags;
 use xmas_elf::{dynamic, header::{self, Type as EType}, program::{self, SegmentData, Type as ShType}, sections::Rela, ElfFile};
-use core::{cmp, iter::Step, mem::size_of, ptr};
-use spin::RwLockWriteGuard;
-
-use shared::{arg::TlsTemplate, print_panic::PrintPanic};
+use core::{cmp, iter::Step, mem::size_of, ptr, slice, str};
+
+use libvdso::auxv::{AT_BASE, AT_ENTRY, AT_NULL, AT_PAGESZ, AT_PHDR, AT_PHENT, AT_PHNUM};
+use libvdso::error::{ENOEXEC, KError, KResult};
+use shared::arg::{KernelArg, TlsTemplate};
+use shared::layout::USER_INTERP_BASE;
+use spin::Once;
+use crate::arch_spec::memcopy;
 use crate::infohart;
-use crate::mem::frame_allocator::frame_alloc;
+use crate::mem::frame_allocator::{frame_dealloc, try_frame_alloc};
 use crate::mem::PAGE_SIZE;
 use crate::mem::user_addr_space::{RwLockUserAddrSpace, UserAddrSpace};
 
-/// load elf to userspace, return entry point
-pub unsafe fn elf_copy_to_addrsp(
-    elf: &[u8],
-    addrsp: Arc<RwLockUserAddrSpace>
-) -> VirtAddr {
-    let elf_file = ElfFile::new(elf).or_panic("failed to parse elf");
-    let elf_bytes_phys_addr = PhysAddr::new(&elf[0] as *const _ as u64);
-    info!("mapping elf, size: {}", elf.len());
+// dynamic loader from boot.cfg `interp`, physical memory is identity mapped in every address space
+static INTERP: Once<&'static [u8]> = Once::new();
+const USER_STACK_SIZE: usize = 16 * PAGE_SIZE;
+
+pub fn init_interp(arg: &KernelArg) {
+    if arg.interp_len != 0 {
+        INTERP.call_once(|| unsafe {
+            slice::from_raw_parts(arg.interp_base as *const u8, arg.interp_len)
+        });
+    }
+}
+
+// malformed or unsupported elf
+fn bad_elf(reason: &str) -> KError {
+    warn!("failed to load elf: {}", reason);
+    KError::new(ENOEXEC)
+}
+
+/// what a loaded elf tells the program it starts, addresses have the load bias applied
+pub struct LoadedElf<'a> {
+    pub entry: VirtAddr,
+    pub load_bias: u64,
+    /// program headers in the loaded image, 0 if no LOAD segment covers them
+    pub phdr: u64,
+    pub phent: usize,
+    pub phnum: usize,
+    /// path of the dynamic loader from PT_INTERP
+    pub interp: Option<&'a str>,
+}
+
+/// load elf to userspace with every address moved by `load_bias`, which must
+/// be 0 for ET_EXEC and page aligned for ET_DYN.
+/// `ENOEXEC` if elf is malformed or unsupported, `ENOMEM` if memory runs out,
+/// frames mapped so far are freed with the address space.
+pub unsafe fn elf_copy_to_addrsp<'a>(
+    elf: &'a [u8],
+    addrsp: Arc<RwLockUserAddrSpace>,
+    load_bias: u64,
+) -> KResult<LoadedElf<'a>> {
+    let elf_file = ElfFile::new(elf).map_err(bad_elf)?;
+    info!("mapping elf, size: {}, load bias: 0x{:x}", elf.len(), load_bias);
 
     let mut addrsp_guard = addrsp.acquire_write();
 
     for program_header in elf_file.program_iter() {
-        program::sanity_check(program_header, &elf_file).or_panic("kernel progran sanity check failed");
+        program::sanity_check(program_header, &elf_file).map_err(bad_elf)?;
     }
-    header::sanity_check(&elf_file).or_panic("kernel header sanity check failed");
+    header::sanity_check(&elf_file).map_err(bad_elf)?;
 
     // get kernel virtual address offset
     let elf_pt2_type = elf_file.header.pt2.type_().as_type();
+    if load_bias % PAGE_SIZE as u64 != 0 || (load_bias != 0 && elf_pt2_type != EType::SharedObject) {
+        return Err(bad_elf("only page aligned shared objects can be moved"));
+    }
+
+    let interp = elf_interp(elf, &elf_file)?;
+    let phdr = elf_phdr(&elf_file).map(|addr| addr + load_bias).unwrap_or(0);
+
     // kernel elf 定义的起始虚拟地址 和 需要用到的虚拟地址空间大小
     let (elf_start_virt_addr, elf_virt_addr_space_size) = match elf_pt2_type {
         EType::Executable | EType::SharedObject => {
@@ -47,7 +91,7 @@ pub unsafe fn elf_copy_to_addrsp(
                 });
             (min_virt_addr, (max_virt_addr - min_virt_addr) as usize)
         }
-        _ => { panic!("elf has type {:?} which cannot be processed.", elf_pt2_type) }
+        _ => { return Err(bad_elf("elf type is neither executable nor shared object")) }
     };
 
     let mut tls_template: Option<TlsTemplate> = None;
@@ -60,18 +104,12 @@ pub unsafe fn elf_copy_to_addrsp(
             continue;
         }
 
-        let seg_bytes_start_addr = elf_bytes_phys_addr + ph.offset();
-
-        let seg_start_virt_addr = VirtAddr::new(ph.virtual_addr());
+        let seg_start_virt_addr = VirtAddr::new(ph.virtual_addr() + load_bias);
         // 段 bss 在实际虚拟内存结束位置，bss 可能追加在 fs 后面
         let seg_mem_end_virt_addr = seg_start_virt_addr + ph.mem_size();
         // 段 fs 在实际虚拟内存结束位置
         let seg_file_end_virt_addr = seg_start_virt_addr + ph.file_size();
 
-        // elf 段数据在物理地址所在的物理帧，end inclusive
-        let seg_bytes_start_phys_frame = PhysFrame::<Size4KiB>::containing_address(seg_bytes_start_addr);
-        let seg_bytes_end_phys_frame = PhysFrame::<Size4KiB>::containing_address(seg_bytes_start_addr + ph.file_size() - 1u64);
-
         // 段实际虚拟内存位置对应的页，end inclusive
         let seg_start_page = Page::<Size4KiB>::containing_address(seg_start_virt_addr);
         let seg_end_page = Page::<Size4KiB>::containing_address(seg_mem_end_virt_addr - 1u64);
@@ -80,8 +118,8 @@ pub unsafe fn elf_copy_to_addrsp(
 
         match sh_type {
             ShType::Load => { // Loadable segment
-            infohart!("loading LOAD segment from phys addr 0x{:x} to virt addr 0x{:x}, file_size = {}, mem_size = {}",
-                seg_bytes_start_addr.as_u64(), ph.virtual_addr(), ph.file_size(), ph.mem_size()
+            infohart!("loading LOAD segment from offset 0x{:x} to virt addr 0x{:x}, file_size = {}, mem_size = {}",
+                ph.offset(), ph.virtual_addr(), ph.file_size(), ph.mem_size()
             );
 
                 let seg_flags = {
@@ -91,20 +129,27 @@ pub unsafe fn elf_copy_to_addrsp(
                     f
                 };
 
-                // setup mapping to the fs part of segment and kernel pml4 table.
-                for original_frame in PhysFrame::range_inclusive(seg_bytes_start_phys_frame, seg_bytes_end_phys_frame) {
-                    let seg_page = seg_start_page + (original_frame - seg_bytes_start_phys_frame);
-
-                    let new_frame = frame_alloc()
-                        .or_panic("failed to allocate new phys frame for bss segment.");
-
-                    ptr::copy(
-                        original_frame.start_address().as_u64() as *const u8,
-                        new_frame.start_address().as_u64() as *mut u8,
-                        PAGE_SIZE
-                    );
-
-                    addrsp_guard.raw_map_to(seg_page, new_frame, seg_flags);
+                // file bytes are read through `elf` wherever it is mapped, page by page
+                // from the file offset matching the start of the first page
+                let page_offset = seg_start_virt_addr.as_u64() & (PAGE_SIZE as u64 - 1);
+                let window_start = ph.offset().checked_sub(page_offset)
+                    .ok_or_else(|| bad_elf("segment offset and address disagree in the page"))? as usize;
+                let file_pages = (page_offset + ph.file_size()).div_ceil(PAGE_SIZE as u64);
+                for index in 0..file_pages {
+                    let seg_page = seg_start_page + index;
+                    let src = elf.get(window_start + index as usize * PAGE_SIZE..).unwrap_or(&[]);
+                    let src = &src[..src.len().min(PAGE_SIZE)];
+
+                    let new_frame = try_frame_alloc()?;
+                    let new_frame_ptr = new_frame.start_address().as_u64() as *mut u8;
+                    memcopy::copy(new_frame_ptr, src.as_ptr(), src.len());
+                    memcopy::zero(new_frame_ptr.add(src.len()), PAGE_SIZE - src.len());
+
+                    // only mapped frames are tracked, see `UserAddrSpace::drop`
+                    if let Err(err) = addrsp_guard.raw_map_to(seg_page, new_frame, seg_flags) {
+                        frame_dealloc(new_frame);
+                        return Err(err);
+                    }
                     addrsp_guard.push_tracked_frame(new_frame);
                 }
 
@@ -130,13 +175,11 @@ pub unsafe fn elf_copy_to_addrsp(
                     // 如果不是对齐的，我们需要特殊处理 bss 段的第一个页
                     // 分配一个新的物理页，把这一页复制过去，然后再 zero-fill 新复制的页的 bss 段
                     let last_page = Page::<Size4KiB>::containing_address(seg_bss_start_virt_addr - 1u64);
-                    let new_frame = copy_page_and_remap(last_page, &mut addrsp_guard)
-                        .or_panic("failed to remap the page of of fs end LOAD segment.");
+                    let new_frame = copy_page_and_remap(last_page, &mut addrsp_guard)?;
 
                     let new_frame_phys_addr = new_frame.start_address().as_u64() as *mut u8;
-                    ptr::write_bytes(
+                    memcopy::zero(
                         new_frame_phys_addr.add(file_end_relative_addr as usize),
-                        0u8,
                         4096 - file_end_relative_addr as usize
                     )
                 }
@@ -149,22 +192,23 @@ pub unsafe fn elf_copy_to_addrsp(
                 let seg_bss_end_page = Page::<Size4KiB>::containing_address(seg_bss_end_virt_addr - 1u64);
 
                 for bss_page in Page::range_inclusive(seg_bss_start_page, seg_bss_end_page) {
-                    let frame = frame_alloc()
-                        .or_panic("failed to allocate new phys frame for bss segment.");
+                    let frame = try_frame_alloc()?;
 
                     let frame_ptr = frame.start_address().as_u64() as *mut u8;
-                    ptr::write_bytes(frame_ptr, 0, 4096);
-                    addrsp_guard.raw_map_to(bss_page, frame, seg_flags);
-                    addrsp_guard.push_tracked_frame(frame)
+                    memcopy::zero(frame_ptr, PAGE_SIZE);
+                    if let Err(err) = addrsp_guard.raw_map_to(bss_page, frame, seg_flags) {
+                        frame_dealloc(frame);
+                        return Err(err);
+                    }
+                    addrsp_guard.push_tracked_frame(frame);
                 }
             }
             ShType::Dynamic => { // dynamic link data
-                let data = ph.get_data(&elf_file)
-                    .or_panic("failed to load kernel elf dynamic data");
+                let data = ph.get_data(&elf_file).map_err(bad_elf)?;
                 let data = if let SegmentData::Dynamic64(data) = data {
                     data
                 } else {
-                    panic!("not dynamic 64 data")
+                    return Err(bad_elf("not dynamic 64 data"))
                 };
 
                 // Relocation entries with addends
@@ -173,27 +217,27 @@ pub unsafe fn elf_copy_to_addrsp(
                 let mut rela_ent = None;
 
                 for rel in data {
-                    let tag = rel.get_tag().or_panic("failed get tag of dynamic data");
+                    let tag = rel.get_tag().map_err(bad_elf)?;
                     match tag {
                         dynamic::Tag::Rela => {
-                            let ptr = rel.get_ptr().or_panic("failed to get rela ptr of dynamic data");
+                            let ptr = rel.get_ptr().map_err(bad_elf)?;
                             let prev = rela.replace(ptr);
                             if prev.is_some() {
-                                panic!("Dynamic section contains more than one Rela entry");
+                                return Err(bad_elf("dynamic section contains more than one Rela entry"));
                             }
                         }
                         dynamic::Tag::RelaSize => {
-                            let val = rel.get_val().or_panic("failed to get rela size of dynamic data");
+                            let val = rel.get_val().map_err(bad_elf)?;
                             let prev = rela_size.replace(val);
                             if prev.is_some() {
-                                panic!("Dynamic section contains more than one RelaSize entry");
+                                return Err(bad_elf("dynamic section contains more than one RelaSize entry"));
                             }
                         }
                         dynamic::Tag::RelaEnt => {
-                            let val = rel.get_val().or_panic("failed to get rela entry of dynamic data");
+                            let val = rel.get_val().map_err(bad_elf)?;
                             let prev = rela_ent.replace(val);
                             if prev.is_some() {
-                                panic!("Dynamic section contains more than one RelaEnt entry");
+                                return Err(bad_elf("dynamic section contains more than one RelaEnt entry"));
                             }
                         }
                         _ => {}
@@ -210,13 +254,13 @@ pub unsafe fn elf_copy_to_addrsp(
                     }
                 };
 
-                let total_size = rela_size.or_panic("RelaSize entry is missing");
-                let entry_size = rela_ent.or_panic("RelaEnt entry is missing");
+                let total_size = rela_size.ok_or_else(|| bad_elf("RelaSize entry is missing"))?;
+                let entry_size = rela_ent.ok_or_else(|| bad_elf("RelaEnt entry is missing"))?;
 
                 infohart!("loading DYNAMIC segment: RELA = 0x{:x}, RELASIZE = {}, RELAENT = {}", rela.unwrap(), total_size, entry_size);
 
                 if entry_size as usize != size_of::<Rela<u64>>() {
-                    panic!("unsupported dynamic relative entry size: {entry_size}");
+                    return Err(bad_elf("unsupported dynamic relative entry size"));
                 }
 
                 let rela_count = total_size / entry_size;
@@ -227,7 +271,7 @@ pub unsafe fn elf_copy_to_addrsp(
                     let rela = &*(&elf[entry_ptr_phys_addr_idx as usize] as *const _ as *const Rela<u64>);
 
                     if rela.get_symbol_table_index() != 0 {
-                        panic!("relocation using symbol table is not supported")
+                        return Err(bad_elf("relocation using symbol table is not supported"));
                     }
 
                     // https://intezer.com/blog/malware-analysis/executable-and-linkable-format-101-part-3-relocations/
@@ -235,13 +279,13 @@ pub unsafe fn elf_copy_to_addrsp(
                         8 => { // R_X86_64_RELATIVE: B + A
                             // TODO: check rela offset is at virtual space of LOAD segments
 
-                            let offset = VirtAddr::new(rela.get_offset());
-                            let attend = VirtAddr::new(rela.get_addend());
+                            let offset = VirtAddr::new(rela.get_offset() + load_bias);
+                            let attend = VirtAddr::new(rela.get_addend() + load_bias);
 
-                            copy_pages_and_write(offset, &attend.as_u64().to_ne_bytes(), &mut addrsp_guard);
+                            copy_pages_and_write(offset, &attend.as_u64().to_ne_bytes(), &mut addrsp_guard)?;
                         }
                         _ => {
-                            panic!("reallocation with type {} is not supported", rela.get_type())
+                            return Err(bad_elf("relocation type is not supported"));
                         }
                     }
                 }
@@ -254,13 +298,14 @@ pub unsafe fn elf_copy_to_addrsp(
             ShType::GnuRelro => {
                 infohart!("loading GNURELRO segment: start_page: {:?}, end_page: {:?}", seg_start_page, seg_end_page);
 
-                update_page_flag(&mut addrsp_guard, Page::range_inclusive(seg_start_page, seg_end_page), !PTFlags::WRITABLE);
+                update_page_flag(&mut addrsp_guard, Page::range_inclusive(seg_start_page, seg_end_page), !PTFlags::WRITABLE)?;
             }
             ShType::Tls => {
                 tls_template.replace(TlsTemplate {
                     start_virt_addr: seg_start_virt_addr.as_u64(),
                     mem_size: ph.mem_size() as usize,
-                    file_size: ph.file_size() as usize
+                    file_size: ph.file_size() as usize,
+                    align: ph.align() as usize
                 });
             }
             _ => {}
@@ -276,15 +321,99 @@ pub unsafe fn elf_copy_to_addrsp(
             continue;
         }
 
-        let seg_start_virt_addr = VirtAddr::new(ph.virtual_addr());
+        let seg_start_virt_addr = VirtAddr::new(ph.virtual_addr() + load_bias);
         let seg_mem_end_virt_addr = seg_start_virt_addr + ph.mem_size();
         let seg_start_page = Page::<Size4KiB>::containing_address(seg_start_virt_addr);
         let seg_end_page = Page::<Size4KiB>::containing_address(seg_mem_end_virt_addr - 1u64);
 
-        update_page_flag(&mut addrsp_guard, Page::range_inclusive(seg_start_page, seg_end_page), !PTFlags::BIT_9);
+        update_page_flag(&mut addrsp_guard, Page::range_inclusive(seg_start_page, seg_end_page), !PTFlags::BIT_9)?;
     }
 
-    VirtAddr::new(elf_file.header.pt2.entry_point())
+    Ok(LoadedElf {
+        entry: VirtAddr::new(elf_file.header.pt2.entry_point() + load_bias),
+        load_bias,
+        phdr,
+        phent: elf_file.header.pt2.ph_entry_size() as usize,
+        phnum: elf_file.header.pt2.ph_count() as usize,
+        interp,
+    })
+}
+
+// path in PT_INTERP without the trailing nul
+fn elf_interp<'a>(elf: &'a [u8], elf_file: &ElfFile) -> KResult<Option<&'a str>> {
+    let Some(ph) = elf_file.program_iter().find(|ph| matches!(ph.get_type(), Ok(ShType::Interp))) else {
+        return Ok(None);
+    };
+    let path = elf.get(ph.offset() as usize..(ph.offset() + ph.file_size()) as usize)
+        .ok_or_else(|| bad_elf("PT_INTERP is out of file"))?;
+    let path = path.split(|b| *b == 0).next().unwrap_or(path);
+    str::from_utf8(path).map(Some).map_err(|_| bad_elf("PT_INTERP is not utf-8"))
+}
+
+// unbiased virtual address of program headers, from PT_PHDR or the LOAD segment containing them
+fn elf_phdr(elf_file: &ElfFile) -> Option<u64> {
+    if let Some(ph) = elf_file.program_iter().find(|ph| matches!(ph.get_type(), Ok(ShType::Phdr))) {
+        return Some(ph.virtual_addr());
+    }
+    let ph_offset = elf_file.header.pt2.ph_offset();
+    elf_file.program_iter()
+        .filter(|ph| matches!(ph.get_type(), Ok(ShType::Load)))
+        .find(|ph| ph.offset() <= ph_offset && ph_offset < ph.offset() + ph.file_size())
+        .map(|ph| ph.virtual_addr() + (ph_offset - ph.offset()))
+}
+
+/// load a program and the interpreter it asks for with PT_INTERP, then set up
+/// its stack. returns entry and stack pointer, the entry is in the interpreter if there is one.
+/// the owning context of `addrsp` must not be locked by the caller.
+pub unsafe fn load_program(name: &str, elf: &[u8], addrsp: &Arc<RwLockUserAddrSpace>) -> KResult<(VirtAddr, u64)> {
+    let main = elf_copy_to_addrsp(elf, Arc::clone(addrsp), 0)?;
+    infohart!("{} entry: 0x{:x}", name, main.entry.as_u64());
+
+    let interp = match main.interp {
+        None => None,
+        Some(path) => {
+            let image = INTERP.get().ok_or_else(|| bad_elf("interpreter is required but not loaded, set `interp` in boot.cfg"))?;
+            infohart!("loading interpreter {} for {}", path, name);
+            Some(elf_copy_to_addrsp(image, Arc::clone(addrsp), USER_INTERP_BASE)?)
+        }
+    };
+    let entry = interp.as_ref().map_or(main.entry, |interp| interp.entry);
+    let stack_pointer = push_initial_stack(addrsp, &main, interp.as_ref())?;
+    Ok((entry, stack_pointer))
+}
+
+/// allocate the stack a program starts with and put argc, empty argv and envp
+/// and the auxiliary vector on it, returns the stack pointer.
+/// with an interpreter, AT_BASE is where it is loaded and AT_ENTRY still points to `main`.
+fn push_initial_stack(addrsp: &RwLockUserAddrSpace, main: &LoadedElf, interp: Option<&LoadedElf>) -> KResult<u64> {
+    let image: [u64; 17] = [
+        0, // argc
+        0, // argv
+        0, // envp
+        AT_PHDR, main.phdr,
+        AT_PHENT, main.phent as u64,
+        AT_PHNUM, main.phnum as u64,
+        AT_PAGESZ, PAGE_SIZE as u64,
+        AT_BASE, interp.map_or(0, |interp| interp.load_bias),
+        AT_ENTRY, main.entry.as_u64(),
+        AT_NULL, 0,
+    ];
+    let image_len = size_of::<[u64; 17]>();
+
+    let stack = addrsp.alloc(USER_STACK_SIZE)?;
+    // stack pointer is 16 bytes aligned at entry and points to argc
+    let stack_pointer = (stack.ptr() as u64 + stack.capacity() as u64 - image_len as u64) & !0xf;
+    // the image is in the last page of the stack
+    let (phys_addr, len) = match addrsp.translate_user(VirtAddr::new(stack_pointer), true) {
+        Ok(translated) => translated,
+        Err(err) => {
+            let _ = addrsp.free(&stack);
+            return Err(err);
+        }
+    };
+    assert!(len as usize >= image_len, "initial stack crosses a page");
+    unsafe { ptr::copy_nonoverlapping(image.as_ptr() as *const u8, phys_addr.as_u64() as *mut u8, image_len); }
+    Ok(stack_pointer)
 }
 
 /// copy underlying phys frame of a page to new allocated frame and remap page to the new one
@@ -292,36 +421,36 @@ pub unsafe fn elf_copy_to_addrsp(
 /// `page` should be a page mapped by a Load segment.
 unsafe fn copy_page_and_remap(
     page: Page,
-    addrsp: &mut RwLockWriteGuard<UserAddrSpace>,
-) -> Option<PhysFrame> {
+    addrsp: &mut UserAddrSpace,
+) -> KResult<PhysFrame> {
     let (curr_frame, flags) = match addrsp.raw_translate(page.start_address()) {
         TranslateResult::Mapped { frame, offset: _, flags, } => {
-            if let MappedFrame::Size4KiB(frame) = frame { (frame, flags) } else { return None }
+            if let MappedFrame::Size4KiB(frame) = frame { (frame, flags) } else { return Err(bad_elf("address is in a huge page")) }
         },
-        _ => return None
+        _ => return Err(bad_elf("address is not in a LOAD segment"))
     };
 
     if flags.contains(PTFlags::BIT_9) {
-        return Some(curr_frame)
+        return Ok(curr_frame)
     }
 
     // allocate new frame
-    let new_frame = match frame_alloc() {
-        Some(f) => f,
-        None => return None
-    };
-    addrsp.push_tracked_frame(new_frame.clone());
+    let new_frame = try_frame_alloc()?;
 
     // copy no overlappiong
     let curr_frame_ptr = curr_frame.start_address().as_u64() as *const u8;
     let new_frame_ptr = new_frame.start_address().as_u64() as *mut u8;
-    ptr::copy_nonoverlapping(curr_frame_ptr, new_frame_ptr, 4096usize);
+    memcopy::copy(new_frame_ptr, curr_frame_ptr, PAGE_SIZE);
 
-    // remap this page
+    // remap this page, unmap frees the old frame, it is tracked
     addrsp.raw_unmap(page);
-    addrsp.raw_map_to(page, new_frame, flags | PTFlags::BIT_9);
+    if let Err(err) = addrsp.raw_map_to(page, new_frame, flags | PTFlags::BIT_9) {
+        frame_dealloc(new_frame);
+        return Err(err);
+    }
+    addrsp.push_tracked_frame(new_frame);
 
-    Some(new_frame)
+    Ok(new_frame)
 }
 
 /// 复制 addr 到 addr + buf_len 所在的 page 到新分配的物理页帧，
@@ -331,23 +460,20 @@ unsafe fn copy_page_and_remap(
 unsafe fn copy_pages_and_write(
     addr: VirtAddr,
     buf: &[u8],
-    addrsp: &mut RwLockWriteGuard<UserAddrSpace>
-) {
+    addrsp: &mut UserAddrSpace
+) -> KResult<()> {
     // We can't know for sure that contiguous virtual address are contiguous
     // in physical memory, so we iterate of the pages spanning the
     // addresses, translate them to frames and copy the data.
 
     let end_inclusive_addr = Step::forward_checked(addr, buf.len() - 1)
-        .expect("the end address should be in the virtual address space");
+        .ok_or_else(|| bad_elf("relocation is out of the virtual address space"))?;
     let start_page = Page::<Size4KiB>::containing_address(addr);
     let end_inclusive_page = Page::<Size4KiB>::containing_address(end_inclusive_addr);
 
     for page in start_page..=end_inclusive_page {
         // Translate the virtual page to the physical frame.
-        let phys_addr = unsafe {
-            copy_page_and_remap(page, addrsp)
-                .or_panic("failed to remap the page of the LOAD segnemt the addr points to")
-        };
+        let phys_addr = unsafe { copy_page_and_remap(page, addrsp)? };
 
         // Figure out which address range we want to copy from the frame.
 
@@ -389,13 +515,14 @@ unsafe fn copy_pages_and_write(
         // Do the actual copy.
         dest.copy_from_slice(src);
     }
+    Ok(())
 }
 
 unsafe fn update_page_flag(
-    addrsp: &mut RwLockWriteGuard<UserAddrSpace>,
+    addrsp: &mut UserAddrSpace,
     range_inclusive: PageRangeInclusive<Size4KiB>,
     flag: PTFlags
-) {
+) -> KResult<()> {
     for page in range_inclusive {
         let translated = addrsp.raw_translate(page.start_address());
         let flags = if let TranslateResult::Mapped {
@@ -405,9 +532,10 @@ unsafe fn update_page_flag(
         } = translated {
             flags
         } else {
-            panic!("page is not mapped while parsing segment GNURELTRO")
+            return Err(bad_elf("GNU_RELRO segment is not covered by LOAD segments"))
         };
 
         addrsp.raw_update_flags(page, flags & flag);
     }
+    Ok(())
 }
\ No newline at end of file
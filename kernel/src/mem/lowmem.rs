@@ -0,0 +1,91 @@
+use core::ops::Range;
+use x86_64::PhysAddr;
+use shared::arg::MemoryRegion;
+use crate::initcall;
+use crate::initcall::{kernel_arg, InitCpuArg};
+use crate::mem::PAGE_SIZE;
+use crate::sync::Spinlock;
+use crate::infohart;
+
+/**
+ *  low memory (< 1 MiB) page allocator.
+ *
+ *  real mode code, e.g. the ap trampoline, must live below 1 MiB. the usable
+ *  regions come from uefi memory map via bootloader, the global frame allocator
+ *  never hands out frames below 1 MiB so they do not conflict.
+ */
+
+const MAX_FREE_RANGES: usize = 32;
+const EMPTY_RANGE: Range<u64> = 0..0;
+
+static LOW_MEM: Spinlock<LowMem> = Spinlock::new(LowMem::new());
+
+struct LowMem {
+    // page aligned, end exclusive, empty ranges are unused slots
+    free: [Range<u64>; MAX_FREE_RANGES],
+}
+
+impl LowMem {
+    const fn new() -> Self {
+        Self { free: [EMPTY_RANGE; MAX_FREE_RANGES] }
+    }
+
+    fn insert(&mut self, range: Range<u64>) -> bool {
+        if range.is_empty() {
+            return true;
+        }
+        // merge with a neighbour first
+        for free in self.free.iter_mut().filter(|r| !r.is_empty()) {
+            if free.end == range.start {
+                free.end = range.end;
+                return true;
+            }
+            if range.end == free.start {
+                free.start = range.start;
+                return true;
+            }
+        }
+        match self.free.iter_mut().find(|r| r.is_empty()) {
+            Some(slot) => {
+                *slot = range;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn alloc(&mut self, pages: usize) -> Option<u64> {
+        let size = (pages * PAGE_SIZE) as u64;
+        let free = self.free.iter_mut().find(|r| r.end - r.start >= size)?;
+        let addr = free.start;
+        free.start += size;
+        Some(addr)
+    }
+}
+
+unsafe fn lowmem_initcall(_: &InitCpuArg) {
+    let arg = kernel_arg();
+    let mut low_mem = LOW_MEM.lock();
+    let mut total = 0;
+
+    for &MemoryRegion { start, length, .. } in &arg.low_mem_regions[..arg.low_mem_regions_len] {
+        let page = PAGE_SIZE as u64;
+        let range = (start + page - 1) / page * page..(start + length) / page * page;
+        total += range.end.saturating_sub(range.start);
+        low_mem.insert(range);
+    }
+
+    infohart!("low memory: {} KiB usable", total / 1024);
+}
+initcall!(early, Bsp, lowmem_initcall, order = 12);
+
+/// allocate `pages` contiguous pages below 1 MiB
+pub fn lowmem_alloc(pages: usize) -> Option<PhysAddr> {
+    LOW_MEM.lock().alloc(pages).map(PhysAddr::new)
+}
+
+/// give pages from [`lowmem_alloc`] back
+pub fn lowmem_free(addr: PhysAddr, pages: usize) {
+    let start = addr.as_u64();
+    LOW_MEM.lock().insert(start..start + (pages * PAGE_SIZE) as u64);
+}
@@ -0,0 +1,79 @@
+use shared::arg::{MemoryRegion, MemoryRegionKind};
+use crate::initcall;
+use crate::initcall::{kernel_arg, InitCpuArg};
+use crate::mem::frame_allocator::with_frame_alloc;
+use crate::{infohart, warnhart};
+
+/**
+ *  physical memory map passed by bootloader.
+ *
+ *  every unavailable region is tagged with its owner, the map is dumped at boot
+ *  and reclaimable regions (acpi tables) are handed to frame allocator once the
+ *  kernel is done with them. uefi boot services memory is usable from the start,
+ *  nothing the kernel needs lives there after exit_boot_services.
+ */
+
+fn unav_regions() -> &'static [MemoryRegion] {
+    let arg = kernel_arg();
+    &arg.unav_phys_mem_regions[..arg.unav_phys_mem_regions_len]
+}
+
+unsafe fn memmap_dump_initcall(_: &InitCpuArg) {
+    infohart!("physical memory map, unavailable regions:");
+    for region in unav_regions() {
+        infohart!(
+            "  [{:#012x}-{:#012x}) {:>8} KiB {}",
+            region.start,
+            region.start + region.length,
+            region.length / 1024,
+            region.kind.name()
+        );
+    }
+}
+initcall!(early, Bsp, memmap_dump_initcall, order = 11);
+
+fn overlaps(a: &MemoryRegion, b: &MemoryRegion) -> bool {
+    a.start < b.start + b.length && b.start < a.start + a.length
+}
+
+// acpi tables are parsed by bootloader, kernel never reads them
+unsafe fn memmap_reclaim_initcall(_: &InitCpuArg) {
+    let regions = unav_regions();
+    let mut reclaimed = 0;
+
+    for region in regions.iter().filter(|r| r.kind.reclaimable()) {
+        // bootloader allocations may span the region, keep it then
+        if regions.iter().any(|other| !other.kind.reclaimable() && overlaps(region, other)) {
+            warnhart!("memmap: {} region at {:#x} overlaps a reserved region, kept", region.kind.name(), region.start);
+            continue;
+        }
+        if !with_frame_alloc(|alloc| alloc.reclaim(region.start..region.start + region.length)) {
+            warnhart!("memmap: too many reclaimed regions, rest is kept");
+            break;
+        }
+        reclaimed += region.length;
+    }
+
+    infohart!("memmap: reclaimed {} KiB", reclaimed / 1024);
+}
+initcall!(late, Bsp, memmap_reclaim_initcall);
+
+/// whether userspace drivers may map physical `start..start + len`: it is above
+/// ram or inside device memory reported by firmware, and no region the kernel
+/// uses itself (local apic, io apics, framebuffer, images, tables) overlaps it
+pub fn is_device_memory(start: u64, len: u64) -> bool {
+    let Some(end) = start.checked_add(len) else { return false };
+    let range = MemoryRegion { start, length: len, kind: MemoryRegionKind::Mmio };
+    let arg = kernel_arg();
+    let apic = |base: u64| overlaps(&range, &MemoryRegion { start: base, length: 4096, kind: MemoryRegionKind::Mmio });
+
+    if apic(arg.acpi.local_apic_phys())
+        || unsafe { arg.acpi.io_apics(arg.phys_mem_mapped_addr) }.iter().any(|io_apic| apic(io_apic.address as u64)) {
+        return false;
+    }
+    if unav_regions().iter().any(|region| region.kind != MemoryRegionKind::Mmio && overlaps(&range, region)) {
+        return false;
+    }
+    start >= arg.phys_mem_size || unav_regions().iter()
+        .any(|region| region.kind == MemoryRegionKind::Mmio && region.start <= start && end <= region.start + region.length)
+}
@@ -3,33 +3,48 @@ use lazy_static::lazy_static;
 use spin::Mutex;
 use x86_64::registers::control::Cr3;
 use x86_64::structures::paging::PageTable;
+use shared::layout::{PHYS_MEM_HIGH_P4, PHYS_MEM_P4};
 use shared::print_panic::PrintPanic;
 use shared::uni_processor::UPSafeCell;
+use crate::initcall;
+use crate::initcall::{kernel_arg, InitCpuArg};
 
 pub mod heap;
 pub mod frame_allocator;
 pub mod aligned_box;
 mod unique;
 pub mod user_buffer;
+pub mod user_ptr;
 pub mod user_addr_space;
 pub mod load_elf;
+pub mod stack;
+pub mod kvm;
+pub mod kstack;
+pub mod vmalloc;
+pub mod memmap;
+pub mod lowmem;
+pub mod ksm;
+pub mod tlb;
 
 pub const PAGE_SIZE: usize = 4096;
 
-pub static KERNEL_PHYS_ADDRSP_P4_INDEX: usize = 256;
-
 lazy_static! {
     static ref KERNEL_PML4_PAGE_TABLE: UPSafeCell<Mutex<Option<&'static PageTable>>> = unsafe {
         UPSafeCell::new(Mutex::new(None))
     };
 }
 
+unsafe fn kernel_page_table_initcall(_: &InitCpuArg) {
+    set_kernel_pml4_page_table(kernel_arg().kernel_pml4_start_addr);
+}
+initcall!(early, Bsp, kernel_page_table_initcall, order = 0);
+
 pub fn set_kernel_pml4_page_table(addr: u64) {
     let refmut = KERNEL_PML4_PAGE_TABLE.inner_exclusive_mut();
     let mut locked = refmut.lock();
 
     let mut pt = unsafe { &mut *(addr as *mut PageTable) };
-    pt[KERNEL_PHYS_ADDRSP_P4_INDEX] = pt[0].clone(); // map phys addr space to higher half
+    pt[PHYS_MEM_HIGH_P4 as usize] = pt[PHYS_MEM_P4 as usize].clone(); // map phys addr space to higher half
 
     *locked = Some(pt);
     drop(locked);
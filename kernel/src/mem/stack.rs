@@ -0,0 +1,83 @@
+use core::ptr;
+use core::slice;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Once;
+use shared::arg::{KernelArg, DEFAULT_AP_STACK_SIZE, DEFAULT_BOOT_STACK_SIZE, DEFAULT_CONTEXT_STACK_SIZE, STACK_FILL_PATTERN};
+use shared::print_panic::PrintPanic;
+use crate::infohart;
+use crate::mem::PAGE_SIZE;
+use crate::initcall;
+use crate::initcall::{kernel_arg, InitCpuArg};
+
+static STACK_CONFIG: Once<StackConfig> = Once::new();
+static BOOT_STACK: Once<&'static [u8]> = Once::new();
+// peak usage of context kernel stacks, collected when contexts are removed
+static CONTEXT_STACK_PEAK: AtomicUsize = AtomicUsize::new(0);
+
+/// kernel stack sizes in bytes, configured by bootloader through `KernelArg`
+#[derive(Debug, Clone, Copy)]
+pub struct StackConfig {
+    pub boot: usize,
+    pub context: usize,
+    pub ap: usize,
+}
+
+impl StackConfig {
+    pub fn context_pages(&self) -> usize {
+        self.context / PAGE_SIZE
+    }
+
+    pub fn ap_pages(&self) -> usize {
+        self.ap / PAGE_SIZE
+    }
+}
+
+fn page_aligned_or(size: usize, default: usize) -> usize {
+    if size == 0 { default } else { size.div_ceil(PAGE_SIZE) * PAGE_SIZE }
+}
+
+unsafe fn stack_config_initcall(_: &InitCpuArg) {
+    init_stack_config(kernel_arg());
+}
+initcall!(early, Bsp, stack_config_initcall, order = 20);
+
+pub fn init_stack_config(arg: &KernelArg) {
+    let config = STACK_CONFIG.call_once(|| StackConfig {
+        boot: page_aligned_or(arg.stack_size, DEFAULT_BOOT_STACK_SIZE),
+        context: page_aligned_or(arg.context_stack_size, DEFAULT_CONTEXT_STACK_SIZE),
+        ap: page_aligned_or(arg.ap_stack_size, DEFAULT_AP_STACK_SIZE),
+    });
+
+    BOOT_STACK.call_once(|| unsafe {
+        slice::from_raw_parts((arg.stack_top_addr as usize - arg.stack_size) as *const u8, arg.stack_size)
+    });
+
+    infohart!("kernel stack config: boot = {} KiB, context = {} KiB, ap = {} KiB", config.boot / 1024, config.context / 1024, config.ap / 1024);
+}
+
+pub fn stack_config() -> &'static StackConfig {
+    STACK_CONFIG.get().or_panic("kernel stack config is not initialized")
+}
+
+/// fill a fresh stack with pattern for high-water mark tracking.
+pub unsafe fn fill_stack_pattern(stack: *mut u8, len: usize) {
+    ptr::write_bytes(stack, STACK_FILL_PATTERN, len);
+}
+
+/// bytes ever used of `stack`, stack grows downward so scan from the low end.
+pub fn stack_high_water_mark(stack: &[u8]) -> usize {
+    let untouched = stack.iter().take_while(|b| **b == STACK_FILL_PATTERN).count();
+    stack.len() - untouched
+}
+
+pub fn boot_stack_high_water_mark() -> usize {
+    BOOT_STACK.get().map(|s| stack_high_water_mark(s)).unwrap_or(0)
+}
+
+pub fn record_context_stack_usage(stack: &[u8]) {
+    CONTEXT_STACK_PEAK.fetch_max(stack_high_water_mark(stack), Ordering::Relaxed);
+}
+
+pub fn context_stack_peak() -> usize {
+    CONTEXT_STACK_PEAK.load(Ordering::Relaxed)
+}
@@ -0,0 +1,78 @@
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use x86_64::instructions::tlb;
+use crate::config::MAX_CPUS;
+use crate::cpu::{LogicalCpuId, PercpuBlock};
+use crate::initcall;
+use crate::initcall::InitCpuArg;
+use crate::ipi::{ipi, IpiKind, IpiTarget};
+use crate::CPU_COUNT;
+
+/**
+ *  tlb shootdown of freed frames.
+ *
+ *  a cpu unmapping a page only flushes its own tlb, any other cpu may still
+ *  cache the old translation. so a frame losing its last reference is not
+ *  reused right away, the frame allocator collects freed frames in a batch
+ *  and [`shootdown`] starts a new epoch for it: every cpu flushes its whole
+ *  tlb on `IpiKind::TlbShootdown` and records the epoch it saw before the
+ *  flush. the batch is reused once [`flushed_since`] its epoch.
+ *
+ *  nobody waits for the other cpus, a cpu with interrupts masked only delays
+ *  the reuse of the batch. kernel mappings are not global, reloading cr3
+ *  flushes everything.
+ */
+
+const UNTRACKED: AtomicU64 = AtomicU64::new(u64::MAX);
+// epoch each cpu saw before its last full flush, `u64::MAX` for cpus not started
+static FLUSHED: [AtomicU64; MAX_CPUS] = [UNTRACKED; MAX_CPUS];
+// one more than the highest cpu id tracked
+static TRACKED: AtomicUsize = AtomicUsize::new(0);
+static EPOCH: AtomicU64 = AtomicU64::new(0);
+
+fn track(cpu: LogicalCpuId, epoch: u64) {
+    FLUSHED[cpu.0 as usize].store(epoch, Ordering::SeqCst);
+    TRACKED.fetch_max(cpu.0 as usize + 1, Ordering::SeqCst);
+}
+
+/// an ap is about to start, its tlb is empty but it may miss shootdowns
+/// until its idt is up, see [`flush_all`] in the initcall
+pub fn track_cpu(cpu: LogicalCpuId) {
+    track(cpu, EPOCH.load(Ordering::SeqCst));
+}
+
+/// flush the whole tlb of this cpu and record the epoch it covers
+pub fn flush_all() {
+    // an epoch started meanwhile is left to the next flush
+    let epoch = EPOCH.load(Ordering::SeqCst);
+    tlb::flush_all();
+    track(PercpuBlock::current().cpu_id, epoch);
+}
+
+/// start a new epoch for the frames unmapped so far and ask every cpu to flush, returns the epoch
+pub fn shootdown() -> u64 {
+    let epoch = EPOCH.fetch_add(1, Ordering::SeqCst) + 1;
+    flush_all();
+    if CPU_COUNT.load(Ordering::SeqCst) > 1 {
+        ipi(IpiKind::TlbShootdown, IpiTarget::Other);
+    }
+    epoch
+}
+
+/// whether every cpu flushed its tlb since `epoch` started
+pub fn flushed_since(epoch: u64) -> bool {
+    FLUSHED[..TRACKED.load(Ordering::SeqCst)].iter().all(|seen| seen.load(Ordering::SeqCst) >= epoch)
+}
+
+unsafe fn tlb_initcall(_arg: &InitCpuArg) {
+    flush_all();
+}
+// shootdowns sent before the idt of an ap was loaded are lost
+initcall!(arch, All, tlb_initcall, order = 12);
+
+#[test_case]
+pub(crate) fn test_tlb_shootdown_epoch() {
+    let epoch = shootdown();
+    // this cpu flushed, others may not have handled the ipi yet
+    assert!(FLUSHED[PercpuBlock::current().cpu_id.0 as usize].load(Ordering::SeqCst) >= epoch);
+    assert!(flushed_since(0));
+}
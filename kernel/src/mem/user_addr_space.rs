@@ -2,313 +2,392 @@ use alloc::sync::Arc;
 use alloc::vec;
 use alloc::vec::Vec;
 use core::hint::spin_loop;
+use core::ptr;
 use core::slice;
-use bitflags::Flags;
-use spin::{RwLock, RwLockReadGuard, RwLockUpgradableGuard, RwLockWriteGuard};
-use spinning_top::RwSpinlock;
+use core::sync::atomic::{fence, AtomicU64, AtomicUsize, Ordering};
 use x86_64::{PhysAddr, VirtAddr};
 use x86_64::registers::control::{Cr3, Cr3Flags};
-use x86_64::structures::paging::{FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size1GiB, Size4KiB, Translate};
-use x86_64::structures::paging::mapper::TranslateResult;
-use libvdso::error::{EFAULT, KError, KResult};
-use shared::{BOOTSTRAP_BYTES_P4, FRAMEBUFFER_P4, KERNEL_BYTES_P4, KERNEL_STACK_P4, PHYS_MEM_P4};
+use x86_64::structures::paging::{FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB, Translate};
+use x86_64::structures::paging::mapper::{MapToError, TranslateResult};
+use libvdso::error::{EAGAIN, EEXIST, EFAULT, EINVAL, ENOMEM, KError, KResult};
+use libvdso::rlimit::RLIMIT_AS;
+use shared::layout::{BOOTSTRAP_BYTES_P4, FRAMEBUFFER_P4, KERNEL_BYTES_P4, KERNEL_RUNTIME_P4, KERNEL_STACK_P4, PHYS_MEM_P4};
 use shared::print_panic::PrintPanic;
-use crate::arch_spec::copy_to;
+use crate::arch_spec::usercopy::user_copy_nonoverlapping;
 use crate::context::Context;
-use crate::infohart;
-use crate::mem::frame_allocator::{frame_alloc, frame_dealloc};
+use crate::mem::frame_allocator::{frame_alloc, frame_dealloc, frame_ref, frame_refcount, frame_try_ref, try_frame_alloc};
+use crate::mem::ksm::{self, MERGED};
 use crate::mem::{get_kernel_pml4_page_table_addr, PAGE_SIZE};
-use crate::mem::user_buffer::UserBuffer;
+use crate::mem::user_buffer::{BufferClass, UserBuffer};
+use shared::layout::{USER_SPACE_END, USER_STACK_BASE, USER_STACK_SLOTS, USER_STACK_SLOT_SIZE};
+use crate::sync::{IrqRwLock, IrqRwLockReadGuard, IrqRwLockWriteGuard, IrqSpinlock, RwSpinlock};
+
+/**
+ *  user address space.
+ *
+ *  [`RwLockUserAddrSpace`] keeps its state under two locks, always taken in
+ *  this order: the buffer bookkeeping of [`RwLockUserAddrSpace::alloc`], then
+ *  the page table ([`UserAddrSpace`]). both disable interrupts while held,
+ *  exception handlers reach the address space too (coredump).
+ *
+ *  switching to an address space and translating user addresses take no lock.
+ *  pml4 never moves and page tables live as long as the address space, so a
+ *  walk racing with `map_to` never reads freed memory. changes which take a
+ *  mapping away bump `revoke_seq` before and after, seqlock style, and walks
+ *  overlapping one of them are retried.
+ */
+
+const BUFFER_FLAGS: PageTableFlags = PageTableFlags::PRESENT.union(PageTableFlags::WRITABLE).union(PageTableFlags::USER_ACCESSIBLE);
+const DEVICE_FLAGS: PageTableFlags = BUFFER_FLAGS.union(PageTableFlags::NO_CACHE)
+    .union(PageTableFlags::WRITE_THROUGH).union(PageTableFlags::NO_EXECUTE);
 
 pub struct RwLockUserAddrSpace {
     context: Arc<RwSpinlock<Context>>,
-    inner: Arc<RwLock<UserAddrSpace>>
+    root: Arc<PageTableRoot>,
+    buffers: IrqSpinlock<BufferTracker>,
+    // bit set while a context sharing the address space has its stack in the slot
+    stack_slots: AtomicU64,
+    inner: IrqRwLock<UserAddrSpace>,
+}
+
+const _: () = assert!(USER_STACK_SLOTS == u64::BITS as u64);
+
+/// lowest address of user stack slot `slot`
+pub fn stack_slot_base(slot: usize) -> u64 {
+    USER_STACK_BASE + slot as u64 * USER_STACK_SLOT_SIZE
+}
+
+// part of the address space read without lock
+struct PageTableRoot {
+    // physical address of pml4, page tables are accessible at their physical address
+    pml4: PhysAddr,
+    // odd while a mapping is being revoked
+    revoke_seq: AtomicUsize,
 }
 
 pub struct UserAddrSpace {
     page_table: OffsetPageTable<'static>,
+    root: Arc<PageTableRoot>,
     // 地址空间页表用到的子页表物理页帧
-    pte_frames: Vec<PhysFrame>,
-    // track buffers which length > PAGE_SIZE
-    tracked_large_buffers: Vec<PhysFrame>,
-    // track buffers which length < 512 and length > 64
-    tracked_medium_buffers: Vec<TrackedPhysFrame>,
-    medium_buffer_pointer: usize,
-    // store small buffers smaller than 64 bytes
-    tracked_small_buffers: Vec<TrackedPhysFrame>,
-    // small buffer 物理页帧的当前指针，用来定位分配新内存区域的地址
-    small_buffer_pointer: usize,
+    pte_frames: PteFrames,
+    // frames mapped into this address space, freed with it
+    tracked_frames: Vec<PhysFrame>,
+    stats: AddrSpaceStats,
+}
+
+// slot sizes of slab pages, larger buffers get pages of their own
+const SLAB_SIZES: [usize; 8] = [16, 32, 64, 128, 256, 512, 1024, 2048];
+const SLAB_MAX_SLOTS: usize = PAGE_SIZE / SLAB_SIZES[0];
+
+// where `alloc` puts the next buffer
+struct BufferTracker {
     // to locate virtual address of newly allocated buffer in the address space
     // 为每次分配的新内存区域定位虚拟内存地址
     consumed_page_count: usize,
     // 用户地址空间基地址，在这之前的东西是未定义的
     base_address: usize,
+    // slab pages sorted by address. headers live here and not in the page,
+    // userspace can write the page
+    slabs: Vec<SlabPage>,
+    // per size class, addresses of slab pages with a free slot
+    partial: [Vec<u64>; SLAB_SIZES.len()],
+    // live large buffers (address, capacity), a stale copy must not unmap a newer buffer
+    large: Vec<(u64, usize)>,
+    // device memory mapped for userspace drivers (page address, pages), frames are not ours to free
+    devices: Vec<(u64, usize)>,
+}
+
+struct SlabPage {
+    addr: u64,
+    // index into `SLAB_SIZES`
+    class: usize,
+    // bit set while the slot is handed out
+    used: [u64; SLAB_MAX_SLOTS / 64],
+    in_use: usize,
 }
 
 impl RwLockUserAddrSpace {
-    pub unsafe fn new(context: &Arc<RwSpinlock<Context>>, base: usize) -> Arc<Self> {
-        let mut addrsp = UserAddrSpace::new(base);
+    pub unsafe fn new(context: &Arc<RwSpinlock<Context>>, base: usize) -> KResult<Arc<Self>> {
+        let mut addrsp = UserAddrSpace::new()?;
         addrsp.setup_kernel();
+        let buffers = BufferTracker::new(base);
 
-        Arc::new(Self {
+        Ok(Arc::new(Self {
             context: Arc::clone(context),
-            inner: Arc::new(RwLock::new(addrsp))
-        })
+            root: Arc::clone(&addrsp.root),
+            buffers: IrqSpinlock::new(buffers),
+            stack_slots: AtomicU64::new(0),
+            inner: IrqRwLock::new(addrsp),
+        }))
     }
 
-    pub fn acquire_read<'a>(self: &'a Arc<Self>) -> RwLockReadGuard<'a, UserAddrSpace> {
-        loop {
-            match self.inner.try_read() {
-                Some(g) => return g,
-                None => { spin_loop() }
+    pub fn acquire_read(&self) -> IrqRwLockReadGuard<'_, UserAddrSpace> {
+        self.inner.read()
+    }
+
+    pub fn acquire_write(&self) -> IrqRwLockWriteGuard<'_, UserAddrSpace> {
+        self.inner.write()
+    }
+
+    /// None while someone else holds the lock, for paths that stopped the other cpus
+    pub fn try_acquire_write(&self) -> Option<IrqRwLockWriteGuard<'_, UserAddrSpace>> {
+        self.inner.try_write()
+    }
+
+    /// load this address space on current cpu
+    pub unsafe fn validate(&self) {
+        Cr3::write(PhysFrame::containing_address(self.root.pml4), Cr3Flags::empty())
+    }
+
+    /// claim the lowest free user stack slot, EAGAIN once every slot is taken
+    pub fn alloc_stack_slot(&self) -> KResult<usize> {
+        self.stack_slots
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| (used != !0).then(|| used | 1 << used.trailing_ones()))
+            .map(|used| used.trailing_ones() as usize)
+            .map_err(|_| KError::new(EAGAIN))
+    }
+
+    /// unmap the first `pages` of stack slot `slot` and hand the slot out again
+    pub fn release_stack_slot(&self, slot: usize, pages: usize) {
+        {
+            let mut addrsp = self.inner.write();
+            let start = Page::<Size4KiB>::containing_address(VirtAddr::new(stack_slot_base(slot)));
+            for page in Page::range(start, start + pages as u64) {
+                unsafe { addrsp.raw_unmap(page) };
             }
         }
+        self.stack_slots.fetch_and(!(1 << slot), Ordering::AcqRel);
     }
 
-    pub fn acquire_upg_read<'a>(self: &'a Arc<Self>) -> RwLockUpgradableGuard<'a, UserAddrSpace> {
-        loop {
-            match self.inner.try_upgradeable_read() {
-                Some(g) => return g,
-                None => { spin_loop() }
-            }
+    /// lock-free [`UserAddrSpace::translate_user`]. a write to a page merged by
+    /// [`ksm`] gives it a frame of its own first, under the page table lock
+    pub fn translate_user(&self, virt_addr: VirtAddr, write: bool) -> KResult<(PhysAddr, u64)> {
+        let result = self.root.translate_user(virt_addr, write);
+        if result.is_err() && write && unsafe { ksm::unmerge(&mut self.inner.write(), Page::containing_address(virt_addr))? } {
+            return self.root.translate_user(virt_addr, write);
         }
+        result
     }
 
-    pub fn acquire_write<'a>(self: &'a Arc<Self>) -> RwLockWriteGuard<'a, UserAddrSpace> {
-        loop {
-            match self.inner.try_write() {
-                Some(g  ) => return g,
-                None => { spin_loop() }
-            }
+    /// [`Self::translate_user`] with a reference on the frame, so it is not
+    /// freed and handed out again while the caller copies through the physical
+    /// address. the reference is dropped with `frame_dealloc`, there is none
+    /// for memory outside the frame allocator
+    pub fn translate_user_pinned(&self, virt_addr: VirtAddr, write: bool) -> KResult<(PhysAddr, u64, Option<PhysFrame>)> {
+        let result = self.root.translate_user_pinned(virt_addr, write);
+        if result.is_err() && write && unsafe { ksm::unmerge(&mut self.inner.write(), Page::containing_address(virt_addr))? } {
+            return self.root.translate_user_pinned(virt_addr, write);
         }
+        result
     }
-}
 
-impl UserAddrSpace {
-    pub unsafe fn new(base: usize) -> Self {
-        assert_eq!(base % PAGE_SIZE, 0, "base address of userspace address space must be 4k aligned.");
+    // resolve userspace buffer to kernel space
+    pub fn resolve(&self, buffer: Arc<UserBuffer>) -> KResult<Vec<&'static [u8]>> {
+        self.resolve_for(buffer, false)
+    }
 
-        let pml4_frame = frame_alloc().or_panic("failed to allocate new frame for user addr space page table");
+    fn resolve_for(&self, buffer: Arc<UserBuffer>, write: bool) -> KResult<Vec<&'static [u8]>> {
+        let mut result = Vec::new();
+        let mut resolved_len = 0;
+        let base = buffer.ptr() as u64;
 
-        let ptr = pml4_frame.start_address().as_u64() as *mut PageTable;
-        ptr.write(PageTable::new());
+        // buffers may cross page boundaries regardless of their size
+        while resolved_len < buffer.len() {
+            let virt_addr = VirtAddr::try_new(base + resolved_len as u64).map_err(|_| KError::new(EFAULT))?;
+            let (phys_addr, len_till_page_end) = self.translate_user(virt_addr, write)?;
 
-        let mut offset_page_table = OffsetPageTable::new(&mut *ptr, VirtAddr::new(0));
+            let len = (len_till_page_end as usize).min(buffer.len() - resolved_len);
+            result.push(unsafe { slice::from_raw_parts(phys_addr.as_u64() as *const u8, len) });
+            resolved_len += len;
+        }
 
-        let small_init_frame = TrackedPhysFrame {
-            frame: frame_alloc().or_panic("failed to allocate new frame for user addr space page buffers"),
-            index: 0
-        };
-        let medium_init_frame = TrackedPhysFrame {
-            frame: frame_alloc().or_panic("failed to allocate new frame for user addr space page buffers"),
-            index: 1
-        };
+        Ok(result)
+    }
 
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    /// fails with ENOMEM past RLIMIT_AS of the owning context, which must not be locked by the caller
+    pub fn alloc(&self, size: usize) -> KResult<Arc<UserBuffer>> {
+        let limit = self.context.read().rlimits.cur(RLIMIT_AS);
+        let mut buffers = self.buffers.lock();
+        let mut addrsp = self.inner.write();
+        // slab buffers map one page at most
+        let new_pages = if size <= SLAB_SIZES[SLAB_SIZES.len() - 1] { 1 } else { size.div_ceil(PAGE_SIZE) };
+        if (addrsp.tracked_frames.len() + new_pages).saturating_mul(PAGE_SIZE) > limit {
+            return Err(KError::new(ENOMEM));
+        }
+        let (virt_addr, class, capacity) = unsafe { buffers.alloc(&mut addrsp, size)? };
+        if let Some(live) = addrsp.stats.buffers_mut(class) {
+            *live += 1;
+        }
+        Ok(Arc::new(UserBuffer::allocated(virt_addr.as_u64(), size, class, capacity, self.root.pml4.as_u64())))
+    }
 
-        let mut pte_frames = vec![];
-        let mut temp_allocator = TempAllocator(&mut pte_frames);
+    /// give a buffer from [`Self::alloc`] back. slab slots are reused by later
+    /// allocations, empty slab pages and pages of large buffers are unmapped and freed.
+    /// `EINVAL` for foreign buffers, buffers of another address space and double frees
+    pub fn free(&self, buffer: &UserBuffer) -> KResult<()> {
+        if buffer.class() == BufferClass::Foreign || buffer.owner() != self.root.pml4.as_u64() {
+            return Err(KError::new(EINVAL));
+        }
+        let mut buffers = self.buffers.lock();
+        let mut addrsp = self.inner.write();
+        unsafe { buffers.free(&mut addrsp, buffer)? };
+        if let Some(live) = addrsp.stats.buffers_mut(buffer.class()) {
+            *live -= 1;
+        }
+        Ok(())
+    }
 
-        offset_page_table.map_to(
-            Page::containing_address(VirtAddr::new((base + 0 * PAGE_SIZE) as u64)),
-            small_init_frame.frame,
-            flags,
-            &mut temp_allocator
-        )
-            .or_panic("failed to map small tracked page.")
-            .flush();
-        offset_page_table.map_to(
-            Page::containing_address(VirtAddr::new((base + 1 * PAGE_SIZE) as u64)),
-            medium_init_frame.frame,
-            flags,
-            &mut temp_allocator
-        )
-            .or_panic("failed to map medium tracked page.")
-            .flush();
+    /// map `len` bytes of device memory at `phys` uncached, the offset inside the
+    /// page is kept. the caller checks the range is device memory.
+    pub fn map_device(&self, phys: PhysAddr, len: usize) -> KResult<VirtAddr> {
+        let mut buffers = self.buffers.lock();
+        let mut addrsp = self.inner.write();
+        unsafe { buffers.map_device(&mut addrsp, phys, len) }
+    }
 
-        Self {
-            page_table: offset_page_table,
-            pte_frames,
-            tracked_large_buffers: vec![],
-            tracked_medium_buffers: vec![medium_init_frame],
-            medium_buffer_pointer: 0,
-            tracked_small_buffers: vec![small_init_frame],
-            small_buffer_pointer: 0,
-            consumed_page_count: 2, // index 0 and 1 is used
-            base_address: base,
-        }
+    /// undo [`Self::map_device`], `EINVAL` unless `virt` and `len` match a mapping
+    pub fn unmap_device(&self, virt: VirtAddr, len: usize) -> KResult<()> {
+        let mut buffers = self.buffers.lock();
+        let mut addrsp = self.inner.write();
+        unsafe { buffers.unmap_device(&mut addrsp, virt, len) }
     }
 
-    pub unsafe fn setup_kernel(&mut self) {
-        // map kernel pml4 page table identically
-        let mut pt = self.page_table.level_4_table();
-        let kernel_pml4_pt = &*(get_kernel_pml4_page_table_addr() as *const PageTable);
+    pub fn alloc_and_copy_from(&self, src: &[u8]) -> KResult<Arc<UserBuffer>> {
+        let allocated = self.alloc(src.len())?;
+        // slab pages of older buffers may be merged
+        let resolved = self.resolve_for(Arc::clone(&allocated), true)?;
 
-        pt[KERNEL_BYTES_P4 as usize] = kernel_pml4_pt[KERNEL_BYTES_P4 as usize].clone();
-        pt[BOOTSTRAP_BYTES_P4 as usize] = kernel_pml4_pt[BOOTSTRAP_BYTES_P4 as usize].clone();
-        pt[KERNEL_STACK_P4 as usize] = kernel_pml4_pt[KERNEL_STACK_P4 as usize].clone();
-        pt[FRAMEBUFFER_P4 as usize] = kernel_pml4_pt[FRAMEBUFFER_P4 as usize].clone();
-        pt[PHYS_MEM_P4 as usize] = kernel_pml4_pt[PHYS_MEM_P4 as usize].clone();
+        assert_eq!(resolved.iter().map(|slice| slice.len()).sum::<usize>(), src.len(), "resolved len is not equal to src");
+
+        let mut start = 0;
+        for slice in resolved.into_iter() {
+            unsafe { user_copy_nonoverlapping(slice.as_ptr() as *mut u8, src[start..].as_ptr(), slice.len())? };
+            start += slice.len();
+        }
+
+        Ok(allocated)
     }
+}
 
-    pub fn alloc(&mut self, size: usize) -> Arc<UserBuffer> {
-        match size {
-            ..=64 => unsafe {
-                if size + self.small_buffer_pointer > PAGE_SIZE {
-                    let new_frame = TrackedPhysFrame {
-                        frame: frame_alloc().or_panic("failed to allocate new frame for small buffer of user addr space"),
-                        index: self.next_page_unused()
-                    };
-                    let virt_addr = VirtAddr::new((self.base_address + new_frame.index * PAGE_SIZE) as u64);
-
-                    self.page_table.map_to(
-                        Page::containing_address(virt_addr),
-                        new_frame.frame.clone(),
-                        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE,
-                        // SAFETY: FrameAllocator as self only modifies `self.pte_frames`
-                        //         so it is safe to leak it
-                        // TODO: leak borrow convention，好孩子不要这样，最好是为 pte_frames 实现 FrameAllocator
-                        &mut *(self as *const Self as u64 as *mut Self)
-                    )
-                        .or_panic("failed to map newly allocated small buffer")
-                        .flush();
-
-                    self.tracked_small_buffers.push(new_frame);
-                    self.consumed_page_count += 1;
-                    self.small_buffer_pointer = size;
-
-                    Arc::new(UserBuffer::new(virt_addr.as_u64(), size))
-                } else {
-                    let last_frame = self.tracked_small_buffers.last()
-                        .or_panic("failed to get last tracked small buffer");
-                    let virt_addr = VirtAddr::new((self.base_address + last_frame.index * PAGE_SIZE) as u64);
-
-                    self.small_buffer_pointer += size;
-                    Arc::new(UserBuffer::new(virt_addr.as_u64(), size))
-                }
+impl PageTableRoot {
+    fn translate_user(&self, virt_addr: VirtAddr, write: bool) -> KResult<(PhysAddr, u64)> {
+        loop {
+            let seq = self.revoke_seq.load(Ordering::Acquire);
+            if seq & 1 == 1 {
+                spin_loop();
+                continue;
             }
-            65..=512 => unsafe {
-                if size + self.medium_buffer_pointer > PAGE_SIZE {
-                    let new_frame = TrackedPhysFrame {
-                        frame: frame_alloc().or_panic("failed to allocate new frame for medium buffer of user addr space"),
-                        index: self.next_page_unused()
-                    };
-                    let virt_addr = VirtAddr::new((self.base_address + new_frame.index * PAGE_SIZE) as u64);
-
-                    self.page_table.map_to(
-                        Page::containing_address(virt_addr),
-                        new_frame.frame.clone(),
-                        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE,
-                        &mut *(self as *const Self as u64 as *mut Self) // leak borrow convention
-                    )
-                        .or_panic("failed to map newly allocated small buffer")
-                        .flush();
-
-                    self.tracked_medium_buffers.push(new_frame);
-                    self.consumed_page_count += 1;
-                    self.medium_buffer_pointer = size;
-
-                    Arc::new(UserBuffer::new(virt_addr.as_u64(), size))
-                } else {
-                    let last_frame = self.tracked_medium_buffers.last()
-                        .or_panic("failed to get last tracked small buffer");
-                    let virt_addr = VirtAddr::new((self.base_address + last_frame.index * PAGE_SIZE) as u64);
-
-                    self.medium_buffer_pointer += size;
-                    Arc::new(UserBuffer::new(virt_addr.as_u64(), size))
-                }
+            let result = self.walk(virt_addr, write);
+            fence(Ordering::Acquire);
+            if self.revoke_seq.load(Ordering::Relaxed) == seq {
+                return result;
             }
-            _ => unsafe {
-                let required_pages = size.div_ceil(PAGE_SIZE);
-                let virt_addr = VirtAddr::new((self.base_address + self.next_page_unused() * PAGE_SIZE) as u64);
-                let start_page = Page::<Size4KiB>::containing_address(virt_addr);
-
-                for page in Page::range(start_page, start_page + required_pages as u64) {
-                    let frame = frame_alloc().or_panic("failed to allocate new frame for large buffer of user addr space");
-
-                    self.page_table.map_to(
-                        page,
-                        frame.clone(),
-                        PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE,
-                        &mut *(self as *const Self as u64 as *mut Self) // leak borrow convention
-                    )
-                        .or_panic("failed to map newly allocated small buffer")
-                        .flush();
-
-                    self.tracked_large_buffers.push(frame)
-                }
+        }
+    }
 
-                self.consumed_page_count += required_pages;
-                Arc::new(UserBuffer::new(virt_addr.as_u64(), size))
+    // the reference counts only if no revoke overlapped the walk, the mapping
+    // held the frame until then
+    fn translate_user_pinned(&self, virt_addr: VirtAddr, write: bool) -> KResult<(PhysAddr, u64, Option<PhysFrame>)> {
+        loop {
+            let seq = self.revoke_seq.load(Ordering::Acquire);
+            if seq & 1 == 1 {
+                spin_loop();
+                continue;
+            }
+            let result = self.walk(virt_addr, write)
+                .map(|(phys_addr, len)| (phys_addr, len, frame_try_ref(PhysFrame::containing_address(phys_addr))));
+            fence(Ordering::Acquire);
+            if self.revoke_seq.load(Ordering::Relaxed) == seq {
+                return result;
+            }
+            if let Ok((_, _, Some(frame))) = result {
+                frame_dealloc(frame);
             }
         }
     }
 
-    // resolve userspace buffer to kernel space
-    pub fn resolve(&self, buffer: Arc<UserBuffer>) -> KResult<Vec<&'static [u8]>> {
-        if buffer.len() <= 512 { // alloc 不会把小于 512 的内存区域分页
-            let virt_addr = VirtAddr::new(buffer.ptr() as u64);
-            let page = Page::<Size4KiB>::containing_address(virt_addr);
-
-            let translated = self.page_table.translate_page(page).map_err(|_| KError::new(EFAULT))?;
-            let phys_addr = translated.start_address().as_u64() + (virt_addr.as_u64() - page.start_address().as_u64());
+    fn walk(&self, virt_addr: VirtAddr, write: bool) -> KResult<(PhysAddr, u64)> {
+        if virt_addr.as_u64() >= USER_SPACE_END {
+            return Err(KError::new(EFAULT));
+        }
 
-            return Ok(vec![unsafe { slice::from_raw_parts(phys_addr as *const u8, buffer.len()) }]);
+        let mut required = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        if write {
+            required |= PageTableFlags::WRITABLE;
         }
 
-        let mut result = Vec::new();
-        let mut resolved_len = 0;
-        let mut base_virt_addr = buffer.ptr();
+        let indexes = [
+            (u16::from(virt_addr.p4_index()), 39),
+            (u16::from(virt_addr.p3_index()), 30),
+            (u16::from(virt_addr.p2_index()), 21),
+            (u16::from(virt_addr.p1_index()), 12),
+        ];
+
+        let mut table = self.pml4.as_u64() as *const PageTable;
+        for (level, (index, shift)) in indexes.into_iter().enumerate() {
+            // writers may change the entry under us, read it once
+            let entry = unsafe { ptr::read_volatile(&(*table)[index as usize]) };
+            if !entry.flags().contains(required) {
+                return Err(KError::new(EFAULT));
+            }
 
-        while resolved_len < buffer.len() {
-            let virt_addr = VirtAddr::new(unsafe { base_virt_addr.add(resolved_len) } as u64);
-            let page = Page::<Size4KiB>::containing_address(virt_addr);
-
-            let translated = self.page_table.translate_page(page).map_err(|_| KError::new(EFAULT))?;
-            let phys_addr = translated.start_address().as_u64() + (virt_addr - page.start_address());
-
-            let len_till_page_end = (page + 1).start_address() - virt_addr;
-            let remain_bytes_len = (buffer.len() - resolved_len) as u64;
-            if len_till_page_end < remain_bytes_len { // 只有部分 buffer
-                result.push(unsafe { slice::from_raw_parts(phys_addr as *const u8, len_till_page_end as usize) });
-                resolved_len += len_till_page_end as usize;
-            } else {
-                result.push(unsafe { slice::from_raw_parts(phys_addr as *const u8, remain_bytes_len as usize) });
-                resolved_len += remain_bytes_len as usize;
+            // 1g and 2m pages end the walk early, p4 entries can't be huge
+            let leaf = level == 3 || (level > 0 && entry.flags().contains(PageTableFlags::HUGE_PAGE));
+            if leaf {
+                let page_size = 1u64 << shift;
+                let offset = virt_addr.as_u64() & (page_size - 1);
+                return Ok((entry.addr() + offset, page_size - offset));
             }
+            table = entry.addr().as_u64() as *const PageTable;
         }
+        unreachable!()
+    }
 
-        Ok(result)
+    // run `f` which takes mappings away, concurrent walks retry
+    fn revoke<R>(&self, f: impl FnOnce() -> R) -> R {
+        self.revoke_seq.fetch_add(1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        let result = f();
+        self.revoke_seq.fetch_add(1, Ordering::Release);
+        result
     }
+}
 
-    pub fn alloc_and_copy_from(&mut self, src: &[u8]) -> KResult<Arc<UserBuffer>> {
-        let allocated = self.alloc(src.len());
-        let mut resolved = self.resolve(Arc::clone(&allocated))?;
+impl UserAddrSpace {
+    pub unsafe fn new() -> KResult<Self> {
+        let pml4_frame = try_frame_alloc()?;
+        let ptr = pml4_frame.start_address().as_u64() as *mut PageTable;
+        ptr.write(PageTable::new());
 
-        assert_eq!(resolved.iter().map(|slice| slice.len()).sum::<usize>(), src.len(), "resolved len is not equal to src");
+        Ok(Self {
+            page_table: OffsetPageTable::new(&mut *ptr, VirtAddr::new(0)),
+            root: Arc::new(PageTableRoot { pml4: pml4_frame.start_address(), revoke_seq: AtomicUsize::new(0) }),
+            pte_frames: PteFrames(vec![]),
+            tracked_frames: vec![],
+            stats: AddrSpaceStats::default(),
+        })
+    }
 
-        let mut start = 0;
-        for slice in resolved.into_iter() {
-            // TODO: check copy to
-            unsafe { copy_to(
-                slice[0] as *mut u8 as usize,
-                src[start..][0] as *mut u8 as usize,
-                slice.len()
-            ); }
-        }
+    pub unsafe fn setup_kernel(&mut self) {
+        // map kernel pml4 page table identically
+        let mut pt = self.page_table.level_4_table();
+        let kernel_pml4_pt = &*(get_kernel_pml4_page_table_addr() as *const PageTable);
 
-        return Ok(allocated)
+        pt[KERNEL_BYTES_P4 as usize] = kernel_pml4_pt[KERNEL_BYTES_P4 as usize].clone();
+        pt[BOOTSTRAP_BYTES_P4 as usize] = kernel_pml4_pt[BOOTSTRAP_BYTES_P4 as usize].clone();
+        pt[KERNEL_STACK_P4 as usize] = kernel_pml4_pt[KERNEL_STACK_P4 as usize].clone();
+        for index in KERNEL_RUNTIME_P4 {
+            pt[index as usize] = kernel_pml4_pt[index as usize].clone();
+        }
+        pt[FRAMEBUFFER_P4 as usize] = kernel_pml4_pt[FRAMEBUFFER_P4 as usize].clone();
+        pt[PHYS_MEM_P4 as usize] = kernel_pml4_pt[PHYS_MEM_P4 as usize].clone();
     }
 
-    pub fn next_page_unused(&mut self) -> usize {
-        loop {
-            let virt_addr = VirtAddr::new((self.base_address + self.consumed_page_count * PAGE_SIZE) as u64);
-            let used = self.page_table.translate_addr(virt_addr).map(|_| true).unwrap_or(false);
-            if !used {
-                return self.consumed_page_count
-            }
-            self.consumed_page_count += 1;
-        }
+    /// translate a user virtual address, checking every level grants user access
+    /// (and write access if `write`).
+    /// returns physical address and bytes left until the end of the mapped page.
+    pub fn translate_user(&self, virt_addr: VirtAddr, write: bool) -> KResult<(PhysAddr, u64)> {
+        self.root.walk(virt_addr, write)
     }
 
     // get reference of the underlying page table
@@ -316,22 +395,42 @@ impl UserAddrSpace {
         self.page_table.level_4_table()
     }
 
-    // preform raw map
-    pub unsafe fn raw_map_to(&mut self, page: Page, frame: PhysFrame, flags: PageTableFlags) {
+    // preform raw map, `ENOMEM` if a page table can't be allocated, `EEXIST` if `page` is mapped
+    pub unsafe fn raw_map_to(&mut self, page: Page, frame: PhysFrame, flags: PageTableFlags) -> KResult<()> {
         self.page_table.map_to(
             page,
             frame,
             flags | PageTableFlags::USER_ACCESSIBLE,
-            &mut *(self as *const Self as u64 as *mut Self)
+            &mut self.pte_frames
         )
-            .or_panic("failed to perform raw map_to")
+            .map_err(|err| KError::new(match err {
+                MapToError::FrameAllocationFailed => ENOMEM,
+                _ => EEXIST,
+            }))?
             .ignore();
+        self.stats.mapped_pages += 1;
+        self.stats.peak_mapped_pages = self.stats.peak_mapped_pages.max(self.stats.mapped_pages);
+        Ok(())
+    }
+
+    /// map a frame someone else owns (a merged page), the address space keeps its own
+    /// reference until the page is unmapped, so the frame outlives either owner
+    pub unsafe fn raw_map_shared(&mut self, page: Page, frame: PhysFrame, flags: PageTableFlags) -> KResult<()> {
+        self.raw_map_to(page, frame, flags)?;
+        self.push_tracked_frame(frame_ref(frame));
+        Ok(())
     }
 
+    // the reference of a tracked frame is dropped, untracked ones (device memory) belong to someone else
     pub unsafe fn raw_unmap(&mut self, page: Page) {
-        let (p1_entry, flusher) = self.page_table.unmap(page).or_panic("failed to perform raw unmap");
-        frame_dealloc(p1_entry);
+        let (frame, flusher) = self.root.revoke(|| self.page_table.unmap(page))
+            .or_panic("failed to perform raw unmap");
         flusher.flush();
+        self.stats.mapped_pages -= 1;
+        if let Some(index) = self.tracked_frames.iter().position(|tracked| *tracked == frame) {
+            self.tracked_frames.swap_remove(index);
+            frame_dealloc(frame);
+        }
     }
 
     pub unsafe fn raw_translate(&mut self, virt_addr: VirtAddr) -> TranslateResult {
@@ -339,68 +438,396 @@ impl UserAddrSpace {
     }
 
     pub unsafe fn raw_update_flags(&mut self, page: Page, flags: PageTableFlags) {
-        self.page_table.update_flags(page, flags)
+        self.root.revoke(|| self.page_table.update_flags(page, flags))
             .or_panic("failed to perform raw update flags")
             .ignore()
     }
 
     pub unsafe fn push_tracked_frame(&mut self, frame: PhysFrame) {
-        self.tracked_large_buffers.push(frame)
+        self.tracked_frames.push(frame)
+    }
+
+    /// frames this address space holds a reference on, one per mapping
+    pub fn tracked_frames(&self) -> &[PhysFrame] {
+        &self.tracked_frames
+    }
+
+    pub fn stats(&self) -> AddrSpaceStats {
+        AddrSpaceStats {
+            pte_frames: self.pte_frames.0.len(),
+            tracked_frames: self.tracked_frames.len(),
+            ..self.stats
+        }
     }
 
-    pub unsafe fn validate(&mut self) {
-        let pg_phys_addr = self.page_table.level_4_table() as *const _ as u64;
-        Cr3::write(PhysFrame::containing_address(PhysAddr::new(pg_phys_addr)), Cr3Flags::empty())
+    // walk lower half of the page table, merge adjacent user pages with same permission
+    pub fn mapped_regions(&mut self) -> Vec<MappedRegion> {
+        const MASK: PageTableFlags = PageTableFlags::WRITABLE
+            .union(PageTableFlags::USER_ACCESSIBLE)
+            .union(PageTableFlags::NO_EXECUTE);
+
+        let mut regions: Vec<MappedRegion> = Vec::new();
+        let mut push = |start: u64, len: u64, flags: PageTableFlags| {
+            if !flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+                return;
+            }
+            if let Some(last) = regions.last_mut() {
+                if last.start.as_u64() + last.len == start && last.flags == flags {
+                    last.len += len;
+                    return;
+                }
+            }
+            regions.push(MappedRegion { start: VirtAddr::new(start), len, flags });
+        };
+        // intermediate levels restrict the permission of their children
+        let inherit = |parent: PageTableFlags, child: PageTableFlags| {
+            (parent & child & (PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE))
+                | ((parent | child) & PageTableFlags::NO_EXECUTE)
+        };
+
+        let pml4 = self.page_table.level_4_table();
+        for (i4, e4) in pml4.iter().enumerate().take(256) {
+            if !e4.flags().contains(PageTableFlags::PRESENT) { continue }
+            let f4 = e4.flags() & MASK;
+            let pml3 = unsafe { &*(e4.addr().as_u64() as *const PageTable) };
+
+            for (i3, e3) in pml3.iter().enumerate() {
+                if !e3.flags().contains(PageTableFlags::PRESENT) { continue }
+                let f3 = inherit(f4, e3.flags());
+                let va3 = (i4 as u64) << 39 | (i3 as u64) << 30;
+                if e3.flags().contains(PageTableFlags::HUGE_PAGE) {
+                    push(va3, 1 << 30, f3);
+                    continue
+                }
+                let pml2 = unsafe { &*(e3.addr().as_u64() as *const PageTable) };
+
+                for (i2, e2) in pml2.iter().enumerate() {
+                    if !e2.flags().contains(PageTableFlags::PRESENT) { continue }
+                    let f2 = inherit(f3, e2.flags());
+                    let va2 = va3 | (i2 as u64) << 21;
+                    if e2.flags().contains(PageTableFlags::HUGE_PAGE) {
+                        push(va2, 1 << 21, f2);
+                        continue
+                    }
+                    let pml1 = unsafe { &*(e2.addr().as_u64() as *const PageTable) };
+
+                    for (i1, e1) in pml1.iter().enumerate() {
+                        if !e1.flags().contains(PageTableFlags::PRESENT) { continue }
+                        // merged pages are read-only until written, to their owner they are writable
+                        let f1 = if e1.flags().contains(MERGED) { e1.flags() | PageTableFlags::WRITABLE } else { e1.flags() };
+                        push(va2 | (i1 as u64) << 12, PAGE_SIZE as u64, inherit(f2, f1));
+                    }
+                }
+            }
+        }
+
+        regions
     }
 }
 
-/**
- *  tracked physics frame at userspace address space
- */
-struct TrackedPhysFrame {
-    frame: PhysFrame,
-    // page index starting from base address
-    index: usize
+/// counters of an address space, for procfs
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AddrSpaceStats {
+    /// user pages mapped through `raw_map_to`, kernel stack included
+    pub mapped_pages: usize,
+    pub peak_mapped_pages: usize,
+    /// page table frames below pml4
+    pub pte_frames: usize,
+    /// frames owned by the address space
+    pub tracked_frames: usize,
+    /// buffers handed out by `alloc` and not freed
+    pub slab_buffers: usize,
+    pub large_buffers: usize,
+    /// pages split into slab slots
+    pub slab_pages: usize,
 }
 
-unsafe impl FrameAllocator<Size4KiB> for UserAddrSpace {
-    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
-        let frame = frame_alloc()
-            .expect("failed to allocate new pte for addr space page table");
-        self.pte_frames.push(frame.clone());
-        Some(frame)
+impl AddrSpaceStats {
+    fn buffers_mut(&mut self, class: BufferClass) -> Option<&mut usize> {
+        match class {
+            BufferClass::Foreign => None,
+            BufferClass::Slab => Some(&mut self.slab_buffers),
+            BufferClass::Large => Some(&mut self.large_buffers),
+        }
+    }
+}
+
+/// a range of virtual memory mapped with identical permission
+pub struct MappedRegion {
+    pub start: VirtAddr,
+    pub len: u64,
+    pub flags: PageTableFlags,
+}
+
+impl SlabPage {
+    fn new(addr: u64, class: usize) -> Self {
+        Self { addr, class, used: [0; SLAB_MAX_SLOTS / 64], in_use: 0 }
+    }
+
+    fn slots(&self) -> usize {
+        PAGE_SIZE / SLAB_SIZES[self.class]
+    }
+
+    fn is_full(&self) -> bool {
+        self.in_use == self.slots()
+    }
+
+    fn take(&mut self) -> Option<usize> {
+        let slot = (0..self.slots()).find(|slot| self.used[slot / 64] & 1 << (slot % 64) == 0)?;
+        self.used[slot / 64] |= 1 << (slot % 64);
+        self.in_use += 1;
+        Some(slot)
+    }
+
+    // false if the slot is not handed out
+    fn put(&mut self, slot: usize) -> bool {
+        if slot >= self.slots() || self.used[slot / 64] & 1 << (slot % 64) == 0 {
+            return false;
+        }
+        self.used[slot / 64] &= !(1 << (slot % 64));
+        self.in_use -= 1;
+        true
+    }
+}
+
+impl BufferTracker {
+    fn new(base: usize) -> Self {
+        assert_eq!(base % PAGE_SIZE, 0, "base address of userspace address space must be 4k aligned.");
+
+        Self {
+            consumed_page_count: 0,
+            base_address: base,
+            slabs: Vec::new(),
+            partial: Default::default(),
+            large: Vec::new(),
+            devices: Vec::new(),
+        }
+    }
+
+    fn page_addr(&self, index: usize) -> VirtAddr {
+        VirtAddr::new((self.base_address + index * PAGE_SIZE) as u64)
+    }
+
+    fn page_index(&self, addr: u64) -> usize {
+        ((addr - self.base_address as u64) / PAGE_SIZE as u64) as usize
+    }
+
+    fn page_used(&self, addrsp: &UserAddrSpace, index: usize) -> bool {
+        addrsp.page_table.translate_addr(self.page_addr(index)).is_some()
+    }
+
+    fn next_page_unused(&mut self, addrsp: &UserAddrSpace) -> usize {
+        while self.page_used(addrsp, self.consumed_page_count) {
+            self.consumed_page_count += 1;
+        }
+        self.consumed_page_count
+    }
+
+    // first index of `pages` unused pages in a row
+    fn next_unused_run(&mut self, addrsp: &UserAddrSpace, pages: usize) -> usize {
+        let mut start = self.next_page_unused(addrsp);
+        while let Some(used) = (start..start + pages).find(|index| self.page_used(addrsp, *index)) {
+            start = used + 1;
+        }
+        start
+    }
+
+    // map a new frame at the next unused page, returns its index
+    unsafe fn map_page(&mut self, addrsp: &mut UserAddrSpace) -> KResult<usize> {
+        let frame = try_frame_alloc()?;
+        let index = self.next_page_unused(addrsp);
+        if let Err(err) = addrsp.raw_map_to(Page::containing_address(self.page_addr(index)), frame, BUFFER_FLAGS) {
+            frame_dealloc(frame);
+            return Err(err);
+        }
+        addrsp.push_tracked_frame(frame);
+        self.consumed_page_count += 1;
+        Ok(index)
+    }
+
+    // unmapped pages are handed out again from the lowest one
+    unsafe fn unmap_pages(&mut self, addrsp: &mut UserAddrSpace, addr: u64, pages: usize) {
+        let start_page = Page::<Size4KiB>::containing_address(VirtAddr::new(addr));
+        for page in Page::range(start_page, start_page + pages as u64) {
+            addrsp.raw_unmap(page);
+        }
+        self.consumed_page_count = self.consumed_page_count.min(self.page_index(addr));
+    }
+
+    fn slab_position(&self, addr: u64) -> Result<usize, usize> {
+        self.slabs.binary_search_by_key(&addr, |slab| slab.addr)
+    }
+
+    // a slab page of `class` with a free slot, mapped if there is none
+    unsafe fn partial_slab(&mut self, addrsp: &mut UserAddrSpace, class: usize) -> KResult<u64> {
+        if let Some(addr) = self.partial[class].last() {
+            return Ok(*addr);
+        }
+        self.slabs.try_reserve(1).map_err(|_| KError::new(ENOMEM))?;
+        self.partial[class].try_reserve(1).map_err(|_| KError::new(ENOMEM))?;
+
+        let addr = self.page_addr(self.map_page(addrsp)?).as_u64();
+        let position = self.slab_position(addr).expect_err("slab page mapped twice");
+        self.slabs.insert(position, SlabPage::new(addr, class));
+        self.partial[class].push(addr);
+        addrsp.stats.slab_pages += 1;
+        Ok(addr)
+    }
+
+    /// returns address, buffer class and capacity
+    unsafe fn alloc(&mut self, addrsp: &mut UserAddrSpace, size: usize) -> KResult<(VirtAddr, BufferClass, usize)> {
+        if let Some(class) = SLAB_SIZES.iter().position(|slot_size| *slot_size >= size) {
+            let addr = self.partial_slab(addrsp, class)?;
+            let position = self.slab_position(addr).or_panic("partial slab page is not tracked");
+            let slab = &mut self.slabs[position];
+            let slot = slab.take().or_panic("partial slab page is full");
+            if slab.is_full() {
+                self.partial[class].pop();
+            }
+            let virt_addr = VirtAddr::new(addr + (slot * SLAB_SIZES[class]) as u64);
+            return Ok((virt_addr, BufferClass::Slab, SLAB_SIZES[class]));
+        }
+
+        let required_pages = size.div_ceil(PAGE_SIZE);
+        self.large.try_reserve(1).map_err(|_| KError::new(ENOMEM))?;
+        // freed large buffers leave holes, a buffer needs a run of unused pages
+        let virt_addr = self.page_addr(self.next_unused_run(addrsp, required_pages));
+        let start_page = Page::<Size4KiB>::containing_address(virt_addr);
+
+        // pages mapped before a failure stay tracked and are skipped by `next_page_unused`
+        for page in Page::range(start_page, start_page + required_pages as u64) {
+            let frame = try_frame_alloc()?;
+            if let Err(err) = addrsp.raw_map_to(page, frame, BUFFER_FLAGS) {
+                frame_dealloc(frame);
+                return Err(err);
+            }
+            addrsp.push_tracked_frame(frame);
+        }
+
+        self.large.push((virt_addr.as_u64(), required_pages * PAGE_SIZE));
+        Ok((virt_addr, BufferClass::Large, required_pages * PAGE_SIZE))
+    }
+
+    unsafe fn free(&mut self, addrsp: &mut UserAddrSpace, buffer: &UserBuffer) -> KResult<()> {
+        let addr = buffer.ptr() as u64;
+        match buffer.class() {
+            BufferClass::Foreign => Err(KError::new(EINVAL)),
+            BufferClass::Slab => {
+                let page_addr = addr & !(PAGE_SIZE as u64 - 1);
+                let position = self.slab_position(page_addr).map_err(|_| KError::new(EINVAL))?;
+                let slab = &self.slabs[position];
+                let (class, was_full) = (slab.class, slab.is_full());
+                let slot_size = SLAB_SIZES[class];
+                if slot_size != buffer.capacity() || (addr - page_addr) as usize % slot_size != 0 {
+                    return Err(KError::new(EINVAL));
+                }
+                if was_full {
+                    self.partial[class].try_reserve(1).map_err(|_| KError::new(ENOMEM))?;
+                }
+
+                let slab = &mut self.slabs[position];
+                if !slab.put((addr - page_addr) as usize / slot_size) {
+                    return Err(KError::new(EINVAL));
+                }
+                let empty = slab.in_use == 0;
+                if was_full {
+                    self.partial[class].push(page_addr);
+                }
+
+                // an empty page stays while it is the only one with free slots,
+                // alloc/free in a loop would map and unmap it every time
+                if empty && self.partial[class].len() > 1 {
+                    self.partial[class].retain(|partial| *partial != page_addr);
+                    self.slabs.remove(position);
+                    self.unmap_pages(addrsp, page_addr, 1);
+                    addrsp.stats.slab_pages -= 1;
+                }
+                Ok(())
+            }
+            BufferClass::Large => {
+                let index = self.large.iter().position(|large| *large == (addr, buffer.capacity()))
+                    .ok_or(KError::new(EINVAL))?;
+                self.large.swap_remove(index);
+                self.unmap_pages(addrsp, addr, buffer.capacity() / PAGE_SIZE);
+                Ok(())
+            }
+        }
+    }
+
+    fn device_pages(phys: u64, len: usize) -> KResult<usize> {
+        if len == 0 {
+            return Err(KError::new(EINVAL));
+        }
+        let offset = (phys & (PAGE_SIZE as u64 - 1)) as usize;
+        Ok(offset.checked_add(len).ok_or(KError::new(EINVAL))?.div_ceil(PAGE_SIZE))
+    }
+
+    unsafe fn map_device(&mut self, addrsp: &mut UserAddrSpace, phys: PhysAddr, len: usize) -> KResult<VirtAddr> {
+        let pages = Self::device_pages(phys.as_u64(), len)?;
+        self.devices.try_reserve(1).map_err(|_| KError::new(ENOMEM))?;
+        let virt_addr = self.page_addr(self.next_unused_run(addrsp, pages));
+        let start_page = Page::<Size4KiB>::containing_address(virt_addr);
+        let start_frame = PhysFrame::<Size4KiB>::containing_address(phys);
+
+        for i in 0..pages as u64 {
+            if let Err(err) = addrsp.raw_map_to(start_page + i, start_frame + i, DEVICE_FLAGS) {
+                // untracked frames are only unmapped
+                self.unmap_pages(addrsp, virt_addr.as_u64(), i as usize);
+                return Err(err);
+            }
+        }
+        self.devices.push((virt_addr.as_u64(), pages));
+        Ok(virt_addr + (phys.as_u64() & (PAGE_SIZE as u64 - 1)))
+    }
+
+    unsafe fn unmap_device(&mut self, addrsp: &mut UserAddrSpace, virt: VirtAddr, len: usize) -> KResult<()> {
+        let mapping = (virt.align_down(PAGE_SIZE as u64).as_u64(), Self::device_pages(virt.as_u64(), len)?);
+        let index = self.devices.iter().position(|device| *device == mapping).ok_or(KError::new(EINVAL))?;
+        self.devices.swap_remove(index);
+        self.unmap_pages(addrsp, mapping.0, mapping.1);
+        Ok(())
     }
 }
 
-struct TempAllocator<'a>(&'a mut Vec<PhysFrame>);
+// page table frames of an address space, `map_to` allocates new tables from here
+struct PteFrames(Vec<PhysFrame>);
 
-unsafe impl FrameAllocator<Size4KiB> for TempAllocator<'_> {
+unsafe impl FrameAllocator<Size4KiB> for PteFrames {
     fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
-        let frame = frame_alloc()
-            .expect("failed to allocate new pte for addr space page table");
-        self.0.push(frame.clone());
+        // `None` makes map_to fail with `FrameAllocationFailed`
+        let frame = frame_alloc()?;
+        self.0.push(frame);
         Some(frame)
     }
 }
 
 impl Drop for UserAddrSpace {
     fn drop(&mut self) {
-        for frame in self.tracked_small_buffers.iter() {
-            frame_dealloc(frame.frame)
-        }
-        for frame in self.tracked_medium_buffers.iter() {
-            frame_dealloc(frame.frame)
+        // a tracked frame holds one reference per mapping, unmapping it untracks
+        // it once. a merged frame may be mapped at several pages, anything else
+        // tracked more often than it is referenced drops a reference twice
+        debug_assert!(
+            self.tracked_frames.len() <= self.stats.mapped_pages,
+            "{} tracked frames but only {} mapped pages", self.tracked_frames.len(), self.stats.mapped_pages
+        );
+        if cfg!(debug_assertions) {
+            self.tracked_frames.sort_unstable();
+            for run in self.tracked_frames.chunk_by(|a, b| a == b).filter(|run| run.len() > 1) {
+                assert!(
+                    frame_refcount(run[0]) as usize >= run.len(),
+                    "frame {:#x} tracked {} times", run[0].start_address().as_u64(), run.len()
+                );
+            }
         }
-        for frame in self.tracked_large_buffers.iter() {
+
+        for frame in self.tracked_frames.iter() {
             frame_dealloc(*frame)
         }
 
-        for frame in self.pte_frames.iter() {
+        for frame in self.pte_frames.0.iter() {
             frame_dealloc(*frame)
         }
 
-        frame_dealloc(PhysFrame::containing_address(
-            PhysAddr::new(self.page_table.level_4_table() as *const _ as u64)
-        ));
+        frame_dealloc(PhysFrame::containing_address(self.root.pml4));
     }
-}
\ No newline at end of file
+}
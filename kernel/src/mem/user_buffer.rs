@@ -3,6 +3,17 @@ use alloc::vec::Vec;
 use libvdso::error::{ENOMEM, ESRCH, KError, KResult};
 use crate::context::list::context_storage;
 
+/// where a [`UserBuffer`] comes from, decides how it is freed
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BufferClass {
+    /// memory passed in by userspace, not ours to free
+    Foreign,
+    /// slot of a slab page, shared with buffers of the same size class
+    Slab,
+    /// pages of its own
+    Large,
+}
+
 // represents a memory region at userspace
 #[repr(C)]
 #[derive(Copy, Clone)]
@@ -10,16 +21,41 @@ pub struct UserBuffer {
     // the base address at the userspace of `context`
     base: *const u8,
     len: usize,
+    class: BufferClass,
+    // bytes reserved for the buffer, at least `len`
+    capacity: usize,
+    // pml4 of the address space `alloc` took it from, 0 for foreign buffers
+    owner: u64,
 }
 
 impl UserBuffer {
     pub fn new(base: u64, len: usize) -> Self {
         Self {
             base: base as *const u8,
-            len
+            len,
+            class: BufferClass::Foreign,
+            capacity: len,
+            owner: 0,
         }
     }
 
+    /// buffer handed out by `RwLockUserAddrSpace::alloc`
+    pub(super) fn allocated(base: u64, len: usize, class: BufferClass, capacity: usize, owner: u64) -> Self {
+        Self { base: base as *const u8, len, class, capacity, owner }
+    }
+
+    pub fn class(&self) -> BufferClass {
+        self.class
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub(super) fn owner(&self) -> u64 {
+        self.owner
+    }
+
     pub fn len(&self) -> usize {
         self.len
     }
@@ -34,13 +70,11 @@ impl UserBuffer {
             Some(lock) => lock,
             None => return Err(KError::new(ESRCH))
         };
-        let guard = context.write_arc();
-        let addrsp = match guard.addrsp {
-            Some(ref r) => r,
+        let addrsp = match context.read().addrsp {
+            Some(ref r) => Arc::clone(r),
             None => return Err(KError::new(ENOMEM))
         };
 
-        let addrsp = addrsp.acquire_read();
         addrsp.resolve(Arc::clone(&self))
     }
 }
\ No newline at end of file
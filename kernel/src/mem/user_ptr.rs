@@ -0,0 +1,185 @@
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+use core::mem::{size_of, MaybeUninit};
+use core::ptr;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::{PhysAddr, VirtAddr};
+use libvdso::error::{EFAULT, EINVAL, ESRCH, KError, KResult};
+use shared::layout::USER_SPACE_END;
+use crate::context::list::context_storage;
+use crate::logger::audit::{audit, AuditKind};
+use crate::mem::frame_allocator::frame_dealloc;
+use crate::mem::user_addr_space::RwLockUserAddrSpace;
+
+/**
+ *  user memory passed to syscalls.
+ *
+ *  every syscall argument pointing into user memory goes through [`UserSlice`] or
+ *  [`UserPtr`]: the range must lie below the user/kernel split and be mapped
+ *  user accessible (and writable for writes) in the current address space.
+ *  the whole range is validated before any byte is copied, a range that
+ *  fails is audited. the frames of the range are referenced until the copy
+ *  is done, a sibling thread unmapping them meanwhile can't get them reused.
+ */
+
+// part of a user slice within one page, its frame stays allocated until dropped
+struct Chunk {
+    phys_addr: PhysAddr,
+    len: usize,
+    pin: Option<PhysFrame>,
+}
+
+impl Drop for Chunk {
+    fn drop(&mut self) {
+        if let Some(frame) = self.pin {
+            frame_dealloc(frame);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct UserSlice {
+    base: usize,
+    len: usize,
+    writable: bool,
+}
+
+impl UserSlice {
+    fn new(base: usize, len: usize, writable: bool) -> KResult<Self> {
+        let end = base.checked_add(len).ok_or_else(|| rejected(base, len))?;
+        if end as u64 > USER_SPACE_END {
+            return Err(rejected(base, len));
+        }
+        Ok(Self { base, len, writable })
+    }
+
+    /// user memory the kernel reads from
+    pub fn ro(base: usize, len: usize) -> KResult<Self> {
+        Self::new(base, len, false)
+    }
+
+    /// user memory the kernel writes to
+    pub fn rw(base: usize, len: usize) -> KResult<Self> {
+        Self::new(base, len, true)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// first `len` bytes of this slice
+    pub fn limit(self, len: usize) -> Self {
+        Self { len: self.len.min(len), ..self }
+    }
+
+    // physical chunks of this slice split at page boundaries
+    fn chunks(&self, addrsp: &RwLockUserAddrSpace) -> KResult<Vec<Chunk>> {
+        let mut chunks = Vec::new();
+        let mut done = 0;
+        while done < self.len {
+            let virt_addr = VirtAddr::new((self.base + done) as u64);
+            let (phys_addr, till_page_end, pin) = addrsp.translate_user_pinned(virt_addr, self.writable)
+                .inspect_err(|err| if err.errno == EFAULT { rejected(self.base, self.len); })?;
+            let len = (till_page_end as usize).min(self.len - done);
+            chunks.push(Chunk { phys_addr, len, pin });
+            done += len;
+        }
+        Ok(chunks)
+    }
+
+    /// copy the whole slice into `dst`, `dst` must be at least as long as this slice
+    pub fn copy_to_kernel(&self, dst: &mut [u8]) -> KResult<()> {
+        if dst.len() < self.len {
+            return Err(KError::new(EINVAL));
+        }
+        with_current_addrsp(|addrsp| {
+            let mut offset = 0;
+            for chunk in self.chunks(addrsp)? {
+                unsafe { ptr::copy_nonoverlapping(chunk.phys_addr.as_u64() as *const u8, dst[offset..].as_mut_ptr(), chunk.len); }
+                offset += chunk.len;
+            }
+            Ok(())
+        })
+    }
+
+    /// copy `src` to the start of this slice, returns copied length
+    pub fn copy_from_kernel(&self, src: &[u8]) -> KResult<usize> {
+        if !self.writable {
+            return Err(KError::new(EFAULT));
+        }
+        let target = self.limit(src.len());
+        with_current_addrsp(|addrsp| {
+            let mut offset = 0;
+            for chunk in target.chunks(addrsp)? {
+                unsafe { ptr::copy_nonoverlapping(src[offset..].as_ptr(), chunk.phys_addr.as_u64() as *mut u8, chunk.len); }
+                offset += chunk.len;
+            }
+            Ok(offset)
+        })
+    }
+
+    pub fn read_to_vec(&self) -> KResult<Vec<u8>> {
+        let mut buf = vec![0; self.len];
+        self.copy_to_kernel(&mut buf)?;
+        Ok(buf)
+    }
+}
+
+/// a single `T` in user memory
+pub struct UserPtr<T: Copy> {
+    slice: UserSlice,
+    _marker: PhantomData<T>,
+}
+
+impl<T: Copy> UserPtr<T> {
+    fn new(addr: usize, writable: bool) -> KResult<Self> {
+        if addr % core::mem::align_of::<T>() != 0 {
+            return Err(rejected(addr, size_of::<T>()));
+        }
+        Ok(Self { slice: UserSlice::new(addr, size_of::<T>(), writable)?, _marker: PhantomData })
+    }
+
+    pub fn ro(addr: usize) -> KResult<Self> {
+        Self::new(addr, false)
+    }
+
+    pub fn rw(addr: usize) -> KResult<Self> {
+        Self::new(addr, true)
+    }
+
+    /// `T` must be valid for any bit pattern
+    pub fn read(&self) -> KResult<T> {
+        let mut value = MaybeUninit::<T>::uninit();
+        let bytes = unsafe { core::slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, size_of::<T>()) };
+        self.slice.copy_to_kernel(bytes)?;
+        Ok(unsafe { value.assume_init() })
+    }
+
+    pub fn write(&self, value: T) -> KResult<()> {
+        let bytes = unsafe { core::slice::from_raw_parts(&value as *const T as *const u8, size_of::<T>()) };
+        self.slice.copy_from_kernel(bytes).map(|_| ())
+    }
+}
+
+// a range that failed validation, audited
+fn rejected(base: usize, len: usize) -> KError {
+    audit(AuditKind::Efault, format_args!("{:#x} + {:#x}", base, len));
+    KError::new(EFAULT)
+}
+
+// translation takes no lock, see `user_addr_space`
+fn with_current_addrsp<R>(f: impl FnOnce(&RwLockUserAddrSpace) -> KResult<R>) -> KResult<R> {
+    let addrsp = {
+        let contexts = context_storage();
+        let context = contexts.current().ok_or(KError::new(ESRCH))?;
+        let context = context.read();
+        context.addrsp.as_ref().map(Arc::clone).ok_or(KError::new(EFAULT))?
+    };
+    f(&addrsp)
+}
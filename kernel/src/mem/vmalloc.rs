@@ -0,0 +1,112 @@
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::VirtAddr;
+use libvdso::error::{EINVAL, ENOMEM, KError, KResult};
+use crate::mem::frame_allocator::{frame_alloc, frame_dealloc, frame_ref};
+use crate::mem::kvm::{kvm_alloc, kvm_map_page, kvm_unmap_page, KvmRegion};
+use crate::mem::PAGE_SIZE;
+use crate::sync::IrqSpinlock;
+
+/**
+ *  virtually contiguous kernel memory.
+ *
+ *  [`vmalloc`] backs a range of the [`KvmRegion::Vmalloc`] region with single
+ *  frames from anywhere in physical memory, [`vmap`] maps frames the caller
+ *  already owns, e.g. dma buffers of a driver. every area is followed by an
+ *  unmapped guard page. only freed frames wait for a tlb shootdown, other cpus
+ *  may still cache a freed address range, so it is never handed out again.
+ */
+
+static AREAS: IrqSpinlock<BTreeMap<u64, VmArea>> = IrqSpinlock::new(BTreeMap::new());
+
+struct VmArea {
+    frames: Vec<PhysFrame>,
+    // frames came from `vmalloc`, only `vfree` takes the area down
+    owned: bool,
+}
+
+fn map_area(frames: &[PhysFrame], flags: PageTableFlags) -> KResult<VirtAddr> {
+    if frames.is_empty() {
+        return Err(KError::new(EINVAL));
+    }
+    // one more page for the guard, left unmapped
+    let start = kvm_alloc(KvmRegion::Vmalloc, frames.len() + 1).ok_or(KError::new(ENOMEM))?;
+    let first = Page::<Size4KiB>::containing_address(start);
+    for (i, frame) in frames.iter().enumerate() {
+        if let Err(err) = unsafe { kvm_map_page(first + i as u64, *frame, flags) } {
+            (0..i).for_each(|j| unsafe { kvm_unmap_page(first + j as u64); });
+            return Err(err);
+        }
+    }
+    Ok(start)
+}
+
+fn unmap_area(start: VirtAddr, area: &VmArea) {
+    let first = Page::<Size4KiB>::containing_address(start);
+    for (i, frame) in area.frames.iter().enumerate() {
+        unsafe { kvm_unmap_page(first + i as u64); }
+        // the area holds one reference, the last one frees a vmalloc frame
+        frame_dealloc(*frame);
+    }
+}
+
+/// allocate `len` bytes of zeroed, virtually contiguous kernel memory
+pub fn vmalloc(len: usize) -> KResult<VirtAddr> {
+    let pages = len.div_ceil(PAGE_SIZE);
+    let mut frames = Vec::with_capacity(pages);
+    for _ in 0..pages {
+        match frame_alloc() {
+            Some(frame) => frames.push(frame),
+            None => {
+                frames.into_iter().for_each(frame_dealloc);
+                return Err(KError::new(ENOMEM));
+            }
+        }
+    }
+
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    let start = match map_area(&frames, flags) {
+        Ok(start) => start,
+        Err(err) => {
+            frames.into_iter().for_each(frame_dealloc);
+            return Err(err);
+        }
+    };
+    unsafe { start.as_mut_ptr::<u8>().write_bytes(0, pages * PAGE_SIZE) };
+    AREAS.lock().insert(start.as_u64(), VmArea { frames, owned: true });
+    Ok(start)
+}
+
+/// free memory of [`vmalloc`]
+pub fn vfree(start: VirtAddr) {
+    let Some(area) = AREAS.lock().remove(&start.as_u64()) else {
+        panic!("vfree of 0x{:x}, not a vmalloc area", start.as_u64());
+    };
+    debug_assert!(area.owned, "vfree of 0x{:x}, mapped by vmap", start.as_u64());
+    unmap_area(start, &area);
+}
+
+/// map `frames` contiguously into kernel space, the caller keeps owning them.
+/// the area holds a reference to each until [`vunmap`]
+pub fn vmap(frames: &[PhysFrame], flags: PageTableFlags) -> KResult<VirtAddr> {
+    let start = map_area(frames, flags | PageTableFlags::PRESENT)?;
+    frames.iter().for_each(|frame| { frame_ref(*frame); });
+    AREAS.lock().insert(start.as_u64(), VmArea { frames: frames.to_vec(), owned: false });
+    Ok(start)
+}
+
+/// undo [`vmap`], drops the references of the area
+pub fn vunmap(start: VirtAddr) {
+    let Some(area) = AREAS.lock().remove(&start.as_u64()) else {
+        panic!("vunmap of 0x{:x}, not a vmap area", start.as_u64());
+    };
+    debug_assert!(!area.owned, "vunmap of 0x{:x}, allocated by vmalloc", start.as_u64());
+    unmap_area(start, &area);
+}
+
+/// (areas, bytes mapped)
+pub fn vmalloc_stats() -> (usize, usize) {
+    let areas = AREAS.lock();
+    (areas.len(), areas.values().map(|area| area.frames.len() * PAGE_SIZE).sum())
+}
@@ -5,9 +5,25 @@ use crate::errorhart;
 #[cfg(not(test))]
 #[panic_handler]
 fn panic_handler(info: &PanicInfo) -> ! {
+    use alloc::format;
+    use crate::context::list::try_context_storage;
     use crate::halt;
 
-    errorhart!("kernel panic: {:?}", info);
+    // returns only if the panic did not come from a recoverable irq handler
+    crate::sync::irq_guard::recover(info);
+
+    // context lock may be held by the panicking code itself
+    let current = try_context_storage()
+        .and_then(|contexts| contexts.current().and_then(|c| c.try_read().map(|c| format!("{}", c.display()))));
+    match current {
+        Some(context) => errorhart!("kernel panic in context {}: {:?}", context, info),
+        None => errorhart!("kernel panic: {:?}", info),
+    }
+    // flusher thread will never run again
+    crate::logger::panic_flush_log();
+    crate::crashdump::write_crash_dump(info);
+    #[cfg(feature = "fuzz")]
+    crate::fuzz::report_panic();
     loop {
         halt();
     }
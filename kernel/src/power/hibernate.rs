@@ -0,0 +1,438 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::slice;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::paging::{Page, Size4KiB};
+use x86_64::VirtAddr;
+use libvdso::error::{EEXIST, EINVAL, KError, KResult};
+use shared::layout::{USER_STACK_BASE, USER_STACK_TOP};
+use crate::arch::ArchContextRegs;
+use crate::arch_spec::frame_check::frame_issue;
+use crate::arch_spec::fsgsbase::save_user_bases;
+use crate::arch_spec::memcopy;
+use crate::context::coredump::{build_core_dump, CoreImage, CoreSegment, CORE_MAX_BYTES};
+use crate::context::list::{context_storage_mut, try_context_storage};
+use crate::context::spawn::{SpawnEntry, SpawnOptions};
+use crate::context::status::Status;
+use crate::context::{context_id, init_context, set_init_context, Context, ContextId, CONTEXT_NAME_LEN};
+use crate::crashdump::{crc32, parked_cs};
+use crate::device::com::COM1;
+use crate::device::tsc::monotonic_ns;
+use crate::initcall;
+use crate::initcall::{kernel_arg, InitCpuArg};
+use crate::mem::frame_allocator::{allocated_frame_count, frame_dealloc, try_frame_alloc, used_frame_count};
+use crate::mem::user_addr_space::UserAddrSpace;
+use crate::mem::PAGE_SIZE;
+use crate::{infohart, warnhart, CPU_COUNT};
+
+/**
+ *  hibernation, experimental.
+ *
+ *  `reboot(REBOOT_HIBERNATE)` parks the other cpus like any reboot, captures
+ *  every userspace context and resets the machine. there is no disk driver,
+ *  the image goes to com1 as hex lines between
+ *
+ *      hibernate: begin
+ *      hibernate: end <bytes> bytes crc <crc32 of the image, 8 hex>
+ *
+ *  `build-image hibernate <serial log>` turns them into a file for the boot
+ *  partition, `resume=` in boot.cfg makes the bootloader load it like a
+ *  module. the kernel then restores the contexts of the image in place of
+ *  bootstrap and the boot modules. the image is little endian:
+ *
+ *      header   "MHIBERN1", u32 version, u32 cpu count, u64 uptime ns,
+ *               u64 used frames, u64 allocated frames
+ *      context  u64 id, u32 flags, u32 priority, 16 bytes name (nul padded),
+ *               u64 core length, elf core file of the context, see `coredump`
+ *
+ *  a context comes back with its memory, registers and fs/gs bases, nothing
+ *  else: no handles, irqs, io ports, timers, signals, credentials or limits,
+ *  no threads and no parent links, and it gets a new id. one blocked in a
+ *  syscall runs the syscall again, the one that asked to hibernate sees its
+ *  call return 0. stack slots map kernel stacks and are left out. user pages
+ *  of the low pml4 entry are shared by all address spaces, a page restored
+ *  for one context is there for the later ones already.
+ *
+ *  contexts and address spaces locked by a parked cpu are lost, a cpu parked
+ *  inside the frame allocator or the heap stalls the capture.
+ */
+
+const IMAGE_MAGIC: &[u8; 8] = b"MHIBERN1";
+const IMAGE_VERSION: u32 = 1;
+const SERIAL_LINE_BYTES: usize = 32;
+
+// context record flags
+const FLAG_INIT: u32 = 1 << 0;
+// blocked in a syscall, it runs again on resume
+const FLAG_RESTART: u32 = 1 << 1;
+// asked to hibernate, its call returns 0 on resume
+const FLAG_CALLER: u32 = 1 << 2;
+
+// backing rip up over `syscall` runs it again
+const SYSCALL_INSN_LEN: usize = 2;
+
+static RESUMED: AtomicBool = AtomicBool::new(false);
+
+/// whether this boot restored contexts from a hibernation image, bootstrap
+/// and the boot modules are not started then
+pub fn resumed() -> bool {
+    RESUMED.load(Ordering::SeqCst)
+}
+
+// image bytes as hex lines, counting length and crc of what went out
+struct ImageWriter<'a, W: Write> {
+    out: &'a mut W,
+    line: [u8; SERIAL_LINE_BYTES],
+    line_len: usize,
+    len: usize,
+    crc: u32,
+}
+
+impl<'a, W: Write> ImageWriter<'a, W> {
+    fn new(out: &'a mut W) -> Self {
+        Self { out, line: [0; SERIAL_LINE_BYTES], line_len: 0, len: 0, crc: 0 }
+    }
+
+    fn put(&mut self, mut bytes: &[u8]) {
+        self.len += bytes.len();
+        self.crc = crc32(self.crc, bytes);
+        while !bytes.is_empty() {
+            let take = bytes.len().min(SERIAL_LINE_BYTES - self.line_len);
+            self.line[self.line_len..self.line_len + take].copy_from_slice(&bytes[..take]);
+            self.line_len += take;
+            bytes = &bytes[take..];
+            if self.line_len == SERIAL_LINE_BYTES {
+                self.flush_line();
+            }
+        }
+    }
+
+    fn flush_line(&mut self) {
+        if self.line_len == 0 {
+            return;
+        }
+        self.line[..self.line_len].iter().for_each(|byte| { let _ = write!(self.out, "{:02x}", byte); });
+        let _ = writeln!(self.out);
+        self.line_len = 0;
+    }
+}
+
+fn put_header<W: Write>(out: &mut ImageWriter<'_, W>) {
+    out.put(IMAGE_MAGIC);
+    out.put(&IMAGE_VERSION.to_le_bytes());
+    out.put(&CPU_COUNT.load(Ordering::SeqCst).to_le_bytes());
+    out.put(&monotonic_ns().to_le_bytes());
+    out.put(&(used_frame_count() as u64).to_le_bytes());
+    out.put(&(allocated_frame_count() as u64).to_le_bytes());
+}
+
+fn put_record<W: Write>(out: &mut ImageWriter<'_, W>, id: ContextId, flags: u32, priority: u8, name: &str, core: &[u8]) {
+    let mut padded = [0u8; CONTEXT_NAME_LEN];
+    padded[..name.len()].copy_from_slice(name.as_bytes());
+    out.put(&(id.get() as u64).to_le_bytes());
+    out.put(&flags.to_le_bytes());
+    out.put(&(priority as u32).to_le_bytes());
+    out.put(&padded);
+    out.put(&(core.len() as u64).to_le_bytes());
+    out.put(core);
+}
+
+// a context running on a parked cpu was in a syscall if the halt ipi found
+// that cpu in the kernel, the others say so themselves
+fn in_syscall(context: &Context) -> bool {
+    match (context.running, context.cpu_id) {
+        (true, Some(cpu)) => parked_cs(cpu.0 as usize).is_some_and(|cs| cs & 3 == 0),
+        _ => context.inside_syscall,
+    }
+}
+
+enum Captured {
+    // kernel, idle and exited contexts, threads
+    Skipped,
+    // locked by a parked cpu or too large for a core file
+    Lost,
+    Core(u32, Vec<u8>),
+}
+
+unsafe fn capture(context: &mut Context, caller: ContextId) -> Captured {
+    if !context.userspace || context.is_idle() || context.stack_slot.is_some() || matches!(context.status, Status::Existed(_)) {
+        return Captured::Skipped;
+    }
+    let Some(addrsp) = context.addrsp.clone() else { return Captured::Skipped };
+
+    let mut flags = 0;
+    if init_context() == Some(context.id) {
+        flags |= FLAG_INIT;
+    }
+    if context.id == caller {
+        // running on this cpu, its bases are still in the msrs
+        save_user_bases(&mut context.ctx_regs);
+        flags |= FLAG_CALLER;
+    } else if in_syscall(context) {
+        flags |= FLAG_RESTART;
+    }
+
+    let (fsbase, gsbase) = (context.ctx_regs.fsbase, context.ctx_regs.gsbase);
+    let Some(regs) = context.regs() else { return Captured::Skipped };
+    let Some(mut addrsp) = addrsp.try_acquire_write() else { return Captured::Lost };
+    // a truncated core would come back with holes
+    if addrsp.mapped_regions().iter().map(|region| region.len).sum::<u64>() > CORE_MAX_BYTES as u64 {
+        return Captured::Lost;
+    }
+    Captured::Core(flags, build_core_dump(context.id, 0, regs, fsbase, gsbase, &mut addrsp))
+}
+
+/// stream every userspace context to com1, called by `reboot` with the other cpus parked
+pub fn write_image() {
+    let Some(contexts) = try_context_storage() else {
+        warnhart!("hibernate: context list is locked, nothing captured");
+        return;
+    };
+    let caller = context_id();
+    let (mut captured, mut lost) = (0, 0);
+    let (len, crc) = {
+        let Some(mut com) = COM1.try_lock() else {
+            warnhart!("hibernate: com1 is busy, nothing captured");
+            return;
+        };
+        let _ = writeln!(com, "hibernate: begin");
+        let mut out = ImageWriter::new(&mut *com);
+        put_header(&mut out);
+
+        for context_lock in contexts.iter().map(|(_, lock)| lock) {
+            let Some(mut context) = context_lock.try_write() else {
+                lost += 1;
+                continue;
+            };
+            match unsafe { capture(&mut context, caller) } {
+                Captured::Skipped => {}
+                Captured::Lost => lost += 1,
+                Captured::Core(flags, core) => {
+                    put_record(&mut out, context.id, flags, context.priority, context.name(), &core);
+                    captured += 1;
+                }
+            }
+        }
+        out.flush_line();
+        let (len, crc) = (out.len, out.crc);
+        let _ = writeln!(com, "hibernate: end {} bytes crc {:08x}", len, crc);
+        (len, crc)
+    };
+    infohart!("hibernate: {} contexts in {} bytes, crc {:08x}, {} lost", captured, len, crc, lost);
+}
+
+struct ImageHeader {
+    uptime_ns: u64,
+    used_frames: u64,
+    allocated_frames: u64,
+}
+
+struct ImageRecord<'a> {
+    id: u64,
+    flags: u32,
+    priority: u8,
+    name: &'a str,
+    core: &'a [u8],
+}
+
+// `len` bytes at `offset`, which moves past them
+fn take<'a>(image: &'a [u8], offset: &mut usize, len: usize) -> Result<&'a [u8], &'static str> {
+    let bytes = offset.checked_add(len).and_then(|end| image.get(*offset..end)).ok_or("image is truncated")?;
+    *offset += len;
+    Ok(bytes)
+}
+
+fn take_u32(image: &[u8], offset: &mut usize) -> Result<u32, &'static str> {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(take(image, offset, 4)?);
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn take_u64(image: &[u8], offset: &mut usize) -> Result<u64, &'static str> {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(take(image, offset, 8)?);
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn parse_image(image: &[u8]) -> Result<(ImageHeader, Vec<ImageRecord<'_>>), &'static str> {
+    let mut offset = 0;
+    if take(image, &mut offset, IMAGE_MAGIC.len())? != IMAGE_MAGIC {
+        return Err("bad magic");
+    }
+    if take_u32(image, &mut offset)? != IMAGE_VERSION {
+        return Err("unknown version");
+    }
+    let _cpu_count = take_u32(image, &mut offset)?;
+    let header = ImageHeader {
+        uptime_ns: take_u64(image, &mut offset)?,
+        used_frames: take_u64(image, &mut offset)?,
+        allocated_frames: take_u64(image, &mut offset)?,
+    };
+
+    let mut records = Vec::new();
+    while offset < image.len() {
+        let id = take_u64(image, &mut offset)?;
+        let flags = take_u32(image, &mut offset)?;
+        let priority = u8::try_from(take_u32(image, &mut offset)?).map_err(|_| "priority out of range")?;
+        let name = take(image, &mut offset, CONTEXT_NAME_LEN)?;
+        let name_len = name.iter().position(|byte| *byte == 0).unwrap_or(CONTEXT_NAME_LEN);
+        let name = core::str::from_utf8(&name[..name_len]).map_err(|_| "context name is not utf-8")?;
+        let core_len = usize::try_from(take_u64(image, &mut offset)?).map_err(|_| "image is truncated")?;
+        let core = take(image, &mut offset, core_len)?;
+        records.push(ImageRecord { id, flags, priority, name, core });
+    }
+    Ok((header, records))
+}
+
+// drops to ring 3 with the registers restored from the image
+extern "C" fn resume_entry() {}
+
+// copy a segment into fresh frames, pages of the stack slots are skipped
+unsafe fn restore_segment(addrsp: &mut UserAddrSpace, segment: &CoreSegment) -> KResult<()> {
+    for (index, chunk) in segment.bytes.chunks(PAGE_SIZE).enumerate() {
+        let addr = segment.start + (index * PAGE_SIZE) as u64;
+        if (USER_STACK_BASE..USER_STACK_TOP).contains(&addr) {
+            continue;
+        }
+        let frame = try_frame_alloc()?;
+        let dst = frame.start_address().as_u64() as *mut u8;
+        memcopy::copy(dst, chunk.as_ptr(), chunk.len());
+        memcopy::zero(dst.add(chunk.len()), PAGE_SIZE - chunk.len());
+        match addrsp.raw_map_to(Page::<Size4KiB>::containing_address(VirtAddr::new(addr)), frame, segment.flags) {
+            Ok(()) => addrsp.push_tracked_frame(frame),
+            // restored for an earlier context of the image
+            Err(err) if err.errno == EEXIST => frame_dealloc(frame),
+            Err(err) => {
+                frame_dealloc(frame);
+                return Err(err);
+            }
+        }
+    }
+    Ok(())
+}
+
+unsafe fn load_context(context: &mut Context, core: &CoreImage, flags: u32) -> KResult<()> {
+    let addrsp = context.addrsp.clone().ok_or(KError::new(EINVAL))?;
+    {
+        let mut addrsp = addrsp.acquire_write();
+        for segment in core.segments.iter() {
+            restore_segment(&mut addrsp, segment)?;
+        }
+    }
+    context.ctx_regs.fsbase = core.fsbase();
+    context.ctx_regs.gsbase = core.gsbase();
+
+    let regs = context.regs_mut().ok_or(KError::new(EINVAL))?;
+    regs.init();
+    core.load_regs(regs);
+    // userspace never runs with interrupts off or an io privilege level
+    let rflags = RFlags::from_bits_truncate(regs.iret.rflags as u64) - RFlags::IOPL_HIGH - RFlags::IOPL_LOW;
+    regs.iret.rflags = (rflags | RFlags::INTERRUPT_FLAG).bits() as usize;
+    if flags & FLAG_CALLER != 0 {
+        regs.scratch.rax = 0;
+    } else if flags & FLAG_RESTART != 0 {
+        regs.set_instr_pointer(regs.instr_pointer() - SYSCALL_INSN_LEN);
+    }
+    match frame_issue(&regs.iret) {
+        Some(issue) => {
+            warnhart!("hibernate: context {}: {}", context.display(), issue);
+            Err(KError::new(EINVAL))
+        }
+        None => Ok(()),
+    }
+}
+
+unsafe fn restore(record: &ImageRecord) -> KResult<ContextId> {
+    let core = CoreImage::parse(record.core).ok_or(KError::new(EINVAL))?;
+    let options = SpawnOptions::userspace(record.name).priority(record.priority);
+    let context_lock = Arc::clone(context_storage_mut().spawn(&options, SpawnEntry::Func(resume_entry))?);
+    let mut context = context_lock.write();
+    let id = context.id;
+    if let Err(err) = load_context(&mut context, &core, record.flags) {
+        drop(context);
+        context_storage_mut().remove(id);
+        return Err(err);
+    }
+    if record.flags & FLAG_INIT != 0 {
+        set_init_context(id);
+    }
+    context.set_status(Status::Runnable);
+    Ok(id)
+}
+
+unsafe fn resume_initcall(_: &InitCpuArg) {
+    let arg = kernel_arg();
+    if arg.resume_len == 0 {
+        return;
+    }
+    let image = slice::from_raw_parts(arg.resume_base as *const u8, arg.resume_len);
+    let (header, records) = match parse_image(image) {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            warnhart!("hibernate: image rejected, {}, booting normally", err);
+            return;
+        }
+    };
+    infohart!(
+        "hibernate: resuming {} contexts, hibernated after {} ms with {} of {} frames in use",
+        records.len(), header.uptime_ns / 1_000_000, header.used_frames, header.allocated_frames
+    );
+
+    let mut resumed = 0;
+    for record in records.iter() {
+        match restore(record) {
+            Ok(id) => {
+                infohart!("hibernate: context {} ({}) resumed as {}", record.id, record.name, id.get());
+                resumed += 1;
+            }
+            Err(err) => warnhart!("hibernate: context {} ({}) not resumed: {}", record.id, record.name, err),
+        }
+    }
+    RESUMED.store(resumed > 0, Ordering::SeqCst);
+}
+// before the supervisor, which starts nothing on a resumed boot
+initcall!(late, Bsp, resume_initcall, order = 215);
+
+#[test_case]
+pub(crate) fn test_hibernate_image() {
+    use alloc::string::String;
+    use crate::syscall::InterruptStack;
+
+    let mut stack = InterruptStack::default();
+    stack.init();
+    stack.iret.rip = 0x4000;
+    stack.iret.rsp = 0x8000;
+    stack.scratch.rax = 7;
+    let mut addrsp = unsafe { UserAddrSpace::new() }.unwrap();
+    let core = build_core_dump(ContextId::from(3), 0, &stack, 0x1000, 0x2000, &mut addrsp);
+
+    let mut serial = String::new();
+    let mut out = ImageWriter::new(&mut serial);
+    put_header(&mut out);
+    put_record(&mut out, ContextId::from(3), FLAG_INIT | FLAG_RESTART, 5, "shell", &core);
+    out.flush_line();
+    let (len, crc) = (out.len, out.crc);
+
+    let image: Vec<u8> = serial.lines()
+        .flat_map(|line| (0..line.len()).step_by(2).map(move |i| u8::from_str_radix(&line[i..i + 2], 16).unwrap()))
+        .collect();
+    assert_eq!((image.len(), crc32(0, &image)), (len, crc));
+
+    let (_, records) = parse_image(&image).unwrap();
+    assert_eq!(records.len(), 1);
+    let record = &records[0];
+    assert_eq!((record.id, record.flags, record.priority, record.name), (3, FLAG_INIT | FLAG_RESTART, 5, "shell"));
+
+    let parsed = CoreImage::parse(record.core).unwrap();
+    assert!(parsed.segments.is_empty());
+    let mut restored = InterruptStack::default();
+    parsed.load_regs(&mut restored);
+    assert_eq!((restored.iret.rip, restored.iret.rsp, restored.scratch.rax), (0x4000, 0x8000, 7));
+    assert_eq!((parsed.fsbase(), parsed.gsbase()), (0x1000, 0x2000));
+
+    // cut short anywhere, the image is rejected
+    assert!(parse_image(&image[..image.len() - 1]).is_err());
+    assert!(parse_image(&image[..8]).is_err());
+}
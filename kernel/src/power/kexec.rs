@@ -0,0 +1,255 @@
+use core::arch::global_asm;
+use core::mem::size_of;
+use core::ptr;
+use x86_64::structures::paging::{PageTable, PageTableFlags, PhysFrame};
+use xmas_elf::{dynamic, header::{self, Type as EType}, program::{self, SegmentData, Type as ShType}, sections::Rela, ElfFile};
+use libvdso::error::{E2BIG, ENOEXEC, ENOMEM, KError, KResult};
+use shared::arg::{KernelArg, MemoryRegion, MemoryRegionKind, TlsTemplate};
+use shared::layout::{p4_base, KERNEL_ARG_P4, KERNEL_BYTES_P4, KERNEL_RUNTIME_P4};
+use crate::arch_spec::memcopy;
+use crate::arch_spec::msr::Msr;
+use crate::initcall::kernel_arg;
+use crate::mem::frame_allocator::{frame_alloc, frame_alloc_n};
+use crate::mem::{get_kernel_pml4_page_table_addr, PAGE_SIZE};
+use crate::infohart;
+
+/**
+ *  mini kexec, boot another kernel image without firmware.
+ *
+ *  does what bootloader does for the first kernel: the elf is copied into
+ *  fresh contiguous frames, relocated to `KERNEL_BYTES_P4`, and a new pml4 maps
+ *  it next to a copy of the current kernel arg. every other pml4 entry (phys
+ *  memory, stack, framebuffer, bootstrap) is shared with the current kernel.
+ *  frames in use by the new image are appended to the unavailable regions so
+ *  the next frame allocator does not hand them out.
+ *
+ *  the jump itself runs from an identity mapped stub, the current kernel text
+ *  disappears when cr3 is switched.
+ */
+
+const PAGE: u64 = PAGE_SIZE as u64;
+// one p1 table maps 2 MiB, image must fit in one p2 table
+const MAX_IMAGE_SIZE: u64 = 1 << 30;
+const R_X86_64_RELATIVE: u32 = 8;
+
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+
+/// a loaded kernel image, ready to be jumped to by [`KexecImage::execute`]
+pub struct KexecImage {
+    pml4: PhysFrame,
+    entry: u64,
+    arg: u64,
+    stack_top: u64,
+    stub: PhysFrame,
+}
+
+extern "C" {
+    static kexec_stub_start: u8;
+    static kexec_stub_end: u8;
+}
+
+// kexec_stub(pml4, stack_top, entry, arg), copied to an identity mapped frame
+global_asm!(
+    ".global kexec_stub_start",
+    ".global kexec_stub_end",
+    "kexec_stub_start:",
+    "    mov cr3, rdi",
+    "    mov rsp, rsi",
+    "    mov rdi, rcx",
+    "    xor ebp, ebp",
+    "    push 0",
+    "    jmp rdx",
+    "kexec_stub_end:",
+);
+
+fn alloc_zeroed(pages: usize) -> KResult<PhysFrame> {
+    let frame = frame_alloc_n(pages).ok_or(KError::new(ENOMEM))?;
+    unsafe { memcopy::zero(frame.start_address().as_u64() as *mut u8, pages * PAGE_SIZE) };
+    Ok(frame)
+}
+
+unsafe fn table(frame: PhysFrame) -> &'static mut PageTable {
+    &mut *(frame.start_address().as_u64() as *mut PageTable)
+}
+
+fn region(start: PhysFrame, pages: usize, kind: MemoryRegionKind) -> MemoryRegion {
+    MemoryRegion { start: start.start_address().as_u64(), length: pages as u64 * PAGE, kind }
+}
+
+/// parse and load `elf`, relocated to the kernel image slot.
+/// the current kernel keeps running until the image is executed.
+pub fn load(elf: &[u8]) -> KResult<KexecImage> {
+    let kernel_elf = ElfFile::new(elf).map_err(|_| KError::new(ENOEXEC))?;
+    header::sanity_check(&kernel_elf).map_err(|_| KError::new(ENOEXEC))?;
+    for ph in kernel_elf.program_iter() {
+        program::sanity_check(ph, &kernel_elf).map_err(|_| KError::new(ENOEXEC))?;
+    }
+    if !matches!(kernel_elf.header.pt2.type_().as_type(), EType::Executable | EType::SharedObject) {
+        return Err(KError::new(ENOEXEC));
+    }
+
+    let loads = || kernel_elf.program_iter().filter(|ph| matches!(ph.get_type(), Ok(ShType::Load)) && ph.mem_size() > 0);
+    let defined_start = loads().map(|ph| ph.virtual_addr()).min().ok_or(KError::new(ENOEXEC))? & !(PAGE - 1);
+    let defined_end = loads().map(|ph| ph.virtual_addr() + ph.mem_size()).max().unwrap_or(defined_start);
+    if defined_end - defined_start > MAX_IMAGE_SIZE {
+        return Err(KError::new(E2BIG));
+    }
+    let image_pages = ((defined_end - defined_start + PAGE - 1) / PAGE) as usize;
+    let image_start = p4_base(KERNEL_BYTES_P4);
+
+    // image 整体放在连续物理帧，bss 已经清零
+    let image = alloc_zeroed(image_pages)?;
+    let image_phys = image.start_address().as_u64();
+    let phys_of = |virt: u64| image_phys + (virt - defined_start);
+
+    let mut tls_template = TlsTemplate::default();
+    for ph in loads() {
+        let offset = ph.offset() as usize;
+        let src = elf.get(offset..offset + ph.file_size() as usize).ok_or(KError::new(ENOEXEC))?;
+        unsafe { memcopy::copy(phys_of(ph.virtual_addr()) as *mut u8, src.as_ptr(), src.len()) };
+    }
+
+    for ph in kernel_elf.program_iter() {
+        match ph.get_type() {
+            Ok(ShType::Dynamic) => {
+                let Ok(SegmentData::Dynamic64(data)) = ph.get_data(&kernel_elf) else {
+                    return Err(KError::new(ENOEXEC));
+                };
+                let (mut rela, mut rela_size) = (None, None);
+                for entry in data {
+                    match entry.get_tag() {
+                        Ok(dynamic::Tag::Rela) => rela = entry.get_ptr().ok(),
+                        Ok(dynamic::Tag::RelaSize) => rela_size = entry.get_val().ok(),
+                        _ => {}
+                    }
+                }
+                let (Some(rela), Some(rela_size)) = (rela, rela_size) else { continue };
+
+                // rela 表在 LOAD 段里，直接读已经复制好的 image
+                let count = rela_size as usize / size_of::<Rela<u64>>();
+                let entries = unsafe { core::slice::from_raw_parts(phys_of(rela) as *const Rela<u64>, count) };
+                for entry in entries {
+                    if entry.get_type() != R_X86_64_RELATIVE || entry.get_symbol_table_index() != 0 {
+                        return Err(KError::new(ENOEXEC));
+                    }
+                    let target = entry.get_offset();
+                    if target < defined_start || target + 8 > defined_end {
+                        return Err(KError::new(ENOEXEC));
+                    }
+                    let value = image_start + (entry.get_addend() - defined_start);
+                    unsafe { ptr::write_unaligned(phys_of(target) as *mut u64, value) };
+                }
+            }
+            Ok(ShType::Tls) => {
+                tls_template = TlsTemplate {
+                    start_virt_addr: image_start + (ph.virtual_addr() - defined_start),
+                    mem_size: ph.mem_size() as usize,
+                    file_size: ph.file_size() as usize,
+                    align: ph.align() as usize,
+                };
+            }
+            _ => {}
+        }
+    }
+
+    // pml4 + image p3/p2/p1s + arg p3/p2/p1
+    let image_p1s = (image_pages + 511) / 512;
+    let arg_pages = (size_of::<KernelArg>() + PAGE_SIZE - 1) / PAGE_SIZE;
+    let table_pages = 1 + 2 + image_p1s + 3;
+    let tables = alloc_zeroed(table_pages)?;
+    let arg_frames = alloc_zeroed(arg_pages)?;
+    let stub = frame_alloc().ok_or(KError::new(ENOMEM))?;
+
+    let current = kernel_arg();
+    let mut arg = *current;
+    arg.kernel_virt_space_offset = i128::from(image_start) - i128::from(defined_start);
+    arg.kernel_pml4_start_addr = tables.start_address().as_u64();
+    arg.tls_template = tls_template;
+    // local apic is disabled before the jump, next kernel enables it again
+    arg.acpi.local_apic_base &= !(APIC_BASE_ENABLE | APIC_BASE_X2APIC) as usize;
+    for new in [
+        region(image, image_pages, MemoryRegionKind::KernelImage),
+        region(tables, table_pages, MemoryRegionKind::PageTables),
+        region(arg_frames, arg_pages, MemoryRegionKind::KernelArg),
+        region(stub, 1, MemoryRegionKind::Bootloader),
+    ] {
+        if arg.unav_phys_mem_regions_len == arg.unav_phys_mem_regions.len() {
+            return Err(KError::new(E2BIG));
+        }
+        arg.unav_phys_mem_regions[arg.unav_phys_mem_regions_len] = new;
+        arg.unav_phys_mem_regions_len += 1;
+    }
+    // sealed in place, the copy does not keep padding bytes
+    unsafe {
+        let dst = arg_frames.start_address().as_u64() as *mut KernelArg;
+        ptr::write(dst, arg);
+        (*dst).seal();
+    }
+
+    unsafe {
+        let table_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        let frame = |i: usize| tables + i as u64;
+        let pml4 = table(frame(0));
+        *pml4 = (*(get_kernel_pml4_page_table_addr() as *const PageTable)).clone();
+        // runtime regions belong to this kernel, the next one maps its own
+        for index in KERNEL_RUNTIME_P4 {
+            pml4[index as usize].set_unused();
+        }
+
+        // image: pml4[KERNEL_BYTES_P4] -> frame(1) -> frame(2) -> frame(3..)
+        pml4[KERNEL_BYTES_P4 as usize].set_frame(frame(1), table_flags);
+        table(frame(1))[0].set_frame(frame(2), table_flags);
+        for i in 0..image_p1s {
+            table(frame(2))[i].set_frame(frame(3 + i), table_flags);
+        }
+        for page in 0..image_pages {
+            let virt = defined_start + page as u64 * PAGE;
+            let mut flags = PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE;
+            // 一个页可能被两个段共享，取并集
+            for ph in loads().filter(|ph| ph.virtual_addr() < virt + PAGE && virt < ph.virtual_addr() + ph.mem_size()) {
+                if ph.flags().is_execute() { flags.remove(PageTableFlags::NO_EXECUTE); }
+                if ph.flags().is_write() { flags |= PageTableFlags::WRITABLE; }
+            }
+            table(frame(3 + page / 512))[page % 512].set_frame(image + page as u64, flags);
+        }
+
+        // arg: pml4[KERNEL_ARG_P4] -> p3 -> p2 -> p1
+        let arg_p3 = 3 + image_p1s;
+        pml4[KERNEL_ARG_P4 as usize].set_frame(frame(arg_p3), table_flags);
+        table(frame(arg_p3))[0].set_frame(frame(arg_p3 + 1), table_flags);
+        table(frame(arg_p3 + 1))[0].set_frame(frame(arg_p3 + 2), table_flags);
+        for page in 0..arg_pages {
+            table(frame(arg_p3 + 2))[page].set_frame(arg_frames + page as u64, table_flags | PageTableFlags::NO_EXECUTE);
+        }
+
+        let stub_start = &kexec_stub_start as *const u8;
+        let stub_len = &kexec_stub_end as *const u8 as usize - stub_start as usize;
+        ptr::copy_nonoverlapping(stub_start, stub.start_address().as_u64() as *mut u8, stub_len);
+    }
+
+    let entry = image_start + (kernel_elf.header.pt2.entry_point() - defined_start);
+    infohart!(
+        "kexec: loaded {} KiB image at phys {:#x}, entry {:#x}",
+        image_pages * PAGE_SIZE / 1024, image_phys, entry
+    );
+    Ok(KexecImage {
+        pml4: tables,
+        entry,
+        arg: p4_base(KERNEL_ARG_P4),
+        stack_top: current.stack_top_addr,
+        stub,
+    })
+}
+
+impl KexecImage {
+    /// jump into the loaded kernel, other cpus must be parked and interrupts disabled
+    pub(super) unsafe fn execute(self) -> ! {
+        // 新内核会重新 enable，x2apic 只能先 disable 才能回到 xapic
+        Msr::IA32_APIC_BASE.update(|base| base & !(APIC_BASE_ENABLE | APIC_BASE_X2APIC));
+
+        let stub: extern "sysv64" fn(u64, u64, u64, u64) -> ! =
+            core::mem::transmute(self.stub.start_address().as_u64());
+        stub(self.pml4.start_address().as_u64(), self.stack_top, self.entry, self.arg)
+    }
+}
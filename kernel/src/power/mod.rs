@@ -0,0 +1,162 @@
+use core::arch::asm;
+use core::hint::spin_loop;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use x86_64::instructions::interrupts;
+use x86_64::instructions::tables::lidt;
+use x86_64::structures::DescriptorTablePointer;
+use x86_64::{PhysAddr, VirtAddr};
+use libvdso::error::{EINVAL, KError};
+use shared::arg::AcpiResetRegister;
+use crate::arch_spec::port::{release_region, request_region};
+use crate::device::tsc::monotonic_ns;
+use crate::initcall::kernel_arg;
+use crate::mem::kvm::kvm_map_mmio;
+use crate::ipi::{ipi, IpiKind, IpiTarget};
+use crate::{infohart, warnhart, CPU_COUNT};
+
+pub mod hibernate;
+pub mod kexec;
+
+/**
+ *  reboot without leaving the machine.
+ *
+ *  the rebooting cpu parks every other cpu with a halt ipi, then either resets
+ *  the machine (acpi reset register, 8042 pulse, triple fault, in this order) or
+ *  jumps into a freshly loaded kernel image, see [`kexec`]. hibernation resets
+ *  after streaming userspace out for the next boot, see [`hibernate`]. parked cpus sit in
+ *  `cli; hlt` until the next kernel wakes them with init-sipi.
+ */
+
+// parked cpus wait 100 ms at most, a stuck cpu must not block the reboot
+const PARK_TIMEOUT_NS: u64 = 100_000_000;
+// give the acpi reset register time to take effect before falling back
+const RESET_SETTLE_NS: u64 = 50_000_000;
+
+const KBD_CONTROLLER_STATUS: u16 = 0x64;
+const KBD_CONTROLLER_PULSE_RESET: u8 = 0xfe;
+
+const PCI_CONFIG_ADDRESS: u16 = 0xcf8;
+
+static PARKED: AtomicU32 = AtomicU32::new(0);
+static REBOOTING: AtomicBool = AtomicBool::new(false);
+
+pub enum RebootMode {
+    Reset,
+    Kexec(kexec::KexecImage),
+    Hibernate,
+}
+
+/// stop every cpu and reboot, only the first caller proceeds, the others park
+pub fn reboot(mode: RebootMode) -> ! {
+    if REBOOTING.swap(true, Ordering::SeqCst) {
+        park_cpu();
+    }
+    infohart!("reboot: {}", match mode {
+        RebootMode::Reset => "machine reset",
+        RebootMode::Kexec(_) => "kexec",
+        RebootMode::Hibernate => "hibernate",
+    });
+    // flusher thread will never run again
+    crate::logger::panic_flush_log();
+
+    interrupts::disable();
+    park_other_cpus();
+
+    match mode {
+        RebootMode::Reset => reset(),
+        RebootMode::Kexec(image) => unsafe { image.execute() },
+        RebootMode::Hibernate => {
+            hibernate::write_image();
+            reset()
+        }
+    }
+}
+
+/// called by halt ipi on every cpu but the rebooting one
+pub fn park_cpu() -> ! {
+    interrupts::disable();
+    PARKED.fetch_add(1, Ordering::SeqCst);
+    loop {
+        unsafe { asm!("cli; hlt", options(nomem, nostack)) };
+    }
+}
+
+/// send halt ipi to every other cpu and wait until they parked, bounded by a timeout
+pub fn park_other_cpus() {
+    let others = CPU_COUNT.load(Ordering::SeqCst).saturating_sub(1);
+    if others == 0 {
+        return;
+    }
+    ipi(IpiKind::Halt, IpiTarget::Other);
+
+    let deadline = monotonic_ns() + PARK_TIMEOUT_NS;
+    while PARKED.load(Ordering::SeqCst) < others {
+        if monotonic_ns() >= deadline {
+            warnhart!("reboot: only {} of {} cpus parked", PARKED.load(Ordering::SeqCst), others);
+            return;
+        }
+        spin_loop();
+    }
+}
+
+fn settle(ns: u64) {
+    let deadline = monotonic_ns() + ns;
+    while monotonic_ns() < deadline {
+        spin_loop();
+    }
+}
+
+fn reset() -> ! {
+    let reg = kernel_arg().acpi.reset_register;
+    if reg.address_space != AcpiResetRegister::NONE {
+        unsafe { acpi_reset(&reg) };
+        settle(RESET_SETTLE_NS);
+        crate::logger::panic_flush_log();
+        warnhart!("reboot: acpi reset register had no effect");
+    }
+
+    // 8042 键盘控制器 pulse reset 线
+    if let Ok(region) = request_region(KBD_CONTROLLER_STATUS, 1, "8042 reset") {
+        unsafe { region.port::<u8>(0).write(KBD_CONTROLLER_PULSE_RESET) };
+        settle(RESET_SETTLE_NS);
+        release_region(region);
+    }
+
+    // 最后 triple fault
+    crate::logger::panic_flush_log();
+    unsafe {
+        lidt(&DescriptorTablePointer { limit: 0, base: VirtAddr::new(0) });
+        asm!("int3", options(noreturn));
+    }
+}
+
+unsafe fn acpi_reset(reg: &AcpiResetRegister) {
+    match reg.address_space {
+        AcpiResetRegister::SYSTEM_IO => {
+            if let Ok(region) = request_region(reg.address as u16, 1, "acpi reset") {
+                region.port::<u8>(0).write(reg.value);
+                release_region(region);
+            }
+        }
+        // may be above the identity mapped ram, map it uncached like any other mmio
+        AcpiResetRegister::SYSTEM_MEMORY => {
+            match PhysAddr::try_new(reg.address).map_err(|_| KError::new(EINVAL)).and_then(|phys| kvm_map_mmio(phys, 1)) {
+                Ok(virt) => ptr::write_volatile(virt.as_mut_ptr::<u8>(), reg.value),
+                Err(err) => warnhart!("reboot: failed to map acpi reset register {:#x}: {:?}", reg.address, err),
+            }
+        }
+        // bus 0, address = device << 32 | function << 16 | offset
+        AcpiResetRegister::PCI_CONFIG => {
+            if let Ok(region) = request_region(PCI_CONFIG_ADDRESS, 8, "acpi reset") {
+                let device = (reg.address >> 32) as u32 & 0x1f;
+                let function = (reg.address >> 16) as u32 & 0x7;
+                let offset = reg.address as u32 & 0xff;
+                region.port::<u32>(0).write(1 << 31 | device << 11 | function << 8 | offset & 0xfc);
+                region.port::<u8>(4 + (offset & 3) as u16).write(reg.value);
+                release_region(region);
+            }
+        }
+        space => warnhart!("reboot: unknown acpi reset register address space {}", space),
+    }
+}
@@ -0,0 +1,266 @@
+use alloc::vec::Vec;
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use alloc::sync::Arc;
+use x86_64::structures::paging::{PageTableFlags, PhysFrame};
+use libvdso::error::{ENOSYS, KError};
+use libvdso::syscall_number::SYS_TSC_KHZ;
+use crate::arch::{ArchInterrupts, CurrentArch};
+use crate::context::context_id;
+use crate::context::list::{context_storage, context_storage_mut};
+use crate::context::spawn::{SpawnEntry, SpawnOptions};
+use crate::context::status::Status;
+use crate::context::switch::switch_context;
+use crate::cpu::LogicalCpuId;
+use crate::device::qemu::{exit_qemu, QemuExitCode};
+use crate::device::tsc::{current_tsc_hz, monotonic_ns, pit_tsc_hz};
+use crate::interrupt::irq_count;
+use crate::ipi::{ipi_single, IpiKind};
+use crate::mem::frame_allocator::{allocated_frame_count, frame_dealloc, frame_ref, frame_refcount, try_frame_alloc, try_frame_alloc_n};
+use crate::mem::kvm::kvm_translate;
+use crate::mem::vmalloc::{vfree, vmalloc, vmap, vunmap};
+use crate::mem::PAGE_SIZE;
+use crate::qemu_println;
+use crate::CPU_COUNT;
+
+/**
+ *  boot time self tests, built with feature `selftest`.
+ *
+ *  run on bsp right after the late initcalls, before bootstrap is spawned, so
+ *  only the kernel itself is exercised. every check prints a line to the debug
+ *  console and qemu exits with the overall result, regressions show up without
+ *  any userspace.
+ */
+
+type CheckResult = Result<(), &'static str>;
+
+const CHECKS: &[(&str, fn() -> CheckResult)] = &[
+    ("frame alloc/dealloc", check_frame_alloc),
+    ("kernel map/unmap", check_kernel_mapping),
+    ("context spawn/switch/exit", check_context_loop),
+    ("syscall roundtrip", check_syscall),
+    ("ipi ping", check_ipi_ping),
+    ("timer accuracy", check_timer),
+];
+
+// deadline of every check waiting on another context or cpu
+const WAIT_NS: u64 = 1_000_000_000;
+const CONTEXT_LOOP_COUNT: usize = 8;
+// tsc frequency measured against pit must be within 1/TIMER_TOLERANCE of the calibrated one
+const TIMER_TOLERANCE: u64 = 50;
+
+pub fn run() -> ! {
+    qemu_println!("selftest: running {} checks", CHECKS.len());
+    let mut failed = 0;
+    for (name, check) in CHECKS {
+        match check() {
+            Ok(()) => qemu_println!("selftest: {:<28} [ok]", name),
+            Err(reason) => {
+                failed += 1;
+                qemu_println!("selftest: {:<28} [failed] {}", name, reason)
+            }
+        }
+    }
+
+    if failed == 0 {
+        qemu_println!("selftest: all {} checks passed", CHECKS.len());
+        exit_qemu(QemuExitCode::Success)
+    } else {
+        qemu_println!("selftest: {} of {} checks failed", failed, CHECKS.len());
+        exit_qemu(QemuExitCode::Failed)
+    }
+}
+
+// spin until `cond` holds or the deadline passes
+fn wait_for(mut cond: impl FnMut() -> bool) -> bool {
+    let deadline = monotonic_ns() + WAIT_NS;
+    while !cond() {
+        if monotonic_ns() > deadline {
+            return false;
+        }
+        spin_loop()
+    }
+    true
+}
+
+fn check_frame_alloc() -> CheckResult {
+    let before = allocated_frame_count();
+    let single = try_frame_alloc().map_err(|_| "single frame allocation failed")?;
+    let run = try_frame_alloc_n(4).map_err(|_| "contiguous allocation failed")?;
+    if allocated_frame_count() < before + 5 {
+        return Err("allocated frame count did not grow");
+    }
+    let run_end = run.start_address() + 4 * PAGE_SIZE as u64;
+    if (run.start_address()..run_end).contains(&single.start_address()) {
+        return Err("frame handed out twice");
+    }
+
+    // physical memory is identity mapped
+    let frames = [single, run, run + 3];
+    for (i, frame) in frames.iter().enumerate() {
+        unsafe { (frame.start_address().as_u64() as *mut u64).write_volatile(0x5e1f_7e57_0000 + i as u64) };
+    }
+    for (i, frame) in frames.iter().enumerate() {
+        if unsafe { (frame.start_address().as_u64() as *const u64).read_volatile() } != 0x5e1f_7e57_0000 + i as u64 {
+            return Err("frame content corrupted");
+        }
+    }
+
+    // a shared frame stays allocated until its last reference is dropped
+    frame_ref(single);
+    frame_dealloc(single);
+    if frame_refcount(single) != 1 {
+        return Err("shared frame freed early");
+    }
+    frame_dealloc(single);
+    if frame_refcount(single) != 0 {
+        return Err("frame not freed with its last reference");
+    }
+    PhysFrame::range(run, run + 4).for_each(frame_dealloc);
+    Ok(())
+}
+
+fn check_kernel_mapping() -> CheckResult {
+    let len = 3 * PAGE_SIZE;
+    let start = vmalloc(len).map_err(|_| "vmalloc failed")?;
+    let bytes = unsafe { core::slice::from_raw_parts_mut(start.as_mut_ptr::<u8>(), len) };
+    if bytes.iter().any(|b| *b != 0) {
+        vfree(start);
+        return Err("vmalloc memory is not zeroed");
+    }
+    bytes.iter_mut().enumerate().for_each(|(i, b)| *b = i as u8);
+    let intact = bytes.iter().enumerate().all(|(i, b)| *b == i as u8);
+    vfree(start);
+    if !intact {
+        return Err("vmalloc memory corrupted");
+    }
+    if kvm_translate(start).is_some() {
+        return Err("vfree left the area mapped");
+    }
+
+    // an alias of a frame sees writes through the identity mapping
+    let frame = try_frame_alloc().map_err(|_| "frame allocation failed")?;
+    let alias = match vmap(&[frame], PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE) {
+        Ok(alias) => alias,
+        Err(_) => {
+            frame_dealloc(frame);
+            return Err("vmap failed");
+        }
+    };
+    let result = (|| {
+        if kvm_translate(alias) != Some(frame.start_address()) {
+            return Err("vmap translates to a wrong frame");
+        }
+        unsafe { (frame.start_address().as_u64() as *mut u64).write_volatile(0xa11a5) };
+        if unsafe { alias.as_ptr::<u64>().read_volatile() } != 0xa11a5 {
+            return Err("vmap alias does not see the frame");
+        }
+        Ok(())
+    })();
+    vunmap(alias);
+    frame_dealloc(frame);
+    if result.is_ok() && kvm_translate(alias).is_some() {
+        return Err("vunmap left the page mapped");
+    }
+    result
+}
+
+fn check_context_loop() -> CheckResult {
+    let counter = Arc::new(AtomicUsize::new(0));
+    let mut ids = Vec::new();
+    {
+        let mut storage = context_storage_mut();
+        for _ in 0..CONTEXT_LOOP_COUNT {
+            let counter = Arc::clone(&counter);
+            let lock = storage
+                .spawn(&SpawnOptions::kernel("selftest"), SpawnEntry::closure(move || {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                }))
+                .map_err(|_| "spawn failed")?;
+            let mut context = lock.write();
+            context.set_status(Status::Runnable);
+            ids.push(context.id);
+        }
+    }
+
+    // we are the idle context of bsp, every switch comes back here once the others exited
+    let done = wait_for(|| {
+        unsafe {
+            CurrentArch::disable_interrupts();
+            switch_context();
+            CurrentArch::enable_interrupts();
+        }
+        counter.load(Ordering::SeqCst) == CONTEXT_LOOP_COUNT
+    });
+    let all_exited = wait_for(|| {
+        let storage = context_storage();
+        ids.iter().all(|id| storage.get(*id).map_or(true, |lock| {
+            let context = lock.read();
+            matches!(context.status, Status::Existed(_)) && !context.running
+        }))
+    });
+
+    if done && all_exited {
+        let mut storage = context_storage_mut();
+        ids.iter().for_each(|id| { storage.reap(context_id(), *id); });
+    }
+    match (done, all_exited) {
+        (false, _) => Err("spawned contexts did not all run"),
+        (true, false) => Err("spawned contexts did not exit"),
+        (true, true) => Ok(()),
+    }
+}
+
+fn check_syscall() -> CheckResult {
+    let khz = crate::syscall::syscall(SYS_TSC_KHZ, 0, 0, 0, 0, 0);
+    if KError::demux(KError::mux(khz)) != Ok((current_tsc_hz() / 1000) as usize) {
+        return Err("tsc khz does not match");
+    }
+    let unknown = crate::syscall::syscall(usize::MAX, 0, 0, 0, 0, 0);
+    if KError::demux(KError::mux(unknown)) != Err(KError::new(ENOSYS)) {
+        return Err("unknown syscall is not ENOSYS");
+    }
+    Ok(())
+}
+
+fn check_ipi_ping() -> CheckResult {
+    let cpus = CPU_COUNT.load(Ordering::SeqCst);
+    if cpus < 2 {
+        qemu_println!("selftest: single cpu, skipping ipi ping");
+        return Ok(());
+    }
+    for cpu in 1..cpus {
+        let before = irq_count(IpiKind::Pit as usize);
+        ipi_single(IpiKind::Pit, LogicalCpuId(cpu));
+        if !wait_for(|| irq_count(IpiKind::Pit as usize) > before) {
+            return Err("an ap did not answer");
+        }
+    }
+    Ok(())
+}
+
+fn check_timer() -> CheckResult {
+    let calibrated = current_tsc_hz();
+    if calibrated == 0 {
+        return Err("tsc is not calibrated");
+    }
+    let measured = pit_tsc_hz();
+    if measured.abs_diff(calibrated) > calibrated / TIMER_TOLERANCE {
+        qemu_println!("selftest: tsc {} hz, pit measured {} hz", calibrated, measured);
+        return Err("tsc frequency disagrees with pit");
+    }
+
+    let start = monotonic_ns();
+    let mut last = start;
+    for _ in 0..1000 {
+        let now = monotonic_ns();
+        if now < last {
+            return Err("monotonic clock went backwards");
+        }
+        last = now;
+    }
+    if start == 0 {
+        return Err("monotonic clock is not running");
+    }
+    Ok(())
+}
@@ -0,0 +1,235 @@
+use alloc::string::String;
+use core::fmt;
+use core::str::FromStr;
+use log::LevelFilter;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{PageTable, PageTableFlags};
+use x86_64::VirtAddr;
+use crate::acpi::local_apic::{set_tick_hz, tick_hz, MAX_TICK_HZ, MIN_TICK_HZ};
+use crate::arch::{ArchInterrupts, CurrentArch};
+use crate::context::list::{context_storage, context_storage_mut};
+use crate::context::spawn::{SpawnEntry, SpawnOptions};
+use crate::context::status::Status;
+use crate::context::switch::switch_context;
+use crate::context::trace;
+use crate::device::keyboard::{has_key, pop_key, set_input_reader};
+use crate::fs::procfs::{gen_kvm, gen_meminfo, gen_ps, gen_uptime};
+use crate::initcall;
+use crate::initcall::InitCpuArg;
+use crate::logger::console_write;
+use crate::logger::filter::{for_each_filter, global_level, set_global_level, set_target_level};
+use crate::power::{reboot, RebootMode};
+
+/**
+ *  in-kernel debug shell, built with feature `shell`.
+ *
+ *  a kernel context reads lines from the keyboard queue, com1 input included,
+ *  and writes to the framebuffer console, com1 when headless. editing is limited to backspace, enter runs the line.
+ *  it works before any userspace exists, commands only read kernel state
+ *  except for `reboot`.
+ */
+
+const SHELL_BLOCK_REASON: &str = "shell input";
+const PROMPT: &str = "kshell> ";
+const LINE_MAX: usize = 128;
+
+macro_rules! out {
+    ($($arg:tt)*) => (console_write(format_args!($($arg)*)));
+}
+
+const COMMANDS: &[(&str, &str, fn(&str))] = &[
+    ("help", "list commands", cmd_help),
+    ("mem", "physical, vmalloc and kvm usage", cmd_mem),
+    ("ps", "list contexts", cmd_ps),
+    ("dumppt", "<addr> walk page table of current cr3 for addr", cmd_dumppt),
+    ("ticks", "pit ticks, tsc, tick rates and uptime", cmd_ticks),
+    ("tick", "[<hz>] show or set the system tick rate", cmd_tick),
+    ("trace", "dump scheduler event trace to serial", cmd_trace),
+    ("loglevel", "[<target>] [<level>|reset] show or set log levels", cmd_loglevel),
+    ("reboot", "reset the machine", cmd_reboot),
+];
+
+unsafe fn shell_initcall(_: &InitCpuArg) {
+    match context_storage_mut().spawn(&SpawnOptions::kernel("kshell"), SpawnEntry::Func(shell_main)) {
+        Ok(lock) => {
+            let mut context = lock.write();
+            context.set_status(Status::Runnable);
+            set_input_reader(context.id);
+        }
+        Err(err) => panic!("failed to spawn debug shell: {}", err),
+    }
+}
+initcall!(late, Bsp, shell_initcall, order = 210);
+
+extern "C" fn shell_main() {
+    // new contexts start with interrupts disabled
+    unsafe { CurrentArch::enable_interrupts(); }
+
+    out!("\ndebug shell, `help` lists commands\n");
+    let mut line = String::new();
+    loop {
+        out!("{}", PROMPT);
+        read_line(&mut line);
+        run(line.trim());
+        line.clear();
+    }
+}
+
+fn read_line(line: &mut String) {
+    loop {
+        let c = wait_key();
+        match c {
+            '\n' | '\r' => {
+                out!("\n");
+                return;
+            }
+            // backspace, some layouts send delete
+            '\x08' | '\x7f' => {
+                if line.pop().is_some() {
+                    out!("\x08");
+                }
+            }
+            c if !c.is_control() && line.len() + c.len_utf8() <= LINE_MAX => {
+                line.push(c);
+                out!("{}", c);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn wait_key() -> char {
+    loop {
+        if let Some(c) = pop_key() {
+            return c;
+        }
+        {
+            let contexts = context_storage();
+            let mut context = contexts.current()
+                .expect("failed to get debug shell context")
+                .write();
+            // block before checking, a key pushed after the check finds us blocked and wakes us
+            context.soft_block(SHELL_BLOCK_REASON);
+            if has_key() {
+                context.unblock_no_ipi();
+            }
+        }
+        unsafe {
+            CurrentArch::disable_interrupts();
+            switch_context();
+            CurrentArch::enable_interrupts();
+        }
+    }
+}
+
+fn run(line: &str) {
+    if line.is_empty() {
+        return;
+    }
+    let (name, args) = line.split_once(' ').unwrap_or((line, ""));
+    match COMMANDS.iter().find(|(cmd, _, _)| *cmd == name) {
+        Some((_, _, func)) => func(args.trim()),
+        None => out!("unknown command `{}`, try `help`\n", name),
+    }
+}
+
+fn print_generated(gen: fn(&mut String) -> fmt::Result) {
+    let mut text = String::new();
+    let _ = gen(&mut text);
+    out!("{}", text);
+}
+
+fn cmd_help(_: &str) {
+    for (name, help, _) in COMMANDS {
+        out!("{:<8} {}\n", name, help);
+    }
+}
+
+fn cmd_mem(_: &str) {
+    print_generated(gen_meminfo);
+    print_generated(gen_kvm);
+}
+
+fn cmd_ps(_: &str) {
+    print_generated(gen_ps);
+}
+
+fn cmd_ticks(_: &str) {
+    print_generated(gen_uptime);
+}
+
+fn cmd_tick(args: &str) {
+    if args.is_empty() {
+        out!("{} hz\n", tick_hz());
+        return;
+    }
+    match args.parse::<u32>().ok().map(set_tick_hz) {
+        Some(Ok(prev)) => out!("was {} hz\n", prev),
+        _ => out!("usage: tick <hz>, {} to {}\n", MIN_TICK_HZ, MAX_TICK_HZ),
+    }
+}
+
+fn cmd_trace(_: &str) {
+    let events = trace::dump();
+    out!("{} events written to serial\n", events);
+}
+
+fn cmd_loglevel(args: &str) {
+    let mut words = args.split_ascii_whitespace();
+    let result = match (words.next(), words.next()) {
+        (None, _) => {
+            out!("global {}\n", global_level());
+            for_each_filter(|target, level| out!("{} {}\n", target, level));
+            return;
+        }
+        (Some(level), None) => match LevelFilter::from_str(level) {
+            Ok(level) => Ok(set_global_level(level)),
+            Err(_) => {
+                out!("unknown level `{}`\n", level);
+                return;
+            }
+        },
+        (Some(target), Some("reset")) => set_target_level(target, None),
+        (Some(target), Some(level)) => match LevelFilter::from_str(level) {
+            Ok(level) => set_target_level(target, Some(level)),
+            Err(_) => {
+                out!("unknown level `{}`\n", level);
+                return;
+            }
+        },
+    };
+    match result {
+        Ok(prev) => out!("was {}\n", prev),
+        Err(err) => out!("failed: {:?}\n", err),
+    }
+}
+
+fn cmd_reboot(_: &str) {
+    reboot(RebootMode::Reset)
+}
+
+fn cmd_dumppt(args: &str) {
+    let digits = args.strip_prefix("0x").unwrap_or(args);
+    let Some(addr) = u64::from_str_radix(digits, 16).ok().and_then(|addr| VirtAddr::try_new(addr).ok()) else {
+        out!("usage: dumppt <hex canonical address>\n");
+        return;
+    };
+
+    let indexes = [addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()];
+    // physical memory is identity mapped
+    let mut table = Cr3::read().0.start_address().as_u64();
+    for (level, index) in indexes.into_iter().enumerate() {
+        let entry = &unsafe { &*(table as *const PageTable) }[index];
+        let flags = entry.flags();
+        out!("p{}[{:>3}] {:#018x} {:?}\n", 4 - level, u16::from(index), entry.addr().as_u64(), flags);
+        if !flags.contains(PageTableFlags::PRESENT) {
+            out!("not mapped\n");
+            return;
+        }
+        // 1 GiB or 2 MiB page, nothing below
+        if level > 0 && flags.contains(PageTableFlags::HUGE_PAGE) {
+            break;
+        }
+        table = entry.addr().as_u64();
+    }
+}
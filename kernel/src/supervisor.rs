@@ -0,0 +1,169 @@
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::slice;
+use log::{info, warn};
+use shared::arg::{BootModule, RestartPolicy};
+use shared::print_panic::PrintPanic;
+use crate::arch::{ArchContextRegs, ArchInterrupts, CurrentArch};
+use crate::context::{context_id, ContextId};
+use crate::context::list::{context_storage, context_storage_mut};
+use crate::context::sleep::sleep_until;
+use crate::context::spawn::{exit_current, SpawnEntry, SpawnOptions};
+use crate::context::status::Status;
+use crate::context::switch::switch_context;
+use crate::device::tsc::monotonic_ns;
+use crate::initcall;
+use crate::initcall::{kernel_arg, InitCpuArg};
+use crate::mem::load_elf::load_program;
+use crate::power::hibernate::resumed;
+use crate::sync::IrqSpinlock;
+
+/**
+ *  init supervisor.
+ *
+ *  starts every module the bootloader loaded next to bootstrap (`module=` in
+ *  boot.cfg) as a userspace context with its own address space. the
+ *  supervisor is their parent: it polls them, reaps the exited ones and starts
+ *  them again according to their restart policy. a module exiting more than
+ *  RESTART_LIMIT times in a row is given up.
+ *
+ *  bootstrap itself stays the init context adopting orphans, it is not
+ *  restarted.
+ */
+
+const POLL_INTERVAL_NS: u64 = 100_000_000;
+const RESTART_LIMIT: usize = 5;
+// exit code of a module that could not be loaded
+const LOAD_FAILED_CODE: usize = 127;
+
+// module index of every started module context, read by `module_entry`
+static STARTED: IrqSpinlock<BTreeMap<ContextId, usize>> = IrqSpinlock::new(BTreeMap::new());
+
+struct Slot {
+    index: usize,
+    context: Option<ContextId>,
+    restarts: usize,
+}
+
+fn modules() -> &'static [BootModule] {
+    let arg = kernel_arg();
+    &arg.modules[..arg.modules_len.min(arg.modules.len())]
+}
+
+unsafe fn supervisor_initcall(_: &InitCpuArg) {
+    // a resumed boot runs the contexts of the hibernation image instead
+    if modules().is_empty() || resumed() {
+        return;
+    }
+    match context_storage_mut().spawn(&SpawnOptions::kernel("initsv"), SpawnEntry::Func(supervisor_main)) {
+        Ok(lock) => lock.write().set_status(Status::Runnable),
+        Err(err) => panic!("failed to spawn init supervisor: {}", err),
+    }
+}
+initcall!(late, Bsp, supervisor_initcall, order = 220);
+
+extern "C" fn supervisor_main() {
+    // new contexts start with interrupts disabled
+    unsafe { CurrentArch::enable_interrupts(); }
+
+    let mut slots: Vec<Slot> = (0..modules().len())
+        .map(|index| Slot { index, context: start(index), restarts: 0 })
+        .collect();
+
+    while slots.iter().any(|slot| slot.context.is_some()) {
+        sleep(POLL_INTERVAL_NS);
+        for slot in slots.iter_mut() {
+            let Some(id) = slot.context else { continue };
+            let Some(code) = context_storage_mut().reap(context_id(), id) else { continue };
+            STARTED.lock().remove(&id);
+            slot.context = None;
+
+            let module = &modules()[slot.index];
+            if !should_restart(module.restart, code) {
+                info!("module {} exited with code {}", module.name(), code);
+                continue;
+            }
+            if slot.restarts == RESTART_LIMIT {
+                warn!("module {} exited with code {}, giving up after {} restarts", module.name(), code, RESTART_LIMIT);
+                continue;
+            }
+            slot.restarts += 1;
+            warn!("module {} exited with code {}, restarting ({}/{})", module.name(), code, slot.restarts, RESTART_LIMIT);
+            slot.context = start(slot.index);
+        }
+    }
+    info!("every module exited, init supervisor stops");
+}
+
+fn should_restart(policy: RestartPolicy, code: usize) -> bool {
+    match policy {
+        RestartPolicy::Never => false,
+        RestartPolicy::OnCrash => code != 0,
+        RestartPolicy::Always => true,
+    }
+}
+
+fn sleep(ns: u64) {
+    {
+        let contexts = context_storage();
+        let mut context = contexts.current()
+            .expect("failed to get init supervisor context")
+            .write();
+        sleep_until(&mut context, monotonic_ns() + ns);
+    }
+    unsafe {
+        CurrentArch::disable_interrupts();
+        switch_context();
+        CurrentArch::enable_interrupts();
+    }
+}
+
+// spawn a context for module `index`, it loads the module when it first runs
+fn start(index: usize) -> Option<ContextId> {
+    let module = &modules()[index];
+    let mut storage = context_storage_mut();
+    match storage.spawn(&SpawnOptions::userspace(module.name()), SpawnEntry::Func(module_entry)) {
+        Ok(lock) => {
+            let mut context = lock.write();
+            STARTED.lock().insert(context.id, index);
+            context.set_status(Status::Runnable);
+            Some(context.id)
+        }
+        Err(err) => {
+            warn!("failed to start module {}: {}", module.name(), err);
+            None
+        }
+    }
+}
+
+extern "C" fn module_entry() {
+    let index = *STARTED.lock().get(&context_id())
+        .or_panic("module context was not started by init supervisor");
+    let module = &modules()[index];
+    let addrsp = {
+        let contexts = context_storage();
+        let context = contexts.current()
+            .or_panic("failed to get module context")
+            .read();
+        context.addrsp.clone().or_panic("failed to get address space of module context")
+    };
+
+    // physical memory is identity mapped in every address space
+    let image = unsafe { slice::from_raw_parts(module.base as *const u8, module.len) };
+    let (entry, stack_pointer) = match unsafe { load_program(module.name(), image, &addrsp) } {
+        Ok(loaded) => loaded,
+        Err(err) => {
+            warn!("failed to load module {}: {:?}", module.name(), err);
+            exit_current(LOAD_FAILED_CODE)
+        }
+    };
+
+    let contexts = context_storage();
+    let mut context = contexts.current()
+        .or_panic("module was not running inside any context")
+        .write();
+    let regs = context.regs_mut().or_panic("module needs registers to be available");
+    regs.init();
+    regs.set_stack_pointer(stack_pointer as usize);
+    regs.set_instr_pointer(entry.as_u64() as usize);
+}
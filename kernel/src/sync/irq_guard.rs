@@ -0,0 +1,158 @@
+use core::arch::global_asm;
+use core::panic::PanicInfo;
+use core::ptr::addr_of_mut;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::acpi::local_apic::LOCAL_APIC;
+use crate::arch::{ArchInterrupts, CurrentArch};
+use crate::arch_spec::msr::Msr;
+use crate::cpu::PercpuBlock;
+use crate::device::pic;
+use crate::errorhart;
+
+/**
+ *  panic recovery for irq handlers.
+ *
+ *  the kernel is built with `panic = "abort"`, nothing unwinds. handlers of
+ *  `interrupt!` run their body through [`guarded`], which records a recovery
+ *  point (callee saved registers and stack pointer, like setjmp) in the percpu
+ *  block. a panic while it is the innermost frame is offered to [`recover`]
+ *  by the panic handler: if the handler left nothing half done that others
+ *  would trip over, the panic is recorded into the log ring, the interrupt is
+ *  acked and the cpu jumps back to the recovery point, the handler returns as
+ *  if it finished and the interrupted code goes on.
+ *
+ *  everything else escalates to the fatal path:
+ *  - a lock was held (preempt count differs from handler entry, the locks of
+ *    `sync` count into it, the context list and contexts included), resuming
+ *    would deadlock the next taker
+ *  - the handler interrupted another handler, the isr bit to ack is not known
+ *  - the recovery itself panicked, or too many handlers panicked already
+ *
+ *  exception handlers are not guarded, their panics are deliberate.
+ */
+
+// recovered panics before the kernel gives up on its irq handlers
+const MAX_RECOVERED: usize = 16;
+
+static RECOVERED: AtomicUsize = AtomicUsize::new(0);
+
+struct GuardFrame {
+    // stack pointer of the recovery point, written by `irq_guard_call`
+    resume_rsp: usize,
+    prev: usize,
+    name: &'static str,
+    // percpu state at handler entry
+    irq_depth: usize,
+    preempt_count: usize,
+    recovering: bool,
+}
+
+extern "sysv64" {
+    // calls `f(data)` and returns 0, or 1 when resumed through `irq_guard_resume`
+    fn irq_guard_call(f: unsafe extern "sysv64" fn(*mut u8), data: *mut u8, resume_rsp: *mut usize) -> u64;
+    fn irq_guard_resume(resume_rsp: usize) -> !;
+}
+
+// callee saved registers and alignment padding are kept on the stack of
+// `irq_guard_call`, the recovery point is the stack pointer below them
+global_asm!(
+    ".global irq_guard_call",
+    "irq_guard_call:",
+    "    push rbp",
+    "    push rbx",
+    "    push r12",
+    "    push r13",
+    "    push r14",
+    "    push r15",
+    "    sub rsp, 8",
+    "    mov [rdx], rsp",
+    "    mov rax, rdi",
+    "    mov rdi, rsi",
+    "    call rax",
+    "    xor eax, eax",
+    "2:",
+    "    add rsp, 8",
+    "    pop r15",
+    "    pop r14",
+    "    pop r13",
+    "    pop r12",
+    "    pop rbx",
+    "    pop rbp",
+    "    ret",
+    "",
+    ".global irq_guard_resume",
+    "irq_guard_resume:",
+    "    mov rsp, rdi",
+    "    mov eax, 1",
+    "    jmp 2b",
+);
+
+unsafe extern "sysv64" fn call_once<F: FnOnce()>(data: *mut u8) {
+    // taken out first, a recovered panic must not drop it again
+    if let Some(f) = (*(data as *mut Option<F>)).take() {
+        f()
+    }
+}
+
+/// run the body of irq handler `name`, a panic inside may be recovered and return here
+#[inline(always)]
+pub fn guarded<F: FnOnce()>(name: &'static str, f: F) {
+    let percpu = PercpuBlock::current();
+    let mut frame = GuardFrame {
+        resume_rsp: 0,
+        prev: percpu.irq_guard.get(),
+        name,
+        irq_depth: percpu.irq_depth.get(),
+        preempt_count: percpu.preempt_count.get(),
+        recovering: false,
+    };
+    let mut f = Some(f);
+    // only touched through the pointer from here on, `recover` writes it too
+    let frame = &mut frame as *mut GuardFrame;
+    percpu.irq_guard.set(frame as usize);
+    unsafe {
+        irq_guard_call(call_once::<F>, &mut f as *mut Option<F> as *mut u8, addr_of_mut!((*frame).resume_rsp));
+        percpu.irq_guard.set((*frame).prev);
+    }
+}
+
+/// called by the panic handler, returns only if the panic has to escalate
+pub fn recover(info: &PanicInfo) {
+    // percpu block is not reachable before gdt is initialized
+    if unsafe { Msr::IA32_GS_BASE.read() } == 0 {
+        return;
+    }
+    let percpu = PercpuBlock::current();
+    let Some(frame) = (unsafe { (percpu.irq_guard.get() as *mut GuardFrame).as_mut() }) else { return };
+    let reason = if frame.recovering {
+        "panicked again while recovering"
+    } else if percpu.preempt_count.get() != frame.preempt_count {
+        "locks held"
+    } else if frame.irq_depth != 0 {
+        "nested in another handler"
+    } else if RECOVERED.fetch_add(1, Ordering::SeqCst) >= MAX_RECOVERED {
+        "too many panics in irq handlers"
+    } else {
+        ""
+    };
+    if !reason.is_empty() {
+        errorhart!("panic in irq handler {} is fatal: {}", frame.name, reason);
+        return;
+    }
+    frame.recovering = true;
+
+    errorhart!("recovered panic in irq handler {}: {:?}", frame.name, info);
+    unsafe {
+        CurrentArch::disable_interrupts();
+        // outermost handler, a bit still in service is its own
+        if pic::routed() {
+            pic::eoi_in_service();
+        }
+        if LOCAL_APIC.highest_in_service().is_some() {
+            LOCAL_APIC.eoi();
+        }
+        percpu.irq_depth.set(frame.irq_depth);
+        percpu.irq_guard.set(frame.prev);
+        irq_guard_resume(frame.resume_rsp);
+    }
+}
@@ -0,0 +1,106 @@
+use core::ops::{Deref, DerefMut};
+use spin::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use crate::arch::{ArchInterrupts, CurrentArch};
+use super::PreemptGuard;
+
+/// reader-writer spinlock for data shared with interrupt handlers.
+///
+/// like [`IrqSpinlock`](super::IrqSpinlock), interrupts are disabled on current cpu
+/// while a guard of either kind is alive and restored when it is dropped.
+pub struct IrqRwLock<T: ?Sized> {
+    inner: RwLock<T>,
+}
+
+pub struct IrqRwLockReadGuard<'a, T: ?Sized + 'a> {
+    guard: Option<RwLockReadGuard<'a, T>>,
+    // counted like spinlocks, a panicking irq handler must not be resumed with it held
+    preempt: Option<PreemptGuard>,
+    irq_was_enabled: bool,
+}
+
+pub struct IrqRwLockWriteGuard<'a, T: ?Sized + 'a> {
+    guard: Option<RwLockWriteGuard<'a, T>>,
+    // counted like spinlocks, a panicking irq handler must not be resumed with it held
+    preempt: Option<PreemptGuard>,
+    irq_was_enabled: bool,
+}
+
+impl<T> IrqRwLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self { inner: RwLock::new(value) }
+    }
+}
+
+impl<T: ?Sized> IrqRwLock<T> {
+    pub fn read(&self) -> IrqRwLockReadGuard<'_, T> {
+        let irq_was_enabled = CurrentArch::interrupts_enabled();
+        unsafe { CurrentArch::disable_interrupts(); }
+
+        IrqRwLockReadGuard { guard: Some(self.inner.read()), preempt: Some(PreemptGuard::new()), irq_was_enabled }
+    }
+
+    pub fn write(&self) -> IrqRwLockWriteGuard<'_, T> {
+        let irq_was_enabled = CurrentArch::interrupts_enabled();
+        unsafe { CurrentArch::disable_interrupts(); }
+
+        IrqRwLockWriteGuard { guard: Some(self.inner.write()), preempt: Some(PreemptGuard::new()), irq_was_enabled }
+    }
+
+    pub fn try_write(&self) -> Option<IrqRwLockWriteGuard<'_, T>> {
+        let irq_was_enabled = CurrentArch::interrupts_enabled();
+        unsafe { CurrentArch::disable_interrupts(); }
+
+        match self.inner.try_write() {
+            Some(guard) => Some(IrqRwLockWriteGuard { guard: Some(guard), preempt: Some(PreemptGuard::new()), irq_was_enabled }),
+            None => {
+                if irq_was_enabled {
+                    unsafe { CurrentArch::enable_interrupts(); }
+                }
+                None
+            }
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for IrqRwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl<T: ?Sized> Drop for IrqRwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        // unlock before interrupts come back
+        drop(self.guard.take());
+        drop(self.preempt.take());
+        if self.irq_was_enabled {
+            unsafe { CurrentArch::enable_interrupts(); }
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for IrqRwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl<T: ?Sized> DerefMut for IrqRwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+impl<T: ?Sized> Drop for IrqRwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        drop(self.guard.take());
+        drop(self.preempt.take());
+        if self.irq_was_enabled {
+            unsafe { CurrentArch::enable_interrupts(); }
+        }
+    }
+}
@@ -0,0 +1,80 @@
+use core::ops::{Deref, DerefMut};
+use spin::{Mutex, MutexGuard};
+use crate::arch::{ArchInterrupts, CurrentArch};
+use super::PreemptGuard;
+
+/// spinlock for data shared with interrupt handlers.
+///
+/// interrupts are disabled on current cpu while the lock is held, so a handler can
+/// never spin on a lock owned by the code it interrupted.
+/// previous interrupt state is restored when the guard is dropped.
+pub struct IrqSpinlock<T: ?Sized> {
+    inner: Mutex<T>,
+}
+
+pub struct IrqSpinlockGuard<'a, T: ?Sized + 'a> {
+    guard: Option<MutexGuard<'a, T>>,
+    // counted like spinlocks, a panicking irq handler must not be resumed with it held
+    preempt: Option<PreemptGuard>,
+    irq_was_enabled: bool,
+}
+
+impl<T> IrqSpinlock<T> {
+    pub const fn new(value: T) -> Self {
+        Self { inner: Mutex::new(value) }
+    }
+}
+
+impl<T: ?Sized> IrqSpinlock<T> {
+    pub fn lock(&self) -> IrqSpinlockGuard<'_, T> {
+        let irq_was_enabled = CurrentArch::interrupts_enabled();
+        unsafe { CurrentArch::disable_interrupts(); }
+
+        IrqSpinlockGuard { guard: Some(self.inner.lock()), preempt: Some(PreemptGuard::new()), irq_was_enabled }
+    }
+
+    pub fn try_lock(&self) -> Option<IrqSpinlockGuard<'_, T>> {
+        let irq_was_enabled = CurrentArch::interrupts_enabled();
+        unsafe { CurrentArch::disable_interrupts(); }
+
+        match self.inner.try_lock() {
+            Some(guard) => Some(IrqSpinlockGuard { guard: Some(guard), preempt: Some(PreemptGuard::new()), irq_was_enabled }),
+            None => {
+                if irq_was_enabled {
+                    unsafe { CurrentArch::enable_interrupts(); }
+                }
+                None
+            }
+        }
+    }
+
+    /// release the lock regardless of owner, only for panic paths
+    pub unsafe fn force_unlock(&self) {
+        self.inner.force_unlock()
+    }
+}
+
+impl<T: ?Sized> Deref for IrqSpinlockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl<T: ?Sized> DerefMut for IrqSpinlockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+impl<T: ?Sized> Drop for IrqSpinlockGuard<'_, T> {
+    fn drop(&mut self) {
+        // unlock before interrupts come back
+        drop(self.guard.take());
+        drop(self.preempt.take());
+        if self.irq_was_enabled {
+            unsafe { CurrentArch::enable_interrupts(); }
+        }
+    }
+}
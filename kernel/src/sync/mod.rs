@@ -0,0 +1,96 @@
+use core::mem;
+use crate::arch_spec::msr::Msr;
+use crate::cpu::PercpuBlock;
+
+pub mod irq_guard;
+pub mod irq_rwlock;
+pub mod irq_spinlock;
+pub mod rw_spinlock;
+pub mod spinlock;
+
+pub use irq_rwlock::{IrqRwLock, IrqRwLockReadGuard, IrqRwLockWriteGuard};
+pub use irq_spinlock::{IrqSpinlock, IrqSpinlockGuard};
+pub use rw_spinlock::{ArcRwSpinlockWriteGuard, RwSpinlock, RwSpinlockReadGuard, RwSpinlockWriteGuard};
+pub use spinlock::{Spinlock, SpinlockGuard};
+
+/// whether current cpu is running an interrupt or exception handler
+pub fn in_irq() -> bool {
+    // percpu block is not reachable before gdt is initialized
+    if unsafe { Msr::IA32_GS_BASE.read() } == 0 {
+        return false;
+    }
+    PercpuBlock::current().irq_depth.get() > 0
+}
+
+/// marks current cpu as inside an interrupt handler while alive,
+/// created by `interrupt!` family of macros.
+pub struct IrqContextGuard(());
+
+impl IrqContextGuard {
+    #[inline(always)]
+    pub fn enter() -> Self {
+        let depth = &PercpuBlock::current().irq_depth;
+        depth.set(depth.get() + 1);
+        Self(())
+    }
+
+    /// leave interrupt context without dropping the guard, for exception
+    /// handlers that switch away from a dying context and never return
+    pub unsafe fn abandon() {
+        let depth = &PercpuBlock::current().irq_depth;
+        depth.set(depth.get() - 1);
+    }
+}
+
+impl Drop for IrqContextGuard {
+    #[inline(always)]
+    fn drop(&mut self) {
+        let depth = &PercpuBlock::current().irq_depth;
+        depth.set(depth.get() - 1);
+    }
+}
+
+/// whether the code running on current cpu may be switched away on interrupt exit
+pub fn preemptible() -> bool {
+    let percpu = PercpuBlock::current();
+    percpu.irq_depth.get() == 0 && percpu.preempt_count.get() == 0
+}
+
+/// forbids kernel preemption on current cpu while alive, nests
+pub struct PreemptGuard {
+    // percpu block is not reachable before gdt is initialized, nothing to count then
+    counted: bool,
+}
+
+impl PreemptGuard {
+    #[inline(always)]
+    pub fn new() -> Self {
+        let counted = unsafe { Msr::IA32_GS_BASE.read() } != 0;
+        if counted {
+            let count = &PercpuBlock::current().preempt_count;
+            count.set(count.get() + 1);
+        }
+        Self { counted }
+    }
+}
+
+impl Drop for PreemptGuard {
+    #[inline(always)]
+    fn drop(&mut self) {
+        if self.counted {
+            let count = &PercpuBlock::current().preempt_count;
+            count.set(count.get() - 1);
+        }
+    }
+}
+
+/// [`PreemptGuard`] for locks without a guard of their own, pairs with [`preempt_enable`] on the same cpu
+#[inline(always)]
+pub fn preempt_disable() {
+    mem::forget(PreemptGuard::new());
+}
+
+#[inline(always)]
+pub unsafe fn preempt_enable() {
+    drop(PreemptGuard { counted: unsafe { Msr::IA32_GS_BASE.read() } != 0 });
+}
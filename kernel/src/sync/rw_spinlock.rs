@@ -0,0 +1,94 @@
+use lock_api::{ArcRwLockWriteGuard, RawRwLock, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use spinning_top::RawRwSpinlock;
+use crate::sync::{preempt_disable, preempt_enable};
+
+/**
+ *  reader-writer spinlock for the context list and the contexts.
+ *
+ *  a `spinning_top` rwlock whose holders count into the preempt count of
+ *  their cpu like [`Spinlock`](super::Spinlock): the holder is not preempted,
+ *  and a panicking irq handler that holds one is not resumed by
+ *  [`recover`](super::irq_guard::recover), the next taker would spin forever.
+ *
+ *  guards of a context switch are released by the next context on the same
+ *  cpu, so the count still pairs up there.
+ */
+
+pub struct RawPreemptRwSpinlock(RawRwSpinlock);
+
+unsafe impl RawRwLock for RawPreemptRwSpinlock {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const INIT: Self = Self(RawRwSpinlock::INIT);
+
+    type GuardMarker = <RawRwSpinlock as RawRwLock>::GuardMarker;
+
+    fn lock_shared(&self) {
+        preempt_disable();
+        self.0.lock_shared()
+    }
+
+    fn try_lock_shared(&self) -> bool {
+        preempt_disable();
+        let locked = self.0.try_lock_shared();
+        if !locked {
+            unsafe { preempt_enable() }
+        }
+        locked
+    }
+
+    unsafe fn unlock_shared(&self) {
+        self.0.unlock_shared();
+        preempt_enable()
+    }
+
+    fn lock_exclusive(&self) {
+        preempt_disable();
+        self.0.lock_exclusive()
+    }
+
+    fn try_lock_exclusive(&self) -> bool {
+        preempt_disable();
+        let locked = self.0.try_lock_exclusive();
+        if !locked {
+            unsafe { preempt_enable() }
+        }
+        locked
+    }
+
+    unsafe fn unlock_exclusive(&self) {
+        self.0.unlock_exclusive();
+        preempt_enable()
+    }
+
+    fn is_locked(&self) -> bool {
+        self.0.is_locked()
+    }
+
+    fn is_locked_exclusive(&self) -> bool {
+        self.0.is_locked_exclusive()
+    }
+}
+
+pub type RwSpinlock<T> = RwLock<RawPreemptRwSpinlock, T>;
+pub type RwSpinlockReadGuard<'a, T> = RwLockReadGuard<'a, RawPreemptRwSpinlock, T>;
+pub type RwSpinlockWriteGuard<'a, T> = RwLockWriteGuard<'a, RawPreemptRwSpinlock, T>;
+pub type ArcRwSpinlockWriteGuard<T> = ArcRwLockWriteGuard<RawPreemptRwSpinlock, T>;
+
+#[test_case]
+pub(crate) fn test_rw_spinlock_preempt_count() {
+    use crate::cpu::PercpuBlock;
+
+    let count = || PercpuBlock::current().preempt_count.get();
+    let before = count();
+    let lock = RwSpinlock::new(0);
+    {
+        let _first = lock.read();
+        let _second = lock.read();
+        assert_eq!(count(), before + 2);
+        // a failed try does not leave the count raised
+        assert!(lock.try_write().is_none());
+        assert_eq!(count(), before + 2);
+    }
+    *lock.write() += 1;
+    assert_eq!(count(), before);
+}
@@ -0,0 +1,62 @@
+use core::ops::{Deref, DerefMut};
+use spin::{Mutex, MutexGuard};
+use crate::sync::{in_irq, PreemptGuard};
+
+/// plain spinlock for data never touched by interrupt handlers.
+///
+/// taking it inside a handler is a bug: the handler may spin forever on a lock held
+/// by the code it interrupted. debug builds assert against it, use
+/// [`IrqSpinlock`](super::IrqSpinlock) for data shared with handlers.
+///
+/// the holder is not preempted, a context switched in on the same cpu could spin on it.
+pub struct Spinlock<T: ?Sized> {
+    inner: Mutex<T>,
+}
+
+pub struct SpinlockGuard<'a, T: ?Sized + 'a> {
+    guard: Option<MutexGuard<'a, T>>,
+    _preempt: PreemptGuard,
+}
+
+impl<T> Spinlock<T> {
+    pub const fn new(value: T) -> Self {
+        Self { inner: Mutex::new(value) }
+    }
+}
+
+impl<T: ?Sized> Spinlock<T> {
+    #[track_caller]
+    pub fn lock(&self) -> SpinlockGuard<'_, T> {
+        debug_assert!(!in_irq(), "irq-unsafe spinlock taken in interrupt context");
+        let preempt = PreemptGuard::new();
+        SpinlockGuard { guard: Some(self.inner.lock()), _preempt: preempt }
+    }
+
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<SpinlockGuard<'_, T>> {
+        debug_assert!(!in_irq(), "irq-unsafe spinlock taken in interrupt context");
+        let preempt = PreemptGuard::new();
+        self.inner.try_lock().map(|guard| SpinlockGuard { guard: Some(guard), _preempt: preempt })
+    }
+}
+
+impl<T: ?Sized> Deref for SpinlockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl<T: ?Sized> DerefMut for SpinlockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+impl<T: ?Sized> Drop for SpinlockGuard<'_, T> {
+    fn drop(&mut self) {
+        // unlock before preemption comes back
+        drop(self.guard.take());
+    }
+}
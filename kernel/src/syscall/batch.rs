@@ -0,0 +1,73 @@
+use alloc::vec;
+use core::mem::size_of;
+use core::slice;
+use libvdso::batch::{SyscallDesc, BATCH_MAX};
+use libvdso::error::{ECANCELED, EINVAL, KError, KResult};
+use libvdso::flag::BATCH_STOP_ON_ERROR;
+use libvdso::syscall_number::SYS_SYSCALL_BATCH;
+use crate::mem::user_ptr::UserSlice;
+
+/**
+ *  several syscalls on one kernel entry.
+ *
+ *  the descriptors are copied in once, run through the same dispatch as
+ *  single syscalls in order, and copied back once with every result filled
+ *  in, so a batch costs two user copies instead of an entry and exit per
+ *  call. a call can not see the results of the calls before it.
+ */
+
+pub fn sys_syscall_batch(descs: usize, count: usize, flags: usize) -> KResult<usize> {
+    if count > BATCH_MAX || flags & !BATCH_STOP_ON_ERROR != 0 {
+        return Err(KError::new(EINVAL));
+    }
+    let user = UserSlice::rw(descs, count * size_of::<SyscallDesc>())?;
+    let mut batch = vec![SyscallDesc::default(); count];
+    // descriptors are plain words, any bytes make a valid one
+    let bytes = unsafe { slice::from_raw_parts_mut(batch.as_mut_ptr() as *mut u8, user.len()) };
+    user.copy_to_kernel(bytes)?;
+
+    let ran = run(&mut batch, flags);
+
+    let bytes = unsafe { slice::from_raw_parts(batch.as_ptr() as *const u8, user.len()) };
+    user.copy_from_kernel(bytes)?;
+    Ok(ran)
+}
+
+// run `batch` in order, returns the number of calls run
+fn run(batch: &mut [SyscallDesc], flags: usize) -> usize {
+    let mut ran = 0;
+    for desc in batch.iter_mut() {
+        let [b, c, d, e, f] = desc.args;
+        let result = match desc.number {
+            SYS_SYSCALL_BATCH => Err(KError::new(EINVAL)),
+            number => super::syscall(number, b, c, d, e, f),
+        };
+        let failed = result.is_err();
+        desc.result = KError::mux(result);
+        ran += 1;
+        if failed && flags & BATCH_STOP_ON_ERROR != 0 {
+            break;
+        }
+    }
+    batch[ran..].iter_mut().for_each(|desc| desc.result = KError::mux(Err(KError::new(ECANCELED))));
+    ran
+}
+
+#[test_case]
+pub(crate) fn test_syscall_batch() {
+    use libvdso::syscall_number::{SYS_GETPID, SYS_TSC_KHZ};
+
+    let mut batch = [
+        SyscallDesc::new(SYS_TSC_KHZ, [0; 5]),
+        SyscallDesc::new(SYS_SYSCALL_BATCH, [0; 5]),
+        SyscallDesc::new(SYS_GETPID, [0; 5]),
+    ];
+    assert_eq!(run(&mut batch, 0), 3);
+    assert!(batch[0].result().is_ok());
+    assert_eq!(batch[1].result as isize, -(EINVAL as isize));
+    assert_eq!(batch[2].result, super::syscall(SYS_GETPID, 0, 0, 0, 0, 0).unwrap());
+
+    // the nested batch fails, the call after it is skipped
+    assert_eq!(run(&mut batch, BATCH_STOP_ON_ERROR), 2);
+    assert_eq!(batch[2].result as isize, -(ECANCELED as isize));
+}
@@ -0,0 +1,27 @@
+use libvdso::error::{EFAULT, EINVAL, KError, KResult};
+use libvdso::flag::{CAP_ADMIN, WATCHPOINT_CLEAR, WATCHPOINT_SET, WATCH_EXEC, WATCH_READ_WRITE, WATCH_WRITE};
+use shared::layout::USER_SPACE_END;
+use crate::arch_spec::debug::{clear_watchpoint, set_watchpoint, Watch};
+use crate::context::cred::require_cap;
+
+/// set or clear a hardware watchpoint for userspace debuggers, needs CAP_ADMIN:
+/// watchpoints are global and hit in every address space
+pub fn sys_watchpoint(op: usize, b: usize, c: usize, d: usize) -> KResult<usize> {
+    require_cap(CAP_ADMIN)?;
+    match op {
+        WATCHPOINT_SET => {
+            let kind = match d {
+                WATCH_EXEC => Watch::Exec,
+                WATCH_WRITE => Watch::Write,
+                WATCH_READ_WRITE => Watch::ReadWrite,
+                _ => return Err(KError::new(EINVAL)),
+            };
+            if b.checked_add(c).map_or(true, |end| end as u64 > USER_SPACE_END) {
+                return Err(KError::new(EFAULT));
+            }
+            set_watchpoint(b, c, kind)
+        }
+        WATCHPOINT_CLEAR => clear_watchpoint(b),
+        _ => Err(KError::new(EINVAL)),
+    }
+}
@@ -0,0 +1,61 @@
+use libvdso::error::{EBADF, EINVAL, ENAMETOOLONG, KError, KResult};
+use crate::fs::{aio, file_table};
+use crate::mem::user_buffer::UserBuffer;
+use crate::mem::user_ptr::UserSlice;
+use crate::qemu_print;
+
+// longest path `open` takes
+const PATH_MAX: usize = 256;
+
+/// open the file at absolute `path`, `flags` must be 0
+pub fn sys_open(path: usize, len: usize, flags: usize) -> KResult<usize> {
+    if flags != 0 {
+        return Err(KError::new(EINVAL));
+    }
+    if len > PATH_MAX {
+        return Err(KError::new(ENAMETOOLONG));
+    }
+    let bytes = UserSlice::ro(path, len)?.read_to_vec()?;
+    let path = core::str::from_utf8(&bytes).map_err(|_| KError::new(EINVAL))?;
+    file_table::open(path)
+}
+
+pub fn sys_read(fd: usize, buf: usize, len: usize) -> KResult<usize> {
+    let file = file_table::get(fd)?;
+    if !file.readable() {
+        return Err(KError::new(EBADF));
+    }
+    file.read(UserBuffer::new(buf as u64, len))
+}
+
+pub fn sys_close(fd: usize) -> KResult<usize> {
+    file_table::close(fd).map(|_| 0)
+}
+
+// stdout and stderr go to debug console, other descriptors to their files
+pub fn sys_write(fd: usize, buf: usize, len: usize) -> KResult<usize> {
+    if fd != 1 && fd != 2 {
+        let file = file_table::get(fd)?;
+        if !file.writable() {
+            return Err(KError::new(EBADF));
+        }
+        return file.write(UserBuffer::new(buf as u64, len));
+    }
+
+    let bytes = UserSlice::ro(buf, len)?.read_to_vec()?;
+    match core::str::from_utf8(&bytes) {
+        Ok(s) => qemu_print!("{}", s),
+        Err(_) => qemu_print!("{:?}", bytes),
+    }
+    Ok(bytes.len())
+}
+
+/// map an async io ring into the calling context, returns its address
+pub fn sys_aio_setup() -> KResult<usize> {
+    aio::setup()
+}
+
+/// submit the queued async io requests, wait for `min_complete` completions
+pub fn sys_aio_enter(min_complete: usize) -> KResult<usize> {
+    aio::enter(min_complete)
+}
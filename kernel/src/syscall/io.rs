@@ -0,0 +1,137 @@
+use alloc::sync::Arc;
+use libvdso::error::{EBUSY, EFAULT, EINVAL, ENODEV, EPERM, ESRCH, KError, KResult};
+use libvdso::framebuffer::FramebufferInfo;
+use libvdso::flag::CAP_IO;
+use x86_64::{PhysAddr, VirtAddr};
+use x86_64::registers::rflags::RFlags;
+use crate::arch_spec::port::claimed_regions;
+use crate::cmdline::cmdline_flag;
+use crate::context::cred::require_cap;
+use crate::context::io::{IoBitmap, IO_PORTS};
+use crate::context::list::context_storage;
+use crate::device::user_irq;
+use crate::framebuffer::framebuffer;
+use crate::infohart;
+use crate::logger::audit::{audit, AuditKind};
+use crate::mem::memmap::is_device_memory;
+use crate::mem::user_ptr::UserPtr;
+use crate::mem::user_addr_space::RwLockUserAddrSpace;
+
+// raw port access is opted in by `userspace_io` in cmdline, then needs CAP_IO
+fn check_io_permitted() -> KResult<()> {
+    if !cmdline_flag("userspace_io") {
+        audit(AuditKind::Cap, format_args!("io denied, userspace_io is off"));
+        return Err(KError::new(EPERM));
+    }
+    require_cap(CAP_IO)
+}
+
+/// allow or deny ports `from..from + num` for the calling context.
+/// ports claimed by a kernel driver can not be granted.
+pub fn sys_ioperm(from: usize, num: usize, turn_on: usize) -> KResult<usize> {
+    check_io_permitted()?;
+    let end = from.checked_add(num).ok_or(KError::new(EINVAL))?;
+    if end > IO_PORTS {
+        return Err(KError::new(EINVAL));
+    }
+    let turn_on = turn_on != 0;
+
+    if turn_on {
+        let requested = from as u32..end as u32;
+        if let Some((_, owner)) = claimed_regions().into_iter()
+            .find(|(ports, _)| ports.start < requested.end && requested.start < ports.end) {
+            infohart!("ioperm: ports {:#x}-{:#x} are owned by {}", from, end - 1, owner);
+            return Err(KError::new(EBUSY));
+        }
+    }
+
+    let contexts = context_storage();
+    let mut context = contexts.current().ok_or(KError::new(ESRCH))?.write();
+    if !context.userspace {
+        return Err(KError::new(EINVAL));
+    }
+
+    let mut bitmap = context.io_bitmap.take().unwrap_or_else(IoBitmap::new);
+    bitmap.set(from as u16, num as u16, turn_on);
+    let allowed = !bitmap.is_empty();
+    context.io_bitmap = allowed.then_some(bitmap);
+    context.ctx_regs.userspace_io_allowed = allowed;
+    // current cpu loaded the old bitmap on switch
+    unsafe {
+        let pcr = crate::gdt::pcr();
+        if let Some(ref bitmap) = context.io_bitmap {
+            pcr.load_io_bitmap(bitmap);
+        }
+        pcr.set_userspace_io_allowed(allowed);
+    }
+    audit(AuditKind::Io, format_args!("ioperm {:#x}..{:#x} {}", from, end, if turn_on { "on" } else { "off" }));
+    Ok(0)
+}
+
+/// set io privilege level of the calling context, 3 allows every port.
+/// takes effect when returning to userspace.
+pub fn sys_iopl(level: usize) -> KResult<usize> {
+    if level > 3 {
+        return Err(KError::new(EINVAL));
+    }
+    check_io_permitted()?;
+
+    let contexts = context_storage();
+    let mut context = contexts.current().ok_or(KError::new(ESRCH))?.write();
+    let regs = context.regs_mut().ok_or(KError::new(EINVAL))?;
+    let rflags = regs.iret.rflags & !(RFlags::IOPL_HIGH | RFlags::IOPL_LOW).bits() as usize;
+    regs.iret.rflags = rflags | level << 12;
+    audit(AuditKind::Io, format_args!("iopl {}", level));
+    Ok(0)
+}
+
+fn current_addrsp() -> KResult<Arc<RwLockUserAddrSpace>> {
+    let contexts = context_storage();
+    let context = contexts.current().ok_or(KError::new(ESRCH))?.read();
+    context.addrsp.clone().ok_or(KError::new(EINVAL))
+}
+
+/// map device memory `phys..phys + len` into the calling context, returns its address.
+/// ram and memory of kernel drivers can not be mapped.
+pub fn sys_map_device(phys: usize, len: usize) -> KResult<usize> {
+    check_io_permitted()?;
+    let phys = PhysAddr::try_new(phys as u64).map_err(|_| KError::new(EINVAL))?;
+    if len == 0 || !is_device_memory(phys.as_u64(), len as u64) {
+        infohart!("map_device: {:#x} + {:#x} is not device memory", phys.as_u64(), len);
+        return Err(KError::new(EPERM));
+    }
+    current_addrsp()?.map_device(phys, len).map(|virt| virt.as_u64() as usize)
+}
+
+pub fn sys_unmap_device(virt: usize, len: usize) -> KResult<usize> {
+    let virt = VirtAddr::try_new(virt as u64).map_err(|_| KError::new(EFAULT))?;
+    current_addrsp()?.unmap_device(virt, len).map(|_| 0)
+}
+
+pub fn sys_irq_register(irq: usize) -> KResult<usize> {
+    check_io_permitted()?;
+    user_irq::register(irq).map(|_| 0)
+}
+
+pub fn sys_irq_wait(irq: usize) -> KResult<usize> {
+    user_irq::wait(irq)
+}
+
+pub fn sys_irq_release(irq: usize) -> KResult<usize> {
+    user_irq::release(irq).map(|_| 0)
+}
+
+pub fn sys_framebuffer_info(info: usize) -> KResult<usize> {
+    let framebuffer = framebuffer().ok_or(KError::new(ENODEV))?;
+    UserPtr::<FramebufferInfo>::rw(info)?.write(FramebufferInfo {
+        len: framebuffer.len,
+        width: framebuffer.width,
+        height: framebuffer.height,
+        stride: framebuffer.stride,
+        bytes_per_pixel: framebuffer.bytes_per_pixel,
+        red_mask: framebuffer.pixel_mask.red,
+        green_mask: framebuffer.pixel_mask.green,
+        blue_mask: framebuffer.pixel_mask.blue,
+    })?;
+    Ok(0)
+}
@@ -0,0 +1,37 @@
+use libvdso::error::{EINVAL, KError, KResult};
+use libvdso::flag::{CAP_ADMIN, LOG_LEVEL_GET, LOG_LEVEL_RESET};
+use crate::context::cred::require_cap;
+use crate::logger::audit::take_records;
+use crate::logger::filter::{global_level, parse_level, set_global_level, set_target_level, target_level, FILTER_TARGET_LEN};
+use crate::mem::user_ptr::UserSlice;
+
+/// get or set the log level of module `target`, the global one if it is empty.
+/// returns the level in effect before, setting needs CAP_ADMIN.
+pub fn sys_log_level(target: usize, target_len: usize, level: usize) -> KResult<usize> {
+    if target_len > FILTER_TARGET_LEN {
+        return Err(KError::new(EINVAL));
+    }
+    let bytes = UserSlice::ro(target, target_len)?.read_to_vec()?;
+    let target = core::str::from_utf8(&bytes).map_err(|_| KError::new(EINVAL))?;
+
+    if level == LOG_LEVEL_GET {
+        let level = if target.is_empty() { global_level() } else { target_level(target) };
+        return Ok(level as usize);
+    }
+    require_cap(CAP_ADMIN)?;
+    let prev = match (target.is_empty(), level) {
+        (true, LOG_LEVEL_RESET) => return Err(KError::new(EINVAL)),
+        (true, level) => set_global_level(parse_level(level)?),
+        (false, LOG_LEVEL_RESET) => set_target_level(target, None)?,
+        (false, level) => set_target_level(target, Some(parse_level(level)?))?,
+    };
+    Ok(prev as usize)
+}
+
+/// move the audit records not read yet into `buf`, whole lines only, returns
+/// the copied length. needs CAP_ADMIN, records are consumed by the read
+pub fn sys_audit_read(buf: usize, len: usize) -> KResult<usize> {
+    require_cap(CAP_ADMIN)?;
+    let buf = UserSlice::rw(buf, len)?;
+    buf.copy_from_kernel(&take_records(buf.len()))
+}
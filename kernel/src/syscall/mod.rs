@@ -4,18 +4,39 @@ use core::mem::offset_of;
 use core::slice::from_raw_parts;
 use log::info;
 use x86_64::{PhysAddr, PrivilegeLevel};
-use x86_64::PrivilegeLevel::Ring3;
 use x86_64::registers::rflags::RFlags;
-use x86_64::registers::segmentation::SegmentSelector;
 use x86_64::structures::paging::{PhysFrame, Size4KiB};
 use x86_64::structures::tss::TaskStateSegment;
-use libvdso::error::{KError, KResult};
-use shared::print_panic::PrintPanic;
-use crate::arch_spec::msr::{rdmsr, wrmsr};
-use crate::gdt::{GDT_USER_CODE64, GDT_USER_DATA, pcr, ProcessorControlRegion};
-use crate::{infohart, push_scratch, push_preserved, pop_scratch, pop_preserved, qemu_println};
+use libvdso::error::{ENOSYS, KError, KResult};
+use libvdso::syscall_number::{
+    SYS_AIO_ENTER, SYS_AIO_SETUP, SYS_AUDIT_READ, SYS_CAPDROP, SYS_CLOSE, SYS_EXIT, SYS_FRAMEBUFFER_INFO, SYS_GETGID,
+    SYS_GETPID, SYS_GETPPID, SYS_GETRLIMIT, SYS_GETUID, SYS_IOPERM, SYS_IOPL, SYS_IRQ_REGISTER, SYS_IRQ_RELEASE,
+    SYS_IRQ_WAIT, SYS_LOG_LEVEL, SYS_MAP_DEVICE, SYS_NANOSLEEP, SYS_OPEN, SYS_PROFILE, SYS_READ, SYS_REBOOT, SYS_SETGID,
+    SYS_SETRLIMIT, SYS_SETUID, SYS_SET_NAME, SYS_SYSCALL_BATCH, SYS_SYSINFO, SYS_THREAD_SPAWN, SYS_TSC_KHZ, SYS_UNAME,
+    SYS_UNMAP_DEVICE, SYS_WATCHPOINT, SYS_WRITE,
+};
+use shared::gdt::{STAR_SYSCALL_BASE, STAR_SYSRET_BASE, USER_CODE_SELECTOR, USER_DATA_SELECTOR};
+use crate::arch::ArchContextRegs;
+use crate::arch_spec::frame_check::{debug_check_entry, frame_issue};
+use crate::arch_spec::msr::Msr;
+use crate::arch_spec::tls;
+use crate::gdt::{pcr, ProcessorControlRegion};
+use crate::{infohart, loghart, push_scratch, push_preserved, pop_scratch, pop_preserved, qemu_println};
 use crate::cpu::PercpuBlock;
 use crate::mem::PAGE_SIZE;
+use crate::initcall;
+use crate::initcall::InitCpuArg;
+
+pub mod batch;
+pub mod debug;
+pub mod fs;
+pub mod io;
+pub mod klog;
+pub mod power;
+pub mod process;
+pub mod profile;
+pub mod sysinfo;
+pub mod time;
 
 #[derive(Default)]
 #[repr(C)]
@@ -115,50 +136,77 @@ impl Debug for IretRegisters {
     }
 }
 
-impl InterruptStack {
-    pub fn init(&mut self) {
-        // Always enable interrupts!
-        self.iret.rflags = RFlags::INTERRUPT_FLAG.bits() as usize;
-        self.iret.cs = GDT_USER_CODE64.get().or_panic("failed to get user code segment sector").0 as usize;
-        self.iret.ss = GDT_USER_DATA.get().or_panic("failed to get user data segment sector").0 as usize;
-    }
-    pub fn set_stack_pointer(&mut self, rsp: usize) {
-        self.iret.rsp = rsp;
-    }
-    pub fn stack_pointer(&self) -> usize {
-        self.iret.rsp
-    }
-    pub fn set_instr_pointer(&mut self, rip: usize) {
-        self.iret.rip = rip;
-    }
-    // TODO: This can maybe be done in userspace?
-    pub fn set_syscall_ret_reg(&mut self, ret: usize) -> usize {
-        self.scratch.rax = ret;
-        ret
-    }
-}
-
 #[no_mangle]
 pub unsafe extern "C" fn __inner_syscall_instruction(stack: *mut InterruptStack) {
     let stack_ref = &mut *stack;
+    tls::enter_from_user();
+
+    // cs and ss are pushed by the entry asm, only rsp comes from userspace
+    debug_check_entry(&stack_ref.iret);
+    if let Some(issue) = frame_issue(&stack_ref.iret) {
+        loghart!(::log::Level::Debug, "syscall {:#x} from rip {:#x}: {}", stack_ref.scratch.rax, stack_ref.iret.rip, issue);
+    }
 
-    let args = [
-        &stack_ref.scratch.rax,
-        &stack_ref.scratch.rdi,
-        &stack_ref.scratch.rsi,
-        &stack_ref.scratch.rdx,
-        &stack_ref.scratch.r10,
-        &stack_ref.scratch.r8
-    ];
+    let args = stack_ref.syscall_args();
+
+    #[cfg(feature = "bench")]
+    let start = crate::bench::timestamp();
 
     PercpuBlock::current().inside_syscall.set(true);
 
-    infohart!("syscall: args = {:?}", stack_ref);
-    let result = Ok(0);
+    let result = syscall(args[0], args[1], args[2], args[3], args[4], args[5]);
 
     PercpuBlock::current().inside_syscall.set(false);
 
-    stack_ref.set_syscall_ret_reg(KError::mux(result));
+    #[cfg(feature = "bench")]
+    crate::bench::record(crate::bench::BenchKind::Syscall, start);
+
+    stack_ref.set_syscall_ret(KError::mux(result));
+}
+
+pub(crate) fn syscall(a: usize, b: usize, c: usize, d: usize, e: usize, f: usize) -> KResult<usize> {
+    match a {
+        SYS_OPEN => fs::sys_open(b, c, d),
+        SYS_READ => fs::sys_read(b, c, d),
+        SYS_WRITE => fs::sys_write(b, c, d),
+        SYS_CLOSE => fs::sys_close(b),
+        SYS_TSC_KHZ => time::sys_tsc_khz(),
+        SYS_NANOSLEEP => time::sys_nanosleep(b, c),
+        SYS_SET_NAME => process::sys_set_name(b, c),
+        SYS_GETPID => process::sys_getpid(),
+        SYS_GETPPID => process::sys_getppid(),
+        SYS_GETUID => process::sys_getuid(),
+        SYS_GETGID => process::sys_getgid(),
+        SYS_SETUID => process::sys_setuid(b),
+        SYS_SETGID => process::sys_setgid(b),
+        SYS_CAPDROP => process::sys_capdrop(b),
+        SYS_GETRLIMIT => process::sys_getrlimit(b, c),
+        SYS_SETRLIMIT => process::sys_setrlimit(b, c),
+        SYS_IOPERM => io::sys_ioperm(b, c, d),
+        SYS_IOPL => io::sys_iopl(b),
+        SYS_MAP_DEVICE => io::sys_map_device(b, c),
+        SYS_UNMAP_DEVICE => io::sys_unmap_device(b, c),
+        SYS_IRQ_REGISTER => io::sys_irq_register(b),
+        SYS_IRQ_WAIT => io::sys_irq_wait(b),
+        SYS_IRQ_RELEASE => io::sys_irq_release(b),
+        SYS_REBOOT => power::sys_reboot(b, c, d),
+        SYS_LOG_LEVEL => klog::sys_log_level(b, c, d),
+        SYS_FRAMEBUFFER_INFO => io::sys_framebuffer_info(b),
+        SYS_PROFILE => profile::sys_profile(b, c, d),
+        SYS_UNAME => sysinfo::sys_uname(b),
+        SYS_SYSINFO => sysinfo::sys_sysinfo(b),
+        SYS_THREAD_SPAWN => process::sys_thread_spawn(b, c, d, e),
+        SYS_EXIT => process::sys_exit(b),
+        SYS_WATCHPOINT => debug::sys_watchpoint(b, c, d, e),
+        SYS_AUDIT_READ => klog::sys_audit_read(b, c),
+        SYS_SYSCALL_BATCH => batch::sys_syscall_batch(b, c, d),
+        SYS_AIO_SETUP => fs::sys_aio_setup(),
+        SYS_AIO_ENTER => fs::sys_aio_enter(b),
+        _ => {
+            infohart!("unknown syscall {:#x}: {:#x} {:#x} {:#x} {:#x} {:#x}", a, b, c, d, e, f);
+            Err(KError::new(ENOSYS))
+        }
+    }
 }
 
 #[naked]
@@ -187,6 +235,9 @@ pub unsafe extern "C" fn syscall_instruction() {
     .globl enter_usermode
         enter_usermode:
         ",
+        // Load user FSBASE, every register is popped from the stack below.
+        "call {exit_to_user};",
+
         // Pop context registers
         pop_preserved!(),
         pop_scratch!(),
@@ -234,8 +285,9 @@ pub unsafe extern "C" fn syscall_instruction() {
 
         sp = const(offset_of!(ProcessorControlRegion, user_rsp_tmp)),
         ksp = const(offset_of!(ProcessorControlRegion, tss) + offset_of!(TaskStateSegment, privilege_stack_table)),
-        cs_sel = const(SegmentSelector::new(5, Ring3).0),
-        ss_sel = const(SegmentSelector::new(4, Ring3).0),
+        cs_sel = const(USER_CODE_SELECTOR),
+        ss_sel = const(USER_DATA_SELECTOR),
+        exit_to_user = sym tls::exit_to_user,
 
         options(noreturn),
     );
@@ -245,13 +297,16 @@ extern "C" {
     pub fn enter_usermode();
 }
 
+unsafe fn syscall_initcall(_: &InitCpuArg) {
+    init_syscall();
+}
+initcall!(arch, All, syscall_initcall, order = 30);
+
 pub unsafe fn init_syscall() {
-    let syscall_cs_ss_base = (1u16) << 3;
-    let sysret_cs_ss_base = ((3u16) << 3) | 3;
-    let star_high = u32::from(syscall_cs_ss_base) | (u32::from(sysret_cs_ss_base) << 16);
+    let star_high = u32::from(STAR_SYSCALL_BASE) | (u32::from(STAR_SYSRET_BASE) << 16);
 
-    wrmsr(0xc0000081, u64::from(star_high) << 32); // IA32_STAR
-    wrmsr( 0xc0000082, syscall_instruction as u64); // IA32_LSTAR
+    Msr::IA32_STAR.write(u64::from(star_high) << 32);
+    Msr::IA32_LSTAR.write(syscall_instruction as u64);
 
     let mask_critical = RFlags::DIRECTION_FLAG
         | RFlags::INTERRUPT_FLAG
@@ -263,7 +318,8 @@ pub unsafe fn init_syscall() {
         | RFlags::ZERO_FLAG
         | RFlags::SIGN_FLAG
         | RFlags::OVERFLOW_FLAG;
-    wrmsr(0xc0000084, (mask_critical | mask_other).bits()); // IA32_FMASK
+    Msr::IA32_FMASK.write((mask_critical | mask_other).bits());
 
-    wrmsr(0xc0000080, rdmsr(0xc0000080) | 1); //  IA32_EFER
+    // system call extensions
+    Msr::IA32_EFER.update(|efer| efer | 1);
 }
\ No newline at end of file
@@ -0,0 +1,22 @@
+use libvdso::error::{EINVAL, KError, KResult};
+use libvdso::flag::{CAP_ADMIN, REBOOT_HIBERNATE, REBOOT_KEXEC, REBOOT_RESET};
+use crate::context::cred::require_cap;
+use crate::mem::user_ptr::UserSlice;
+use crate::power::{kexec, reboot, RebootMode};
+
+/// reset the machine, or boot the kernel elf at `image_base` in its place.
+/// hibernating resets too, the call returns 0 once the next boot resumes
+/// the caller. needs CAP_ADMIN.
+pub fn sys_reboot(cmd: usize, image_base: usize, image_len: usize) -> KResult<usize> {
+    require_cap(CAP_ADMIN)?;
+    match cmd {
+        REBOOT_RESET => reboot(RebootMode::Reset),
+        REBOOT_KEXEC => {
+            let elf = UserSlice::ro(image_base, image_len)?.read_to_vec()?;
+            let image = kexec::load(&elf)?;
+            reboot(RebootMode::Kexec(image))
+        }
+        REBOOT_HIBERNATE => reboot(RebootMode::Hibernate),
+        _ => Err(KError::new(EINVAL)),
+    }
+}
@@ -0,0 +1,140 @@
+use alloc::string::String;
+use libvdso::error::{EFAULT, EINVAL, ESRCH, KError, KResult};
+use libvdso::rlimit::RLimit;
+use shared::layout::USER_SPACE_END;
+use shared::print_panic::PrintPanic;
+use crate::arch::ArchContextRegs;
+use crate::context::cred::Credentials;
+use crate::context::list::{context_storage, context_storage_mut};
+use crate::context::spawn::{exit_current, SpawnEntry, SpawnOptions};
+use crate::context::status::Status;
+use crate::context::{context_id, CONTEXT_NAME_LEN};
+use crate::logger::audit::{audit, AuditKind};
+use crate::mem::user_ptr::{UserPtr, UserSlice};
+
+/// set debug name of the calling context, longer names are truncated
+pub fn sys_set_name(buf: usize, len: usize) -> KResult<usize> {
+    let bytes = UserSlice::ro(buf, len.min(CONTEXT_NAME_LEN))?.read_to_vec()?;
+    let name = match core::str::from_utf8(&bytes) {
+        Ok(name) => name,
+        // a multibyte char cut at the truncation point
+        Err(e) if e.error_len().is_none() => unsafe { core::str::from_utf8_unchecked(&bytes[..e.valid_up_to()]) },
+        Err(_) => return Err(KError::new(EINVAL)),
+    };
+
+    let contexts = context_storage();
+    let context = contexts.current().ok_or(KError::new(ESRCH))?;
+    context.write().set_name(name);
+    Ok(0)
+}
+
+/// id of the calling context, a context is both process and thread
+pub fn sys_getpid() -> KResult<usize> {
+    Ok(context_id().get())
+}
+
+/// id of the parent context, 0 for orphans nobody adopted
+pub fn sys_getppid() -> KResult<usize> {
+    let contexts = context_storage();
+    let context = contexts.current().ok_or(KError::new(ESRCH))?;
+    let ppid = context.read().ppid;
+    Ok(ppid.map_or(0, |ppid| ppid.get()))
+}
+
+fn with_cred<T>(f: impl FnOnce(&mut Credentials) -> KResult<T>) -> KResult<T> {
+    let contexts = context_storage();
+    let mut context = contexts.current().ok_or(KError::new(ESRCH))?.write();
+    f(&mut context.cred)
+}
+
+pub fn sys_getuid() -> KResult<usize> {
+    with_cred(|cred| Ok(cred.uid as usize))
+}
+
+pub fn sys_getgid() -> KResult<usize> {
+    with_cred(|cred| Ok(cred.gid as usize))
+}
+
+/// changing to another uid needs CAP_ADMIN, any uid but 0 drops all capabilities
+pub fn sys_setuid(uid: usize) -> KResult<usize> {
+    let uid = u32::try_from(uid).map_err(|_| KError::new(EINVAL))?;
+    with_cred(|cred| {
+        let old = cred.uid;
+        let result = cred.set_uid(uid);
+        audit(AuditKind::Cred, format_args!("setuid {} -> {} {}", old, uid, if result.is_ok() { "granted" } else { "denied" }));
+        result.map(|_| 0)
+    })
+}
+
+pub fn sys_setgid(gid: usize) -> KResult<usize> {
+    let gid = u32::try_from(gid).map_err(|_| KError::new(EINVAL))?;
+    with_cred(|cred| {
+        let old = cred.gid;
+        let result = cred.set_gid(gid);
+        audit(AuditKind::Cred, format_args!("setgid {} -> {} {}", old, gid, if result.is_ok() { "granted" } else { "denied" }));
+        result.map(|_| 0)
+    })
+}
+
+/// clear capability bits `caps` of the calling context, returns the remaining ones
+pub fn sys_capdrop(caps: usize) -> KResult<usize> {
+    with_cred(|cred| {
+        cred.caps &= !(caps as u64);
+        audit(AuditKind::Cred, format_args!("capdrop {:#x}, {:#x} left", caps, cred.caps));
+        Ok(cred.caps as usize)
+    })
+}
+
+pub fn sys_getrlimit(resource: usize, limit: usize) -> KResult<usize> {
+    let value = {
+        let contexts = context_storage();
+        let context = contexts.current().ok_or(KError::new(ESRCH))?.read();
+        context.rlimits.get(resource)?
+    };
+    UserPtr::<RLimit>::rw(limit)?.write(value)?;
+    Ok(0)
+}
+
+pub fn sys_setrlimit(resource: usize, limit: usize) -> KResult<usize> {
+    let limit = UserPtr::<RLimit>::ro(limit)?.read()?;
+    let contexts = context_storage();
+    let mut context = contexts.current().ok_or(KError::new(ESRCH))?.write();
+    let cred = context.cred;
+    context.rlimits.set(resource, limit, &cred)?;
+    Ok(0)
+}
+
+/// end the calling context with `code`, its parent reaps it
+pub fn sys_exit(code: usize) -> ! {
+    exit_current(code)
+}
+
+// a thread drops to ring 3 with the registers `sys_thread_spawn` set up
+extern "C" fn thread_entry() {}
+
+/// start a thread of the calling context in its address space at `entry`,
+/// running on `stack` with `arg` in rdi and fs base `tls`. returns its id
+pub fn sys_thread_spawn(entry: usize, stack: usize, arg: usize, tls: usize) -> KResult<usize> {
+    if [entry, stack, tls].iter().any(|addr| *addr as u64 >= USER_SPACE_END) {
+        return Err(KError::new(EFAULT));
+    }
+    let (name, addrsp) = {
+        let contexts = context_storage();
+        let context = contexts.current().ok_or(KError::new(ESRCH))?.read();
+        if !context.userspace {
+            return Err(KError::new(EINVAL));
+        }
+        (String::from(context.name()), context.addrsp.clone().ok_or(KError::new(EINVAL))?)
+    };
+
+    let mut contexts = context_storage_mut();
+    let options = SpawnOptions::userspace(&name).share_addr_space(&addrsp);
+    let mut context = contexts.spawn(&options, SpawnEntry::Func(thread_entry))?.write();
+    context.ctx_regs.fsbase = tls;
+    let regs = context.regs_mut().or_panic("thread needs registers to be available");
+    regs.set_instr_pointer(entry);
+    regs.set_stack_pointer(stack);
+    regs.set_entry_arg(arg);
+    context.set_status(Status::Runnable);
+    Ok(context.id.get())
+}
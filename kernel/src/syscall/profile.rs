@@ -0,0 +1,26 @@
+use alloc::vec::Vec;
+use core::mem::size_of;
+use libvdso::error::{EINVAL, KError, KResult};
+use libvdso::flag::{CAP_ADMIN, PROFILE_DUMP, PROFILE_START, PROFILE_STOP};
+use crate::arch_spec::pmu;
+use crate::context::cred::require_cap;
+use crate::mem::user_ptr::UserSlice;
+
+/// start, stop or dump the sampling profiler, needs CAP_ADMIN: samples are kernel addresses
+pub fn sys_profile(op: usize, b: usize, c: usize) -> KResult<usize> {
+    require_cap(CAP_ADMIN)?;
+    match op {
+        PROFILE_START => pmu::start(b, c as u64).map(|_| 0),
+        PROFILE_STOP => pmu::stop().map(|_| 0),
+        PROFILE_DUMP => {
+            let buf = UserSlice::rw(b, c.checked_mul(size_of::<u64>()).ok_or(KError::new(EINVAL))?)?;
+            let mut bytes = Vec::new();
+            pmu::for_each_sample(|_, rip| {
+                bytes.extend_from_slice(&rip.to_ne_bytes());
+                bytes.len() < buf.len()
+            });
+            buf.copy_from_kernel(&bytes).map(|copied| copied / size_of::<u64>())
+        }
+        _ => Err(KError::new(EINVAL)),
+    }
+}
@@ -0,0 +1,38 @@
+use core::sync::atomic::Ordering;
+use libvdso::error::KResult;
+use libvdso::sysinfo::{SysInfo, UtsName, UTS_LEN};
+use crate::device::tsc::monotonic_ns;
+use crate::mem::frame_allocator::{used_frame_count, PHYS_MEM_SIZE};
+use crate::mem::user_ptr::UserPtr;
+use crate::mem::PAGE_SIZE;
+use crate::CPU_COUNT;
+
+// copies `text` into a uts field, truncated to keep the nul
+fn uts_field(text: &str) -> [u8; UTS_LEN] {
+    let mut field = [0; UTS_LEN];
+    let len = text.len().min(UTS_LEN - 1);
+    field[..len].copy_from_slice(&text.as_bytes()[..len]);
+    field
+}
+
+pub fn sys_uname(name: usize) -> KResult<usize> {
+    UserPtr::<UtsName>::rw(name)?.write(UtsName {
+        sysname: uts_field(env!("CARGO_PKG_NAME")),
+        release: uts_field(env!("CARGO_PKG_VERSION")),
+        version: uts_field(env!("KERNEL_GIT_HASH")),
+        machine: uts_field("x86_64"),
+    })?;
+    Ok(0)
+}
+
+pub fn sys_sysinfo(info: usize) -> KResult<usize> {
+    let total_mem = *PHYS_MEM_SIZE.get().unwrap_or(&0);
+    let used_mem = (used_frame_count() * PAGE_SIZE) as u64;
+    UserPtr::<SysInfo>::rw(info)?.write(SysInfo {
+        uptime_ns: monotonic_ns(),
+        cpus: CPU_COUNT.load(Ordering::SeqCst) as u64,
+        total_mem,
+        free_mem: total_mem.saturating_sub(used_mem),
+    })?;
+    Ok(0)
+}
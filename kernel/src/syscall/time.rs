@@ -0,0 +1,62 @@
+use core::hint::spin_loop;
+use libvdso::error::{EINTR, EINVAL, ENODEV, ESRCH, KError, KResult};
+use libvdso::time::TimeSpec;
+use crate::context::list::context_storage;
+use crate::context::sleep::{sleep_until, wake_if_expired};
+use crate::context::switch::{switch_context, SwitchResult};
+use crate::device::tsc::{current_tsc_hz, monotonic_ns};
+use crate::mem::user_ptr::UserPtr;
+
+const NSEC_PER_SEC: u64 = 1_000_000_000;
+
+/// tsc frequency of current cpu in khz, userspace converts rdtsc readings with it
+pub fn sys_tsc_khz() -> KResult<usize> {
+    match current_tsc_hz() {
+        0 => Err(KError::new(ENODEV)),
+        hz => Ok((hz / 1000) as usize),
+    }
+}
+
+pub fn sys_nanosleep(req: usize, rem: usize) -> KResult<usize> {
+    let req = UserPtr::<TimeSpec>::ro(req)?.read()?;
+    if req.tv_sec < 0 || !(0..NSEC_PER_SEC as i64).contains(&req.tv_nsec) {
+        return Err(KError::new(EINVAL));
+    }
+    let duration = (req.tv_sec as u64).saturating_mul(NSEC_PER_SEC).saturating_add(req.tv_nsec as u64);
+    let deadline = monotonic_ns().saturating_add(duration);
+
+    let context_lock = context_storage().current().cloned().ok_or(KError::new(ESRCH))?;
+    sleep_until(&mut context_lock.write(), deadline);
+
+    // interrupts are masked on syscall entry
+    loop {
+        match unsafe { switch_context() } {
+            SwitchResult::Switched { .. } => {
+                if context_lock.read().status.is_runnable() {
+                    break;
+                }
+            }
+            // nothing else to run on this cpu, wait here
+            SwitchResult::AllContextsIdle => {
+                if wake_if_expired(&mut context_lock.write(), monotonic_ns()) {
+                    break;
+                }
+                spin_loop();
+            }
+        }
+    }
+
+    let now = monotonic_ns();
+    if now >= deadline {
+        return Ok(0);
+    }
+    // woken before deadline, only signals do that
+    if rem != 0 {
+        let left = deadline - now;
+        UserPtr::<TimeSpec>::rw(rem)?.write(TimeSpec {
+            tv_sec: (left / NSEC_PER_SEC) as i64,
+            tv_nsec: (left % NSEC_PER_SEC) as i64,
+        })?;
+    }
+    Err(KError::new(EINTR))
+}
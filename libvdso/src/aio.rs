@@ -0,0 +1,110 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+use crate::error::{KError, KResult};
+use crate::r#macro::{syscall0, syscall1};
+use crate::syscall_number::{SYS_AIO_ENTER, SYS_AIO_SETUP};
+
+/// slots of each queue of an [`AioRing`]
+pub const AIO_RING_ENTRIES: usize = 32;
+
+// ops of an AioSubmission
+pub const AIO_OP_NOP: u32 =     0;
+pub const AIO_OP_READ: u32 =    1;
+pub const AIO_OP_WRITE: u32 =   2;
+
+// files an AioSubmission can name, besides descriptors returned by `open`
+/// serial console on com2, com1 belongs to the kernel shell
+pub const AIO_FD_CONSOLE: u32 = 0;
+
+/// one request, the kernel reads it when the caller enters
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AioSubmission {
+    /// `AIO_OP_*`
+    pub op: u32,
+    /// `AIO_FD_*` or a descriptor from `open`
+    pub fd: u32,
+    pub buf: usize,
+    pub len: usize,
+    /// handed back untouched in the completion
+    pub user_data: u64,
+}
+
+/// one finished request
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AioCompletion {
+    pub user_data: u64,
+    /// bytes transferred, a negated errno on failure
+    pub result: usize,
+}
+
+impl AioCompletion {
+    pub fn result(&self) -> KResult<usize> {
+        KError::demux(self.result)
+    }
+}
+
+/// submission and completion queues shared with the kernel.
+///
+/// the caller produces submissions at `sq_tail` and the kernel consumes them
+/// from `sq_head` on [`aio_enter`], the kernel produces completions at `cq_tail`
+/// and the caller consumes them from `cq_head`. indices run freely and wrap
+/// at `u32::MAX`, a slot is the index modulo [`AIO_RING_ENTRIES`].
+#[repr(C)]
+pub struct AioRing {
+    pub sq_head: AtomicU32,
+    pub sq_tail: AtomicU32,
+    pub cq_head: AtomicU32,
+    pub cq_tail: AtomicU32,
+    pub sq: [AioSubmission; AIO_RING_ENTRIES],
+    pub cq: [AioCompletion; AIO_RING_ENTRIES],
+}
+
+impl AioRing {
+    /// queue `submission` for the next [`aio_enter`], false while the queue is full
+    pub fn submit(&mut self, submission: AioSubmission) -> bool {
+        let tail = self.sq_tail.load(Ordering::Relaxed);
+        if tail.wrapping_sub(self.sq_head.load(Ordering::Acquire)) as usize >= AIO_RING_ENTRIES {
+            return false;
+        }
+        self.sq[tail as usize % AIO_RING_ENTRIES] = submission;
+        self.sq_tail.store(tail.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    /// oldest completion not consumed yet
+    pub fn complete(&mut self) -> Option<AioCompletion> {
+        let head = self.cq_head.load(Ordering::Relaxed);
+        if head == self.cq_tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let completion = self.cq[head as usize % AIO_RING_ENTRIES];
+        self.cq_head.store(head.wrapping_add(1), Ordering::Release);
+        Some(completion)
+    }
+}
+
+/// Map an [`AioRing`] into the calling context, returns it empty
+///
+/// # Errors
+///
+/// * `EBUSY` - the context has a ring already
+/// * `ENOMEM` - no memory for the ring, or `RLIMIT_AS` is reached
+pub fn aio_setup() -> KResult<&'static mut AioRing> {
+    unsafe { syscall0(SYS_AIO_SETUP).map(|ring| &mut *(ring as *mut AioRing)) }
+}
+
+/// Hand the queued submissions to the kernel and wait until `min_complete` completions
+/// are in the ring, returns the number of submissions taken
+///
+/// A submission is taken only while the requests in flight and the completions not
+/// consumed yet leave a completion slot for it, the rest stays queued. Errors of a
+/// single request go to its completion.
+///
+/// # Errors
+///
+/// * `EINVAL` - the context has no ring, or `min_complete` exceeds `AIO_RING_ENTRIES`
+/// * `EFAULT` - the ring was unmapped
+pub fn aio_enter(min_complete: usize) -> KResult<usize> {
+    unsafe { syscall1(SYS_AIO_ENTER, min_complete) }
+}
@@ -0,0 +1,16 @@
+// auxiliary vector entries the kernel puts after envp on the initial stack,
+// each entry is a `(type, value)` pair of u64, the vector ends with `AT_NULL`.
+// the values match the System V x86_64 ABI.
+
+pub const AT_NULL: u64 =    0;
+/// address of the program headers of the executable
+pub const AT_PHDR: u64 =    3;
+/// size of one program header
+pub const AT_PHENT: u64 =   4;
+/// number of program headers
+pub const AT_PHNUM: u64 =   5;
+pub const AT_PAGESZ: u64 =  6;
+/// load address of the interpreter, 0 without one
+pub const AT_BASE: u64 =    7;
+/// entry point of the executable, the interpreter jumps there when it is done
+pub const AT_ENTRY: u64 =   9;
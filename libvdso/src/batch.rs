@@ -0,0 +1,46 @@
+use crate::error::{KError, KResult};
+use crate::r#macro::syscall3;
+use crate::syscall_number::SYS_SYSCALL_BATCH;
+
+/// most descriptors one [`syscall_batch`] takes
+pub const BATCH_MAX: usize = 64;
+
+/// one call of a [`syscall_batch`], the kernel fills in `result`
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SyscallDesc {
+    /// `SYS_*` number
+    pub number: usize,
+    pub args: [usize; 5],
+    /// return value, a negated errno on failure
+    pub result: usize,
+}
+
+impl SyscallDesc {
+    pub const fn new(number: usize, args: [usize; 5]) -> Self {
+        Self { number, args, result: 0 }
+    }
+
+    /// result of the call as returned by its own wrapper
+    pub fn result(&self) -> KResult<usize> {
+        KError::demux(self.result)
+    }
+}
+
+/// Run the calls of `descs` in order on one kernel entry, returns the number of calls run
+///
+/// Each result is stored in its descriptor. All calls run unless `flags` has
+/// `BATCH_STOP_ON_ERROR`, then the ones after the first failure are skipped with
+/// `ECANCELED`. Descriptors are read before the first call and results written after
+/// the last, so a call can not take the result of an earlier one as argument, and
+/// `SYS_EXIT` never returns to write them.
+///
+/// # Errors
+///
+/// * `EINVAL` - more than `BATCH_MAX` descriptors or unknown `flags`
+/// * `EFAULT` - `descs` does not point to the process's addressible memory
+///
+/// A nested `SYS_SYSCALL_BATCH` fails with `EINVAL` in its own result.
+pub fn syscall_batch(descs: &mut [SyscallDesc], flags: usize) -> KResult<usize> {
+    unsafe { syscall3(SYS_SYSCALL_BATCH, descs.as_mut_ptr() as usize, descs.len(), flags) }
+}
@@ -1,5 +1,6 @@
 use core::fmt;
 
+/// error of every fallible kernel path, `mux` turns it into a negative errno at the syscall boundary
 #[derive(Clone, Copy, Eq, PartialEq)]
 pub struct KError {
     pub errno: i32,
@@ -21,6 +22,18 @@ impl KError {
     }
 }
 
+impl fmt::Debug for KError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "KError(errno {})", self.errno)
+    }
+}
+
+impl fmt::Display for KError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "errno {}", self.errno)
+    }
+}
+
 // error
 pub const EPERM: i32 = 1;  /* Operation not permitted */
 pub const ENOENT: i32 = 2;  /* No such fs or directory */
@@ -29,4 +29,47 @@ pub const SIGPROF: usize =  27;
 pub const SIGWINCH: usize = 28;
 pub const SIGIO: usize =    29;
 pub const SIGPWR: usize =   30;
-pub const SIGSYS: usize =   31;
\ No newline at end of file
+pub const SIGSYS: usize =   31;
+
+// reboot
+pub const REBOOT_RESET: usize =   0;
+pub const REBOOT_KEXEC: usize =   1;
+// experimental, see kernel/src/power/hibernate.rs
+pub const REBOOT_HIBERNATE: usize = 2;
+
+// log_level, levels follow `log::LevelFilter`
+pub const LOG_LEVEL_OFF: usize =   0;
+pub const LOG_LEVEL_ERROR: usize = 1;
+pub const LOG_LEVEL_WARN: usize =  2;
+pub const LOG_LEVEL_INFO: usize =  3;
+pub const LOG_LEVEL_DEBUG: usize = 4;
+pub const LOG_LEVEL_TRACE: usize = 5;
+// only query the level in effect
+pub const LOG_LEVEL_GET: usize =   usize::MAX;
+// drop the filter of a target, it follows the global level again
+pub const LOG_LEVEL_RESET: usize = usize::MAX - 1;
+
+// profile
+pub const PROFILE_START: usize =    0;
+pub const PROFILE_STOP: usize =     1;
+pub const PROFILE_DUMP: usize =     2;
+// events sampled by PROFILE_START, kernel mode only
+pub const PROFILE_EVENT_CYCLES: usize =       0;
+pub const PROFILE_EVENT_INSTRUCTIONS: usize = 1;
+
+// watchpoint
+pub const WATCHPOINT_SET: usize =   0;
+pub const WATCHPOINT_CLEAR: usize = 1;
+// access kinds of WATCHPOINT_SET, the dr7 r/w encoding
+pub const WATCH_EXEC: usize =       0;
+pub const WATCH_WRITE: usize =      1;
+pub const WATCH_READ_WRITE: usize = 3;
+
+// syscall_batch
+// skip the calls after the first that fails, their results are ECANCELED
+pub const BATCH_STOP_ON_ERROR: usize = 1 << 0;
+
+// capability bits of a context, see getcaps/capdrop
+pub const CAP_IO: u64 =     1 << 0;
+pub const CAP_ADMIN: u64 =  1 << 1;
+pub const CAP_ALL: u64 =    CAP_IO | CAP_ADMIN;
@@ -0,0 +1,31 @@
+use crate::error::KResult;
+use crate::r#macro::syscall1;
+use crate::syscall_number::SYS_FRAMEBUFFER_INFO;
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FramebufferInfo {
+    /// size in bytes
+    pub len: usize,
+    /// visible pixels per row
+    pub width: usize,
+    pub height: usize,
+    /// pixels per row in memory, at least `width`
+    pub stride: usize,
+    /// 1 to 4, a pixel is a little endian value of this many bytes
+    pub bytes_per_pixel: usize,
+    /// bits of each channel in a pixel
+    pub red_mask: u32,
+    pub green_mask: u32,
+    pub blue_mask: u32,
+}
+
+/// Get the geometry and pixel layout of the framebuffer the kernel console renders to
+///
+/// # Errors
+///
+/// * `EFAULT` - `info` does not point to the process's addressible memory
+/// * `ENODEV` - the kernel runs headless, firmware did not provide a framebuffer
+pub fn framebuffer_info(info: &mut FramebufferInfo) -> KResult<usize> {
+    unsafe { syscall1(SYS_FRAMEBUFFER_INFO, info as *mut FramebufferInfo as usize) }
+}
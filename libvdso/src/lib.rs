@@ -1,7 +1,14 @@
 #![no_std]
 
+pub mod aio;
+pub mod auxv;
+pub mod batch;
 pub mod flag;
+pub mod framebuffer;
 pub(crate) mod r#macro;
 pub mod error;
+pub mod rlimit;
 pub mod syscall;
-mod syscall_number;
\ No newline at end of file
+pub mod sysinfo;
+pub mod time;
+pub mod syscall_number;
\ No newline at end of file
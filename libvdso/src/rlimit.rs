@@ -0,0 +1,49 @@
+use crate::error::KResult;
+use crate::r#macro::syscall2;
+use crate::syscall_number::{SYS_GETRLIMIT, SYS_SETRLIMIT};
+
+/// bytes of buffers allocated in the address space
+pub const RLIMIT_AS: usize =        0;
+/// open file descriptors
+pub const RLIMIT_NOFILE: usize =    1;
+/// spawned children not reaped yet
+pub const RLIMIT_CHILDREN: usize =  2;
+/// cpu time in nanoseconds, the context is killed once it passes `max`
+pub const RLIMIT_CPU: usize =       3;
+pub const RLIMIT_COUNT: usize =     4;
+
+pub const RLIM_INFINITY: usize = usize::MAX;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RLimit {
+    /// enforced limit
+    pub cur: usize,
+    /// ceiling of `cur`, only raised with `CAP_ADMIN`
+    pub max: usize,
+}
+
+impl RLimit {
+    pub const INFINITY: RLimit = RLimit { cur: RLIM_INFINITY, max: RLIM_INFINITY };
+}
+
+/// Get the limit of `resource` (`RLIMIT_*`) of the calling context
+///
+/// # Errors
+///
+/// * `EFAULT` - `limit` does not point to the process's addressible memory
+/// * `EINVAL` - `resource` is unknown
+pub fn getrlimit(resource: usize, limit: &mut RLimit) -> KResult<usize> {
+    unsafe { syscall2(SYS_GETRLIMIT, resource, limit as *mut RLimit as usize) }
+}
+
+/// Set the limit of `resource` (`RLIMIT_*`) of the calling context, contexts spawned later inherit it
+///
+/// # Errors
+///
+/// * `EFAULT` - `limit` does not point to the process's addressible memory
+/// * `EINVAL` - `resource` is unknown or `cur` is greater than `max`
+/// * `EPERM` - `max` is raised and the caller lacks `CAP_ADMIN`
+pub fn setrlimit(resource: usize, limit: &RLimit) -> KResult<usize> {
+    unsafe { syscall2(SYS_SETRLIMIT, resource, limit as *const RLimit as usize) }
+}
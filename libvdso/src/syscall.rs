@@ -1,6 +1,15 @@
 use crate::error::KResult;
-use crate::r#macro::syscall3;
-use crate::syscall_number::SYS_WRITE;
+use crate::r#macro::{syscall0, syscall1, syscall2, syscall3, syscall4};
+use crate::flag::{
+    LOG_LEVEL_GET, PROFILE_DUMP, PROFILE_START, PROFILE_STOP, REBOOT_HIBERNATE, REBOOT_KEXEC, REBOOT_RESET,
+    WATCHPOINT_CLEAR, WATCHPOINT_SET,
+};
+use crate::syscall_number::{
+    SYS_AUDIT_READ, SYS_CAPDROP, SYS_CLOSE, SYS_EXIT, SYS_GETGID, SYS_GETPID, SYS_GETPPID, SYS_GETUID, SYS_IOPERM,
+    SYS_IOPL, SYS_IRQ_REGISTER, SYS_IRQ_RELEASE, SYS_IRQ_WAIT, SYS_LOG_LEVEL, SYS_MAP_DEVICE, SYS_OPEN, SYS_PROFILE,
+    SYS_READ, SYS_REBOOT, SYS_SETGID, SYS_SETUID, SYS_SET_NAME, SYS_THREAD_SPAWN, SYS_UNMAP_DEVICE, SYS_WATCHPOINT,
+    SYS_WRITE,
+};
 
 /// Write a buffer to a fs descriptor
 ///
@@ -18,4 +27,347 @@ use crate::syscall_number::SYS_WRITE;
 /// * `EPIPE` - the fs descriptor refers to a pipe or socket whose reading end is closed
 pub fn write(fd: usize, buf: &[u8]) -> KResult<usize> {
     unsafe { syscall3(SYS_WRITE, fd, buf.as_ptr() as usize, buf.len()) }
-}
\ No newline at end of file
+}
+
+/// Open the file at absolute `path`, returns its descriptor
+///
+/// Only procfs is mounted, at `/proc`. Directories read as their entry names, one a line.
+///
+/// # Errors
+///
+/// * `EFAULT` - `path` does not point to the process's addressible memory
+/// * `EINVAL` - `path` is not valid utf-8
+/// * `EMFILE` - `RLIMIT_NOFILE` files are open already
+/// * `ENAMETOOLONG` - `path` is longer than 256 bytes
+/// * `ENOENT` - nothing is at `path`
+pub fn open(path: &str) -> KResult<usize> {
+    unsafe { syscall3(SYS_OPEN, path.as_ptr() as usize, path.len(), 0) }
+}
+
+/// Read from a fs descriptor into `buf`, returns the number of bytes read, 0 at the end
+///
+/// # Errors
+///
+/// * `EBADF` - the fs descriptor is not valid or is not open for reading
+/// * `EFAULT` - `buf` does not point to the process's addressible memory
+/// * `ENOENT` - the context a procfs file describes exited
+pub fn read(fd: usize, buf: &mut [u8]) -> KResult<usize> {
+    unsafe { syscall3(SYS_READ, fd, buf.as_mut_ptr() as usize, buf.len()) }
+}
+
+/// Close a fs descriptor, it may be returned by the next `open`
+///
+/// # Errors
+///
+/// * `EBADF` - the fs descriptor is not valid
+pub fn close(fd: usize) -> KResult<usize> {
+    unsafe { syscall1(SYS_CLOSE, fd) }
+}
+
+/// Set the debug name of the calling context
+///
+/// The name shows up in kernel logs and process listings, it is truncated to 16 bytes.
+///
+/// # Errors
+///
+/// * `EFAULT` - `name` does not point to the process's addressible memory
+/// * `EINVAL` - `name` is not valid utf-8
+pub fn set_name(name: &str) -> KResult<usize> {
+    unsafe { syscall2(SYS_SET_NAME, name.as_ptr() as usize, name.len()) }
+}
+
+/// Get the id of the calling context
+///
+/// Contexts are not grouped into processes, this is also the thread id.
+pub fn getpid() -> KResult<usize> {
+    unsafe { syscall0(SYS_GETPID) }
+}
+
+/// Get the id of the context that spawned the calling context
+///
+/// Orphans are adopted by init, the first userspace context. Returns 0 if
+/// nobody adopted the caller.
+pub fn getppid() -> KResult<usize> {
+    unsafe { syscall0(SYS_GETPPID) }
+}
+
+/// End the calling context with `code`
+///
+/// Other threads sharing the address space keep running.
+pub fn exit(code: usize) -> ! {
+    let _ = unsafe { syscall1(SYS_EXIT, code) };
+    unreachable!("exit returned")
+}
+
+/// Start a thread at `entry` with `arg` as its argument, returns its context id
+///
+/// The thread shares the address space of the caller. It runs on `stack`, the top of
+/// memory the caller set aside, and its fs base is `tls`. It ends with [`exit`].
+///
+/// # Errors
+///
+/// * `EFAULT` - `entry`, `stack` or `tls` is outside user space
+/// * `EINVAL` - the caller is a kernel context
+/// * `EAGAIN` - the caller has too many children or 63 threads share the address space already
+/// * `ENOMEM` - out of memory
+pub fn thread_spawn(entry: extern "C" fn(usize) -> !, stack: usize, arg: usize, tls: usize) -> KResult<usize> {
+    unsafe { syscall4(SYS_THREAD_SPAWN, entry as usize, stack, arg, tls) }
+}
+
+/// Get the user id of the calling context
+pub fn getuid() -> KResult<usize> {
+    unsafe { syscall0(SYS_GETUID) }
+}
+
+/// Get the group id of the calling context
+pub fn getgid() -> KResult<usize> {
+    unsafe { syscall0(SYS_GETGID) }
+}
+
+/// Set the user id of the calling context, contexts spawned later inherit it
+///
+/// Any uid but 0 drops every capability for good.
+///
+/// # Errors
+///
+/// * `EPERM` - `uid` differs from the current one and the caller lacks `CAP_ADMIN`
+/// * `EINVAL` - `uid` does not fit 32 bits
+pub fn setuid(uid: usize) -> KResult<usize> {
+    unsafe { syscall1(SYS_SETUID, uid) }
+}
+
+/// Set the group id of the calling context, contexts spawned later inherit it
+///
+/// # Errors
+///
+/// * `EPERM` - `gid` differs from the current one and the caller lacks `CAP_ADMIN`
+/// * `EINVAL` - `gid` does not fit 32 bits
+pub fn setgid(gid: usize) -> KResult<usize> {
+    unsafe { syscall1(SYS_SETGID, gid) }
+}
+
+/// Drop capability bits `caps` (`CAP_*`) of the calling context, returns the remaining ones
+///
+/// Dropped capabilities can not be regained, `capdrop(0)` only queries.
+pub fn capdrop(caps: u64) -> KResult<usize> {
+    unsafe { syscall1(SYS_CAPDROP, caps as usize) }
+}
+
+/// Allow or deny the calling context direct access to I/O ports `from..from + num`
+///
+/// Only permitted if the kernel is booted with `userspace_io` and the caller has `CAP_IO`.
+///
+/// # Errors
+///
+/// * `EPERM` - userspace port access is not enabled or the caller lacks `CAP_IO`
+/// * `EINVAL` - the range exceeds port `0xffff`
+/// * `EBUSY` - some port in the range is owned by a kernel driver
+pub fn ioperm(from: u16, num: usize, turn_on: bool) -> KResult<usize> {
+    unsafe { syscall3(SYS_IOPERM, from as usize, num, turn_on as usize) }
+}
+
+/// Set the I/O privilege level of the calling context, level 3 allows access to every port
+///
+/// Only permitted if the kernel is booted with `userspace_io` and the caller has `CAP_IO`.
+///
+/// # Errors
+///
+/// * `EPERM` - userspace port access is not enabled or the caller lacks `CAP_IO`
+/// * `EINVAL` - `level` is greater than 3
+pub fn iopl(level: usize) -> KResult<usize> {
+    unsafe { syscall1(SYS_IOPL, level) }
+}
+
+/// Map `len` bytes of device memory at physical address `phys` into the calling context
+///
+/// Returns the address of `phys`, the mapping is uncached. Only permitted if the kernel is
+/// booted with `userspace_io` and the caller has `CAP_IO`.
+///
+/// # Errors
+///
+/// * `EPERM` - userspace io is not enabled, the caller lacks `CAP_IO`, or the range is ram
+///   or used by the kernel
+/// * `EINVAL` - `len` is 0 or `phys` is not a physical address
+/// * `ENOMEM` - page tables can not be allocated
+pub fn map_device(phys: usize, len: usize) -> KResult<usize> {
+    unsafe { syscall2(SYS_MAP_DEVICE, phys, len) }
+}
+
+/// Unmap device memory mapped by [`map_device`], `addr` and `len` are what it was called with
+///
+/// # Errors
+///
+/// * `EINVAL` - there is no such mapping
+pub fn unmap_device(addr: usize, len: usize) -> KResult<usize> {
+    unsafe { syscall2(SYS_UNMAP_DEVICE, addr, len) }
+}
+
+/// Receive legacy interrupt line `irq` in the calling context, see [`irq_wait`]
+///
+/// The kernel acknowledges the interrupt controller, the driver only handles the device.
+/// Only permitted if the kernel is booted with `userspace_io` and the caller has `CAP_IO`.
+///
+/// # Errors
+///
+/// * `EPERM` - userspace io is not enabled or the caller lacks `CAP_IO`
+/// * `EINVAL` - `irq` is not a legacy line or is used by the kernel
+/// * `EBUSY` - another context owns `irq`
+pub fn irq_register(irq: usize) -> KResult<usize> {
+    unsafe { syscall1(SYS_IRQ_REGISTER, irq) }
+}
+
+/// Block until `irq` fires, returns how many times it fired since the last call
+///
+/// # Errors
+///
+/// * `EINVAL` - the caller does not own `irq`
+pub fn irq_wait(irq: usize) -> KResult<usize> {
+    unsafe { syscall1(SYS_IRQ_WAIT, irq) }
+}
+
+/// Give `irq` back
+///
+/// # Errors
+///
+/// * `EINVAL` - the caller does not own `irq`
+pub fn irq_release(irq: usize) -> KResult<usize> {
+    unsafe { syscall1(SYS_IRQ_RELEASE, irq) }
+}
+
+/// Park every other cpu and reset the machine, only returns on error
+///
+/// # Errors
+///
+/// * `EPERM` - the caller lacks `CAP_ADMIN`
+pub fn reboot() -> KResult<usize> {
+    unsafe { syscall3(SYS_REBOOT, REBOOT_RESET, 0, 0) }
+}
+
+/// Park every other cpu and boot the kernel elf `image` in place of the running kernel
+///
+/// Only returns on error, the running kernel is left intact then.
+///
+/// # Errors
+///
+/// * `EPERM` - the caller lacks `CAP_ADMIN`
+/// * `EFAULT` - `image` does not point to the process's addressible memory
+/// * `ENOEXEC` - `image` is not a relocatable x86_64 kernel elf
+/// * `E2BIG` - the image is larger than 1 GiB
+/// * `ENOMEM` - out of physical memory for the image
+pub fn kexec(image: &[u8]) -> KResult<usize> {
+    unsafe { syscall3(SYS_REBOOT, REBOOT_KEXEC, image.as_ptr() as usize, image.len()) }
+}
+
+/// Stream every process to the serial port and reset the machine, experimental
+///
+/// Returns 0 in the process resumed from the image on a later boot, see `resume=` in boot.cfg.
+///
+/// # Errors
+///
+/// * `EPERM` - the caller lacks `CAP_ADMIN`
+pub fn hibernate() -> KResult<usize> {
+    unsafe { syscall3(SYS_REBOOT, REBOOT_HIBERNATE, 0, 0) }
+}
+
+/// Get the kernel log level in effect for `target`, or the global level if `target` is empty
+///
+/// Levels are the `LOG_LEVEL_*` numbers. A target is a module path such as `kernel::mem`.
+///
+/// # Errors
+///
+/// * `EFAULT` - `target` does not point to the process's addressible memory
+/// * `EINVAL` - `target` is not valid utf-8 or longer than 48 bytes
+pub fn get_log_level(target: &str) -> KResult<usize> {
+    unsafe { syscall3(SYS_LOG_LEVEL, target.as_ptr() as usize, target.len(), LOG_LEVEL_GET) }
+}
+
+/// Set the kernel log level of `target` and the modules below it, or the global level if `target` is empty
+///
+/// `LOG_LEVEL_RESET` drops the filter of `target`, it follows the global level again.
+/// Returns the level in effect before.
+///
+/// # Errors
+///
+/// * `EPERM` - the caller lacks `CAP_ADMIN`
+/// * `EFAULT` - `target` does not point to the process's addressible memory
+/// * `EINVAL` - `level` is unknown, `target` is not valid utf-8 or longer than 48 bytes
+/// * `ENOSPC` - 16 targets are filtered already
+pub fn set_log_level(target: &str, level: usize) -> KResult<usize> {
+    unsafe { syscall3(SYS_LOG_LEVEL, target.as_ptr() as usize, target.len(), level) }
+}
+
+/// Move the kernel audit records not read yet into `buf`, returns the number of bytes copied
+///
+/// Records are lines of uptime, context, kind and details: capability checks, credential
+/// changes, io port grants, rejected user pointers and killed contexts. Only whole lines are
+/// copied unless the first one is longer than `buf`. A read consumes them.
+///
+/// # Errors
+///
+/// * `EPERM` - the caller lacks `CAP_ADMIN`
+/// * `EFAULT` - `buf` does not point to the process's addressible memory
+pub fn audit_read(buf: &mut [u8]) -> KResult<usize> {
+    unsafe { syscall2(SYS_AUDIT_READ, buf.as_mut_ptr() as usize, buf.len()) }
+}
+
+/// Sample the kernel every `period` events of `event` (`PROFILE_EVENT_*`) on all cpus
+///
+/// Samples are the interrupted instruction pointers, kept in a ring per cpu that is cleared here.
+///
+/// # Errors
+///
+/// * `EPERM` - the caller lacks `CAP_ADMIN`
+/// * `ENODEV` - the cpu has no architectural fixed performance counters
+/// * `EINVAL` - `event` is unknown or `period` is below 10000 or wider than the counter
+/// * `EBUSY` - a profile is running already
+pub fn profile_start(event: usize, period: u64) -> KResult<usize> {
+    unsafe { syscall3(SYS_PROFILE, PROFILE_START, event, period as usize) }
+}
+
+/// Stop sampling, the samples are kept until the next [`profile_start`]
+///
+/// # Errors
+///
+/// * `EPERM` - the caller lacks `CAP_ADMIN`
+/// * `ENODEV` - the cpu has no architectural fixed performance counters
+pub fn profile_stop() -> KResult<usize> {
+    unsafe { syscall3(SYS_PROFILE, PROFILE_STOP, 0, 0) }
+}
+
+/// Copy samples into `rips`, cpu by cpu and oldest first, returns the number copied
+///
+/// Works while the profile runs too.
+///
+/// # Errors
+///
+/// * `EPERM` - the caller lacks `CAP_ADMIN`
+/// * `EFAULT` - `rips` does not point to the process's addressible memory
+pub fn profile_dump(rips: &mut [u64]) -> KResult<usize> {
+    unsafe { syscall3(SYS_PROFILE, PROFILE_DUMP, rips.as_mut_ptr() as usize, rips.len()) }
+}
+
+/// Watch `len` bytes at `addr` for `kind` (`WATCH_*`) with a debug register, returns the slot
+///
+/// Hits are logged by the kernel with the instruction pointer and context. Watchpoints are global,
+/// they fire in every address space that touches `addr`. `addr` must be aligned to `len`.
+///
+/// # Errors
+///
+/// * `EPERM` - the caller lacks `CAP_ADMIN`
+/// * `EFAULT` - the range is not below the end of user space
+/// * `EINVAL` - `kind` is unknown, `len` is not 1, 2, 4 or 8, `addr` is not aligned to it or an
+///   execution watchpoint is wider than 1 byte
+/// * `ENOSPC` - all 4 debug registers are in use
+pub fn set_watchpoint(addr: usize, len: usize, kind: usize) -> KResult<usize> {
+    unsafe { syscall4(SYS_WATCHPOINT, WATCHPOINT_SET, addr, len, kind) }
+}
+
+/// Clear the watchpoint in `slot`, returns how often it was hit
+///
+/// # Errors
+///
+/// * `EPERM` - the caller lacks `CAP_ADMIN`
+/// * `EINVAL` - `slot` holds no watchpoint
+pub fn clear_watchpoint(slot: usize) -> KResult<usize> {
+    unsafe { syscall4(SYS_WATCHPOINT, WATCHPOINT_CLEAR, slot, 0, 0) }
+}
@@ -72,11 +72,13 @@ pub const SYS_GETPID: usize =   20;
 pub const SYS_GETPGID: usize =  132;
 pub const SYS_GETPPID: usize =  64;
 pub const SYS_GETUID: usize =   199;
+pub const SYS_IOPERM: usize =   101;
 pub const SYS_IOPL: usize =     110;
 pub const SYS_KILL: usize =     37;
 pub const SYS_MPROTECT: usize = 125;
 pub const SYS_MKNS: usize =     984;
 pub const SYS_NANOSLEEP: usize =162;
+pub const SYS_REBOOT: usize =   88;
 pub const SYS_VIRTTOPHYS: usize=949;
 pub const SYS_SETPGID: usize =  57;
 pub const SYS_SETREGID: usize = 204;
@@ -88,3 +90,28 @@ pub const SYS_SIGRETURN: usize =119;
 pub const SYS_UMASK: usize =    60;
 pub const SYS_WAITPID: usize =  7;
 pub const SYS_YIELD: usize =    158;
+
+// miniature specific
+pub const SYS_TSC_KHZ: usize =  1000;
+pub const SYS_SET_NAME: usize = SYS_ARG_SLICE | 1001;
+pub const SYS_SETUID: usize =   1002;
+pub const SYS_SETGID: usize =   1003;
+pub const SYS_CAPDROP: usize =  1004;
+pub const SYS_GETRLIMIT: usize =1005;
+pub const SYS_SETRLIMIT: usize =1006;
+pub const SYS_MAP_DEVICE: usize =1007;
+pub const SYS_UNMAP_DEVICE: usize =1008;
+pub const SYS_IRQ_REGISTER: usize =1009;
+pub const SYS_IRQ_WAIT: usize = 1010;
+pub const SYS_IRQ_RELEASE: usize =1011;
+pub const SYS_LOG_LEVEL: usize = SYS_ARG_SLICE | 1012;
+pub const SYS_FRAMEBUFFER_INFO: usize =1013;
+pub const SYS_PROFILE: usize =  1014;
+pub const SYS_UNAME: usize =    1015;
+pub const SYS_SYSINFO: usize =  1016;
+pub const SYS_THREAD_SPAWN: usize =1017;
+pub const SYS_WATCHPOINT: usize =1018;
+pub const SYS_AUDIT_READ: usize = SYS_ARG_MSLICE | 1019;
+pub const SYS_SYSCALL_BATCH: usize = SYS_ARG_MSLICE | 1020;
+pub const SYS_AIO_SETUP: usize = 1021;
+pub const SYS_AIO_ENTER: usize = 1022;
@@ -0,0 +1,63 @@
+use crate::error::KResult;
+use crate::r#macro::syscall1;
+use crate::syscall_number::{SYS_SYSINFO, SYS_UNAME};
+
+/// length of each [`UtsName`] field, nul terminated
+pub const UTS_LEN: usize = 65;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UtsName {
+    /// kernel name
+    pub sysname: [u8; UTS_LEN],
+    /// kernel version
+    pub release: [u8; UTS_LEN],
+    /// git commit the kernel was built from
+    pub version: [u8; UTS_LEN],
+    pub machine: [u8; UTS_LEN],
+}
+
+impl Default for UtsName {
+    fn default() -> Self {
+        Self { sysname: [0; UTS_LEN], release: [0; UTS_LEN], version: [0; UTS_LEN], machine: [0; UTS_LEN] }
+    }
+}
+
+impl UtsName {
+    /// text of a field up to its nul, empty if it is not utf-8
+    pub fn field(field: &[u8; UTS_LEN]) -> &str {
+        let len = field.iter().position(|&b| b == 0).unwrap_or(UTS_LEN);
+        core::str::from_utf8(&field[..len]).unwrap_or("")
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SysInfo {
+    /// nanoseconds since boot
+    pub uptime_ns: u64,
+    /// cpus brought up, bsp included
+    pub cpus: u64,
+    /// bytes of physical memory
+    pub total_mem: u64,
+    /// bytes of physical memory not in use by kernel or processes
+    pub free_mem: u64,
+}
+
+/// Get the name, version and build of the kernel
+///
+/// # Errors
+///
+/// * `EFAULT` - `name` does not point to the process's addressible memory
+pub fn uname(name: &mut UtsName) -> KResult<usize> {
+    unsafe { syscall1(SYS_UNAME, name as *mut UtsName as usize) }
+}
+
+/// Get uptime, cpu count and memory usage of the system
+///
+/// # Errors
+///
+/// * `EFAULT` - `info` does not point to the process's addressible memory
+pub fn sysinfo(info: &mut SysInfo) -> KResult<usize> {
+    unsafe { syscall1(SYS_SYSINFO, info as *mut SysInfo as usize) }
+}
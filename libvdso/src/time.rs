@@ -0,0 +1,44 @@
+use crate::error::KResult;
+use crate::r#macro::{syscall0, syscall2};
+use crate::syscall_number::{SYS_NANOSLEEP, SYS_TSC_KHZ};
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TimeSpec {
+    pub tv_sec: i64,
+    pub tv_nsec: i64,
+}
+
+/// tsc frequency of the cpu the caller runs on, in khz
+///
+/// # Errors
+///
+/// * `ENODEV` - the kernel has not calibrated tsc
+pub fn tsc_khz() -> KResult<usize> {
+    unsafe { syscall0(SYS_TSC_KHZ) }
+}
+
+#[inline]
+pub fn rdtsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// convert tsc ticks to nanoseconds with frequency from [`tsc_khz`]
+#[inline]
+pub fn tsc_to_ns(ticks: u64, khz: usize) -> u64 {
+    (ticks as u128 * 1_000_000 / khz as u128) as u64
+}
+
+/// Suspend the calling context for at least `req`
+///
+/// If the sleep is interrupted by a signal, the remaining time is written to `rem`.
+///
+/// # Errors
+///
+/// * `EFAULT` - `req` or `rem` does not point to the process's addressible memory
+/// * `EINTR` - the sleep was interrupted by a signal
+/// * `EINVAL` - `tv_nsec` is not in `0..1_000_000_000` or `tv_sec` is negative
+pub fn nanosleep(req: &TimeSpec, rem: Option<&mut TimeSpec>) -> KResult<usize> {
+    let rem = rem.map_or(0, |rem| rem as *mut TimeSpec as usize);
+    unsafe { syscall2(SYS_NANOSLEEP, req as *const TimeSpec as usize, rem) }
+}
@@ -1,4 +1,9 @@
-cp target/x86_64-unknown-uefi/debug/asos.efi test_esp/efi/boot/bootx64.efi
-qemu-system-x86_64 \
-  -drive if=pflash,format=raw,readonly=on,file="/c/Program Files/qemu/share/edk2-x86_64-code.fd" \
-  -drive format=raw,file=fat:rw:test_esp
\ No newline at end of file
+#!/bin/sh
+# build bootloader, kernel and bootstrap, then boot them in qemu. extra arguments go to `build-image run`.
+set -e
+cargo build -p bootloader --target x86_64-unknown-uefi
+cargo build -p kernel --target kernel/x86_64-myos.json \
+  -Z build-std=core,compiler_builtins,alloc -Z build-std-features=compiler-builtins-mem
+cargo build -p bootstrap --target x86_64-unknown-none \
+  -Z build-std=core,compiler_builtins,alloc -Z build-std-features=compiler-builtins-mem
+cargo run -p build-image -- run --bootstrap target/x86_64-unknown-none/debug/bootstrap "$@"
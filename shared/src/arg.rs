@@ -1,6 +1,20 @@
-use core::{fmt::{self, Debug}, mem::MaybeUninit};
+use core::{fmt::{self, Debug}, mem::{align_of, offset_of, size_of, MaybeUninit}, slice};
+use crate::{font::FontConfig, framebuffer::PixelMask};
 
-pub const MAX_CPUS: usize = 256;
+// default stack sizes, must be multiple of 4 KiB
+pub const DEFAULT_BOOT_STACK_SIZE: usize = 4096 * 128;
+pub const DEFAULT_CONTEXT_STACK_SIZE: usize = 4096 * 64;
+pub const DEFAULT_AP_STACK_SIZE: usize = 4096 * 64;
+// kernel command line passed from boot.cfg
+pub const MAX_CMDLINE_LEN: usize = 256;
+// usable regions below 1 MiB, for real mode code such as the ap trampoline
+pub const MAX_LOW_MEM_REGIONS: usize = 16;
+pub const LOW_MEM_END: u64 = 0x10_0000;
+// programs besides bootstrap the bootloader loads for the init supervisor
+pub const MAX_BOOT_MODULES: usize = 8;
+pub const BOOT_MODULE_NAME_LEN: usize = 32;
+// fresh kernel stacks are filled with this byte, the lowest overwritten byte is the high-water mark.
+pub const STACK_FILL_PATTERN: u8 = 0xa5;
 
 #[derive(Debug, Clone, Copy)]
 #[repr(C)]
@@ -37,6 +51,28 @@ pub enum MemoryRegionKind {
     ///
     /// This memory should _not_ be used by the kernel.
     Bootloader,
+    /// Kernel elf file loaded by the bootloader.
+    KernelImage,
+    /// Bootstrap elf file loaded by the bootloader.
+    Bootstrap,
+    /// Kernel argument passed to the kernel entry.
+    KernelArg,
+    /// Frame buffer of the graphics output.
+    Framebuffer,
+    /// Page tables built by the bootloader.
+    PageTables,
+    /// Global descriptor table of the bootstrap processor.
+    Gdt,
+    /// Memory mapped registers, e.g. local apic and io apic.
+    Mmio,
+    /// ACPI tables, reclaimable once the kernel no longer reads them.
+    AcpiReclaim,
+    /// ACPI non-volatile storage, must be preserved.
+    AcpiNvs,
+    /// UEFI runtime services code and data, must be preserved.
+    RuntimeServices,
+    /// UEFI boot services code and data, free after boot services exit.
+    BootServices,
     /// An unknown memory region reported by the UEFI firmware.
     ///
     /// Contains the UEFI memory type tag.
@@ -45,17 +81,204 @@ pub enum MemoryRegionKind {
     UnknownBios(u32),
 }
 
+impl MemoryRegionKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            MemoryRegionKind::Usable => "usable",
+            MemoryRegionKind::Bootloader => "bootloader",
+            MemoryRegionKind::KernelImage => "kernel image",
+            MemoryRegionKind::Bootstrap => "bootstrap",
+            MemoryRegionKind::KernelArg => "kernel arg",
+            MemoryRegionKind::Framebuffer => "framebuffer",
+            MemoryRegionKind::PageTables => "page tables",
+            MemoryRegionKind::Gdt => "gdt",
+            MemoryRegionKind::Mmio => "mmio",
+            MemoryRegionKind::AcpiReclaim => "acpi reclaim",
+            MemoryRegionKind::AcpiNvs => "acpi nvs",
+            MemoryRegionKind::RuntimeServices => "uefi runtime",
+            MemoryRegionKind::BootServices => "uefi boot",
+            MemoryRegionKind::UnknownUefi(_) => "unknown uefi",
+            MemoryRegionKind::UnknownBios(_) => "unknown bios",
+        }
+    }
+
+    /// whether the kernel may hand out this region after it is done with the content
+    pub fn reclaimable(&self) -> bool {
+        matches!(self, MemoryRegionKind::AcpiReclaim | MemoryRegionKind::BootServices)
+    }
+}
+
 #[repr(C)]
 #[derive(Clone, Copy, Debug, Default)]
 pub struct TlsTemplate {
     pub start_virt_addr: u64,
     pub mem_size: usize,
-    pub file_size: usize
+    pub file_size: usize,
+    // p_align of the segment, the block of every thread is aligned to it
+    pub align: usize
+}
+
+/// what the init supervisor does when a boot module exits
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum RestartPolicy {
+    #[default]
+    Never,
+    /// restart on a non-zero exit code, faults exit with 128 + signal
+    OnCrash,
+    Always,
+}
+
+impl RestartPolicy {
+    /// `never`, `on-crash` or `always`
+    pub fn parse(value: &str) -> Option<Self> {
+        Some(match value {
+            "never" => RestartPolicy::Never,
+            "on-crash" => RestartPolicy::OnCrash,
+            "always" => RestartPolicy::Always,
+            _ => return None,
+        })
+    }
+}
+
+/// elf loaded by the bootloader and started by the init supervisor in its own context
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct BootModule {
+    // 物理地址，内核直接映射了物理内存
+    pub base: u64,
+    pub len: usize,
+    // file name without directories, truncated
+    pub name: [u8; BOOT_MODULE_NAME_LEN],
+    pub name_len: usize,
+    pub restart: RestartPolicy,
+}
+
+impl BootModule {
+    pub const EMPTY: Self = Self { base: 0, len: 0, name: [0; BOOT_MODULE_NAME_LEN], name_len: 0, restart: RestartPolicy::Never };
+
+    /// `path` is shortened to its file name
+    pub fn new(bytes: &[u8], path: &str, restart: RestartPolicy) -> Self {
+        let file_name = path.rsplit(['/', '\\']).next().unwrap_or(path);
+        // cut at a char boundary
+        let name_len = (0..=file_name.len().min(BOOT_MODULE_NAME_LEN))
+            .rev()
+            .find(|len| file_name.is_char_boundary(*len))
+            .unwrap_or(0);
+        let mut name = [0; BOOT_MODULE_NAME_LEN];
+        name[..name_len].copy_from_slice(&file_name.as_bytes()[..name_len]);
+        Self { base: bytes.as_ptr() as u64, len: bytes.len(), name, name_len, restart }
+    }
+
+    pub fn name(&self) -> &str {
+        core::str::from_utf8(&self.name[..self.name_len]).unwrap_or("module")
+    }
+}
+
+/// first bytes of [`KernelArg`], at the same place in every version so a kernel
+/// can tell it was booted by a bootloader built from other sources
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct KernelArgHeader {
+    pub magic: u64,
+    pub version: u32,
+    // fnv-1a of the whole arg with this field zeroed, padding included
+    pub checksum: u32,
+    pub layout_hash: u64,
+    pub size: u64,
+}
+
+pub const KERNEL_ARG_MAGIC: u64 = u64::from_le_bytes(*b"MINIARG\0");
+// bump when a field changes meaning without changing the layout
+pub const KERNEL_ARG_VERSION: u32 = 1;
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+const fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    let mut i = 0;
+    while i < bytes.len() {
+        hash ^= bytes[i] as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+        i += 1;
+    }
+    hash
+}
+
+// size, alignment and field offsets of every type, in order
+macro_rules! layout_hash {
+    ($($ty:ty => [$($field:ident),*]),* $(,)?) => {{
+        let mut hash = FNV_OFFSET;
+        $(
+            hash = fnv1a(hash, &(size_of::<$ty>() as u64).to_le_bytes());
+            hash = fnv1a(hash, &(align_of::<$ty>() as u64).to_le_bytes());
+            $(hash = fnv1a(hash, &(offset_of!($ty, $field) as u64).to_le_bytes());)*
+        )*
+        hash
+    }};
+}
+
+/// changes with any field of [`KernelArg`] or the types inside it moving,
+/// bootloader and kernel compare the values they were built with
+pub const KERNEL_ARG_LAYOUT_HASH: u64 = layout_hash!(
+    KernelArgHeader => [magic, version, checksum, layout_hash, size],
+    KernelArg => [
+        header, kernel_virt_space_offset, gdt_start_addr, kernel_pml4_start_addr, acpi,
+        stack_top_addr, stack_size, context_stack_size, ap_stack_size,
+        framebuffer_addr, framebuffer_len, framebuffer_width, framebuffer_height, framebuffer_stride,
+        framebuffer_pixel_format, framebuffer_pixel_mask, framebuffer_bytes_per_pixel, framebuffer_font,
+        phys_mem_mapped_addr, phys_mem_size, unav_phys_mem_regions, unav_phys_mem_regions_len,
+        low_mem_regions, low_mem_regions_len, bootstrap_base, bootstrap_len, interp_base, interp_len,
+        modules, modules_len, resume_base, resume_len, tls_template, cmdline, cmdline_len
+    ],
+    AcpiSettings => [
+        local_apic_base, madt_table_addr, local_apic_count, io_apic_count, interrupt_src_override_count,
+        nmi_source_count, reset_register, flags
+    ],
+    AcpiResetRegister => [address_space, address, value],
+    MemoryRegion => [start, length, kind],
+    MemoryRegionKind => [],
+    BootModule => [base, len, name, name_len, restart],
+    TlsTemplate => [start_virt_addr, mem_size, file_size, align],
+    FontConfig => [],
+    PixelMask => [red, green, blue, reserved],
+    MadtLocalApic => [id, processor_id],
+    MadtIoApic => [id, address, gsi_base],
+    MadtInterruptSrcOverride => [bus_source, irq_source, gsi, flags],
+    MadtNmiSource => [gsi, flags],
+);
+
+/// why a [`KernelArg`] is rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KernelArgError {
+    BadMagic(u64),
+    Version { found: u32, expected: u32 },
+    Layout { found: u64, expected: u64 },
+    Size { found: u64, expected: u64 },
+    Checksum { found: u32, expected: u32 },
+}
+
+impl fmt::Display for KernelArgError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KernelArgError::BadMagic(magic) => write!(f, "bad magic {:#018x}, not a kernel arg", magic),
+            KernelArgError::Version { found, expected } =>
+                write!(f, "version {} from bootloader, kernel expects {}", found, expected),
+            KernelArgError::Layout { found, expected } =>
+                write!(f, "layout hash {:#018x} from bootloader, kernel expects {:#018x}", found, expected),
+            KernelArgError::Size { found, expected } =>
+                write!(f, "size {} from bootloader, kernel expects {}", found, expected),
+            KernelArgError::Checksum { found, expected } =>
+                write!(f, "checksum {:#010x} does not match content {:#010x}", found, expected),
+        }
+    }
 }
 
 #[repr(C)]
 #[derive(Debug, Clone, Copy)]
 pub struct KernelArg {
+    // magic, version and checksum, must stay the first field
+    pub header: KernelArgHeader,
     // kerrnel 的定义虚拟地址空间与实际虚拟地址空间的偏移
     pub kernel_virt_space_offset: i128,
 
@@ -72,6 +295,10 @@ pub struct KernelArg {
     pub stack_top_addr: u64,
     // 栈大小
     pub stack_size: usize,
+    // 每个 context 的内核栈大小
+    pub context_stack_size: usize,
+    // AP 启动栈大小
+    pub ap_stack_size: usize,
 
     // framebuffer 起始虚拟地址
     pub framebuffer_addr: u64,
@@ -80,38 +307,225 @@ pub struct KernelArg {
     pub framebuffer_width: usize,
     pub framebuffer_height: usize,
     pub framebuffer_stride: usize,
+    // FBPixelFormat 的 bits，gop 的像素格式
+    pub framebuffer_pixel_format: u32,
+    pub framebuffer_pixel_mask: PixelMask,
+    pub framebuffer_bytes_per_pixel: usize,
+    // console 字体
+    pub framebuffer_font: FontConfig,
 
     // 实际物理地址空间起始虚拟地址
     pub phys_mem_mapped_addr: u64,
     pub phys_mem_size: u64,
     pub unav_phys_mem_regions: [MemoryRegion; 512],
     pub unav_phys_mem_regions_len: usize,
+    // 1 MiB 以下可用的物理内存区域
+    pub low_mem_regions: [MemoryRegion; MAX_LOW_MEM_REGIONS],
+    pub low_mem_regions_len: usize,
 
     // bootstrap
     pub bootstrap_base: u64,
     pub bootstrap_len: usize,
+    // bootstrap 的动态链接器 (PT_INTERP)，物理地址，interp_len 为 0 表示没有
+    pub interp_base: u64,
+    pub interp_len: usize,
+    pub modules: [BootModule; MAX_BOOT_MODULES],
+    pub modules_len: usize,
+    // 休眠镜像 (boot.cfg 的 resume)，物理地址，resume_len 为 0 表示正常启动
+    pub resume_base: u64,
+    pub resume_len: usize,
 
-    pub tls_template: TlsTemplate
+    pub tls_template: TlsTemplate,
+
+    // 内核命令行，utf-8
+    pub cmdline: [u8; MAX_CMDLINE_LEN],
+    pub cmdline_len: usize,
 }
 
+impl KernelArg {
+    pub const HEADER: KernelArgHeader = KernelArgHeader {
+        magic: KERNEL_ARG_MAGIC,
+        version: KERNEL_ARG_VERSION,
+        checksum: 0,
+        layout_hash: KERNEL_ARG_LAYOUT_HASH,
+        size: size_of::<KernelArg>() as u64,
+    };
+
+    fn compute_checksum(&self) -> u32 {
+        // the arg is sealed and checked in place, padding bytes stay as they are in between
+        let bytes = unsafe { slice::from_raw_parts(self as *const _ as *const u8, size_of::<Self>()) };
+        let checksum = offset_of!(KernelArg, header) + offset_of!(KernelArgHeader, checksum);
+        let hash = fnv1a(FNV_OFFSET, &bytes[..checksum]);
+        let hash = fnv1a(hash, &[0; size_of::<u32>()]);
+        let hash = fnv1a(hash, &bytes[checksum + size_of::<u32>()..]);
+        (hash ^ hash >> 32) as u32
+    }
+
+    /// fill in the header once every other field is final, at the address the kernel reads
+    pub fn seal(&mut self) {
+        self.header = Self::HEADER;
+        self.header.checksum = self.compute_checksum();
+    }
 
+    /// check the header before trusting any other field
+    pub fn verify(&self) -> Result<(), KernelArgError> {
+        let header = self.header;
+        if header.magic != KERNEL_ARG_MAGIC {
+            return Err(KernelArgError::BadMagic(header.magic));
+        }
+        if header.version != KERNEL_ARG_VERSION {
+            return Err(KernelArgError::Version { found: header.version, expected: KERNEL_ARG_VERSION });
+        }
+        if header.layout_hash != KERNEL_ARG_LAYOUT_HASH {
+            return Err(KernelArgError::Layout { found: header.layout_hash, expected: KERNEL_ARG_LAYOUT_HASH });
+        }
+        if header.size != size_of::<KernelArg>() as u64 {
+            return Err(KernelArgError::Size { found: header.size, expected: size_of::<KernelArg>() as u64 });
+        }
+        let checksum = self.compute_checksum();
+        if header.checksum != checksum {
+            return Err(KernelArgError::Checksum { found: header.checksum, expected: checksum });
+        }
+        Ok(())
+    }
+}
+
+
+/// madt entries, or the equivalent intel mp table entries on machines without
+/// acpi, are copied by bootloader into their own pages, sized by the machine
+/// instead of a fixed cpu limit. the pages are identity mapped with the
+/// rest of physical memory, see [`MadtTableLayout`] for the layout.
 #[repr(C)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Default)]
 pub struct AcpiSettings {
     pub local_apic_base: usize,
-    pub local_apic: [MadtLocalApic; MAX_CPUS],
+    // physical address of madt table pages, 0 without madt and mp table
+    pub madt_table_addr: u64,
     pub local_apic_count: usize,
-    pub io_apic: [MadtIoApic; MAX_CPUS],
     pub io_apic_count: usize,
-    pub interrupt_src_override: [MadtInterruptSrcOverride; MAX_CPUS],
-    pub interrupt_src_override_count: usize
+    pub interrupt_src_override_count: usize,
+    pub nmi_source_count: usize,
+    // fadt reset register, `address_space` is 0xff if unsupported
+    pub reset_register: AcpiResetRegister,
+    // what firmware reported, `AcpiSettings::*` bits
+    pub flags: u32,
+}
+
+/// register written with `value` to reset the machine, acpi generic address subset
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct AcpiResetRegister {
+    // 0 system memory, 1 system io, 2 pci config, 0xff none
+    pub address_space: u8,
+    pub address: u64,
+    pub value: u8,
+}
+
+impl AcpiResetRegister {
+    pub const SYSTEM_MEMORY: u8 = 0;
+    pub const SYSTEM_IO: u8 = 1;
+    pub const PCI_CONFIG: u8 = 2;
+    pub const NONE: u8 = 0xff;
+}
+
+impl Default for AcpiResetRegister {
+    fn default() -> Self {
+        Self { address_space: Self::NONE, address: 0, value: 0 }
+    }
+}
+
+impl AcpiSettings {
+    // rsdp is found and its tables are parsed
+    pub const ACPI: u32 = 1 << 0;
+    pub const FADT: u32 = 1 << 1;
+    pub const MADT: u32 = 1 << 2;
+    // cpus and io apics come from the intel mp table, there is no madt
+    pub const MP_TABLE: u32 = 1 << 3;
+    // the bsp has a local apic, `local_apic_base` is valid
+    pub const LOCAL_APIC: u32 = 1 << 4;
+
+    pub fn has(&self, flag: u32) -> bool {
+        self.flags & flag == flag
+    }
+
+    /// physical address of the local apic registers, `local_apic_base` is the
+    /// whole IA32_APIC_BASE msr with its flag bits in the low 12 bits
+    pub fn local_apic_phys(&self) -> u64 {
+        self.local_apic_base as u64 & !0xfff
+    }
+
+    pub fn madt_table_layout(&self) -> MadtTableLayout {
+        MadtTableLayout::new(self.local_apic_count, self.io_apic_count, self.interrupt_src_override_count, self.nmi_source_count)
+    }
+
+    /// # Safety
+    /// physical memory must be mapped at `phys_offset`
+    pub unsafe fn local_apics(&self, phys_offset: u64) -> &'static [MadtLocalApic] {
+        self.entries(phys_offset, 0, self.local_apic_count)
+    }
+
+    /// # Safety
+    /// physical memory must be mapped at `phys_offset`
+    pub unsafe fn io_apics(&self, phys_offset: u64) -> &'static [MadtIoApic] {
+        self.entries(phys_offset, self.madt_table_layout().io_apic_offset, self.io_apic_count)
+    }
+
+    /// # Safety
+    /// physical memory must be mapped at `phys_offset`
+    pub unsafe fn interrupt_src_overrides(&self, phys_offset: u64) -> &'static [MadtInterruptSrcOverride] {
+        self.entries(phys_offset, self.madt_table_layout().interrupt_src_override_offset, self.interrupt_src_override_count)
+    }
+
+    /// # Safety
+    /// physical memory must be mapped at `phys_offset`
+    pub unsafe fn nmi_sources(&self, phys_offset: u64) -> &'static [MadtNmiSource] {
+        self.entries(phys_offset, self.madt_table_layout().nmi_source_offset, self.nmi_source_count)
+    }
+
+    unsafe fn entries<T>(&self, phys_offset: u64, offset: usize, count: usize) -> &'static [T] {
+        if self.madt_table_addr == 0 || count == 0 {
+            return &[];
+        }
+        slice::from_raw_parts((phys_offset + self.madt_table_addr + offset as u64) as *const T, count)
+    }
+}
+
+/// `[MadtLocalApic; n] [MadtIoApic; m] [MadtInterruptSrcOverride; k] [MadtNmiSource; l]`,
+/// each array aligned for its entry
+#[derive(Debug, Clone, Copy)]
+pub struct MadtTableLayout {
+    pub io_apic_offset: usize,
+    pub interrupt_src_override_offset: usize,
+    pub nmi_source_offset: usize,
+    pub len: usize,
+}
+
+impl MadtTableLayout {
+    pub const fn new(local_apics: usize, io_apics: usize, interrupt_src_overrides: usize, nmi_sources: usize) -> Self {
+        let io_apic_offset = align_up(local_apics * size_of::<MadtLocalApic>(), align_of::<MadtIoApic>());
+        let interrupt_src_override_offset = align_up(
+            io_apic_offset + io_apics * size_of::<MadtIoApic>(),
+            align_of::<MadtInterruptSrcOverride>()
+        );
+        let nmi_source_offset = align_up(
+            interrupt_src_override_offset + interrupt_src_overrides * size_of::<MadtInterruptSrcOverride>(),
+            align_of::<MadtNmiSource>()
+        );
+        let len = nmi_source_offset + nmi_sources * size_of::<MadtNmiSource>();
+        Self { io_apic_offset, interrupt_src_override_offset, nmi_source_offset, len }
+    }
+}
+
+const fn align_up(value: usize, align: usize) -> usize {
+    (value + align - 1) / align * align
 }
 
 #[repr(C)]
 #[derive(Debug, Clone, Copy, Default)]
 pub struct MadtLocalApic {
-    pub id: u8,
-    pub processor_id: u8
+    // 8-bit for local apic entries, 32-bit for local x2apic entries
+    pub id: u32,
+    pub processor_id: u32
 }
 
 #[repr(C)]
@@ -131,16 +545,10 @@ pub struct MadtInterruptSrcOverride {
     pub flags: u16,
 }
 
-impl Default for AcpiSettings {
-    fn default() -> Self {
-        Self {
-            local_apic_base: Default::default(),
-            local_apic: [Default::default(); MAX_CPUS],
-            local_apic_count: Default::default(),
-            io_apic: [Default::default(); MAX_CPUS],
-            io_apic_count: Default::default(),
-            interrupt_src_override: [Default::default(); MAX_CPUS],
-            interrupt_src_override_count: Default::default()
-        }
-    }
-}
\ No newline at end of file
+/// gsi wired to nmi, `flags` are mps inti flags like the source overrides
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MadtNmiSource {
+    pub gsi: u32,
+    pub flags: u16,
+}
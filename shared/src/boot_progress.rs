@@ -0,0 +1,119 @@
+use core::ptr;
+use log::info;
+use noto_sans_mono_bitmap::{get_raster, FontWeight, RasterHeight};
+use crate::framebuffer::Framebuffer;
+
+// framebuffer text writers leave this strip at the bottom of screen to the progress bar
+pub const PROGRESS_STRIP_HEIGHT: usize = 40;
+
+const STRIP_PADDING: usize = 16;
+const LABEL_TOP: usize = 4;
+const BAR_TOP: usize = 24;
+const BAR_HEIGHT: usize = 10;
+
+const BACKGROUND: (u8, u8, u8) = (0, 0, 0);
+const BORDER: (u8, u8, u8) = (0x60, 0x60, 0x60);
+const FILLED: (u8, u8, u8) = (0xe0, 0xe0, 0x70);
+
+/// boot stages shared by bootloader and kernel, in order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum BootStage {
+    Bootloader = 0,
+    Acpi,
+    LoadImages,
+    Memory,
+    KernelEntry,
+    Interrupts,
+    Smp,
+    Userspace,
+}
+
+impl BootStage {
+    pub const COUNT: usize = 8;
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            BootStage::Bootloader => "bootloader",
+            BootStage::Acpi => "acpi",
+            BootStage::LoadImages => "loading images",
+            BootStage::Memory => "memory",
+            BootStage::KernelEntry => "kernel",
+            BootStage::Interrupts => "interrupts",
+            BootStage::Smp => "smp",
+            BootStage::Userspace => "userspace",
+        }
+    }
+}
+
+/// report boot progress.
+///
+/// renders a progress bar to the bottom strip of `framebuffer`, falls back to
+/// text via logger if there is no usable framebuffer.
+pub fn report_boot_stage(framebuffer: Option<&Framebuffer>, stage: BootStage) {
+    let index = stage as usize + 1;
+
+    match framebuffer {
+        Some(fb) if drawable(fb) => draw_progress(fb, stage.name(), index),
+        _ => info!("boot progress [{}/{}]: {}", index, BootStage::COUNT, stage.name()),
+    }
+}
+
+fn drawable(fb: &Framebuffer) -> bool {
+    fb.drawable()
+        && fb.height > PROGRESS_STRIP_HEIGHT
+        && fb.width > STRIP_PADDING * 2
+}
+
+fn draw_progress(fb: &Framebuffer, label: &str, index: usize) {
+    let top = fb.height - PROGRESS_STRIP_HEIGHT;
+    let left = STRIP_PADDING;
+    let right = fb.width - STRIP_PADDING;
+
+    fill_rect(fb, 0, top, fb.width, fb.height, BACKGROUND);
+
+    // label
+    let mut x = left;
+    for c in label.chars() {
+        let Some(raster) = get_raster(c, FontWeight::Regular, RasterHeight::Size16) else { continue };
+        for (dy, row) in raster.raster().iter().enumerate() {
+            for (dx, intensity) in row.iter().enumerate() {
+                if x + dx < right {
+                    write_pixel(fb, x + dx, top + LABEL_TOP + dy, (*intensity, *intensity, *intensity));
+                }
+            }
+        }
+        x += raster.width();
+    }
+
+    // bar
+    let bar_top = top + BAR_TOP;
+    let bar_bottom = bar_top + BAR_HEIGHT;
+    fill_rect(fb, left, bar_top, right, bar_bottom, BORDER);
+    fill_rect(fb, left + 1, bar_top + 1, right - 1, bar_bottom - 1, BACKGROUND);
+
+    let filled_width = (right - left - 2) * index.min(BootStage::COUNT) / BootStage::COUNT;
+    fill_rect(fb, left + 1, bar_top + 1, left + 1 + filled_width, bar_bottom - 1, FILLED);
+}
+
+fn fill_rect(fb: &Framebuffer, x0: usize, y0: usize, x1: usize, y1: usize, color: (u8, u8, u8)) {
+    for y in y0..y1 {
+        for x in x0..x1 {
+            write_pixel(fb, x, y, color);
+        }
+    }
+}
+
+fn write_pixel(fb: &Framebuffer, x: usize, y: usize, (r, g, b): (u8, u8, u8)) {
+    let bytes_per_pixel = fb.bytes_per_pixel;
+    let byte_offset = fb.pixel_offset(x, y);
+    if byte_offset + bytes_per_pixel > fb.len {
+        return;
+    }
+
+    let color = fb.encode(r, g, b);
+    // SAFETY: offset is checked above
+    unsafe {
+        ptr::copy_nonoverlapping(color.as_ptr(), fb.ptr.add(byte_offset), bytes_per_pixel);
+    }
+}
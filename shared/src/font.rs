@@ -0,0 +1,70 @@
+use noto_sans_mono_bitmap::{FontWeight, RasterHeight};
+
+/**
+ *  console font selection, from `font_size=` / `font_weight=` of boot.cfg.
+ *
+ *  passed to the kernel in [`KernelArg`](crate::arg::KernelArg), so bootloader
+ *  and kernel consoles look the same.
+ */
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FontSize {
+    /// picked by framebuffer height, see [`FontConfig::raster_height`]
+    Auto,
+    Size16,
+    Size20,
+    Size24,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FontConfig {
+    pub size: FontSize,
+    pub bold: bool,
+}
+
+impl Default for FontConfig {
+    fn default() -> Self {
+        Self { size: FontSize::Auto, bold: false }
+    }
+}
+
+impl FontConfig {
+    pub fn parse_size(value: &str) -> Option<FontSize> {
+        Some(match value {
+            "auto" => FontSize::Auto,
+            "16" => FontSize::Size16,
+            "20" => FontSize::Size20,
+            "24" => FontSize::Size24,
+            _ => return None,
+        })
+    }
+
+    /// `regular` or `bold`, returns whether bold
+    pub fn parse_weight(value: &str) -> Option<bool> {
+        match value {
+            "regular" => Some(false),
+            "bold" => Some(true),
+            _ => None,
+        }
+    }
+
+    pub fn raster_height(&self, framebuffer_height: usize) -> RasterHeight {
+        match self.size {
+            FontSize::Size16 => RasterHeight::Size16,
+            FontSize::Size20 => RasterHeight::Size20,
+            FontSize::Size24 => RasterHeight::Size24,
+            // keep roughly the same number of lines on high-dpi modes
+            FontSize::Auto => match framebuffer_height {
+                1440.. => RasterHeight::Size24,
+                1080.. => RasterHeight::Size20,
+                _ => RasterHeight::Size16,
+            },
+        }
+    }
+
+    pub fn weight(&self) -> FontWeight {
+        if self.bold { FontWeight::Bold } else { FontWeight::Regular }
+    }
+}
@@ -11,6 +11,9 @@ pub struct Framebuffer {
     pub height: usize,
     pub stride: usize,
     pub pixel_format: FBPixelFormat,
+    // channel bits of a pixel, also filled in for RGB and BGR
+    pub pixel_mask: PixelMask,
+    pub bytes_per_pixel: usize,
 }
 
 unsafe impl Sync for Framebuffer {}
@@ -21,16 +24,82 @@ bitflags! {
     pub struct FBPixelFormat: u32 {
         const RGB = 1 << 0;
         const BGR = 1 << 1;
+        // channels at the bits of `PixelMask`, gop PixelBitMask
+        const BITMASK = 1 << 2;
+    }
+}
+
+/// bits of each channel in a little endian pixel, as gop reports them
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PixelMask {
+    pub red: u32,
+    pub green: u32,
+    pub blue: u32,
+    pub reserved: u32,
+}
+
+impl PixelMask {
+    pub const RGB: PixelMask = PixelMask { red: 0xff, green: 0xff00, blue: 0xff_0000, reserved: 0xff00_0000 };
+    pub const BGR: PixelMask = PixelMask { red: 0xff_0000, green: 0xff00, blue: 0xff, reserved: 0xff00_0000 };
+
+    /// the highest bit of any mask sets the size of a pixel, 0 without any bits
+    pub const fn bytes_per_pixel(&self) -> usize {
+        let bits = u32::BITS - (self.red | self.green | self.blue | self.reserved).leading_zeros();
+        bits.div_ceil(8) as usize
+    }
+
+    // scale an 8 bit channel to the bits of `mask`, masks are contiguous runs of bits
+    const fn channel(value: u8, mask: u32) -> u32 {
+        if mask == 0 {
+            return 0;
+        }
+        let shift = mask.trailing_zeros();
+        let max = (mask >> shift) as u64;
+        ((value as u64 * max / 0xff) as u32) << shift
     }
 }
 
 impl Framebuffer {
-    pub fn new(ptr: *mut u8, len: usize, width: usize, height: usize, stride: usize, pixel_format: FBPixelFormat) -> Self {
-        Self { ptr, len, width, height, stride, pixel_format }
+    pub fn new(ptr: *mut u8, len: usize, width: usize, height: usize, stride: usize, pixel_format: FBPixelFormat, pixel_mask: PixelMask) -> Self {
+        let pixel_mask = match pixel_format {
+            FBPixelFormat::RGB => PixelMask::RGB,
+            FBPixelFormat::BGR => PixelMask::BGR,
+            _ => pixel_mask,
+        };
+        Self { ptr, len, width, height, stride, pixel_format, pixel_mask, bytes_per_pixel: pixel_mask.bytes_per_pixel() }
     }
 
     pub fn slice(&self) -> &'static mut [u8] {
         // SAFETY: we ensure that `self.ptr` is the pointer points to the framebuffer u8 array
         unsafe { slice::from_raw_parts_mut(self.ptr, self.len) }
     }
-}
\ No newline at end of file
+
+    /// whether writers can draw to it: a known format, 1 to 4 bytes a pixel
+    /// and rows that fit in the buffer
+    pub fn drawable(&self) -> bool {
+        let known = [FBPixelFormat::RGB, FBPixelFormat::BGR, FBPixelFormat::BITMASK].contains(&self.pixel_format);
+        known && (1..=4).contains(&self.bytes_per_pixel)
+            && self.stride >= self.width
+            && self.stride.checked_mul(self.height).and_then(|pixels| pixels.checked_mul(self.bytes_per_pixel))
+                .is_some_and(|bytes| bytes <= self.len)
+    }
+
+    /// byte offset of pixel (`x`, `y`)
+    pub fn pixel_offset(&self, x: usize, y: usize) -> usize {
+        (y * self.stride + x) * self.bytes_per_pixel
+    }
+
+    /// bytes of a pixel of color (`r`, `g`, `b`), the first `bytes_per_pixel` of them are used
+    pub fn encode(&self, r: u8, g: u8, b: u8) -> [u8; 4] {
+        match self.pixel_format {
+            FBPixelFormat::RGB => [r, g, b, 0],
+            FBPixelFormat::BGR => [b, g, r, 0],
+            _ => {
+                let mask = &self.pixel_mask;
+                let pixel = PixelMask::channel(r, mask.red) | PixelMask::channel(g, mask.green) | PixelMask::channel(b, mask.blue);
+                pixel.to_le_bytes()
+            }
+        }
+    }
+}
@@ -1,6 +1,6 @@
 use core::{fmt, slice};
 
-use crate::{framebuffer::{Framebuffer, FBPixelFormat}, print_panic::PrintPanic};
+use crate::{boot_progress::PROGRESS_STRIP_HEIGHT, font::FontConfig, framebuffer::Framebuffer, print_panic::PrintPanic};
 use noto_sans_mono_bitmap::{
     get_raster, get_raster_width, FontWeight, RasterHeight, RasterizedChar,
 };
@@ -10,7 +10,58 @@ const LINE_SPACING: usize = 2;
 const LETTER_SPACING: usize = 0;
 
 const BORDER_PADDING: usize = 1;
+// tab stops every this many cells
+const TAB_WIDTH: usize = 8;
 
+// at most this many parameters of a csi sequence are kept, the rest are dropped
+const MAX_SGR_PARAMS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Color {
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
+        Self { r, g, b }
+    }
+}
+
+// 原来的默认颜色，偏黄的白
+pub const DEFAULT_FOREGROUND: Color = Color::new(0xff, 0xff, 0x7f);
+
+// sgr 30..=37
+const PALETTE: [Color; 8] = [
+    Color::new(0x00, 0x00, 0x00),
+    Color::new(0xcd, 0x31, 0x31),
+    Color::new(0x0d, 0xbc, 0x79),
+    Color::new(0xe5, 0xe5, 0x10),
+    Color::new(0x24, 0x72, 0xc8),
+    Color::new(0xbc, 0x3f, 0xbc),
+    Color::new(0x11, 0xa8, 0xcd),
+    Color::new(0xe5, 0xe5, 0xe5),
+];
+// sgr 90..=97
+const BRIGHT_PALETTE: [Color; 8] = [
+    Color::new(0x66, 0x66, 0x66),
+    Color::new(0xf1, 0x4c, 0x4c),
+    Color::new(0x23, 0xd1, 0x8b),
+    Color::new(0xf5, 0xf5, 0x43),
+    Color::new(0x3b, 0x8e, 0xea),
+    Color::new(0xd6, 0x70, 0xd6),
+    Color::new(0x29, 0xb8, 0xdb),
+    Color::new(0xff, 0xff, 0xff),
+];
+
+// parser of escape sequences, only sgr (`ESC [ ... m`) has an effect
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EscapeState {
+    Normal,
+    Escape,
+    Csi,
+}
 
 pub struct FrameBufferWriter<'a> {
     framebuffer: &'a Framebuffer,
@@ -19,23 +70,44 @@ pub struct FrameBufferWriter<'a> {
     curr_x_pos: usize,
     curr_y_pos: usize,
 
+    raster_height: RasterHeight,
+    // weight of the config, sgr 22 goes back to it
+    base_bold: bool,
+
+    foreground: Color,
+    bold: bool,
+
+    escape: EscapeState,
+    params: [u16; MAX_SGR_PARAMS],
+    param_count: usize,
 }
 
 impl <'a> FrameBufferWriter<'a> {
     /// Creates a new logger that uses the given framebuffer.
     pub fn new(framebuffer: &'a Framebuffer) -> Self {
+        Self::with_font(framebuffer, FontConfig::default())
+    }
+
+    pub fn with_font(framebuffer: &'a Framebuffer, font: FontConfig) -> Self {
         let mut writer = Self {
             framebuffer,
             buffer_slice: unsafe { slice::from_raw_parts_mut(framebuffer.ptr, framebuffer.len) },
             curr_x_pos: 0,
             curr_y_pos: 0,
+            raster_height: font.raster_height(framebuffer.height),
+            base_bold: font.bold,
+            foreground: DEFAULT_FOREGROUND,
+            bold: font.bold,
+            escape: EscapeState::Normal,
+            params: [0; MAX_SGR_PARAMS],
+            param_count: 0,
         };
         writer.clear();
         writer
     }
 
     fn newline(&mut self) {
-        self.curr_y_pos += RasterHeight::Size16.val() + LINE_SPACING;
+        self.curr_y_pos += self.raster_height.val() + LINE_SPACING;
         self.carriage_return()
     }
 
@@ -46,27 +118,164 @@ impl <'a> FrameBufferWriter<'a> {
     pub fn clear(&mut self) {
         self.curr_x_pos = BORDER_PADDING;
         self.curr_y_pos = BORDER_PADDING;
-        self.buffer_slice.fill(0);
+        // keep boot progress strip
+        let text_area_len = self.text_area_height() * self.framebuffer.stride * self.framebuffer.bytes_per_pixel;
+        let len = self.buffer_slice.len().min(text_area_len);
+        self.buffer_slice[..len].fill(0);
+    }
+
+    /// back to default color and configured weight
+    pub fn reset_style(&mut self) {
+        self.foreground = DEFAULT_FOREGROUND;
+        self.bold = self.base_bold;
+    }
+
+    fn text_area_height(&self) -> usize {
+        self.framebuffer.height.saturating_sub(PROGRESS_STRIP_HEIGHT)
+    }
+
+    fn font_weight(&self) -> FontWeight {
+        if self.bold { FontWeight::Bold } else { FontWeight::Regular }
+    }
+
+    // all glyphs are as wide as one cell, the font is monospace
+    fn cell_width(&self) -> usize {
+        get_raster_width(self.font_weight(), self.raster_height) + LETTER_SPACING
     }
 
     fn write_char(&mut self, c: char) {
+        match self.escape {
+            EscapeState::Normal => {}
+            EscapeState::Escape => {
+                self.escape = if c == '[' { EscapeState::Csi } else { EscapeState::Normal };
+                self.params = [0; MAX_SGR_PARAMS];
+                self.param_count = 0;
+                return;
+            }
+            EscapeState::Csi => {
+                self.csi_char(c);
+                return;
+            }
+        }
+
         match c {
+            '\x1b' => self.escape = EscapeState::Escape,
             '\n' => self.newline(),
             '\r' => self.carriage_return(),
+            // backspace erases the cell before the cursor, never across lines
+            '\x08' => {
+                let cell = self.cell_width();
+                if self.curr_x_pos >= BORDER_PADDING + cell {
+                    self.curr_x_pos -= cell;
+                    self.erase_cell();
+                }
+            }
+            '\t' => {
+                let cell = self.cell_width();
+                let column = (self.curr_x_pos - BORDER_PADDING) / cell;
+                let next_stop = (column / TAB_WIDTH + 1) * TAB_WIDTH;
+                self.curr_x_pos = BORDER_PADDING + next_stop * cell;
+                if self.curr_x_pos >= self.framebuffer.width {
+                    self.newline();
+                }
+            }
             c => {
-                let new_xpos = self.curr_x_pos +  get_raster_width(FontWeight::Regular, RasterHeight::Size16);
+                let glyph = get_raster(c, self.font_weight(), self.raster_height);
+                // the font has no cjk, a wide char still takes two cells so columns line up
+                let cells = if glyph.is_none() && is_wide(c) { 2 } else { 1 };
+
+                let new_xpos = self.curr_x_pos + cells * self.cell_width();
                 if new_xpos >= self.framebuffer.width {
                     self.newline();
                 }
-                let new_ypos = self.curr_y_pos + RasterHeight::Size16.val() + BORDER_PADDING;
-                if new_ypos >= self.framebuffer.height {
+                let new_ypos = self.curr_y_pos + self.raster_height.val() + BORDER_PADDING;
+                if new_ypos >= self.text_area_height() {
                     self.clear();
                 }
-                self.write_rendered_char(get_raser_or_fallback(c));
+                match glyph {
+                    Some(glyph) => self.write_rendered_char(glyph),
+                    None if cells == 2 => self.write_wide_placeholder(),
+                    None => self.write_rendered_char(self.fallback_char()),
+                }
+            }
+        }
+    }
+
+    fn fallback_char(&self) -> RasterizedChar {
+        get_raster('\u{FFFD}', self.font_weight(), self.raster_height)
+            .or_panic("failed to get fallback char")
+    }
+
+    fn erase_cell(&mut self) {
+        let line_height = self.raster_height.val() + LINE_SPACING;
+        for y in self.curr_y_pos..self.curr_y_pos + line_height {
+            for x in self.curr_x_pos..self.curr_x_pos + self.cell_width() {
+                self.write_pixel(x, y, 0);
+            }
+        }
+    }
+
+    // hollow box over two cells
+    fn write_wide_placeholder(&mut self) {
+        let width = 2 * self.cell_width() - LETTER_SPACING;
+        let height = self.raster_height.val();
+        let (left, right) = (self.curr_x_pos + 1, self.curr_x_pos + width - 2);
+        let (top, bottom) = (self.curr_y_pos + 2, self.curr_y_pos + height - 3);
+        for x in left..=right {
+            self.write_pixel(x, top, 0xff);
+            self.write_pixel(x, bottom, 0xff);
+        }
+        for y in top..=bottom {
+            self.write_pixel(left, y, 0xff);
+            self.write_pixel(right, y, 0xff);
+        }
+        self.curr_x_pos += width + LETTER_SPACING;
+    }
+
+    fn csi_char(&mut self, c: char) {
+        match c {
+            '0'..='9' => {
+                // first digit opens the first parameter
+                if self.param_count == 0 {
+                    self.param_count = 1;
+                }
+                if let Some(param) = self.params.get_mut(self.param_count - 1) {
+                    *param = param.saturating_mul(10).saturating_add(c as u16 - '0' as u16);
+                }
+            }
+            ';' => {
+                // empty parameters count as 0
+                self.param_count = (self.param_count.max(1) + 1).min(MAX_SGR_PARAMS + 1);
             }
+            'm' => {
+                self.apply_sgr();
+                self.escape = EscapeState::Normal;
+            }
+            // final byte of a sequence we don't support
+            '\x40'..='\x7e' => self.escape = EscapeState::Normal,
+            _ => {}
         }
     }
 
+    fn apply_sgr(&mut self) {
+        // `ESC [ m` is a reset
+        if self.param_count == 0 {
+            self.reset_style();
+            return;
+        }
+        let count = self.param_count.min(MAX_SGR_PARAMS);
+        for i in 0..count {
+            match self.params[i] {
+                0 => self.reset_style(),
+                1 => self.bold = true,
+                22 => self.bold = false,
+                n @ 30..=37 => self.foreground = PALETTE[(n - 30) as usize],
+                39 => self.foreground = DEFAULT_FOREGROUND,
+                n @ 90..=97 => self.foreground = BRIGHT_PALETTE[(n - 90) as usize],
+                _ => {}
+            }
+        }
+    }
 
     fn write_rendered_char(&mut self, rendered_char: RasterizedChar) {
         for (y, row) in rendered_char.raster().iter().enumerate() {
@@ -78,29 +287,35 @@ impl <'a> FrameBufferWriter<'a> {
     }
 
     fn write_pixel(&mut self, x: usize, y: usize, intensity: u8) {
-        let pixel_offset = y * self.framebuffer.stride + x;
-        let color = match self.framebuffer.pixel_format {
-            FBPixelFormat::RGB => [intensity, intensity, intensity / 2, 0],
-            FBPixelFormat::BGR => [intensity / 2, intensity, intensity, 0],
-            other => {
-                panic!("pixel format {:?} not supported in logger", other)
-            }
-        };
-        let bytes_per_pixel = 4;
-        let byte_offset = pixel_offset * bytes_per_pixel;
+        let scale = |channel: u8| (channel as u16 * intensity as u16 / 0xff) as u8;
+        let color = self.framebuffer.encode(scale(self.foreground.r), scale(self.foreground.g), scale(self.foreground.b));
+        let bytes_per_pixel = self.framebuffer.bytes_per_pixel;
+        let byte_offset = self.framebuffer.pixel_offset(x, y);
         self.buffer_slice[byte_offset..(byte_offset + bytes_per_pixel)]
             .copy_from_slice(&color[..bytes_per_pixel]);
         let _ = unsafe { ptr::read_volatile(&self.buffer_slice[byte_offset]) };
     }
 }
 
-fn get_raser_or_fallback(c: char) -> RasterizedChar {
-    get_raster(c, FontWeight::Regular, RasterHeight::Size16)
-        .unwrap_or_else(|| get_raster('\u{FFFD}', FontWeight::Regular, RasterHeight::Size16)
-            .or_panic("failed to get char and its fallback")
-        )
+// east asian wide ranges: hangul jamo, cjk, hangul syllables, compatibility and fullwidth forms
+fn is_wide(c: char) -> bool {
+    matches!(c as u32,
+        0x1100..=0x115f | 0x2e80..=0xa4cf | 0xac00..=0xd7a3 | 0xf900..=0xfaff
+        | 0xfe30..=0xfe4f | 0xff00..=0xff60 | 0xffe0..=0xffe6 | 0x20000..=0x3fffd)
 }
 
+/// sgr sequence coloring a log line of `level`
+pub fn level_sgr(level: log::Level) -> &'static str {
+    match level {
+        log::Level::Error => "\x1b[1;31m",
+        log::Level::Warn => "\x1b[33m",
+        log::Level::Info => "\x1b[37m",
+        log::Level::Debug | log::Level::Trace => "\x1b[90m",
+    }
+}
+
+pub const SGR_RESET: &str = "\x1b[0m";
+
 unsafe impl Send for FrameBufferWriter<'_> {}
 unsafe impl Sync for FrameBufferWriter<'_> {}
 
@@ -0,0 +1,85 @@
+/**
+ *  layout of the kernel gdt, shared by bootloader and kernel.
+ *
+ *  the bootloader builds the first `BOOT_GDT_ENTRIES` entries and jumps to
+ *  the kernel on them, the kernel builds the whole table per cpu and reloads
+ *  the segment registers from it, so the selectors stay the same across the
+ *  handoff. both walk `GDT_LAYOUT` and take every selector from here.
+ *
+ *  syscall and sysret do not read the table, they derive selectors from the
+ *  bases in STAR: kernel data must follow kernel code, user data and then
+ *  user code must follow the sysret base.
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GdtEntry {
+    KernelCode,
+    KernelData,
+    /// compatibility mode code, the sysret base in STAR
+    KernelCode32,
+    UserData,
+    UserCode,
+    /// a system descriptor, takes two slots
+    Tss,
+}
+
+impl GdtEntry {
+    pub const fn slots(self) -> u16 {
+        if matches!(self, GdtEntry::Tss) { 2 } else { 1 }
+    }
+}
+
+/// entries in table order from index 1, index 0 is the null descriptor
+pub const GDT_LAYOUT: [GdtEntry; 6] = [
+    GdtEntry::KernelCode,
+    GdtEntry::KernelData,
+    GdtEntry::KernelCode32,
+    GdtEntry::UserData,
+    GdtEntry::UserCode,
+    GdtEntry::Tss,
+];
+
+/// leading entries of `GDT_LAYOUT` the bootloader builds
+pub const BOOT_GDT_ENTRIES: usize = 2;
+
+/// table index of `entry`
+pub const fn gdt_index(entry: GdtEntry) -> u16 {
+    let mut index = 1;
+    let mut i = 0;
+    while i < GDT_LAYOUT.len() {
+        if GDT_LAYOUT[i] as u8 == entry as u8 {
+            return index;
+        }
+        index += GDT_LAYOUT[i].slots();
+        i += 1;
+    }
+    panic!("entry is missing from the gdt layout");
+}
+
+/// selector of `entry` with requested privilege level `rpl`
+pub const fn selector(entry: GdtEntry, rpl: u16) -> u16 {
+    gdt_index(entry) << 3 | rpl
+}
+
+pub const KERNEL_CODE_SELECTOR: u16 = selector(GdtEntry::KernelCode, 0);
+pub const KERNEL_DATA_SELECTOR: u16 = selector(GdtEntry::KernelData, 0);
+pub const KERNEL_CODE32_SELECTOR: u16 = selector(GdtEntry::KernelCode32, 0);
+pub const USER_DATA_SELECTOR: u16 = selector(GdtEntry::UserData, 3);
+pub const USER_CODE_SELECTOR: u16 = selector(GdtEntry::UserCode, 3);
+pub const TSS_SELECTOR: u16 = selector(GdtEntry::Tss, 0);
+
+/// STAR[47:32], syscall loads cs from it and ss from the entry after
+pub const STAR_SYSCALL_BASE: u16 = KERNEL_CODE_SELECTOR;
+/// STAR[63:48], 64 bit sysret loads ss from the entry after and cs from the one after that
+pub const STAR_SYSRET_BASE: u16 = KERNEL_CODE32_SELECTOR | 3;
+
+// slots of the whole table, the null descriptor included
+const GDT_SLOTS: u16 = gdt_index(GdtEntry::Tss) + GdtEntry::Tss.slots();
+
+const _: () = assert!(KERNEL_DATA_SELECTOR == STAR_SYSCALL_BASE + 8);
+const _: () = assert!(USER_DATA_SELECTOR == STAR_SYSRET_BASE + 8);
+const _: () = assert!(USER_CODE_SELECTOR == STAR_SYSRET_BASE + 16);
+// the kernel entry runs on the bootloader's table until its gdt initcall
+const _: () = assert!(gdt_index(GdtEntry::KernelData) as usize <= BOOT_GDT_ENTRIES);
+// x86_64's GlobalDescriptorTable holds 8 slots
+const _: () = assert!(GDT_SLOTS <= 8);
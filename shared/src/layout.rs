@@ -0,0 +1,107 @@
+/**
+ *  virtual memory layout, every fixed address of the kernel and user address
+ *  spaces is defined here.
+ *
+ *  kernel regions own whole pml4 entries (512 GiB each) in the upper half,
+ *  the bootloader maps the first group and the kernel manages
+ *  `KERNEL_RUNTIME_P4` itself. pml4 entry 0 is the identity mapped physical
+ *  memory, shared by every address space. user address spaces put their fixed
+ *  regions at the top gigabytes of that same entry and hand out buffers from
+ *  `USER_BASE` upwards.
+ *
+ *  the image bases in kernel/build.rs and bootstrap/build.rs are linker
+ *  arguments and can not use these, keep them in sync with `KERNEL_BASE`.
+ */
+
+pub const PML4_ENTRY_SIZE: u64 = 1 << 39;
+pub const GIB: u64 = 1 << 30;
+
+/// canonical start address of pml4 entry `index`
+pub const fn p4_base(index: u16) -> u64 {
+    let addr = (index as u64) * PML4_ENTRY_SIZE;
+    // upper half is sign extended from bit 47
+    if index >= 256 { addr | 0xffff_0000_0000_0000 } else { addr }
+}
+
+// 内核 bytes 在 kernel pml4 page table 位置
+pub const KERNEL_BYTES_P4: u16 = 511;
+// bootstrap bytes 在 kernel pml4 page table 位置
+pub const BOOTSTRAP_BYTES_P4: u16 = 510;
+// 物理地址空间在 kernel pml4 page table 位置
+pub const PHYS_MEM_P4: u16 = 0;
+// 物理地址空间在高半部分的副本
+pub const PHYS_MEM_HIGH_P4: u16 = 256;
+// kernel 栈在 kernel pml4 page table 位置
+pub const KERNEL_STACK_P4: u16 = 509;
+// framebuffer 在 kernel pml4 page table 位置
+pub const FRAMEBUFFER_P4: u16 = 508;
+pub const KERNEL_ARG_P4: u16 = 507;
+// 以下 pml4 位置由内核运行时管理，bootloader 不使用，见 kernel/src/mem/kvm.rs
+// context kernel 栈
+pub const KERNEL_VSTACK_P4: u16 = 506;
+// vmalloc 非连续映射
+pub const KERNEL_VMALLOC_P4: u16 = 505;
+// mmio 映射
+pub const KERNEL_MMIO_P4: u16 = 504;
+// heap 扩展
+pub const KERNEL_HEAP_P4: u16 = 503;
+// per-cpu 区域
+pub const KERNEL_PERCPU_P4: u16 = 502;
+pub const KERNEL_RUNTIME_P4: core::ops::RangeInclusive<u16> = KERNEL_PERCPU_P4..=KERNEL_VSTACK_P4;
+
+pub const PHYS_MAP_BASE: u64 = p4_base(PHYS_MEM_P4);
+pub const PHYS_MAP_HIGH_BASE: u64 = p4_base(PHYS_MEM_HIGH_P4);
+pub const KERNEL_BASE: u64 = p4_base(KERNEL_BYTES_P4);
+pub const BOOTSTRAP_BASE: u64 = p4_base(BOOTSTRAP_BYTES_P4);
+pub const KERNEL_STACK_BASE: u64 = p4_base(KERNEL_STACK_P4);
+pub const FRAMEBUFFER_BASE: u64 = p4_base(FRAMEBUFFER_P4);
+pub const KERNEL_ARG_BASE: u64 = p4_base(KERNEL_ARG_P4);
+pub const KSTACK_BASE: u64 = p4_base(KERNEL_VSTACK_P4);
+pub const VMALLOC_BASE: u64 = p4_base(KERNEL_VMALLOC_P4);
+pub const MMIO_BASE: u64 = p4_base(KERNEL_MMIO_P4);
+pub const KHEAP_BASE: u64 = p4_base(KERNEL_HEAP_P4);
+pub const PERCPU_BASE: u64 = p4_base(KERNEL_PERCPU_P4);
+
+// end of canonical lower half
+pub const USER_SPACE_END: u64 = 0x0000_8000_0000_0000;
+// user buffers grow up from here, page 0 stays unmapped
+pub const USER_BASE: u64 = 0x1000;
+// dynamic loader, AddrspPageTable[0][509]
+pub const USER_INTERP_BASE: u64 = 509 * GIB;
+// kernel stack of the context mapped for userspace, AddrspPageTable[0][510]
+pub const USER_STACK_BASE: u64 = 510 * GIB;
+pub const USER_STACK_TOP: u64 = USER_STACK_BASE + GIB;
+// one slot of the stack region per context sharing the address space, the
+// owner takes the first at USER_STACK_BASE
+pub const USER_STACK_SLOTS: u64 = 64;
+pub const USER_STACK_SLOT_SIZE: u64 = (USER_STACK_TOP - USER_STACK_BASE) / USER_STACK_SLOTS;
+
+const KERNEL_P4: [u16; 12] = [
+    PHYS_MEM_P4, PHYS_MEM_HIGH_P4, KERNEL_BYTES_P4, BOOTSTRAP_BYTES_P4, KERNEL_STACK_P4, FRAMEBUFFER_P4, KERNEL_ARG_P4,
+    KERNEL_VSTACK_P4, KERNEL_VMALLOC_P4, KERNEL_MMIO_P4, KERNEL_HEAP_P4, KERNEL_PERCPU_P4,
+];
+
+const fn distinct(indices: &[u16]) -> bool {
+    let mut i = 0;
+    while i < indices.len() {
+        let mut j = i + 1;
+        while j < indices.len() {
+            if indices[i] == indices[j] {
+                return false;
+            }
+            j += 1;
+        }
+        i += 1;
+    }
+    true
+}
+
+// every kernel region owns its pml4 entry
+const _: () = assert!(distinct(&KERNEL_P4));
+// the runtime range holds exactly the five kvm regions
+const _: () = assert!(KERNEL_VSTACK_P4 - KERNEL_PERCPU_P4 + 1 == 5);
+// user regions are 1 GiB each, in order and inside pml4 entry 0 below the kernel half
+const _: () = assert!(USER_BASE < USER_INTERP_BASE);
+const _: () = assert!(USER_INTERP_BASE + GIB <= USER_STACK_BASE);
+const _: () = assert!(USER_STACK_TOP <= PHYS_MAP_BASE + PML4_ENTRY_SIZE);
+const _: () = assert!(PHYS_MAP_BASE + PML4_ENTRY_SIZE <= USER_SPACE_END);
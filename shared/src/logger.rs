@@ -0,0 +1,50 @@
+use core::fmt::{self, Write};
+
+use crate::font::FontConfig;
+use crate::framebuffer::Framebuffer;
+use crate::framebuffer_writer::{level_sgr, FrameBufferWriter, SGR_RESET};
+use crate::sync::IrqSafeSpinlock;
+
+/**
+ *  logger rendering every record to the framebuffer as it comes in.
+ *
+ *  lines are colored by level on screen and can be mirrored, without color, to
+ *  another sink, e.g. serial port of the bootloader.
+ *  the kernel logs through a ring buffer instead and only shares the writer.
+ */
+
+pub struct FramebufferLogger<'a> {
+    writer: IrqSafeSpinlock<FrameBufferWriter<'a>>,
+    mirror: Option<fn(fmt::Arguments)>,
+}
+
+impl<'a> FramebufferLogger<'a> {
+    pub fn new(framebuffer: &'a Framebuffer, font: FontConfig, mirror: Option<fn(fmt::Arguments)>) -> Self {
+        Self {
+            writer: IrqSafeSpinlock::new(FrameBufferWriter::with_font(framebuffer, font)),
+            mirror,
+        }
+    }
+
+    pub fn writer(&self) -> &IrqSafeSpinlock<FrameBufferWriter<'a>> {
+        &self.writer
+    }
+}
+
+impl log::Log for FramebufferLogger<'_> {
+    fn enabled(&self, _metadata: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        let _ = writeln!(
+            self.writer.lock(),
+            "{}{:5}: {}{}", level_sgr(record.level()), record.level(), record.args(), SGR_RESET
+        );
+        if let Some(mirror) = self.mirror {
+            mirror(format_args!("{:5}: {}\n", record.level(), record.args()));
+        }
+    }
+
+    fn flush(&self) {}
+}
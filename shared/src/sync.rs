@@ -0,0 +1,97 @@
+use core::ops::{Deref, DerefMut};
+use spin::{Mutex, MutexGuard};
+
+/**
+ *  spinlock usable from both bootloader and kernel.
+ *
+ *  with feature `kernel`, interrupts are masked on current cpu while the lock is
+ *  held and restored on drop, handlers can never spin on a lock owned by the
+ *  code they interrupted. with feature `uefi`, firmware owns the interrupt flag
+ *  (boot services timers stop under cli), the lock only spins.
+ *
+ *  the kernel has richer locks in `kernel::sync`, this one is for code shared
+ *  between both environments.
+ */
+
+pub struct IrqSafeSpinlock<T: ?Sized> {
+    inner: Mutex<T>,
+}
+
+pub struct IrqSafeSpinlockGuard<'a, T: ?Sized + 'a> {
+    guard: Option<MutexGuard<'a, T>>,
+    irq_was_enabled: bool,
+}
+
+impl<T> IrqSafeSpinlock<T> {
+    pub const fn new(value: T) -> Self {
+        Self { inner: Mutex::new(value) }
+    }
+}
+
+impl<T: ?Sized> IrqSafeSpinlock<T> {
+    pub fn lock(&self) -> IrqSafeSpinlockGuard<'_, T> {
+        let irq_was_enabled = unsafe { irq_save() };
+        IrqSafeSpinlockGuard { guard: Some(self.inner.lock()), irq_was_enabled }
+    }
+
+    pub fn try_lock(&self) -> Option<IrqSafeSpinlockGuard<'_, T>> {
+        let irq_was_enabled = unsafe { irq_save() };
+        match self.inner.try_lock() {
+            Some(guard) => Some(IrqSafeSpinlockGuard { guard: Some(guard), irq_was_enabled }),
+            None => {
+                unsafe { irq_restore(irq_was_enabled) };
+                None
+            }
+        }
+    }
+
+    /// release the lock regardless of owner, only for panic paths
+    pub unsafe fn force_unlock(&self) {
+        self.inner.force_unlock()
+    }
+}
+
+impl<T: ?Sized> Deref for IrqSafeSpinlockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl<T: ?Sized> DerefMut for IrqSafeSpinlockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+impl<T: ?Sized> Drop for IrqSafeSpinlockGuard<'_, T> {
+    fn drop(&mut self) {
+        // unlock before interrupts come back
+        drop(self.guard.take());
+        unsafe { irq_restore(self.irq_was_enabled) };
+    }
+}
+
+// disable interrupts, returns whether they were enabled
+#[cfg(all(feature = "kernel", target_arch = "x86_64"))]
+unsafe fn irq_save() -> bool {
+    let rflags: u64;
+    core::arch::asm!("pushfq; pop {}; cli", out(reg) rflags, options(preserves_flags));
+    rflags & (1 << 9) != 0
+}
+
+#[cfg(all(feature = "kernel", target_arch = "x86_64"))]
+unsafe fn irq_restore(enabled: bool) {
+    if enabled {
+        core::arch::asm!("sti", options(nomem, nostack));
+    }
+}
+
+#[cfg(not(all(feature = "kernel", target_arch = "x86_64")))]
+unsafe fn irq_save() -> bool {
+    false
+}
+
+#[cfg(not(all(feature = "kernel", target_arch = "x86_64")))]
+unsafe fn irq_restore(_enabled: bool) {}